@@ -0,0 +1,212 @@
+//! Micro-benchmarks for the per-request hot paths. Run with `cargo bench`;
+//! criterion keeps the previous run under `target/criterion` and reports
+//! regressions against it. The `allocations/` group counts heap allocations
+//! per iteration instead of timing it, so an extra copy on the hot path shows
+//! up as a regression however fast the machine is.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use bytes::Bytes;
+use criterion::measurement::{Measurement, ValueFormatter};
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use hyper::{HeaderMap, Method, StatusCode};
+use api_gateway::{
+    config::{CacheKeyConfig, CacheMode, FieldsConfig, GatewayConfig, RouteConfig},
+    fields::FieldFilter,
+    routes::{RouteRequest, RouteTable},
+    services::{cache_key, cache_response, check_rate_limit, get_cached_response},
+    AppState, CachedResponse,
+};
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+/// The system allocator, counting allocations for the `allocations/` group.
+struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// Heap allocations made while a benchmark runs, growing a buffer included.
+struct Allocations;
+
+impl Measurement for Allocations {
+    type Intermediate = u64;
+    type Value = u64;
+
+    fn start(&self) -> u64 {
+        ALLOCATIONS.load(Ordering::Relaxed)
+    }
+
+    fn end(&self, started: u64) -> u64 {
+        ALLOCATIONS.load(Ordering::Relaxed) - started
+    }
+
+    fn add(&self, v1: &u64, v2: &u64) -> u64 {
+        v1 + v2
+    }
+
+    fn zero(&self) -> u64 {
+        0
+    }
+
+    fn to_f64(&self, value: &u64) -> f64 {
+        *value as f64
+    }
+
+    fn formatter(&self) -> &dyn ValueFormatter {
+        &AllocationsFormatter
+    }
+}
+
+struct AllocationsFormatter;
+
+impl ValueFormatter for AllocationsFormatter {
+    fn scale_values(&self, _typical_value: f64, _values: &mut [f64]) -> &'static str {
+        "allocs"
+    }
+
+    fn scale_throughputs(&self, _typical_value: f64, throughput: &Throughput, values: &mut [f64]) -> &'static str {
+        let (Throughput::Bytes(n) | Throughput::BytesDecimal(n) | Throughput::Elements(n)) = *throughput;
+        for value in values {
+            *value /= n as f64;
+        }
+        "allocs/elem"
+    }
+
+    fn scale_for_machines(&self, _values: &mut [f64]) -> &'static str {
+        "allocs"
+    }
+}
+
+fn route_table(routes: usize) -> RouteTable {
+    let routes = (0..routes)
+        .map(|i| RouteConfig {
+            path_prefix: format!("/api/service-{}/{{id}}", i),
+            upstream: Some(format!("http://127.0.0.1:{}", 9000 + i)),
+            ..RouteConfig::default()
+        })
+        .collect();
+    RouteTable::from_config(&GatewayConfig { routes, ..GatewayConfig::default() }).unwrap()
+}
+
+fn route_matching(c: &mut Criterion) {
+    let headers = HeaderMap::new();
+    for routes in [100, 1000] {
+        let table = route_table(routes);
+        c.bench_function(&format!("route_match/{}_routes", routes), |b| {
+            b.iter(|| {
+                let request = RouteRequest {
+                    method: &Method::GET,
+                    path: black_box("/api/service-87/42/details"),
+                    query: "",
+                    headers: &headers,
+                    body: None,
+                };
+                table.find(&request).unwrap().route.name.len()
+            })
+        });
+    }
+}
+
+fn cache_lookup(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let state = Arc::new(AppState::new());
+    let mut headers = HeaderMap::new();
+    headers.insert("content-type", "application/json".parse().unwrap());
+    let body = Bytes::from(vec![b'x'; 16 * 1024]);
+    runtime.block_on(async {
+        for i in 0..10_000 {
+            let response = CachedResponse::new(StatusCode::OK, headers.clone(), body.clone());
+            cache_response(&state, &format!("users:GET/api/users/{}", i), Arc::new(response)).await;
+        }
+    });
+
+    c.bench_function("cache_lookup/hit", |b| {
+        b.to_async(&runtime)
+            .iter(|| async { get_cached_response(&state, black_box("users:GET/api/users/4242")).await.unwrap() })
+    });
+    c.bench_function("cache_lookup/miss", |b| {
+        b.to_async(&runtime)
+            .iter(|| async { get_cached_response(&state, black_box("users:GET/api/users/none")).await })
+    });
+}
+
+fn limiter_check(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let state = Arc::new(AppState::new());
+    let clients: Vec<HeaderMap> = (0..1_000)
+        .map(|i| {
+            let mut headers = HeaderMap::new();
+            headers.insert("x-forwarded-for", format!("10.0.{}.{}", i / 256, i % 256).parse().unwrap());
+            headers
+        })
+        .collect();
+
+    let mut next = 0;
+    c.bench_function("limiter_check/1000_clients", |b| {
+        b.to_async(&runtime).iter(|| {
+            next = (next + 1) % clients.len();
+            let headers = &clients[next];
+            let state = &state;
+            async move { check_rate_limit(state, headers).await }
+        })
+    });
+}
+
+/// Allocations of the steps every proxied GET takes: matching the route,
+/// splitting off the field selection, building the cache key and the
+/// upstream URI.
+fn hot_path_allocations(c: &mut Criterion<Allocations>) {
+    let table = route_table(100);
+    let headers = HeaderMap::new();
+    let request = RouteRequest {
+        method: &Method::GET,
+        path: "/api/service-87/42/details",
+        query: "page=2&sort=name",
+        headers: &headers,
+        body: None,
+    };
+    let route_match = table.find(&request).unwrap();
+    let mut per_user = route_match.route.clone();
+    per_user.cache = CacheMode::PerUser;
+    let filter = FieldFilter::new(&FieldsConfig::default());
+    let key_config = CacheKeyConfig::default();
+
+    c.bench_function("allocations/route_match", |b| b.iter(|| table.find(black_box(&request)).unwrap().params.len()));
+    c.bench_function("allocations/field_split", |b| b.iter(|| filter.split(black_box(request.query)).0.len()));
+    c.bench_function("allocations/cache_key", |b| {
+        b.iter(|| cache_key(route_match.route, &Method::GET, black_box(request.path), request.query, Some("acme"), None, &key_config))
+    });
+    c.bench_function("allocations/cache_key_per_user", |b| {
+        b.iter(|| cache_key(&per_user, &Method::GET, black_box(request.path), request.query, None, Some("example-user"), &key_config))
+    });
+    c.bench_function("allocations/upstream_uri", |b| {
+        b.iter(|| route_match.upstream_uri(black_box(request.path), request.query))
+    });
+}
+
+criterion_group!(benches, route_matching, cache_lookup, limiter_check);
+criterion_group! {
+    name = allocations;
+    // Every sample of a count is the same, which leaves nothing to plot.
+    config = Criterion::default().with_measurement(Allocations).without_plots();
+    targets = hot_path_allocations
+}
+criterion_main!(benches, allocations);
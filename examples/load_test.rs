@@ -0,0 +1,153 @@
+//! End-to-end load test: starts a local echo upstream, runs the gateway binary
+//! in front of it and reports throughput and latency percentiles.
+//!
+//! ```text
+//! cargo build --release
+//! cargo run --release --example load_test -- --requests 20000 --concurrency 64
+//! ```
+//!
+//! Options: `--requests N`, `--concurrency N`, `--path /echo/...` and
+//! `--gateway <binary>` (defaults to the `api-gateway` built alongside this
+//! example). Each request carries its own `X-Forwarded-For`, so the limiter
+//! sees many clients rather than throttling one.
+
+use std::collections::BTreeMap;
+use std::net::{SocketAddr, TcpListener};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use hyper::{Body, Client, Request};
+use warp::Filter;
+use api_gateway::config::CONFIG_PATH_ENV;
+
+struct Options {
+    requests: usize,
+    concurrency: usize,
+    path: String,
+    gateway: PathBuf,
+}
+
+fn options() -> Options {
+    let mut options = Options {
+        requests: 10_000,
+        concurrency: 32,
+        path: "/echo/hello".to_string(),
+        gateway: std::env::current_exe()
+            .ok()
+            .and_then(|exe| Some(exe.parent()?.parent()?.join("api-gateway")))
+            .unwrap_or_else(|| PathBuf::from("target/release/api-gateway")),
+    };
+    let mut args = std::env::args().skip(1);
+    while let Some(flag) = args.next() {
+        let value = args.next().unwrap_or_else(|| panic!("{} needs a value", flag));
+        match flag.as_str() {
+            "--requests" => options.requests = value.parse().expect("--requests takes a number"),
+            "--concurrency" => options.concurrency = value.parse().expect("--concurrency takes a number"),
+            "--path" => options.path = value,
+            "--gateway" => options.gateway = PathBuf::from(value),
+            other => panic!("unknown option {}", other),
+        }
+    }
+    options
+}
+
+#[tokio::main]
+async fn main() {
+    let options = options();
+
+    let echo = warp::any()
+        .and(warp::body::bytes())
+        .map(|body: bytes::Bytes| hyper::Response::new(Body::from(body)));
+    let (upstream, server) = warp::serve(echo).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+
+    let listen_addr: SocketAddr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let config_path = std::env::temp_dir().join(format!("api-gateway-load-test-{}.json", std::process::id()));
+    let config = serde_json::json!({
+        "listen_addr": listen_addr.to_string(),
+        "routes": [{ "name": "echo", "path_prefix": "/echo", "upstream": format!("http://{}", upstream) }],
+    });
+    std::fs::write(&config_path, config.to_string()).unwrap();
+
+    let mut gateway = Command::new(&options.gateway)
+        .env(CONFIG_PATH_ENV, &config_path)
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap_or_else(|e| panic!("starting {}: {} (build it with `cargo build --release`)", options.gateway.display(), e));
+
+    let client = Client::new();
+    let health = format!("http://{}/health", listen_addr);
+    let ready = Instant::now();
+    while client.get(health.parse().unwrap()).await.is_err() {
+        assert!(ready.elapsed() < Duration::from_secs(10), "gateway did not come up");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    let url = format!("http://{}{}", listen_addr, options.path);
+    let next = Arc::new(AtomicUsize::new(0));
+    let started = Instant::now();
+    let workers: Vec<_> = (0..options.concurrency)
+        .map(|_| {
+            let (client, url, next) = (client.clone(), url.clone(), next.clone());
+            let total = options.requests;
+            tokio::spawn(async move {
+                let mut latencies = Vec::new();
+                let mut statuses = BTreeMap::new();
+                loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    if i >= total {
+                        break;
+                    }
+                    let request = Request::get(&url)
+                        .header("authorization", "Bearer example-token")
+                        .header("x-forwarded-for", format!("10.{}.{}.{}", i >> 16 & 255, i >> 8 & 255, i & 255))
+                        .body(Body::empty())
+                        .unwrap();
+                    let sent = Instant::now();
+                    let status = match client.request(request).await {
+                        Ok(response) => {
+                            let status = response.status().as_u16();
+                            let _ = hyper::body::to_bytes(response.into_body()).await;
+                            status
+                        }
+                        Err(_) => 0,
+                    };
+                    latencies.push(sent.elapsed());
+                    *statuses.entry(status).or_insert(0usize) += 1;
+                }
+                (latencies, statuses)
+            })
+        })
+        .collect();
+
+    let mut latencies = Vec::with_capacity(options.requests);
+    let mut statuses = BTreeMap::new();
+    for worker in workers {
+        let (worker_latencies, worker_statuses) = worker.await.unwrap();
+        latencies.extend(worker_latencies);
+        for (status, count) in worker_statuses {
+            *statuses.entry(status).or_insert(0) += count;
+        }
+    }
+    let elapsed = started.elapsed();
+
+    let _ = gateway.kill();
+    let _ = gateway.wait();
+    let _ = std::fs::remove_file(&config_path);
+
+    latencies.sort();
+    let percentile = |p: f64| latencies[((latencies.len() - 1) as f64 * p) as usize];
+    println!("{} requests, {} concurrent, {:.2?}", latencies.len(), options.concurrency, elapsed);
+    println!("throughput: {:.0} req/s", latencies.len() as f64 / elapsed.as_secs_f64());
+    println!(
+        "latency: p50 {:.2?}  p90 {:.2?}  p99 {:.2?}  max {:.2?}",
+        percentile(0.50),
+        percentile(0.90),
+        percentile(0.99),
+        latencies[latencies.len() - 1]
+    );
+    let statuses: Vec<_> = statuses.iter().map(|(status, count)| format!("{}: {}", status, count)).collect();
+    println!("statuses: {}", statuses.join(", "));
+}
@@ -0,0 +1,639 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use base64::Engine;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use bytes::Bytes;
+use hyper::{Body, HeaderMap, Request, Response, StatusCode, header::{HeaderValue, CONTENT_TYPE, LOCATION}};
+use ring::digest::{digest, SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{EcdsaKeyPair, EcdsaSigningAlgorithm, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING, ECDSA_P256_SHA256_FIXED_SIGNING};
+use rustls::sign::CertifiedKey;
+use serde_json::{json, Value};
+use tokio::sync::Mutex;
+use tokio::time::timeout;
+use warp::{Filter, Rejection};
+use crate::api_keys::write_private;
+use crate::aws::{self, HttpsClient};
+use crate::cluster::LeaderElection;
+use crate::config::{AcmeChallenge, AcmeConfig};
+use crate::tls::{self, CertResolver};
+use crate::syslog;
+
+#[cfg(test)]
+mod tests;
+
+const ACCOUNT_KEY_FILE: &str = "account.key";
+const REQUEST_TIMEOUT_SECS: u64 = 30;
+const POLL_ATTEMPTS: u32 = 30;
+const POLL_INTERVAL_SECS: u64 = 2;
+/// How soon a failed order is tried again, unless `check_interval_secs` is
+/// shorter. Let's Encrypt allows five failed validations per hour.
+const RETRY_SECS: u64 = 3600;
+const CHALLENGE_CERT_DAYS: u64 = 7;
+const DAY_SECS: u64 = 86_400;
+const BAD_NONCE: &str = "urn:ietf:params:acme:error:badNonce";
+
+const OID_EC_PUBLIC_KEY: &[u8] = &[0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+const OID_P256: &[u8] = &[0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const OID_ECDSA_SHA256: &[u8] = &[0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+const OID_COMMON_NAME: &[u8] = &[0x06, 0x03, 0x55, 0x04, 0x03];
+const OID_SUBJECT_ALT_NAME: &[u8] = &[0x06, 0x03, 0x55, 0x1d, 0x11];
+const OID_EXTENSION_REQUEST: &[u8] = &[0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x0e];
+/// id-pe-acmeIdentifier, RFC 8737.
+const OID_ACME_IDENTIFIER: &[u8] = &[0x06, 0x08, 0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x01, 0x1f];
+
+#[derive(Clone)]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+/// What one conversation with the CA has learned so far.
+#[derive(Default)]
+struct Session {
+    directory: Option<Directory>,
+    /// The account URL, sent as `kid` once the account exists.
+    account: Option<String>,
+    nonce: Option<String>,
+}
+
+/// Obtains and renews a certificate per configured domain from an ACME CA
+/// (RFC 8555), serving them through `resolver` as they arrive.
+pub struct Acme {
+    config: AcmeConfig,
+    resolver: Arc<CertResolver>,
+    client: HttpsClient,
+    random: SystemRandom,
+    account_key: EcdsaKeyPair,
+    /// When each domain's certificate expires, Unix seconds.
+    expiry: RwLock<HashMap<String, u64>>,
+    /// HTTP-01 token to key authorization.
+    http_tokens: RwLock<HashMap<String, String>>,
+    /// Orders are placed one at a time.
+    session: Mutex<Session>,
+}
+
+impl Acme {
+    /// Loads the account key, creating it on first use, and serves the
+    /// certificates already in `cert_dir`.
+    pub fn new(config: &AcmeConfig, resolver: Arc<CertResolver>) -> Result<Self, String> {
+        let random = SystemRandom::new();
+        let account_path = config.cert_dir.join(ACCOUNT_KEY_FILE);
+        let pkcs8 = match std::fs::read(&account_path) {
+            Ok(pem) => pem_contents(&pem).ok_or_else(|| format!("{} is not a PEM private key", account_path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let pkcs8 = generate_key(&ECDSA_P256_SHA256_FIXED_SIGNING, &random)?;
+                write_private(&account_path, pem("PRIVATE KEY", &pkcs8).as_bytes())
+                    .map_err(|e| format!("writing {}: {}", account_path.display(), e))?;
+                pkcs8
+            }
+            Err(e) => return Err(format!("reading {}: {}", account_path.display(), e)),
+        };
+        let account_key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8, &random)
+            .map_err(|_| format!("{} is not a P-256 key", account_path.display()))?;
+
+        let acme = Self {
+            config: config.clone(),
+            resolver,
+            client: aws::https_client(),
+            random,
+            account_key,
+            expiry: RwLock::new(HashMap::new()),
+            http_tokens: RwLock::new(HashMap::new()),
+            session: Mutex::new(Session::default()),
+        };
+        for domain in &config.domains {
+            match acme.load(domain) {
+                Ok(Some(expires)) => acme.set_expiry(domain, expires),
+                Ok(None) => {}
+                Err(e) => syslog::error(format_args!("Ignoring the stored certificate for {}: {}", domain, e)),
+            }
+        }
+        Ok(acme)
+    }
+
+    /// When the certificate for `domain` expires, Unix seconds.
+    pub fn expires_at(&self, domain: &str) -> Option<u64> {
+        self.expiry.read().unwrap_or_else(|e| e.into_inner()).get(domain).copied()
+    }
+
+    /// Domains without a certificate or whose certificate expires within
+    /// `renew_before_days` of `now`.
+    pub fn due(&self, now: u64) -> Vec<String> {
+        let renew_at = now + self.config.renew_before_days * DAY_SECS;
+        self.config
+            .domains
+            .iter()
+            .filter(|domain| self.expires_at(domain).is_none_or(|expires| expires <= renew_at))
+            .cloned()
+            .collect()
+    }
+
+    /// The key authorization for an HTTP-01 token being validated.
+    pub fn http_challenge(&self, token: &str) -> Option<String> {
+        self.http_tokens.read().unwrap_or_else(|e| e.into_inner()).get(token).cloned()
+    }
+
+    /// Orders certificates for every due domain each `check_interval_secs`,
+    /// logging the outcome. After a failure the next round comes sooner.
+    /// With `leader`, only the leader orders; the other instances serve what
+    /// it stored in the shared `cert_dir`.
+    pub async fn watch(self: Arc<Self>, leader: Option<Arc<LeaderElection>>) {
+        loop {
+            let mut failed = false;
+            let leads = leader.as_ref().is_none_or(|leader| leader.is_leader());
+            for domain in self.due(unix_now()) {
+                if !leads {
+                    match self.load(&domain) {
+                        Ok(Some(expires)) => self.set_expiry(&domain, expires),
+                        Ok(None) => {}
+                        Err(e) => syslog::error(format_args!("Ignoring the stored certificate for {}: {}", domain, e)),
+                    }
+                    continue;
+                }
+                match self.issue(&domain).await {
+                    Ok(expires) => println!(
+                        "Obtained a certificate for {}, valid until {}",
+                        domain,
+                        httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(expires))
+                    ),
+                    Err(e) => {
+                        failed = true;
+                        syslog::error(format_args!("Failed to obtain a certificate for {}: {}", domain, e));
+                    }
+                }
+            }
+            let interval = self.config.check_interval_secs;
+            let wait = if failed { interval.min(RETRY_SECS) } else { interval };
+            tokio::time::sleep(Duration::from_secs(wait)).await;
+        }
+    }
+
+    /// Orders a certificate for `domain`, stores it in `cert_dir` and serves
+    /// it from the next handshake on. Returns when it expires.
+    pub async fn issue(&self, domain: &str) -> Result<u64, String> {
+        let mut session = self.session.lock().await;
+        let directory = self.directory(&mut session).await?;
+        if session.account.is_none() {
+            let contact: Vec<String> = self
+                .config
+                .contact
+                .iter()
+                .map(|contact| if contact.starts_with("mailto:") { contact.clone() } else { format!("mailto:{}", contact) })
+                .collect();
+            let payload = json!({ "termsOfServiceAgreed": true, "contact": contact });
+            let (headers, _) = self.post(&mut session, &directory.new_account, Some(&payload)).await?;
+            session.account = Some(location(&headers)?);
+        }
+
+        let payload = json!({ "identifiers": [{ "type": "dns", "value": domain }] });
+        let (headers, body) = self.post(&mut session, &directory.new_order, Some(&payload)).await?;
+        let order_url = location(&headers)?;
+        let order = parse(&body)?;
+        for authorization in order["authorizations"].as_array().into_iter().flatten() {
+            let url = authorization.as_str().ok_or("authorization is not a URL")?;
+            self.authorize(&mut session, url).await?;
+        }
+
+        let order = self.poll(&mut session, &order_url, "ready").await?;
+        let finalize = order["finalize"].as_str().ok_or("order has no finalize URL")?;
+        let pkcs8 = generate_key(&ECDSA_P256_SHA256_ASN1_SIGNING, &self.random)?;
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &pkcs8, &self.random)
+            .map_err(|_| "generated key is unusable".to_string())?;
+        let csr = certificate_request(domain, &key, &self.random)?;
+        self.post(&mut session, finalize, Some(&json!({ "csr": URL_SAFE_NO_PAD.encode(csr) }))).await?;
+        let order = self.poll(&mut session, &order_url, "valid").await?;
+        let certificate = order["certificate"].as_str().ok_or("order has no certificate URL")?;
+        let (_, chain) = self.post(&mut session, certificate, None).await?;
+        drop(session);
+
+        let key_pem = pem("PRIVATE KEY", &pkcs8);
+        let certified = tls::certified_key(&chain, key_pem.as_bytes())?;
+        let expires = not_after(&certified.cert[0]).ok_or("the certificate's validity can't be read")?;
+        let (cert_path, key_path) = self.paths(domain);
+        write_private(&key_path, key_pem.as_bytes()).map_err(|e| format!("writing {}: {}", key_path.display(), e))?;
+        std::fs::write(&cert_path, &chain).map_err(|e| format!("writing {}: {}", cert_path.display(), e))?;
+        self.resolver.insert(domain, certified);
+        self.set_expiry(domain, expires);
+        Ok(expires)
+    }
+
+    /// Proves control of the authorization's domain with the configured
+    /// challenge, unless the CA already considers it proven.
+    async fn authorize(&self, session: &mut Session, url: &str) -> Result<(), String> {
+        let (_, body) = self.post(session, url, None).await?;
+        let authorization = parse(&body)?;
+        if authorization["status"] == "valid" {
+            return Ok(());
+        }
+        let kind = match self.config.challenge {
+            AcmeChallenge::TlsAlpn01 => "tls-alpn-01",
+            AcmeChallenge::Http01 => "http-01",
+        };
+        let challenge = authorization["challenges"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find(|challenge| challenge["type"] == kind)
+            .ok_or_else(|| format!("the CA offers no {} challenge", kind))?;
+        let (Some(token), Some(challenge_url)) = (challenge["token"].as_str(), challenge["url"].as_str()) else {
+            return Err(format!("malformed {} challenge", kind));
+        };
+        let domain = authorization["identifier"]["value"].as_str().ok_or("authorization has no identifier")?;
+        let key_authorization = format!("{}.{}", token, self.thumbprint());
+        match self.config.challenge {
+            AcmeChallenge::TlsAlpn01 => self.resolver.insert_challenge(domain, challenge_key(domain, &key_authorization)?),
+            AcmeChallenge::Http01 => {
+                self.http_tokens.write().unwrap_or_else(|e| e.into_inner()).insert(token.to_string(), key_authorization);
+            }
+        }
+
+        let result = match self.post(session, challenge_url, Some(&json!({}))).await {
+            Ok(_) => self.poll(session, url, "valid").await.map(|_| ()),
+            Err(e) => Err(e),
+        };
+        match self.config.challenge {
+            AcmeChallenge::TlsAlpn01 => self.resolver.remove_challenge(domain),
+            AcmeChallenge::Http01 => {
+                self.http_tokens.write().unwrap_or_else(|e| e.into_inner()).remove(token);
+            }
+        }
+        result
+    }
+
+    /// Fetches `url` until its status is `status`, failing if it becomes
+    /// `invalid` or takes too long.
+    async fn poll(&self, session: &mut Session, url: &str, status: &str) -> Result<Value, String> {
+        for attempt in 0..POLL_ATTEMPTS {
+            if attempt > 0 {
+                tokio::time::sleep(Duration::from_secs(POLL_INTERVAL_SECS)).await;
+            }
+            let (_, body) = self.post(session, url, None).await?;
+            let resource = parse(&body)?;
+            if resource["status"] == status {
+                return Ok(resource);
+            }
+            if resource["status"] == "invalid" {
+                return Err(format!("{} is invalid: {}", url, problem_detail(&resource)));
+            }
+        }
+        Err(format!("{} did not become {}", url, status))
+    }
+
+    async fn directory(&self, session: &mut Session) -> Result<Directory, String> {
+        if let Some(directory) = &session.directory {
+            return Ok(directory.clone());
+        }
+        let request = Request::get(&self.config.directory_url).body(Body::empty()).map_err(|e| e.to_string())?;
+        let (status, _, body) = self.send(request).await?;
+        if !status.is_success() {
+            return Err(format!("{} from {}", status, self.config.directory_url));
+        }
+        let listing = parse(&body)?;
+        let url = |name: &str| {
+            listing[name].as_str().map(str::to_string).ok_or_else(|| format!("the directory has no {}", name))
+        };
+        let directory = Directory { new_nonce: url("newNonce")?, new_account: url("newAccount")?, new_order: url("newOrder")? };
+        session.directory = Some(directory.clone());
+        Ok(directory)
+    }
+
+    /// A JWS-signed POST, or POST-as-GET without a payload. A rejected nonce
+    /// is retried once with the fresh one the CA sent back.
+    async fn post(&self, session: &mut Session, url: &str, payload: Option<&Value>) -> Result<(HeaderMap, Bytes), String> {
+        let mut retried = false;
+        loop {
+            let nonce = match session.nonce.take() {
+                Some(nonce) => nonce,
+                None => self.new_nonce(session).await?,
+            };
+            let body = self.sign(session.account.as_deref(), url, &nonce, payload)?;
+            let request = Request::post(url)
+                .header(CONTENT_TYPE, "application/jose+json")
+                .body(Body::from(body))
+                .map_err(|e| e.to_string())?;
+            let (status, headers, body) = self.send(request).await?;
+            session.nonce = replay_nonce(&headers);
+            if status.is_success() {
+                return Ok((headers, body));
+            }
+            let problem: Value = serde_json::from_slice(&body).unwrap_or(Value::Null);
+            if problem["type"] == BAD_NONCE && !retried {
+                retried = true;
+                continue;
+            }
+            return Err(format!("{} from {}: {}", status, url, problem_detail(&problem)));
+        }
+    }
+
+    async fn new_nonce(&self, session: &mut Session) -> Result<String, String> {
+        let directory = self.directory(session).await?;
+        let request = Request::head(&directory.new_nonce).body(Body::empty()).map_err(|e| e.to_string())?;
+        let (_, headers, _) = self.send(request).await?;
+        replay_nonce(&headers).ok_or_else(|| "the CA sent no Replay-Nonce".to_string())
+    }
+
+    async fn send(&self, request: Request<Body>) -> Result<(StatusCode, HeaderMap, Bytes), String> {
+        let response = timeout(Duration::from_secs(REQUEST_TIMEOUT_SECS), self.client.request(request))
+            .await
+            .map_err(|_| "request to the CA timed out".to_string())?
+            .map_err(|e| format!("request to the CA failed: {}", e))?;
+        let (parts, body) = response.into_parts();
+        let body = hyper::body::to_bytes(body).await.map_err(|e| format!("reading the CA's response: {}", e))?;
+        Ok((parts.status, parts.headers, body))
+    }
+
+    /// The flattened JWS of RFC 8555 section 6.2: the account URL as `kid`
+    /// once there is an account, the public key before.
+    fn sign(&self, account: Option<&str>, url: &str, nonce: &str, payload: Option<&Value>) -> Result<String, String> {
+        let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+        match account {
+            Some(account) => protected["kid"] = json!(account),
+            None => protected["jwk"] = self.jwk(),
+        }
+        let protected = URL_SAFE_NO_PAD.encode(protected.to_string());
+        let payload = payload.map(|payload| URL_SAFE_NO_PAD.encode(payload.to_string())).unwrap_or_default();
+        let signature = self
+            .account_key
+            .sign(&self.random, format!("{}.{}", protected, payload).as_bytes())
+            .map_err(|_| "signing the request failed".to_string())?;
+        Ok(json!({ "protected": protected, "payload": payload, "signature": URL_SAFE_NO_PAD.encode(signature) }).to_string())
+    }
+
+    fn jwk(&self) -> Value {
+        let (x, y) = self.account_key.public_key().as_ref()[1..].split_at(32);
+        json!({ "crv": "P-256", "kty": "EC", "x": URL_SAFE_NO_PAD.encode(x), "y": URL_SAFE_NO_PAD.encode(y) })
+    }
+
+    /// The RFC 7638 thumbprint of the account key, with the members in the
+    /// required order.
+    fn thumbprint(&self) -> String {
+        let jwk = self.jwk();
+        let canonical = format!(
+            r#"{{"crv":"P-256","kty":"EC","x":{},"y":{}}}"#,
+            jwk["x"], jwk["y"]
+        );
+        URL_SAFE_NO_PAD.encode(digest(&SHA256, canonical.as_bytes()))
+    }
+
+    fn paths(&self, domain: &str) -> (PathBuf, PathBuf) {
+        let dir = &self.config.cert_dir;
+        (dir.join(format!("{}.crt", domain)), dir.join(format!("{}.key", domain)))
+    }
+
+    /// Serves the stored certificate for `domain`, if there is one.
+    fn load(&self, domain: &str) -> Result<Option<u64>, String> {
+        let (cert_path, key_path) = self.paths(domain);
+        let (cert, key) = match (std::fs::read(&cert_path), std::fs::read(&key_path)) {
+            (Ok(cert), Ok(key)) => (cert, key),
+            _ => return Ok(None),
+        };
+        let certified = tls::certified_key(&cert, &key)?;
+        let expires = not_after(&certified.cert[0]).ok_or("the certificate's validity can't be read")?;
+        self.resolver.insert(domain, certified);
+        Ok(Some(expires))
+    }
+
+    fn set_expiry(&self, domain: &str, expires: u64) {
+        self.expiry.write().unwrap_or_else(|e| e.into_inner()).insert(domain.to_string(), expires);
+    }
+}
+
+/// `GET /.well-known/acme-challenge/<token>`, answered while an HTTP-01
+/// validation is under way.
+pub fn routes(acme: Option<Arc<Acme>>) -> impl Filter<Extract = (Response<Body>,), Error = Rejection> + Clone {
+    warp::path!(".well-known" / "acme-challenge" / String)
+        .and(warp::get())
+        .and_then(move |token: String| {
+            let key_authorization = acme.as_ref().and_then(|acme| acme.http_challenge(&token));
+            async move {
+                let key_authorization = key_authorization.ok_or_else(warp::reject::not_found)?;
+                let mut response = Response::new(Body::from(key_authorization));
+                response.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("application/octet-stream"));
+                Ok::<_, Rejection>(response)
+            }
+        })
+}
+
+/// The self-signed certificate that answers TLS-ALPN-01 validation of
+/// `domain`: it names the domain and carries the SHA-256 of the key
+/// authorization in a critical acmeIdentifier extension.
+pub fn challenge_key(domain: &str, key_authorization: &str) -> Result<CertifiedKey, String> {
+    let random = SystemRandom::new();
+    let proof = der(0x04, digest(&SHA256, key_authorization.as_bytes()).as_ref());
+    let acme_identifier = sequence(&[OID_ACME_IDENTIFIER.to_vec(), der(0x01, &[0xff]), der(0x04, &proof)]);
+    let (cert, pkcs8) = self_signed(domain, vec![acme_identifier], CHALLENGE_CERT_DAYS, &random)?;
+    tls::certified_key(pem("CERTIFICATE", &cert).as_bytes(), pem("PRIVATE KEY", &pkcs8).as_bytes())
+}
+
+/// A P-256 certificate for `domain` valid from a day ago for `days`, with a
+/// subjectAltName and `extensions`. Returns it and its PKCS#8 key, as DER.
+pub fn self_signed(domain: &str, extensions: Vec<Vec<u8>>, days: u64, random: &SystemRandom) -> Result<(Vec<u8>, Vec<u8>), String> {
+    let pkcs8 = generate_key(&ECDSA_P256_SHA256_ASN1_SIGNING, random)?;
+    let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &pkcs8, random)
+        .map_err(|_| "generated key is unusable".to_string())?;
+    let mut serial = [0u8; 8];
+    random.fill(&mut serial).map_err(|_| "no randomness available".to_string())?;
+    serial[0] = (serial[0] & 0x7f) | 0x40;
+    let now = unix_now();
+    let mut all_extensions = vec![subject_alt_name(domain)];
+    all_extensions.extend(extensions);
+    let tbs = sequence(&[
+        der(0xa0, &der(0x02, &[2])),
+        der(0x02, &serial),
+        sequence(&[OID_ECDSA_SHA256.to_vec()]),
+        name(domain),
+        sequence(&[utc_time(now - DAY_SECS), utc_time(now + days * DAY_SECS)]),
+        name(domain),
+        public_key_info(&key),
+        der(0xa3, &sequence(&all_extensions)),
+    ]);
+    Ok((signed(tbs, &key, random)?, pkcs8))
+}
+
+/// A PKCS#10 request for `domain`, signed with `key`.
+fn certificate_request(domain: &str, key: &EcdsaKeyPair, random: &SystemRandom) -> Result<Vec<u8>, String> {
+    let extension_request = sequence(&[
+        OID_EXTENSION_REQUEST.to_vec(),
+        der(0x31, &sequence(&[subject_alt_name(domain)])),
+    ]);
+    let info = sequence(&[der(0x02, &[0]), name(domain), public_key_info(key), der(0xa0, &extension_request)]);
+    signed(info, key, random)
+}
+
+fn signed(body: Vec<u8>, key: &EcdsaKeyPair, random: &SystemRandom) -> Result<Vec<u8>, String> {
+    let signature = key.sign(random, &body).map_err(|_| "signing failed".to_string())?;
+    Ok(sequence(&[body, sequence(&[OID_ECDSA_SHA256.to_vec()]), bit_string(signature.as_ref())]))
+}
+
+fn name(domain: &str) -> Vec<u8> {
+    sequence(&[der(0x31, &sequence(&[OID_COMMON_NAME.to_vec(), der(0x0c, domain.as_bytes())]))])
+}
+
+fn public_key_info(key: &EcdsaKeyPair) -> Vec<u8> {
+    sequence(&[
+        sequence(&[OID_EC_PUBLIC_KEY.to_vec(), OID_P256.to_vec()]),
+        bit_string(key.public_key().as_ref()),
+    ])
+}
+
+fn subject_alt_name(domain: &str) -> Vec<u8> {
+    sequence(&[OID_SUBJECT_ALT_NAME.to_vec(), der(0x04, &sequence(&[der(0x82, domain.as_bytes())]))])
+}
+
+fn bit_string(bytes: &[u8]) -> Vec<u8> {
+    der(0x03, &[&[0][..], bytes].concat())
+}
+
+fn utc_time(unix: u64) -> Vec<u8> {
+    let days = (unix / DAY_SECS) as i64;
+    let secs = unix % DAY_SECS;
+    let (year, month, day) = civil_from_days(days);
+    let text = format!(
+        "{:02}{:02}{:02}{:02}{:02}{:02}Z",
+        year % 100,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    );
+    der(0x17, text.as_bytes())
+}
+
+fn sequence(parts: &[Vec<u8>]) -> Vec<u8> {
+    der(0x30, &parts.concat())
+}
+
+fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = content.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes: Vec<u8> = len.to_be_bytes().into_iter().skip_while(|&b| b == 0).collect();
+        out.push(0x80 | bytes.len() as u8);
+        out.extend(bytes);
+    }
+    out.extend_from_slice(content);
+    out
+}
+
+/// Splits the first DER element off `input`: its tag, contents and the rest.
+fn tlv(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = if first < 0x80 {
+        (first as usize, rest)
+    } else {
+        let count = (first & 0x7f) as usize;
+        if count == 0 || count > 4 || rest.len() < count {
+            return None;
+        }
+        let len = rest[..count].iter().fold(0usize, |len, &b| len << 8 | b as usize);
+        (len, &rest[count..])
+    };
+    (rest.len() >= len).then(|| (tag, &rest[..len], &rest[len..]))
+}
+
+/// The notAfter of a DER certificate, Unix seconds.
+pub fn not_after(cert: &[u8]) -> Option<u64> {
+    let (_, cert, _) = tlv(cert)?;
+    let (_, tbs, _) = tlv(cert)?;
+    let (tag, _, after_version) = tlv(tbs)?;
+    let fields = if tag == 0xa0 { after_version } else { tbs };
+    let (_, _, fields) = tlv(fields)?; // serialNumber
+    let (_, _, fields) = tlv(fields)?; // signature
+    let (_, _, fields) = tlv(fields)?; // issuer
+    let (_, validity, _) = tlv(fields)?;
+    let (_, _, validity) = tlv(validity)?; // notBefore
+    let (tag, time, _) = tlv(validity)?;
+    let text = std::str::from_utf8(time).ok()?.strip_suffix('Z')?;
+    let (year, rest) = match tag {
+        0x17 => {
+            let year: i64 = text.get(..2)?.parse().ok()?;
+            (if year < 50 { 2000 + year } else { 1900 + year }, text.get(2..)?)
+        }
+        0x18 => (text.get(..4)?.parse().ok()?, text.get(4..)?),
+        _ => return None,
+    };
+    if rest.len() != 10 {
+        return None;
+    }
+    let field = |at: usize| rest.get(at..at + 2)?.parse::<i64>().ok();
+    let days = days_from_civil(year, field(0)?, field(2)?);
+    let secs = days * DAY_SECS as i64 + field(4)? * 3600 + field(6)? * 60 + field(8)?;
+    u64::try_from(secs).ok()
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// The date `days` after 1970-01-01.
+pub fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+fn generate_key(algorithm: &'static EcdsaSigningAlgorithm, random: &SystemRandom) -> Result<Vec<u8>, String> {
+    EcdsaKeyPair::generate_pkcs8(algorithm, random)
+        .map(|pkcs8| pkcs8.as_ref().to_vec())
+        .map_err(|_| "generating a key failed".to_string())
+}
+
+/// `der` as a PEM block labelled `label`.
+pub fn pem(label: &str, der: &[u8]) -> String {
+    let encoded = STANDARD.encode(der);
+    let lines: Vec<&str> = encoded.as_bytes().chunks(64).map(|line| std::str::from_utf8(line).unwrap_or_default()).collect();
+    format!("-----BEGIN {}-----\n{}\n-----END {}-----\n", label, lines.join("\n"), label)
+}
+
+/// The DER inside the first PEM block.
+fn pem_contents(pem: &[u8]) -> Option<Vec<u8>> {
+    let text = std::str::from_utf8(pem).ok()?;
+    let body: String = text.lines().skip_while(|line| !line.starts_with("-----BEGIN")).skip(1).take_while(|line| !line.starts_with("-----END")).collect();
+    STANDARD.decode(body).ok()
+}
+
+fn parse(body: &[u8]) -> Result<Value, String> {
+    serde_json::from_slice(body).map_err(|e| format!("invalid response from the CA: {}", e))
+}
+
+fn location(headers: &HeaderMap) -> Result<String, String> {
+    headers
+        .get(LOCATION)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+        .ok_or_else(|| "the CA sent no Location".to_string())
+}
+
+fn replay_nonce(headers: &HeaderMap) -> Option<String> {
+    headers.get("replay-nonce").and_then(|value| value.to_str().ok()).map(str::to_string)
+}
+
+fn problem_detail(problem: &Value) -> String {
+    let problem = problem.get("error").unwrap_or(problem);
+    problem["detail"].as_str().or(problem["type"].as_str()).unwrap_or("no details").to_string()
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
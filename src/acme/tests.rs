@@ -0,0 +1,239 @@
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use base64::Engine;
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use hyper::{Body, Client, Response};
+    use ring::digest::{digest, SHA256};
+    use ring::rand::SystemRandom;
+    use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_FIXED};
+    use serde_json::{json, Value};
+    use warp::Filter;
+    use crate::acme::{challenge_key, not_after, pem, routes, self_signed, utc_time, Acme, OID_ACME_IDENTIFIER};
+    use crate::config::{AcmeChallenge, AcmeConfig};
+    use crate::tls::CertResolver;
+
+    const DOMAIN: &str = "gateway.example.com";
+    const TOKEN: &str = "token-1";
+
+    #[derive(Default)]
+    struct Ca {
+        base: String,
+        /// Where the gateway answers HTTP-01 challenges.
+        challenges: String,
+        jwk: Option<Value>,
+        nonces: u32,
+        authorized: bool,
+        csr: Option<Vec<u8>>,
+    }
+
+    fn b64(value: &Value) -> Vec<u8> {
+        URL_SAFE_NO_PAD.decode(value.as_str().unwrap()).unwrap()
+    }
+
+    fn with_nonce(ca: &mut Ca, mut response: Response<Body>) -> Response<Body> {
+        ca.nonces += 1;
+        response.headers_mut().insert("replay-nonce", format!("nonce-{}", ca.nonces).parse().unwrap());
+        response
+    }
+
+    fn reply(status: u16, location: Option<String>, body: Value) -> Response<Body> {
+        let mut response = Response::new(Body::from(body.to_string()));
+        *response.status_mut() = warp::http::StatusCode::from_u16(status).unwrap();
+        if let Some(location) = location {
+            response.headers_mut().insert("location", location.parse().unwrap());
+        }
+        response
+    }
+
+    /// A CA that checks every JWS and validates HTTP-01 by fetching the
+    /// gateway's answer. The first nonce it hands out is refused.
+    async fn ca() -> Arc<Mutex<Ca>> {
+        let state = Arc::new(Mutex::new(Ca::default()));
+        let directory = warp::path!("directory").and(warp::get()).map({
+            let state = state.clone();
+            move || {
+                let base = state.lock().unwrap().base.clone();
+                warp::reply::json(&json!({
+                    "newNonce": format!("{}/nonce", base),
+                    "newAccount": format!("{}/account", base),
+                    "newOrder": format!("{}/order/new", base),
+                }))
+            }
+        });
+        let nonce = warp::path!("nonce").and(warp::head()).map({
+            let state = state.clone();
+            move || with_nonce(&mut state.lock().unwrap(), Response::new(Body::empty()))
+        });
+        let post = warp::post().and(warp::path::tail()).and(warp::body::bytes()).then({
+            let state = state.clone();
+            move |tail: warp::path::Tail, body: bytes::Bytes| {
+                let state = state.clone();
+                async move {
+                    let jws: Value = serde_json::from_slice(&body).unwrap();
+                    let protected: Value = serde_json::from_slice(&b64(&jws["protected"])).unwrap();
+                    let (base, challenges) = {
+                        let ca = state.lock().unwrap();
+                        (ca.base.clone(), ca.challenges.clone())
+                    };
+                    assert_eq!(protected["alg"], "ES256");
+                    assert_eq!(protected["url"], format!("{}/{}", base, tail.as_str()));
+                    if protected["nonce"] == "nonce-1" {
+                        let problem = json!({ "type": "urn:ietf:params:acme:error:badNonce" });
+                        return with_nonce(&mut state.lock().unwrap(), reply(400, None, problem));
+                    }
+
+                    let jwk = match protected.get("jwk") {
+                        Some(jwk) => jwk.clone(),
+                        None => {
+                            assert_eq!(protected["kid"], format!("{}/account/1", base));
+                            state.lock().unwrap().jwk.clone().unwrap()
+                        }
+                    };
+                    let point = [&[4u8][..], &b64(&jwk["x"]), &b64(&jwk["y"])].concat();
+                    let signing_input = format!("{}.{}", jws["protected"].as_str().unwrap(), jws["payload"].as_str().unwrap());
+                    UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, point)
+                        .verify(signing_input.as_bytes(), &b64(&jws["signature"]))
+                        .unwrap();
+                    let payload: Value = match jws["payload"].as_str().unwrap() {
+                        "" => Value::Null,
+                        _ => serde_json::from_slice(&b64(&jws["payload"])).unwrap(),
+                    };
+
+                    if tail.as_str() == "challenge/http" {
+                        let canonical = format!(r#"{{"crv":"P-256","kty":"EC","x":{},"y":{}}}"#, jwk["x"], jwk["y"]);
+                        let thumbprint = URL_SAFE_NO_PAD.encode(digest(&SHA256, canonical.as_bytes()));
+                        let url = format!("{}/.well-known/acme-challenge/{}", challenges, TOKEN);
+                        let response = Client::new().get(url.parse().unwrap()).await.unwrap();
+                        let answer = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                        assert_eq!(answer, format!("{}.{}", TOKEN, thumbprint));
+                        state.lock().unwrap().authorized = true;
+                    }
+
+                    let mut ca = state.lock().unwrap();
+                    let order = |ca: &Ca| {
+                        let status = match (&ca.csr, ca.authorized) {
+                            (Some(_), _) => "valid",
+                            (None, true) => "ready",
+                            (None, false) => "pending",
+                        };
+                        json!({
+                            "status": status,
+                            "authorizations": [format!("{}/authz/1", base)],
+                            "finalize": format!("{}/finalize/1", base),
+                            "certificate": format!("{}/certificate/1", base),
+                        })
+                    };
+                    let response = match tail.as_str() {
+                        "account" => {
+                            assert_eq!(payload["termsOfServiceAgreed"], true);
+                            assert_eq!(payload["contact"][0], "mailto:ops@example.com");
+                            ca.jwk = Some(jwk);
+                            reply(201, Some(format!("{}/account/1", base)), json!({ "status": "valid" }))
+                        }
+                        "order/new" => {
+                            assert_eq!(payload["identifiers"][0]["value"], DOMAIN);
+                            reply(201, Some(format!("{}/order/1", base)), order(&ca))
+                        }
+                        "order/1" => reply(200, None, order(&ca)),
+                        "authz/1" => reply(200, None, json!({
+                            "status": if ca.authorized { "valid" } else { "pending" },
+                            "identifier": { "type": "dns", "value": DOMAIN },
+                            "challenges": [
+                                { "type": "tls-alpn-01", "url": format!("{}/challenge/alpn", base), "token": TOKEN },
+                                { "type": "http-01", "url": format!("{}/challenge/http", base), "token": TOKEN },
+                            ],
+                        })),
+                        "challenge/http" => reply(200, None, json!({ "status": "valid" })),
+                        "finalize/1" => {
+                            ca.csr = Some(b64(&payload["csr"]));
+                            reply(200, None, order(&ca))
+                        }
+                        "certificate/1" => {
+                            let (cert, _) = self_signed(DOMAIN, Vec::new(), 90, &SystemRandom::new()).unwrap();
+                            let mut response = Response::new(Body::from(pem("CERTIFICATE", &cert)));
+                            response.headers_mut().insert("content-type", "application/pem-certificate-chain".parse().unwrap());
+                            response
+                        }
+                        other => panic!("unexpected POST to {}", other),
+                    };
+                    with_nonce(&mut ca, response)
+                }
+            }
+        });
+        let (addr, server) = warp::serve(directory.or(nonce).or(post)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        state.lock().unwrap().base = format!("http://{}", addr);
+        state
+    }
+
+    fn acme_config(name: &str, directory_url: String) -> AcmeConfig {
+        let cert_dir = std::env::temp_dir().join(format!("api-gateway-acme-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&cert_dir);
+        std::fs::create_dir_all(&cert_dir).unwrap();
+        AcmeConfig {
+            domains: vec![DOMAIN.to_string()],
+            cert_dir,
+            contact: vec!["ops@example.com".to_string()],
+            accept_terms: true,
+            directory_url,
+            challenge: AcmeChallenge::Http01,
+            http_listen_addr: "127.0.0.1:0".to_string(),
+            renew_before_days: 30,
+            check_interval_secs: 3600,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_certificate_is_ordered_over_http_01() {
+        let ca = ca().await;
+        let base = ca.lock().unwrap().base.clone();
+        let config = acme_config("http", format!("{}/directory", base));
+        let acme = Arc::new(Acme::new(&config, Arc::new(CertResolver::default())).unwrap());
+        let (challenges, server) = warp::serve(routes(Some(acme.clone()))).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        ca.lock().unwrap().challenges = format!("http://{}", challenges);
+        assert_eq!(acme.due(0), [DOMAIN]);
+
+        let expires = acme.issue(DOMAIN).await.unwrap();
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+        assert!(expires.abs_diff(now + 90 * 86_400) < 5);
+        assert!(acme.due(now).is_empty());
+        assert_eq!(acme.due(expires - 29 * 86_400), [DOMAIN]);
+        assert!(acme.http_challenge(TOKEN).is_none());
+
+        let csr = ca.lock().unwrap().csr.clone().unwrap();
+        assert!(csr.windows(DOMAIN.len()).any(|window| window == DOMAIN.as_bytes()));
+        let key_path = config.cert_dir.join(format!("{}.key", DOMAIN));
+        assert!(config.cert_dir.join(format!("{}.crt", DOMAIN)).is_file());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(std::fs::metadata(&key_path).unwrap().permissions().mode() & 0o777, 0o600);
+            assert_eq!(std::fs::metadata(config.cert_dir.join("account.key")).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+
+        // The stored certificate and account are picked up on restart.
+        let reopened = Acme::new(&config, Arc::new(CertResolver::default())).unwrap();
+        assert_eq!(reopened.expires_at(DOMAIN), Some(expires));
+        assert_eq!(reopened.jwk(), acme.jwk());
+        std::fs::remove_dir_all(&config.cert_dir).unwrap();
+    }
+
+    #[test]
+    fn test_challenge_certificate_and_dates() {
+        let key = challenge_key(DOMAIN, "token.thumbprint").unwrap();
+        let cert = key.cert[0].as_ref();
+        let proof = digest(&SHA256, b"token.thumbprint");
+        let extension = [OID_ACME_IDENTIFIER, &[0x01, 0x01, 0xff, 0x04, 0x22, 0x04, 0x20], proof.as_ref()].concat();
+        assert!(cert.windows(extension.len()).any(|window| window == extension.as_slice()));
+        assert!(cert.windows(DOMAIN.len()).any(|window| window == DOMAIN.as_bytes()));
+
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+        let expires = not_after(cert).unwrap();
+        assert!(expires.abs_diff(now + 7 * 86_400) < 5);
+        assert!(not_after(b"not a certificate").is_none());
+
+        assert_eq!(utc_time(1_709_210_096), [&[0x17, 13][..], b"240229123456Z"].concat());
+    }
+}
@@ -0,0 +1,684 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use hyper::{HeaderMap, Method, StatusCode, Uri, header::{HeaderName, HeaderValue, HOST}};
+use percent_encoding::percent_decode_str;
+use std::sync::atomic::Ordering;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use warp::{Filter, Rejection, Reply};
+use crate::analytics::Analytics;
+use crate::api_keys::{ApiKeyStore, StoredKey};
+use crate::audit::{actor, AuditLog};
+use crate::bulkheads::Bulkheads;
+use crate::cluster::RunningConfig;
+use crate::config::AdminConfig;
+use crate::dns::DnsCache;
+use crate::errors::GatewayError;
+use crate::handlers::handle_rejection;
+use crate::health::{Draining, FailureInjection};
+use crate::persistence::{Persistence, DEPLOYMENTS};
+use crate::routes::{LiveRouteTable, RouteAction, RouteRequest, RouteTable};
+use crate::services::{admin_token_index, bucket_limit, is_admin, purge_cache, CachePurge};
+use crate::tenants::Tenants;
+use crate::AppState;
+
+#[cfg(test)]
+mod tests;
+
+/// Pins a proxied request to one upstream instance, e.g. `http://10.0.0.7:8080`.
+pub const UPSTREAM_OVERRIDE_HEADER: &str = "x-gateway-upstream";
+/// Carries the admin token for `X-Gateway-Upstream`, so the proxied request
+/// keeps its own `Authorization`.
+pub const ADMIN_TOKEN_HEADER: &str = "x-gateway-admin-token";
+
+/// Body of `POST /admin/deployments/<route>`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeploymentSwitch {
+    pub active: String,
+    #[serde(default)]
+    pub ramp_secs: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteTestRequest {
+    #[serde(default = "default_method")]
+    pub method: String,
+    #[serde(default)]
+    pub host: Option<String>,
+    pub path: String,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Lets routes that match on the body, such as SOAP operations, be tried.
+    #[serde(default)]
+    pub body: Option<String>,
+}
+
+fn default_method() -> String {
+    "GET".to_string()
+}
+
+/// Body of `PUT /admin/failure-injection`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FailureInjectionSwitch {
+    pub enabled: bool,
+    #[serde(default)]
+    pub fraction: Option<f64>,
+}
+
+/// Query of `GET /admin/analytics`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AnalyticsQuery {
+    pub top: Option<usize>,
+}
+
+/// Body of `POST /admin/keys`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KeyRequest {
+    pub user: String,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+}
+
+/// All `/admin` endpoints. Requests under `/admin` never fall through to the
+/// proxy: failures are turned into responses here.
+#[allow(clippy::too_many_arguments)]
+pub fn routes(
+    config: Arc<AdminConfig>,
+    running_config: Arc<RunningConfig>,
+    route_table: Arc<LiveRouteTable>,
+    state: Arc<AppState>,
+    tenants: Arc<Tenants>,
+    bulkheads: Arc<Bulkheads>,
+    audit: Arc<AuditLog>,
+    key_store: Option<Arc<ApiKeyStore>>,
+    diagnostics: Arc<Value>,
+    analytics: Option<Arc<Analytics>>,
+    dns_cache: Option<Arc<DnsCache>>,
+    failure_injection: Arc<FailureInjection>,
+    draining: Arc<Draining>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let actor = warp::header::headers_cloned().map({
+        let config = config.clone();
+        move |headers: HeaderMap| actor(&headers, &config.tokens)
+    });
+    let audit = warp::any().map(move || audit.clone());
+    let show_config = warp::path!("config")
+        .and(warp::get())
+        .map(move || warp::reply::json(&running_config.view()));
+    let show_diagnostics = warp::path!("diagnostics")
+        .and(warp::get())
+        .map(move || warp::reply::json(&*diagnostics));
+    let show_analytics = warp::path!("analytics")
+        .and(warp::get())
+        .and(warp::query::<AnalyticsQuery>())
+        .and_then(move |query: AnalyticsQuery| {
+            let analytics = analytics.clone();
+            async move {
+                let analytics = analytics.ok_or_else(|| warp::reject::custom(GatewayError::NotFound))?;
+                Ok::<_, Rejection>(warp::reply::json(&analytics.report(query.top, SystemTime::now())))
+            }
+        });
+    let list_bulkheads = warp::path!("bulkheads")
+        .and(warp::get())
+        .map(move || warp::reply::json(&bulkheads.status()));
+    let list_deployments = warp::path!("deployments").and(warp::get()).map({
+        let route_table = route_table.clone();
+        move || warp::reply::json(&deployments(&route_table.load()))
+    });
+    let switch_deployment = warp::path!("deployments" / String)
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(actor.clone())
+        .and(audit.clone())
+        .and_then({
+            let route_table = route_table.clone();
+            let persistence = state.persistence.clone();
+            move |route: String, switch: DeploymentSwitch, actor: String, audit: Arc<AuditLog>| {
+                let route_table = route_table.load();
+                let persistence = persistence.clone();
+                async move {
+                    switch_deployment(&route_table, &route, &switch, &actor, &audit, persistence.as_deref())
+                        .map(|status| warp::reply::json(&status))
+                        .map_err(warp::reject::custom)
+                }
+            }
+        });
+
+    let route_test = warp::path!("routes" / "test")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and_then(move |request: RouteTestRequest| {
+            let route_table = route_table.load();
+            async move {
+                test_route(&route_table, &request)
+                    .map(|report| warp::reply::json(&report))
+                    .map_err(warp::reject::custom)
+            }
+        });
+
+    let key_store = warp::any().map(move || key_store.clone());
+    let list_keys = warp::path!("keys")
+        .and(warp::get())
+        .and(key_store.clone())
+        .and_then(|key_store: Option<Arc<ApiKeyStore>>| async move {
+            let key_store = key_store.ok_or_else(|| warp::reject::custom(GatewayError::NotFound))?;
+            let keys: Vec<Value> = key_store.list().iter().map(StoredKey::summary).collect();
+            Ok::<_, Rejection>(warp::reply::json(&json!({ "keys": keys })))
+        });
+    let create_key = warp::path!("keys")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(key_store.clone())
+        .and(actor.clone())
+        .and(audit.clone())
+        .and_then(|request: KeyRequest, key_store: Option<Arc<ApiKeyStore>>, actor: String, audit: Arc<AuditLog>| async move {
+            create_key(key_store.as_deref(), &request, &actor, &audit)
+                .map(|created| warp::reply::with_status(warp::reply::json(&created), StatusCode::CREATED))
+                .map_err(warp::reject::custom)
+        });
+    let revoke_key = warp::path!("keys" / String)
+        .and(warp::delete())
+        .and(key_store.clone())
+        .and(actor.clone())
+        .and(audit.clone())
+        .and_then(|id: String, key_store: Option<Arc<ApiKeyStore>>, actor: String, audit: Arc<AuditLog>| async move {
+            revoke_key(key_store.as_deref(), &id, &actor, &audit)
+                .map(|()| StatusCode::NO_CONTENT)
+                .map_err(warp::reject::custom)
+        });
+
+    let list_lockouts = warp::path!("lockouts")
+        .and(warp::get())
+        .and(key_store.clone())
+        .and_then(|key_store: Option<Arc<ApiKeyStore>>| async move {
+            let lockouts = key_store
+                .as_deref()
+                .and_then(ApiKeyStore::lockouts)
+                .ok_or_else(|| warp::reject::custom(GatewayError::NotFound))?;
+            Ok::<_, Rejection>(warp::reply::json(&json!({ "lockouts": lockouts.list() })))
+        });
+    let unlock = warp::path!("lockouts" / String)
+        .and(warp::delete())
+        .and(key_store)
+        .and(actor.clone())
+        .and(audit.clone())
+        .and_then(|id: String, key_store: Option<Arc<ApiKeyStore>>, actor: String, audit: Arc<AuditLog>| async move {
+            unlock(key_store.as_deref(), &id, &actor, &audit)
+                .map(|()| StatusCode::NO_CONTENT)
+                .map_err(warp::reject::custom)
+        });
+
+    let flush_dns_cache = warp::path!("dns-cache")
+        .and(warp::delete())
+        .and(actor.clone())
+        .and(audit.clone())
+        .and_then(move |actor: String, audit: Arc<AuditLog>| {
+            let dns_cache = dns_cache.clone();
+            async move {
+                let dns_cache = dns_cache.ok_or_else(|| warp::reject::custom(GatewayError::NotFound))?;
+                let flushed = dns_cache.flush();
+                audit.record(&actor, "dns_cache.flush", "*", json!({ "entries": flushed }), json!({ "entries": 0 }));
+                Ok::<_, Rejection>(warp::reply::json(&json!({ "flushed": flushed })))
+            }
+        });
+
+    let show_failure_injection = warp::path!("failure-injection").and(warp::get()).map({
+        let failure_injection = failure_injection.clone();
+        move || warp::reply::json(&failure_injection.status())
+    });
+    let switch_failure_injection = warp::path!("failure-injection")
+        .and(warp::put())
+        .and(warp::body::json())
+        .and(actor.clone())
+        .and(audit.clone())
+        .and_then(move |switch: FailureInjectionSwitch, actor: String, audit: Arc<AuditLog>| {
+            let failure_injection = failure_injection.clone();
+            async move {
+                let before = failure_injection.status();
+                let after = failure_injection.set(switch.enabled, switch.fraction).map_err(warp::reject::custom)?;
+                println!("Failure injection {} by {}", if switch.enabled { "enabled" } else { "disabled" }, actor);
+                audit.record(&actor, "failure_injection.switch", "*", before, after.clone());
+                Ok::<_, Rejection>(warp::reply::json(&after))
+            }
+        });
+
+    let list_drains = warp::path!("drains").and(warp::get()).map({
+        let draining = draining.clone();
+        move || warp::reply::json(&draining.status())
+    });
+    let show_drain = warp::path!("drains" / String).and(warp::get()).and_then({
+        let draining = draining.clone();
+        move |upstream: String| {
+            let status = draining.status_of(&percent_decode_str(&upstream).decode_utf8_lossy());
+            async move {
+                let status = status.ok_or_else(|| warp::reject::custom(GatewayError::NotFound))?;
+                Ok::<_, Rejection>(warp::reply::json(&status))
+            }
+        }
+    });
+    let start_drain = warp::path!("drains" / String)
+        .and(warp::put())
+        .and(actor.clone())
+        .and(audit.clone())
+        .map({
+            let draining = draining.clone();
+            move |upstream: String, actor: String, audit: Arc<AuditLog>| {
+                let upstream = percent_decode_str(&upstream).decode_utf8_lossy().into_owned();
+                let status = draining.start(&upstream);
+                println!("Upstream {} draining, started by {}", upstream, actor);
+                audit.record(&actor, "upstream.drain", &upstream, Value::Null, status.clone());
+                warp::reply::json(&status)
+            }
+        });
+    let stop_drain = warp::path!("drains" / String)
+        .and(warp::delete())
+        .and(actor.clone())
+        .and(audit.clone())
+        .and_then(move |upstream: String, actor: String, audit: Arc<AuditLog>| {
+            let upstream = percent_decode_str(&upstream).decode_utf8_lossy().into_owned();
+            let stopped = draining.stop(&upstream);
+            async move {
+                if !stopped {
+                    return Err(warp::reject::custom(GatewayError::NotFound));
+                }
+                println!("Upstream {} back in rotation, by {}", upstream, actor);
+                audit.record(&actor, "upstream.undrain", &upstream, json!({ "draining": true }), Value::Null);
+                Ok::<_, Rejection>(StatusCode::NO_CONTENT)
+            }
+        });
+
+    let state = warp::any().map(move || state.clone());
+    let list_rate_limits = warp::path!("ratelimits")
+        .and(warp::get())
+        .and(state.clone())
+        .then(move |state: Arc<AppState>| {
+            let tenants = tenants.clone();
+            async move { warp::reply::json(&rate_limits(&state, &tenants).await) }
+        });
+    let reset_rate_limits = warp::path!("ratelimits")
+        .and(warp::delete())
+        .and(state.clone())
+        .and(actor.clone())
+        .and(audit.clone())
+        .then(|state: Arc<AppState>, actor: String, audit: Arc<AuditLog>| async move {
+            let removed = state.rate_limits.len();
+            state.rate_limits.clear();
+            state.penalties.clear();
+            audit.record(&actor, "ratelimits.reset", "*", json!({ "buckets": removed }), json!({ "buckets": 0 }));
+            warp::reply::json(&json!({ "removed": removed }))
+        });
+    let purge = warp::path!("cache" / "purge")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(state.clone())
+        .and(actor.clone())
+        .and(audit.clone())
+        .and_then(|purge: CachePurge, state: Arc<AppState>, actor: String, audit: Arc<AuditLog>| async move {
+            let result = purge_cache(&state, &purge).await;
+            let removed = match &result {
+                Ok(removed) => json!({ "removed": removed }),
+                Err(message) => json!({ "error": message }),
+            };
+            audit.record(&actor, "cache.purge", "*", json!(purge), removed.clone());
+            result
+                .map(|_| warp::reply::json(&removed))
+                .map_err(|message| warp::reject::custom(GatewayError::BadGateway(message)))
+        });
+    let delete_rate_limit = warp::path!("ratelimits" / String)
+        .and(warp::delete())
+        .and(state)
+        .and(actor)
+        .and(audit)
+        .and_then(|key: String, state: Arc<AppState>, actor: String, audit: Arc<AuditLog>| async move {
+            let key = percent_decode_str(&key).decode_utf8_lossy().into_owned();
+            match state.rate_limits.remove(&key) {
+                Some((_, bucket)) => {
+                    audit.record(&actor, "ratelimits.delete", &key, json!({ "count": bucket.count }), Value::Null);
+                    Ok(StatusCode::NO_CONTENT)
+                }
+                None => Err(warp::reject::custom(GatewayError::NotFound)),
+            }
+        });
+
+    // Boxed one at a time: chained with `or` alone, the filter's type grows
+    // with every endpoint until rustc takes hours to check it.
+    let endpoints = [
+        route_test.map(Reply::into_response).boxed(),
+        list_rate_limits.map(Reply::into_response).boxed(),
+        reset_rate_limits.map(Reply::into_response).boxed(),
+        delete_rate_limit.map(Reply::into_response).boxed(),
+        purge.map(Reply::into_response).boxed(),
+        list_deployments.map(Reply::into_response).boxed(),
+        switch_deployment.map(Reply::into_response).boxed(),
+        list_bulkheads.map(Reply::into_response).boxed(),
+        show_config.map(Reply::into_response).boxed(),
+        show_diagnostics.map(Reply::into_response).boxed(),
+        show_analytics.map(Reply::into_response).boxed(),
+        list_keys.map(Reply::into_response).boxed(),
+        create_key.map(Reply::into_response).boxed(),
+        revoke_key.map(Reply::into_response).boxed(),
+        list_lockouts.map(Reply::into_response).boxed(),
+        unlock.map(Reply::into_response).boxed(),
+        flush_dns_cache.map(Reply::into_response).boxed(),
+        show_failure_injection.map(Reply::into_response).boxed(),
+        switch_failure_injection.map(Reply::into_response).boxed(),
+        list_drains.map(Reply::into_response).boxed(),
+        show_drain.map(Reply::into_response).boxed(),
+        start_drain.map(Reply::into_response).boxed(),
+        stop_drain.map(Reply::into_response).boxed(),
+    ];
+    let endpoints = endpoints.into_iter().reduce(|routes, endpoint| routes.or(endpoint).unify().boxed()).expect("there are admin endpoints");
+    warp::path("admin").and(authorize(config).and(endpoints).recover(handle_rejection))
+}
+
+/// The live group of every route with a deployment.
+pub fn deployments(route_table: &RouteTable) -> Value {
+    let deployments: serde_json::Map<_, _> = route_table
+        .routes()
+        .iter()
+        .filter_map(|route| Some((route.name.clone(), route.deployment.as_ref()?.status())))
+        .collect();
+    json!({ "deployments": deployments })
+}
+
+/// Switches `route` to another deployment group, returning its new status.
+/// With a database, the switch outlives a restart.
+pub fn switch_deployment(
+    route_table: &RouteTable,
+    route: &str,
+    switch: &DeploymentSwitch,
+    actor: &str,
+    audit: &AuditLog,
+    persistence: Option<&Persistence>,
+) -> Result<Value, GatewayError> {
+    let deployment = route_table
+        .routes()
+        .iter()
+        .find(|r| r.name == route)
+        .and_then(|r| r.deployment.as_ref())
+        .ok_or(GatewayError::NotFound)?;
+    let before = deployment.status();
+    deployment.switch(&switch.active, Duration::from_secs(switch.ramp_secs))?;
+    println!("Deployment {} switched to {} (ramp {}s)", route, switch.active, switch.ramp_secs);
+    let after = deployment.status();
+    if let Some(persistence) = persistence {
+        persistence.save(DEPLOYMENTS, route, &json!({ "active": switch.active }));
+    }
+    audit.record(actor, "deployment.switch", route, before, after.clone());
+    Ok(after)
+}
+
+/// Issues an API key. The response carries the key itself, which is never
+/// shown again.
+pub fn create_key(
+    key_store: Option<&ApiKeyStore>,
+    request: &KeyRequest,
+    actor: &str,
+    audit: &AuditLog,
+) -> Result<Value, GatewayError> {
+    let key_store = key_store.ok_or(GatewayError::NotFound)?;
+    if request.user.trim().is_empty() {
+        return Err(GatewayError::BadRequest("user must not be empty".to_string()));
+    }
+    let (stored, key) = key_store.create(&request.user, request.scopes.clone()).map_err(GatewayError::Http)?;
+    let mut created = stored.summary();
+    audit.record(actor, "keys.create", &stored.id, Value::Null, created.clone());
+    created["key"] = json!(key);
+    Ok(created)
+}
+
+pub fn revoke_key(key_store: Option<&ApiKeyStore>, id: &str, actor: &str, audit: &AuditLog) -> Result<(), GatewayError> {
+    let key_store = key_store.ok_or(GatewayError::NotFound)?;
+    let stored = key_store.list().into_iter().find(|key| key.id == id).ok_or(GatewayError::NotFound)?;
+    if !key_store.revoke(id).map_err(GatewayError::Http)? {
+        return Err(GatewayError::NotFound);
+    }
+    audit.record(actor, "keys.revoke", id, stored.summary(), Value::Null);
+    Ok(())
+}
+
+/// Clears the failed logins and any lockout of key `id`.
+pub fn unlock(key_store: Option<&ApiKeyStore>, id: &str, actor: &str, audit: &AuditLog) -> Result<(), GatewayError> {
+    let lockouts = key_store.and_then(ApiKeyStore::lockouts).ok_or(GatewayError::NotFound)?;
+    let before = lockouts.list().into_iter().find(|entry| entry["identity"] == id).ok_or(GatewayError::NotFound)?;
+    lockouts.unlock(id);
+    audit.record(actor, "lockouts.unlock", id, before, Value::Null);
+    Ok(())
+}
+
+/// Every rate-limit bucket with its limit and what is left of its window,
+/// plus how many buckets have been evicted to bound memory.
+pub async fn rate_limits(state: &AppState, tenants: &Tenants) -> Value {
+    let now = SystemTime::now();
+    let mut buckets: Vec<_> = state
+        .rate_limits
+        .iter()
+        .map(|bucket| {
+            let key = bucket.key();
+            let limit = bucket_limit(key, tenants);
+            let reset_at = bucket.window_start + Duration::from_secs(limit.window_secs);
+            let count = if reset_at > now { bucket.count } else { 0 };
+            json!({
+                "key": key,
+                "count": count,
+                "limit": limit.requests,
+                "remaining": limit.requests.saturating_sub(count),
+                "reset_at": reset_at.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+            })
+        })
+        .collect();
+    buckets.sort_by(|a, b| a["key"].as_str().cmp(&b["key"].as_str()));
+    json!({
+        "buckets": buckets,
+        "max_buckets": state.max_rate_limit_buckets,
+        "tarpitted_clients": state.penalties.len(),
+        "evictions": {
+            "capacity": state.rate_limit_evictions.capacity.load(Ordering::Relaxed),
+            "idle": state.rate_limit_evictions.idle.load(Ordering::Relaxed),
+        },
+    })
+}
+
+fn authorize(config: Arc<AdminConfig>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::headers_cloned()
+        .and_then(move |headers: HeaderMap| {
+            let config = config.clone();
+            async move {
+                if !config.enabled() {
+                    return Err(warp::reject::not_found());
+                }
+                if !is_admin(&headers, &config.tokens) {
+                    return Err(warp::reject::custom(GatewayError::Unauthorized));
+                }
+                Ok(())
+            }
+        })
+        .untuple_one()
+}
+
+/// Reports how the proxy would handle a request without forwarding it.
+pub fn test_route(route_table: &RouteTable, request: &RouteTestRequest) -> Result<Value, GatewayError> {
+    let (path, query) = request.path.split_once('?').unwrap_or((&request.path, ""));
+    let method = Method::from_bytes(request.method.as_bytes())
+        .map_err(|_| GatewayError::BadRequest(format!("invalid method {}", request.method)))?;
+
+    let mut headers = HeaderMap::new();
+    for (name, value) in &request.headers {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| GatewayError::BadRequest(format!("invalid header name {}", name)))?;
+        let value = HeaderValue::from_str(value)
+            .map_err(|_| GatewayError::BadRequest(format!("invalid value for header {}", name)))?;
+        headers.append(name, value);
+    }
+    if let Some(host) = &request.host {
+        let value = HeaderValue::from_str(host)
+            .map_err(|_| GatewayError::BadRequest(format!("invalid host {}", host)))?;
+        headers.insert(HOST, value);
+    }
+
+    let body = request.body.as_deref().map(str::as_bytes);
+    let route_match = match route_table.find(&RouteRequest { method: &method, path, query, headers: &headers, body }) {
+        Ok(route_match) => route_match,
+        Err(GatewayError::MethodNotAllowed(allowed)) => {
+            return Ok(json!({
+                "method": request.method,
+                "path": path,
+                "matched": false,
+                "reason": "method_not_allowed",
+                "allow": allowed,
+            }));
+        }
+        Err(_) => {
+            return Ok(json!({
+                "method": request.method,
+                "path": path,
+                "matched": false,
+                "reason": "no_route",
+            }));
+        }
+    };
+
+    let route = route_match.route;
+    let mut report = json!({
+        "method": request.method,
+        "path": path,
+        "matched": true,
+        "route": {
+            "name": route.name,
+            "path_prefix": route.path_prefix,
+            "bulkhead": route.bulkhead,
+            "priority": format!("{:?}", route.priority).to_lowercase(),
+        },
+        "params": route_match.params,
+    });
+
+    let route_request = RouteRequest { method: &method, path, query, headers: &headers, body };
+    let details = match &route.action {
+        RouteAction::Redirect { .. } => {
+            let (status, location) = route_match.redirect(&route_request).unwrap_or_default();
+            json!({
+                "redirect": {
+                    "status": status.as_u16(),
+                    "location": location,
+                },
+            })
+        }
+        RouteAction::Respond { status, .. } => json!({
+            "respond": {
+                "status": status.as_u16(),
+            },
+        }),
+        RouteAction::Grpc(grpc) => json!({
+            "grpc": {
+                "upstream": grpc.upstream(),
+                "method": grpc.method(),
+            },
+        }),
+        RouteAction::StaticFiles(files) => json!({
+            "static_files": {
+                "root": files.root,
+                "file": route_match.remaining_path(path),
+            },
+        }),
+        RouteAction::Proxy { upstream } => {
+            let upstream_path = route_match.upstream_path(path);
+            let mut rewrites = Vec::new();
+            if let Some(rewrite) = &route.rewrite {
+                rewrites.push(json!({
+                    "type": "rewrite",
+                    "template": rewrite,
+                    "before": path,
+                    "after": upstream_path,
+                }));
+            } else if let Some(prefix) = &route.strip_prefix {
+                if upstream_path != path {
+                    rewrites.push(json!({
+                        "type": "strip_prefix",
+                        "prefix": prefix,
+                        "before": path,
+                        "after": upstream_path,
+                    }));
+                }
+            }
+            let request_headers: serde_json::Map<String, Value> = route_match
+                .request_headers()
+                .into_iter()
+                .map(|(name, value)| (name, Value::String(value)))
+                .collect();
+
+            json!({
+                "rewrites": rewrites,
+                "request_headers": request_headers,
+                "upstream": upstream,
+                "upstream_uri": route_match.upstream_uri(path, query),
+            })
+        }
+    };
+
+    if let (Some(report), Value::Object(details)) = (report.as_object_mut(), details) {
+        report.extend(details);
+    }
+    Ok(report)
+}
+
+/// Whether the request carries an admin token in `X-Gateway-Admin-Token`.
+pub fn holds_admin_token(headers: &HeaderMap, config: &AdminConfig) -> bool {
+    headers
+        .get(ADMIN_TOKEN_HEADER)
+        .and_then(|token| token.to_str().ok())
+        .is_some_and(|token| admin_token_index(&config.tokens, token).is_some())
+}
+
+/// Reads and removes the upstream override headers. Returns the upstream base
+/// to use if an override was requested, the caller holds an admin token and
+/// the host is in `upstream_override_hosts`; the headers are ignored entirely
+/// unless `upstream_override` is enabled.
+pub fn take_upstream_override(
+    headers: &mut HeaderMap,
+    config: &AdminConfig,
+) -> Result<Option<String>, GatewayError> {
+    let requested = headers.remove(UPSTREAM_OVERRIDE_HEADER);
+    let token = headers.remove(ADMIN_TOKEN_HEADER);
+    let Some(requested) = requested else {
+        return Ok(None);
+    };
+    if !config.upstream_override || !config.enabled() {
+        return Ok(None);
+    }
+
+    let token = token.as_ref().and_then(|t| t.to_str().ok()).unwrap_or("");
+    if admin_token_index(&config.tokens, token).is_none() {
+        return Err(GatewayError::Unauthorized);
+    }
+
+    let requested = requested
+        .to_str()
+        .map_err(|_| GatewayError::BadRequest("invalid X-Gateway-Upstream header".to_string()))?;
+    let uri: Uri = requested
+        .parse()
+        .map_err(|_| GatewayError::BadRequest(format!("invalid X-Gateway-Upstream \"{}\"", requested)))?;
+    let (scheme, authority) = match (uri.scheme_str(), uri.authority()) {
+        (Some(scheme @ ("http" | "https")), Some(authority)) if matches!(uri.path(), "" | "/") && uri.query().is_none() => {
+            (scheme, authority)
+        }
+        _ => {
+            return Err(GatewayError::BadRequest(format!(
+                "X-Gateway-Upstream \"{}\" must be an http(s) scheme and authority only",
+                requested
+            )))
+        }
+    };
+    if !config.upstream_override_hosts.iter().any(|host| host.eq_ignore_ascii_case(authority.host())) {
+        return Err(GatewayError::Forbidden(format!(
+            "X-Gateway-Upstream host \"{}\" is not in admin.upstream_override_hosts",
+            authority.host()
+        )));
+    }
+    Ok(Some(format!("{}://{}", scheme, authority)))
+}
@@ -0,0 +1,560 @@
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+    use serde_json::Value;
+    use warp::http::StatusCode;
+    use hyper::{HeaderMap, Method};
+    use crate::admin::{routes, take_upstream_override};
+    use crate::analytics::Analytics;
+    use crate::api_keys::ApiKeyStore;
+    use crate::audit::AuditLog;
+    use crate::redaction::Redactor;
+    use crate::bulkheads::Bulkheads;
+    use crate::cluster::RunningConfig;
+    use crate::errors::GatewayError;
+    use crate::config::{AdminConfig, AnalyticsConfig, ApiKeyStoreConfig, DnsCacheConfig, FailureInjectionConfig, GatewayConfig, LockoutConfig};
+    use crate::dns::DnsCache;
+    use crate::health::{Draining, FailureInjection};
+    use crate::routes::{LiveRouteTable, RouteTable};
+    use crate::services::{cache_response, check_rate_limit};
+    use crate::tenants::Tenants;
+    use crate::{AppState, CachedResponse};
+    use bytes::Bytes;
+
+    fn admin_config() -> Arc<AdminConfig> {
+        Arc::new(AdminConfig {
+            tokens: vec!["admin-token".to_string()],
+            ..AdminConfig::default()
+        })
+    }
+
+    fn running_config() -> Arc<RunningConfig> {
+        Arc::new(RunningConfig::new(GatewayConfig::default(), None))
+    }
+
+    fn route_table() -> Arc<LiveRouteTable> {
+        let config = GatewayConfig::from_json(r#"{
+            "routes": [
+                { "name": "users", "path_prefix": "/api/users", "upstream": "http://users:8080", "strip_prefix": "/api" },
+                { "name": "reports", "path_prefix": "/reports", "upstream": "http://reports:8080" },
+                { "name": "gone", "path_prefix": "/v1", "respond": { "status": 410, "body": "gone" } },
+                { "name": "reports-v2", "path_prefix": "/reports", "upstream": "http://reports-v2:8080", "match_headers": { "X-API-Version": "2" } }
+            ]
+        }"#).unwrap();
+        Arc::new(LiveRouteTable::new(RouteTable::from_config(&config).unwrap()))
+    }
+
+    fn state() -> Arc<AppState> {
+        Arc::new(AppState::new())
+    }
+
+    fn tenants() -> Arc<Tenants> {
+        Arc::new(Tenants::default())
+    }
+
+    fn bulkheads() -> Arc<Bulkheads> {
+        Arc::new(Bulkheads::default())
+    }
+
+    fn failure_injection() -> Arc<FailureInjection> {
+        Arc::new(FailureInjection::new(&FailureInjectionConfig::default()))
+    }
+
+    fn draining() -> Arc<Draining> {
+        Arc::new(Draining::default())
+    }
+
+    fn audit() -> Arc<AuditLog> {
+        Arc::new(AuditLog::stdout())
+    }
+
+    #[tokio::test]
+    async fn test_route_test_reports_match() {
+        let filter = routes(admin_config(), running_config(), route_table(), state(), tenants(), bulkheads(), audit(), None, Arc::default(), None, None, failure_injection(), draining());
+        let response = warp::test::request()
+            .method("POST")
+            .path("/admin/routes/test")
+            .header("Authorization", "Bearer admin-token")
+            .json(&serde_json::json!({ "path": "/api/users/42?expand=1" }))
+            .reply(&filter)
+            .await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["matched"], true);
+        assert_eq!(body["route"]["name"], "users");
+        assert_eq!(body["rewrites"][0]["after"], "/users/42");
+        assert_eq!(body["upstream_uri"], "http://users:8080/users/42?expand=1");
+    }
+
+    #[tokio::test]
+    async fn test_route_test_reports_no_match() {
+        let filter = routes(admin_config(), running_config(), route_table(), state(), tenants(), bulkheads(), audit(), None, Arc::default(), None, None, failure_injection(), draining());
+        let response = warp::test::request()
+            .method("POST")
+            .path("/admin/routes/test")
+            .header("Authorization", "Bearer admin-token")
+            .json(&serde_json::json!({ "method": "DELETE", "path": "/unknown" }))
+            .reply(&filter)
+            .await;
+
+        let body: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["matched"], false);
+        assert_eq!(body["reason"], "no_route");
+    }
+
+    #[tokio::test]
+    async fn test_admin_requires_token() {
+        let filter = routes(admin_config(), running_config(), route_table(), state(), tenants(), bulkheads(), audit(), None, Arc::default(), None, None, failure_injection(), draining());
+        let response = warp::test::request()
+            .method("POST")
+            .path("/admin/routes/test")
+            .header("Authorization", "Bearer example-token")
+            .json(&serde_json::json!({ "path": "/api/users" }))
+            .reply(&filter)
+            .await;
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_admin_disabled_without_tokens() {
+        let filter = routes(Arc::new(AdminConfig::default()), running_config(), route_table(), state(), tenants(), bulkheads(), audit(), None, Arc::default(), None, None, failure_injection(), draining());
+        let response = warp::test::request()
+            .method("POST")
+            .path("/admin/routes/test")
+            .json(&serde_json::json!({ "path": "/api/users" }))
+            .reply(&filter)
+            .await;
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_route_test_uses_headers() {
+        let filter = routes(admin_config(), running_config(), route_table(), state(), tenants(), bulkheads(), audit(), None, Arc::default(), None, None, failure_injection(), draining());
+        let response = warp::test::request()
+            .method("POST")
+            .path("/admin/routes/test")
+            .header("Authorization", "Bearer admin-token")
+            .json(&serde_json::json!({ "path": "/reports/daily", "headers": { "X-API-Version": "2" } }))
+            .reply(&filter)
+            .await;
+
+        let body: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["route"]["name"], "reports-v2");
+        assert_eq!(body["upstream"], "http://reports-v2:8080");
+    }
+
+    #[tokio::test]
+    async fn test_route_test_reports_direct_response() {
+        let filter = routes(admin_config(), running_config(), route_table(), state(), tenants(), bulkheads(), audit(), None, Arc::default(), None, None, failure_injection(), draining());
+        let response = warp::test::request()
+            .method("POST")
+            .path("/admin/routes/test")
+            .header("Authorization", "Bearer admin-token")
+            .json(&serde_json::json!({ "path": "/v1/anything" }))
+            .reply(&filter)
+            .await;
+
+        let body: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["route"]["name"], "gone");
+        assert_eq!(body["respond"]["status"], 410);
+        assert!(body.get("upstream").is_none());
+    }
+
+    fn override_headers(upstream: &str, token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-gateway-upstream", upstream.parse().unwrap());
+        headers.insert("x-gateway-admin-token", token.parse().unwrap());
+        headers.insert("authorization", "Bearer user-token".parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_upstream_override_requires_opt_in_and_token() {
+        let mut config = AdminConfig {
+            upstream_override: true,
+            upstream_override_hosts: vec!["10.0.0.7".to_string()],
+            ..(*admin_config()).clone()
+        };
+
+        let mut headers = override_headers("http://10.0.0.7:8080", "admin-token");
+        let upstream = take_upstream_override(&mut headers, &config).unwrap();
+        assert_eq!(upstream.as_deref(), Some("http://10.0.0.7:8080"));
+        assert_eq!(headers.len(), 1, "override headers must not reach the upstream");
+
+        let mut headers = override_headers("http://10.0.0.7:8080", "wrong");
+        let result = take_upstream_override(&mut headers, &config);
+        assert!(matches!(result, Err(GatewayError::Unauthorized)));
+
+        let mut headers = override_headers("http://10.0.0.8:8080", "admin-token");
+        let result = take_upstream_override(&mut headers, &config);
+        assert!(matches!(result, Err(GatewayError::Forbidden(_))), "hosts outside the allowlist are refused");
+
+        config.upstream_override = false;
+        let mut headers = override_headers("http://10.0.0.7:8080", "admin-token");
+        assert_eq!(take_upstream_override(&mut headers, &config).unwrap(), None);
+        assert_eq!(headers.len(), 1);
+    }
+
+    #[test]
+    fn test_upstream_override_rejects_paths() {
+        let config = AdminConfig {
+            upstream_override: true,
+            upstream_override_hosts: vec!["10.0.0.7".to_string()],
+            ..(*admin_config()).clone()
+        };
+
+        for upstream in ["10.0.0.7:8080", "ftp://10.0.0.7", "http://10.0.0.7/internal"] {
+            let mut headers = override_headers(upstream, "admin-token");
+            let result = take_upstream_override(&mut headers, &config);
+            assert!(matches!(result, Err(GatewayError::BadRequest(_))), "{}", upstream);
+        }
+    }
+
+    async fn limited_state() -> Arc<AppState> {
+        let state = state();
+        for ip in ["10.0.0.1", "10.0.0.2"] {
+            let mut headers = HeaderMap::new();
+            headers.insert("x-forwarded-for", ip.parse().unwrap());
+            check_rate_limit(&state, &headers).await;
+        }
+        state
+    }
+
+    #[tokio::test]
+    async fn test_list_rate_limits() {
+        let filter = routes(admin_config(), running_config(), route_table(), limited_state().await, tenants(), bulkheads(), audit(), None, Arc::default(), None, None, failure_injection(), draining());
+        let response = warp::test::request()
+            .path("/admin/ratelimits")
+            .header("Authorization", "Bearer admin-token")
+            .reply(&filter)
+            .await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: Value = serde_json::from_slice(response.body()).unwrap();
+        let buckets = body["buckets"].as_array().unwrap();
+        assert_eq!(buckets.len(), 2);
+        assert_eq!(buckets[0]["key"], "ip:10.0.0.1");
+        assert_eq!(buckets[0]["count"], 1);
+        assert_eq!(buckets[0]["remaining"], 99);
+        assert!(buckets[0]["reset_at"].as_u64().unwrap() > 0);
+    }
+
+    #[tokio::test]
+    async fn test_delete_and_reset_rate_limits() {
+        let state = limited_state().await;
+        let filter = routes(admin_config(), running_config(), route_table(), state.clone(), tenants(), bulkheads(), audit(), None, Arc::default(), None, None, failure_injection(), draining());
+        let delete = |path: &'static str| {
+            warp::test::request()
+                .method("DELETE")
+                .path(path)
+                .header("Authorization", "Bearer admin-token")
+        };
+
+        let response = delete("/admin/ratelimits/ip:10.0.0.1").reply(&filter).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(!state.rate_limits.contains_key("ip:10.0.0.1"));
+
+        let response = delete("/admin/ratelimits/ip:10.0.0.1").reply(&filter).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = delete("/admin/ratelimits").reply(&filter).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["removed"], 1);
+        assert!(state.rate_limits.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_purge_cache() {
+        let state = state();
+        let response = || Arc::new(CachedResponse::new(StatusCode::OK, HeaderMap::new(), Bytes::from("x")));
+        for key in ["users:GET/api/users/1", "users:GET/api/users/2", "reports:GET/reports"] {
+            cache_response(&state, key, response()).await;
+        }
+        let filter = routes(admin_config(), running_config(), route_table(), state.clone(), tenants(), bulkheads(), audit(), None, Arc::default(), None, None, failure_injection(), draining());
+        let purge = |body: &'static str| {
+            warp::test::request()
+                .method("POST")
+                .path("/admin/cache/purge")
+                .header("Authorization", "Bearer admin-token")
+                .body(body)
+        };
+
+        let response = purge(r#"{ "route": "users", "path_prefix": "/api/users/1" }"#).reply(&filter).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["removed"], 1);
+        assert_eq!(state.cache.len(), 2);
+
+        let response = purge("{}").reply(&filter).await;
+        let body: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["removed"], 2);
+        assert!(state.cache.is_empty());
+        assert_eq!(purge(r#"{ "paths": [] }"#).reply(&filter).await.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_switch_deployment() {
+        let config = GatewayConfig::from_json(r#"{
+            "routes": [
+                { "name": "orders", "path_prefix": "/orders", "deployment": {
+                    "groups": { "blue": "http://orders-blue:8080", "green": "http://orders-green:8080" },
+                    "active": "blue"
+                } },
+                { "name": "users", "path_prefix": "/users", "upstream": "http://users:8080" }
+            ]
+        }"#).unwrap();
+        let route_table = Arc::new(LiveRouteTable::new(RouteTable::from_config(&config).unwrap()));
+        let filter = routes(admin_config(), running_config(), route_table.clone(), state(), tenants(), bulkheads(), audit(), None, Arc::default(), None, None, failure_injection(), draining());
+        let switch = |path: &'static str, body: &'static str| {
+            warp::test::request()
+                .method("POST")
+                .path(path)
+                .header("Authorization", "Bearer admin-token")
+                .body(body)
+        };
+
+        let response = switch("/admin/deployments/orders", r#"{ "active": "green" }"#).reply(&filter).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["active"], "green");
+        assert_eq!(route_table.load().routes()[0].deployment.as_ref().unwrap().upstream(), "http://orders-green:8080");
+
+        let response = switch("/admin/deployments/orders", r#"{ "active": "red" }"#).reply(&filter).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = switch("/admin/deployments/users", r#"{ "active": "green" }"#).reply(&filter).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = warp::test::request()
+            .path("/admin/deployments")
+            .header("Authorization", "Bearer admin-token")
+            .reply(&filter)
+            .await;
+        let body: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["deployments"]["orders"]["active"], "green");
+        assert!(body["deployments"].get("users").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_show_running_config() {
+        let filter = routes(admin_config(), running_config(), route_table(), state(), tenants(), bulkheads(), audit(), None, Arc::default(), None, None, failure_injection(), draining());
+        let response = warp::test::request()
+            .path("/admin/config")
+            .header("Authorization", "Bearer admin-token")
+            .reply(&filter)
+            .await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["config"]["listen_addr"], "127.0.0.1:3030");
+        assert_eq!(body["sources"]["listen_addr"], "default");
+        assert_eq!(body["version"], Value::Null);
+    }
+
+    #[tokio::test]
+    async fn test_mutations_are_audited() {
+        let path = std::env::temp_dir().join(format!("api-gateway-admin-audit-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let audit = Arc::new(AuditLog::open(Some(&path), Redactor::default()).unwrap());
+        let filter = routes(admin_config(), running_config(), route_table(), limited_state().await, tenants(), bulkheads(), audit, None, Arc::default(), None, None, failure_injection(), draining());
+
+        let response = warp::test::request()
+            .method("DELETE")
+            .path("/admin/ratelimits/ip:10.0.0.1")
+            .header("Authorization", "Bearer admin-token")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let entry: Value = serde_json::from_str(std::fs::read_to_string(&path).unwrap().trim()).unwrap();
+        assert_eq!(entry["action"], "ratelimits.delete");
+        assert_eq!(entry["target"], "ip:10.0.0.1");
+        assert_eq!(entry["actor"], "admin.tokens[0]");
+        assert_eq!(entry["before"]["count"], 1);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_issue_list_and_revoke_keys() {
+        let path = std::env::temp_dir().join(format!("api-gateway-admin-keys-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let store_config = ApiKeyStoreConfig { path: path.clone(), kms: None, argon2_memory_kib: 8, argon2_iterations: 1, lockout: None };
+        let key_store = Arc::new(ApiKeyStore::open(&store_config).await.unwrap());
+        let filter = routes(admin_config(), running_config(), route_table(), state(), tenants(), bulkheads(), audit(), Some(key_store.clone()), Arc::default(), None, None, failure_injection(), draining());
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/admin/keys")
+            .header("Authorization", "Bearer admin-token")
+            .body(r#"{"user": "billing", "scopes": ["invoices:read"]}"#)
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let created: Value = serde_json::from_slice(response.body()).unwrap();
+        let id = created["id"].as_str().unwrap();
+        assert_eq!(key_store.verify(created["key"].as_str().unwrap()).await.unwrap().user, "billing");
+
+        let response = warp::test::request()
+            .path("/admin/keys")
+            .header("Authorization", "Bearer admin-token")
+            .reply(&filter)
+            .await;
+        let listed: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(listed["keys"][0]["id"], id);
+        assert_eq!(listed["keys"][0]["scopes"][0], "invoices:read");
+        assert!(listed["keys"][0].get("key").is_none());
+        assert!(listed["keys"][0].get("hash").is_none());
+
+        for status in [StatusCode::NO_CONTENT, StatusCode::NOT_FOUND] {
+            let response = warp::test::request()
+                .method("DELETE")
+                .path(&format!("/admin/keys/{}", id))
+                .header("Authorization", "Bearer admin-token")
+                .reply(&filter)
+                .await;
+            assert_eq!(response.status(), status);
+        }
+        assert!(key_store.verify(created["key"].as_str().unwrap()).await.is_none());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_list_and_clear_lockouts() {
+        let path = std::env::temp_dir().join(format!("api-gateway-admin-lockouts-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let lockout = LockoutConfig { max_failures: 2, ..LockoutConfig::default() };
+        let store_config = ApiKeyStoreConfig { path: path.clone(), kms: None, argon2_memory_kib: 8, argon2_iterations: 1, lockout: Some(lockout) };
+        let key_store = Arc::new(ApiKeyStore::open(&store_config).await.unwrap());
+        let (stored, key) = key_store.create("billing", Vec::new()).unwrap();
+        for _ in 0..2 {
+            assert!(key_store.login(&stored.id, "wrong").await.is_err());
+        }
+        assert!(key_store.verify(&key).await.is_none());
+        let filter = routes(admin_config(), running_config(), route_table(), state(), tenants(), bulkheads(), audit(), Some(key_store.clone()), Arc::default(), None, None, failure_injection(), draining());
+
+        let response = warp::test::request()
+            .path("/admin/lockouts")
+            .header("Authorization", "Bearer admin-token")
+            .reply(&filter)
+            .await;
+        let listed: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(listed["lockouts"][0]["identity"], stored.id.as_str());
+        assert_eq!(listed["lockouts"][0]["locked"], true);
+
+        for status in [StatusCode::NO_CONTENT, StatusCode::NOT_FOUND] {
+            let response = warp::test::request()
+                .method("DELETE")
+                .path(&format!("/admin/lockouts/{}", stored.id))
+                .header("Authorization", "Bearer admin-token")
+                .reply(&filter)
+                .await;
+            assert_eq!(response.status(), status);
+        }
+        assert!(key_store.verify(&key).await.is_some());
+
+        let unconfigured = routes(admin_config(), running_config(), route_table(), state(), tenants(), bulkheads(), audit(), None, Arc::default(), None, None, failure_injection(), draining());
+        let response = warp::test::request()
+            .path("/admin/lockouts")
+            .header("Authorization", "Bearer admin-token")
+            .reply(&unconfigured)
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_show_analytics() {
+        let filter = routes(admin_config(), running_config(), route_table(), state(), tenants(), bulkheads(), audit(), None, Arc::default(), None, None, failure_injection(), draining());
+        let response = warp::test::request().path("/admin/analytics").header("Authorization", "Bearer admin-token").reply(&filter).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let analytics = Arc::new(Analytics::new(&AnalyticsConfig::default()));
+        for route in ["users", "users", "reports"] {
+            analytics.record(Some(route), &Method::GET, None, StatusCode::OK, Duration::from_millis(10), SystemTime::now());
+        }
+        let filter = routes(admin_config(), running_config(), route_table(), state(), tenants(), bulkheads(), audit(), None, Arc::default(), Some(analytics), None, failure_injection(), draining());
+        let response = warp::test::request()
+            .path("/admin/analytics?top=1")
+            .header("Authorization", "Bearer admin-token")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["top_routes"], serde_json::json!([{ "route": "users", "requests": 2, "errors": 0 }]));
+    }
+
+    #[tokio::test]
+    async fn test_flush_dns_cache() {
+        let flush = |filter| async move {
+            warp::test::request()
+                .method("DELETE")
+                .path("/admin/dns-cache")
+                .header("Authorization", "Bearer admin-token")
+                .reply(&filter)
+                .await
+        };
+        let filter = routes(admin_config(), running_config(), route_table(), state(), tenants(), bulkheads(), audit(), None, Arc::default(), None, None, failure_injection(), draining());
+        assert_eq!(flush(filter).await.status(), StatusCode::NOT_FOUND);
+
+        let cache = Arc::new(DnsCache::new(&DnsCacheConfig::default()));
+        cache.resolve("localhost").await.unwrap();
+        let filter = routes(admin_config(), running_config(), route_table(), state(), tenants(), bulkheads(), audit(), None, Arc::default(), None, Some(cache.clone()), failure_injection(), draining());
+        let response = flush(filter).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(serde_json::from_slice::<Value>(response.body()).unwrap(), serde_json::json!({ "flushed": 1 }));
+        assert!(cache.render().contains("gateway_dns_cache_entries 0\n"));
+    }
+
+    #[tokio::test]
+    async fn test_switch_failure_injection() {
+        let failure_injection = failure_injection();
+        let filter = routes(admin_config(), running_config(), route_table(), state(), tenants(), bulkheads(), audit(), None, Arc::default(), None, None, failure_injection.clone(), draining());
+        let switch = |body: &'static str| warp::test::request()
+            .method("PUT")
+            .path("/admin/failure-injection")
+            .header("Authorization", "Bearer admin-token")
+            .body(body)
+            .reply(&filter);
+        let response = switch(r#"{ "enabled": true, "fraction": 1 }"#).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(serde_json::from_slice::<Value>(response.body()).unwrap(), serde_json::json!({ "enabled": true, "fraction": 1.0, "injected": 0 }));
+        assert!(failure_injection.excludes("users:8080", None));
+
+        assert_eq!(switch(r#"{ "enabled": true, "fraction": -1 }"#).await.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(switch(r#"{ "enabled": false }"#).await.status(), StatusCode::OK);
+        assert!(!failure_injection.excludes("users:8080", None));
+    }
+
+    #[tokio::test]
+    async fn test_drain_upstream() {
+        let draining = draining();
+        let in_flight = draining.begin("orders:8080");
+        let filter = routes(admin_config(), running_config(), route_table(), state(), tenants(), bulkheads(), audit(), None, Arc::default(), None, None, failure_injection(), draining.clone());
+        let request = |method: &'static str, path: &'static str| warp::test::request()
+            .method(method)
+            .path(path)
+            .header("Authorization", "Bearer admin-token")
+            .reply(&filter);
+        assert_eq!(request("GET", "/admin/drains/orders:8080").await.status(), StatusCode::NOT_FOUND);
+        assert_eq!(request("DELETE", "/admin/drains/orders:8080").await.status(), StatusCode::NOT_FOUND);
+
+        let response = request("PUT", "/admin/drains/orders:8080").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(serde_json::from_slice::<Value>(response.body()).unwrap(), serde_json::json!({ "upstream": "orders:8080", "in_flight": 1, "drained": false }));
+        assert!(draining.is_draining("orders:8080"));
+
+        drop(in_flight);
+        let response = request("GET", "/admin/drains/orders:8080").await;
+        assert_eq!(serde_json::from_slice::<Value>(response.body()).unwrap()["drained"], true);
+        let response = request("GET", "/admin/drains").await;
+        assert_eq!(serde_json::from_slice::<Value>(response.body()).unwrap()["draining"].as_array().unwrap().len(), 1);
+
+        assert_eq!(request("DELETE", "/admin/drains/orders:8080").await.status(), StatusCode::NO_CONTENT);
+        assert!(!draining.is_draining("orders:8080"));
+    }
+}
+
@@ -0,0 +1,83 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+#[cfg(test)]
+mod tests;
+
+/// Rows of the count-min sketch; a key's estimate is its lowest counter.
+const DEPTH: usize = 4;
+/// Counters saturate here, which is plenty to rank keys against each other.
+const MAX_COUNT: u8 = 15;
+/// Accesses recorded per counter before every count is halved.
+const SAMPLE_FACTOR: usize = 10;
+
+/// How often keys have been asked for recently, in a fixed amount of memory
+/// (TinyLFU). Counts are halved every `SAMPLE_FACTOR` accesses per counter,
+/// so keys that were popular long ago fade.
+#[derive(Debug)]
+pub struct FrequencySketch {
+    rows: Vec<Vec<u8>>,
+    mask: usize,
+    additions: usize,
+    sample_size: usize,
+}
+
+impl FrequencySketch {
+    /// A sketch sized for a cache of `capacity` entries.
+    pub fn new(capacity: usize) -> Self {
+        let width = capacity.max(16).next_power_of_two();
+        Self {
+            rows: vec![vec![0; width]; DEPTH],
+            mask: width - 1,
+            additions: 0,
+            sample_size: width * SAMPLE_FACTOR,
+        }
+    }
+
+    pub fn record(&mut self, key: &str) {
+        let estimate = self.frequency(key);
+        if estimate < MAX_COUNT {
+            // Only the lowest counters are raised (conservative update),
+            // which keeps collisions from inflating other keys' estimates.
+            for (row, index) in self.indexes(key).into_iter().enumerate() {
+                if self.rows[row][index] == estimate {
+                    self.rows[row][index] += 1;
+                }
+            }
+        }
+        self.additions += 1;
+        if self.additions >= self.sample_size {
+            self.age();
+        }
+    }
+
+    pub fn frequency(&self, key: &str) -> u8 {
+        self.indexes(key).into_iter().enumerate().map(|(row, index)| self.rows[row][index]).min().unwrap_or(0)
+    }
+
+    /// Whether `candidate` should replace `victim`: only if it has been asked
+    /// for more often, so one-hit wonders don't push out hot entries.
+    pub fn admit(&self, candidate: &str, victim: &str) -> bool {
+        self.frequency(candidate) > self.frequency(victim)
+    }
+
+    fn age(&mut self) {
+        for row in &mut self.rows {
+            for counter in row.iter_mut() {
+                *counter /= 2;
+            }
+        }
+        self.additions /= 2;
+    }
+
+    fn indexes(&self, key: &str) -> [usize; DEPTH] {
+        let mut indexes = [0; DEPTH];
+        for (seed, index) in indexes.iter_mut().enumerate() {
+            let mut hasher = DefaultHasher::new();
+            seed.hash(&mut hasher);
+            key.hash(&mut hasher);
+            *index = hasher.finish() as usize & self.mask;
+        }
+        indexes
+    }
+}
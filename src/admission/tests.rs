@@ -0,0 +1,37 @@
+#[cfg(test)]
+mod tests {
+    use crate::admission::FrequencySketch;
+
+    #[test]
+    fn test_frequency_ranks_keys() {
+        let mut sketch = FrequencySketch::new(100);
+        for _ in 0..5 {
+            sketch.record("/hot");
+        }
+        sketch.record("/once");
+        assert_eq!((sketch.frequency("/hot"), sketch.frequency("/once"), sketch.frequency("/never")), (5, 1, 0));
+        assert!(sketch.admit("/hot", "/once"));
+        assert!(!sketch.admit("/once", "/hot"));
+        // Ties keep the entry already cached.
+        assert!(!sketch.admit("/once", "/once"));
+
+        for _ in 0..100 {
+            sketch.record("/hot");
+        }
+        assert_eq!(sketch.frequency("/hot"), 15);
+    }
+
+    #[test]
+    fn test_old_popularity_fades() {
+        let mut sketch = FrequencySketch::new(16);
+        for _ in 0..8 {
+            sketch.record("/yesterday");
+        }
+        // 16 counters sample 160 accesses before halving.
+        for _ in 0..152 {
+            sketch.record("/today");
+        }
+        assert_eq!((sketch.frequency("/yesterday"), sketch.frequency("/today")), (4, 7));
+        assert!(sketch.admit("/today", "/yesterday"));
+    }
+}
@@ -0,0 +1,173 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use hyper::{Method, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use crate::acme::civil_from_days;
+use crate::config::AnalyticsConfig;
+
+#[cfg(test)]
+mod tests;
+
+/// Names each list counts at once. Once a list is full, names not in it
+/// yet aren't counted until the next day.
+const MAX_NAMES: usize = 10_000;
+const DAY_SECS: u64 = 86_400;
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+struct Tally {
+    requests: u64,
+    /// Responses with a 4xx or 5xx status.
+    errors: u64,
+    total_ms: u64,
+    max_ms: u64,
+}
+
+impl Tally {
+    fn add(&mut self, status: StatusCode, elapsed_ms: u64) {
+        self.requests += 1;
+        if status.is_client_error() || status.is_server_error() {
+            self.errors += 1;
+        }
+        self.total_ms += elapsed_ms;
+        self.max_ms = self.max_ms.max(elapsed_ms);
+    }
+
+    fn merge(&mut self, other: &Tally) {
+        self.requests += other.requests;
+        self.errors += other.errors;
+        self.total_ms += other.total_ms;
+        self.max_ms = self.max_ms.max(other.max_ms);
+    }
+
+    fn average_ms(&self) -> u64 {
+        self.total_ms.checked_div(self.requests).unwrap_or(0)
+    }
+}
+
+/// One UTC day's figures, as persisted.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Day {
+    /// Days since the epoch.
+    day: u64,
+    routes: HashMap<String, Tally>,
+    keys: HashMap<String, Tally>,
+    /// `<method> <route>`.
+    endpoints: HashMap<String, Tally>,
+}
+
+/// Requests counted by route, by key holder and by endpoint since midnight
+/// UTC, for the top-N lists of `/admin/analytics`. The count starts over
+/// each day.
+pub struct Analytics {
+    top: usize,
+    today: Mutex<Day>,
+}
+
+impl Analytics {
+    pub fn new(config: &AnalyticsConfig) -> Self {
+        Self { top: config.top, today: Mutex::new(Day::default()) }
+    }
+
+    /// Counts a response. `route` is the route the request matched, if it
+    /// matched one, and `key_holder` whom it was identified as.
+    pub fn record(&self, route: Option<&str>, method: &Method, key_holder: Option<&str>, status: StatusCode, elapsed: Duration, now: SystemTime) {
+        let elapsed_ms = elapsed.as_millis() as u64;
+        let mut today = self.today(now);
+        if let Some(route) = route {
+            tally(&mut today.routes, route, status, elapsed_ms);
+            tally(&mut today.endpoints, &format!("{} {}", method, route), status, elapsed_ms);
+        }
+        if let Some(key_holder) = key_holder {
+            tally(&mut today.keys, key_holder, status, elapsed_ms);
+        }
+    }
+
+    /// The day's busiest routes, the key holders with the most errors and
+    /// the endpoints slowest on average, `top` of each, or as many as
+    /// configured.
+    pub fn report(&self, top: Option<usize>, now: SystemTime) -> Value {
+        let top = top.unwrap_or(self.top);
+        let today = self.today(now);
+        let routes: Vec<Value> = ranked(&today.routes, top, |tally| tally.requests)
+            .into_iter()
+            .map(|(route, tally)| json!({ "route": route, "requests": tally.requests, "errors": tally.errors }))
+            .collect();
+        let errors = today.keys.iter().filter(|(_, tally)| tally.errors > 0).map(|(key, tally)| (key.clone(), *tally)).collect();
+        let keys: Vec<Value> = ranked(&errors, top, |tally| tally.errors)
+            .into_iter()
+            .map(|(key, tally)| json!({ "key_holder": key, "errors": tally.errors, "requests": tally.requests }))
+            .collect();
+        let endpoints: Vec<Value> = ranked(&today.endpoints, top, Tally::average_ms)
+            .into_iter()
+            .map(|(endpoint, tally)| {
+                json!({ "endpoint": endpoint, "requests": tally.requests, "average_ms": tally.average_ms(), "max_ms": tally.max_ms })
+            })
+            .collect();
+        json!({
+            "date": date(today.day),
+            "top_routes": routes,
+            "top_key_holders_by_errors": keys,
+            "slowest_endpoints": endpoints,
+        })
+    }
+
+    /// The day's figures as persisted, named by their date.
+    pub fn snapshot(&self, now: SystemTime) -> (String, Value) {
+        let today = self.today(now);
+        (date(today.day), serde_json::to_value(&*today).unwrap_or_default())
+    }
+
+    /// Takes up `saved`, a snapshot, if it is of the current day, and adds
+    /// what was counted since startup to it. Returns whether it was.
+    pub fn restore(&self, saved: &Value, now: SystemTime) -> bool {
+        let Ok(saved) = Day::deserialize(saved) else {
+            return false;
+        };
+        let mut today = self.today(now);
+        if saved.day != today.day {
+            return false;
+        }
+        let counted = std::mem::replace(&mut *today, saved);
+        let today = &mut *today;
+        for (names, counted) in [(&mut today.routes, counted.routes), (&mut today.keys, counted.keys), (&mut today.endpoints, counted.endpoints)] {
+            for (name, tally) in counted {
+                names.entry(name).or_default().merge(&tally);
+            }
+        }
+        true
+    }
+
+    /// The figures, started over if the day has changed.
+    fn today(&self, now: SystemTime) -> std::sync::MutexGuard<'_, Day> {
+        let day = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / DAY_SECS;
+        let mut today = self.today.lock().unwrap_or_else(|e| e.into_inner());
+        if today.day != day {
+            *today = Day { day, ..Day::default() };
+        }
+        today
+    }
+}
+
+fn tally(names: &mut HashMap<String, Tally>, name: &str, status: StatusCode, elapsed_ms: u64) {
+    if let Some(tally) = names.get_mut(name) {
+        tally.add(status, elapsed_ms);
+    } else if names.len() < MAX_NAMES {
+        names.entry(name.to_string()).or_default().add(status, elapsed_ms);
+    }
+}
+
+/// The `top` names with the highest `by`, ties by name.
+fn ranked(names: &HashMap<String, Tally>, top: usize, by: impl Fn(&Tally) -> u64) -> Vec<(String, Tally)> {
+    let mut ranked: Vec<(String, Tally)> = names.iter().map(|(name, tally)| (name.clone(), *tally)).collect();
+    ranked.sort_by(|(a, a_tally), (b, b_tally)| by(b_tally).cmp(&by(a_tally)).then_with(|| a.cmp(b)));
+    ranked.truncate(top);
+    ranked
+}
+
+/// `YYYY-MM-DD` of a day since the epoch.
+pub fn date(day: u64) -> String {
+    let (year, month, day) = civil_from_days(day as i64);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
@@ -0,0 +1,78 @@
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use hyper::{Method, StatusCode};
+    use serde_json::json;
+    use crate::analytics::{date, Analytics};
+    use crate::config::AnalyticsConfig;
+
+    /// 2023-11-14 22:13:20 UTC.
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_700_000_000 + secs)
+    }
+
+    fn analytics() -> Analytics {
+        Analytics::new(&AnalyticsConfig { top: 2, ..AnalyticsConfig::default() })
+    }
+
+    #[test]
+    fn test_top_routes_keys_and_endpoints() {
+        let analytics = analytics();
+        let record = |route, method, key, status, ms| {
+            analytics.record(route, &method, key, status, Duration::from_millis(ms), at(0));
+        };
+        record(Some("orders"), Method::GET, Some("alice"), StatusCode::OK, 20);
+        record(Some("orders"), Method::GET, Some("alice"), StatusCode::NOT_FOUND, 10);
+        record(Some("orders"), Method::POST, Some("bob"), StatusCode::BAD_GATEWAY, 900);
+        record(Some("users"), Method::GET, Some("bob"), StatusCode::UNAUTHORIZED, 5);
+        record(Some("reports"), Method::GET, Some("carol"), StatusCode::OK, 300);
+        record(None, Method::GET, Some("bob"), StatusCode::NOT_FOUND, 1);
+
+        assert_eq!(analytics.report(None, at(60)), json!({
+            "date": "2023-11-14",
+            "top_routes": [
+                { "route": "orders", "requests": 3, "errors": 2 },
+                { "route": "reports", "requests": 1, "errors": 0 }
+            ],
+            "top_key_holders_by_errors": [
+                { "key_holder": "bob", "errors": 3, "requests": 3 },
+                { "key_holder": "alice", "errors": 1, "requests": 2 }
+            ],
+            "slowest_endpoints": [
+                { "endpoint": "POST orders", "requests": 1, "average_ms": 900, "max_ms": 900 },
+                { "endpoint": "GET reports", "requests": 1, "average_ms": 300, "max_ms": 300 }
+            ]
+        }));
+        assert_eq!(analytics.report(Some(5), at(60))["top_routes"].as_array().unwrap().len(), 3);
+
+        // Past midnight UTC the count starts over.
+        let tomorrow = analytics.report(None, at(7_000));
+        assert_eq!(tomorrow["date"], "2023-11-15");
+        assert_eq!(tomorrow["top_routes"], json!([]));
+    }
+
+    #[test]
+    fn test_restore_adds_to_the_snapshot_of_the_same_day() {
+        let before = analytics();
+        before.record(Some("orders"), &Method::GET, Some("alice"), StatusCode::BAD_GATEWAY, Duration::from_millis(100), at(0));
+        let (name, saved) = before.snapshot(at(0));
+        assert_eq!(name, "2023-11-14");
+
+        let after = analytics();
+        after.record(Some("orders"), &Method::GET, Some("alice"), StatusCode::OK, Duration::from_millis(300), at(10));
+        assert!(after.restore(&saved, at(20)));
+        let report = after.report(None, at(20));
+        assert_eq!(report["top_routes"], json!([{ "route": "orders", "requests": 2, "errors": 1 }]));
+        assert_eq!(report["slowest_endpoints"][0]["average_ms"], 200);
+        assert_eq!(report["slowest_endpoints"][0]["max_ms"], 300);
+
+        assert!(!analytics().restore(&saved, at(7_000)));
+        assert!(!analytics().restore(&json!({ "day": "yesterday" }), at(0)));
+    }
+
+    #[test]
+    fn test_date() {
+        assert_eq!(date(0), "1970-01-01");
+        assert_eq!(date(19_782), "2024-02-29");
+    }
+}
@@ -0,0 +1,380 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use argon2::{Algorithm, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version};
+use argon2::password_hash::SaltString;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::digest::{digest, SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::sync::Semaphore;
+use crate::aws::{self, HttpsClient};
+use crate::config::{ApiKeyStoreConfig, KmsConfig};
+use crate::lockout::Lockouts;
+use crate::persistence::{Persistence, API_KEYS, API_KEY_STORE};
+
+#[cfg(test)]
+mod tests;
+
+/// Issued keys read `gwk_<id>_<secret>`; the id finds the stored hash.
+pub const KEY_PREFIX: &str = "gwk_";
+const ID_BYTES: usize = 8;
+const SECRET_BYTES: usize = 32;
+const KMS_SERVICE: &str = "kms";
+/// Binds the ciphertext to its purpose, so it can't pass for another file
+/// encrypted under the same data key.
+const STORE_AAD: &[u8] = b"api-gateway api key store";
+
+/// An issued key. Only the argon2id hash of its secret is kept.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StoredKey {
+    pub id: String,
+    pub user: String,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    pub hash: String,
+    pub created_at: u64,
+}
+
+impl StoredKey {
+    /// The key without its hash, as the admin API lists it.
+    pub fn summary(&self) -> Value {
+        json!({ "id": self.id, "user": self.user, "scopes": self.scopes, "created_at": self.created_at })
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Contents {
+    keys: Vec<StoredKey>,
+}
+
+/// The file when encrypted: the contents sealed with AES-256-GCM under a
+/// data key that only KMS can decrypt.
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    kms_key_id: String,
+    data_key: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+/// Why `login` refused a key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoginError {
+    /// No key with this id, or a wrong secret.
+    Invalid,
+    /// Too many recent failures; the secret wasn't checked.
+    Throttled(Duration),
+}
+
+struct DataKey {
+    key: LessSafeKey,
+    /// The data key as encrypted by KMS, base64.
+    encrypted: String,
+    kms_key_id: String,
+}
+
+/// API keys issued through the admin API and persisted to a file, or to
+/// the database when one is configured.
+pub struct ApiKeyStore {
+    path: PathBuf,
+    persistence: Option<Arc<Persistence>>,
+    argon2: Argon2<'static>,
+    data_key: Option<DataKey>,
+    keys: RwLock<Vec<StoredKey>>,
+    /// Key id to the SHA-256 of a secret that passed argon2 verification, so
+    /// a key is checked the slow way only once.
+    verified: RwLock<HashMap<String, Vec<u8>>>,
+    /// Bounds the argon2 checks running at once to one per core. They run
+    /// on the blocking pool, so secrets that don't verify can't hold up
+    /// request handling.
+    verifying: Semaphore,
+    lockouts: Option<Lockouts>,
+    random: SystemRandom,
+}
+
+impl ApiKeyStore {
+    /// Loads the store, decrypting it through KMS if it is encrypted. A
+    /// missing file is an empty store. With `kms` configured, a plaintext
+    /// file is encrypted right away.
+    pub async fn open(config: &ApiKeyStoreConfig) -> Result<Self, String> {
+        Self::load(config, read_store(&config.path)?, None).await
+    }
+
+    /// Loads the store from the database. While the database has none yet,
+    /// the file is read and copied into it.
+    pub async fn open_persisted(config: &ApiKeyStoreConfig, persistence: Arc<Persistence>) -> Result<Self, String> {
+        let saved = persistence.load(API_KEYS).await?.into_iter().find(|(name, _)| name == API_KEY_STORE);
+        let migrate = saved.is_none();
+        let stored = match saved {
+            Some((_, value)) => Some(value),
+            None => read_store(&config.path)?,
+        };
+        let store = Self::load(config, stored, Some(persistence)).await?;
+        if migrate {
+            store.save(&store.list())?;
+        }
+        Ok(store)
+    }
+
+    async fn load(config: &ApiKeyStoreConfig, stored: Option<Value>, persistence: Option<Arc<Persistence>>) -> Result<Self, String> {
+        let params = Params::new(config.argon2_memory_kib, config.argon2_iterations, 1, None)
+            .map_err(|e| format!("invalid argon2 parameters: {}", e))?;
+        let client = aws::https_client();
+        let existed = stored.is_some();
+        let (contents, data_key, encrypt_now) = match stored {
+            Some(value) if value.get("ciphertext").is_some() => {
+                let envelope: Envelope = serde_json::from_value(value).map_err(|e| format!("{}: {}", config.path.display(), e))?;
+                let kms = config
+                    .kms
+                    .as_ref()
+                    .ok_or_else(|| format!("{} is encrypted but no kms key is configured", config.path.display()))?;
+                let data_key = decrypt_data_key(&client, kms, &envelope).await?;
+                let contents = open_envelope(&data_key.key, &envelope)?;
+                (contents, Some(data_key), false)
+            }
+            stored => {
+                let contents = match stored {
+                    Some(value) => serde_json::from_value(value).map_err(|e| format!("{}: {}", config.path.display(), e))?,
+                    None => Contents::default(),
+                };
+                let data_key = match &config.kms {
+                    Some(kms) => Some(generate_data_key(&client, kms).await?),
+                    None => None,
+                };
+                let encrypt_now = data_key.is_some() && existed;
+                (contents, data_key, encrypt_now)
+            }
+        };
+
+        let store = Self {
+            path: config.path.clone(),
+            persistence,
+            argon2: Argon2::new(Algorithm::Argon2id, Version::V0x13, params),
+            data_key,
+            keys: RwLock::new(contents.keys),
+            verified: RwLock::new(HashMap::new()),
+            verifying: Semaphore::new(std::thread::available_parallelism().map_or(1, |cores| cores.get())),
+            lockouts: config.lockout.as_ref().map(Lockouts::new),
+            random: SystemRandom::new(),
+        };
+        if encrypt_now {
+            store.save(&store.list())?;
+        }
+        Ok(store)
+    }
+
+    /// Every stored key, oldest first.
+    pub fn list(&self) -> Vec<StoredKey> {
+        self.keys.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Issues a key for `user` and persists its hash. The returned key is
+    /// the only time the secret is ever available.
+    pub fn create(&self, user: &str, scopes: Vec<String>) -> Result<(StoredKey, String), String> {
+        let id = self.random_hex(ID_BYTES)?;
+        let secret = self.random_hex(SECRET_BYTES)?;
+        let mut salt = [0u8; 16];
+        self.random.fill(&mut salt).map_err(|_| "no randomness available".to_string())?;
+        let salt = SaltString::encode_b64(&salt).map_err(|e| e.to_string())?;
+        let hash = self
+            .argon2
+            .hash_password(secret.as_bytes(), &salt)
+            .map_err(|e| format!("hashing key: {}", e))?
+            .to_string();
+        let created_at = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let stored = StoredKey { id: id.clone(), user: user.to_string(), scopes, hash, created_at };
+
+        let mut keys = self.keys.write().unwrap_or_else(|e| e.into_inner());
+        let mut updated = keys.clone();
+        updated.push(stored.clone());
+        self.save(&updated)?;
+        *keys = updated;
+        Ok((stored, format!("{}{}_{}", KEY_PREFIX, id, secret)))
+    }
+
+    /// Removes a key. `false` if there is none with this id.
+    pub fn revoke(&self, id: &str) -> Result<bool, String> {
+        let mut keys = self.keys.write().unwrap_or_else(|e| e.into_inner());
+        if !keys.iter().any(|key| key.id == id) {
+            return Ok(false);
+        }
+        let updated: Vec<StoredKey> = keys.iter().filter(|key| key.id != id).cloned().collect();
+        self.save(&updated)?;
+        *keys = updated;
+        self.verified.write().unwrap_or_else(|e| e.into_inner()).remove(id);
+        if let Some(lockouts) = &self.lockouts {
+            lockouts.unlock(id);
+        }
+        Ok(true)
+    }
+
+    /// Failed logins per key id, when `lockout` is configured.
+    pub fn lockouts(&self) -> Option<&Lockouts> {
+        self.lockouts.as_ref()
+    }
+
+    /// The stored key a presented `gwk_` key belongs to.
+    pub async fn verify(&self, presented: &str) -> Option<StoredKey> {
+        let (id, secret) = presented.strip_prefix(KEY_PREFIX)?.split_once('_')?;
+        self.login(id, secret).await.ok()
+    }
+
+    /// `authenticate`, throttled by the lockout settings. Failures are only
+    /// counted against ids that exist, so made-up ids can't fill the table.
+    pub async fn login(&self, id: &str, secret: &str) -> Result<StoredKey, LoginError> {
+        let Some(lockouts) = &self.lockouts else {
+            return self.authenticate(id, secret).await.ok_or(LoginError::Invalid);
+        };
+        if let Some(wait) = lockouts.retry_after(id) {
+            return Err(LoginError::Throttled(wait));
+        }
+        match self.authenticate(id, secret).await {
+            Some(stored) => {
+                lockouts.record_success(id);
+                Ok(stored)
+            }
+            None => {
+                if self.list().iter().any(|key| key.id == id) {
+                    lockouts.record_failure(id);
+                }
+                Err(LoginError::Invalid)
+            }
+        }
+    }
+
+    /// The stored key with this id, if `secret` is its secret.
+    pub async fn authenticate(&self, id: &str, secret: &str) -> Option<StoredKey> {
+        let stored = self.list().into_iter().find(|key| key.id == id)?;
+        let fingerprint = digest(&SHA256, secret.as_bytes()).as_ref().to_vec();
+        let known = self.verified.read().unwrap_or_else(|e| e.into_inner()).get(id) == Some(&fingerprint);
+        if !known {
+            let _permit = self.verifying.acquire().await.ok()?;
+            let (argon2, hash, secret) = (self.argon2.clone(), stored.hash.clone(), secret.to_string());
+            let matches = tokio::task::spawn_blocking(move || {
+                PasswordHash::new(&hash).is_ok_and(|hash| argon2.verify_password(secret.as_bytes(), &hash).is_ok())
+            })
+            .await
+            .unwrap_or(false);
+            if !matches {
+                return None;
+            }
+            self.verified.write().unwrap_or_else(|e| e.into_inner()).insert(id.to_string(), fingerprint);
+        }
+        Some(stored)
+    }
+
+    fn random_hex(&self, len: usize) -> Result<String, String> {
+        let mut bytes = vec![0u8; len];
+        self.random.fill(&mut bytes).map_err(|_| "no randomness available".to_string())?;
+        Ok(aws::hex(&bytes))
+    }
+
+    /// Writes the keys to a temporary file readable only by the gateway and
+    /// moves it into place, so a crash never leaves a partial store. With a
+    /// database, the same document is queued for it instead.
+    fn save(&self, keys: &[StoredKey]) -> Result<(), String> {
+        let file = match &self.data_key {
+            Some(data_key) => {
+                let mut nonce = [0u8; NONCE_LEN];
+                self.random.fill(&mut nonce).map_err(|_| "no randomness available".to_string())?;
+                let mut sealed = serde_json::to_vec(&json!({ "keys": keys })).map_err(|e| e.to_string())?;
+                data_key
+                    .key
+                    .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(STORE_AAD), &mut sealed)
+                    .map_err(|_| "encrypting the key store failed".to_string())?;
+                serde_json::to_vec_pretty(&Envelope {
+                    kms_key_id: data_key.kms_key_id.clone(),
+                    data_key: data_key.encrypted.clone(),
+                    nonce: STANDARD.encode(nonce),
+                    ciphertext: STANDARD.encode(sealed),
+                })
+                .map_err(|e| e.to_string())?
+            }
+            None => serde_json::to_vec_pretty(&json!({ "keys": keys })).map_err(|e| e.to_string())?,
+        };
+        if let Some(persistence) = &self.persistence {
+            let document = serde_json::from_slice(&file).map_err(|e| e.to_string())?;
+            persistence.save(API_KEYS, API_KEY_STORE, &document);
+            return Ok(());
+        }
+        write_private(&self.path, &file).map_err(|e| format!("writing {}: {}", self.path.display(), e))
+    }
+}
+
+/// The store file as JSON, or `None` when there is no file.
+fn read_store(path: &Path) -> Result<Option<Value>, String> {
+    match std::fs::read(path) {
+        Ok(bytes) => serde_json::from_slice(&bytes).map(Some).map_err(|e| format!("{}: {}", path.display(), e)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(format!("reading {}: {}", path.display(), e)),
+    }
+}
+
+/// Replaces `path` atomically with a file only its owner can read.
+pub fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let temporary = PathBuf::from(temporary);
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(&temporary)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    std::fs::rename(&temporary, path)
+}
+
+fn open_envelope(key: &LessSafeKey, envelope: &Envelope) -> Result<Contents, String> {
+    let nonce: [u8; NONCE_LEN] = STANDARD
+        .decode(&envelope.nonce)
+        .ok()
+        .and_then(|nonce| nonce.try_into().ok())
+        .ok_or("the key store has an invalid nonce")?;
+    let mut sealed = STANDARD.decode(&envelope.ciphertext).map_err(|_| "the key store ciphertext is not base64")?;
+    let contents = key
+        .open_in_place(Nonce::assume_unique_for_key(nonce), Aad::from(STORE_AAD), &mut sealed)
+        .map_err(|_| "the key store could not be decrypted".to_string())?;
+    serde_json::from_slice(contents).map_err(|e| format!("decrypted key store: {}", e))
+}
+
+fn data_key(plaintext: &[u8], encrypted: String, kms_key_id: String) -> Result<DataKey, String> {
+    let key = UnboundKey::new(&AES_256_GCM, plaintext).map_err(|_| "KMS returned a data key of the wrong size".to_string())?;
+    Ok(DataKey { key: LessSafeKey::new(key), encrypted, kms_key_id })
+}
+
+fn plaintext(response: &Value) -> Result<Vec<u8>, String> {
+    response["Plaintext"]
+        .as_str()
+        .and_then(|plaintext| STANDARD.decode(plaintext).ok())
+        .ok_or_else(|| "KMS response has no Plaintext".to_string())
+}
+
+/// Asks KMS for a fresh AES-256 data key, in the clear and encrypted.
+async fn generate_data_key(client: &HttpsClient, kms: &KmsConfig) -> Result<DataKey, String> {
+    let region = kms.region().ok_or("the kms key has no region")?;
+    let payload = json!({ "KeyId": kms.key_id, "KeySpec": "AES_256" });
+    let response = aws::call(client, KMS_SERVICE, &region, kms.endpoint.as_deref(), "TrentService.GenerateDataKey", &payload)
+        .await
+        .map_err(|e| format!("KMS {}", e))?;
+    let encrypted = response["CiphertextBlob"]
+        .as_str()
+        .ok_or("KMS response has no CiphertextBlob")?
+        .to_string();
+    data_key(&plaintext(&response)?, encrypted, kms.key_id.clone())
+}
+
+async fn decrypt_data_key(client: &HttpsClient, kms: &KmsConfig, envelope: &Envelope) -> Result<DataKey, String> {
+    let region = kms.region().ok_or("the kms key has no region")?;
+    let payload = json!({ "KeyId": kms.key_id, "CiphertextBlob": envelope.data_key });
+    let response = aws::call(client, KMS_SERVICE, &region, kms.endpoint.as_deref(), "TrentService.Decrypt", &payload)
+        .await
+        .map_err(|e| format!("KMS {}", e))?;
+    data_key(&plaintext(&response)?, envelope.data_key.clone(), envelope.kms_key_id.clone())
+}
@@ -0,0 +1,119 @@
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD;
+    use serde_json::{json, Value};
+    use warp::Filter;
+    use crate::api_keys::{ApiKeyStore, KEY_PREFIX};
+    use crate::config::{ApiKeyStoreConfig, KmsConfig};
+
+    const DATA_KEY: [u8; 32] = [7; 32];
+
+    fn store_config(name: &str, kms: Option<KmsConfig>) -> ApiKeyStoreConfig {
+        let path: PathBuf = std::env::temp_dir().join(format!("api-gateway-keys-{}-{}.json", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        ApiKeyStoreConfig { path, kms, argon2_memory_kib: 8, argon2_iterations: 1, lockout: None }
+    }
+
+    /// A KMS stand-in whose data key is always `DATA_KEY`.
+    fn kms() -> KmsConfig {
+        let kms = warp::post()
+            .and(warp::header::<String>("x-amz-target"))
+            .and(warp::body::bytes())
+            .map(|target: String, body: bytes::Bytes| {
+                let request: Value = serde_json::from_slice(&body).unwrap();
+                assert_eq!(request["KeyId"], "arn:aws:kms:eu-west-1:1:key/store");
+                match target.as_str() {
+                    "TrentService.GenerateDataKey" => warp::reply::json(&json!({
+                        "CiphertextBlob": STANDARD.encode(b"wrapped"),
+                        "Plaintext": STANDARD.encode(DATA_KEY),
+                    })),
+                    _ => {
+                        assert_eq!(request["CiphertextBlob"], STANDARD.encode(b"wrapped"));
+                        warp::reply::json(&json!({ "Plaintext": STANDARD.encode(DATA_KEY) }))
+                    }
+                }
+            });
+        let (addr, server) = warp::serve(kms).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        std::env::set_var("AWS_ACCESS_KEY_ID", "AKIDTEST");
+        std::env::set_var("AWS_SECRET_ACCESS_KEY", "secret");
+        KmsConfig {
+            key_id: "arn:aws:kms:eu-west-1:1:key/store".to_string(),
+            region: None,
+            endpoint: Some(format!("http://{}/", addr)),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_keys_are_stored_as_hashes() {
+        let config = store_config("plain", None);
+        let store = ApiKeyStore::open(&config).await.unwrap();
+        let (stored, key) = store.create("billing", vec!["invoices:read".to_string()]).unwrap();
+        let secret = key.strip_prefix(&format!("{}{}_", KEY_PREFIX, stored.id)).unwrap();
+        assert_eq!(secret.len(), 64);
+        assert!(stored.hash.starts_with("$argon2id$"));
+
+        let file = std::fs::read_to_string(&config.path).unwrap();
+        assert!(file.contains(&stored.hash));
+        assert!(!file.contains(secret));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(std::fs::metadata(&config.path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+
+        assert_eq!(store.verify(&key).await.unwrap().user, "billing");
+        assert_eq!(store.verify(&key).await.unwrap().scopes, ["invoices:read"]);
+        assert!(store.verify(&format!("{}{}_{}", KEY_PREFIX, stored.id, "0".repeat(64))).await.is_none());
+        assert!(store.verify("example-token").await.is_none());
+
+        let reopened = ApiKeyStore::open(&config).await.unwrap();
+        assert_eq!(reopened.authenticate(&stored.id, secret).await.unwrap().user, "billing");
+        assert!(reopened.revoke(&stored.id).unwrap());
+        assert!(!reopened.revoke(&stored.id).unwrap());
+        assert!(reopened.verify(&key).await.is_none());
+        assert!(ApiKeyStore::open(&config).await.unwrap().list().is_empty());
+        std::fs::remove_file(&config.path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_envelope_encryption_with_kms() {
+        let kms = kms();
+        let config = store_config("kms", Some(kms.clone()));
+        let store = ApiKeyStore::open(&config).await.unwrap();
+        let (stored, key) = store.create("billing", Vec::new()).unwrap();
+
+        let file: Value = serde_json::from_str(&std::fs::read_to_string(&config.path).unwrap()).unwrap();
+        assert_eq!(file["data_key"], STANDARD.encode(b"wrapped"));
+        assert!(file.get("keys").is_none());
+        assert!(!file.to_string().contains(&stored.id));
+
+        let reopened = ApiKeyStore::open(&config).await.unwrap();
+        assert_eq!(reopened.verify(&key).await.unwrap().id, stored.id);
+
+        let unconfigured = ApiKeyStore::open(&ApiKeyStoreConfig { kms: None, ..config.clone() }).await;
+        assert!(unconfigured.err().unwrap().contains("no kms key is configured"));
+
+        let mut tampered = file.clone();
+        let mut ciphertext = STANDARD.decode(file["ciphertext"].as_str().unwrap()).unwrap();
+        ciphertext[0] ^= 1;
+        tampered["ciphertext"] = json!(STANDARD.encode(ciphertext));
+        std::fs::write(&config.path, tampered.to_string()).unwrap();
+        assert!(ApiKeyStore::open(&config).await.err().unwrap().contains("could not be decrypted"));
+        std::fs::remove_file(&config.path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_plaintext_store_is_encrypted_once_kms_is_configured() {
+        let plain = store_config("migrate", None);
+        let (_, key) = ApiKeyStore::open(&plain).await.unwrap().create("billing", Vec::new()).unwrap();
+
+        let encrypted = ApiKeyStore::open(&ApiKeyStoreConfig { kms: Some(kms()), ..plain.clone() }).await.unwrap();
+        assert!(encrypted.verify(&key).await.is_some());
+        let file: Value = serde_json::from_str(&std::fs::read_to_string(&plain.path).unwrap()).unwrap();
+        assert!(file.get("ciphertext").is_some());
+        std::fs::remove_file(&plain.path).unwrap();
+    }
+}
@@ -0,0 +1,103 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use hyper::HeaderMap;
+use serde_json::{json, Map, Value};
+use crate::persistence::Persistence;
+use crate::redaction::Redactor;
+use crate::services::admin_token_index;
+use crate::syslog;
+
+#[cfg(test)]
+mod tests;
+
+/// Append-only record of admin API changes, one JSON object per line.
+/// Without a file configured the entries go to stdout with the other logs.
+/// With a database, they are also appended to its audit table.
+pub struct AuditLog {
+    file: Option<Mutex<File>>,
+    redactor: Redactor,
+    persistence: Option<Arc<Persistence>>,
+}
+
+impl AuditLog {
+    pub fn open(path: Option<&Path>, redactor: Redactor) -> io::Result<Self> {
+        let file = match path {
+            Some(path) => Some(Mutex::new(OpenOptions::new().create(true).append(true).open(path)?)),
+            None => None,
+        };
+        Ok(Self { file, redactor, persistence: None })
+    }
+
+    pub fn stdout() -> Self {
+        Self { file: None, redactor: Redactor::default(), persistence: None }
+    }
+
+    pub fn with_persistence(mut self, persistence: Arc<Persistence>) -> Self {
+        self.persistence = Some(persistence);
+        self
+    }
+
+    /// Records `action` on `target` by `actor`, with the state before and
+    /// after and which of its fields changed.
+    pub fn record(&self, actor: &str, action: &str, target: &str, before: Value, after: Value) {
+        let mut entry = json!({
+            "timestamp": SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+            "actor": actor,
+            "action": action,
+            "target": target,
+            "changed": changed_fields(&before, &after),
+            "before": before,
+            "after": after,
+        });
+        self.redactor.json(&mut entry);
+        if let Some(persistence) = &self.persistence {
+            persistence.append_audit(&entry);
+        }
+        let line = entry.to_string();
+        match &self.file {
+            Some(file) => {
+                let mut file = file.lock().unwrap_or_else(|e| e.into_inner());
+                if let Err(e) = writeln!(file, "{}", line) {
+                    syslog::error(format_args!("Failed to write audit log entry {}: {}", line, e));
+                }
+            }
+            None => println!("audit {}", line),
+        }
+    }
+}
+
+/// Names the admin token a request was made with by its position in
+/// `admin.tokens`, so the log identifies who acted without storing the token.
+pub fn actor(headers: &HeaderMap, admin_tokens: &[String]) -> String {
+    headers
+        .get("Authorization")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|auth| auth.strip_prefix("Bearer "))
+        .and_then(|token| admin_token_index(admin_tokens, token))
+        .map(|i| format!("admin.tokens[{}]", i))
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Top-level fields that differ, with `null` standing for an object that
+/// didn't exist or was removed.
+fn changed_fields(before: &Value, after: &Value) -> Vec<String> {
+    fn fields<'a>(value: &'a Value, none: &'a Map<String, Value>) -> Option<&'a Map<String, Value>> {
+        match value {
+            Value::Object(fields) => Some(fields),
+            Value::Null => Some(none),
+            _ => None,
+        }
+    }
+    let none = Map::new();
+    let (Some(before), Some(after)) = (fields(before, &none), fields(after, &none)) else {
+        return Vec::new();
+    };
+    let mut keys: Vec<_> = before.keys().chain(after.keys()).cloned().collect();
+    keys.sort();
+    keys.dedup();
+    keys.retain(|key| before.get(key) != after.get(key));
+    keys
+}
@@ -0,0 +1,52 @@
+#[cfg(test)]
+mod tests {
+    use hyper::HeaderMap;
+    use serde_json::{json, Value};
+    use crate::audit::{actor, AuditLog};
+    use crate::redaction::Redactor;
+
+    #[test]
+    fn test_entries_are_appended() {
+        let path = std::env::temp_dir().join(format!("api-gateway-audit-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        AuditLog::open(Some(&path), Redactor::default()).unwrap().record(
+            "admin.tokens[0]",
+            "deployment.switch",
+            "orders",
+            json!({ "active": "blue", "groups": ["blue", "green"] }),
+            json!({ "active": "green", "groups": ["blue", "green"] }),
+        );
+        // Reopening keeps what was written before.
+        AuditLog::open(Some(&path), Redactor::default()).unwrap().record(
+            "admin.tokens[1]",
+            "ratelimits.delete",
+            "10.0.0.1",
+            json!({ "count": 3, "secret": "hunter2" }),
+            Value::Null,
+        );
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let entries: Vec<Value> = contents.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0]["actor"], "admin.tokens[0]");
+        assert_eq!(entries[0]["changed"], json!(["active"]));
+        assert!(entries[0]["timestamp"].as_u64().unwrap() > 0);
+        assert_eq!(entries[1]["changed"], json!(["count", "secret"]));
+        assert_eq!(entries[1]["before"]["secret"], "[redacted]");
+        assert!(entries[1]["after"].is_null());
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_actor_names_token_by_position() {
+        let tokens = vec!["first".to_string(), "second".to_string()];
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", "Bearer second".parse().unwrap());
+        assert_eq!(actor(&headers, &tokens), "admin.tokens[1]");
+
+        headers.insert("authorization", "Bearer other".parse().unwrap());
+        assert_eq!(actor(&headers, &tokens), "unknown");
+    }
+}
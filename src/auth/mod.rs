@@ -0,0 +1,481 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use hyper::{HeaderMap, header::{HeaderName, HeaderValue, AUTHORIZATION}};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair, RsaKeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+use serde_json::{json, Map, Value};
+use crate::api_keys::ApiKeyStore;
+use crate::config::{AuthConfig, InternalJwtConfig, JwtAlgorithm};
+use crate::errors::{ConfigError, GatewayError};
+use crate::oidc::Oidc;
+use crate::services::authenticated_user;
+use crate::syslog;
+
+#[cfg(test)]
+mod tests;
+
+/// Marks the tokens minted by `/token`, so the internal tokens sent upstream
+/// by `token_exchange` routes can't be replayed against the gateway, and
+/// tokens from `/token` are refused everywhere else.
+pub const CLIENT_ID_CLAIM: &str = "client_id";
+
+/// Who a request was made by, as resolved from its bearer token.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Identity {
+    pub user_id: String,
+    pub scopes: Vec<String>,
+    /// Claims of a client JWT or OIDC session; empty for static tokens.
+    pub claims: Map<String, Value>,
+}
+
+impl Identity {
+    /// A claim rendered as a header value: strings as-is, arrays joined with
+    /// commas. `sub` falls back to the user id for static tokens.
+    pub fn claim(&self, name: &str) -> Option<String> {
+        match self.claims.get(name) {
+            Some(Value::String(value)) => Some(value.clone()),
+            Some(Value::Array(values)) => Some(
+                values
+                    .iter()
+                    .map(|value| value.as_str().map(str::to_string).unwrap_or_else(|| value.to_string()))
+                    .collect::<Vec<_>>()
+                    .join(","),
+            ),
+            Some(Value::Null) | None if name == "sub" => Some(self.user_id.clone()),
+            Some(Value::Null) | None => None,
+            Some(value) => Some(value.to_string()),
+        }
+    }
+}
+
+/// Resolves client identities and mints internal tokens. Without any JWT
+/// configuration only the static bearer tokens are accepted.
+///
+/// Key material is read from disk by `reload`, which swaps it in atomically:
+/// a request sees either the old set or the new one, never a mix.
+#[derive(Default)]
+pub struct Authenticator {
+    config: RwLock<AuthConfig>,
+    keys: RwLock<Arc<Keys>>,
+    claim_headers: Vec<(String, HeaderName)>,
+    key_store: Option<Arc<ApiKeyStore>>,
+    oidc: Option<Arc<Oidc>>,
+    reloads: AtomicU64,
+    reload_failures: AtomicU64,
+}
+
+#[derive(Default)]
+struct Keys {
+    /// Bearer token to user id from `api_keys_file`; the built-in tokens apply
+    /// when it isn't configured.
+    api_keys: Option<HashMap<String, String>>,
+    client_jwt: Option<(DecodingKey, Validation)>,
+    internal_jwt: Option<InternalSigner>,
+    /// Checks the tokens minted by `/token`; set when it is enabled.
+    issued_jwt: Option<(DecodingKey, Validation)>,
+    /// The `aud` of the tokens minted by `/token`.
+    issued_audience: Option<String>,
+}
+
+struct InternalSigner {
+    key: EncodingKey,
+    algorithm: Algorithm,
+    issuer: String,
+    audience: Option<String>,
+    ttl_secs: u64,
+}
+
+impl Authenticator {
+    pub fn from_config(config: &AuthConfig) -> Result<Self, ConfigError> {
+        let mut claim_headers = config
+            .claim_headers
+            .iter()
+            .map(|(claim, header)| {
+                HeaderName::from_bytes(header.as_bytes())
+                    .map(|header| (claim.clone(), header))
+                    .map_err(|_| ConfigError::Invalid(format!("\"{}\" is not a valid header name", header)))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        claim_headers.sort_by(|a, b| a.0.cmp(&b.0));
+
+        Ok(Self {
+            keys: RwLock::new(Arc::new(Keys::load(config)?)),
+            config: RwLock::new(config.clone()),
+            claim_headers,
+            ..Self::default()
+        })
+    }
+
+    /// Also accepts the keys issued into `key_store`.
+    pub fn with_key_store(mut self, key_store: Arc<ApiKeyStore>) -> Self {
+        self.key_store = Some(key_store);
+        self
+    }
+
+    /// Also accepts the session cookies of users signed in through `oidc`.
+    pub fn with_oidc(mut self, oidc: Arc<Oidc>) -> Self {
+        self.oidc = Some(oidc);
+        self
+    }
+
+    /// Re-reads the API key and JWT key files. On failure the current keys
+    /// stay in use.
+    pub fn reload(&self) -> Result<(), ConfigError> {
+        let config = self.config.read().unwrap_or_else(|e| e.into_inner()).clone();
+        self.load(config)
+    }
+
+    /// Switches to new key settings, such as refreshed secrets, and loads
+    /// them. On failure the current settings and keys stay in use.
+    pub fn reconfigure(&self, config: &AuthConfig) -> Result<(), ConfigError> {
+        self.load(config.clone())
+    }
+
+    fn load(&self, config: AuthConfig) -> Result<(), ConfigError> {
+        match Keys::load(&config) {
+            Ok(keys) => {
+                *self.keys.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(keys);
+                *self.config.write().unwrap_or_else(|e| e.into_inner()) = config;
+                self.reloads.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            }
+            Err(e) => {
+                self.reload_failures.fetch_add(1, Ordering::Relaxed);
+                Err(e)
+            }
+        }
+    }
+
+    /// Successful and failed reloads since startup.
+    pub fn reload_counts(&self) -> (u64, u64) {
+        (self.reloads.load(Ordering::Relaxed), self.reload_failures.load(Ordering::Relaxed))
+    }
+
+    /// The key files `watch` polls.
+    pub fn watched_files(&self) -> Vec<PathBuf> {
+        let config = self.config.read().unwrap_or_else(|e| e.into_inner());
+        let jwt_keys = [
+            config.client_jwt.as_ref().and_then(|jwt| jwt.key_file.clone()),
+            config.internal_jwt.as_ref().and_then(|jwt| jwt.key_file.clone()),
+        ];
+        config.api_keys_file.iter().cloned().chain(jwt_keys.into_iter().flatten()).collect()
+    }
+
+    /// Polls the key files every `interval` and reloads when any of them
+    /// changes, logging each reload.
+    pub async fn watch(self: Arc<Self>, interval: Duration) {
+        let files = self.watched_files();
+        let stamps = || files.iter().map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok()).collect::<Vec<_>>();
+        let mut last = stamps();
+        loop {
+            tokio::time::sleep(interval).await;
+            let current = stamps();
+            if current == last {
+                continue;
+            }
+            last = current;
+            match self.reload() {
+                Ok(()) => println!("Reloaded auth keys (reload #{})", self.reload_counts().0),
+                Err(e) => syslog::error(format_args!("Auth key reload failed, keeping previous keys: {}", e)),
+            }
+        }
+    }
+
+    fn keys(&self) -> Arc<Keys> {
+        self.keys.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// The identity behind the request's bearer token: a static token, an
+    /// issued key, a token from `/token`, or a client JWT that verifies and
+    /// carries a `sub`. Without one, an OIDC session cookie.
+    pub async fn identify(&self, headers: &HeaderMap) -> Option<Identity> {
+        let keys = self.keys();
+        let static_user = match &keys.api_keys {
+            Some(api_keys) => bearer_token(headers).and_then(|token| api_keys.get(token)).map(String::as_str),
+            None => authenticated_user(headers),
+        };
+        if let Some(user) = static_user {
+            return Some(Identity {
+                user_id: user.to_string(),
+                scopes: Vec::new(),
+                claims: Map::new(),
+            });
+        }
+        let issued = match (&self.key_store, bearer_token(headers)) {
+            (Some(store), Some(token)) => store.verify(token).await,
+            _ => None,
+        };
+        if let Some(issued) = issued {
+            return Some(Identity {
+                user_id: issued.user,
+                scopes: issued.scopes,
+                claims: Map::new(),
+            });
+        }
+
+        match bearer_token(headers) {
+            Some(token) => jwt_identity(&keys, token),
+            None => self.oidc.as_ref()?.session(headers),
+        }
+    }
+
+    /// Replaces the configured claim headers in `headers` with the caller's
+    /// claims. Whatever the client sent under those names is dropped.
+    pub fn apply_claim_headers(&self, headers: &mut HeaderMap, identity: Option<&Identity>) {
+        for (claim, header) in &self.claim_headers {
+            headers.remove(header);
+            let value = identity
+                .and_then(|identity| identity.claim(claim))
+                .and_then(|value| HeaderValue::from_str(&value).ok());
+            if let Some(value) = value {
+                headers.insert(header.clone(), value);
+            }
+        }
+    }
+
+    /// A `Bearer` value carrying a freshly minted internal JWT for `identity`.
+    pub fn mint(&self, identity: &Identity) -> Result<HeaderValue, GatewayError> {
+        let token = self.sign(identity, None, None, None)?;
+        HeaderValue::from_str(&format!("Bearer {}", token))
+            .map_err(|_| GatewayError::Http("internal token is not a valid header value".to_string()))
+    }
+
+    /// A JWT for the client `client_id`, valid for `ttl_secs`, that this
+    /// gateway accepts as a bearer token while `/token` is enabled. Its `aud`
+    /// is the token endpoint's, not the internal tokens' one.
+    pub fn issue(&self, client_id: &str, identity: &Identity, ttl_secs: u64) -> Result<String, GatewayError> {
+        let audience = self
+            .keys()
+            .issued_audience
+            .clone()
+            .ok_or_else(|| GatewayError::Http("the token endpoint is not enabled".to_string()))?;
+        self.sign(identity, Some(ttl_secs), Some(&audience), Some(client_id))
+    }
+
+    /// `audience` overrides the internal key's one.
+    fn sign(
+        &self,
+        identity: &Identity,
+        ttl_secs: Option<u64>,
+        audience: Option<&str>,
+        client_id: Option<&str>,
+    ) -> Result<String, GatewayError> {
+        let keys = self.keys();
+        let signer = keys
+            .internal_jwt
+            .as_ref()
+            .ok_or_else(|| GatewayError::Http("no internal JWT key configured".to_string()))?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        let mut claims = json!({
+            "iss": signer.issuer,
+            "sub": identity.user_id,
+            "scope": identity.scopes.join(" "),
+            "iat": now,
+            "exp": now + ttl_secs.unwrap_or(signer.ttl_secs),
+        });
+        if let Some(audience) = audience.or(signer.audience.as_deref()) {
+            claims["aud"] = json!(audience);
+        }
+        if let Some(client_id) = client_id {
+            claims[CLIENT_ID_CLAIM] = json!(client_id);
+        }
+
+        jsonwebtoken::encode(&Header::new(signer.algorithm), &claims, &signer.key)
+            .map_err(|e| GatewayError::Http(format!("minting internal token: {}", e)))
+    }
+}
+
+/// A token from `/token`, or else a client JWT. A token carrying `client_id`
+/// is only accepted as one from `/token`, even when the client JWT key is the
+/// internal one.
+fn jwt_identity(keys: &Keys, token: &str) -> Option<Identity> {
+    if let Some((key, validation)) = &keys.issued_jwt {
+        let issued = jsonwebtoken::decode::<Map<String, Value>>(token, key, validation)
+            .ok()
+            .filter(|data| data.claims.contains_key(CLIENT_ID_CLAIM));
+        if let Some(data) = issued {
+            return claims_identity(data.claims);
+        }
+    }
+
+    let (key, validation) = keys.client_jwt.as_ref()?;
+    let claims = jsonwebtoken::decode::<Map<String, Value>>(token, key, validation)
+        .ok()?
+        .claims;
+    if claims.contains_key(CLIENT_ID_CLAIM) {
+        return None;
+    }
+    claims_identity(claims)
+}
+
+fn claims_identity(claims: Map<String, Value>) -> Option<Identity> {
+    Some(Identity {
+        user_id: claims.get("sub")?.as_str()?.to_string(),
+        scopes: scopes(&claims),
+        claims,
+    })
+}
+
+impl Keys {
+    fn load(config: &AuthConfig) -> Result<Self, ConfigError> {
+        let api_keys = match &config.api_keys_file {
+            Some(path) => {
+                let contents = std::fs::read_to_string(path)
+                    .map_err(|e| ConfigError::Invalid(format!("reading {}: {}", path.display(), e)))?;
+                Some(serde_json::from_str(&contents)
+                    .map_err(|e| ConfigError::Invalid(format!("{}: {}", path.display(), e)))?)
+            }
+            None => None,
+        };
+
+        let client_jwt = match &config.client_jwt {
+            Some(jwt) => {
+                let key = match jwt.algorithm {
+                    JwtAlgorithm::HS256 => Ok(DecodingKey::from_secret(secret(&jwt.secret)?.as_bytes())),
+                    JwtAlgorithm::RS256 => DecodingKey::from_rsa_pem(&read_key(&jwt.key, &jwt.key_file)?),
+                    JwtAlgorithm::ES256 => DecodingKey::from_ec_pem(&read_key(&jwt.key, &jwt.key_file)?),
+                }
+                .map_err(|e| ConfigError::Invalid(format!("auth.client_jwt: {}", e)))?;
+
+                let mut validation = Validation::new(algorithm(jwt.algorithm));
+                if let Some(issuer) = &jwt.issuer {
+                    validation.set_issuer(&[issuer]);
+                }
+                match &jwt.audience {
+                    Some(audience) => validation.set_audience(&[audience]),
+                    None => validation.validate_aud = false,
+                }
+                Some((key, validation))
+            }
+            None => None,
+        };
+
+        let internal_jwt = config
+            .internal_jwt
+            .as_ref()
+            .map(InternalSigner::from_config)
+            .transpose()?;
+        let issued_jwt = match (&config.token_endpoint, &config.internal_jwt) {
+            (Some(endpoint), Some(jwt)) => Some(issued_verifier(jwt, &endpoint.audience)?),
+            _ => None,
+        };
+        let issued_audience = config.token_endpoint.as_ref().map(|endpoint| endpoint.audience.clone());
+
+        Ok(Self { api_keys, client_jwt, internal_jwt, issued_jwt, issued_audience })
+    }
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers.get(AUTHORIZATION)?.to_str().ok()?.strip_prefix("Bearer ")
+}
+
+impl InternalSigner {
+    fn from_config(config: &InternalJwtConfig) -> Result<Self, ConfigError> {
+        let key = match config.algorithm {
+            JwtAlgorithm::HS256 => Ok(EncodingKey::from_secret(secret(&config.secret)?.as_bytes())),
+            JwtAlgorithm::RS256 => EncodingKey::from_rsa_pem(&read_key(&config.key, &config.key_file)?),
+            JwtAlgorithm::ES256 => EncodingKey::from_ec_pem(&read_key(&config.key, &config.key_file)?),
+        }
+        .map_err(|e| ConfigError::Invalid(format!("auth.internal_jwt: {}", e)))?;
+
+        Ok(Self {
+            key,
+            algorithm: algorithm(config.algorithm),
+            issuer: config.issuer.clone(),
+            audience: config.audience.clone(),
+            ttl_secs: config.ttl_secs,
+        })
+    }
+}
+
+/// Checks tokens signed with the internal key for `audience`. For RS256 and
+/// ES256 the public key is derived from the private one.
+fn issued_verifier(config: &InternalJwtConfig, audience: &str) -> Result<(DecodingKey, Validation), ConfigError> {
+    let key = match config.algorithm {
+        JwtAlgorithm::HS256 => DecodingKey::from_secret(secret(&config.secret)?.as_bytes()),
+        algorithm => public_key(algorithm, &read_key(&config.key, &config.key_file)?)
+            .map_err(|e| ConfigError::Invalid(format!("auth.internal_jwt: {}", e)))?,
+    };
+    let mut validation = Validation::new(self::algorithm(config.algorithm));
+    validation.set_issuer(&[&config.issuer]);
+    validation.set_audience(&[audience]);
+    Ok((key, validation))
+}
+
+/// The public half of a PEM private key: PKCS#8 for ES256, PKCS#8 or PKCS#1
+/// for RS256, as `EncodingKey` accepts them.
+fn public_key(algorithm: JwtAlgorithm, pem: &[u8]) -> Result<DecodingKey, String> {
+    let pem = std::str::from_utf8(pem).map_err(|_| "key is not PEM text".to_string())?;
+    let (label, der) = pem_block(pem).ok_or("key is not PEM")?;
+    let rejected = |e: ring::error::KeyRejected| format!("unusable private key: {}", e);
+    match (algorithm, label) {
+        (JwtAlgorithm::ES256, "PRIVATE KEY") => {
+            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &der, &SystemRandom::new())
+                .map(|pair| DecodingKey::from_ec_der(pair.public_key().as_ref()))
+                .map_err(rejected)
+        }
+        (JwtAlgorithm::RS256, "PRIVATE KEY") => RsaKeyPair::from_pkcs8(&der)
+            .map(|pair| DecodingKey::from_rsa_der(pair.public_key().as_ref()))
+            .map_err(rejected),
+        (JwtAlgorithm::RS256, "RSA PRIVATE KEY") => RsaKeyPair::from_der(&der)
+            .map(|pair| DecodingKey::from_rsa_der(pair.public_key().as_ref()))
+            .map_err(rejected),
+        (_, label) => Err(format!("a {} PEM block can't sign {:?}", label, algorithm)),
+    }
+}
+
+/// The label and decoded contents of the first PEM block.
+fn pem_block(pem: &str) -> Option<(&str, Vec<u8>)> {
+    let (_, rest) = pem.split_once("-----BEGIN ")?;
+    let (label, rest) = rest.split_once("-----")?;
+    let (body, _) = rest.split_once("-----END ")?;
+    let body: String = body.split_whitespace().collect();
+    Some((label, STANDARD.decode(body).ok()?))
+}
+
+fn algorithm(algorithm: JwtAlgorithm) -> Algorithm {
+    match algorithm {
+        JwtAlgorithm::HS256 => Algorithm::HS256,
+        JwtAlgorithm::RS256 => Algorithm::RS256,
+        JwtAlgorithm::ES256 => Algorithm::ES256,
+    }
+}
+
+fn secret(secret: &Option<String>) -> Result<&str, ConfigError> {
+    secret
+        .as_deref()
+        .ok_or_else(|| ConfigError::Invalid("HS256 needs a secret".to_string()))
+}
+
+/// The inline PEM `key`, or else the contents of `key_file`.
+fn read_key(key: &Option<String>, path: &Option<std::path::PathBuf>) -> Result<Vec<u8>, ConfigError> {
+    if let Some(key) = key {
+        return Ok(key.clone().into_bytes());
+    }
+    let path = path
+        .as_ref()
+        .ok_or_else(|| ConfigError::Invalid("a key or key_file is required".to_string()))?;
+    std::fs::read(path).map_err(|e| ConfigError::Invalid(format!("reading key {}: {}", path.display(), e)))
+}
+
+/// Scopes from a space-separated `scope` claim or a `scp`/`scopes` array.
+fn scopes(claims: &Map<String, Value>) -> Vec<String> {
+    if let Some(scope) = claims.get("scope").and_then(Value::as_str) {
+        return scope.split_whitespace().map(str::to_string).collect();
+    }
+    ["scp", "scopes"]
+        .iter()
+        .find_map(|name| claims.get(*name).and_then(Value::as_array))
+        .map(|values| values.iter().filter_map(Value::as_str).map(str::to_string).collect())
+        .unwrap_or_default()
+}
@@ -0,0 +1,264 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use bytes::Bytes;
+use hyper::{Body, Client, HeaderMap, Request, Uri, client::HttpConnector, header::{AUTHORIZATION, ETAG}};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use ring::{digest, hmac};
+use serde_json::Value;
+use tokio::time::timeout;
+use crate::config::REQUEST_TIMEOUT_SECS;
+
+#[cfg(test)]
+mod tests;
+
+/// A client for the gateway's own calls to Vault and AWS, which are usually
+/// served over TLS.
+pub type HttpsClient = Client<HttpsConnector<HttpConnector>>;
+
+pub fn https_client() -> HttpsClient {
+    let connector = HttpsConnectorBuilder::new()
+        .with_webpki_roots()
+        .https_or_http()
+        .enable_http1()
+        .build();
+    Client::builder().build(connector)
+}
+
+/// Sends a request and returns the body of a successful response.
+pub async fn send(client: &HttpsClient, request: Request<Body>) -> Result<Bytes, String> {
+    send_for_headers(client, request).await.map(|(_, body)| body)
+}
+
+/// Like `send`, also returning the response headers.
+async fn send_for_headers(client: &HttpsClient, request: Request<Body>) -> Result<(HeaderMap, Bytes), String> {
+    let response = timeout(Duration::from_secs(REQUEST_TIMEOUT_SECS), client.request(request))
+        .await
+        .map_err(|_| "request timed out".to_string())?
+        .map_err(|e| format!("request failed: {}", e))?;
+    let (parts, body) = response.into_parts();
+    let body = hyper::body::to_bytes(body)
+        .await
+        .map_err(|e| format!("reading response: {}", e))?;
+    if !parts.status.is_success() {
+        return Err(format!("answered {}", parts.status));
+    }
+    Ok((parts.headers, body))
+}
+
+/// Calls an action of an AWS JSON API such as Secrets Manager or KMS,
+/// signed with the credentials in the environment. `endpoint` replaces the
+/// service's regional endpoint.
+pub async fn call(
+    client: &HttpsClient,
+    service: &str,
+    region: &str,
+    endpoint: Option<&str>,
+    target: &str,
+    payload: &Value,
+) -> Result<Value, String> {
+    let credentials = AwsCredentials::from_env()?;
+    let endpoint = match endpoint {
+        Some(endpoint) => endpoint.to_string(),
+        None => format!("https://{}.{}.amazonaws.com/", service, region),
+    };
+    let uri: Uri = endpoint.parse().map_err(|_| format!("\"{}\" is not a valid endpoint", endpoint))?;
+    let host = uri.authority().ok_or_else(|| format!("endpoint \"{}\" has no host", endpoint))?.to_string();
+    let body = payload.to_string();
+    let amz_date = amz_date(SystemTime::now());
+
+    let mut headers = vec![
+        ("content-type", "application/x-amz-json-1.1".to_string()),
+        ("host", host),
+        ("x-amz-date", amz_date.clone()),
+        ("x-amz-target", target.to_string()),
+    ];
+    if let Some(token) = &credentials.session_token {
+        headers.push(("x-amz-security-token", token.clone()));
+    }
+    headers.sort();
+    let authorization = sign_v4(
+        &credentials,
+        &SigningScope { service, region, amz_date: &amz_date },
+        "POST",
+        uri.path(),
+        "",
+        &headers,
+        body.as_bytes(),
+    );
+
+    let mut request = Request::post(uri).header(AUTHORIZATION, authorization);
+    for (name, value) in &headers {
+        if *name != "host" {
+            request = request.header(*name, value.as_str());
+        }
+    }
+    let request = request.body(Body::from(body)).map_err(|e| format!("building request: {}", e))?;
+    let body = send(client, request).await?;
+    serde_json::from_slice(&body).map_err(|e| format!("invalid response: {}", e))
+}
+
+/// Reads an S3 object, signed with the credentials in the environment, and
+/// returns it with its version: the version ID in a versioned bucket and
+/// the ETag otherwise. With an `endpoint` the bucket is addressed by path,
+/// as S3-compatible stores expect.
+pub async fn get_object(
+    client: &HttpsClient,
+    region: &str,
+    endpoint: Option<&str>,
+    bucket: &str,
+    key: &str,
+) -> Result<(Bytes, String), String> {
+    let credentials = AwsCredentials::from_env()?;
+    let key: Vec<String> = key.split('/').map(uri_encode).collect();
+    let url = match endpoint {
+        Some(endpoint) => format!("{}/{}/{}", endpoint.trim_end_matches('/'), uri_encode(bucket), key.join("/")),
+        None => format!("https://{}.s3.{}.amazonaws.com/{}", bucket, region, key.join("/")),
+    };
+    let uri: Uri = url.parse().map_err(|_| format!("\"{}\" is not a valid S3 URL", url))?;
+    let host = uri.authority().ok_or_else(|| format!("S3 URL \"{}\" has no host", url))?.to_string();
+    let amz_date = amz_date(SystemTime::now());
+
+    let mut headers = vec![
+        ("host", host),
+        ("x-amz-content-sha256", hex(digest::digest(&digest::SHA256, b"").as_ref())),
+        ("x-amz-date", amz_date.clone()),
+    ];
+    if let Some(token) = &credentials.session_token {
+        headers.push(("x-amz-security-token", token.clone()));
+    }
+    headers.sort();
+    let authorization = sign_v4(
+        &credentials,
+        &SigningScope { service: "s3", region, amz_date: &amz_date },
+        "GET",
+        uri.path(),
+        "",
+        &headers,
+        b"",
+    );
+
+    let mut request = Request::get(uri).header(AUTHORIZATION, authorization);
+    for (name, value) in &headers {
+        if *name != "host" {
+            request = request.header(*name, value.as_str());
+        }
+    }
+    let request = request.body(Body::empty()).map_err(|e| format!("building request: {}", e))?;
+    let (headers, body) = send_for_headers(client, request).await?;
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok()).filter(|value| *value != "null");
+    let version = header("x-amz-version-id")
+        .or_else(|| header(ETAG.as_str()))
+        .map(|version| version.trim_matches('"').to_string())
+        .ok_or("S3 response has no ETag")?;
+    Ok((body, version))
+}
+
+/// Percent-encodes everything but the characters SigV4 leaves unreserved.
+fn uri_encode(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+struct AwsCredentials {
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+}
+
+impl AwsCredentials {
+    fn from_env() -> Result<Self, String> {
+        let var = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+        Ok(Self {
+            access_key_id: var("AWS_ACCESS_KEY_ID").ok_or("AWS_ACCESS_KEY_ID is not set")?,
+            secret_access_key: var("AWS_SECRET_ACCESS_KEY").ok_or("AWS_SECRET_ACCESS_KEY is not set")?,
+            session_token: var("AWS_SESSION_TOKEN"),
+        })
+    }
+}
+
+struct SigningScope<'a> {
+    service: &'a str,
+    region: &'a str,
+    /// `YYYYMMDDTHHMMSSZ`.
+    amz_date: &'a str,
+}
+
+/// The Signature Version 4 `Authorization` value for a request. `headers`
+/// are lowercase, sorted by name, and all of them are signed.
+fn sign_v4(
+    credentials: &AwsCredentials,
+    scope: &SigningScope,
+    method: &str,
+    path: &str,
+    query: &str,
+    headers: &[(&str, String)],
+    body: &[u8],
+) -> String {
+    let canonical_headers: String = headers.iter().map(|(name, value)| format!("{}:{}\n", name, value.trim())).collect();
+    let signed_headers = headers.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(";");
+    let canonical_request = format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        method,
+        path,
+        query,
+        canonical_headers,
+        signed_headers,
+        hex(digest::digest(&digest::SHA256, body).as_ref())
+    );
+
+    let date = &scope.amz_date[..8];
+    let credential_scope = format!("{}/{}/{}/aws4_request", date, scope.region, scope.service);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        scope.amz_date,
+        credential_scope,
+        hex(digest::digest(&digest::SHA256, canonical_request.as_bytes()).as_ref())
+    );
+    let key = signing_key(&credentials.secret_access_key, date, scope.region, scope.service);
+    let signature = hex(hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, &key), string_to_sign.as_bytes()).as_ref());
+    format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        credentials.access_key_id, credential_scope, signed_headers, signature
+    )
+}
+
+fn signing_key(secret_access_key: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    [date, region, service, "aws4_request"]
+        .iter()
+        .fold(format!("AWS4{}", secret_access_key).into_bytes(), |key, part| {
+            hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, &key), part.as_bytes()).as_ref().to_vec()
+        })
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// `YYYYMMDDTHHMMSSZ` in UTC.
+fn amz_date(at: SystemTime) -> String {
+    let secs = at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let (days, time) = ((secs / 86400) as i64, secs % 86400);
+    // Howard Hinnant's days-to-civil conversion.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
@@ -0,0 +1,34 @@
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+    use crate::aws::{amz_date, hex, sign_v4, signing_key, AwsCredentials, SigningScope};
+
+    #[test]
+    fn test_signature_v4() {
+        assert_eq!(amz_date(UNIX_EPOCH + Duration::from_secs(1440938160)), "20150830T123600Z");
+        assert_eq!(amz_date(UNIX_EPOCH + Duration::from_secs(951868799)), "20000229T235959Z");
+
+        // The example request from the AWS Signature Version 4 documentation.
+        let credentials = AwsCredentials {
+            access_key_id: "AKIDEXAMPLE".to_string(),
+            secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_string(),
+            session_token: None,
+        };
+        assert_eq!(
+            hex(&signing_key(&credentials.secret_access_key, "20150830", "us-east-1", "iam")),
+            "c4afb1cc5771d871763a393e44b703571b55cc28424d1a5e86da6ed3c154a4b9"
+        );
+        let headers = [
+            ("content-type", "application/x-www-form-urlencoded; charset=utf-8".to_string()),
+            ("host", "iam.amazonaws.com".to_string()),
+            ("x-amz-date", "20150830T123600Z".to_string()),
+        ];
+        let scope = SigningScope { service: "iam", region: "us-east-1", amz_date: "20150830T123600Z" };
+        assert_eq!(
+            sign_v4(&credentials, &scope, "GET", "/", "Action=ListUsers&Version=2010-05-08", &headers, b""),
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/iam/aws4_request, \
+             SignedHeaders=content-type;host;x-amz-date, \
+             Signature=5d672d79c15b13162d9279b0855cfba6789a8edb4c82c400e06b5924a6f2b5d7"
+        );
+    }
+}
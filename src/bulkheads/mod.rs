@@ -0,0 +1,212 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use serde_json::{json, Value};
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+use crate::config::{BulkheadConfig, Priority};
+use crate::errors::GatewayError;
+
+#[cfg(test)]
+mod tests;
+
+pub struct Bulkhead {
+    semaphore: Arc<Semaphore>,
+    max_concurrent: usize,
+    reserved_for_high: usize,
+    queue: Option<Queue>,
+}
+
+struct Queue {
+    max_waiting: usize,
+    timeout: Duration,
+    released: Arc<Notify>,
+    waiting: AtomicUsize,
+    stats: QueueStats,
+}
+
+/// Counters for requests that found the bulkhead full.
+#[derive(Default)]
+struct QueueStats {
+    admitted: AtomicU64,
+    rejected: AtomicU64,
+    timed_out: AtomicU64,
+    wait_micros: AtomicU64,
+    max_wait_micros: AtomicU64,
+}
+
+/// A slot in a bulkhead, released when dropped. Releasing wakes any queued
+/// requests so they can try for it.
+pub struct BulkheadPermit {
+    permit: Option<OwnedSemaphorePermit>,
+    released: Option<Arc<Notify>>,
+}
+
+impl Drop for BulkheadPermit {
+    fn drop(&mut self) {
+        drop(self.permit.take());
+        if let Some(released) = &self.released {
+            released.notify_waiters();
+        }
+    }
+}
+
+/// Keeps the queue depth right even if the waiting request is cancelled.
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Bulkhead {
+    pub fn new(config: &BulkheadConfig) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(config.max_concurrent)),
+            max_concurrent: config.max_concurrent,
+            reserved_for_high: config.reserved_for_high,
+            queue: config.queue.as_ref().map(|queue| Queue {
+                max_waiting: queue.max_waiting,
+                timeout: Duration::from_millis(queue.timeout_ms),
+                released: Arc::new(Notify::new()),
+                waiting: AtomicUsize::new(0),
+                stats: QueueStats::default(),
+            }),
+        }
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.max_concurrent - self.semaphore.available_permits()
+    }
+
+    /// Requests currently waiting for a slot.
+    pub fn queued(&self) -> usize {
+        self.queue.as_ref().map_or(0, |queue| queue.waiting.load(Ordering::Relaxed))
+    }
+
+    /// Takes a slot without waiting. Anything below high priority is refused
+    /// once only the reserved slots are left.
+    pub fn try_acquire(&self, priority: Priority) -> Option<BulkheadPermit> {
+        if priority != Priority::High && self.semaphore.available_permits() <= self.reserved_for_high {
+            return None;
+        }
+        let permit = self.semaphore.clone().try_acquire_owned().ok()?;
+        Some(BulkheadPermit {
+            permit: Some(permit),
+            released: self.queue.as_ref().map(|queue| queue.released.clone()),
+        })
+    }
+
+    /// Takes a slot, waiting in the queue if the bulkhead has one. Every
+    /// release wakes all waiters, so reserved slots freed for high priority
+    /// aren't handed to a low-priority request that happened to queue first.
+    pub async fn acquire(&self, priority: Priority) -> Option<BulkheadPermit> {
+        if let Some(permit) = self.try_acquire(priority) {
+            return Some(permit);
+        }
+        let queue = self.queue.as_ref()?;
+        let joined = queue
+            .waiting
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| (n < queue.max_waiting).then_some(n + 1));
+        if joined.is_err() {
+            queue.stats.rejected.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        let _waiting = Waiting(&queue.waiting);
+
+        let started = Instant::now();
+        let deadline = started + queue.timeout;
+        let permit = loop {
+            let released = queue.released.notified();
+            tokio::pin!(released);
+            // Register before checking, so a release in between isn't missed.
+            released.as_mut().enable();
+            if let Some(permit) = self.try_acquire(priority) {
+                break Some(permit);
+            }
+            if tokio::time::timeout_at(deadline, released).await.is_err() {
+                break None;
+            }
+        };
+
+        let waited = started.elapsed().as_micros() as u64;
+        if permit.is_some() {
+            queue.stats.admitted.fetch_add(1, Ordering::Relaxed);
+            queue.stats.wait_micros.fetch_add(waited, Ordering::Relaxed);
+            queue.stats.max_wait_micros.fetch_max(waited, Ordering::Relaxed);
+        } else {
+            queue.stats.timed_out.fetch_add(1, Ordering::Relaxed);
+        }
+        permit
+    }
+
+    /// Occupancy and, for queued bulkheads, queue depth and wait times.
+    pub fn status(&self) -> Value {
+        let mut status = json!({
+            "max_concurrent": self.max_concurrent,
+            "in_flight": self.in_flight(),
+        });
+        if let Some(queue) = &self.queue {
+            let admitted = queue.stats.admitted.load(Ordering::Relaxed);
+            let wait_micros = queue.stats.wait_micros.load(Ordering::Relaxed);
+            status["queue"] = json!({
+                "depth": queue.waiting.load(Ordering::Relaxed),
+                "max_waiting": queue.max_waiting,
+                "admitted": admitted,
+                "rejected": queue.stats.rejected.load(Ordering::Relaxed),
+                "timed_out": queue.stats.timed_out.load(Ordering::Relaxed),
+                "mean_wait_ms": if admitted == 0 { 0.0 } else { wait_micros as f64 / admitted as f64 / 1000.0 },
+                "max_wait_ms": queue.stats.max_wait_micros.load(Ordering::Relaxed) as f64 / 1000.0,
+            });
+        }
+        status
+    }
+}
+
+/// All configured bulkheads by name. Routes without a bulkhead are not limited.
+#[derive(Default)]
+pub struct Bulkheads {
+    bulkheads: HashMap<String, Bulkhead>,
+}
+
+impl Bulkheads {
+    pub fn from_config(config: &HashMap<String, BulkheadConfig>) -> Self {
+        Self {
+            bulkheads: config
+                .iter()
+                .map(|(name, bulkhead)| (name.clone(), Bulkhead::new(bulkhead)))
+                .collect(),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Bulkhead> {
+        self.bulkheads.get(name)
+    }
+
+    /// The returned permit must be held until the request has completed.
+    pub async fn acquire(
+        &self,
+        name: Option<&str>,
+        priority: Priority,
+    ) -> Result<Option<BulkheadPermit>, GatewayError> {
+        let Some(bulkhead) = name.and_then(|name| self.bulkheads.get(name)) else {
+            return Ok(None);
+        };
+        bulkhead
+            .acquire(priority)
+            .await
+            .map(Some)
+            .ok_or_else(|| GatewayError::BulkheadFull(name.unwrap_or_default().to_string()))
+    }
+
+    pub fn status(&self) -> Value {
+        let bulkheads: serde_json::Map<_, _> = self
+            .bulkheads
+            .iter()
+            .map(|(name, bulkhead)| (name.clone(), bulkhead.status()))
+            .collect();
+        json!({ "bulkheads": bulkheads })
+    }
+}
@@ -0,0 +1,103 @@
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use crate::bulkheads::Bulkheads;
+    use crate::config::{BulkheadConfig, BulkheadQueueConfig, Priority};
+    use crate::GatewayError;
+
+    fn bulkheads() -> Bulkheads {
+        let mut config = HashMap::new();
+        config.insert("batch".to_string(), BulkheadConfig { max_concurrent: 2, reserved_for_high: 0, queue: None });
+        config.insert("shared".to_string(), BulkheadConfig { max_concurrent: 3, reserved_for_high: 1, queue: None });
+        Bulkheads::from_config(&config)
+    }
+
+    #[tokio::test]
+    async fn test_bulkhead_limits_concurrency() {
+        let bulkheads = bulkheads();
+
+        let first = bulkheads.acquire(Some("batch"), Priority::Low).await.unwrap();
+        let _second = bulkheads.acquire(Some("batch"), Priority::Low).await.unwrap();
+        assert!(matches!(
+            bulkheads.acquire(Some("batch"), Priority::Low).await,
+            Err(GatewayError::BulkheadFull(name)) if name == "batch"
+        ));
+        assert_eq!(bulkheads.get("batch").unwrap().in_flight(), 2);
+
+        // Other bulkheads are unaffected by a full one.
+        assert!(bulkheads.acquire(Some("shared"), Priority::Normal).await.unwrap().is_some());
+
+        drop(first);
+        assert!(bulkheads.acquire(Some("batch"), Priority::Low).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_reserved_slots_for_high_priority() {
+        let bulkheads = bulkheads();
+
+        let _a = bulkheads.acquire(Some("shared"), Priority::Normal).await.unwrap();
+        let _b = bulkheads.acquire(Some("shared"), Priority::Low).await.unwrap();
+        assert!(bulkheads.acquire(Some("shared"), Priority::Normal).await.is_err());
+        let high = bulkheads.acquire(Some("shared"), Priority::High).await.unwrap();
+        assert!(high.is_some());
+        assert!(bulkheads.acquire(Some("shared"), Priority::High).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_routes_without_bulkhead_are_unlimited() {
+        let bulkheads = bulkheads();
+        assert!(bulkheads.acquire(None, Priority::Low).await.unwrap().is_none());
+    }
+
+    fn queued(max_waiting: usize, timeout_ms: u64) -> Bulkheads {
+        let mut config = HashMap::new();
+        config.insert(
+            "queued".to_string(),
+            BulkheadConfig {
+                max_concurrent: 1,
+                reserved_for_high: 0,
+                queue: Some(BulkheadQueueConfig { max_waiting, timeout_ms }),
+            },
+        );
+        Bulkheads::from_config(&config)
+    }
+
+    #[tokio::test]
+    async fn test_queued_request_gets_released_slot() {
+        let bulkheads = Arc::new(queued(1, 1_000));
+        let held = bulkheads.acquire(Some("queued"), Priority::Normal).await.unwrap();
+
+        let waiter = tokio::spawn({
+            let bulkheads = bulkheads.clone();
+            async move { bulkheads.acquire(Some("queued"), Priority::Normal).await.map(|p| p.is_some()) }
+        });
+        while bulkheads.get("queued").unwrap().queued() == 0 {
+            tokio::task::yield_now().await;
+        }
+        // The queue holds one: a second waiter is turned away at once.
+        assert!(bulkheads.acquire(Some("queued"), Priority::Normal).await.is_err());
+
+        drop(held);
+        assert!(waiter.await.unwrap().unwrap());
+        let status = bulkheads.status();
+        let queue = &status["bulkheads"]["queued"]["queue"];
+        assert_eq!(queue["depth"], 0);
+        assert_eq!(queue["admitted"], 1);
+        assert_eq!(queue["rejected"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_queued_request_times_out() {
+        let bulkheads = queued(4, 50);
+        let _held = bulkheads.acquire(Some("queued"), Priority::Normal).await.unwrap();
+
+        let started = tokio::time::Instant::now();
+        assert!(matches!(
+            bulkheads.acquire(Some("queued"), Priority::Normal).await,
+            Err(GatewayError::BulkheadFull(_))
+        ));
+        assert!(started.elapsed() >= std::time::Duration::from_millis(50));
+        assert_eq!(bulkheads.status()["bulkheads"]["queued"]["queue"]["timed_out"], 1);
+    }
+}
@@ -0,0 +1,151 @@
+use std::time::{Duration, Instant};
+use dashmap::DashMap;
+use hyper::{Body, HeaderMap, Request, Response, StatusCode};
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::time::timeout;
+use crate::aws::{self, HttpsClient};
+use crate::config::{ChallengeConfig, REQUEST_TIMEOUT_SECS};
+use crate::errors::{GatewayError, ERROR_CODE_HEADER};
+use crate::experiments::random;
+use crate::models::AppState;
+use crate::services::{client_ip, strikes};
+
+#[cfg(test)]
+mod tests;
+
+pub const CHALLENGE_HEADER: &str = "x-gateway-challenge";
+pub const SOLUTION_HEADER: &str = "x-gateway-challenge-solution";
+/// Outstanding challenges kept before expired ones are pruned.
+const MAX_PENDING_CHALLENGES: usize = 10_000;
+
+/// Makes clients with too many rate-limit strikes solve a challenge, such as
+/// a CAPTCHA, before they are let through again. Solutions are checked by an
+/// external verifier speaking the `siteverify` protocol shared by Turnstile,
+/// reCAPTCHA and hCaptcha.
+pub struct Challenges {
+    config: ChallengeConfig,
+    status: StatusCode,
+    /// The challenge token issued to each client IP.
+    pending: DashMap<String, Pending>,
+    client: HttpsClient,
+}
+
+struct Pending {
+    token: String,
+    expires_at: Instant,
+}
+
+impl Pending {
+    fn new(now: Instant, ttl_secs: u64) -> Self {
+        Self {
+            token: format!("{:016x}{:016x}", random(), random()),
+            expires_at: now + Duration::from_secs(ttl_secs),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Verdict {
+    success: bool,
+}
+
+impl Challenges {
+    pub fn new(config: ChallengeConfig) -> Self {
+        Self {
+            status: StatusCode::from_u16(config.status).unwrap_or(StatusCode::PRECONDITION_REQUIRED),
+            config,
+            pending: DashMap::new(),
+            client: aws::https_client(),
+        }
+    }
+
+    /// `None` lets the request through: the client isn't suspected, or it
+    /// just solved its challenge, which clears its strikes. Otherwise the
+    /// response to send is a fresh challenge.
+    pub async fn check(&self, state: &AppState, headers: &HeaderMap) -> Result<Option<Response<Body>>, GatewayError> {
+        if strikes(state, headers) < self.config.strikes {
+            return Ok(None);
+        }
+
+        let ip = client_ip(headers).to_string();
+        let token = headers.get(CHALLENGE_HEADER).and_then(|value| value.to_str().ok());
+        let solution = headers.get(SOLUTION_HEADER).and_then(|value| value.to_str().ok());
+        if let (Some(token), Some(solution)) = (token, solution) {
+            let issued = self
+                .pending
+                .get(&ip)
+                .is_some_and(|pending| pending.token == token && pending.expires_at > Instant::now());
+            if issued && self.verify(solution, &ip).await? {
+                self.pending.remove(&ip);
+                state.penalties.remove(&ip);
+                return Ok(None);
+            }
+        }
+        Ok(Some(self.challenge(ip)))
+    }
+
+    /// Reissues the client's outstanding token while it is valid, so
+    /// concurrent requests don't invalidate the one being solved.
+    fn challenge(&self, ip: String) -> Response<Body> {
+        let now = Instant::now();
+        if self.pending.len() >= MAX_PENDING_CHALLENGES {
+            self.pending.retain(|_, pending| pending.expires_at > now);
+        }
+        let token = self
+            .pending
+            .entry(ip)
+            .and_modify(|pending| {
+                if pending.expires_at <= now {
+                    *pending = Pending::new(now, self.config.token_ttl_secs);
+                }
+            })
+            .or_insert_with(|| Pending::new(now, self.config.token_ttl_secs))
+            .token
+            .clone();
+        let body = json!({
+            "error": "challenge_required",
+            "challenge": token,
+            "site_key": self.config.site_key,
+        });
+        let mut response = Response::new(Body::from(body.to_string()));
+        *response.status_mut() = self.status;
+        let headers = response.headers_mut();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        headers.insert(ERROR_CODE_HEADER, HeaderValue::from_static("challenge_required"));
+        if let Ok(value) = HeaderValue::from_str(&token) {
+            headers.insert(CHALLENGE_HEADER, value);
+        }
+        response
+    }
+
+    /// Asks the verifier whether `solution` is valid for this client.
+    async fn verify(&self, solution: &str, ip: &str) -> Result<bool, GatewayError> {
+        let form = form_urlencoded::Serializer::new(String::new())
+            .append_pair("secret", &self.config.secret)
+            .append_pair("response", solution)
+            .append_pair("remoteip", ip)
+            .finish();
+        let request = Request::post(self.config.verify_url.as_str())
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from(form))
+            .map_err(|e| GatewayError::Http(format!("building challenge verification: {}", e)))?;
+        let response = timeout(Duration::from_secs(REQUEST_TIMEOUT_SECS), self.client.request(request))
+            .await
+            .map_err(|_| GatewayError::Timeout)?
+            .map_err(|e| GatewayError::upstream(&e))?;
+        if !response.status().is_success() {
+            return Err(GatewayError::BadGateway(format!(
+                "challenge verifier answered {}",
+                response.status()
+            )));
+        }
+        let body = hyper::body::to_bytes(response.into_body())
+            .await
+            .map_err(|e| GatewayError::BadGateway(format!("reading challenge verdict: {}", e)))?;
+        let verdict: Verdict = serde_json::from_slice(&body)
+            .map_err(|e| GatewayError::BadGateway(format!("invalid challenge verdict: {}", e)))?;
+        Ok(verdict.success)
+    }
+}
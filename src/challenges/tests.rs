@@ -0,0 +1,87 @@
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use hyper::{HeaderMap, StatusCode};
+    use warp::Filter;
+    use crate::AppState;
+    use crate::challenges::Challenges;
+    use crate::config::{ChallengeConfig, TarpitConfig};
+    use crate::services::add_strike;
+
+    fn suspected_state() -> (AppState, HeaderMap) {
+        let mut state = AppState::new();
+        state.tarpit = Some(TarpitConfig::default());
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "203.0.113.7".parse().unwrap());
+        for _ in 0..3 {
+            add_strike(&state, &headers);
+        }
+        (state, headers)
+    }
+
+    fn challenges(verify_url: String) -> Challenges {
+        Challenges::new(ChallengeConfig {
+            verify_url,
+            secret: "s3cret".to_string(),
+            site_key: Some("site".to_string()),
+            strikes: 3,
+            token_ttl_secs: 300,
+            status: 428,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_only_suspected_clients_are_challenged() {
+        let (state, headers) = suspected_state();
+        let challenges = challenges("http://127.0.0.1:1/verify".to_string());
+
+        let mut innocent = HeaderMap::new();
+        innocent.insert("x-forwarded-for", "198.51.100.1".parse().unwrap());
+        assert!(challenges.check(&state, &innocent).await.unwrap().is_none());
+
+        let first = challenges.check(&state, &headers).await.unwrap().unwrap();
+        assert_eq!(first.status(), StatusCode::PRECONDITION_REQUIRED);
+        assert_eq!(first.headers()["x-gateway-error"], "challenge_required");
+        let token = first.headers()["x-gateway-challenge"].clone();
+        let body: serde_json::Value =
+            serde_json::from_slice(&hyper::body::to_bytes(first.into_body()).await.unwrap()).unwrap();
+        assert_eq!(body["challenge"], token.to_str().unwrap());
+        assert_eq!(body["site_key"], "site");
+
+        let again = challenges.check(&state, &headers).await.unwrap().unwrap();
+        assert_eq!(again.headers()["x-gateway-challenge"], token, "an outstanding token is reissued");
+    }
+
+    #[tokio::test]
+    async fn test_verified_solution_restores_access() {
+        let verifier = warp::path("verify")
+            .and(warp::post())
+            .and(warp::body::form())
+            .map(|form: HashMap<String, String>| {
+                assert_eq!(form["secret"], "s3cret");
+                assert_eq!(form["remoteip"], "203.0.113.7");
+                warp::reply::json(&serde_json::json!({ "success": form["response"] == "solved" }))
+            });
+        let (addr, server) = warp::serve(verifier).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let (state, mut headers) = suspected_state();
+        let challenges = challenges(format!("http://{}/verify", addr));
+        let challenge = challenges.check(&state, &headers).await.unwrap().unwrap();
+        headers.insert("x-gateway-challenge", challenge.headers()["x-gateway-challenge"].clone());
+
+        headers.insert("x-gateway-challenge-solution", "wrong".parse().unwrap());
+        assert!(challenges.check(&state, &headers).await.unwrap().is_some());
+
+        headers.insert("x-gateway-challenge-solution", "solved".parse().unwrap());
+        assert!(challenges.check(&state, &headers).await.unwrap().is_none());
+        assert!(state.penalties.is_empty());
+
+        let mut forged = headers.clone();
+        forged.insert("x-gateway-challenge", "forged".parse().unwrap());
+        for _ in 0..3 {
+            add_strike(&state, &forged);
+        }
+        assert!(challenges.check(&state, &forged).await.unwrap().is_some(), "only issued tokens are accepted");
+    }
+}
@@ -0,0 +1,489 @@
+use std::fmt::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use hyper::{Body, Method, Request, header::{AUTHORIZATION, CONTENT_TYPE}};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use tokio::sync::Mutex;
+use crate::auth::Authenticator;
+use crate::aws::{self, HttpsClient};
+use crate::config::{has_errors, ClusterConfig, ClusterSource, ConfigSource, GatewayConfig, LeaderElectionConfig, Severity, WebhookEvent, CLUSTER_FIELDS};
+use crate::persistence::{Persistence, ROUTES};
+use crate::routes::{LiveRouteTable, RouteTable};
+use crate::secrets::Secrets;
+use crate::webhooks::Webhooks;
+use crate::syslog;
+
+#[cfg(test)]
+mod tests;
+
+pub const CONSUL_TOKEN_ENV: &str = "CONSUL_HTTP_TOKEN";
+const CONSUL_TOKEN_HEADER: &str = "x-consul-token";
+const CONSUL_SESSION_NAME: &str = "api-gateway-leader";
+pub const CLUSTER_LEADER: &str = "gateway_cluster_leader";
+
+/// Which version of the shared configuration an instance runs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ConfigVersion {
+    /// `consul`, `etcd` or `s3`.
+    pub source: &'static str,
+    /// The store's own version: Consul's modify index, etcd's mod revision,
+    /// or the S3 object's version ID or ETag.
+    pub version: String,
+    /// When it was applied, in Unix seconds.
+    pub applied_at: u64,
+}
+
+struct Snapshot {
+    config: GatewayConfig,
+    shared: Map<String, Value>,
+    version: Option<ConfigVersion>,
+}
+
+/// The configuration the gateway runs, with secret references unresolved:
+/// the file's, with a cluster's shared settings in place of its own once
+/// one has been applied.
+pub struct RunningConfig {
+    source: Option<ConfigSource>,
+    current: RwLock<Arc<Snapshot>>,
+}
+
+impl RunningConfig {
+    pub fn new(config: GatewayConfig, source: Option<ConfigSource>) -> Self {
+        let snapshot = Snapshot { config, shared: Map::new(), version: None };
+        Self { source, current: RwLock::new(Arc::new(snapshot)) }
+    }
+
+    pub fn config(&self) -> GatewayConfig {
+        self.current.read().unwrap_or_else(|e| e.into_inner()).config.clone()
+    }
+
+    pub fn version(&self) -> Option<ConfigVersion> {
+        self.current.read().unwrap_or_else(|e| e.into_inner()).version.clone()
+    }
+
+    /// What `/admin/config` shows: the effective configuration, with the
+    /// settings taken from the cluster sourced as `"cluster"`, and the
+    /// version applied.
+    pub fn view(&self) -> Value {
+        let snapshot = self.current.read().unwrap_or_else(|e| e.into_inner()).clone();
+        let mut view = snapshot.config.effective(self.source.as_ref());
+        if let Some(sources) = view["sources"].as_object_mut() {
+            for (location, source) in sources.iter_mut() {
+                let section = location.split(['.', '[']).next().unwrap_or_default();
+                if snapshot.shared.contains_key(section) {
+                    *source = Value::from("cluster");
+                }
+            }
+        }
+        view["version"] = json!(snapshot.version);
+        view
+    }
+
+    /// The configuration file with a shared document's settings in place of
+    /// its own. The document may only set `CLUSTER_FIELDS`.
+    pub fn overlay(&self, shared: &Value) -> Result<GatewayConfig, String> {
+        let shared = shared.as_object().ok_or("cluster configuration is not a JSON object")?;
+        if let Some(field) = shared.keys().find(|field| !CLUSTER_FIELDS.contains(&field.as_str())) {
+            return Err(format!("cluster configuration cannot set {}", field));
+        }
+        let mut file = match &self.source {
+            Some(source) => source.contents.clone(),
+            None => Value::Object(Map::new()),
+        };
+        if let Value::Object(sections) = &mut file {
+            sections.remove("include");
+            sections.extend(shared.clone());
+        }
+        GatewayConfig::from_json(&file.to_string()).map_err(|e| e.to_string())
+    }
+
+    fn replace(&self, config: GatewayConfig, shared: Map<String, Value>, version: ConfigVersion) {
+        *self.current.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(Snapshot { config, shared, version: Some(version) });
+    }
+}
+
+/// Keeps an instance on the configuration its cluster shares: polls the
+/// store and applies each new version that validates to the routes, the
+/// auth keys and the running configuration.
+pub struct ClusterSync {
+    source: ClusterSource,
+    interval: Duration,
+    client: HttpsClient,
+    running: Arc<RunningConfig>,
+    secrets: Arc<Secrets>,
+    authenticator: Arc<Authenticator>,
+    route_table: Arc<LiveRouteTable>,
+    webhooks: Option<Arc<Webhooks>>,
+    persistence: Option<Arc<Persistence>>,
+}
+
+impl ClusterSync {
+    pub fn new(
+        config: &ClusterConfig,
+        running: Arc<RunningConfig>,
+        secrets: Arc<Secrets>,
+        authenticator: Arc<Authenticator>,
+        route_table: Arc<LiveRouteTable>,
+    ) -> Self {
+        Self {
+            source: config.source.clone(),
+            interval: Duration::from_secs(config.poll_interval_secs),
+            client: aws::https_client(),
+            running,
+            secrets,
+            authenticator,
+            route_table,
+            webhooks: None,
+            persistence: None,
+        }
+    }
+
+    /// Announces each version applied after startup as `config_reloaded`.
+    pub fn with_webhooks(mut self, webhooks: Arc<Webhooks>) -> Self {
+        self.webhooks = Some(webhooks);
+        self
+    }
+
+    /// Keeps each version applied in the database, for `restore` to start
+    /// from when the store can't be reached.
+    pub fn with_persistence(mut self, persistence: Arc<Persistence>) -> Self {
+        self.persistence = Some(persistence);
+        self
+    }
+
+    /// Fetches the shared configuration and applies it if its version is
+    /// new. Returns whether it was applied.
+    pub async fn sync(&self) -> Result<bool, String> {
+        let (document, version) = self.fetch().await?;
+        let current = self.running.version();
+        if current.is_some_and(|current| current.version == version) {
+            return Ok(false);
+        }
+        let shared: Value = serde_json::from_slice(&document).map_err(|e| format!("invalid cluster configuration: {}", e))?;
+        let applied_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        self.apply(&shared, ConfigVersion { source: self.source_name(), version: version.clone(), applied_at }).await?;
+        if let Some(persistence) = &self.persistence {
+            persistence.save(ROUTES, self.source_name(), &json!({ "version": version, "applied_at": applied_at, "config": shared }));
+        }
+        Ok(true)
+    }
+
+    /// Applies the version last kept in the database, when there is one.
+    /// Returns whether there was.
+    pub async fn restore(&self) -> Result<bool, String> {
+        let Some(persistence) = &self.persistence else {
+            return Ok(false);
+        };
+        let saved = persistence.load(ROUTES).await?.into_iter().find(|(source, _)| source == self.source_name());
+        let Some((_, saved)) = saved else {
+            return Ok(false);
+        };
+        let (Some(version), Some(applied_at)) = (saved["version"].as_str(), saved["applied_at"].as_u64()) else {
+            return Err(format!("saved {} configuration has no version", self.source_name()));
+        };
+        let version = ConfigVersion { source: self.source_name(), version: version.to_string(), applied_at };
+        self.apply(&saved["config"], version).await?;
+        Ok(true)
+    }
+
+    /// Validates a shared document and puts it into effect.
+    async fn apply(&self, shared: &Value, version: ConfigVersion) -> Result<(), String> {
+        let config = self.running.overlay(shared)?;
+        let diagnostics = config.validate();
+        if has_errors(&diagnostics) {
+            let errors: Vec<String> = diagnostics
+                .iter()
+                .filter(|diagnostic| diagnostic.severity == Severity::Error)
+                .map(ToString::to_string)
+                .collect();
+            return Err(format!("cluster configuration {} is invalid: {}", version.version, errors.join("; ")));
+        }
+        let resolved = self.secrets.resolve(&config).await?;
+        let route_table = RouteTable::from_config(&resolved).map_err(|e| e.to_string())?;
+        self.authenticator.reconfigure(&resolved.auth).map_err(|e| e.to_string())?;
+        self.route_table.store(route_table);
+
+        let shared = shared.as_object().cloned().unwrap_or_default();
+        self.running.replace(config, shared, version);
+        Ok(())
+    }
+
+    /// Syncs every `poll_interval_secs`. A version that can't be fetched or
+    /// applied leaves the instance on the one it has.
+    pub async fn watch(self: Arc<Self>) {
+        loop {
+            tokio::time::sleep(self.interval).await;
+            match self.sync().await {
+                Ok(true) => {
+                    let version = self.running.version().map(|version| version.version).unwrap_or_default();
+                    println!("Applied cluster configuration version {}", version);
+                    if let Some(webhooks) = &self.webhooks {
+                        let text = format!("Applied cluster configuration version {} from {}", version, self.source_name());
+                        let details = json!({ "source": self.source_name(), "version": version });
+                        webhooks.notify(WebhookEvent::ConfigReloaded, text, details);
+                    }
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    let version = self.running.version().map_or("local".to_string(), |version| version.version);
+                    syslog::error(format_args!("Cluster configuration sync failed, keeping version {}: {}", version, e));
+                }
+            }
+        }
+    }
+
+    fn source_name(&self) -> &'static str {
+        match self.source {
+            ClusterSource::Consul { .. } => "consul",
+            ClusterSource::Etcd { .. } => "etcd",
+            ClusterSource::S3 { .. } => "s3",
+        }
+    }
+
+    /// The shared document and its version.
+    async fn fetch(&self) -> Result<(Vec<u8>, String), String> {
+        match &self.source {
+            ClusterSource::Consul { address, key, token_file } => {
+                let url = format!("{}/v1/kv/{}", address.trim_end_matches('/'), key.trim_start_matches('/'));
+                let token = consul_token(token_file.as_deref()).await?;
+                let response = consul(&self.client, Method::GET, &url, token.as_deref(), Body::empty()).await?;
+                let entry = &response[0];
+                let version = entry["ModifyIndex"].as_u64().ok_or("Consul response has no ModifyIndex")?;
+                let value = decode(&entry["Value"]).ok_or_else(|| format!("Consul key \"{}\" has no value", key))?;
+                Ok((value, version.to_string()))
+            }
+            ClusterSource::Etcd { address, key, username, password } => {
+                let address = address.trim_end_matches('/');
+                let token = etcd_token(&self.client, address, username.as_deref(), password.as_deref()).await?;
+                let range = json!({ "key": STANDARD.encode(key) });
+                let response = etcd(&self.client, &format!("{}/v3/kv/range", address), &range, token.as_deref()).await?;
+                let entry = &response["kvs"][0];
+                let value = decode(&entry["value"]).ok_or_else(|| format!("etcd key \"{}\" has no value", key))?;
+                let version = int64(&entry["mod_revision"]).ok_or("etcd response has no mod_revision")?;
+                Ok((value, version))
+            }
+            ClusterSource::S3 { bucket, key, region, endpoint } => {
+                let (body, version) = aws::get_object(&self.client, region, endpoint.as_deref(), bucket, key)
+                    .await
+                    .map_err(|e| format!("S3 {}", e))?;
+                Ok((body.to_vec(), version))
+            }
+        }
+    }
+}
+
+/// Picks one instance of a cluster to run the jobs that should run once
+/// for the whole cluster rather than on every instance. Leadership is a
+/// lock in Consul (a session) or etcd (a lease) that the leader keeps
+/// renewing; an instance that can't reach the store steps down at once.
+pub struct LeaderElection {
+    source: ClusterSource,
+    key: String,
+    ttl: Duration,
+    instance: String,
+    client: HttpsClient,
+    /// The Consul session or etcd lease this instance holds.
+    lease: Mutex<Option<String>>,
+    leader: AtomicBool,
+}
+
+impl LeaderElection {
+    pub fn new(cluster: &ClusterConfig, config: &LeaderElectionConfig) -> Result<Self, String> {
+        let mut instance = [0u8; 8];
+        SystemRandom::new().fill(&mut instance).map_err(|_| "no randomness available".to_string())?;
+        Ok(Self {
+            source: cluster.source.clone(),
+            key: config.key.clone(),
+            ttl: Duration::from_secs(config.ttl_secs),
+            instance: aws::hex(&instance),
+            client: aws::https_client(),
+            lease: Mutex::new(None),
+            leader: AtomicBool::new(false),
+        })
+    }
+
+    /// Whether this instance should run cluster-wide jobs right now.
+    pub fn is_leader(&self) -> bool {
+        self.leader.load(Ordering::Relaxed)
+    }
+
+    /// Campaigns for leadership, and renews it once held, every third of
+    /// the TTL.
+    pub async fn campaign(self: Arc<Self>) {
+        loop {
+            let was_leader = self.is_leader();
+            let leader = match self.elect().await {
+                Ok(leader) => leader,
+                Err(e) => {
+                    syslog::error(format_args!("Leader election failed: {}", e));
+                    false
+                }
+            };
+            if leader != was_leader {
+                if leader {
+                    println!("This instance is now the cluster leader");
+                } else {
+                    println!("This instance is no longer the cluster leader");
+                }
+            }
+            tokio::time::sleep(self.ttl / 3).await;
+        }
+    }
+
+    /// One round of the election: renews this instance's lease, or takes a
+    /// new one, and tries to take the lock with it. Returns whether this
+    /// instance leads. On failure the lease is given up and it doesn't.
+    pub async fn elect(&self) -> Result<bool, String> {
+        let mut lease = self.lease.lock().await;
+        let result = match &self.source {
+            ClusterSource::Consul { address, token_file, .. } => self.elect_consul(&mut lease, address, token_file.as_deref()).await,
+            ClusterSource::Etcd { address, username, password, .. } => {
+                self.elect_etcd(&mut lease, address, username.as_deref(), password.as_deref()).await
+            }
+            ClusterSource::S3 { .. } => Err("leader election needs a consul or etcd source".to_string()),
+        };
+        if result.is_err() {
+            *lease = None;
+        }
+        let leader = result.as_ref().is_ok_and(|leader| *leader);
+        self.leader.store(leader, Ordering::Relaxed);
+        result
+    }
+
+    /// Metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "# HELP {} Whether this instance is the cluster leader.", CLUSTER_LEADER);
+        let _ = writeln!(out, "# TYPE {} gauge", CLUSTER_LEADER);
+        let _ = writeln!(out, "{} {}", CLUSTER_LEADER, u8::from(self.is_leader()));
+        out
+    }
+
+    /// Consul: a session with the TTL that deletes the lock when it
+    /// expires, and `acquire` on the lock key, which succeeds again for the
+    /// session already holding it.
+    async fn elect_consul(&self, lease: &mut Option<String>, address: &str, token_file: Option<&Path>) -> Result<bool, String> {
+        let address = address.trim_end_matches('/');
+        let token = consul_token(token_file).await?;
+        let session = match lease.as_deref() {
+            Some(session) => {
+                let url = format!("{}/v1/session/renew/{}", address, session);
+                consul(&self.client, Method::PUT, &url, token.as_deref(), Body::empty()).await?;
+                session.to_string()
+            }
+            None => {
+                let session = json!({ "Name": CONSUL_SESSION_NAME, "TTL": format!("{}s", self.ttl.as_secs()), "Behavior": "delete" });
+                let url = format!("{}/v1/session/create", address);
+                let response = consul(&self.client, Method::PUT, &url, token.as_deref(), Body::from(session.to_string())).await?;
+                let session = response["ID"].as_str().ok_or("Consul returned no session ID")?.to_string();
+                *lease = Some(session.clone());
+                session
+            }
+        };
+        let url = format!("{}/v1/kv/{}?acquire={}", address, self.key.trim_start_matches('/'), session);
+        let response = consul(&self.client, Method::PUT, &url, token.as_deref(), Body::from(self.instance.clone())).await?;
+        response.as_bool().ok_or_else(|| "Consul acquire returned no result".to_string())
+    }
+
+    /// etcd: a lease with the TTL, kept alive, and a transaction that puts
+    /// the lock key under it only if the key doesn't exist yet.
+    async fn elect_etcd(
+        &self,
+        lease: &mut Option<String>,
+        address: &str,
+        username: Option<&str>,
+        password: Option<&str>,
+    ) -> Result<bool, String> {
+        let address = address.trim_end_matches('/');
+        let token = etcd_token(&self.client, address, username, password).await?;
+        let id = match lease.as_deref() {
+            Some(id) => {
+                let response = etcd(&self.client, &format!("{}/v3/lease/keepalive", address), &json!({ "ID": id }), token.as_deref()).await?;
+                // An expired lease is answered with no TTL.
+                if int64(&response["result"]["TTL"]).is_none_or(|ttl| ttl == "0") {
+                    return Err(format!("etcd lease {} expired", id));
+                }
+                id.to_string()
+            }
+            None => {
+                let grant = json!({ "TTL": self.ttl.as_secs() });
+                let response = etcd(&self.client, &format!("{}/v3/lease/grant", address), &grant, token.as_deref()).await?;
+                let id = int64(&response["ID"]).ok_or("etcd returned no lease ID")?;
+                *lease = Some(id.clone());
+                id
+            }
+        };
+        let key = STANDARD.encode(&self.key);
+        let txn = json!({
+            "compare": [{ "key": key, "target": "CREATE", "result": "EQUAL", "create_revision": "0" }],
+            "success": [{ "request_put": { "key": key, "value": STANDARD.encode(&self.instance), "lease": id } }],
+            "failure": [{ "request_range": { "key": key } }],
+        });
+        let response = etcd(&self.client, &format!("{}/v3/kv/txn", address), &txn, token.as_deref()).await?;
+        if response["succeeded"].as_bool() == Some(true) {
+            return Ok(true);
+        }
+        let holder = &response["responses"][0]["response_range"]["kvs"][0];
+        Ok(int64(&holder["lease"]).as_deref() == Some(id.as_str()))
+    }
+}
+
+/// The Consul ACL token, read from `token_file` or else `CONSUL_HTTP_TOKEN`.
+async fn consul_token(token_file: Option<&Path>) -> Result<Option<String>, String> {
+    match token_file {
+        Some(token_file) => tokio::fs::read_to_string(token_file)
+            .await
+            .map(|token| Some(token.trim().to_string()))
+            .map_err(|e| format!("reading Consul token {}: {}", token_file.display(), e)),
+        None => Ok(std::env::var(CONSUL_TOKEN_ENV).ok().filter(|token| !token.is_empty())),
+    }
+}
+
+async fn consul(client: &HttpsClient, method: Method, url: &str, token: Option<&str>, body: Body) -> Result<Value, String> {
+    let mut request = Request::builder().method(method).uri(url);
+    if let Some(token) = token {
+        request = request.header(CONSUL_TOKEN_HEADER, token);
+    }
+    let request = request.body(body).map_err(|e| format!("building Consul request: {}", e))?;
+    let body = aws::send(client, request).await.map_err(|e| format!("Consul {}", e))?;
+    serde_json::from_slice(&body).map_err(|e| format!("invalid Consul response: {}", e))
+}
+
+/// A token for etcd's auth, if a username and password are configured.
+async fn etcd_token(client: &HttpsClient, address: &str, username: Option<&str>, password: Option<&str>) -> Result<Option<String>, String> {
+    let (Some(username), Some(password)) = (username, password) else {
+        return Ok(None);
+    };
+    let credentials = json!({ "name": username, "password": password });
+    let response = etcd(client, &format!("{}/v3/auth/authenticate", address), &credentials, None).await?;
+    Ok(Some(response["token"].as_str().ok_or("etcd authentication returned no token")?.to_string()))
+}
+
+async fn etcd(client: &HttpsClient, url: &str, payload: &Value, token: Option<&str>) -> Result<Value, String> {
+    let mut request = Request::post(url).header(CONTENT_TYPE, "application/json");
+    if let Some(token) = token {
+        request = request.header(AUTHORIZATION, token);
+    }
+    let request = request.body(Body::from(payload.to_string())).map_err(|e| format!("building etcd request: {}", e))?;
+    let body = aws::send(client, request).await.map_err(|e| format!("etcd {}", e))?;
+    serde_json::from_slice(&body).map_err(|e| format!("invalid etcd response: {}", e))
+}
+
+/// An etcd 64-bit integer, which its JSON gateway writes as a string.
+fn int64(value: &Value) -> Option<String> {
+    match value {
+        Value::String(value) => Some(value.clone()),
+        Value::Number(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+fn decode(value: &Value) -> Option<Vec<u8>> {
+    value.as_str().and_then(|value| STANDARD.decode(value).ok())
+}
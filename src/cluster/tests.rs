@@ -0,0 +1,259 @@
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD;
+    use serde_json::{json, Value};
+    use warp::Filter;
+    use crate::auth::Authenticator;
+    use crate::cluster::{ClusterSync, LeaderElection, RunningConfig};
+    use crate::config::{ClusterConfig, ClusterSource, ConfigSource, GatewayConfig, LeaderElectionConfig, PersistenceConfig, SecretsConfig};
+    use crate::persistence::Persistence;
+    use crate::routes::{LiveRouteTable, RouteTable};
+    use crate::secrets::Secrets;
+
+    const FILE: &str = r#"{
+        "listen_addr": "127.0.0.1:4040",
+        "routes": [{ "name": "local", "path_prefix": "/local", "upstream": "http://local:8080" }]
+    }"#;
+
+    fn cluster(source: ClusterSource) -> (ClusterSync, Arc<RunningConfig>, Arc<LiveRouteTable>) {
+        let contents: Value = serde_json::from_str(FILE).unwrap();
+        let config = GatewayConfig::from_json(FILE).unwrap();
+        let route_table = Arc::new(LiveRouteTable::new(RouteTable::from_config(&config).unwrap()));
+        let authenticator = Arc::new(Authenticator::from_config(&config.auth).unwrap());
+        let source_file = ConfigSource { path: PathBuf::from("gateway.json"), from_env: false, contents };
+        let running = Arc::new(RunningConfig::new(config, Some(source_file)));
+        let sync = ClusterSync::new(
+            &ClusterConfig { source, poll_interval_secs: 1, leader_election: None },
+            running.clone(),
+            Arc::new(Secrets::new(&SecretsConfig::default())),
+            authenticator,
+            route_table.clone(),
+        );
+        (sync, running, route_table)
+    }
+
+    fn route_names(route_table: &LiveRouteTable) -> Vec<String> {
+        route_table.load().routes().iter().map(|route| route.name.clone()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_sync_from_consul() {
+        let shared = Arc::new(Mutex::new((7, json!({
+            "routes": [{ "name": "orders", "path_prefix": "/orders", "upstream": "http://orders:8080" }]
+        }))));
+        let store = shared.clone();
+        let consul = warp::path!("v1" / "kv" / "gateway" / "config")
+            .and(warp::header::<String>("x-consul-token"))
+            .map(move |token: String| {
+                assert_eq!(token, "consul-token");
+                let (index, document) = store.lock().unwrap().clone();
+                let value = STANDARD.encode(document.to_string());
+                warp::reply::json(&json!([{ "Key": "gateway/config", "Value": value, "ModifyIndex": index }]))
+            });
+        let (addr, server) = warp::serve(consul).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let token_file = std::env::temp_dir().join(format!("api-gateway-consul-token-{}", std::process::id()));
+        std::fs::write(&token_file, "consul-token\n").unwrap();
+        let (sync, running, route_table) = cluster(ClusterSource::Consul {
+            address: format!("http://{}/", addr),
+            key: "gateway/config".to_string(),
+            token_file: Some(token_file.clone()),
+        });
+
+        assert!(sync.sync().await.unwrap());
+        assert_eq!(route_names(&route_table), ["orders"]);
+        let view = running.view();
+        assert_eq!((view["version"]["source"].as_str(), view["version"]["version"].as_str()), (Some("consul"), Some("7")));
+        assert_eq!(view["config"]["listen_addr"], "127.0.0.1:4040");
+        assert_eq!((view["sources"]["listen_addr"].as_str(), view["sources"]["routes[0].upstream"].as_str()), (Some("file"), Some("cluster")));
+        // An unchanged version isn't applied again.
+        assert!(!sync.sync().await.unwrap());
+
+        *shared.lock().unwrap() = (8, json!({ "routes": [{ "path_prefix": "orders", "upstream": "http://orders:8080" }] }));
+        let error = sync.sync().await.unwrap_err();
+        assert!(error.starts_with("cluster configuration 8 is invalid: "), "{}", error);
+        *shared.lock().unwrap() = (9, json!({ "listen_addr": "0.0.0.0:80" }));
+        assert_eq!(sync.sync().await.unwrap_err(), "cluster configuration cannot set listen_addr");
+        assert_eq!(route_names(&route_table), ["orders"]);
+        assert_eq!(running.version().unwrap().version, "7");
+        let _ = std::fs::remove_file(token_file);
+    }
+
+    #[tokio::test]
+    async fn test_saved_routes_are_restored_without_the_store() {
+        let consul = warp::path!("v1" / "kv" / "gateway" / "config").map(|| {
+            let document = json!({ "routes": [{ "name": "orders", "path_prefix": "/orders", "upstream": "http://orders:8080" }] });
+            warp::reply::json(&json!([{ "Key": "gateway/config", "Value": STANDARD.encode(document.to_string()), "ModifyIndex": 7 }]))
+        });
+        let (addr, server) = warp::serve(consul).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let path = std::env::temp_dir().join(format!("api-gateway-cluster-routes-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let config = PersistenceConfig { url: format!("sqlite://{}", path.display()), ..PersistenceConfig::default() };
+        let persistence = Persistence::open(&config).await.unwrap();
+        let consul = |address: String| ClusterSource::Consul { address, key: "gateway/config".to_string(), token_file: None };
+
+        let (sync, _, _) = cluster(consul(format!("http://{}", addr)));
+        let sync = sync.with_persistence(persistence.clone());
+        assert!(sync.sync().await.unwrap());
+        persistence.flush().await;
+
+        // A restart while the store is down.
+        let unreachable = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let (sync, running, route_table) = cluster(consul(format!("http://{}", unreachable)));
+        let sync = sync.with_persistence(persistence);
+        assert!(sync.sync().await.is_err());
+        assert!(sync.restore().await.unwrap());
+        assert_eq!(route_names(&route_table), ["orders"]);
+        assert_eq!(running.version().unwrap().version, "7");
+    }
+
+    #[tokio::test]
+    async fn test_sync_from_etcd() {
+        let login = warp::path!("v3" / "auth" / "authenticate").and(warp::body::json()).map(|credentials: Value| {
+            assert_eq!(credentials, json!({ "name": "gateway", "password": "etcd-password" }));
+            warp::reply::json(&json!({ "token": "etcd-token" }))
+        });
+        let range = warp::path!("v3" / "kv" / "range")
+            .and(warp::header::<String>("authorization"))
+            .and(warp::body::json())
+            .map(|token: String, request: Value| {
+                assert_eq!(token, "etcd-token");
+                assert_eq!(request["key"], STANDARD.encode("/gateway/config"));
+                let document = json!({
+                    "routes": [{ "name": "users", "path_prefix": "/users", "upstream": "http://users:8080" }],
+                    "auth": { "claim_headers": { "tenant": "X-Tenant" } }
+                });
+                warp::reply::json(&json!({ "kvs": [{ "value": STANDARD.encode(document.to_string()), "mod_revision": "42" }] }))
+            });
+        let (addr, server) = warp::serve(warp::post().and(login.or(range))).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let (sync, running, route_table) = cluster(ClusterSource::Etcd {
+            address: format!("http://{}", addr),
+            key: "/gateway/config".to_string(),
+            username: Some("gateway".to_string()),
+            password: Some("etcd-password".to_string()),
+        });
+
+        assert!(sync.sync().await.unwrap());
+        assert_eq!(route_names(&route_table), ["users"]);
+        assert_eq!(running.config().auth.claim_headers["tenant"], "X-Tenant");
+        assert_eq!(running.version().unwrap().version, "42");
+    }
+
+    fn elections(source: ClusterSource) -> (LeaderElection, LeaderElection) {
+        let cluster = ClusterConfig { source, poll_interval_secs: 10, leader_election: None };
+        let election = || LeaderElection::new(&cluster, &LeaderElectionConfig::default()).unwrap();
+        (election(), election())
+    }
+
+    /// Consul sessions and the lock key, with `Behavior: delete`.
+    #[derive(Default)]
+    struct Consul {
+        created: usize,
+        sessions: HashSet<String>,
+        holder: Option<String>,
+    }
+
+    #[tokio::test]
+    async fn test_leader_election_with_consul() {
+        let store = Arc::new(Mutex::new(Consul::default()));
+        let (create, renew, acquire) = (store.clone(), store.clone(), store.clone());
+        let create = warp::path!("v1" / "session" / "create").and(warp::body::json()).map(move |session: Value| {
+            assert_eq!((session["TTL"].as_str(), session["Behavior"].as_str()), (Some("15s"), Some("delete")));
+            let mut store = create.lock().unwrap();
+            let id = format!("session-{}", store.created);
+            store.created += 1;
+            store.sessions.insert(id.clone());
+            warp::reply::json(&json!({ "ID": id }))
+        });
+        let renew = warp::path!("v1" / "session" / "renew" / String).map(move |id: String| {
+            let status = if renew.lock().unwrap().sessions.contains(&id) { 200 } else { 404 };
+            warp::reply::with_status(warp::reply::json(&json!([{ "ID": id }])), warp::http::StatusCode::from_u16(status).unwrap())
+        });
+        let acquire = warp::path!("v1" / "kv" / "gateway" / "leader")
+            .and(warp::query::<std::collections::HashMap<String, String>>())
+            .map(move |query: std::collections::HashMap<String, String>| {
+                let mut store = acquire.lock().unwrap();
+                let session = &query["acquire"];
+                let acquired = store.holder.as_ref().is_none_or(|holder| holder == session);
+                if acquired {
+                    store.holder = Some(session.clone());
+                }
+                warp::reply::json(&acquired)
+            });
+        let (addr, server) = warp::serve(warp::put().and(create.or(renew).or(acquire))).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let (first, second) = elections(ClusterSource::Consul { address: format!("http://{}", addr), key: "gateway/config".to_string(), token_file: None });
+
+        assert!(first.elect().await.unwrap());
+        assert!(!second.elect().await.unwrap());
+        // Renewing keeps the lock.
+        assert!(first.elect().await.unwrap());
+        assert!(first.render().ends_with("gateway_cluster_leader 1\n"));
+        assert!(!second.is_leader());
+
+        // The leader's session expires, and the lock with it.
+        {
+            let mut store = store.lock().unwrap();
+            store.sessions.remove("session-0");
+            store.holder = None;
+        }
+        assert!(first.elect().await.unwrap_err().contains("404"));
+        assert!(!first.is_leader());
+        assert!(second.elect().await.unwrap());
+        assert!(!first.elect().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_leader_election_with_etcd() {
+        // Live leases and the lease holding the lock key.
+        let store = Arc::new(Mutex::new((HashSet::<String>::new(), None::<String>)));
+        let (grant, keepalive, txn) = (store.clone(), store.clone(), store.clone());
+        let granted = Arc::new(AtomicUsize::new(100));
+        let grant = warp::path!("v3" / "lease" / "grant").map(move || {
+            let mut store = grant.lock().unwrap();
+            let id = granted.fetch_add(1, Ordering::SeqCst).to_string();
+            store.0.insert(id.clone());
+            warp::reply::json(&json!({ "ID": id, "TTL": "15" }))
+        });
+        let keepalive = warp::path!("v3" / "lease" / "keepalive").and(warp::body::json()).map(move |request: Value| {
+            let id = request["ID"].as_str().unwrap().to_string();
+            let result = if keepalive.lock().unwrap().0.contains(&id) { json!({ "ID": id, "TTL": "15" }) } else { json!({ "ID": id }) };
+            warp::reply::json(&json!({ "result": result }))
+        });
+        let txn = warp::path!("v3" / "kv" / "txn").and(warp::body::json()).map(move |request: Value| {
+            assert_eq!(request["compare"][0]["key"], STANDARD.encode("gateway/leader"));
+            let mut store = txn.lock().unwrap();
+            match &store.1 {
+                Some(holder) => warp::reply::json(&json!({
+                    "succeeded": false,
+                    "responses": [{ "response_range": { "kvs": [{ "lease": holder }] } }]
+                })),
+                None => {
+                    store.1 = Some(request["success"][0]["request_put"]["lease"].as_str().unwrap().to_string());
+                    warp::reply::json(&json!({ "succeeded": true }))
+                }
+            }
+        });
+        let (addr, server) = warp::serve(warp::post().and(grant.or(keepalive).or(txn))).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let source = ClusterSource::Etcd { address: format!("http://{}", addr), key: "/gateway/config".to_string(), username: None, password: None };
+        let (first, second) = elections(source);
+
+        assert!(first.elect().await.unwrap());
+        assert!(!second.elect().await.unwrap());
+        // The leader's own lease still holds the key.
+        assert!(first.elect().await.unwrap());
+
+        *store.lock().unwrap() = (HashSet::from(["101".to_string()]), None);
+        assert_eq!(first.elect().await.unwrap_err(), "etcd lease 100 expired");
+        assert!(second.elect().await.unwrap());
+        assert!(!first.elect().await.unwrap());
+    }
+}
@@ -0,0 +1,4285 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use hyper::{Method, StatusCode, Uri, header::{HeaderName, HeaderValue}, http::uri::Authority};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use crate::errors::ConfigError;
+use crate::routes::{template_params, PathPattern};
+use crate::deployments::Deployment;
+use crate::dns;
+use crate::egress::Proxy;
+use crate::experiments::Experiment;
+use crate::formats::valid_xml_name;
+use crate::grpc::GrpcRoute;
+use crate::handlers::ErrorPage;
+use crate::kafka;
+use crate::nats::{self, NatsAddress};
+use crate::redaction::{Redactor, REDACTED_CONFIG_POINTERS};
+use crate::database::DatabaseAddress;
+use crate::redis::RedisAddress;
+use crate::schedules::Schedule;
+use crate::secrets::{self, SecretRef, VAULT_TOKEN_ENV};
+use crate::signing::SIGNATURE_HEADER;
+use crate::syslog;
+use crate::tls;
+
+#[cfg(test)]
+mod tests;
+
+pub const BACKEND_BASE: &str = "http://localhost:8081";
+pub const RATE_LIMIT_REQUESTS: u32 = 100; // requests per window
+pub const RATE_LIMIT_WINDOW_SECS: u64 = 60; // window size in seconds
+pub const REQUEST_TIMEOUT_SECS: u64 = 30;
+pub const CACHE_DURATION_SECS: u64 = 300; // 5 minutes
+pub const STRIP_PATH_PREFIX: &str = "/api";
+pub const LISTEN_ADDR: &str = "127.0.0.1:3030";
+pub const CONFIG_PATH_ENV: &str = "GATEWAY_CONFIG";
+pub const UPSTREAM_PROBE_TIMEOUT_SECS: u64 = 3;
+pub const IDEMPOTENCY_WINDOW_SECS: u64 = 86400; // 24 hours
+pub const INTERNAL_JWT_TTL_SECS: u64 = 60;
+pub const MAX_METRIC_SERIES: usize = 1000;
+/// Prometheus' default latency buckets, in seconds.
+pub const LATENCY_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+pub const DEBUG_LOG_HEADER: &str = "x-gateway-debug";
+pub const METRIC_LABELS: [&str; 5] = ["route", "method", "upstream", "status_class", "status"];
+/// Names a route tag can't take besides `METRIC_LABELS`, as the access log
+/// and the latency histogram use them already.
+pub const RESERVED_TAGS: [&str; 4] = ["tenant", "variant", "request_id", "le"];
+pub const KEY_RELOAD_INTERVAL_SECS: u64 = 5;
+pub const MAX_RATE_LIMIT_BUCKETS: usize = 100_000;
+pub const MAX_CACHE_ENTRIES: usize = 10_000;
+/// Most `Idempotency-Key` records held at once, in flight or answered.
+pub const MAX_IDEMPOTENCY_RECORDS: usize = 10_000;
+pub const MAX_CACHED_OBJECT_BYTES: usize = 1024 * 1024;
+pub const PREFLIGHT_MAX_AGE_SECS: u64 = 600;
+/// Chromium's cap on `Access-Control-Max-Age`; Firefox allows a day.
+const BROWSER_MAX_AGE_CAP_SECS: u64 = 7200;
+pub const LISTEN_BACKLOG: u32 = 1024;
+/// RFC 8305's recommended wait before racing the other address family.
+pub const HAPPY_EYEBALLS_DELAY_MS: u64 = 250;
+pub const DNS_MIN_TTL_SECS: u64 = 5;
+pub const DNS_MAX_TTL_SECS: u64 = 300;
+pub const DNS_NEGATIVE_TTL_SECS: u64 = 5;
+pub const DNS_TIMEOUT_MS: u64 = 2000;
+pub const UNHEALTHY_CONNECT_FAILURES: u32 = 3;
+pub const UNHEALTHY_SERVER_ERRORS: u32 = 5;
+pub const HEALTHY_SUCCESSES: u32 = 2;
+pub const FAILURE_INJECTION_FRACTION: f64 = 0.1;
+pub const SECRETS_REFRESH_INTERVAL_SECS: u64 = 300;
+pub const CLUSTER_POLL_INTERVAL_SECS: u64 = 10;
+pub const QUOTA_SNAPSHOT_INTERVAL_SECS: u64 = 10;
+pub const PERSISTENCE_QUEUE_CAPACITY: usize = 10_000;
+pub const CERTIFICATE_WARNING_DAYS: u64 = 14;
+pub const WEBHOOK_ATTEMPTS: u32 = 3;
+pub const WEBHOOK_TIMEOUT_MS: u64 = 5000;
+pub const SYNTHETIC_CHECK_INTERVAL_SECS: u64 = 60;
+pub const SYNTHETIC_CHECK_TIMEOUT_MS: u64 = 5000;
+pub const OPENAPI_SPEC_PATH: &str = "/openapi.json";
+pub const OPENAPI_REFRESH_INTERVAL_SECS: u64 = 300;
+pub const OPENAPI_TIMEOUT_MS: u64 = 5000;
+/// The swagger-ui-dist release Swagger UI is loaded from by default.
+pub const SWAGGER_UI_VERSION: &str = "5.17.14";
+pub const PORTAL_PATH: &str = "/portal";
+pub const ANALYTICS_TOP: usize = 10;
+pub const ANALYTICS_SNAPSHOT_INTERVAL_SECS: u64 = 60;
+pub const EVENT_BATCH_SIZE: usize = 100;
+pub const EVENT_FLUSH_INTERVAL_MS: u64 = 1000;
+pub const EVENT_QUEUE_CAPACITY: usize = 10_000;
+pub const EVENT_SINK_TIMEOUT_MS: u64 = 5000;
+pub const TRACE_SERVICE_NAME: &str = "api-gateway";
+pub const TRACE_BATCH_SIZE: usize = 100;
+pub const TRACE_FLUSH_INTERVAL_MS: u64 = 1000;
+pub const TRACE_QUEUE_CAPACITY: usize = 10_000;
+pub const TRACE_EXPORT_TIMEOUT_MS: u64 = 5000;
+pub const SYSLOG_APP_NAME: &str = "api-gateway";
+pub const SYSLOG_QUEUE_CAPACITY: usize = 10_000;
+pub const LEADER_KEY: &str = "gateway/leader";
+/// Consul accepts session TTLs from 10 seconds to a day.
+pub const LEADER_TTL_SECS: u64 = 15;
+pub const MIN_LEADER_TTL_SECS: u64 = 10;
+pub const MAX_LEADER_TTL_SECS: u64 = 86_400;
+/// The settings a shared cluster configuration may set.
+pub const CLUSTER_FIELDS: [&str; 4] = ["routes", "route_groups", "policies", "auth"];
+/// Argon2id costs recommended by OWASP for password storage.
+pub const ARGON2_MEMORY_KIB: u32 = 19_456;
+pub const ARGON2_ITERATIONS: u32 = 2;
+pub const VAULT_KV_VERSIONS: [u8; 2] = [1, 2];
+pub const ISSUED_TOKEN_TTL_SECS: u64 = 300;
+pub const ISSUED_TOKEN_AUDIENCE: &str = "api-gateway-token";
+pub const LOGIN_FREE_ATTEMPTS: u32 = 3;
+pub const LOGIN_BACKOFF_BASE_MS: u64 = 1000;
+pub const LOGIN_MAX_FAILURES: u32 = 10;
+pub const LOGIN_LOCKOUT_SECS: u64 = 900;
+pub const SESSION_TTL_SECS: u64 = 8 * 3600;
+pub const SESSION_COOKIE: &str = "gateway_session";
+pub const CSRF_COOKIE: &str = "gateway_csrf";
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+/// The session cookie is sealed with a key derived from this many bytes of
+/// secret or more.
+pub const MIN_SESSION_SECRET_LEN: usize = 32;
+/// The protocols the listener can serve, and the default ALPN offer.
+pub const ALPN_PROTOCOLS: &[&str] = &["h2", "http/1.1"];
+pub const ALT_SVC_MAX_AGE_SECS: u64 = 86_400;
+pub const LETS_ENCRYPT_DIRECTORY: &str = "https://acme-v02.api.letsencrypt.org/directory";
+pub const ACME_RENEW_BEFORE_DAYS: u64 = 30;
+pub const ACME_CHECK_INTERVAL_SECS: u64 = 12 * 3600;
+
+lazy_static! {
+    pub static ref VALID_AUTH_TOKENS: HashMap<String, String> = {
+        let mut m = HashMap::new();
+        m.insert("example-token".to_string(), "example-user".to_string());
+        m
+    };
+}
+
+/// Gateway configuration as loaded from a JSON file. Every field is optional;
+/// anything left out falls back to the constants above.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GatewayConfig {
+    pub listen_addr: String,
+    pub routes: Vec<RouteConfig>,
+    /// Expanded into `routes` when the file is read, after the routes
+    /// listed there.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub route_groups: Vec<RouteGroupConfig>,
+    /// Named sets of route fields that routes take on by listing them in
+    /// their `policies`.
+    pub policies: HashMap<String, Map<String, Value>>,
+    pub tls: Option<TlsConfig>,
+    pub admin: AdminConfig,
+    pub bulkheads: HashMap<String, BulkheadConfig>,
+    pub auth: AuthConfig,
+    pub tenancy: TenancyConfig,
+    pub rate_limiting: RateLimitingConfig,
+    pub cache: CacheConfig,
+    pub runtime: RuntimeConfig,
+    pub socket: SocketConfig,
+    pub upstream_connect: UpstreamConnectConfig,
+    pub upstream_health: Option<UpstreamHealthConfig>,
+    pub failure_injection: FailureInjectionConfig,
+    pub metrics: MetricsConfig,
+    pub debug_log: DebugLogConfig,
+    pub redaction: RedactionConfig,
+    pub correlation: CorrelationConfig,
+    pub challenge: Option<ChallengeConfig>,
+    pub cors: CorsConfig,
+    pub secrets: SecretsConfig,
+    pub cluster: Option<ClusterConfig>,
+    pub persistence: Option<PersistenceConfig>,
+    pub webhooks: Option<WebhooksConfig>,
+    pub synthetic_checks: Vec<SyntheticCheckConfig>,
+    pub openapi: Option<OpenApiConfig>,
+    pub portal: Option<PortalConfig>,
+    pub analytics: Option<AnalyticsConfig>,
+    pub events: Option<EventsConfig>,
+    pub tracing: Option<TracingConfig>,
+    pub syslog: Option<SyslogConfig>,
+}
+
+impl Default for GatewayConfig {
+    fn default() -> Self {
+        Self {
+            listen_addr: LISTEN_ADDR.to_string(),
+            routes: vec![RouteConfig::default()],
+            route_groups: Vec::new(),
+            policies: HashMap::new(),
+            tls: None,
+            admin: AdminConfig::default(),
+            bulkheads: HashMap::new(),
+            auth: AuthConfig::default(),
+            tenancy: TenancyConfig::default(),
+            rate_limiting: RateLimitingConfig::default(),
+            cache: CacheConfig::default(),
+            runtime: RuntimeConfig::default(),
+            socket: SocketConfig::default(),
+            upstream_connect: UpstreamConnectConfig::default(),
+            upstream_health: None,
+            failure_injection: FailureInjectionConfig::default(),
+            metrics: MetricsConfig::default(),
+            debug_log: DebugLogConfig::default(),
+            redaction: RedactionConfig::default(),
+            correlation: CorrelationConfig::default(),
+            challenge: None,
+            cors: CorsConfig::default(),
+            secrets: SecretsConfig::default(),
+            cluster: None,
+            persistence: None,
+            webhooks: None,
+            synthetic_checks: Vec::new(),
+            openapi: None,
+            portal: None,
+            analytics: None,
+            events: None,
+            tracing: None,
+            syslog: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConfig {
+    #[serde(default)]
+    pub name: Option<String>,
+    pub path_prefix: String,
+    /// Named policies applied in order; the route's own fields win.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub policies: Vec<String>,
+    #[serde(default)]
+    pub upstream: Option<String>,
+    #[serde(default)]
+    pub redirect: Option<RedirectConfig>,
+    #[serde(default)]
+    pub static_files: Option<StaticFilesConfig>,
+    #[serde(default)]
+    pub respond: Option<DirectResponseConfig>,
+    /// Serve the route by calling a unary method on a gRPC upstream.
+    #[serde(default)]
+    pub grpc: Option<GrpcConfig>,
+    #[serde(default)]
+    pub strip_prefix: Option<String>,
+    #[serde(default)]
+    pub methods: Vec<String>,
+    #[serde(default)]
+    pub rewrite: Option<String>,
+    #[serde(default)]
+    pub request_headers: HashMap<String, String>,
+    #[serde(default)]
+    pub match_headers: HashMap<String, String>,
+    #[serde(default)]
+    pub match_query: HashMap<String, String>,
+    /// Match SOAP requests by their action or the operation in the body.
+    #[serde(default)]
+    pub match_soap: Option<SoapMatchConfig>,
+    #[serde(default)]
+    pub bulkhead: Option<String>,
+    #[serde(default)]
+    pub priority: Priority,
+    #[serde(default)]
+    pub host_header: HostHeader,
+    #[serde(default)]
+    pub cache: CacheMode,
+    /// Compute an ETag from the body of `200 OK` GET responses that lack one.
+    #[serde(default)]
+    pub generate_etag: bool,
+    /// Report gateway and upstream time in `X-Gateway-Time-Ms` and
+    /// `X-Upstream-Time-Ms` response headers.
+    #[serde(default)]
+    pub timing_headers: bool,
+    #[serde(default)]
+    pub idempotency: Option<IdempotencyConfig>,
+    #[serde(default)]
+    pub upstream_auth: Option<UpstreamAuthConfig>,
+    /// `Content-Type`s a request body may have, such as `application/json`
+    /// or `text/*`. Any type is accepted if empty.
+    #[serde(default)]
+    pub content_types: Vec<String>,
+    /// Translate between the upstream's JSON and XML or CSV for clients
+    /// that ask for them.
+    #[serde(default)]
+    pub formats: Option<FormatsConfig>,
+    /// Inflate gzip request bodies for an upstream that can't.
+    #[serde(default)]
+    pub decompress_requests: Option<DecompressionConfig>,
+    /// Let clients trim JSON responses to the fields they list.
+    #[serde(default)]
+    pub fields: Option<FieldsConfig>,
+    /// Replace the bodies of upstream 5xx responses, logging the originals.
+    #[serde(default)]
+    pub sanitize_errors: Option<ErrorPageConfig>,
+    /// Map `Location` on upstream redirects back to the public origin and
+    /// path prefix.
+    #[serde(default = "default_true")]
+    pub rewrite_location: bool,
+    /// Rewrite the upstream's absolute URLs in responses to the public ones.
+    #[serde(default)]
+    pub rewrite_urls: Option<UrlRewriteConfig>,
+    /// Adjust upstream `Set-Cookie` attributes for the public host and path.
+    #[serde(default)]
+    pub rewrite_cookies: Option<CookieRewriteConfig>,
+    /// Sign forwarded requests with a secret shared with the upstream.
+    #[serde(default)]
+    pub signing: Option<SigningConfig>,
+    /// Replace the client's token with an internal JWT minted by the gateway.
+    #[serde(default)]
+    pub token_exchange: bool,
+    /// CSRF protection for requests authenticated by the OIDC session
+    /// cookie, in place of `auth.oidc.csrf.mode`.
+    #[serde(default)]
+    pub csrf: Option<CsrfMode>,
+    /// UTC windows the route is available in; outside them it is refused.
+    #[serde(default)]
+    pub schedule: Option<ScheduleConfig>,
+    /// Splits traffic between upstream variants with sticky assignment.
+    #[serde(default)]
+    pub experiment: Option<ExperimentConfig>,
+    /// Blue/green upstream groups, used in place of `upstream`.
+    #[serde(default)]
+    pub deployment: Option<DeploymentConfig>,
+    /// Seconds to wait for the upstream's response headers.
+    #[serde(default = "default_request_timeout")]
+    pub timeout_secs: u64,
+    /// Seconds allowed to read the upstream's body once headers arrived.
+    #[serde(default = "default_request_timeout")]
+    pub body_timeout_secs: u64,
+    /// Caps how much of an upstream's response body is buffered.
+    #[serde(default)]
+    pub response_limit: Option<ResponseLimitConfig>,
+    /// How much of the route's traffic is logged.
+    #[serde(default)]
+    pub logging: RouteLoggingConfig,
+    /// Static labels, such as the owning team or cost center, added to the
+    /// route's access log lines and access events, and available as
+    /// metric labels.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub tags: HashMap<String, String>,
+}
+
+fn default_request_timeout() -> u64 {
+    REQUEST_TIMEOUT_SECS
+}
+
+fn default_true() -> bool {
+    true
+}
+
+pub const ROUTE_ACTION_ERROR: &str =
+    "route must define exactly one of upstream, deployment, redirect, static_files, respond or grpc";
+
+impl RouteConfig {
+    /// Names of the action fields set on this route; valid routes have one.
+    pub fn actions(&self) -> Vec<&'static str> {
+        let mut actions = Vec::new();
+        if self.upstream.is_some() {
+            actions.push("upstream");
+        }
+        if self.deployment.is_some() {
+            actions.push("deployment");
+        }
+        if self.redirect.is_some() {
+            actions.push("redirect");
+        }
+        if self.static_files.is_some() {
+            actions.push("static_files");
+        }
+        if self.respond.is_some() {
+            actions.push("respond");
+        }
+        if self.grpc.is_some() {
+            actions.push("grpc");
+        }
+        actions
+    }
+
+    /// Whether requests are proxied, to `upstream` or a deployment group.
+    pub fn proxies(&self) -> bool {
+        self.upstream.is_some() || self.deployment.is_some()
+    }
+
+    /// Every upstream the route may send to.
+    pub fn upstreams(&self) -> impl Iterator<Item = &String> {
+        let groups = self.deployment.iter().flat_map(|deployment| deployment.groups.values());
+        let variants = self.experiment.iter().flat_map(|experiment| experiment.variants.iter().map(|v| &v.upstream));
+        self.upstream.iter().chain(groups).chain(variants)
+    }
+}
+
+/// Routes sharing settings. `defaults` may hold any route field, which each
+/// route inherits unless it sets the field itself; nested `groups` inherit
+/// their parent's defaults the same way. Fields are replaced whole, never
+/// merged. `path_prefix` is put in front of every route's own.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteGroupConfig {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub path_prefix: Option<String>,
+    #[serde(default)]
+    pub defaults: Map<String, Value>,
+    #[serde(default)]
+    pub routes: Vec<Map<String, Value>>,
+    #[serde(default)]
+    pub groups: Vec<RouteGroupConfig>,
+}
+
+impl RouteGroupConfig {
+    /// Appends the group's routes, then its subgroups', to `out`.
+    fn expand(
+        &self,
+        location: &str,
+        inherited: &Map<String, Value>,
+        prefix: &str,
+        policies: &HashMap<String, Map<String, Value>>,
+        out: &mut Vec<RouteConfig>,
+    ) -> Result<(), String> {
+        let location = match &self.name {
+            Some(name) => format!("{} ({})", location, name),
+            None => location.to_string(),
+        };
+        if let Some(field) = ["name", "path_prefix"].into_iter().find(|field| self.defaults.contains_key(*field)) {
+            return Err(format!("{}: defaults cannot set {}", location, field));
+        }
+        let prefix = match &self.path_prefix {
+            Some(path_prefix) if !path_prefix.starts_with('/') => {
+                return Err(format!("{}: path_prefix \"{}\" must start with '/'", location, path_prefix));
+            }
+            Some(path_prefix) => join_prefix(prefix, path_prefix),
+            None => prefix.to_string(),
+        };
+        let mut defaults = inherited.clone();
+        defaults.extend(self.defaults.clone());
+
+        for (i, route) in self.routes.iter().enumerate() {
+            let route_location = format!("{}.routes[{}]", location, i);
+            let mut fields = resolve_route(&route_location, &defaults, route, policies)?;
+            if let Some(Value::String(path_prefix)) = fields.get_mut("path_prefix") {
+                *path_prefix = join_prefix(&prefix, path_prefix);
+            }
+            let route = serde_json::from_value(Value::Object(fields))
+                .map_err(|e| format!("{}: {}", route_location, e))?;
+            out.push(route);
+        }
+        for (i, group) in self.groups.iter().enumerate() {
+            group.expand(&format!("{}.groups[{}]", location, i), &defaults, &prefix, policies, out)?;
+        }
+        Ok(())
+    }
+}
+
+/// What a file named in `include` may define. Only parsed to report
+/// mistakes against the file they are in.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[allow(dead_code)]
+struct ConfigInclude {
+    #[serde(default)]
+    routes: Vec<RouteConfig>,
+    #[serde(default)]
+    route_groups: Vec<RouteGroupConfig>,
+    #[serde(default)]
+    policies: HashMap<String, Map<String, Value>>,
+    #[serde(default)]
+    bulkheads: HashMap<String, BulkheadConfig>,
+}
+
+/// Replaces `include` in a configuration file with the routes, route
+/// groups, policies and bulkheads of the files it names, which are added in
+/// the order listed and, within a pattern, by file name. A route name,
+/// policy or bulkhead defined by two files is refused.
+fn resolve_includes(path: &Path, file: &mut Value) -> Result<(), ConfigError> {
+    let Some(main) = file.as_object_mut() else {
+        return Ok(());
+    };
+    let patterns: Vec<String> = main
+        .remove("include")
+        .map(serde_json::from_value)
+        .transpose()
+        .map_err(|_| ConfigError::Parse("include must be a list of paths".to_string()))?
+        .unwrap_or_default();
+    let base = path.parent().unwrap_or(Path::new("."));
+    let mut paths: Vec<PathBuf> = Vec::new();
+    for pattern in &patterns {
+        for included in include_paths(base, pattern)? {
+            if !paths.contains(&included) {
+                paths.push(included);
+            }
+        }
+    }
+
+    let mut owners: HashMap<(&str, String), PathBuf> = HashMap::new();
+    let mut claim = |kind: &'static str, name: &str, owner: &Path| match owners.get(&(kind, name.to_string())) {
+        Some(first) => Err(ConfigError::Invalid(format!(
+            "{} \"{}\" is defined in both {} and {}",
+            kind,
+            name,
+            first.display(),
+            owner.display()
+        ))),
+        None => {
+            owners.insert((kind, name.to_string()), owner.to_path_buf());
+            Ok(())
+        }
+    };
+    let names = |section: &Value, kind: &'static str| -> Vec<(&'static str, String)> {
+        match (kind, section) {
+            ("route", Value::Array(routes)) => routes
+                .iter()
+                .filter_map(|route| route.get("name")?.as_str())
+                .map(|name| (kind, name.to_string()))
+                .collect(),
+            (_, Value::Object(entries)) => entries.keys().map(|name| (kind, name.clone())).collect(),
+            _ => Vec::new(),
+        }
+    };
+    const SECTIONS: [(&str, &str); 3] = [("routes", "route"), ("policies", "policy"), ("bulkheads", "bulkhead")];
+    for (section, kind) in SECTIONS {
+        for (kind, name) in main.get(section).map(|value| names(value, kind)).unwrap_or_default() {
+            claim(kind, &name, path)?;
+        }
+    }
+
+    for included in paths {
+        let contents = std::fs::read_to_string(&included)
+            .map_err(|e| ConfigError::Read(format!("{}: {}", included.display(), e)))?;
+        serde_json::from_str::<ConfigInclude>(&contents)
+            .map_err(|e| ConfigError::Parse(format!("{}: {}", included.display(), e)))?;
+        let Value::Object(sections) = serde_json::from_str(&contents).map_err(|e| ConfigError::Parse(e.to_string()))? else {
+            continue;
+        };
+        for (section, kind) in SECTIONS {
+            for (kind, name) in sections.get(section).map(|value| names(value, kind)).unwrap_or_default() {
+                claim(kind, &name, &included)?;
+            }
+        }
+        for (section, value) in sections {
+            match (main.get_mut(&section), value) {
+                (Some(Value::Array(existing)), Value::Array(added)) => existing.extend(added),
+                (Some(Value::Object(existing)), Value::Object(added)) => existing.extend(added),
+                (_, value) => {
+                    main.insert(section, value);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The files an `include` entry names, relative to the including file. A
+/// `*` or `?` in the file name matches any files in that directory.
+fn include_paths(base: &Path, pattern: &str) -> Result<Vec<PathBuf>, ConfigError> {
+    let full = base.join(pattern);
+    let name = full.file_name().and_then(|name| name.to_str()).unwrap_or("");
+    if !name.contains(['*', '?']) {
+        return Ok(vec![full]);
+    }
+    let dir = full.parent().unwrap_or(base);
+    if dir.to_string_lossy().contains(['*', '?']) {
+        return Err(ConfigError::Parse(format!("include \"{}\": wildcards are only allowed in file names", pattern)));
+    }
+    let entries = std::fs::read_dir(dir).map_err(|e| ConfigError::Read(format!("{}: {}", dir.display(), e)))?;
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file())
+        .filter(|path| path.file_name().and_then(|n| n.to_str()).is_some_and(|n| wildcard_match(name, n)))
+        .collect();
+    paths.sort();
+    Ok(paths)
+}
+
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let (pattern, name): (Vec<char>, Vec<char>) = (pattern.chars().collect(), name.chars().collect());
+    let (mut p, mut n) = (0, 0);
+    let mut backtrack = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    p = star + 1;
+                    n = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// A route's fields over those of the policies it lists, over `inherited`.
+fn resolve_route(
+    location: &str,
+    inherited: &Map<String, Value>,
+    route: &Map<String, Value>,
+    policies: &HashMap<String, Map<String, Value>>,
+) -> Result<Map<String, Value>, String> {
+    let mut fields = inherited.clone();
+    let names = route.get("policies").or_else(|| inherited.get("policies")).and_then(Value::as_array);
+    for name in names.into_iter().flatten() {
+        let name = name.as_str().ok_or_else(|| format!("{}: policies must be names", location))?;
+        let policy = policies.get(name).ok_or_else(|| format!("{}: unknown policy \"{}\"", location, name))?;
+        fields.extend(policy.clone());
+    }
+    fields.extend(route.clone());
+    Ok(fields)
+}
+
+fn join_prefix(prefix: &str, path: &str) -> String {
+    match (prefix.trim_end_matches('/'), path) {
+        ("", path) => path.to_string(),
+        (prefix, "/") => prefix.to_string(),
+        (prefix, path) => format!("{}{}", prefix, path),
+    }
+}
+
+impl Default for RouteConfig {
+    fn default() -> Self {
+        Self {
+            name: Some("default".to_string()),
+            path_prefix: "/".to_string(),
+            policies: Vec::new(),
+            upstream: Some(BACKEND_BASE.to_string()),
+            redirect: None,
+            static_files: None,
+            respond: None,
+            grpc: None,
+            strip_prefix: Some(STRIP_PATH_PREFIX.to_string()),
+            methods: Vec::new(),
+            rewrite: None,
+            request_headers: HashMap::new(),
+            match_headers: HashMap::new(),
+            match_query: HashMap::new(),
+            match_soap: None,
+            bulkhead: None,
+            priority: Priority::default(),
+            host_header: HostHeader::default(),
+            cache: CacheMode::default(),
+            generate_etag: false,
+            timing_headers: false,
+            idempotency: None,
+            upstream_auth: None,
+            content_types: Vec::new(),
+            formats: None,
+            fields: None,
+            decompress_requests: None,
+            sanitize_errors: None,
+            rewrite_location: true,
+            rewrite_urls: None,
+            rewrite_cookies: None,
+            signing: None,
+            token_exchange: false,
+            csrf: None,
+            schedule: None,
+            experiment: None,
+            deployment: None,
+            timeout_secs: REQUEST_TIMEOUT_SECS,
+            body_timeout_secs: REQUEST_TIMEOUT_SECS,
+            response_limit: None,
+            logging: RouteLoggingConfig::default(),
+            tags: HashMap::new(),
+        }
+    }
+}
+
+/// What a route logs: at `info` every error and access log line, though
+/// only `sample_rate` of the lines for requests that didn't fail with a
+/// 5xx; at `error` only errors and the lines for requests that failed; at
+/// `off` nothing.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RouteLoggingConfig {
+    pub level: LogLevel,
+    pub sample_rate: f64,
+}
+
+impl Default for RouteLoggingConfig {
+    fn default() -> Self {
+        Self { level: LogLevel::Info, sample_rate: 1.0 }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogLevel {
+    Off,
+    Error,
+    #[default]
+    Info,
+}
+
+/// Answers matching requests with a redirect instead of proxying them.
+/// `location` may reference path parameters as well as `{host}`, `{path}`
+/// (the full request path) and `{rest}` (the path after the matched prefix).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RedirectConfig {
+    #[serde(default = "default_redirect_status")]
+    pub status: u16,
+    pub location: String,
+    #[serde(default)]
+    pub preserve_query: bool,
+}
+
+fn default_redirect_status() -> u16 {
+    302
+}
+
+/// Serves files below `root` instead of proxying. The part of the request path
+/// after the matched prefix selects the file; directories serve `index`, and
+/// missing files serve `fallback` when set (useful for single-page apps).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StaticFilesConfig {
+    pub root: PathBuf,
+    #[serde(default = "default_index")]
+    pub index: String,
+    #[serde(default)]
+    pub fallback: Option<String>,
+}
+
+fn default_index() -> String {
+    "index.html".to_string()
+}
+
+/// `method` is `package.Service/Method`, looked up in `descriptor`, a
+/// `FileDescriptorSet` written by `protoc --include_imports
+/// --descriptor_set_out`. The upstream is reached over cleartext HTTP/2.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GrpcConfig {
+    pub upstream: String,
+    pub descriptor: PathBuf,
+    pub method: String,
+}
+
+/// Either part may be left out; a route with both needs both to match.
+/// `operation` is the local name of the first element in the SOAP `Body`,
+/// or of the root element for plain XML.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SoapMatchConfig {
+    #[serde(default)]
+    pub action: Option<String>,
+    #[serde(default)]
+    pub operation: Option<String>,
+}
+
+/// Answers matching requests with a fixed response, e.g. `/robots.txt` or a
+/// tombstone for a retired endpoint.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DirectResponseConfig {
+    #[serde(default = "default_direct_status")]
+    pub status: u16,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    #[serde(default)]
+    pub body: String,
+}
+
+fn default_direct_status() -> u16 {
+    200
+}
+
+/// Replays the stored response to POST retries carrying the same
+/// `Idempotency-Key` and body for `window_secs`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IdempotencyConfig {
+    #[serde(default = "default_idempotency_window")]
+    pub window_secs: u64,
+}
+
+fn default_idempotency_window() -> u64 {
+    IDEMPOTENCY_WINDOW_SECS
+}
+
+/// Restricts a route to `windows` (UTC). Outside them the gateway answers
+/// `status` with `message`, which may use `{route}`, `{windows}` and
+/// `{next_open}`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScheduleConfig {
+    pub windows: Vec<ScheduleWindowConfig>,
+    #[serde(default = "default_schedule_status")]
+    pub status: u16,
+    #[serde(default)]
+    pub message: Option<String>,
+}
+
+/// `start` and `end` are `HH:MM` in UTC; an `end` before `start` runs past
+/// midnight. `days` (`mon`..`sun`) names the days a window opens on, every
+/// day if empty.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScheduleWindowConfig {
+    #[serde(default)]
+    pub days: Vec<String>,
+    pub start: String,
+    pub end: String,
+}
+
+fn default_schedule_status() -> u16 {
+    503
+}
+
+/// An A/B experiment: each client is assigned one of `variants`, in
+/// proportion to their weights, and its requests go to that variant's
+/// upstream. The assignment sticks through a cookie, or with
+/// `assign_by: "user"` through a hash of the authenticated user id.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExperimentConfig {
+    pub name: String,
+    pub variants: Vec<VariantConfig>,
+    #[serde(default)]
+    pub assign_by: AssignBy,
+    /// Defaults to `gw_exp_<name>`.
+    #[serde(default)]
+    pub cookie: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VariantConfig {
+    pub name: String,
+    pub upstream: String,
+    #[serde(default = "default_variant_weight")]
+    pub weight: u32,
+}
+
+fn default_variant_weight() -> u32 {
+    1
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AssignBy {
+    #[default]
+    Cookie,
+    /// Hash of the user id; anonymous clients fall back to the cookie.
+    User,
+}
+
+/// Named upstream groups for blue/green releases. `active` receives the
+/// traffic until the admin API switches it to another group.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeploymentConfig {
+    pub groups: HashMap<String, String>,
+    pub active: String,
+}
+
+/// Credentials the gateway sends upstream in place of the client's
+/// `Authorization`. When the upstream answers 401 a refreshable credential is
+/// fetched again from its source and the request retried once.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum UpstreamAuthConfig {
+    /// A static bearer token.
+    Bearer(String),
+    Basic {
+        username: String,
+        #[serde(default)]
+        password: String,
+    },
+    /// A bearer token read from a file, e.g. a rotated service-account token.
+    TokenFile(PathBuf),
+    /// A token from the OAuth2 client-credentials grant, renewed before it
+    /// expires.
+    #[serde(rename = "oauth2")]
+    OAuth2(OAuth2Config),
+    /// NTLM for Windows-authenticated upstreams. Each request gets a
+    /// connection of its own, authenticated before the request is answered.
+    Ntlm(NtlmConfig),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OAuth2Config {
+    pub token_url: String,
+    pub client_id: String,
+    pub client_secret: String,
+    #[serde(default)]
+    pub scope: Option<String>,
+}
+
+/// The service account NTLM authenticates as, and the `WWW-Authenticate`
+/// scheme the upstream offers it under.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NtlmConfig {
+    pub username: String,
+    #[serde(default)]
+    pub password: String,
+    #[serde(default)]
+    pub domain: String,
+    #[serde(default)]
+    pub workstation: String,
+    #[serde(default)]
+    pub scheme: NtlmScheme,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NtlmScheme {
+    /// SPNEGO's `Negotiate`, which Windows servers accept NTLM tokens under.
+    #[default]
+    Negotiate,
+    Ntlm,
+}
+
+impl NtlmScheme {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            NtlmScheme::Negotiate => "Negotiate",
+            NtlmScheme::Ntlm => "NTLM",
+        }
+    }
+}
+
+/// Formats offered besides JSON. A response is translated when the client's
+/// `Accept` prefers one of them, and request bodies in one are sent upstream
+/// as JSON. `xml_root` names the XML document element.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FormatsConfig {
+    pub offer: Vec<Format>,
+    pub xml_root: String,
+}
+
+impl Default for FormatsConfig {
+    fn default() -> Self {
+        Self {
+            offer: vec![Format::Xml, Format::Csv],
+            xml_root: "response".to_string(),
+        }
+    }
+}
+
+/// `max_bytes` caps a body's decompressed size, so a small compressed body
+/// can't expand without bound.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DecompressionConfig {
+    pub max_bytes: usize,
+}
+
+impl Default for DecompressionConfig {
+    fn default() -> Self {
+        Self { max_bytes: 10 * 1024 * 1024 }
+    }
+}
+
+/// `max_bytes` caps an upstream response body. Past it the response is
+/// refused with a 502, or with `on_exceed: truncate` cut short and marked.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResponseLimitConfig {
+    pub max_bytes: usize,
+    pub on_exceed: LimitAction,
+}
+
+impl Default for ResponseLimitConfig {
+    fn default() -> Self {
+        Self { max_bytes: 10 * 1024 * 1024, on_exceed: LimitAction::default() }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LimitAction {
+    #[default]
+    Reject,
+    Truncate,
+}
+
+/// `param` names the query parameter listing the fields to keep. It is
+/// removed from the query before the request is forwarded.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FieldsConfig {
+    pub param: String,
+}
+
+impl Default for FieldsConfig {
+    fn default() -> Self {
+        Self { param: "fields".to_string() }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Xml,
+    Csv,
+}
+
+/// The body sent in place of an upstream's 5xx, by default a small JSON
+/// object with the status and request id.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ErrorPageConfig {
+    #[serde(default)]
+    pub body: Option<String>,
+    #[serde(default = "default_error_page_content_type")]
+    pub content_type: String,
+}
+
+fn default_error_page_content_type() -> String {
+    "application/json".to_string()
+}
+
+/// `public_url` is the origin clients use, taken from the request's `Host`
+/// if unset. `internal_urls` are other backend addresses the upstream writes
+/// into its responses, besides its own.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UrlRewriteConfig {
+    pub public_url: Option<String>,
+    pub internal_urls: Vec<String>,
+    pub content_types: Vec<String>,
+}
+
+impl Default for UrlRewriteConfig {
+    fn default() -> Self {
+        Self {
+            public_url: None,
+            internal_urls: Vec::new(),
+            content_types: vec!["application/json".to_string(), "text/html".to_string()],
+        }
+    }
+}
+
+/// The upstream's `Domain` is replaced by `domain`, or dropped so the cookie
+/// belongs to the public host. `Path` gets the route's stripped prefix back.
+/// `secure` and `same_site` set those attributes on every cookie.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CookieRewriteConfig {
+    pub domain: Option<String>,
+    pub secure: bool,
+    pub same_site: Option<String>,
+}
+
+pub const SAME_SITE_VALUES: [&str; 3] = ["Strict", "Lax", "None"];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SigningConfig {
+    pub secret: String,
+    #[serde(default = "default_signature_header")]
+    pub header: String,
+}
+
+fn default_signature_header() -> String {
+    SIGNATURE_HEADER.to_string()
+}
+
+pub const REDIRECT_STATUSES: [u16; 4] = [301, 302, 307, 308];
+pub const REDIRECT_TEMPLATE_VARS: [&str; 3] = ["host", "path", "rest"];
+
+/// The `Host` header sent upstream: the upstream's own authority, the one
+/// the client sent, or a fixed value.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HostHeader {
+    #[default]
+    Upstream,
+    Preserve,
+    Override(String),
+}
+
+/// How GET responses of a proxy route are cached. `PerUser` partitions the
+/// cache by the caller's resolved identity, so per-user resources such as
+/// `/api/me/orders` can be cached without leaking between users.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CacheMode {
+    #[default]
+    Shared,
+    PerUser,
+    Disabled,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    High,
+    #[default]
+    Normal,
+    Low,
+}
+
+/// A named concurrency pool. Routes assigned to it share `max_concurrent`
+/// in-flight requests; `reserved_for_high` of those can only be taken by
+/// high-priority routes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BulkheadConfig {
+    pub max_concurrent: usize,
+    #[serde(default)]
+    pub reserved_for_high: usize,
+    /// Without a queue, requests are refused as soon as the pool is full.
+    #[serde(default)]
+    pub queue: Option<BulkheadQueueConfig>,
+}
+
+/// Lets up to `max_waiting` requests wait `timeout_ms` for a slot to free up.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BulkheadQueueConfig {
+    pub max_waiting: usize,
+    pub timeout_ms: u64,
+}
+
+/// The PEM certificate chain and private key, each read from a file or
+/// given inline, typically as a secret reference. They serve every name
+/// without a certificate of its own from `certificates`, `cert_dir` or
+/// `acme`, and are optional when one of those is set.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    #[serde(default)]
+    pub cert_path: Option<PathBuf>,
+    #[serde(default)]
+    pub key_path: Option<PathBuf>,
+    #[serde(default)]
+    pub cert: Option<String>,
+    #[serde(default)]
+    pub key: Option<String>,
+    /// Certificates by SNI name; `*.example.com` serves any single label
+    /// below `example.com`.
+    #[serde(default)]
+    pub certificates: HashMap<String, CertificateConfig>,
+    /// A directory of `<name>.crt` and `<name>.key` pairs, with `_` as the
+    /// first label of a wildcard name.
+    #[serde(default)]
+    pub cert_dir: Option<PathBuf>,
+    #[serde(default)]
+    pub acme: Option<AcmeConfig>,
+    #[serde(default)]
+    pub min_version: TlsVersion,
+    /// Cipher suites by their IANA names, such as
+    /// `TLS13_AES_256_GCM_SHA384`. Empty allows every suite rustls supports.
+    #[serde(default)]
+    pub cipher_suites: Vec<String>,
+    /// Protocols offered over ALPN, most preferred first.
+    #[serde(default = "default_alpn_protocols")]
+    pub alpn_protocols: Vec<String>,
+    /// Whether to issue stateless session tickets. Without them, sessions
+    /// are only resumed from the gateway's in-memory session cache.
+    #[serde(default)]
+    pub session_tickets: bool,
+    /// Alternative services advertised in `Alt-Svc` on every response,
+    /// after the gateway's own HTTP/3 listener if there is one.
+    #[serde(default)]
+    pub alt_svc: Vec<AltService>,
+    /// An experimental HTTP/3 listener over QUIC, next to the TCP one.
+    #[serde(default)]
+    pub http3: Option<Http3Config>,
+}
+
+/// Serves the same routes over HTTP/3 on a UDP port, advertised in
+/// `Alt-Svc`. Only the default certificate is used, read at startup.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Http3Config {
+    /// The UDP address to listen on; defaults to `listen_addr`.
+    #[serde(default)]
+    pub listen_addr: Option<String>,
+    /// How long clients may remember the listener.
+    #[serde(default = "default_alt_svc_max_age")]
+    pub max_age_secs: u64,
+}
+
+/// An endpoint that serves the same origin over another protocol, such as
+/// an HTTP/3 terminator in front of the gateway on the same host.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AltService {
+    /// The ALPN protocol the endpoint speaks.
+    #[serde(default = "default_alt_svc_protocol")]
+    pub protocol: String,
+    /// Another host name, if the endpoint isn't on the gateway's host.
+    #[serde(default)]
+    pub host: Option<String>,
+    pub port: u16,
+    /// How long clients may remember the endpoint.
+    #[serde(default = "default_alt_svc_max_age")]
+    pub max_age_secs: u64,
+}
+
+fn default_alt_svc_protocol() -> String {
+    "h3".to_string()
+}
+
+fn default_alt_svc_max_age() -> u64 {
+    ALT_SVC_MAX_AGE_SECS
+}
+
+fn default_alpn_protocols() -> Vec<String> {
+    ALPN_PROTOCOLS.iter().map(|protocol| protocol.to_string()).collect()
+}
+
+/// The lowest TLS version a client may negotiate.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TlsVersion {
+    #[default]
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+/// A certificate chain and key for the names of one `tls.certificates` entry.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CertificateConfig {
+    #[serde(default)]
+    pub cert_path: Option<PathBuf>,
+    #[serde(default)]
+    pub key_path: Option<PathBuf>,
+    #[serde(default)]
+    pub cert: Option<String>,
+    #[serde(default)]
+    pub key: Option<String>,
+}
+
+/// Certificates obtained and renewed from an ACME CA such as Let's Encrypt,
+/// one per domain, kept in `cert_dir` as `<domain>.crt` and `<domain>.key`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AcmeConfig {
+    pub domains: Vec<String>,
+    pub cert_dir: PathBuf,
+    /// Contact addresses for the account, e.g. `ops@example.com`.
+    #[serde(default)]
+    pub contact: Vec<String>,
+    /// Must be `true`: the CA's terms of service are agreed to on the
+    /// operator's behalf.
+    #[serde(default)]
+    pub accept_terms: bool,
+    #[serde(default = "default_acme_directory")]
+    pub directory_url: String,
+    #[serde(default)]
+    pub challenge: AcmeChallenge,
+    /// Where `http-01` challenges are answered; the CA connects to port 80.
+    #[serde(default = "default_acme_http_listen_addr")]
+    pub http_listen_addr: String,
+    #[serde(default = "default_acme_renew_before_days")]
+    pub renew_before_days: u64,
+    #[serde(default = "default_acme_check_interval")]
+    pub check_interval_secs: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AcmeChallenge {
+    /// Answered on the TLS listener itself, which must be reachable on 443.
+    #[default]
+    #[serde(rename = "tls-alpn-01")]
+    TlsAlpn01,
+    /// Answered over plain HTTP on `http_listen_addr`.
+    #[serde(rename = "http-01")]
+    Http01,
+}
+
+fn default_acme_directory() -> String {
+    LETS_ENCRYPT_DIRECTORY.to_string()
+}
+
+fn default_acme_http_listen_addr() -> String {
+    "0.0.0.0:80".to_string()
+}
+
+fn default_acme_renew_before_days() -> u64 {
+    ACME_RENEW_BEFORE_DAYS
+}
+
+fn default_acme_check_interval() -> u64 {
+    ACME_CHECK_INTERVAL_SECS
+}
+
+/// Where secret references are fetched from. Any string in the
+/// configuration of the form `vault:<mount>/<path>#<key>`, or an AWS
+/// Secrets Manager ARN with an optional `#<key>`, stands for the secret's
+/// value.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecretsConfig {
+    pub vault: Option<VaultConfig>,
+    pub aws: AwsSecretsConfig,
+    /// How often referenced secrets are fetched again; 0 fetches them only
+    /// at startup.
+    pub refresh_interval_secs: u64,
+}
+
+impl Default for SecretsConfig {
+    fn default() -> Self {
+        Self {
+            vault: None,
+            aws: AwsSecretsConfig::default(),
+            refresh_interval_secs: SECRETS_REFRESH_INTERVAL_SECS,
+        }
+    }
+}
+
+/// A Postgres or SQLite database holding what the admin API changes and
+/// what otherwise lives only in memory: issued API keys, deployment
+/// switches, route definitions from the cluster store, tenant quota usage
+/// and the audit trail, so all of it survives restarts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PersistenceConfig {
+    /// `postgres://host:port/database`, or `sqlite://path` for a file
+    /// created if missing.
+    pub url: String,
+    pub username: String,
+    pub password: Option<String>,
+    /// How often tenant quota usage is written.
+    pub quota_snapshot_secs: u64,
+    /// Deadline for each statement.
+    pub timeout_ms: u64,
+    /// Most writes waiting for the database; more are dropped.
+    pub queue_capacity: usize,
+    /// The CA bundle the Postgres server's certificate must chain to, and
+    /// the client certificate to present, for the TLS connection.
+    pub tls: UpstreamTlsConfig,
+    /// Connects without TLS to a Postgres server that doesn't offer it.
+    pub allow_plaintext: bool,
+}
+
+impl Default for PersistenceConfig {
+    fn default() -> Self {
+        Self {
+            url: "postgres://127.0.0.1:5432/gateway".to_string(),
+            username: "gateway".to_string(),
+            password: None,
+            quota_snapshot_secs: QUOTA_SNAPSHOT_INTERVAL_SECS,
+            timeout_ms: 5000,
+            queue_capacity: PERSISTENCE_QUEUE_CAPACITY,
+            tls: UpstreamTlsConfig::default(),
+            allow_plaintext: false,
+        }
+    }
+}
+
+/// Lifecycle events a webhook can be sent for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEvent {
+    /// A new cluster configuration version, or refreshed secrets, went live.
+    ConfigReloaded,
+    /// A tenant used up its quota for the current window.
+    QuotaExceeded,
+    /// A certificate being served expires within `certificate_warning_days`.
+    CertificateExpiring,
+    /// Passive health checks marked an upstream endpoint unhealthy.
+    UpstreamUnhealthy,
+}
+
+/// JSON POSTs to chat or paging integrations when something happens to the
+/// gateway that an operator should hear about.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhooksConfig {
+    pub endpoints: Vec<WebhookEndpoint>,
+    pub certificate_warning_days: u64,
+    /// Tries per delivery, the first included.
+    pub max_attempts: u32,
+    pub timeout_ms: u64,
+}
+
+impl Default for WebhooksConfig {
+    fn default() -> Self {
+        Self {
+            endpoints: Vec::new(),
+            certificate_warning_days: CERTIFICATE_WARNING_DAYS,
+            max_attempts: WEBHOOK_ATTEMPTS,
+            timeout_ms: WEBHOOK_TIMEOUT_MS,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookEndpoint {
+    pub url: String,
+    /// The events sent here; every event when empty.
+    #[serde(default)]
+    pub events: Vec<WebhookEvent>,
+    /// Key for the HMAC-SHA256 signature of each body.
+    #[serde(default)]
+    pub secret: Option<String>,
+}
+
+/// A request the gateway sends through its own pipeline on a schedule, to
+/// catch a broken route, auth setting or upstream before clients do.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SyntheticCheckConfig {
+    pub name: String,
+    #[serde(default = "default_synthetic_method")]
+    pub method: String,
+    /// Path and query, as a client would send them.
+    pub path: String,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    #[serde(default)]
+    pub body: String,
+    /// The status that counts as success; any 2xx when unset.
+    #[serde(default)]
+    pub expect_status: Option<u16>,
+    #[serde(default = "default_synthetic_interval")]
+    pub interval_secs: u64,
+    #[serde(default = "default_synthetic_timeout")]
+    pub timeout_ms: u64,
+}
+
+fn default_synthetic_method() -> String {
+    "GET".to_string()
+}
+
+fn default_synthetic_interval() -> u64 {
+    SYNTHETIC_CHECK_INTERVAL_SECS
+}
+
+fn default_synthetic_timeout() -> u64 {
+    SYNTHETIC_CHECK_TIMEOUT_MS
+}
+
+/// One OpenAPI document for every backend: each source's document is
+/// fetched from its route's upstream, its paths rewritten to the ones
+/// clients use, and the results merged. `server_url` is the gateway's public
+/// address; the document is served relative to wherever it was fetched from
+/// if unset.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OpenApiConfig {
+    pub title: String,
+    pub version: String,
+    pub server_url: Option<String>,
+    pub sources: Vec<OpenApiSource>,
+    /// Where Swagger UI is served for the merged document; not served if
+    /// unset.
+    pub swagger_ui_path: Option<String>,
+    /// The pinned Swagger UI release and its Subresource Integrity hashes;
+    /// required with `swagger_ui_path`.
+    pub swagger_ui_assets: Option<SwaggerUiAssets>,
+    pub refresh_interval_secs: u64,
+    pub timeout_ms: u64,
+}
+
+impl Default for OpenApiConfig {
+    fn default() -> Self {
+        Self {
+            title: "API Gateway".to_string(),
+            version: "1.0.0".to_string(),
+            server_url: None,
+            sources: Vec::new(),
+            swagger_ui_path: None,
+            swagger_ui_assets: None,
+            refresh_interval_secs: OPENAPI_REFRESH_INTERVAL_SECS,
+            timeout_ms: OPENAPI_TIMEOUT_MS,
+        }
+    }
+}
+
+/// An exact swagger-ui-dist `version`, with the `integrity` values browsers
+/// check `swagger-ui.css` and `swagger-ui-bundle.js` against.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SwaggerUiAssets {
+    #[serde(default = "default_swagger_ui_version")]
+    pub version: String,
+    pub css_integrity: String,
+    pub bundle_integrity: String,
+}
+
+fn default_swagger_ui_version() -> String {
+    SWAGGER_UI_VERSION.to_string()
+}
+
+/// A backend's OpenAPI 3 document, found at `path` on the upstream of the
+/// route named `route`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OpenApiSource {
+    pub route: String,
+    #[serde(default = "default_openapi_path")]
+    pub path: String,
+}
+
+fn default_openapi_path() -> String {
+    OPENAPI_SPEC_PATH.to_string()
+}
+
+/// A page where API key holders see their limits and recent usage, served
+/// at `path`, with the figures as JSON at `<path>/usage`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PortalConfig {
+    pub path: String,
+}
+
+impl Default for PortalConfig {
+    fn default() -> Self {
+        Self { path: PORTAL_PATH.to_string() }
+    }
+}
+
+/// The day's top routes, key holders and endpoints, listed at
+/// `/admin/analytics`. With `persistence`, the figures are written every
+/// `snapshot_secs` and picked up again after a restart.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AnalyticsConfig {
+    /// Entries in each list, unless the request asks for another number.
+    pub top: usize,
+    pub snapshot_secs: u64,
+}
+
+impl Default for AnalyticsConfig {
+    fn default() -> Self {
+        Self { top: ANALYTICS_TOP, snapshot_secs: ANALYTICS_SNAPSHOT_INTERVAL_SECS }
+    }
+}
+
+/// One JSON event per answered request, published to Kafka or NATS for
+/// analytics pipelines. Events wait in a queue of `queue_capacity` and go
+/// out `batch_size` at a time, or `flush_interval_ms` after the first of a
+/// batch. Events that find the queue full, or the sink unreachable, are
+/// dropped and counted rather than holding up requests.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EventsConfig {
+    pub sink: EventSinkConfig,
+    #[serde(default = "default_event_batch_size")]
+    pub batch_size: usize,
+    #[serde(default = "default_event_flush_interval")]
+    pub flush_interval_ms: u64,
+    #[serde(default = "default_event_queue_capacity")]
+    pub queue_capacity: usize,
+    /// Deadline for publishing each batch, connecting included.
+    #[serde(default = "default_event_sink_timeout")]
+    pub timeout_ms: u64,
+}
+
+fn default_event_batch_size() -> usize {
+    EVENT_BATCH_SIZE
+}
+
+fn default_event_flush_interval() -> u64 {
+    EVENT_FLUSH_INTERVAL_MS
+}
+
+fn default_event_queue_capacity() -> usize {
+    EVENT_QUEUE_CAPACITY
+}
+
+fn default_event_sink_timeout() -> u64 {
+    EVENT_SINK_TIMEOUT_MS
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum EventSinkConfig {
+    /// A Kafka topic. `brokers` are `host:port` addresses to bootstrap
+    /// from; with `tls` the brokers are reached over TLS, and with `sasl`
+    /// the gateway logs in.
+    Kafka {
+        brokers: Vec<String>,
+        topic: String,
+        #[serde(default)]
+        tls: Option<UpstreamTlsConfig>,
+        #[serde(default)]
+        sasl: Option<KafkaSaslConfig>,
+    },
+    /// A NATS subject on the server at `nats://host:port`, reached over TLS
+    /// with `tls`. A password without a username is sent as the server's
+    /// auth token.
+    Nats {
+        url: String,
+        subject: String,
+        #[serde(default)]
+        username: Option<String>,
+        #[serde(default)]
+        password: Option<String>,
+        #[serde(default)]
+        tls: Option<UpstreamTlsConfig>,
+    },
+}
+
+/// The account the gateway logs in to Kafka with.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KafkaSaslConfig {
+    pub mechanism: SaslMechanism,
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SaslMechanism {
+    Plain,
+    ScramSha256,
+    ScramSha512,
+}
+
+/// One span per request, exported as OTLP/JSON to a collector's `endpoint`
+/// (such as `http://collector:4318/v1/traces`). Requests whose client sent
+/// a sampled `traceparent` are always traced, unsampled ones never, and
+/// `sample_rate` of the rest. Spans are queued and exported in batches like
+/// access events, and dropped rather than holding up requests.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TracingConfig {
+    pub endpoint: String,
+    #[serde(default = "default_trace_service_name")]
+    pub service_name: String,
+    #[serde(default = "default_trace_sample_rate")]
+    pub sample_rate: f64,
+    #[serde(default = "default_trace_batch_size")]
+    pub batch_size: usize,
+    #[serde(default = "default_trace_flush_interval")]
+    pub flush_interval_ms: u64,
+    #[serde(default = "default_trace_queue_capacity")]
+    pub queue_capacity: usize,
+    /// Deadline for exporting each batch.
+    #[serde(default = "default_trace_export_timeout")]
+    pub timeout_ms: u64,
+}
+
+fn default_trace_service_name() -> String {
+    TRACE_SERVICE_NAME.to_string()
+}
+
+fn default_trace_sample_rate() -> f64 {
+    1.0
+}
+
+fn default_trace_batch_size() -> usize {
+    TRACE_BATCH_SIZE
+}
+
+fn default_trace_flush_interval() -> u64 {
+    TRACE_FLUSH_INTERVAL_MS
+}
+
+fn default_trace_queue_capacity() -> usize {
+    TRACE_QUEUE_CAPACITY
+}
+
+fn default_trace_export_timeout() -> u64 {
+    TRACE_EXPORT_TIMEOUT_MS
+}
+
+/// Copies the access log and error logs to a syslog collector at `address`
+/// (`host:port`), as RFC 5424 messages: one per datagram over UDP, framed
+/// by their length over TCP and TLS. Lines wait in a queue of
+/// `queue_capacity`, and are dropped and counted when it is full or the
+/// collector can't be reached.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SyslogConfig {
+    pub address: String,
+    #[serde(default)]
+    pub transport: SyslogTransport,
+    #[serde(default)]
+    pub facility: SyslogFacility,
+    #[serde(default = "default_syslog_app_name")]
+    pub app_name: String,
+    /// Defaults to the machine's host name.
+    #[serde(default)]
+    pub hostname: Option<String>,
+    #[serde(default = "default_syslog_queue_capacity")]
+    pub queue_capacity: usize,
+}
+
+fn default_syslog_app_name() -> String {
+    SYSLOG_APP_NAME.to_string()
+}
+
+fn default_syslog_queue_capacity() -> usize {
+    SYSLOG_QUEUE_CAPACITY
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum SyslogTransport {
+    #[default]
+    Udp,
+    Tcp,
+    /// TLS trusting `ca_path`, or the public roots without one, presenting
+    /// the client certificate if there is one.
+    Tls(UpstreamTlsConfig),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SyslogFacility {
+    User,
+    Daemon,
+    #[default]
+    Local0,
+    Local1,
+    Local2,
+    Local3,
+    Local4,
+    Local5,
+    Local6,
+    Local7,
+}
+
+impl SyslogFacility {
+    /// The facility's number in a message's priority.
+    pub fn code(self) -> u8 {
+        match self {
+            SyslogFacility::User => 1,
+            SyslogFacility::Daemon => 3,
+            SyslogFacility::Local0 => 16,
+            SyslogFacility::Local1 => 17,
+            SyslogFacility::Local2 => 18,
+            SyslogFacility::Local3 => 19,
+            SyslogFacility::Local4 => 20,
+            SyslogFacility::Local5 => 21,
+            SyslogFacility::Local6 => 22,
+            SyslogFacility::Local7 => 23,
+        }
+    }
+}
+
+/// A shared store the instances of a cluster take their routes, route
+/// groups, policies and auth settings from, in place of the file's. It is
+/// polled every `poll_interval_secs` and a new version applied when valid.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClusterConfig {
+    pub source: ClusterSource,
+    #[serde(default = "default_cluster_poll_interval")]
+    pub poll_interval_secs: u64,
+    /// Elects one instance to run the jobs the whole cluster needs only once.
+    #[serde(default)]
+    pub leader_election: Option<LeaderElectionConfig>,
+}
+
+fn default_cluster_poll_interval() -> u64 {
+    CLUSTER_POLL_INTERVAL_SECS
+}
+
+/// A lock held in the cluster's Consul or etcd under `key`. The leader
+/// renews it every third of `ttl_secs`; if it stops, another instance takes
+/// over once the TTL runs out.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LeaderElectionConfig {
+    pub key: String,
+    pub ttl_secs: u64,
+}
+
+impl Default for LeaderElectionConfig {
+    fn default() -> Self {
+        Self { key: LEADER_KEY.to_string(), ttl_secs: LEADER_TTL_SECS }
+    }
+}
+
+/// Where the shared configuration document is kept.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum ClusterSource {
+    /// A Consul KV key. The ACL token is read from `token_file` on every
+    /// fetch, or else taken from `CONSUL_HTTP_TOKEN` if set.
+    Consul {
+        address: String,
+        key: String,
+        #[serde(default)]
+        token_file: Option<PathBuf>,
+    },
+    /// An etcd v3 key, read through its JSON gateway.
+    Etcd {
+        address: String,
+        key: String,
+        #[serde(default)]
+        username: Option<String>,
+        #[serde(default)]
+        password: Option<String>,
+    },
+    /// An S3 object, read with the credentials in the environment.
+    S3 {
+        bucket: String,
+        key: String,
+        region: String,
+        #[serde(default)]
+        endpoint: Option<String>,
+    },
+}
+
+/// A Vault server and its KV engine version. The token is read from
+/// `token_file` on every fetch, so a Vault agent can rotate it, or else
+/// taken from `VAULT_TOKEN`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VaultConfig {
+    pub address: String,
+    #[serde(default)]
+    pub token_file: Option<PathBuf>,
+    #[serde(default)]
+    pub namespace: Option<String>,
+    #[serde(default = "default_kv_version")]
+    pub kv_version: u8,
+}
+
+fn default_kv_version() -> u8 {
+    2
+}
+
+/// AWS Secrets Manager, signed with the credentials in `AWS_ACCESS_KEY_ID`,
+/// `AWS_SECRET_ACCESS_KEY` and `AWS_SESSION_TOKEN`. Requests go to the
+/// ARN's regional endpoint unless `endpoint` replaces it, e.g. with a VPC
+/// endpoint.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AwsSecretsConfig {
+    pub endpoint: Option<String>,
+}
+
+/// Client authentication beyond the static bearer tokens, and the key used to
+/// mint internal tokens for `token_exchange` routes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// JSON object of bearer token to user id, replacing the built-in tokens.
+    pub api_keys_file: Option<PathBuf>,
+    /// How often the API key and JWT key files are checked for changes.
+    pub reload_interval_secs: u64,
+    pub client_jwt: Option<ClientJwtConfig>,
+    pub internal_jwt: Option<InternalJwtConfig>,
+    /// API keys issued through the admin API, accepted alongside the others.
+    pub key_store: Option<ApiKeyStoreConfig>,
+    /// Serves `POST /token`, trading issued API keys for gateway JWTs.
+    pub token_endpoint: Option<TokenEndpointConfig>,
+    /// Signs browsers in through an OpenID Connect provider.
+    pub oidc: Option<OidcConfig>,
+    /// Claim name to upstream header, e.g. `"tenant": "X-Tenant"`. The headers
+    /// are always removed from client requests so they can't be spoofed.
+    pub claim_headers: HashMap<String, String>,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            api_keys_file: None,
+            reload_interval_secs: KEY_RELOAD_INTERVAL_SECS,
+            client_jwt: None,
+            internal_jwt: None,
+            key_store: None,
+            token_endpoint: None,
+            oidc: None,
+            claim_headers: HashMap::new(),
+        }
+    }
+}
+
+/// Where issued API keys are kept. Only argon2id hashes of the keys are
+/// written to `path`; with `kms` the whole file is also encrypted with a data
+/// key from AWS KMS.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApiKeyStoreConfig {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub kms: Option<KmsConfig>,
+    #[serde(default = "default_argon2_memory_kib")]
+    pub argon2_memory_kib: u32,
+    #[serde(default = "default_argon2_iterations")]
+    pub argon2_iterations: u32,
+    /// Slows down and then locks out a key whose secret keeps being wrong.
+    #[serde(default)]
+    pub lockout: Option<LockoutConfig>,
+}
+
+fn default_argon2_memory_kib() -> u32 {
+    ARGON2_MEMORY_KIB
+}
+
+fn default_argon2_iterations() -> u32 {
+    ARGON2_ITERATIONS
+}
+
+/// Failed logins per key id. After `free_attempts` failures each further
+/// attempt must wait `backoff_base_ms`, doubling with every failure; at
+/// `max_failures` the key is locked for `lockout_secs`. A successful login
+/// starts over, as does a quiet `lockout_secs` without failures.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LockoutConfig {
+    pub free_attempts: u32,
+    pub backoff_base_ms: u64,
+    pub max_failures: u32,
+    pub lockout_secs: u64,
+}
+
+impl Default for LockoutConfig {
+    fn default() -> Self {
+        Self {
+            free_attempts: LOGIN_FREE_ATTEMPTS,
+            backoff_base_ms: LOGIN_BACKOFF_BASE_MS,
+            max_failures: LOGIN_MAX_FAILURES,
+            lockout_secs: LOGIN_LOCKOUT_SECS,
+        }
+    }
+}
+
+/// The OAuth2 client-credentials grant at `/token`: an issued key's id and
+/// secret buy a JWT signed with `auth.internal_jwt`, valid for `ttl_secs`.
+/// The JWT's `aud` is `audience`, never the internal tokens' one, so backends
+/// checking `internal_jwt.audience` don't take it for an internal token.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TokenEndpointConfig {
+    #[serde(default = "default_issued_token_ttl")]
+    pub ttl_secs: u64,
+    #[serde(default = "default_issued_token_audience")]
+    pub audience: String,
+}
+
+impl Default for TokenEndpointConfig {
+    fn default() -> Self {
+        Self { ttl_secs: ISSUED_TOKEN_TTL_SECS, audience: default_issued_token_audience() }
+    }
+}
+
+fn default_issued_token_ttl() -> u64 {
+    ISSUED_TOKEN_TTL_SECS
+}
+
+fn default_issued_token_audience() -> String {
+    ISSUED_TOKEN_AUDIENCE.to_string()
+}
+
+/// The gateway as an OpenID Connect relying party. Browsers without
+/// credentials are sent to `issuer` with the authorization-code flow, which
+/// returns to `redirect_uri` on the gateway; the signed-in user is then kept
+/// in a session cookie.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OidcConfig {
+    /// Where `/.well-known/openid-configuration` is found.
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub redirect_uri: String,
+    #[serde(default = "default_oidc_scopes")]
+    pub scopes: Vec<String>,
+    /// Clears the session and redirects to `/`.
+    #[serde(default = "default_sign_out_path")]
+    pub sign_out_path: String,
+    pub session: SessionConfig,
+    #[serde(default)]
+    pub csrf: CsrfConfig,
+}
+
+fn default_oidc_scopes() -> Vec<String> {
+    ["openid", "email", "profile"].iter().map(|scope| scope.to_string()).collect()
+}
+
+fn default_sign_out_path() -> String {
+    "/oauth2/sign_out".to_string()
+}
+
+/// The session cookie. `secret` seals it, so rotating the secret signs
+/// everyone out.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SessionConfig {
+    pub secret: String,
+    #[serde(default = "default_session_cookie")]
+    pub cookie_name: String,
+    #[serde(default = "default_session_ttl")]
+    pub ttl_secs: u64,
+    /// Marks the cookies `Secure`; turn off only for plain-HTTP development.
+    #[serde(default = "default_true")]
+    pub secure: bool,
+}
+
+/// How state-changing requests that rely on the session cookie show they
+/// come from the application's own pages. `header` carries the token, which
+/// pages read from the `cookie_name` cookie.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CsrfConfig {
+    pub mode: CsrfMode,
+    pub header: String,
+    pub cookie_name: String,
+}
+
+impl Default for CsrfConfig {
+    fn default() -> Self {
+        Self {
+            mode: CsrfMode::DoubleSubmit,
+            header: CSRF_HEADER.to_string(),
+            cookie_name: CSRF_COOKIE.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CsrfMode {
+    /// The header must repeat the token the gateway set in the CSRF cookie,
+    /// which is bound to the session.
+    DoubleSubmit,
+    /// The header must be present. Browsers only add it to cross-site
+    /// requests after a CORS preflight.
+    CustomHeader,
+    Off,
+}
+
+fn default_session_cookie() -> String {
+    SESSION_COOKIE.to_string()
+}
+
+fn default_session_ttl() -> u64 {
+    SESSION_TTL_SECS
+}
+
+/// A KMS key, by ARN or by id or alias together with its `region`.
+/// `endpoint` replaces the regional KMS endpoint.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KmsConfig {
+    pub key_id: String,
+    #[serde(default)]
+    pub region: Option<String>,
+    #[serde(default)]
+    pub endpoint: Option<String>,
+}
+
+impl KmsConfig {
+    /// `region`, or else the region of a key ARN.
+    pub fn region(&self) -> Option<String> {
+        self.region.clone().or_else(|| {
+            let parts: Vec<&str> = self.key_id.split(':').collect();
+            (parts.len() >= 6 && parts[0] == "arn" && !parts[3].is_empty()).then(|| parts[3].to_string())
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum JwtAlgorithm {
+    HS256,
+    RS256,
+    ES256,
+}
+
+/// Accepts bearer JWTs signed by an external issuer. HS256 uses `secret`, the
+/// others a PEM public key in `key_file` or inline in `key`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientJwtConfig {
+    pub algorithm: JwtAlgorithm,
+    #[serde(default)]
+    pub secret: Option<String>,
+    #[serde(default)]
+    pub key: Option<String>,
+    #[serde(default)]
+    pub key_file: Option<PathBuf>,
+    #[serde(default)]
+    pub issuer: Option<String>,
+    #[serde(default)]
+    pub audience: Option<String>,
+}
+
+/// Signs the short-lived tokens sent upstream by `token_exchange` routes.
+/// RS256 and ES256 take a PEM private key in `key_file` or `key`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InternalJwtConfig {
+    pub algorithm: JwtAlgorithm,
+    #[serde(default)]
+    pub secret: Option<String>,
+    #[serde(default)]
+    pub key: Option<String>,
+    #[serde(default)]
+    pub key_file: Option<PathBuf>,
+    #[serde(default = "default_internal_issuer")]
+    pub issuer: String,
+    #[serde(default)]
+    pub audience: Option<String>,
+    #[serde(default = "default_internal_ttl")]
+    pub ttl_secs: u64,
+}
+
+fn default_internal_issuer() -> String {
+    "api-gateway".to_string()
+}
+
+fn default_internal_ttl() -> u64 {
+    INTERNAL_JWT_TTL_SECS
+}
+
+/// Memory bounds for the rate limiter. Buckets whose window has passed are
+/// swept every `sweep_interval_secs`; once `max_buckets` is reached the least
+/// recently used are dropped.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitingConfig {
+    pub max_buckets: usize,
+    pub sweep_interval_secs: u64,
+    pub tarpit: Option<TarpitConfig>,
+}
+
+impl Default for RateLimitingConfig {
+    fn default() -> Self {
+        Self {
+            max_buckets: MAX_RATE_LIMIT_BUCKETS,
+            sweep_interval_secs: RATE_LIMIT_WINDOW_SECS,
+            tarpit: None,
+        }
+    }
+}
+
+/// Memory bounds for the response cache. Bodies over `max_object_bytes` are
+/// never stored. Once `max_entries` are held, a new response replaces the
+/// oldest entry, with `admission: tinylfu` only if its key has recently been
+/// asked for more often than the oldest entry's. `max_idempotency_records`
+/// bounds the responses kept for `Idempotency-Key` retries the same way.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    pub max_entries: usize,
+    pub max_object_bytes: usize,
+    pub max_idempotency_records: usize,
+    pub admission: CacheAdmission,
+    pub key: CacheKeyConfig,
+    pub control: Option<CacheControlConfig>,
+    /// A second tier shared by every replica.
+    pub redis: Option<RedisCacheConfig>,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            max_entries: MAX_CACHE_ENTRIES,
+            max_object_bytes: MAX_CACHED_OBJECT_BYTES,
+            max_idempotency_records: MAX_IDEMPOTENCY_RECORDS,
+            admission: CacheAdmission::default(),
+            key: CacheKeyConfig::default(),
+            control: None,
+            redis: None,
+        }
+    }
+}
+
+/// How a request is reduced to its cache key, so requests that only differ
+/// in ways the upstream ignores share an entry. The upstream still gets the
+/// path and query as sent. `ignore_query_params` names parameters left out
+/// of the key; one ending in `*` leaves out every name starting with the
+/// rest, such as `utm_*`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheKeyConfig {
+    pub lowercase_path: bool,
+    pub sort_query: bool,
+    pub ignore_query_params: Vec<String>,
+}
+
+impl CacheKeyConfig {
+    /// Whether the query is rewritten before it goes into the key.
+    pub fn normalizes_query(&self) -> bool {
+        self.sort_query || !self.ignore_query_params.is_empty()
+    }
+
+    /// Whether the query parameter `name` is left out of the key.
+    pub fn ignores(&self, name: &str) -> bool {
+        self.ignore_query_params.iter().any(|ignored| match ignored.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix),
+            None => name == ignored,
+        })
+    }
+}
+
+/// Lets trusted callers skip the cache for one request with
+/// `X-Gateway-Cache: bypass`, or fetch a fresh response and store it over
+/// the cached one with `refresh`. Callers holding an admin token are
+/// trusted, and so are those granted `scope`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheControlConfig {
+    pub scope: Option<String>,
+}
+
+/// The Redis at `url` (`redis://host:port/database`) holds every cached
+/// response up to `max_object_bytes` under `key_prefix`; the in-memory cache
+/// keeps the small, hot ones. Replicas announce the keys they store and the
+/// purges they are given on `channel` so the others drop stale in-memory
+/// copies. With `store_responses` off, Redis only carries the purges.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RedisCacheConfig {
+    pub url: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub store_responses: bool,
+    pub key_prefix: String,
+    pub channel: String,
+    pub max_object_bytes: usize,
+    /// Deadline for each Redis command; on failure the cache is skipped.
+    pub timeout_ms: u64,
+}
+
+impl Default for RedisCacheConfig {
+    fn default() -> Self {
+        Self {
+            url: "redis://127.0.0.1:6379".to_string(),
+            username: None,
+            password: None,
+            store_responses: true,
+            key_prefix: "gateway:cache:".to_string(),
+            channel: "gateway:cache:invalidate".to_string(),
+            max_object_bytes: 16 * 1024 * 1024,
+            timeout_ms: 250,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheAdmission {
+    /// Admit by recent request frequency, kept in a TinyLFU sketch.
+    #[default]
+    TinyLfu,
+    /// Always admit, evicting the oldest entry.
+    Always,
+}
+
+/// Escalating delays for clients that keep getting 429s. From
+/// `soft_threshold` strikes their requests wait `base_delay_ms`, doubling per
+/// strike up to `max_delay_ms`; from `hard_threshold` they wait the maximum
+/// and are refused. Strikes are forgotten after `reset_secs` without one.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TarpitConfig {
+    pub soft_threshold: u32,
+    pub hard_threshold: u32,
+    pub base_delay_ms: u64,
+    pub max_delay_ms: u64,
+    pub reset_secs: u64,
+}
+
+impl Default for TarpitConfig {
+    fn default() -> Self {
+        Self {
+            soft_threshold: 3,
+            hard_threshold: 20,
+            base_delay_ms: 250,
+            max_delay_ms: 10_000,
+            reset_secs: 300,
+        }
+    }
+}
+
+/// Tokio runtime sizing. Unset values keep Tokio's defaults: one worker per
+/// CPU and up to 512 blocking threads.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RuntimeConfig {
+    pub worker_threads: Option<usize>,
+    pub max_blocking_threads: Option<usize>,
+}
+
+/// How upstream connections are opened. A host name's addresses are tried
+/// in the order `ip_preference` puts them in, and when the first family
+/// hasn't connected after `happy_eyeballs_delay_ms`, the other family is
+/// raced against it (RFC 8305). `0` tries the addresses one at a time.
+/// `tls` holds the TLS settings of https upstreams, keyed by upstream URL;
+/// their files are checked for changes every `tls_reload_interval_secs`.
+/// Connections go through `proxy` unless `upstream_proxies` names another
+/// proxy, or `direct`, for the upstream. With `dns_cache`, host names are
+/// resolved through the cache instead of on every new connection.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamConnectConfig {
+    pub ip_preference: IpPreference,
+    pub happy_eyeballs_delay_ms: u64,
+    pub tls: HashMap<String, UpstreamTlsConfig>,
+    pub tls_reload_interval_secs: u64,
+    pub proxy: Option<ProxyConfig>,
+    pub upstream_proxies: HashMap<String, ProxyConfig>,
+    pub dns_cache: Option<DnsCacheConfig>,
+}
+
+impl Default for UpstreamConnectConfig {
+    fn default() -> Self {
+        Self {
+            ip_preference: IpPreference::default(),
+            happy_eyeballs_delay_ms: HAPPY_EYEBALLS_DELAY_MS,
+            tls: HashMap::new(),
+            tls_reload_interval_secs: KEY_RELOAD_INTERVAL_SECS,
+            proxy: None,
+            upstream_proxies: HashMap::new(),
+            dns_cache: None,
+        }
+    }
+}
+
+/// Upstream host names' addresses, kept for their records' TTL clamped to
+/// `min_ttl_secs`..`max_ttl_secs`. Names are asked of `nameservers` (those
+/// in /etc/resolv.conf by default) directly; names without a dot or that
+/// the nameservers don't know go to the system resolver, which also reads
+/// the hosts file and search domains, and are kept for `min_ttl_secs`.
+/// Names that don't resolve at all are remembered for `negative_ttl_secs`.
+/// Truncated answers are asked for again over TCP.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DnsCacheConfig {
+    pub nameservers: Vec<String>,
+    pub min_ttl_secs: u64,
+    pub max_ttl_secs: u64,
+    pub negative_ttl_secs: u64,
+    pub timeout_ms: u64,
+}
+
+impl Default for DnsCacheConfig {
+    fn default() -> Self {
+        Self {
+            nameservers: Vec::new(),
+            min_ttl_secs: DNS_MIN_TTL_SECS,
+            max_ttl_secs: DNS_MAX_TTL_SECS,
+            negative_ttl_secs: DNS_NEGATIVE_TTL_SECS,
+            timeout_ms: DNS_TIMEOUT_MS,
+        }
+    }
+}
+
+/// Passive health checking of upstream endpoints (host and port), judged
+/// by the requests clients send them. An endpoint is marked unhealthy
+/// after `connect_failures` connections that couldn't be opened or
+/// `server_errors` 5xx responses in a row, and healthy again after
+/// `healthy_after` responses in a row that were neither.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamHealthConfig {
+    pub connect_failures: u32,
+    pub server_errors: u32,
+    pub healthy_after: u32,
+}
+
+impl Default for UpstreamHealthConfig {
+    fn default() -> Self {
+        Self {
+            connect_failures: UNHEALTHY_CONNECT_FAILURES,
+            server_errors: UNHEALTHY_SERVER_ERRORS,
+            healthy_after: HEALTHY_SUCCESSES,
+        }
+    }
+}
+
+/// Resilience drills: while `enabled`, each request to a healthy upstream
+/// endpoint fails with probability `fraction` as if the endpoint refused
+/// the connection. Admins turn it on and off at runtime.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FailureInjectionConfig {
+    pub enabled: bool,
+    pub fraction: f64,
+}
+
+impl Default for FailureInjectionConfig {
+    fn default() -> Self {
+        Self { enabled: false, fraction: FAILURE_INJECTION_FRACTION }
+    }
+}
+
+/// How the gateway connects to one https upstream: the client certificate
+/// it presents for mTLS, and the CA bundle the upstream's certificate must
+/// chain to in place of the public roots.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamTlsConfig {
+    #[serde(default)]
+    pub cert_path: Option<PathBuf>,
+    #[serde(default)]
+    pub key_path: Option<PathBuf>,
+    #[serde(default)]
+    pub ca_path: Option<PathBuf>,
+}
+
+/// An egress proxy: an `http://` proxy that allows `CONNECT`, or a
+/// `socks5://` (names resolved by the gateway) or `socks5h://` (names
+/// resolved by the proxy) one.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProxyConfig {
+    pub url: String,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+/// Which address family upstream connections try first, or use at all.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IpPreference {
+    /// The resolver's order.
+    #[default]
+    System,
+    PreferIpv6,
+    PreferIpv4,
+    Ipv4Only,
+    Ipv6Only,
+}
+
+impl IpPreference {
+    pub fn allows(self, ip: IpAddr) -> bool {
+        match self {
+            IpPreference::Ipv4Only => ip.is_ipv4(),
+            IpPreference::Ipv6Only => ip.is_ipv6(),
+            _ => true,
+        }
+    }
+}
+
+/// Options for the listening socket. `reuse_port` lets several gateway
+/// processes share `listen_addr`, with the kernel spreading connections
+/// between them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SocketConfig {
+    pub tcp_nodelay: bool,
+    pub reuse_port: bool,
+    pub backlog: u32,
+}
+
+impl Default for SocketConfig {
+    fn default() -> Self {
+        Self {
+            tcp_nodelay: true,
+            reuse_port: false,
+            backlog: LISTEN_BACKLOG,
+        }
+    }
+}
+
+/// The Prometheus endpoint at `/metrics`. `labels` picks which of
+/// `METRIC_LABELS` and route tags the upstream latency histogram is broken
+/// down by, a tag being empty for routes without it; once
+/// `max_series` label combinations exist, new ones are counted under
+/// `"other"` rather than growing the output further.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    pub enabled: bool,
+    pub buckets: Vec<f64>,
+    pub labels: Vec<String>,
+    pub max_series: usize,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            buckets: LATENCY_BUCKETS.to_vec(),
+            labels: vec!["route".to_string(), "upstream".to_string(), "status_class".to_string()],
+            max_series: MAX_METRIC_SERIES,
+        }
+    }
+}
+
+/// Detailed logging of a sample of requests: `sample_rate` of all requests,
+/// plus any carrying `header` with an admin token as its value. Up to
+/// `max_body_bytes` of each body are included; 0 leaves bodies out.
+/// `redaction` applies to everything logged.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DebugLogConfig {
+    pub sample_rate: f64,
+    pub header: String,
+    pub max_body_bytes: usize,
+}
+
+impl Default for DebugLogConfig {
+    fn default() -> Self {
+        Self {
+            sample_rate: 0.0,
+            header: DEBUG_LOG_HEADER.to_string(),
+            max_body_bytes: 0,
+        }
+    }
+}
+
+/// Header names and JSON field or query parameter names redacted from logs,
+/// the audit log and `/admin/config`, on top of the built-in ones.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RedactionConfig {
+    pub headers: Vec<String>,
+    pub fields: Vec<String>,
+}
+
+/// CORS preflights for a routed path and method are answered by the gateway
+/// itself. Browsers reuse the answer for `max_age_secs`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    pub preflight: bool,
+    pub max_age_secs: u64,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self { preflight: true, max_age_secs: PREFLIGHT_MAX_AGE_SECS }
+    }
+}
+
+/// A challenge, such as a CAPTCHA, for clients with `strikes` tarpit
+/// strikes. Solutions are posted to `verify_url` with `secret`; `site_key`
+/// is handed to the client for rendering the widget.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChallengeConfig {
+    pub verify_url: String,
+    pub secret: String,
+    #[serde(default)]
+    pub site_key: Option<String>,
+    #[serde(default = "default_challenge_strikes")]
+    pub strikes: u32,
+    #[serde(default = "default_challenge_ttl_secs")]
+    pub token_ttl_secs: u64,
+    /// 428 Precondition Required or 401 Unauthorized.
+    #[serde(default = "default_challenge_status")]
+    pub status: u16,
+}
+
+fn default_challenge_strikes() -> u32 {
+    TarpitConfig::default().soft_threshold
+}
+
+fn default_challenge_ttl_secs() -> u64 {
+    300
+}
+
+fn default_challenge_status() -> u16 {
+    428
+}
+
+/// Whether `X-Request-Id` and `traceparent` sent by clients are kept. When
+/// off they are replaced, e.g. for a gateway facing untrusted clients.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorrelationConfig {
+    pub trust_client_headers: bool,
+}
+
+impl Default for CorrelationConfig {
+    fn default() -> Self {
+        Self { trust_client_headers: true }
+    }
+}
+
+/// Tenants and how requests are attributed to them: by the caller's `claim`
+/// if set, else by `Host`, else by a leading path prefix, which is removed
+/// before routing.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TenancyConfig {
+    pub claim: Option<String>,
+    pub tenants: HashMap<String, TenantConfig>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TenantConfig {
+    pub hosts: Vec<String>,
+    pub path_prefix: Option<String>,
+    /// Per-client limit for this tenant's requests, replacing the global one.
+    pub rate_limit: Option<RateLimitConfig>,
+    /// Limit on all of this tenant's requests together.
+    pub quota: Option<RateLimitConfig>,
+    /// Route name to the upstream this tenant's requests go to instead.
+    pub upstreams: HashMap<String, String>,
+    /// Routes that answer 404 for this tenant.
+    pub disabled_routes: Vec<String>,
+    /// Most cache entries this tenant may hold; its oldest are evicted first.
+    pub max_cache_entries: Option<usize>,
+    /// Most per-client rate-limit buckets this tenant may hold.
+    pub max_rate_limit_buckets: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub requests: u32,
+    pub window_secs: u64,
+}
+
+/// The admin API under `/admin` is disabled unless at least one token is set.
+/// `upstream_override` lets admin-token holders pin a proxied request to a
+/// specific upstream with `X-Gateway-Upstream`, on one of the
+/// `upstream_override_hosts`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    pub tokens: Vec<String>,
+    pub upstream_override: bool,
+    pub upstream_override_hosts: Vec<String>,
+    /// File admin API changes are appended to; stdout if unset.
+    pub audit_log: Option<PathBuf>,
+}
+
+impl AdminConfig {
+    pub fn enabled(&self) -> bool {
+        !self.tokens.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A single finding produced while validating a configuration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigDiagnostic {
+    pub severity: Severity,
+    pub location: String,
+    pub message: String,
+}
+
+impl ConfigDiagnostic {
+    pub fn error(location: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Error,
+            location: location.into(),
+            message: message.into(),
+        }
+    }
+
+    pub fn warning(location: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Warning,
+            location: location.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ConfigDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let level = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "{}: {}: {}", level, self.location, self.message)
+    }
+}
+
+/// The file a running configuration was read from, kept so its values can be
+/// told apart from defaults.
+#[derive(Debug, Clone)]
+pub struct ConfigSource {
+    pub path: PathBuf,
+    /// Whether the path came from `GATEWAY_CONFIG` rather than `--config`.
+    pub from_env: bool,
+    pub contents: Value,
+}
+
+impl GatewayConfig {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        Self::load_with_source(path, false).map(|(config, _)| config)
+    }
+
+    pub fn load_with_source(path: &Path, from_env: bool) -> Result<(Self, ConfigSource), ConfigError> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| ConfigError::Read(format!("{}: {}", path.display(), e)))?;
+        let mut file: Value = serde_json::from_str(&contents).map_err(|e| ConfigError::Parse(e.to_string()))?;
+        let config = if file.get("include").is_some() {
+            resolve_includes(path, &mut file)?;
+            Self::from_json(&file.to_string())?
+        } else {
+            Self::from_json(&contents)?
+        };
+        Ok((config, ConfigSource { path: path.to_path_buf(), from_env, contents: file }))
+    }
+
+    pub fn from_json(contents: &str) -> Result<Self, ConfigError> {
+        let mut config: Self = serde_json::from_str(contents).map_err(|e| ConfigError::Parse(e.to_string()))?;
+        let groups = std::mem::take(&mut config.route_groups);
+        let uses_policies = config.routes.iter().any(|route| !route.policies.is_empty());
+        if groups.is_empty() && !uses_policies {
+            return Ok(config);
+        }
+        for (name, policy) in &config.policies {
+            if let Some(field) = ["name", "path_prefix", "policies"].into_iter().find(|field| policy.contains_key(*field)) {
+                return Err(ConfigError::Parse(format!("policies.{}: a policy cannot set {}", name, field)));
+            }
+        }
+
+        // Routes listing policies are read again from the file, which tells
+        // the fields they set from the ones left at their defaults.
+        let file: Value = serde_json::from_str(contents).map_err(|e| ConfigError::Parse(e.to_string()))?;
+        let listed = file.get("routes").and_then(Value::as_array);
+        for (i, (route, fields)) in config.routes.iter_mut().zip(listed.into_iter().flatten()).enumerate() {
+            let (false, Some(fields)) = (route.policies.is_empty(), fields.as_object()) else {
+                continue;
+            };
+            let location = format!("routes[{}]", i);
+            let fields = resolve_route(&location, &Map::new(), fields, &config.policies).map_err(ConfigError::Parse)?;
+            *route = serde_json::from_value(Value::Object(fields))
+                .map_err(|e| ConfigError::Parse(format!("{}: {}", location, e)))?;
+        }
+
+        // Without a `routes` list, only the groups' routes are configured.
+        if !groups.is_empty() && listed.is_none() {
+            config.routes.clear();
+        }
+        for (i, group) in groups.iter().enumerate() {
+            group
+                .expand(&format!("route_groups[{}]", i), &Map::new(), "", &config.policies, &mut config.routes)
+                .map_err(ConfigError::Parse)?;
+        }
+        Ok(config)
+    }
+
+    /// The configuration with defaults filled in and secrets redacted, and
+    /// for each setting whether it was set in the file or left at its default.
+    /// Settings are listed individually only where the file set some of them.
+    pub fn effective(&self, source: Option<&ConfigSource>) -> Value {
+        let mut config = serde_json::to_value(self).unwrap_or_default();
+        let mut sources = Map::new();
+        let unset = Value::Object(Map::new());
+        let file = source.map_or(&unset, |source| &source.contents);
+        value_sources(&config, Some(file), String::new(), &mut sources);
+        let redactor = Redactor::new(&self.redaction);
+        redactor.json(&mut config);
+        redactor.pointers(&mut config, &REDACTED_CONFIG_POINTERS);
+        let file = match source {
+            Some(source) => serde_json::json!({
+                "path": source.path,
+                "from": if source.from_env { CONFIG_PATH_ENV } else { "--config" },
+            }),
+            None => Value::Null,
+        };
+        serde_json::json!({ "file": file, "config": config, "sources": sources })
+    }
+
+    /// Checks everything that can be verified without touching the network.
+    pub fn validate(&self) -> Vec<ConfigDiagnostic> {
+        let mut diagnostics = Vec::new();
+
+        if self.listen_addr.parse::<SocketAddr>().is_err() {
+            diagnostics.push(ConfigDiagnostic::error(
+                "listen_addr",
+                format!("\"{}\" is not a valid socket address", self.listen_addr),
+            ));
+        }
+
+        if self.routes.is_empty() {
+            diagnostics.push(ConfigDiagnostic::warning(
+                "routes",
+                "no routes configured, every proxied request will return 404",
+            ));
+        }
+
+        let mut seen_prefixes: HashMap<String, Vec<usize>> = HashMap::new();
+        let mut seen_names = HashSet::new();
+        for (i, route) in self.routes.iter().enumerate() {
+            let location = route_location(i, route);
+
+            if !route.path_prefix.starts_with('/') {
+                diagnostics.push(ConfigDiagnostic::error(
+                    &location,
+                    format!("path_prefix \"{}\" must start with '/'", route.path_prefix),
+                ));
+            }
+            match PathPattern::parse(&route.path_prefix) {
+                Ok(pattern) => {
+                    let templates = route.rewrite
+                        .iter()
+                        .map(|rewrite| ("rewrite", rewrite))
+                        .chain(route.request_headers.values().map(|value| ("request_headers", value)));
+                    let redirect = route.redirect.iter().map(|r| ("redirect.location", &r.location));
+                    for (field, template) in templates.chain(redirect) {
+                        for name in template_params(template) {
+                            let builtin = field == "redirect.location" && REDIRECT_TEMPLATE_VARS.contains(&name);
+                            if !builtin && !pattern.params().iter().any(|p| p == name) {
+                                diagnostics.push(ConfigDiagnostic::error(
+                                    &location,
+                                    format!("{} references unknown parameter \"{{{}}}\"", field, name),
+                                ));
+                            }
+                        }
+                    }
+                }
+                Err(message) => diagnostics.push(ConfigDiagnostic::error(&location, message)),
+            }
+            for name in route.request_headers.keys().chain(route.match_headers.keys()) {
+                if HeaderName::from_bytes(name.as_bytes()).is_err() {
+                    diagnostics.push(ConfigDiagnostic::error(
+                        &location,
+                        format!("\"{}\" is not a valid header name", name),
+                    ));
+                }
+            }
+            if let Some(soap) = &route.match_soap {
+                if soap.action.is_none() && soap.operation.is_none() {
+                    diagnostics.push(ConfigDiagnostic::error(&location, "match_soap needs an action or an operation"));
+                }
+                if let Some(operation) = soap.operation.as_ref().filter(|operation| !valid_xml_name(operation)) {
+                    diagnostics.push(ConfigDiagnostic::error(
+                        &location,
+                        format!("match_soap operation \"{}\" is not an XML element name", operation),
+                    ));
+                }
+            }
+            if let Some(strip) = &route.strip_prefix {
+                if !strip.starts_with('/') {
+                    diagnostics.push(ConfigDiagnostic::error(
+                        &location,
+                        format!("strip_prefix \"{}\" must start with '/'", strip),
+                    ));
+                }
+            }
+            if route.actions().len() != 1 {
+                diagnostics.push(ConfigDiagnostic::error(&location, ROUTE_ACTION_ERROR));
+            }
+            if let Some(upstream) = &route.upstream {
+                match parse_upstream(upstream) {
+                    Err(message) => diagnostics.push(ConfigDiagnostic::error(&location, message)),
+                    Ok(address) => {
+                        let preference = self.upstream_connect.ip_preference;
+                        if address.parse::<SocketAddr>().is_ok_and(|address| !preference.allows(address.ip())) {
+                            diagnostics.push(ConfigDiagnostic::error(
+                                &location,
+                                format!("upstream \"{}\" is an address upstream_connect.ip_preference excludes", upstream),
+                            ));
+                        }
+                    }
+                }
+            }
+            if let Some(redirect) = &route.redirect {
+                if !REDIRECT_STATUSES.contains(&redirect.status) {
+                    diagnostics.push(ConfigDiagnostic::error(
+                        &location,
+                        format!("redirect status {} must be one of 301, 302, 307 or 308", redirect.status),
+                    ));
+                }
+            }
+            if let Some(respond) = &route.respond {
+                if StatusCode::from_u16(respond.status).is_err() {
+                    diagnostics.push(ConfigDiagnostic::error(
+                        &location,
+                        format!("respond status {} is not a valid HTTP status", respond.status),
+                    ));
+                }
+                for (name, value) in &respond.headers {
+                    if HeaderName::from_bytes(name.as_bytes()).is_err() || HeaderValue::from_str(value).is_err() {
+                        diagnostics.push(ConfigDiagnostic::error(
+                            &location,
+                            format!("respond header \"{}\" has an invalid name or value", name),
+                        ));
+                    }
+                }
+            }
+            if let Some(files) = &route.static_files {
+                if !files.root.is_dir() {
+                    diagnostics.push(ConfigDiagnostic::error(
+                        &location,
+                        format!("static_files.root {} does not exist or is not a directory", files.root.display()),
+                    ));
+                }
+            }
+            if let Some(grpc) = &route.grpc {
+                let uri = grpc.upstream.parse::<Uri>().ok();
+                if !uri.is_some_and(|uri| uri.scheme_str() == Some("http") && uri.host().is_some()) {
+                    diagnostics.push(ConfigDiagnostic::error(
+                        &location,
+                        format!("grpc upstream \"{}\" must be an http:// URL", grpc.upstream),
+                    ));
+                }
+                if let Err(message) = GrpcRoute::from_config(grpc) {
+                    diagnostics.push(ConfigDiagnostic::error(&location, format!("grpc: {}", message)));
+                }
+            }
+            for method in &route.methods {
+                if Method::from_bytes(method.as_bytes()).is_err() || method.to_uppercase() != *method {
+                    diagnostics.push(ConfigDiagnostic::error(
+                        &location,
+                        format!("\"{}\" is not a valid HTTP method", method),
+                    ));
+                }
+            }
+
+            if let HostHeader::Override(host) = &route.host_header {
+                if HeaderValue::from_str(host).is_err() || host.is_empty() {
+                    diagnostics.push(ConfigDiagnostic::error(
+                        &location,
+                        format!("host_header override \"{}\" is not a valid Host value", host),
+                    ));
+                }
+            }
+            if let Some(idempotency) = &route.idempotency {
+                if idempotency.window_secs == 0 {
+                    diagnostics.push(ConfigDiagnostic::error(&location, "idempotency window_secs must be positive"));
+                }
+                if !route.proxies() {
+                    diagnostics.push(ConfigDiagnostic::error(&location, "idempotency requires an upstream"));
+                }
+                if !route.methods.is_empty() && !route.methods.iter().any(|m| m == "POST") {
+                    diagnostics.push(ConfigDiagnostic::warning(
+                        &location,
+                        "idempotency only applies to POST, which this route does not allow",
+                    ));
+                }
+            }
+            if let Some(experiment) = &route.experiment {
+                if route.upstream.is_none() {
+                    diagnostics.push(ConfigDiagnostic::error(&location, "experiment requires an upstream"));
+                }
+                if route.deployment.is_some() {
+                    diagnostics.push(ConfigDiagnostic::error(&location, "experiment cannot be combined with deployment"));
+                }
+                for variant in &experiment.variants {
+                    if let Err(message) = parse_upstream(&variant.upstream) {
+                        diagnostics.push(ConfigDiagnostic::error(&location, format!("experiment: {}", message)));
+                    }
+                }
+                if let Err(message) = Experiment::from_config(experiment) {
+                    diagnostics.push(ConfigDiagnostic::error(&location, format!("experiment: {}", message)));
+                }
+            }
+            if route.timeout_secs == 0 || route.body_timeout_secs == 0 {
+                diagnostics.push(ConfigDiagnostic::error(
+                    &location,
+                    "timeout_secs and body_timeout_secs must be positive",
+                ));
+            }
+            if let Some(limit) = &route.response_limit {
+                if !route.proxies() {
+                    diagnostics.push(ConfigDiagnostic::error(&location, "response_limit requires an upstream"));
+                }
+                if limit.max_bytes == 0 {
+                    diagnostics.push(ConfigDiagnostic::error(&location, "response_limit max_bytes must be positive"));
+                }
+            }
+            if !(0.0..=1.0).contains(&route.logging.sample_rate) {
+                diagnostics.push(ConfigDiagnostic::error(&location, "logging.sample_rate must be between 0 and 1"));
+            }
+            let mut tags: Vec<_> = route.tags.iter().collect();
+            tags.sort();
+            for (name, value) in tags {
+                if !is_label_name(name) || METRIC_LABELS.contains(&name.as_str()) || RESERVED_TAGS.contains(&name.as_str()) {
+                    diagnostics.push(ConfigDiagnostic::error(
+                        &location,
+                        format!("tag \"{}\" must be a Prometheus label name other than {}, {}", name, METRIC_LABELS.join(", "), RESERVED_TAGS.join(", ")),
+                    ));
+                }
+                if value.is_empty() || value.chars().any(|c| c.is_whitespace() || c.is_control()) {
+                    diagnostics.push(ConfigDiagnostic::error(&location, format!("tag \"{}\" must have a value without spaces", name)));
+                }
+            }
+            if let Some(deployment) = &route.deployment {
+                for upstream in deployment.groups.values() {
+                    if let Err(message) = parse_upstream(upstream) {
+                        diagnostics.push(ConfigDiagnostic::error(&location, format!("deployment: {}", message)));
+                    }
+                }
+                if let Err(message) = Deployment::from_config(deployment) {
+                    diagnostics.push(ConfigDiagnostic::error(&location, format!("deployment: {}", message)));
+                }
+            }
+            if let Some(schedule) = &route.schedule {
+                if let Err(message) = Schedule::from_config(schedule) {
+                    diagnostics.push(ConfigDiagnostic::error(&location, format!("schedule: {}", message)));
+                }
+            }
+            if let Some(upstream_auth) = &route.upstream_auth {
+                if !route.proxies() {
+                    diagnostics.push(ConfigDiagnostic::error(&location, "upstream_auth requires an upstream"));
+                }
+                if let Err(message) = validate_upstream_auth(upstream_auth) {
+                    diagnostics.push(ConfigDiagnostic::error(&location, message));
+                }
+            }
+            for content_type in &route.content_types {
+                let valid = content_type
+                    .split_once('/')
+                    .is_some_and(|(kind, subtype)| !kind.is_empty() && kind != "*" && !subtype.is_empty() && !content_type.contains([';', ' ']));
+                if !valid {
+                    diagnostics.push(ConfigDiagnostic::error(
+                        &location,
+                        format!("content type \"{}\" must be type/subtype or type/*", content_type),
+                    ));
+                }
+            }
+            if let Some(formats) = &route.formats {
+                if !route.proxies() {
+                    diagnostics.push(ConfigDiagnostic::error(&location, "formats requires an upstream"));
+                }
+                if formats.offer.is_empty() {
+                    diagnostics.push(ConfigDiagnostic::warning(&location, "formats offers nothing besides JSON"));
+                }
+                if !valid_xml_name(&formats.xml_root) {
+                    diagnostics.push(ConfigDiagnostic::error(
+                        &location,
+                        format!("formats xml_root \"{}\" is not a valid XML element name", formats.xml_root),
+                    ));
+                }
+            }
+            if let Some(decompression) = &route.decompress_requests {
+                if !route.proxies() {
+                    diagnostics.push(ConfigDiagnostic::error(&location, "decompress_requests requires an upstream"));
+                }
+                if decompression.max_bytes == 0 {
+                    diagnostics.push(ConfigDiagnostic::error(&location, "decompress_requests max_bytes must be positive"));
+                }
+            }
+            if let Some(fields) = &route.fields {
+                if !route.proxies() {
+                    diagnostics.push(ConfigDiagnostic::error(&location, "fields requires an upstream"));
+                }
+                if fields.param.is_empty() {
+                    diagnostics.push(ConfigDiagnostic::error(&location, "fields param must not be empty"));
+                }
+            }
+            if let Some(error_page) = &route.sanitize_errors {
+                if !route.proxies() {
+                    diagnostics.push(ConfigDiagnostic::error(&location, "sanitize_errors requires an upstream"));
+                }
+                if let Err(message) = ErrorPage::from_config(error_page) {
+                    diagnostics.push(ConfigDiagnostic::error(&location, format!("sanitize_errors: {}", message)));
+                }
+            }
+            if let Some(rewrite_urls) = &route.rewrite_urls {
+                if !route.proxies() {
+                    diagnostics.push(ConfigDiagnostic::error(&location, "rewrite_urls requires an upstream"));
+                }
+                for url in rewrite_urls.public_url.iter().chain(&rewrite_urls.internal_urls) {
+                    let uri = url.parse::<Uri>().ok();
+                    if !uri.is_some_and(|uri| matches!(uri.scheme_str(), Some("http" | "https")) && uri.host().is_some()) {
+                        diagnostics.push(ConfigDiagnostic::error(
+                            &location,
+                            format!("rewrite_urls: \"{}\" must be an http(s) URL", url),
+                        ));
+                    }
+                }
+            }
+            if let Some(cookies) = &route.rewrite_cookies {
+                if !route.proxies() {
+                    diagnostics.push(ConfigDiagnostic::error(&location, "rewrite_cookies requires an upstream"));
+                }
+                if let Some(same_site) = cookies.same_site.as_deref().filter(|s| !SAME_SITE_VALUES.contains(s)) {
+                    diagnostics.push(ConfigDiagnostic::error(
+                        &location,
+                        format!("rewrite_cookies same_site \"{}\" must be one of {}", same_site, SAME_SITE_VALUES.join(", ")),
+                    ));
+                }
+                if cookies.same_site.as_deref() == Some("None") && !cookies.secure {
+                    diagnostics.push(ConfigDiagnostic::warning(
+                        &location,
+                        "browsers reject SameSite=None cookies that are not Secure",
+                    ));
+                }
+                if cookies.domain.as_deref().is_some_and(|domain| domain.contains([';', ' ', ',']) || domain.is_empty()) {
+                    diagnostics.push(ConfigDiagnostic::error(&location, "rewrite_cookies domain is not a valid cookie domain"));
+                }
+            }
+            if let Some(signing) = &route.signing {
+                if !route.proxies() {
+                    diagnostics.push(ConfigDiagnostic::error(&location, "signing requires an upstream"));
+                }
+                if signing.secret.is_empty() {
+                    diagnostics.push(ConfigDiagnostic::error(&location, "signing secret must not be empty"));
+                }
+                if HeaderName::from_bytes(signing.header.as_bytes()).is_err() {
+                    diagnostics.push(ConfigDiagnostic::error(
+                        &location,
+                        format!("signing header \"{}\" is not a valid header name", signing.header),
+                    ));
+                }
+            }
+            if route.token_exchange {
+                if self.auth.internal_jwt.is_none() {
+                    diagnostics.push(ConfigDiagnostic::error(&location, "token_exchange requires auth.internal_jwt"));
+                }
+                if !route.proxies() || route.upstream_auth.is_some() {
+                    diagnostics.push(ConfigDiagnostic::error(
+                        &location,
+                        "token_exchange requires an upstream and cannot be combined with upstream_auth",
+                    ));
+                }
+            }
+            if route.csrf.is_some() && self.auth.oidc.is_none() {
+                diagnostics.push(ConfigDiagnostic::warning(&location, "csrf has no effect without auth.oidc"));
+            }
+            if let Some(bulkhead) = &route.bulkhead {
+                if !self.bulkheads.contains_key(bulkhead) {
+                    diagnostics.push(ConfigDiagnostic::error(
+                        &location,
+                        format!("unknown bulkhead \"{}\"", bulkhead),
+                    ));
+                }
+            }
+
+            if let Some(name) = &route.name {
+                if !seen_names.insert(name.clone()) {
+                    diagnostics.push(ConfigDiagnostic::error(
+                        &location,
+                        format!("duplicate route name \"{}\"", name),
+                    ));
+                }
+            }
+
+            // Routes may share a prefix as long as they accept disjoint methods
+            // or are told apart by header and query predicates.
+            let same_prefix = seen_prefixes.entry(normalize_prefix(&route.path_prefix)).or_default();
+            let conflict = same_prefix
+                .iter()
+                .find(|&&j| methods_overlap(route, &self.routes[j]) && same_predicates(route, &self.routes[j]));
+            if let Some(other) = conflict {
+                diagnostics.push(ConfigDiagnostic::error(
+                    &location,
+                    format!(
+                        "path_prefix \"{}\" conflicts with {}",
+                        route.path_prefix,
+                        route_location(*other, &self.routes[*other])
+                    ),
+                ));
+            }
+            same_prefix.push(i);
+        }
+
+        let mut bulkheads: Vec<_> = self.bulkheads.iter().collect();
+        bulkheads.sort_by_key(|(name, _)| name.as_str());
+        for (name, bulkhead) in bulkheads {
+            if bulkhead.max_concurrent == 0 {
+                diagnostics.push(ConfigDiagnostic::error(
+                    format!("bulkheads.{}", name),
+                    "max_concurrent must be greater than 0",
+                ));
+            } else if bulkhead.reserved_for_high >= bulkhead.max_concurrent {
+                diagnostics.push(ConfigDiagnostic::error(
+                    format!("bulkheads.{}", name),
+                    "reserved_for_high must be less than max_concurrent",
+                ));
+            }
+            if let Some(queue) = &bulkhead.queue {
+                if queue.max_waiting == 0 || queue.timeout_ms == 0 {
+                    diagnostics.push(ConfigDiagnostic::error(
+                        format!("bulkheads.{}.queue", name),
+                        "max_waiting and timeout_ms must be greater than 0",
+                    ));
+                }
+            }
+        }
+
+        if self.admin.tokens.iter().any(|token| token.trim().is_empty()) {
+            diagnostics.push(ConfigDiagnostic::error("admin.tokens", "admin tokens must not be empty"));
+        }
+        if self.admin.upstream_override && !self.admin.enabled() {
+            diagnostics.push(ConfigDiagnostic::warning(
+                "admin.upstream_override",
+                "upstream_override has no effect without admin tokens",
+            ));
+        }
+        if self.admin.upstream_override && self.admin.upstream_override_hosts.is_empty() {
+            diagnostics.push(ConfigDiagnostic::error(
+                "admin.upstream_override_hosts",
+                "upstream_override requires the hosts it may send requests to",
+            ));
+        }
+
+        if let Some(path) = &self.auth.api_keys_file {
+            if !path.is_file() {
+                diagnostics.push(ConfigDiagnostic::error(
+                    "auth.api_keys_file",
+                    format!("{} does not exist or is not a file", path.display()),
+                ));
+            }
+        }
+        if self.auth.reload_interval_secs == 0 {
+            diagnostics.push(ConfigDiagnostic::error("auth.reload_interval_secs", "reload_interval_secs must be positive"));
+        }
+        if let Some(jwt) = &self.auth.client_jwt {
+            if let Err(message) = validate_jwt_key(jwt.algorithm, &jwt.secret, &jwt.key, &jwt.key_file) {
+                diagnostics.push(ConfigDiagnostic::error("auth.client_jwt", message));
+            }
+        }
+        if let Some(jwt) = &self.auth.internal_jwt {
+            if let Err(message) = validate_jwt_key(jwt.algorithm, &jwt.secret, &jwt.key, &jwt.key_file) {
+                diagnostics.push(ConfigDiagnostic::error("auth.internal_jwt", message));
+            }
+            if jwt.ttl_secs == 0 {
+                diagnostics.push(ConfigDiagnostic::error("auth.internal_jwt", "ttl_secs must be positive"));
+            }
+        }
+        if let Some(key_store) = &self.auth.key_store {
+            let directory = key_store.path.parent().filter(|parent| !parent.as_os_str().is_empty());
+            if directory.is_some_and(|directory| !directory.is_dir()) {
+                diagnostics.push(ConfigDiagnostic::error(
+                    "auth.key_store.path",
+                    format!("the directory of {} does not exist", key_store.path.display()),
+                ));
+            }
+            if key_store.argon2_memory_kib < 8 || key_store.argon2_iterations == 0 {
+                diagnostics.push(ConfigDiagnostic::error(
+                    "auth.key_store",
+                    "argon2_memory_kib must be at least 8 and argon2_iterations positive",
+                ));
+            }
+            if let Some(kms) = &key_store.kms {
+                if kms.region().is_none() {
+                    diagnostics.push(ConfigDiagnostic::error(
+                        "auth.key_store.kms",
+                        "set region, or give key_id as an ARN",
+                    ));
+                }
+                if kms.endpoint.as_deref().is_some_and(|endpoint| !is_http_url(endpoint)) {
+                    diagnostics.push(ConfigDiagnostic::error("auth.key_store.kms.endpoint", "endpoint must be an http(s) URL"));
+                }
+            }
+            if let Some(lockout) = &key_store.lockout {
+                if lockout.max_failures <= lockout.free_attempts {
+                    diagnostics.push(ConfigDiagnostic::error(
+                        "auth.key_store.lockout",
+                        "max_failures must be greater than free_attempts",
+                    ));
+                }
+                if lockout.lockout_secs == 0 || lockout.backoff_base_ms == 0 {
+                    diagnostics.push(ConfigDiagnostic::error(
+                        "auth.key_store.lockout",
+                        "lockout_secs and backoff_base_ms must be positive",
+                    ));
+                }
+            }
+        }
+        if let Some(token_endpoint) = &self.auth.token_endpoint {
+            if self.auth.key_store.is_none() || self.auth.internal_jwt.is_none() {
+                diagnostics.push(ConfigDiagnostic::error(
+                    "auth.token_endpoint",
+                    "token_endpoint requires auth.key_store and auth.internal_jwt",
+                ));
+            }
+            if token_endpoint.ttl_secs == 0 {
+                diagnostics.push(ConfigDiagnostic::error("auth.token_endpoint.ttl_secs", "ttl_secs must be positive"));
+            }
+            let internal_audience = self.auth.internal_jwt.as_ref().and_then(|jwt| jwt.audience.as_deref());
+            if token_endpoint.audience.is_empty() || internal_audience == Some(token_endpoint.audience.as_str()) {
+                diagnostics.push(ConfigDiagnostic::error(
+                    "auth.token_endpoint.audience",
+                    "audience must be set and differ from auth.internal_jwt.audience",
+                ));
+            }
+        }
+        if let Some(oidc) = &self.auth.oidc {
+            validate_oidc(oidc, &mut diagnostics);
+        }
+
+        let mut claim_headers: Vec<_> = self.auth.claim_headers.iter().collect();
+        claim_headers.sort();
+        let mut seen_claim_headers = HashSet::new();
+        for (claim, header) in claim_headers {
+            let location = format!("auth.claim_headers.{}", claim);
+            match HeaderName::from_bytes(header.as_bytes()) {
+                Ok(name) if !seen_claim_headers.insert(name.clone()) => diagnostics.push(ConfigDiagnostic::error(
+                    &location,
+                    format!("header \"{}\" is already mapped from another claim", header),
+                )),
+                Ok(_) => {}
+                Err(_) => diagnostics.push(ConfigDiagnostic::error(
+                    &location,
+                    format!("\"{}\" is not a valid header name", header),
+                )),
+            }
+        }
+
+        if self.rate_limiting.max_buckets == 0 || self.rate_limiting.sweep_interval_secs == 0 {
+            diagnostics.push(ConfigDiagnostic::error(
+                "rate_limiting",
+                "max_buckets and sweep_interval_secs must be positive",
+            ));
+        }
+        if self.cache.max_entries == 0 || self.cache.max_object_bytes == 0 || self.cache.max_idempotency_records == 0 {
+            diagnostics.push(ConfigDiagnostic::error(
+                "cache",
+                "max_entries, max_object_bytes and max_idempotency_records must be positive",
+            ));
+        }
+        if let Some(control) = &self.cache.control {
+            if control.scope.as_deref() == Some("") {
+                diagnostics.push(ConfigDiagnostic::error("cache.control.scope", "must not be empty"));
+            } else if control.scope.is_none() && !self.admin.enabled() {
+                diagnostics.push(ConfigDiagnostic::warning(
+                    "cache.control",
+                    "no caller can steer the cache without a scope or admin tokens",
+                ));
+            }
+        }
+        for (i, name) in self.cache.key.ignore_query_params.iter().enumerate() {
+            let stem = name.strip_suffix('*').unwrap_or(name);
+            if stem.is_empty() || stem.contains('*') {
+                diagnostics.push(ConfigDiagnostic::error(
+                    format!("cache.key.ignore_query_params[{}]", i),
+                    format!("\"{}\" must be a parameter name, with `*` only at the end", name),
+                ));
+            }
+        }
+        if let Some(redis) = &self.cache.redis {
+            if let Err(message) = RedisAddress::new(redis) {
+                diagnostics.push(ConfigDiagnostic::error("cache.redis", message));
+            }
+            if redis.max_object_bytes == 0 || redis.timeout_ms == 0 {
+                diagnostics.push(ConfigDiagnostic::error("cache.redis", "max_object_bytes and timeout_ms must be positive"));
+            }
+            if redis.channel.is_empty() {
+                diagnostics.push(ConfigDiagnostic::error("cache.redis", "channel must not be empty"));
+            }
+        }
+        if let Some(tarpit) = &self.rate_limiting.tarpit {
+            if tarpit.soft_threshold == 0 || tarpit.soft_threshold > tarpit.hard_threshold {
+                diagnostics.push(ConfigDiagnostic::error(
+                    "rate_limiting.tarpit",
+                    "soft_threshold must be positive and at most hard_threshold",
+                ));
+            }
+            if tarpit.base_delay_ms > tarpit.max_delay_ms || tarpit.reset_secs == 0 {
+                diagnostics.push(ConfigDiagnostic::error(
+                    "rate_limiting.tarpit",
+                    "base_delay_ms must be at most max_delay_ms and reset_secs must be positive",
+                ));
+            }
+        }
+
+        if self.metrics.buckets.is_empty()
+            || self.metrics.buckets.windows(2).any(|pair| pair[0] >= pair[1])
+            || self.metrics.buckets.iter().any(|bucket| !bucket.is_finite() || *bucket <= 0.0)
+        {
+            diagnostics.push(ConfigDiagnostic::error(
+                "metrics.buckets",
+                "buckets must be positive and strictly increasing",
+            ));
+        }
+        let tags: HashSet<&String> = self.routes.iter().flat_map(|route| route.tags.keys()).collect();
+        for label in &self.metrics.labels {
+            if !METRIC_LABELS.contains(&label.as_str()) && !tags.contains(label) {
+                diagnostics.push(ConfigDiagnostic::error(
+                    "metrics.labels",
+                    format!("unknown label \"{}\", expected one of {} or a route tag", label, METRIC_LABELS.join(", ")),
+                ));
+            }
+        }
+        if self.metrics.labels.iter().any(|label| label == "status")
+            && self.metrics.labels.iter().any(|label| label == "status_class")
+        {
+            diagnostics.push(ConfigDiagnostic::warning(
+                "metrics.labels",
+                "status already implies status_class",
+            ));
+        }
+        if self.metrics.max_series == 0 {
+            diagnostics.push(ConfigDiagnostic::error("metrics.max_series", "max_series must be positive"));
+        }
+
+        if !(0.0..=1.0).contains(&self.debug_log.sample_rate) {
+            diagnostics.push(ConfigDiagnostic::error(
+                "debug_log.sample_rate",
+                "sample_rate must be between 0 and 1",
+            ));
+        }
+        if HeaderName::from_bytes(self.debug_log.header.as_bytes()).is_err() {
+            diagnostics.push(ConfigDiagnostic::error(
+                "debug_log.header",
+                format!("\"{}\" is not a valid header name", self.debug_log.header),
+            ));
+        }
+
+        if self.cors.max_age_secs > BROWSER_MAX_AGE_CAP_SECS {
+            diagnostics.push(ConfigDiagnostic::warning(
+                "cors.max_age_secs",
+                format!("some browsers cache preflights for at most {} seconds", BROWSER_MAX_AGE_CAP_SECS),
+            ));
+        }
+
+        if let Some(challenge) = &self.challenge {
+            if self.rate_limiting.tarpit.is_none() {
+                diagnostics.push(ConfigDiagnostic::error(
+                    "challenge",
+                    "challenge needs rate_limiting.tarpit, whose strikes trigger it",
+                ));
+            }
+            if !is_http_url(&challenge.verify_url) {
+                diagnostics.push(ConfigDiagnostic::error(
+                    "challenge.verify_url",
+                    format!("\"{}\" must be an http(s) URL", challenge.verify_url),
+                ));
+            }
+            if challenge.strikes == 0 || challenge.token_ttl_secs == 0 {
+                diagnostics.push(ConfigDiagnostic::error(
+                    "challenge",
+                    "strikes and token_ttl_secs must be positive",
+                ));
+            }
+            if !matches!(challenge.status, 401 | 428) {
+                diagnostics.push(ConfigDiagnostic::error("challenge.status", "status must be 401 or 428"));
+            }
+        }
+
+        for header in &self.redaction.headers {
+            if HeaderName::from_bytes(header.as_bytes()).is_err() {
+                diagnostics.push(ConfigDiagnostic::error(
+                    "redaction.headers",
+                    format!("\"{}\" is not a valid header name", header),
+                ));
+            }
+        }
+
+        if self.runtime.worker_threads == Some(0) || self.runtime.max_blocking_threads == Some(0) {
+            diagnostics.push(ConfigDiagnostic::error(
+                "runtime",
+                "worker_threads and max_blocking_threads must be positive",
+            ));
+        }
+        if self.socket.backlog == 0 {
+            diagnostics.push(ConfigDiagnostic::error("socket", "backlog must be positive"));
+        }
+        if cfg!(not(unix)) && self.socket.reuse_port {
+            diagnostics.push(ConfigDiagnostic::warning("socket", "reuse_port is only supported on Unix"));
+        }
+
+        self.validate_tenancy(&mut diagnostics);
+
+        let mut policies: Vec<&String> = self.policies.keys().collect();
+        policies.sort();
+        for name in policies {
+            if !self.routes.iter().any(|route| route.policies.contains(name)) {
+                diagnostics.push(ConfigDiagnostic::warning(format!("policies.{}", name), "not used by any route"));
+            }
+        }
+
+        if let Some(tls) = &self.tls {
+            let optional = tls.acme.is_some() || tls.cert_dir.is_some() || !tls.certificates.is_empty();
+            let pems = [("cert", &tls.cert, &tls.cert_path), ("key", &tls.key, &tls.key_path)];
+            validate_pems("tls", pems, optional, &mut diagnostics);
+            let mut names: Vec<&String> = tls.certificates.keys().collect();
+            names.sort();
+            for name in names {
+                let location = format!("tls.certificates.{}", name);
+                if !is_server_name(name.strip_prefix("*.").unwrap_or(name)) {
+                    diagnostics.push(ConfigDiagnostic::error(&location, format!("\"{}\" is not a server name", name)));
+                }
+                let certificate = &tls.certificates[name];
+                let pems = [
+                    ("cert", &certificate.cert, &certificate.cert_path),
+                    ("key", &certificate.key, &certificate.key_path),
+                ];
+                validate_pems(&location, pems, false, &mut diagnostics);
+            }
+            if let Some(dir) = tls.cert_dir.as_ref().filter(|dir| !dir.is_dir()) {
+                diagnostics.push(ConfigDiagnostic::error(
+                    "tls.cert_dir",
+                    format!("{} does not exist or is not a directory", dir.display()),
+                ));
+            }
+            if let Some(acme) = &tls.acme {
+                validate_acme(acme, &mut diagnostics);
+            }
+            validate_tls_policy(tls, &mut diagnostics);
+        }
+        validate_upstream_connect(&self.upstream_connect, &mut diagnostics);
+        if let Some(health) = &self.upstream_health {
+            if health.connect_failures == 0 || health.server_errors == 0 || health.healthy_after == 0 {
+                diagnostics.push(ConfigDiagnostic::error(
+                    "upstream_health",
+                    "connect_failures, server_errors and healthy_after must be at least 1",
+                ));
+            }
+        }
+
+        if !(0.0..=1.0).contains(&self.failure_injection.fraction) {
+            diagnostics.push(ConfigDiagnostic::error("failure_injection.fraction", "must be between 0 and 1"));
+        }
+        if self.failure_injection.enabled {
+            diagnostics.push(ConfigDiagnostic::warning("failure_injection", "enabled, requests fail from startup"));
+        }
+
+        self.validate_secrets(&mut diagnostics);
+
+        diagnostics
+    }
+
+    fn validate_secrets(&self, diagnostics: &mut Vec<ConfigDiagnostic>) {
+        if let Some(vault) = &self.secrets.vault {
+            if !is_http_url(&vault.address) {
+                diagnostics.push(ConfigDiagnostic::error(
+                    "secrets.vault.address",
+                    format!("\"{}\" must be an http(s) URL", vault.address),
+                ));
+            }
+            if !VAULT_KV_VERSIONS.contains(&vault.kv_version) {
+                diagnostics.push(ConfigDiagnostic::error("secrets.vault.kv_version", "kv_version must be 1 or 2"));
+            }
+            match &vault.token_file {
+                Some(path) if !path.is_file() => diagnostics.push(ConfigDiagnostic::error(
+                    "secrets.vault.token_file",
+                    format!("{} does not exist or is not a file", path.display()),
+                )),
+                None if std::env::var_os(VAULT_TOKEN_ENV).is_none() => diagnostics.push(ConfigDiagnostic::warning(
+                    "secrets.vault",
+                    format!("no token_file and {} is not set", VAULT_TOKEN_ENV),
+                )),
+                _ => {}
+            }
+        }
+        if let Some(persistence) = &self.persistence {
+            match DatabaseAddress::new(persistence) {
+                Ok(DatabaseAddress::Postgres(_)) => validate_client_tls("persistence.tls", &persistence.tls, diagnostics),
+                Ok(DatabaseAddress::Sqlite(_)) => {}
+                Err(message) => diagnostics.push(ConfigDiagnostic::error("persistence", message)),
+            }
+            if persistence.quota_snapshot_secs == 0 || persistence.timeout_ms == 0 || persistence.queue_capacity == 0 {
+                diagnostics.push(ConfigDiagnostic::error("persistence", "quota_snapshot_secs, timeout_ms and queue_capacity must be positive"));
+            }
+            if persistence.allow_plaintext {
+                diagnostics.push(ConfigDiagnostic::warning("persistence", "the password or state may be sent unencrypted"));
+            }
+        }
+        let mut check_names = HashSet::new();
+        for (i, check) in self.synthetic_checks.iter().enumerate() {
+            let location = format!("synthetic_checks[{}]", i);
+            if check.name.is_empty() || !check_names.insert(check.name.as_str()) {
+                diagnostics.push(ConfigDiagnostic::error(&location, "name must be set and unique"));
+            }
+            if Method::from_bytes(check.method.as_bytes()).is_err() || check.method.to_uppercase() != check.method {
+                diagnostics.push(ConfigDiagnostic::error(&location, format!("\"{}\" is not a valid HTTP method", check.method)));
+            }
+            if !check.path.starts_with('/') || check.path.parse::<Uri>().is_err() {
+                diagnostics.push(ConfigDiagnostic::error(&location, format!("path \"{}\" must be a path starting with '/'", check.path)));
+            }
+            for (name, value) in &check.headers {
+                if HeaderName::from_bytes(name.as_bytes()).is_err() || HeaderValue::from_str(value).is_err() {
+                    diagnostics.push(ConfigDiagnostic::error(&location, format!("header \"{}\" has an invalid name or value", name)));
+                }
+            }
+            if check.expect_status.is_some_and(|status| StatusCode::from_u16(status).is_err()) {
+                diagnostics.push(ConfigDiagnostic::error(&location, "expect_status is not a valid HTTP status"));
+            }
+            if check.interval_secs == 0 || check.timeout_ms == 0 {
+                diagnostics.push(ConfigDiagnostic::error(&location, "interval_secs and timeout_ms must be positive"));
+            }
+        }
+        if let Some(openapi) = &self.openapi {
+            if openapi.sources.is_empty() {
+                diagnostics.push(ConfigDiagnostic::warning("openapi", "no sources, the document will have no paths"));
+            }
+            if openapi.refresh_interval_secs == 0 || openapi.timeout_ms == 0 {
+                diagnostics.push(ConfigDiagnostic::error("openapi", "refresh_interval_secs and timeout_ms must be positive"));
+            }
+            if let Some(url) = openapi.server_url.as_ref().filter(|url| !is_http_url(url)) {
+                diagnostics.push(ConfigDiagnostic::error("openapi.server_url", format!("\"{}\" must be an http(s) URL", url)));
+            }
+            if let Some(path) = openapi.swagger_ui_path.as_ref().filter(|path| !path.starts_with('/') || path.as_str() == OPENAPI_SPEC_PATH) {
+                diagnostics.push(ConfigDiagnostic::error(
+                    "openapi.swagger_ui_path",
+                    format!("\"{}\" must be a path starting with '/' other than {}", path, OPENAPI_SPEC_PATH),
+                ));
+            }
+            match &openapi.swagger_ui_assets {
+                None if openapi.swagger_ui_path.is_some() => diagnostics.push(ConfigDiagnostic::error(
+                    "openapi.swagger_ui_assets",
+                    "swagger_ui_path needs the integrity hashes of the Swagger UI release it loads",
+                )),
+                None => {}
+                Some(assets) => {
+                    let exact = assets.version.split('.').count() == 3
+                        && assets.version.split('.').all(|part| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit()));
+                    if !exact {
+                        diagnostics.push(ConfigDiagnostic::error(
+                            "openapi.swagger_ui_assets.version",
+                            format!("\"{}\" must be an exact version such as {}", assets.version, SWAGGER_UI_VERSION),
+                        ));
+                    }
+                    for (field, value) in [("css_integrity", &assets.css_integrity), ("bundle_integrity", &assets.bundle_integrity)] {
+                        if !is_integrity(value) {
+                            diagnostics.push(ConfigDiagnostic::error(
+                                format!("openapi.swagger_ui_assets.{}", field),
+                                "must be a sha256-, sha384- or sha512- Subresource Integrity value",
+                            ));
+                        }
+                    }
+                }
+            }
+            for (i, source) in openapi.sources.iter().enumerate() {
+                let location = format!("openapi.sources[{}]", i);
+                match self.routes.iter().find(|route| route.name.as_deref() == Some(source.route.as_str())) {
+                    None => diagnostics.push(ConfigDiagnostic::error(&location, format!("no route named \"{}\"", source.route))),
+                    Some(route) if route.upstream.is_none() && route.deployment.is_none() => {
+                        diagnostics.push(ConfigDiagnostic::error(&location, format!("route \"{}\" has no upstream", source.route)));
+                    }
+                    // A rewrite can't be run backwards to find the paths clients use.
+                    Some(route) if route.rewrite.is_some() => {
+                        diagnostics.push(ConfigDiagnostic::error(&location, format!("route \"{}\" rewrites paths", source.route)));
+                    }
+                    Some(_) => {}
+                }
+                if !source.path.starts_with('/') {
+                    diagnostics.push(ConfigDiagnostic::error(&location, format!("path \"{}\" must start with '/'", source.path)));
+                }
+            }
+        }
+        if let Some(portal) = &self.portal {
+            if !portal.path.starts_with('/') || portal.path.ends_with('/') {
+                diagnostics.push(ConfigDiagnostic::error(
+                    "portal.path",
+                    format!("\"{}\" must be a path starting with '/' and not ending with one", portal.path),
+                ));
+            }
+        }
+        if let Some(analytics) = &self.analytics {
+            if analytics.top == 0 || analytics.snapshot_secs == 0 {
+                diagnostics.push(ConfigDiagnostic::error("analytics", "top and snapshot_secs must be positive"));
+            }
+            if self.persistence.is_none() {
+                diagnostics.push(ConfigDiagnostic::warning("analytics", "without persistence, a restart loses the day's figures"));
+            }
+        }
+        if let Some(events) = &self.events {
+            if events.batch_size == 0 || events.flush_interval_ms == 0 || events.queue_capacity == 0 || events.timeout_ms == 0 {
+                diagnostics.push(ConfigDiagnostic::error(
+                    "events",
+                    "batch_size, flush_interval_ms, queue_capacity and timeout_ms must be positive",
+                ));
+            }
+            if events.batch_size > events.queue_capacity {
+                diagnostics.push(ConfigDiagnostic::warning("events", "batch_size is larger than queue_capacity, batches never fill"));
+            }
+            match &events.sink {
+                EventSinkConfig::Kafka { brokers, topic, tls, sasl } => {
+                    if brokers.is_empty() {
+                        diagnostics.push(ConfigDiagnostic::error("events.sink.kafka.brokers", "must not be empty"));
+                    }
+                    for (i, broker) in brokers.iter().enumerate() {
+                        if broker.parse::<Authority>().ok().and_then(|authority| authority.port_u16()).is_none() {
+                            diagnostics.push(ConfigDiagnostic::error(
+                                format!("events.sink.kafka.brokers[{}]", i),
+                                format!("\"{}\" must be host:port", broker),
+                            ));
+                        }
+                    }
+                    if let Err(message) = kafka::check_topic(topic) {
+                        diagnostics.push(ConfigDiagnostic::error("events.sink.kafka.topic", message));
+                    }
+                    if let Some(tls) = tls {
+                        validate_client_tls("events.sink.kafka.tls", tls, diagnostics);
+                    }
+                    if sasl.as_ref().is_some_and(|sasl| sasl.username.is_empty() || sasl.password.is_empty()) {
+                        diagnostics.push(ConfigDiagnostic::error("events.sink.kafka.sasl", "username and password must not be empty"));
+                    }
+                }
+                EventSinkConfig::Nats { url, subject, username, password, tls } => {
+                    if let Err(message) = NatsAddress::new(url, username.as_deref(), password.as_deref()) {
+                        diagnostics.push(ConfigDiagnostic::error("events.sink.nats", message));
+                    }
+                    if let Err(message) = nats::check_subject(subject) {
+                        diagnostics.push(ConfigDiagnostic::error("events.sink.nats.subject", message));
+                    }
+                    if let Some(tls) = tls {
+                        validate_client_tls("events.sink.nats.tls", tls, diagnostics);
+                    }
+                }
+            }
+        }
+        if let Some(tracing) = &self.tracing {
+            if !is_http_url(&tracing.endpoint) {
+                diagnostics.push(ConfigDiagnostic::error(
+                    "tracing.endpoint",
+                    format!("\"{}\" must be an http(s) URL", tracing.endpoint),
+                ));
+            }
+            if tracing.service_name.is_empty() {
+                diagnostics.push(ConfigDiagnostic::error("tracing.service_name", "must not be empty"));
+            }
+            if !(0.0..=1.0).contains(&tracing.sample_rate) {
+                diagnostics.push(ConfigDiagnostic::error("tracing.sample_rate", "must be between 0 and 1"));
+            }
+            if tracing.batch_size == 0 || tracing.flush_interval_ms == 0 || tracing.queue_capacity == 0 || tracing.timeout_ms == 0 {
+                diagnostics.push(ConfigDiagnostic::error(
+                    "tracing",
+                    "batch_size, flush_interval_ms, queue_capacity and timeout_ms must be positive",
+                ));
+            }
+        }
+        if let Some(syslog) = &self.syslog {
+            if syslog.address.parse::<Authority>().ok().and_then(|authority| authority.port_u16()).is_none() {
+                diagnostics.push(ConfigDiagnostic::error("syslog.address", format!("\"{}\" must be host:port", syslog.address)));
+            }
+            if let SyslogTransport::Tls(tls) = &syslog.transport {
+                validate_client_tls("syslog.transport.tls", tls, diagnostics);
+            }
+            if !syslog::is_header_field(&syslog.app_name, syslog::MAX_APP_NAME_LEN) {
+                diagnostics.push(ConfigDiagnostic::error(
+                    "syslog.app_name",
+                    format!("must be 1 to {} printable ASCII characters without spaces", syslog::MAX_APP_NAME_LEN),
+                ));
+            }
+            if syslog.hostname.as_ref().is_some_and(|hostname| !syslog::is_header_field(hostname, syslog::MAX_HOSTNAME_LEN)) {
+                diagnostics.push(ConfigDiagnostic::error(
+                    "syslog.hostname",
+                    format!("must be 1 to {} printable ASCII characters without spaces", syslog::MAX_HOSTNAME_LEN),
+                ));
+            }
+            if syslog.queue_capacity == 0 {
+                diagnostics.push(ConfigDiagnostic::error("syslog.queue_capacity", "must be positive"));
+            }
+        }
+        if let Some(webhooks) = &self.webhooks {
+            if webhooks.endpoints.is_empty() {
+                diagnostics.push(ConfigDiagnostic::warning("webhooks", "no endpoints, no webhooks will be sent"));
+            }
+            if webhooks.max_attempts == 0 || webhooks.timeout_ms == 0 {
+                diagnostics.push(ConfigDiagnostic::error("webhooks", "max_attempts and timeout_ms must be positive"));
+            }
+            for (i, endpoint) in webhooks.endpoints.iter().enumerate() {
+                // A URL holding a token is best given as a secret reference.
+                if SecretRef::parse(&endpoint.url).is_none() && !is_http_url(&endpoint.url) {
+                    diagnostics.push(ConfigDiagnostic::error(
+                        format!("webhooks.endpoints[{}]", i),
+                        format!("\"{}\" must be an http(s) URL", endpoint.url),
+                    ));
+                }
+            }
+        }
+        if let Some(cluster) = &self.cluster {
+            if cluster.poll_interval_secs == 0 {
+                diagnostics.push(ConfigDiagnostic::error("cluster", "poll_interval_secs must be positive"));
+            }
+            let (address, key) = match &cluster.source {
+                ClusterSource::Consul { address, key, .. } => (Some(address), key),
+                ClusterSource::Etcd { address, key, username, password } => {
+                    if username.is_some() != password.is_some() {
+                        diagnostics.push(ConfigDiagnostic::error("cluster.source", "etcd username and password must be set together"));
+                    }
+                    (Some(address), key)
+                }
+                ClusterSource::S3 { key, region, endpoint, .. } => {
+                    if region.is_empty() {
+                        diagnostics.push(ConfigDiagnostic::error("cluster.source", "region must not be empty"));
+                    }
+                    (endpoint.as_ref(), key)
+                }
+            };
+            if let Some(address) = address.filter(|address| !is_http_url(address)) {
+                diagnostics.push(ConfigDiagnostic::error("cluster.source", format!("\"{}\" must be an http(s) URL", address)));
+            }
+            if key.is_empty() {
+                diagnostics.push(ConfigDiagnostic::error("cluster.source", "key must not be empty"));
+            }
+            if let Some(election) = &cluster.leader_election {
+                if matches!(cluster.source, ClusterSource::S3 { .. }) {
+                    diagnostics.push(ConfigDiagnostic::error("cluster.leader_election", "leader election needs a consul or etcd source"));
+                }
+                if election.key.is_empty() {
+                    diagnostics.push(ConfigDiagnostic::error("cluster.leader_election.key", "key must not be empty"));
+                }
+                if !(MIN_LEADER_TTL_SECS..=MAX_LEADER_TTL_SECS).contains(&election.ttl_secs) {
+                    diagnostics.push(ConfigDiagnostic::error(
+                        "cluster.leader_election.ttl_secs",
+                        format!("ttl_secs must be between {} and {}", MIN_LEADER_TTL_SECS, MAX_LEADER_TTL_SECS),
+                    ));
+                }
+            }
+        }
+        if let Some(endpoint) = &self.secrets.aws.endpoint {
+            if !is_http_url(endpoint) {
+                diagnostics.push(ConfigDiagnostic::error(
+                    "secrets.aws.endpoint",
+                    format!("\"{}\" must be an http(s) URL", endpoint),
+                ));
+            }
+        }
+
+        for (location, reference) in secrets::config_references(self) {
+            match SecretRef::parse(&reference) {
+                Some(Err(message)) => diagnostics.push(ConfigDiagnostic::error(location, message)),
+                Some(Ok(SecretRef::Vault { .. })) if self.secrets.vault.is_none() => {
+                    diagnostics.push(ConfigDiagnostic::error(location, "refers to Vault but secrets.vault is not configured"));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn validate_tenancy(&self, diagnostics: &mut Vec<ConfigDiagnostic>) {
+        if self.tenancy.claim.as_ref().is_some_and(|claim| claim.is_empty()) {
+            diagnostics.push(ConfigDiagnostic::error("tenancy.claim", "claim must not be empty"));
+        }
+
+        let route_names: HashSet<&str> = self.routes.iter().filter_map(|r| r.name.as_deref()).collect();
+        let mut hosts = HashMap::new();
+        let mut prefixes = HashMap::new();
+        let mut tenants: Vec<_> = self.tenancy.tenants.iter().collect();
+        tenants.sort_by_key(|(name, _)| name.as_str());
+        for (name, tenant) in tenants {
+            let location = format!("tenancy.tenants.{}", name);
+            for host in &tenant.hosts {
+                if let Some(other) = hosts.insert(host.to_ascii_lowercase(), name) {
+                    diagnostics.push(ConfigDiagnostic::error(
+                        &location,
+                        format!("host \"{}\" is already used by tenant \"{}\"", host, other),
+                    ));
+                }
+            }
+            if let Some(prefix) = &tenant.path_prefix {
+                if !prefix.starts_with('/') || normalize_prefix(prefix) == "/" {
+                    diagnostics.push(ConfigDiagnostic::error(
+                        &location,
+                        format!("path_prefix \"{}\" must start with '/' and not be the root", prefix),
+                    ));
+                } else if let Some(other) = prefixes.insert(normalize_prefix(prefix), name) {
+                    diagnostics.push(ConfigDiagnostic::error(
+                        &location,
+                        format!("path_prefix \"{}\" is already used by tenant \"{}\"", prefix, other),
+                    ));
+                }
+            }
+            for (field, limit) in [("rate_limit", &tenant.rate_limit), ("quota", &tenant.quota)] {
+                if limit.is_some_and(|limit| limit.requests == 0 || limit.window_secs == 0) {
+                    diagnostics.push(ConfigDiagnostic::error(
+                        &location,
+                        format!("{} requests and window_secs must be positive", field),
+                    ));
+                }
+            }
+
+            for (field, cap) in [
+                ("max_cache_entries", tenant.max_cache_entries),
+                ("max_rate_limit_buckets", tenant.max_rate_limit_buckets),
+            ] {
+                if cap == Some(0) {
+                    diagnostics.push(ConfigDiagnostic::error(&location, format!("{} must be positive", field)));
+                }
+            }
+
+            let mut upstreams: Vec<_> = tenant.upstreams.iter().collect();
+            upstreams.sort();
+            let referenced = upstreams.iter().map(|(route, _)| route.as_str()).chain(tenant.disabled_routes.iter().map(String::as_str));
+            for route in referenced {
+                if !route_names.contains(route) {
+                    diagnostics.push(ConfigDiagnostic::error(&location, format!("unknown route \"{}\"", route)));
+                }
+            }
+            for (_, upstream) in upstreams {
+                if let Err(message) = parse_upstream(upstream) {
+                    diagnostics.push(ConfigDiagnostic::error(&location, message));
+                }
+            }
+        }
+    }
+
+    /// Attempts a TCP connection to every distinct upstream. Unreachable
+    /// upstreams are reported as warnings since they may simply not be up yet.
+    pub async fn probe_upstreams(&self) -> Vec<ConfigDiagnostic> {
+        let mut diagnostics = Vec::new();
+        let mut probed = HashSet::new();
+
+        for (i, route) in self.routes.iter().enumerate() {
+            for upstream in route.upstreams() {
+                let Ok(address) = parse_upstream(upstream) else {
+                    continue;
+                };
+                if !probed.insert(address.clone()) {
+                    continue;
+                }
+
+                let location = route_location(i, route);
+                let probe = timeout(
+                    Duration::from_secs(UPSTREAM_PROBE_TIMEOUT_SECS),
+                    TcpStream::connect(&address),
+                ).await;
+                let message = match probe {
+                    Ok(Ok(_)) => continue,
+                    Ok(Err(e)) => format!("upstream {} is unreachable: {}", address, e),
+                    Err(_) => format!(
+                        "upstream {} did not accept a connection within {}s",
+                        address, UPSTREAM_PROBE_TIMEOUT_SECS
+                    ),
+                };
+                diagnostics.push(ConfigDiagnostic::warning(location, message));
+            }
+        }
+
+        diagnostics
+    }
+}
+
+pub fn has_errors(diagnostics: &[ConfigDiagnostic]) -> bool {
+    diagnostics.iter().any(|d| d.severity == Severity::Error)
+}
+
+/// Records `"file"` or `"default"` for `effective` under `location`,
+/// descending into objects, and lists of objects such as `routes`, that the
+/// file set.
+fn value_sources(effective: &Value, file: Option<&Value>, location: String, out: &mut Map<String, Value>) {
+    let Some(file) = file else {
+        out.insert(location, Value::from("default"));
+        return;
+    };
+    let join = |key: &str| if location.is_empty() { key.to_string() } else { format!("{}.{}", location, key) };
+    match (effective, file) {
+        (Value::Object(effective), Value::Object(file)) if !effective.is_empty() => {
+            for (key, value) in effective {
+                value_sources(value, file.get(key), join(key), out);
+            }
+        }
+        (Value::Array(effective), Value::Array(file)) if effective.iter().any(Value::is_object) => {
+            for (i, value) in effective.iter().enumerate() {
+                value_sources(value, file.get(i), format!("{}[{}]", location, i), out);
+            }
+        }
+        _ => {
+            out.insert(location, Value::from("file"));
+        }
+    }
+}
+
+fn route_location(index: usize, route: &RouteConfig) -> String {
+    match &route.name {
+        Some(name) => format!("routes[{}] ({})", index, name),
+        None => format!("routes[{}]", index),
+    }
+}
+
+fn methods_overlap(a: &RouteConfig, b: &RouteConfig) -> bool {
+    a.methods.is_empty() || b.methods.is_empty() || a.methods.iter().any(|m| b.methods.contains(m))
+}
+
+fn same_predicates(a: &RouteConfig, b: &RouteConfig) -> bool {
+    let lowercase = |headers: &HashMap<String, String>| -> HashMap<String, String> {
+        headers.iter().map(|(k, v)| (k.to_lowercase(), v.clone())).collect()
+    };
+    lowercase(&a.match_headers) == lowercase(&b.match_headers)
+        && a.match_query == b.match_query
+        && a.match_soap == b.match_soap
+}
+
+fn normalize_prefix(prefix: &str) -> String {
+    let trimmed = prefix.trim_end_matches('/');
+    if trimmed.is_empty() { "/".to_string() } else { trimmed.to_string() }
+}
+
+fn validate_jwt_key(
+    algorithm: JwtAlgorithm,
+    secret: &Option<String>,
+    key: &Option<String>,
+    key_file: &Option<PathBuf>,
+) -> Result<(), String> {
+    match (algorithm, secret, key, key_file) {
+        (JwtAlgorithm::HS256, Some(secret), None, None) if !secret.is_empty() => Ok(()),
+        (JwtAlgorithm::HS256, _, _, _) => Err("HS256 needs a non-empty secret and no key or key_file".to_string()),
+        (_, None, Some(key), None) if !key.trim().is_empty() => Ok(()),
+        (_, None, None, Some(path)) if path.is_file() => Ok(()),
+        (_, None, None, Some(path)) => Err(format!("key_file {} does not exist", path.display())),
+        (algorithm, _, _, _) => Err(format!("{:?} needs one of key and key_file, and no secret", algorithm)),
+    }
+}
+
+fn validate_upstream_auth(config: &UpstreamAuthConfig) -> Result<(), String> {
+    match config {
+        UpstreamAuthConfig::Bearer(token) if token.trim().is_empty() => {
+            Err("upstream_auth bearer token must not be empty".to_string())
+        }
+        UpstreamAuthConfig::Basic { username, .. } if username.is_empty() || username.contains(':') => {
+            Err("upstream_auth basic username must be non-empty and must not contain ':'".to_string())
+        }
+        UpstreamAuthConfig::TokenFile(path) if !path.is_file() => {
+            Err(format!("upstream_auth token_file {} does not exist", path.display()))
+        }
+        UpstreamAuthConfig::OAuth2(oauth2) => {
+            let uri: Uri = oauth2
+                .token_url
+                .parse()
+                .map_err(|_| format!("upstream_auth token_url \"{}\" is not a valid URI", oauth2.token_url))?;
+            if !matches!(uri.scheme_str(), Some("http" | "https")) || uri.host().is_none() {
+                return Err(format!("upstream_auth token_url \"{}\" must be an http(s) URL", oauth2.token_url));
+            }
+            if oauth2.client_id.is_empty() {
+                return Err("upstream_auth oauth2 client_id must not be empty".to_string());
+            }
+            Ok(())
+        }
+        UpstreamAuthConfig::Ntlm(ntlm) if ntlm.username.is_empty() || ntlm.username.contains('\\') => {
+            Err("upstream_auth ntlm username must be non-empty, with the domain in domain".to_string())
+        }
+        _ => Ok(()),
+    }
+}
+
+fn validate_oidc(oidc: &OidcConfig, diagnostics: &mut Vec<ConfigDiagnostic>) {
+    if !is_http_url(&oidc.issuer) {
+        diagnostics.push(ConfigDiagnostic::error("auth.oidc.issuer", "issuer must be an http(s) URL"));
+    }
+    let callback = oidc.redirect_uri.parse::<Uri>().ok().filter(|_| is_http_url(&oidc.redirect_uri));
+    match callback {
+        Some(uri) if uri.path() == "/" || uri.path() == oidc.sign_out_path => diagnostics.push(ConfigDiagnostic::error(
+            "auth.oidc.redirect_uri",
+            "redirect_uri needs a path of its own for the callback",
+        )),
+        Some(_) => {}
+        None => diagnostics.push(ConfigDiagnostic::error("auth.oidc.redirect_uri", "redirect_uri must be an http(s) URL")),
+    }
+    if !oidc.scopes.iter().any(|scope| scope == "openid") {
+        diagnostics.push(ConfigDiagnostic::error("auth.oidc.scopes", "scopes must include openid"));
+    }
+    if !oidc.sign_out_path.starts_with('/') {
+        diagnostics.push(ConfigDiagnostic::error("auth.oidc.sign_out_path", "sign_out_path must start with /"));
+    }
+    let session = &oidc.session;
+    if session.secret.len() < MIN_SESSION_SECRET_LEN {
+        diagnostics.push(ConfigDiagnostic::error(
+            "auth.oidc.session.secret",
+            format!("secret must be at least {} bytes", MIN_SESSION_SECRET_LEN),
+        ));
+    }
+    let valid_name = !session.cookie_name.is_empty()
+        && session.cookie_name.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_.".contains(&b));
+    if !valid_name {
+        diagnostics.push(ConfigDiagnostic::error("auth.oidc.session.cookie_name", "cookie_name may only use letters, digits, - _ and ."));
+    }
+    if session.ttl_secs == 0 {
+        diagnostics.push(ConfigDiagnostic::error("auth.oidc.session.ttl_secs", "ttl_secs must be positive"));
+    }
+    if HeaderName::from_bytes(oidc.csrf.header.as_bytes()).is_err() {
+        diagnostics.push(ConfigDiagnostic::error(
+            "auth.oidc.csrf.header",
+            format!("\"{}\" is not a valid header name", oidc.csrf.header),
+        ));
+    }
+    if oidc.csrf.cookie_name.is_empty() || oidc.csrf.cookie_name == session.cookie_name {
+        diagnostics.push(ConfigDiagnostic::error("auth.oidc.csrf.cookie_name", "cookie_name must differ from the session cookie"));
+    }
+    if !session.secure {
+        diagnostics.push(ConfigDiagnostic::warning("auth.oidc.session.secure", "session cookies are sent over plain HTTP"));
+    }
+}
+
+fn validate_acme(acme: &AcmeConfig, diagnostics: &mut Vec<ConfigDiagnostic>) {
+    if acme.domains.is_empty() {
+        diagnostics.push(ConfigDiagnostic::error("tls.acme.domains", "list at least one domain"));
+    }
+    for domain in &acme.domains {
+        if !is_server_name(domain) {
+            diagnostics.push(ConfigDiagnostic::error(
+                "tls.acme.domains",
+                format!("\"{}\" is not a domain name; wildcards can't be validated over HTTP or TLS-ALPN", domain),
+            ));
+        }
+    }
+    if !acme.cert_dir.is_dir() {
+        diagnostics.push(ConfigDiagnostic::error(
+            "tls.acme.cert_dir",
+            format!("{} does not exist or is not a directory", acme.cert_dir.display()),
+        ));
+    }
+    if !acme.accept_terms {
+        diagnostics.push(ConfigDiagnostic::error(
+            "tls.acme.accept_terms",
+            "the CA's terms of service must be accepted",
+        ));
+    }
+    if !is_http_url(&acme.directory_url) {
+        diagnostics.push(ConfigDiagnostic::error("tls.acme.directory_url", "directory_url must be an http(s) URL"));
+    }
+    if acme.challenge == AcmeChallenge::Http01 && acme.http_listen_addr.parse::<SocketAddr>().is_err() {
+        diagnostics.push(ConfigDiagnostic::error(
+            "tls.acme.http_listen_addr",
+            format!("\"{}\" is not a socket address", acme.http_listen_addr),
+        ));
+    }
+    if acme.check_interval_secs == 0 {
+        diagnostics.push(ConfigDiagnostic::error("tls.acme.check_interval_secs", "check_interval_secs must be positive"));
+    }
+}
+
+fn validate_tls_policy(tls: &TlsConfig, diagnostics: &mut Vec<ConfigDiagnostic>) {
+    match tls::cipher_suites(&tls.cipher_suites) {
+        Err(message) => diagnostics.push(ConfigDiagnostic::error("tls.cipher_suites", message)),
+        Ok(suites) if !suites.is_empty() && tls::protocol_versions(tls.min_version, &suites).is_empty() => {
+            diagnostics.push(ConfigDiagnostic::error(
+                "tls.cipher_suites",
+                "none of the cipher suites can be used with min_version",
+            ));
+        }
+        Ok(_) => {}
+    }
+    for protocol in &tls.alpn_protocols {
+        if !ALPN_PROTOCOLS.contains(&protocol.as_str()) {
+            diagnostics.push(ConfigDiagnostic::error(
+                "tls.alpn_protocols",
+                format!("\"{}\" is not one of {}", protocol, ALPN_PROTOCOLS.join(", ")),
+            ));
+        }
+    }
+    for (i, service) in tls.alt_svc.iter().enumerate() {
+        let location = format!("tls.alt_svc[{}]", i);
+        let token = |text: &str| !text.is_empty() && text.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$&'*+-.^_`|~".contains(&b));
+        if !token(&service.protocol) {
+            diagnostics.push(ConfigDiagnostic::error(&location, format!("protocol \"{}\" is not a token", service.protocol)));
+        }
+        if service.host.as_deref().is_some_and(|host| !is_server_name(host)) {
+            diagnostics.push(ConfigDiagnostic::error(&location, "host must be a DNS name"));
+        }
+        if service.port == 0 {
+            diagnostics.push(ConfigDiagnostic::error(&location, "port must be positive"));
+        }
+    }
+    if let Some(http3) = &tls.http3 {
+        if (tls.cert.is_none() && tls.cert_path.is_none()) || (tls.key.is_none() && tls.key_path.is_none()) {
+            diagnostics.push(ConfigDiagnostic::error("tls.http3", "HTTP/3 needs the default certificate and key"));
+        }
+        if let Some(addr) = http3.listen_addr.as_ref().filter(|addr| addr.parse::<SocketAddr>().is_err()) {
+            diagnostics.push(ConfigDiagnostic::error("tls.http3.listen_addr", format!("\"{}\" is not a socket address", addr)));
+        }
+    }
+}
+
+fn validate_upstream_connect(connect: &UpstreamConnectConfig, diagnostics: &mut Vec<ConfigDiagnostic>) {
+    if let Err(message) = connect.proxy.as_ref().map(Proxy::new).transpose() {
+        diagnostics.push(ConfigDiagnostic::error("upstream_connect.proxy", message));
+    }
+    let mut proxied: Vec<&String> = connect.upstream_proxies.keys().collect();
+    proxied.sort();
+    for upstream in proxied {
+        let location = format!("upstream_connect.upstream_proxies.{}", upstream);
+        if let Err(message) = parse_upstream(upstream) {
+            diagnostics.push(ConfigDiagnostic::error(&location, message));
+        }
+        if let Err(message) = Proxy::new(&connect.upstream_proxies[upstream]) {
+            diagnostics.push(ConfigDiagnostic::error(&location, message));
+        }
+    }
+
+    if !connect.tls.is_empty() && connect.tls_reload_interval_secs == 0 {
+        diagnostics.push(ConfigDiagnostic::error(
+            "upstream_connect.tls_reload_interval_secs",
+            "tls_reload_interval_secs must be positive",
+        ));
+    }
+    let mut upstreams: Vec<&String> = connect.tls.keys().collect();
+    upstreams.sort();
+    for upstream in upstreams {
+        let location = format!("upstream_connect.tls.{}", upstream);
+        if !upstream.parse::<Uri>().is_ok_and(|uri| uri.scheme_str() == Some("https") && uri.host().is_some()) {
+            diagnostics.push(ConfigDiagnostic::error(&location, format!("\"{}\" is not an https upstream", upstream)));
+        }
+        validate_client_tls(&location, &connect.tls[upstream], diagnostics);
+    }
+
+    if let Some(cache) = &connect.dns_cache {
+        for (i, nameserver) in cache.nameservers.iter().enumerate() {
+            if let Err(message) = dns::nameserver(nameserver) {
+                diagnostics.push(ConfigDiagnostic::error(format!("upstream_connect.dns_cache.nameservers[{}]", i), message));
+            }
+        }
+        if cache.max_ttl_secs == 0 || cache.min_ttl_secs > cache.max_ttl_secs {
+            diagnostics.push(ConfigDiagnostic::error(
+                "upstream_connect.dns_cache",
+                "max_ttl_secs must be positive and at least min_ttl_secs",
+            ));
+        }
+        if cache.timeout_ms == 0 {
+            diagnostics.push(ConfigDiagnostic::error("upstream_connect.dns_cache.timeout_ms", "must be positive"));
+        }
+    }
+}
+
+/// Checks the files of a client TLS setting exist, with the certificate and
+/// key given together.
+fn validate_client_tls(location: &str, tls: &UpstreamTlsConfig, diagnostics: &mut Vec<ConfigDiagnostic>) {
+    if tls.cert_path.is_some() != tls.key_path.is_some() {
+        diagnostics.push(ConfigDiagnostic::error(location, "set both or neither of cert_path and key_path"));
+    }
+    for (field, path) in [("cert_path", &tls.cert_path), ("key_path", &tls.key_path), ("ca_path", &tls.ca_path)] {
+        if let Some(path) = path.as_ref().filter(|path| !path.is_file()) {
+            diagnostics.push(ConfigDiagnostic::error(
+                format!("{}.{}", location, field),
+                format!("{} does not exist or is not a file", path.display()),
+            ));
+        }
+    }
+}
+
+/// Checks a certificate's PEM fields: each of cert and key is given either
+/// inline or as a readable file, or, when `optional`, both are left out.
+fn validate_pems(
+    location: &str,
+    pems: [(&str, &Option<String>, &Option<PathBuf>); 2],
+    optional: bool,
+    diagnostics: &mut Vec<ConfigDiagnostic>,
+) {
+    let without_pems = pems.iter().all(|(_, pem, path)| pem.is_none() && path.is_none());
+    for (field, pem, path) in pems {
+        match (pem, path) {
+            (Some(_), None) => {}
+            (None, None) if optional && without_pems => {}
+            (None, Some(path)) if path.is_file() => {}
+            (None, Some(path)) => diagnostics.push(ConfigDiagnostic::error(
+                format!("{}.{}_path", location, field),
+                format!("{} does not exist or is not a file", path.display()),
+            )),
+            _ => diagnostics.push(ConfigDiagnostic::error(
+                format!("{}.{}", location, field),
+                format!("set exactly one of {} and {}_path", field, field),
+            )),
+        }
+    }
+}
+
+/// A DNS name with at least two labels, as TLS clients send in SNI.
+fn is_server_name(name: &str) -> bool {
+    name.contains('.')
+        && name
+            .split('.')
+            .all(|label| !label.is_empty() && label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-'))
+}
+
+/// A Prometheus label name that isn't reserved for Prometheus itself.
+fn is_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !name.starts_with("__")
+}
+
+/// A Subresource Integrity value: a SHA-2 name and the base64 digest.
+fn is_integrity(value: &str) -> bool {
+    let Some((algorithm, digest)) = value.split_once('-') else {
+        return false;
+    };
+    let len = match algorithm {
+        "sha256" => 32,
+        "sha384" => 48,
+        "sha512" => 64,
+        _ => return false,
+    };
+    STANDARD.decode(digest).is_ok_and(|digest| digest.len() == len)
+}
+
+fn is_http_url(url: &str) -> bool {
+    url.parse::<Uri>().is_ok_and(|uri| matches!(uri.scheme_str(), Some("http" | "https")) && uri.host().is_some())
+}
+
+/// Parses an upstream base URL and returns its `host:port` address.
+pub fn parse_upstream(upstream: &str) -> Result<String, String> {
+    let uri: Uri = upstream
+        .parse()
+        .map_err(|e| format!("upstream \"{}\" is not a valid URI: {}", upstream, e))?;
+    let port = match uri.scheme_str() {
+        Some("http") => 80,
+        Some("https") => 443,
+        _ => return Err(format!("upstream \"{}\" must use the http or https scheme", upstream)),
+    };
+    let host = uri
+        .host()
+        .ok_or_else(|| format!("upstream \"{}\" has no host", upstream))?;
+    Ok(format!("{}:{}", host, uri.port_u16().unwrap_or(port)))
+}
@@ -0,0 +1,377 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use hyper::client::connect::dns::{GaiResolver, Name};
+use hyper::client::connect::{Connected, Connection};
+use hyper::client::conn::SendRequest;
+use hyper::client::HttpConnector;
+use hyper::service::Service;
+use hyper::{Body, Client, Request, Response, Uri};
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, RootCertStore};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+use crate::config::{IpPreference, UpstreamConnectConfig, UpstreamTlsConfig};
+use crate::dns::DnsCache;
+use crate::egress::{address, Egress, ProxyKind};
+use crate::errors::GatewayError;
+use crate::metrics::Connections;
+use crate::syslog;
+
+#[cfg(test)]
+mod tests;
+
+/// The connector behind the proxy's upstream client. It tunnels through
+/// the upstream's egress proxy if it has one, speaks TLS to https upstreams
+/// and counts every new connection per upstream, so pool reuse shows in the
+/// metrics.
+#[derive(Clone)]
+pub struct UpstreamConnector {
+    http: HttpConnector<Resolver>,
+    resolver: Resolver,
+    egress: Arc<Egress>,
+    tls: Arc<UpstreamTls>,
+    connections: Arc<Connections>,
+}
+
+impl Service<Uri> for UpstreamConnector {
+    type Response = UpstreamStream;
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+    type Future = Pin<Box<dyn Future<Output = Result<UpstreamStream, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.http.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let upstream = uri.authority().map(|authority| authority.to_string()).unwrap_or_default();
+        let address = address(&uri);
+        let host = uri.host().unwrap_or_default().trim_start_matches('[').trim_end_matches(']').to_string();
+        let port = uri.port_u16().unwrap_or(if uri.scheme_str() == Some("https") { 443 } else { 80 });
+        let tls = (uri.scheme_str() == Some("https")).then(|| self.tls.client_config(&address));
+        let proxy = self.egress.proxy(&address).cloned();
+        let connecting = self.http.call(proxy.as_ref().map_or(uri, |proxy| proxy.uri.clone()));
+        let (mut resolver, connections) = (self.resolver.clone(), self.connections.clone());
+        Box::pin(async move {
+            let mut stream = connecting.await?;
+            if let Some(proxy) = proxy {
+                let resolved = match proxy.kind {
+                    ProxyKind::Socks5 if host.parse::<IpAddr>().is_err() => {
+                        let mut addresses = resolver.call(Name::from_str(&host)?).await?;
+                        addresses.next().map(|address| address.ip())
+                    }
+                    _ => None,
+                };
+                proxy.tunnel(&mut stream, &host, port, resolved).await?;
+            }
+            let stream = match tls {
+                Some(config) => {
+                    let name = ServerName::try_from(host)?;
+                    UpstreamStream::Tls(Box::new(TlsConnector::from(config).connect(name, stream).await?))
+                }
+                None => UpstreamStream::Plain(stream),
+            };
+            connections.upstream_connected(&upstream);
+            Ok(stream)
+        })
+    }
+}
+
+/// A connection to an upstream, over TLS for https upstreams.
+pub enum UpstreamStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl Connection for UpstreamStream {
+    fn connected(&self) -> Connected {
+        match self {
+            UpstreamStream::Plain(stream) => stream.connected(),
+            UpstreamStream::Tls(stream) => stream.get_ref().0.connected(),
+        }
+    }
+}
+
+impl AsyncRead for UpstreamStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for UpstreamStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Client TLS settings for https upstreams: those in `upstream_connect.tls`,
+/// which `watch` reloads when their files change, and the public roots for
+/// every other upstream.
+pub struct UpstreamTls {
+    public: Arc<ClientConfig>,
+    upstreams: HashMap<String, ConfiguredUpstream>,
+}
+
+struct ConfiguredUpstream {
+    config: UpstreamTlsConfig,
+    client: RwLock<Arc<ClientConfig>>,
+}
+
+impl UpstreamTls {
+    pub fn new(configs: &HashMap<String, UpstreamTlsConfig>) -> Result<Self, String> {
+        let mut upstreams = HashMap::new();
+        for (upstream, config) in configs {
+            let uri: Uri = upstream.parse().map_err(|e| format!("{}: {}", upstream, e))?;
+            let client = client_config(config).map_err(|e| format!("{}: {}", upstream, e))?;
+            let configured = ConfiguredUpstream { config: config.clone(), client: RwLock::new(Arc::new(client)) };
+            upstreams.insert(address(&uri), configured);
+        }
+        Ok(Self { public: Arc::new(client_config(&UpstreamTlsConfig::default())?), upstreams })
+    }
+
+    /// The settings for new connections to `address`, a `host:port`.
+    pub fn client_config(&self, address: &str) -> Arc<ClientConfig> {
+        match self.upstreams.get(address) {
+            Some(upstream) => upstream.client.read().unwrap_or_else(|e| e.into_inner()).clone(),
+            None => self.public.clone(),
+        }
+    }
+
+    /// The certificate, key and CA files `watch` polls.
+    pub fn watched_files(&self) -> Vec<PathBuf> {
+        self.upstreams
+            .values()
+            .flat_map(|upstream| [&upstream.config.cert_path, &upstream.config.key_path, &upstream.config.ca_path])
+            .flatten()
+            .cloned()
+            .collect()
+    }
+
+    /// Reads every upstream's files again. An upstream whose files no longer
+    /// load keeps its previous settings. Pooled connections carry on with
+    /// the settings they were opened with.
+    pub fn reload(&self) -> Result<(), String> {
+        let mut errors = Vec::new();
+        for (address, upstream) in &self.upstreams {
+            match client_config(&upstream.config) {
+                Ok(client) => *upstream.client.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(client),
+                Err(e) => errors.push(format!("{}: {}", address, e)),
+            }
+        }
+        if errors.is_empty() {
+            return Ok(());
+        }
+        errors.sort();
+        Err(errors.join("; "))
+    }
+
+    /// Polls the files every `interval` and reloads when any of them
+    /// changes, so rotated client certificates are picked up.
+    pub async fn watch(self: Arc<Self>, interval: Duration) {
+        let files = self.watched_files();
+        let stamps = || files.iter().map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok()).collect::<Vec<_>>();
+        let mut last = stamps();
+        loop {
+            tokio::time::sleep(interval).await;
+            let current = stamps();
+            if current == last {
+                continue;
+            }
+            last = current;
+            match self.reload() {
+                Ok(()) => println!("Reloaded upstream TLS certificates"),
+                Err(e) => syslog::error(format_args!("Upstream TLS reload failed, keeping previous certificates: {}", e)),
+            }
+        }
+    }
+}
+
+/// Trusts the CA bundle in `ca_path`, or the public roots without one, and
+/// presents the client certificate if there is one.
+pub fn client_config(config: &UpstreamTlsConfig) -> Result<ClientConfig, String> {
+    let read = |path: &Path| std::fs::read(path).map_err(|e| format!("reading {}: {}", path.display(), e));
+    let mut roots = RootCertStore::empty();
+    match &config.ca_path {
+        Some(path) => {
+            for cert in rustls_pemfile::certs(&mut &*read(path)?) {
+                let cert = cert.map_err(|e| format!("reading {}: {}", path.display(), e))?;
+                roots.add(cert).map_err(|e| format!("{}: {}", path.display(), e))?;
+            }
+            if roots.is_empty() {
+                return Err(format!("no certificate found in {}", path.display()));
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+    let builder = ClientConfig::builder().with_root_certificates(roots);
+    let (Some(cert_path), Some(key_path)) = (&config.cert_path, &config.key_path) else {
+        return Ok(builder.with_no_client_auth());
+    };
+    let certs = rustls_pemfile::certs(&mut &*read(cert_path)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("reading {}: {}", cert_path.display(), e))?;
+    if certs.is_empty() {
+        return Err(format!("no certificate found in {}", cert_path.display()));
+    }
+    let key = rustls_pemfile::private_key(&mut &*read(key_path)?)
+        .map_err(|e| format!("reading {}: {}", key_path.display(), e))?
+        .ok_or_else(|| format!("no private key found in {}", key_path.display()))?;
+    builder.with_client_auth_cert(certs, key).map_err(|e| format!("client certificate: {}", e))
+}
+
+/// Resolves upstream host names with the system resolver, or through the
+/// DNS cache when there is one, then orders the addresses by family
+/// preference and drops any family that isn't allowed. The connector tries
+/// the first address's family first.
+#[derive(Clone)]
+pub struct Resolver {
+    system: GaiResolver,
+    cache: Option<Arc<DnsCache>>,
+    preference: IpPreference,
+}
+
+impl Resolver {
+    pub fn new(preference: IpPreference) -> Self {
+        Self { system: GaiResolver::new(), cache: None, preference }
+    }
+
+    pub fn with_cache(mut self, cache: Arc<DnsCache>) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+}
+
+impl Service<Name> for Resolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<Self::Response>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.system.poll_ready(cx)
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let preference = self.preference;
+        let lookup: Pin<Box<dyn Future<Output = io::Result<Vec<SocketAddr>>> + Send>> = match &self.cache {
+            Some(cache) => {
+                let (cache, name) = (cache.clone(), name.clone());
+                Box::pin(async move {
+                    let addresses = cache.resolve(name.as_str()).await?;
+                    Ok(addresses.into_iter().map(|ip| SocketAddr::new(ip, 0)).collect())
+                })
+            }
+            None => {
+                let lookup = self.system.call(name.clone());
+                Box::pin(async move { Ok(lookup.await?.collect()) })
+            }
+        };
+        Box::pin(async move {
+            let addresses = order(lookup.await?, preference);
+            if addresses.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{} has no address of the allowed family", name),
+                ));
+            }
+            Ok(addresses.into_iter())
+        })
+    }
+}
+
+/// `addresses` in the order `preference` asks for, keeping the resolver's
+/// order within each family.
+pub fn order(mut addresses: Vec<SocketAddr>, preference: IpPreference) -> Vec<SocketAddr> {
+    match preference {
+        IpPreference::System => {}
+        IpPreference::PreferIpv6 => addresses.sort_by_key(|address| address.is_ipv4()),
+        IpPreference::PreferIpv4 => addresses.sort_by_key(|address| address.is_ipv6()),
+        IpPreference::Ipv4Only | IpPreference::Ipv6Only => addresses.retain(|address| preference.allows(address.ip())),
+    }
+    addresses
+}
+
+/// The proxy's pooled upstream client. Every request is counted per
+/// upstream next to the connections the connector opens.
+#[derive(Clone)]
+pub struct UpstreamClient {
+    client: Client<UpstreamConnector>,
+    connector: UpstreamConnector,
+    connections: Arc<Connections>,
+}
+
+impl UpstreamClient {
+    pub fn new(config: &UpstreamConnectConfig, tls: Arc<UpstreamTls>, connections: Arc<Connections>) -> Result<Self, String> {
+        let connector = http_connector(config, tls, connections.clone())?;
+        let client = Client::builder().build(connector.clone());
+        Ok(Self { client, connector, connections })
+    }
+
+    /// The cache upstream host names are resolved through, if any.
+    pub fn dns_cache(&self) -> Option<Arc<DnsCache>> {
+        self.connector.resolver.cache.clone()
+    }
+
+    pub async fn request(&self, request: Request<Body>) -> hyper::Result<Response<Body>> {
+        let upstream = request.uri().authority().map(|authority| authority.to_string()).unwrap_or_default();
+        self.connections.upstream_request(&upstream);
+        self.client.request(request).await
+    }
+
+    /// A new HTTP/1.1 connection to `uri`'s upstream, outside the pool, for
+    /// authentication that holds for a connection rather than a request.
+    /// Requests on it need an origin-form URI and a `Host` header.
+    pub async fn connection(&self, uri: &Uri) -> Result<SendRequest<Body>, GatewayError> {
+        let upstream = uri.authority().map(|authority| authority.to_string()).unwrap_or_default();
+        self.connections.upstream_request(&upstream);
+        let stream = self.connector.clone().call(uri.clone()).await.map_err(|e| GatewayError::UpstreamConnect(e.to_string()))?;
+        let (sender, connection) = hyper::client::conn::handshake(stream).await.map_err(|e| GatewayError::upstream(&e))?;
+        tokio::spawn(connection);
+        Ok(sender)
+    }
+}
+
+pub fn http_connector(
+    config: &UpstreamConnectConfig,
+    tls: Arc<UpstreamTls>,
+    connections: Arc<Connections>,
+) -> Result<UpstreamConnector, String> {
+    let mut resolver = Resolver::new(config.ip_preference);
+    if let Some(cache) = &config.dns_cache {
+        resolver = resolver.with_cache(Arc::new(DnsCache::new(cache)));
+    }
+    let mut http = HttpConnector::new_with_resolver(resolver.clone());
+    let delay = (config.happy_eyeballs_delay_ms > 0).then(|| Duration::from_millis(config.happy_eyeballs_delay_ms));
+    http.set_happy_eyeballs_timeout(delay);
+    http.enforce_http(false);
+    let egress = Arc::new(Egress::new(config)?);
+    Ok(UpstreamConnector { http, resolver, egress, tls, connections })
+}
@@ -0,0 +1,157 @@
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::path::Path;
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use hyper::client::connect::dns::Name;
+    use hyper::server::conn::Http;
+    use hyper::service::{service_fn, Service};
+    use hyper::{Body, Request, Response};
+    use ring::rand::SystemRandom;
+    use rustls::server::WebPkiClientVerifier;
+    use rustls::{RootCertStore, ServerConfig};
+    use tokio_rustls::TlsAcceptor;
+    use warp::Filter;
+    use crate::acme::{pem, self_signed};
+    use crate::config::{IpPreference, UpstreamConnectConfig, UpstreamTlsConfig};
+    use crate::connector::{order, Resolver, UpstreamClient, UpstreamTls};
+    use crate::metrics::Connections;
+
+    fn addresses(list: &[&str]) -> Vec<SocketAddr> {
+        list.iter().map(|address| address.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_addresses_are_ordered_by_preference() {
+        let resolved = addresses(&["10.0.0.1:80", "[2001:db8::1]:80", "10.0.0.2:80", "[2001:db8::2]:80"]);
+        assert_eq!(order(resolved.clone(), IpPreference::System), resolved);
+        assert_eq!(
+            order(resolved.clone(), IpPreference::PreferIpv6),
+            addresses(&["[2001:db8::1]:80", "[2001:db8::2]:80", "10.0.0.1:80", "10.0.0.2:80"])
+        );
+        assert_eq!(
+            order(resolved.clone(), IpPreference::PreferIpv4),
+            addresses(&["10.0.0.1:80", "10.0.0.2:80", "[2001:db8::1]:80", "[2001:db8::2]:80"])
+        );
+        assert_eq!(order(resolved.clone(), IpPreference::Ipv4Only), addresses(&["10.0.0.1:80", "10.0.0.2:80"]));
+        assert_eq!(order(resolved, IpPreference::Ipv6Only), addresses(&["[2001:db8::1]:80", "[2001:db8::2]:80"]));
+    }
+
+    #[tokio::test]
+    async fn test_resolver_drops_excluded_family() {
+        let localhost = Name::from_str("localhost").unwrap();
+        let found: Vec<SocketAddr> = Resolver::new(IpPreference::Ipv4Only).call(localhost.clone()).await.unwrap().collect();
+        assert!(!found.is_empty() && found.iter().all(SocketAddr::is_ipv4), "{:?}", found);
+
+        let only_v6 = Resolver::new(IpPreference::Ipv6Only).call(localhost).await;
+        if let Ok(found) = only_v6 {
+            assert!(found.into_iter().all(|address| address.is_ipv6()));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_upstream_over_ipv6() {
+        let (addr, server) = warp::serve(warp::any().map(|| "over v6")).bind_ephemeral(([0, 0, 0, 0, 0, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let config = UpstreamConnectConfig { ip_preference: IpPreference::PreferIpv6, ..UpstreamConnectConfig::default() };
+        let connections = Arc::new(Connections::default());
+        let tls = Arc::new(UpstreamTls::new(&config.tls).unwrap());
+        let client = UpstreamClient::new(&config, tls, connections.clone()).unwrap();
+        for _ in 0..2 {
+            let request = Request::get(format!("http://{}/", addr)).body(Body::empty()).unwrap();
+            let response = client.request(request).await.unwrap();
+            assert_eq!(hyper::body::to_bytes(response.into_body()).await.unwrap(), "over v6");
+        }
+
+        // The second request reused the pooled connection.
+        let text = connections.render();
+        assert!(text.contains(&format!("gateway_upstream_connections_opened_total{{upstream=\"{}\"}} 1", addr)), "{}", text);
+        assert!(text.contains(&format!("gateway_upstream_requests_total{{upstream=\"{}\"}} 2", addr)));
+    }
+
+    /// Writes a new self-signed certificate and key for `name` to
+    /// `<name>.crt` and `<name>.key` in `dir`, returning the certificate.
+    fn write_identity(dir: &Path, name: &str) -> Vec<u8> {
+        let (cert, key) = self_signed(name, Vec::new(), 1, &SystemRandom::new()).unwrap();
+        std::fs::write(dir.join(format!("{}.crt", name)), pem("CERTIFICATE", &cert)).unwrap();
+        std::fs::write(dir.join(format!("{}.key", name)), pem("PRIVATE KEY", &key)).unwrap();
+        cert
+    }
+
+    /// An https upstream on localhost that only accepts clients presenting
+    /// `client_cert`.
+    async fn mtls_upstream(dir: &Path, client_cert: Vec<u8>) -> SocketAddr {
+        write_identity(dir, "localhost");
+        let mut clients = RootCertStore::empty();
+        clients.add(client_cert.into()).unwrap();
+        let verifier = WebPkiClientVerifier::builder(Arc::new(clients)).build().unwrap();
+        let key = crate::tls::certified_key(
+            &std::fs::read(dir.join("localhost.crt")).unwrap(),
+            &std::fs::read(dir.join("localhost.key")).unwrap(),
+        )
+        .unwrap();
+        let config = ServerConfig::builder()
+            .with_client_cert_verifier(verifier)
+            .with_cert_resolver(Arc::new(crate::tls::CertResolver::new(Some(key))));
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    if let Ok(stream) = acceptor.accept(stream).await {
+                        let service = service_fn(|_| async { Ok::<_, Infallible>(Response::new(Body::from("mutual"))) });
+                        let _ = Http::new().serve_connection(stream, service).await;
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_client_certificate_is_presented_and_reloaded() {
+        let dir = std::env::temp_dir().join(format!("api-gateway-upstream-tls-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let trusted = write_identity(&dir, "trusted");
+        let addr = mtls_upstream(&dir, trusted).await;
+        // The gateway starts out with a certificate the upstream doesn't trust.
+        write_identity(&dir, "gateway");
+        let upstream = format!("https://localhost:{}", addr.port());
+        let upstream_tls = UpstreamTlsConfig {
+            cert_path: Some(dir.join("gateway.crt")),
+            key_path: Some(dir.join("gateway.key")),
+            ca_path: Some(dir.join("localhost.crt")),
+        };
+        let config = UpstreamConnectConfig { tls: HashMap::from([(upstream.clone(), upstream_tls)]), ..UpstreamConnectConfig::default() };
+        let tls = Arc::new(UpstreamTls::new(&config.tls).unwrap());
+        assert_eq!(tls.watched_files().len(), 3);
+        let client = UpstreamClient::new(&config, tls.clone(), Arc::new(Connections::default())).unwrap();
+        let get = |url: String| client.request(Request::get(url).body(Body::empty()).unwrap());
+        assert!(get(format!("{}/", upstream)).await.is_err());
+
+        // Rotating the files in place takes effect on reload.
+        std::fs::copy(dir.join("trusted.crt"), dir.join("gateway.crt")).unwrap();
+        std::fs::copy(dir.join("trusted.key"), dir.join("gateway.key")).unwrap();
+        tls.reload().unwrap();
+        let response = get(format!("{}/", upstream)).await.unwrap();
+        assert_eq!(hyper::body::to_bytes(response.into_body()).await.unwrap(), "mutual");
+
+        // Other upstreams are checked against the public roots.
+        assert!(get(format!("https://127.0.0.1:{}/", addr.port())).await.is_err());
+
+        // A half-written rotation keeps the settings that worked.
+        let address = format!("localhost:{}", addr.port());
+        let loaded = tls.client_config(&address);
+        std::fs::write(dir.join("gateway.key"), "not a key").unwrap();
+        assert!(tls.reload().unwrap_err().contains("no private key found"));
+        assert!(Arc::ptr_eq(&loaded, &tls.client_config(&address)));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
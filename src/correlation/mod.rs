@@ -0,0 +1,98 @@
+use hyper::HeaderMap;
+use hyper::header::HeaderValue;
+use crate::config::CorrelationConfig;
+use crate::experiments::random;
+
+#[cfg(test)]
+mod tests;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+pub const TRACEPARENT_HEADER: &str = "traceparent";
+pub const TRACESTATE_HEADER: &str = "tracestate";
+pub const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Gives every request an `X-Request-Id` and a W3C `traceparent`. Valid ones
+/// sent by the client are kept when `trust_client_headers` is set; anything
+/// else is replaced by freshly generated ids.
+#[derive(Clone)]
+pub struct Correlation {
+    trust_client_headers: bool,
+}
+
+impl Correlation {
+    pub fn new(config: &CorrelationConfig) -> Self {
+        Self { trust_client_headers: config.trust_client_headers }
+    }
+
+    /// Sets both headers on the request and returns its request id. The
+    /// gateway is a hop in the trace, so a kept `traceparent` gets a new
+    /// parent id and only its trace id and flags carry over.
+    pub fn apply(&self, headers: &mut HeaderMap) -> String {
+        let client_id = headers
+            .get(REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .filter(|id| self.trust_client_headers && valid_request_id(id))
+            .map(str::to_string);
+        let request_id = client_id.unwrap_or_else(|| hex(&[random(), random()]));
+
+        let client_trace = headers
+            .get(TRACEPARENT_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .filter(|_| self.trust_client_headers)
+            .and_then(parse_traceparent);
+        let traceparent = match client_trace {
+            Some((trace_id, flags)) => format!("00-{}-{}-{}", trace_id, hex(&[random()]), flags),
+            None => {
+                headers.remove(TRACESTATE_HEADER);
+                format!("00-{}-{}-00", hex(&[random(), random()]), hex(&[random()]))
+            }
+        };
+
+        if let Ok(value) = HeaderValue::from_str(&request_id) {
+            headers.insert(REQUEST_ID_HEADER, value);
+        }
+        if let Ok(value) = HeaderValue::from_str(&traceparent) {
+            headers.insert(TRACEPARENT_HEADER, value);
+        }
+        request_id
+    }
+
+    /// The parent id of the client's `traceparent` if `apply` would keep
+    /// its trace, which makes it the parent of the gateway's span.
+    pub fn client_parent_id(&self, headers: &HeaderMap) -> Option<String> {
+        let value = headers.get(TRACEPARENT_HEADER).filter(|_| self.trust_client_headers)?.to_str().ok()?;
+        parse_traceparent_ids(value).map(|(_, parent_id, _)| parent_id.to_string())
+    }
+}
+
+/// Up to `MAX_REQUEST_ID_LEN` characters that are safe in logs and headers.
+pub fn valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LEN
+        && id.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_.:".contains(&b))
+}
+
+/// The trace id and flags of a version-00 `traceparent`, rejecting the
+/// all-zero ids the W3C spec forbids.
+pub fn parse_traceparent(value: &str) -> Option<(&str, &str)> {
+    parse_traceparent_ids(value).map(|(trace_id, _, flags)| (trace_id, flags))
+}
+
+/// The trace id, parent id and flags of a valid `traceparent`.
+pub fn parse_traceparent_ids(value: &str) -> Option<(&str, &str, &str)> {
+    let mut parts = value.trim().split('-');
+    let (version, trace_id, parent_id, flags) = (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+    let is_hex = |s: &str, len: usize| s.len() == len && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'));
+    let valid = parts.next().is_none()
+        && version == "00"
+        && is_hex(trace_id, 32)
+        && is_hex(parent_id, 16)
+        && is_hex(flags, 2)
+        && trace_id.bytes().any(|b| b != b'0')
+        && parent_id.bytes().any(|b| b != b'0');
+    valid.then_some((trace_id, parent_id, flags))
+}
+
+fn hex(words: &[u64]) -> String {
+    words.iter().map(|word| format!("{:016x}", word)).collect()
+}
@@ -0,0 +1,60 @@
+#[cfg(test)]
+mod tests {
+    use hyper::HeaderMap;
+    use crate::config::CorrelationConfig;
+    use crate::correlation::{parse_traceparent, valid_request_id, Correlation};
+
+    const TRACEPARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    fn client_headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-request-id", "req-123".parse().unwrap());
+        headers.insert("traceparent", TRACEPARENT.parse().unwrap());
+        headers.insert("tracestate", "vendor=abc".parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_trusted_client_ids_are_kept() {
+        let correlation = Correlation::new(&CorrelationConfig { trust_client_headers: true });
+        let mut headers = client_headers();
+        assert_eq!(correlation.apply(&mut headers), "req-123");
+        assert_eq!(headers["x-request-id"], "req-123");
+
+        let traceparent = headers["traceparent"].to_str().unwrap();
+        let (trace_id, flags) = parse_traceparent(traceparent).unwrap();
+        assert_eq!(trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(flags, "01");
+        assert_ne!(traceparent, TRACEPARENT, "the gateway adds its own parent id");
+        assert_eq!(headers["tracestate"], "vendor=abc");
+    }
+
+    #[test]
+    fn test_untrusted_or_invalid_ids_are_replaced() {
+        let correlation = Correlation::new(&CorrelationConfig { trust_client_headers: false });
+        let mut headers = client_headers();
+        let request_id = correlation.apply(&mut headers);
+        assert_ne!(request_id, "req-123");
+        assert_eq!(request_id.len(), 32);
+        assert!(!headers["traceparent"].to_str().unwrap().contains("4bf92f3577b34da6a3ce929d0e0e4736"));
+        assert!(!headers.contains_key("tracestate"));
+
+        let correlation = Correlation::new(&CorrelationConfig::default());
+        let mut headers = HeaderMap::new();
+        headers.insert("x-request-id", "has spaces".parse().unwrap());
+        headers.insert("traceparent", "00-00000000000000000000000000000000-00f067aa0ba902b7-01".parse().unwrap());
+        assert_ne!(correlation.apply(&mut headers), "has spaces");
+        assert!(parse_traceparent(headers["traceparent"].to_str().unwrap()).is_some());
+    }
+
+    #[test]
+    fn test_validation() {
+        assert!(valid_request_id("0f4c:worker-7.abc_1"));
+        assert!(!valid_request_id(""));
+        assert!(!valid_request_id(&"a".repeat(129)));
+        assert!(parse_traceparent(TRACEPARENT).is_some());
+        assert!(parse_traceparent("01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").is_none());
+        assert!(parse_traceparent("00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01").is_none());
+        assert!(parse_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01").is_none());
+    }
+}
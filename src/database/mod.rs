@@ -0,0 +1,158 @@
+use std::str::FromStr;
+use std::time::Duration;
+use hyper::Uri;
+use sqlx::postgres::{PgConnectOptions, PgPool, PgPoolOptions, PgSslMode};
+use sqlx::query::Query;
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions};
+use sqlx::{Encode, Row, Type};
+use crate::config::PersistenceConfig;
+
+#[cfg(test)]
+mod tests;
+
+const APPLICATION_NAME: &str = "api-gateway";
+
+/// Where the database is and how to log in to it.
+#[derive(Clone)]
+pub enum DatabaseAddress {
+    Postgres(PgConnectOptions),
+    Sqlite(SqliteConnectOptions),
+}
+
+impl std::fmt::Debug for DatabaseAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DatabaseAddress::Postgres(options) => f
+                .debug_struct("Postgres")
+                .field("host", &options.get_host())
+                .field("port", &options.get_port())
+                .field("database", &options.get_database())
+                .field("username", &options.get_username())
+                .finish_non_exhaustive(),
+            DatabaseAddress::Sqlite(options) => f.debug_struct("Sqlite").field("filename", &options.get_filename()).finish_non_exhaustive(),
+        }
+    }
+}
+
+impl DatabaseAddress {
+    /// Parses `postgres://host:port/database`, whose credentials come from
+    /// the config, never the URL, so they stay out of logs; or
+    /// `sqlite://path`, created if missing, or `sqlite::memory:`.
+    pub fn new(config: &PersistenceConfig) -> Result<Self, String> {
+        let url = config.url.as_str();
+        if url.starts_with("sqlite:") {
+            let options = SqliteConnectOptions::from_str(url).map_err(|e| format!("sqlite url \"{}\" is not valid: {}", url, e))?;
+            return Ok(DatabaseAddress::Sqlite(options.create_if_missing(true)));
+        }
+        let uri: Uri = url.parse().map_err(|e| format!("postgres url \"{}\" is not valid: {}", url, e))?;
+        if !matches!(uri.scheme_str(), Some("postgres" | "postgresql")) {
+            return Err(format!("database url \"{}\" must use the postgres or sqlite scheme", url));
+        }
+        let authority = uri.authority().ok_or_else(|| format!("postgres url \"{}\" has no host", url))?;
+        if authority.as_str().contains('@') {
+            return Err(format!("postgres url \"{}\" must not hold credentials, set username and password instead", url));
+        }
+        if uri.query().is_some() {
+            return Err(format!("postgres url \"{}\" must not have parameters", url));
+        }
+        if config.username.is_empty() {
+            return Err("postgres username must not be empty".to_string());
+        }
+        // The database defaults to the username, as with psql.
+        let database = match uri.path().trim_start_matches('/') {
+            "" => config.username.as_str(),
+            database => database,
+        };
+        let ssl_mode = if config.allow_plaintext { PgSslMode::Prefer } else { PgSslMode::VerifyFull };
+        let mut options = PgConnectOptions::new_without_pgpass()
+            .host(authority.host().trim_start_matches('[').trim_end_matches(']'))
+            .port(authority.port_u16().unwrap_or(5432))
+            .database(database)
+            .username(&config.username)
+            .application_name(APPLICATION_NAME)
+            .ssl_mode(ssl_mode);
+        if let Some(password) = &config.password {
+            options = options.password(password);
+        }
+        if let Some(ca_path) = &config.tls.ca_path {
+            options = options.ssl_root_cert(ca_path);
+        }
+        if let (Some(cert_path), Some(key_path)) = (&config.tls.cert_path, &config.tls.key_path) {
+            options = options.ssl_client_cert(cert_path).ssl_client_key(key_path);
+        }
+        Ok(DatabaseAddress::Postgres(options))
+    }
+}
+
+/// A value bound to a statement.
+#[derive(Debug, Clone, Copy)]
+pub enum Param<'a> {
+    Text(&'a str),
+    Int(i64),
+}
+
+/// A pool of connections to Postgres or SQLite. Connections are opened when
+/// first needed, and again after one fails.
+pub enum Database {
+    Postgres(PgPool),
+    Sqlite(SqlitePool),
+}
+
+impl Database {
+    /// Waits at most `timeout` for a connection. SQLite gets a single one,
+    /// so writers never contend for the file and an in-memory database
+    /// lives as long as the gateway.
+    pub fn connect(address: &DatabaseAddress, timeout: Duration) -> Self {
+        match address {
+            DatabaseAddress::Postgres(options) => {
+                Database::Postgres(PgPoolOptions::new().max_connections(2).acquire_timeout(timeout).connect_lazy_with(options.clone()))
+            }
+            DatabaseAddress::Sqlite(options) => Database::Sqlite(
+                SqlitePoolOptions::new()
+                    .max_connections(1)
+                    .acquire_timeout(timeout)
+                    .idle_timeout(None)
+                    .max_lifetime(None)
+                    .connect_lazy_with(options.clone()),
+            ),
+        }
+    }
+
+    pub async fn execute(&self, sql: &str, params: &[Param<'_>]) -> Result<(), sqlx::Error> {
+        match self {
+            Database::Postgres(pool) => bind(sqlx::query(sql), params).execute(pool).await.map(drop),
+            Database::Sqlite(pool) => bind(sqlx::query(sql), params).execute(pool).await.map(drop),
+        }
+    }
+
+    /// The rows of a query selecting two text columns.
+    pub async fn fetch_pairs(&self, sql: &str, params: &[Param<'_>]) -> Result<Vec<(String, String)>, sqlx::Error> {
+        match self {
+            Database::Postgres(pool) => {
+                let rows = bind(sqlx::query(sql), params).fetch_all(pool).await?;
+                rows.iter().map(|row| Ok((row.try_get(0)?, row.try_get(1)?))).collect()
+            }
+            Database::Sqlite(pool) => {
+                let rows = bind(sqlx::query(sql), params).fetch_all(pool).await?;
+                rows.iter().map(|row| Ok((row.try_get(0)?, row.try_get(1)?))).collect()
+            }
+        }
+    }
+}
+
+fn bind<'q, DB: sqlx::Database>(
+    mut query: Query<'q, DB, <DB as sqlx::Database>::Arguments<'q>>,
+    params: &[Param<'q>],
+) -> Query<'q, DB, <DB as sqlx::Database>::Arguments<'q>>
+where
+    &'q str: Encode<'q, DB> + Type<DB>,
+    i64: Encode<'q, DB> + Type<DB>,
+{
+    for param in params {
+        query = match *param {
+            Param::Text(text) => query.bind(text),
+            Param::Int(number) => query.bind(number),
+        };
+    }
+    query
+}
@@ -0,0 +1,53 @@
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use sqlx::postgres::PgSslMode;
+    use crate::config::PersistenceConfig;
+    use crate::database::{Database, DatabaseAddress, Param};
+
+    fn config(url: &str) -> PersistenceConfig {
+        PersistenceConfig { url: url.to_string(), ..PersistenceConfig::default() }
+    }
+
+    #[test]
+    fn test_addresses() {
+        let DatabaseAddress::Postgres(options) = DatabaseAddress::new(&config("postgresql://db.internal/state")).unwrap() else {
+            panic!("not a postgres address");
+        };
+        assert_eq!((options.get_host(), options.get_port(), options.get_database()), ("db.internal", 5432, Some("state")));
+        assert_eq!(options.get_username(), "gateway");
+        assert!(matches!(options.get_ssl_mode(), PgSslMode::VerifyFull));
+        // The database defaults to the username, as with psql.
+        let DatabaseAddress::Postgres(options) = DatabaseAddress::new(&config("postgres://db:6432")).unwrap() else {
+            panic!("not a postgres address");
+        };
+        assert_eq!(options.get_database(), Some("gateway"));
+        let plaintext = PersistenceConfig { allow_plaintext: true, ..config("postgres://db") };
+        let DatabaseAddress::Postgres(options) = DatabaseAddress::new(&plaintext).unwrap() else {
+            panic!("not a postgres address");
+        };
+        assert!(matches!(options.get_ssl_mode(), PgSslMode::Prefer));
+        assert!(matches!(DatabaseAddress::new(&config("sqlite:///var/lib/gateway/state.db")).unwrap(), DatabaseAddress::Sqlite(_)));
+
+        let error = |url: &str| DatabaseAddress::new(&config(url)).unwrap_err();
+        assert_eq!(error("mysql://db/state"), "database url \"mysql://db/state\" must use the postgres or sqlite scheme");
+        assert_eq!(
+            error("postgres://gateway:secret@db/state"),
+            "postgres url \"postgres://gateway:secret@db/state\" must not hold credentials, set username and password instead"
+        );
+        assert_eq!(error("postgres://db/state?sslmode=require"), "postgres url \"postgres://db/state?sslmode=require\" must not have parameters");
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_statements() {
+        let database = Database::connect(&DatabaseAddress::new(&config("sqlite::memory:")).unwrap(), Duration::from_secs(5));
+        database.execute("CREATE TABLE t (name text PRIMARY KEY, value text NOT NULL, n bigint NOT NULL)", &[]).await.unwrap();
+        for (name, n) in [("b", 2), ("a", 1)] {
+            database.execute("INSERT INTO t (name, value, n) VALUES ($1, $2, $3)", &[Param::Text(name), Param::Text("v"), Param::Int(n)]).await.unwrap();
+        }
+        let rows = database.fetch_pairs("SELECT name, value FROM t WHERE n > $1 ORDER BY name", &[Param::Int(0)]).await.unwrap();
+        assert_eq!(rows, [("a".to_string(), "v".to_string()), ("b".to_string(), "v".to_string())]);
+        let error = database.execute("INSERT INTO t (name, value, n) VALUES ($1, $2, $3)", &[Param::Text("a"), Param::Text("v"), Param::Int(3)]).await;
+        assert!(matches!(error, Err(sqlx::Error::Database(_))));
+    }
+}
@@ -0,0 +1,120 @@
+use bytes::Bytes;
+use hyper::{Body, HeaderMap, Method, Response, StatusCode, header::HeaderName};
+use serde_json::{json, Map, Value};
+use crate::config::DebugLogConfig;
+use crate::errors::GatewayError;
+use crate::experiments::random;
+use crate::redaction::Redactor;
+use crate::services::admin_token_index;
+
+#[cfg(test)]
+mod tests;
+
+/// Picks requests for detailed logging and writes one JSON line per request
+/// with its headers and, if configured, the start of both bodies.
+pub struct DebugLog {
+    sample_rate: f64,
+    header: Option<HeaderName>,
+    max_body_bytes: usize,
+    admin_tokens: Vec<String>,
+    redactor: Redactor,
+}
+
+impl DebugLog {
+    pub fn new(config: &DebugLogConfig, admin_tokens: &[String], redactor: Redactor) -> Self {
+        Self {
+            sample_rate: config.sample_rate,
+            header: HeaderName::from_bytes(config.header.as_bytes()).ok(),
+            max_body_bytes: config.max_body_bytes,
+            admin_tokens: admin_tokens.to_vec(),
+            redactor,
+        }
+    }
+
+    /// Decides whether to log this request and if so captures it. The
+    /// trigger header is removed either way so it never reaches the upstream.
+    pub fn capture(&self, method: &Method, path: &str, query: &str, headers: &mut HeaderMap, body: &Bytes) -> Option<Value> {
+        let triggered = self
+            .header
+            .as_ref()
+            .and_then(|header| headers.remove(header))
+            .and_then(|value| value.to_str().ok().map(|value| admin_token_index(&self.admin_tokens, value).is_some()))
+            .unwrap_or(false);
+        let sampled = self.sample_rate >= 1.0
+            || (self.sample_rate > 0.0 && (random() as f64 / u64::MAX as f64) < self.sample_rate);
+        if !triggered && !sampled {
+            return None;
+        }
+
+        let mut request = json!({
+            "method": method.as_str(),
+            "path": path,
+            "query": self.redactor.query(query),
+            "headers": self.headers(headers),
+        });
+        if self.max_body_bytes > 0 {
+            request["body"] = self.body(body);
+        }
+        Some(json!({ "trigger": if triggered { "header" } else { "sample" }, "request": request }))
+    }
+
+    /// Logs a captured request with its outcome and hands the response back,
+    /// its body buffered if it had to be read for the log.
+    pub async fn finish(
+        &self,
+        mut entry: Value,
+        result: Result<Response<Body>, warp::Rejection>,
+    ) -> Result<Response<Body>, warp::Rejection> {
+        let result = match result {
+            Ok(response) => {
+                let (parts, body) = response.into_parts();
+                let mut response = json!({
+                    "status": parts.status.as_u16(),
+                    "headers": self.headers(&parts.headers),
+                });
+                let body = if self.max_body_bytes > 0 {
+                    let bytes = hyper::body::to_bytes(body).await.unwrap_or_default();
+                    response["body"] = self.body(&bytes);
+                    Body::from(bytes)
+                } else {
+                    body
+                };
+                entry["response"] = response;
+                Ok(Response::from_parts(parts, body))
+            }
+            Err(rejection) => {
+                let error = rejection.find::<GatewayError>();
+                let status = match error {
+                    Some(error) => error.status(),
+                    None if rejection.is_not_found() => StatusCode::NOT_FOUND,
+                    None => StatusCode::INTERNAL_SERVER_ERROR,
+                };
+                entry["response"] = json!({
+                    "status": status.as_u16(),
+                    "error": error.map(|e| e.to_string()),
+                });
+                Err(rejection)
+            }
+        };
+        println!("debug {}", entry);
+        result
+    }
+
+    fn body(&self, body: &Bytes) -> Value {
+        Value::from(self.redactor.body(body, self.max_body_bytes))
+    }
+
+    fn headers(&self, headers: &HeaderMap) -> Value {
+        let mut map = Map::new();
+        for (name, value) in headers {
+            let value = Value::from(self.redactor.header(name.as_str(), &String::from_utf8_lossy(value.as_bytes())));
+            match map.get_mut(name.as_str()) {
+                Some(Value::Array(values)) => values.push(value),
+                _ => {
+                    map.insert(name.to_string(), Value::Array(vec![value]));
+                }
+            }
+        }
+        Value::Object(map)
+    }
+}
@@ -0,0 +1,69 @@
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use hyper::{Body, HeaderMap, Method, Response};
+    use crate::config::DebugLogConfig;
+    use crate::debug_log::DebugLog;
+    use crate::redaction::Redactor;
+    use crate::GatewayError;
+
+    fn debug_log(sample_rate: f64, max_body_bytes: usize) -> DebugLog {
+        let config = DebugLogConfig { sample_rate, max_body_bytes, ..DebugLogConfig::default() };
+        DebugLog::new(&config, &["admin-token".to_string()], Redactor::default())
+    }
+
+    fn headers(debug: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", "Bearer client-token".parse().unwrap());
+        headers.insert("accept", "application/json".parse().unwrap());
+        headers.insert("x-gateway-debug", debug.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_header_needs_admin_token() {
+        let log = debug_log(0.0, 0);
+        let body = Bytes::new();
+
+        let mut wrong = headers("guess");
+        assert!(log.capture(&Method::GET, "/api", "", &mut wrong, &body).is_none());
+        assert!(!wrong.contains_key("x-gateway-debug"));
+
+        let mut right = headers("admin-token");
+        let entry = log.capture(&Method::GET, "/api", "a=1", &mut right, &body).unwrap();
+        assert!(!right.contains_key("x-gateway-debug"));
+        assert_eq!(entry["trigger"], "header");
+        assert_eq!(entry["request"]["query"], "a=1");
+        assert_eq!(entry["request"]["headers"]["authorization"][0], "[redacted]");
+        assert_eq!(entry["request"]["headers"]["accept"][0], "application/json");
+        assert!(entry["request"].get("body").is_none());
+    }
+
+    #[test]
+    fn test_bodies_are_redacted_and_truncated() {
+        let log = debug_log(1.0, 40);
+        let body = Bytes::from(r#"{"user":"ann","password":"hunter2","note":"a fairly long note that goes past the limit"}"#);
+        let entry = log.capture(&Method::POST, "/login", "", &mut HeaderMap::new(), &body).unwrap();
+
+        assert_eq!(entry["trigger"], "sample");
+        let logged = entry["request"]["body"].as_str().unwrap();
+        assert!(!logged.contains("hunter2"), "{}", logged);
+        assert!(logged.ends_with(&format!("… ({} bytes)", body.len())), "{}", logged);
+
+        let binary = Bytes::from_static(&[0xff, 0xfe, 0x00]);
+        let entry = log.capture(&Method::POST, "/upload", "", &mut HeaderMap::new(), &binary).unwrap();
+        assert_eq!(entry["request"]["body"], "<3 bytes of binary data>");
+    }
+
+    #[tokio::test]
+    async fn test_finish_passes_outcome_through() {
+        let log = debug_log(1.0, 64);
+        let entry = log.capture(&Method::GET, "/api", "", &mut HeaderMap::new(), &Bytes::new()).unwrap();
+        let response = log.finish(entry, Ok(Response::new(Body::from("hello")))).await.unwrap();
+        assert_eq!(hyper::body::to_bytes(response.into_body()).await.unwrap(), "hello");
+
+        let entry = log.capture(&Method::GET, "/api", "", &mut HeaderMap::new(), &Bytes::new()).unwrap();
+        let rejection = log.finish(entry, Err(warp::reject::custom(GatewayError::Timeout))).await.unwrap_err();
+        assert!(matches!(rejection.find::<GatewayError>(), Some(GatewayError::Timeout)));
+    }
+}
@@ -0,0 +1,45 @@
+use std::io::Read;
+use bytes::Bytes;
+use flate2::read::MultiGzDecoder;
+use hyper::HeaderMap;
+use hyper::header::CONTENT_ENCODING;
+use crate::config::DecompressionConfig;
+use crate::errors::GatewayError;
+
+#[cfg(test)]
+mod tests;
+
+/// Inflates gzip request bodies for upstreams that only accept them
+/// uncompressed.
+#[derive(Debug, Clone)]
+pub struct Decompressor {
+    max_bytes: usize,
+}
+
+impl Decompressor {
+    pub fn new(config: &DecompressionConfig) -> Self {
+        Self { max_bytes: config.max_bytes }
+    }
+
+    /// The body inflated if its `Content-Encoding` is gzip, `None` if it
+    /// should be forwarded as it is. Other and stacked encodings are left
+    /// alone.
+    pub fn request(&self, headers: &HeaderMap, body: &[u8]) -> Result<Option<Bytes>, GatewayError> {
+        let gzip = headers
+            .get(CONTENT_ENCODING)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| matches!(value.trim().to_ascii_lowercase().as_str(), "gzip" | "x-gzip"));
+        if !gzip {
+            return Ok(None);
+        }
+        let mut inflated = Vec::new();
+        MultiGzDecoder::new(body)
+            .take(self.max_bytes as u64 + 1)
+            .read_to_end(&mut inflated)
+            .map_err(|e| GatewayError::BadRequest(format!("invalid gzip body: {}", e)))?;
+        if inflated.len() > self.max_bytes {
+            return Err(GatewayError::PayloadTooLarge(self.max_bytes));
+        }
+        Ok(Some(Bytes::from(inflated)))
+    }
+}
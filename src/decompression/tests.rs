@@ -0,0 +1,48 @@
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+    use hyper::HeaderMap;
+    use crate::GatewayError;
+    use crate::config::DecompressionConfig;
+    use crate::decompression::Decompressor;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn encoded(encoding: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("content-encoding", encoding.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_inflates_gzip_bodies() {
+        let decompressor = Decompressor::new(&DecompressionConfig::default());
+        let body = gzip(br#"{"id":1}"#);
+        assert_eq!(decompressor.request(&encoded("gzip"), &body).unwrap().unwrap(), &br#"{"id":1}"#[..]);
+        assert_eq!(decompressor.request(&encoded(" X-GZIP "), &body).unwrap().unwrap(), &br#"{"id":1}"#[..]);
+
+        assert!(decompressor.request(&HeaderMap::new(), &body).unwrap().is_none());
+        assert!(decompressor.request(&encoded("br"), &body).unwrap().is_none());
+        assert!(decompressor.request(&encoded("gzip, br"), &body).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_refuses_invalid_and_oversized_bodies() {
+        let decompressor = Decompressor::new(&DecompressionConfig { max_bytes: 1024 });
+        assert!(matches!(
+            decompressor.request(&encoded("gzip"), b"not gzip"),
+            Err(GatewayError::BadRequest(_))
+        ));
+        assert!(decompressor.request(&encoded("gzip"), &gzip(&[b'a'; 1024])).unwrap().is_some());
+        assert!(matches!(
+            decompressor.request(&encoded("gzip"), &gzip(&[b'a'; 1025])),
+            Err(GatewayError::PayloadTooLarge(1024))
+        ));
+    }
+}
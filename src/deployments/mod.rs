@@ -0,0 +1,100 @@
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+use serde_json::{json, Value};
+use crate::config::DeploymentConfig;
+use crate::errors::GatewayError;
+use crate::experiments::random;
+
+#[cfg(test)]
+mod tests;
+
+/// A route's blue/green upstream groups and which of them is live. Switching
+/// is atomic; with a ramp, the new group's share of requests grows linearly
+/// from nothing to all of them while the rest still go to the old group.
+#[derive(Debug)]
+pub struct Deployment {
+    groups: Vec<(String, String)>,
+    state: RwLock<State>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct State {
+    active: usize,
+    ramp: Option<Ramp>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Ramp {
+    from: usize,
+    started: Instant,
+    duration: Duration,
+}
+
+impl Ramp {
+    /// Share of traffic the new group gets, or `None` once the ramp is over.
+    fn progress(&self) -> Option<f64> {
+        let progress = self.started.elapsed().as_secs_f64() / self.duration.as_secs_f64();
+        (progress < 1.0).then_some(progress)
+    }
+}
+
+impl Deployment {
+    pub fn from_config(config: &DeploymentConfig) -> Result<Self, String> {
+        if config.groups.is_empty() {
+            return Err("at least one group is required".to_string());
+        }
+        let mut groups: Vec<_> = config
+            .groups
+            .iter()
+            .map(|(name, upstream)| (name.clone(), upstream.trim_end_matches('/').to_string()))
+            .collect();
+        groups.sort();
+        let active = groups
+            .iter()
+            .position(|(name, _)| *name == config.active)
+            .ok_or_else(|| format!("active group \"{}\" is not one of the groups", config.active))?;
+        Ok(Self {
+            groups,
+            state: RwLock::new(State { active, ramp: None }),
+        })
+    }
+
+    /// The upstream for one request.
+    pub fn upstream(&self) -> &str {
+        let state = *self.state.read().unwrap_or_else(|e| e.into_inner());
+        let group = match state.ramp.and_then(|ramp| Some((ramp.from, ramp.progress()?))) {
+            Some((from, progress)) if (random() as f64 / u64::MAX as f64) >= progress => from,
+            _ => state.active,
+        };
+        &self.groups[group].1
+    }
+
+    /// Makes `group` the live group, immediately or over `ramp`. Switching to
+    /// the group that is already active ends any ramp towards it at once.
+    pub fn switch(&self, group: &str, ramp: Duration) -> Result<(), GatewayError> {
+        let target = self
+            .groups
+            .iter()
+            .position(|(name, _)| name == group)
+            .ok_or_else(|| GatewayError::BadRequest(format!("unknown deployment group \"{}\"", group)))?;
+        let mut state = self.state.write().unwrap_or_else(|e| e.into_inner());
+        let ramp = (target != state.active && !ramp.is_zero()).then(|| Ramp {
+            from: state.active,
+            started: Instant::now(),
+            duration: ramp,
+        });
+        *state = State { active: target, ramp };
+        Ok(())
+    }
+
+    pub fn status(&self) -> Value {
+        let state = *self.state.read().unwrap_or_else(|e| e.into_inner());
+        let ramp = state.ramp.and_then(|ramp| Some((ramp.from, ramp.progress()?)));
+        json!({
+            "active": self.groups[state.active].0,
+            "ramping_from": ramp.map(|(from, _)| self.groups[from].0.clone()),
+            "ramp_progress": ramp.map_or(1.0, |(_, progress)| progress),
+            "groups": self.groups.iter().map(|(name, upstream)| (name.clone(), json!(upstream))).collect::<serde_json::Map<_, _>>(),
+        })
+    }
+}
@@ -0,0 +1,57 @@
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use crate::config::DeploymentConfig;
+    use crate::deployments::Deployment;
+    use crate::errors::GatewayError;
+
+    fn deployment() -> Deployment {
+        let config: DeploymentConfig = serde_json::from_str(r#"{
+            "groups": { "blue": "http://orders-blue:8080/", "green": "http://orders-green:8080" },
+            "active": "blue"
+        }"#).unwrap();
+        Deployment::from_config(&config).unwrap()
+    }
+
+    #[test]
+    fn test_switch_is_immediate_without_ramp() {
+        let deployment = deployment();
+        assert_eq!(deployment.upstream(), "http://orders-blue:8080");
+
+        deployment.switch("green", Duration::ZERO).unwrap();
+        assert!((0..100).all(|_| deployment.upstream() == "http://orders-green:8080"));
+        assert_eq!(deployment.status()["active"], "green");
+        assert!(deployment.status()["ramping_from"].is_null());
+
+        assert!(matches!(deployment.switch("purple", Duration::ZERO), Err(GatewayError::BadRequest(_))));
+    }
+
+    #[test]
+    fn test_ramp_splits_traffic_until_rolled_back() {
+        let deployment = deployment();
+        deployment.switch("green", Duration::from_secs(3600)).unwrap();
+        let status = deployment.status();
+        assert_eq!(status["active"], "green");
+        assert_eq!(status["ramping_from"], "blue");
+        assert!(status["ramp_progress"].as_f64().unwrap() < 0.01);
+        let green = (0..1000).filter(|_| deployment.upstream().contains("green")).count();
+        assert!(green < 50, "a ramp starts with almost no traffic, got {}", green);
+
+        // Rolling back is instant.
+        deployment.switch("blue", Duration::from_secs(3600)).unwrap();
+        let status = deployment.status();
+        assert_eq!(status["active"], "blue");
+        assert_eq!(status["ramping_from"], "green");
+        deployment.switch("blue", Duration::ZERO).unwrap();
+        assert!(deployment.status()["ramping_from"].is_null());
+        assert!((0..100).all(|_| deployment.upstream() == "http://orders-blue:8080"));
+    }
+
+    #[test]
+    fn test_active_must_be_a_group() {
+        let config: DeploymentConfig = serde_json::from_str(r#"{
+            "groups": { "blue": "http://orders-blue:8080" }, "active": "green"
+        }"#).unwrap();
+        assert!(Deployment::from_config(&config).is_err());
+    }
+}
@@ -0,0 +1,152 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, UNIX_EPOCH};
+use serde_json::{json, Value};
+use tokio::net::lookup_host;
+use tokio::time::timeout;
+use crate::config::{parse_upstream, ConfigDiagnostic, GatewayConfig, Severity, UPSTREAM_PROBE_TIMEOUT_SECS};
+use crate::redaction::Redactor;
+use crate::routes::{RouteAction, RouteTable};
+use crate::tls::CertResolver;
+
+#[cfg(test)]
+mod tests;
+
+/// Admin tokens shorter than this are reported as guessable.
+const MIN_ADMIN_TOKEN_LEN: usize = 16;
+const DAY_SECS: u64 = 86400;
+
+/// What the gateway started with, for the startup log and
+/// `/admin/diagnostics`: the configuration `view` shows, the routes loaded,
+/// what each upstream host resolves to, when the served certificates
+/// expire, and settings worth a second look.
+pub async fn report(
+    config: &GatewayConfig,
+    view: Value,
+    route_table: &RouteTable,
+    resolver: Option<&CertResolver>,
+    now: u64,
+) -> Value {
+    let redactor = Redactor::new(&config.redaction);
+    let routes: Vec<Value> = route_table
+        .routes()
+        .iter()
+        .map(|route| {
+            let (action, upstream) = match &route.action {
+                RouteAction::Proxy { upstream } => ("proxy", Some(upstream.as_str())),
+                RouteAction::Grpc(grpc) => ("grpc", Some(grpc.upstream())),
+                RouteAction::Redirect { .. } => ("redirect", None),
+                RouteAction::StaticFiles(_) => ("static_files", None),
+                RouteAction::Respond { .. } => ("respond", None),
+            };
+            json!({
+                "name": route.name,
+                "path_prefix": route.path_prefix,
+                "action": action,
+                "upstream": upstream.map(|upstream| redactor.uri(upstream)),
+            })
+        })
+        .collect();
+    let warnings: Vec<Value> = config
+        .validate()
+        .into_iter()
+        .filter(|diagnostic| diagnostic.severity == Severity::Warning)
+        .chain(suspicious_settings(config))
+        .map(|diagnostic| json!({ "location": diagnostic.location, "message": diagnostic.message }))
+        .collect();
+    json!({
+        "generated_at": now,
+        "version": env!("CARGO_PKG_VERSION"),
+        "config": view,
+        "routes": routes,
+        "upstreams": resolve_upstreams(config).await,
+        "certificates": resolver.map(|resolver| certificates(resolver, now)).unwrap_or_default(),
+        "warnings": warnings,
+    })
+}
+
+/// Looks up every distinct upstream host, with the routes that use it. A
+/// host that doesn't resolve is reported rather than failing startup, as
+/// with `--check-upstreams`.
+pub async fn resolve_upstreams(config: &GatewayConfig) -> Vec<Value> {
+    let mut addresses: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for (i, route) in config.routes.iter().enumerate() {
+        let name = route.name.clone().unwrap_or_else(|| format!("routes[{}]", i));
+        for upstream in route.upstreams() {
+            if let Ok(address) = parse_upstream(upstream) {
+                let routes = addresses.entry(address).or_default();
+                if !routes.contains(&name) {
+                    routes.push(name.clone());
+                }
+            }
+        }
+    }
+    let lookups = addresses.into_iter().map(|(address, routes)| async move {
+        let resolved = match timeout(Duration::from_secs(UPSTREAM_PROBE_TIMEOUT_SECS), lookup_host(address.as_str())).await {
+            Ok(Ok(resolved)) => Ok(resolved.map(|addr| addr.ip().to_string()).collect::<Vec<_>>()),
+            Ok(Err(e)) => Err(e.to_string()),
+            Err(_) => Err(format!("no answer within {}s", UPSTREAM_PROBE_TIMEOUT_SECS)),
+        };
+        match resolved {
+            Ok(resolved) => json!({ "address": address, "routes": routes, "resolved": resolved }),
+            Err(error) => json!({ "address": address, "routes": routes, "error": error }),
+        }
+    });
+    futures::future::join_all(lookups).await
+}
+
+/// Each served certificate's expiry, soonest first.
+pub fn certificates(resolver: &CertResolver, now: u64) -> Vec<Value> {
+    let mut expiries = resolver.expiries();
+    expiries.sort_by_key(|(name, expires)| (*expires, name.clone()));
+    expiries
+        .into_iter()
+        .map(|(name, expires)| {
+            json!({
+                "name": name,
+                "expires_at": expires,
+                "expires": httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(expires)),
+                "days_left": (expires as i64 - now as i64).div_euclid(DAY_SECS as i64),
+            })
+        })
+        .collect()
+}
+
+/// Settings that are valid but often a mistake. Unlike `validate`'s
+/// warnings, these depend on how the gateway is deployed, so they are only
+/// reported, never printed on their own.
+pub fn suspicious_settings(config: &GatewayConfig) -> Vec<ConfigDiagnostic> {
+    let mut diagnostics = Vec::new();
+    if let Some(oidc) = &config.auth.oidc {
+        diagnostics.push(ConfigDiagnostic::warning(
+            "cors",
+            "responses allow any origin, and browsers don't send credentials to a wildcard origin, so pages on other origins can't use the auth.oidc session",
+        ));
+        if !oidc.session.secure {
+            diagnostics.push(ConfigDiagnostic::warning(
+                "auth.oidc.session.secure",
+                "session cookies are sent over plain HTTP as well",
+            ));
+        }
+    }
+    if config.admin.enabled() && config.tls.is_none() {
+        diagnostics.push(ConfigDiagnostic::warning(
+            "admin.tokens",
+            "admin tokens travel in the clear unless TLS is terminated in front of the gateway",
+        ));
+    }
+    for (i, token) in config.admin.tokens.iter().enumerate() {
+        if token.len() < MIN_ADMIN_TOKEN_LEN {
+            diagnostics.push(ConfigDiagnostic::warning(
+                format!("admin.tokens[{}]", i),
+                format!("shorter than {} characters", MIN_ADMIN_TOKEN_LEN),
+            ));
+        }
+    }
+    if config.debug_log.sample_rate > 0.0 && config.debug_log.max_body_bytes > 0 {
+        diagnostics.push(ConfigDiagnostic::warning(
+            "debug_log",
+            "bodies of sampled requests are logged, with only the redaction fields masked",
+        ));
+    }
+    diagnostics
+}
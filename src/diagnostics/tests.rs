@@ -0,0 +1,81 @@
+#[cfg(test)]
+mod tests {
+    use ring::rand::SystemRandom;
+    use serde_json::{json, Value};
+    use crate::acme::{not_after, pem, self_signed};
+    use crate::config::GatewayConfig;
+    use crate::diagnostics::{certificates, report, suspicious_settings};
+    use crate::routes::RouteTable;
+    use crate::tls::{certified_key, CertResolver};
+
+    #[tokio::test]
+    async fn test_report() {
+        let config = GatewayConfig::from_json(r#"{
+            "routes": [
+                { "name": "orders", "path_prefix": "/orders", "upstream": "http://127.0.0.1:8080?token=abc" },
+                { "name": "orders-v2", "path_prefix": "/v2/orders", "upstream": "http://127.0.0.1:8080" },
+                { "name": "legacy", "path_prefix": "/legacy", "upstream": "http://legacy.invalid" },
+                { "name": "gone", "path_prefix": "/v1", "respond": { "status": 410 } }
+            ],
+            "redaction": { "fields": ["token"] },
+            "cors": { "max_age_secs": 86400 }
+        }"#).unwrap();
+        let route_table = RouteTable::from_config(&config).unwrap();
+        let report = report(&config, json!({ "config": {} }), &route_table, None, 1_700_000_000).await;
+
+        assert_eq!((&report["generated_at"], &report["config"]), (&json!(1_700_000_000), &json!({ "config": {} })));
+        assert_eq!(report["routes"][0], json!({
+            "name": "orders", "path_prefix": "/orders", "action": "proxy", "upstream": "http://127.0.0.1:8080?token=[redacted]",
+        }));
+        assert_eq!((&report["routes"][3]["action"], &report["routes"][3]["upstream"]), (&json!("respond"), &Value::Null));
+
+        let upstreams = report["upstreams"].as_array().unwrap();
+        assert_eq!(upstreams[0], json!({ "address": "127.0.0.1:8080", "routes": ["orders", "orders-v2"], "resolved": ["127.0.0.1"] }));
+        assert_eq!(upstreams[1]["address"], "legacy.invalid:80");
+        assert!(upstreams[1]["error"].is_string());
+
+        assert_eq!(report["certificates"], json!([]));
+        assert_eq!(report["warnings"], json!([{
+            "location": "cors.max_age_secs",
+            "message": "some browsers cache preflights for at most 7200 seconds",
+        }]));
+    }
+
+    #[test]
+    fn test_certificates_soonest_first() {
+        let random = SystemRandom::new();
+        let key = |days| {
+            let (cert, key) = self_signed("api.example.com", Vec::new(), days, &random).unwrap();
+            (not_after(&cert).unwrap(), certified_key(pem("CERTIFICATE", &cert).as_bytes(), pem("PRIVATE KEY", &key).as_bytes()).unwrap())
+        };
+        let (later, default) = key(90);
+        let (sooner, named) = key(5);
+        let resolver = CertResolver::new(Some(default));
+        resolver.insert("api.example.com", named);
+
+        let now = sooner - 3 * 86400 - 60;
+        let reported = certificates(&resolver, now);
+        assert_eq!((&reported[0]["name"], &reported[0]["days_left"]), (&json!("api.example.com"), &json!(3)));
+        assert_eq!((&reported[1]["name"], &reported[1]["expires_at"]), (&json!("default"), &json!(later)));
+        assert_eq!(certificates(&resolver, sooner + 1)[0]["days_left"], -1);
+    }
+
+    #[test]
+    fn test_suspicious_settings() {
+        let config = GatewayConfig::from_json(r#"{
+            "admin": { "tokens": ["short", "a-long-enough-admin-token"] },
+            "auth": { "oidc": {
+                "issuer": "https://login.example.com",
+                "client_id": "gateway",
+                "client_secret": "secret",
+                "redirect_uri": "https://api.example.com/callback",
+                "session": { "secret": "session-secret-session-secret-32", "secure": false }
+            } },
+            "debug_log": { "sample_rate": 0.1, "max_body_bytes": 1024 }
+        }"#).unwrap();
+        let locations: Vec<String> = suspicious_settings(&config).into_iter().map(|d| d.location).collect();
+        assert_eq!(locations, ["cors", "auth.oidc.session.secure", "admin.tokens", "admin.tokens[0]", "debug_log"]);
+
+        assert!(suspicious_settings(&GatewayConfig::default()).is_empty());
+    }
+}
@@ -0,0 +1,180 @@
+use std::fmt::Write;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use dashmap::DashMap;
+use futures::future::{BoxFuture, FutureExt, Shared};
+use hickory_resolver::config::{LookupIpStrategy, NameServerConfig, ResolveHosts, ResolverConfig, ResolverOpts};
+use hickory_resolver::name_server::TokioConnectionProvider;
+use hickory_resolver::proto::xfer::Protocol;
+use hickory_resolver::{system_conf, TokioResolver};
+use tokio::net::lookup_host;
+use crate::config::DnsCacheConfig;
+
+#[cfg(test)]
+mod tests;
+
+pub const DNS_LOOKUPS: &str = "gateway_dns_cache_lookups_total";
+pub const DNS_ENTRIES: &str = "gateway_dns_cache_entries";
+pub const DNS_PORT: u16 = 53;
+
+/// A nameserver given as an IP address, with or without a port.
+pub fn nameserver(value: &str) -> Result<SocketAddr, String> {
+    match value.parse::<IpAddr>() {
+        Ok(ip) => Ok(SocketAddr::new(ip, DNS_PORT)),
+        Err(_) => value
+            .parse::<SocketAddr>()
+            .map_err(|_| format!("\"{}\" is not an IP address, with or without a port", value)),
+    }
+}
+
+/// Whether `name` can be asked of a nameserver as it is: names without a
+/// dot are left to the system resolver and its search domains.
+fn is_qualified(name: &str) -> bool {
+    let name = name.strip_suffix('.').unwrap_or(name);
+    name.contains('.') && name.len() <= 253 && name.split('.').all(|label| (1..=63).contains(&label.len()))
+}
+
+/// A name's addresses and, when nameservers gave them, how long their
+/// records hold; or why it has none.
+type Lookup = Result<(Vec<IpAddr>, Option<Duration>), String>;
+
+struct Entry {
+    addresses: Result<Vec<IpAddr>, String>,
+    expires: Instant,
+}
+
+/// Upstream host names' addresses, kept for as long as their records say
+/// within the configured bounds, so new connections don't wait on a lookup.
+/// Names that don't resolve are kept too, briefly, so a missing upstream
+/// doesn't cost a lookup per request.
+pub struct DnsCache {
+    resolver: TokioResolver,
+    min_ttl: Duration,
+    max_ttl: Duration,
+    negative_ttl: Duration,
+    entries: DashMap<String, Entry>,
+    /// Lookups under way, awaited by every request for the same name.
+    pending: DashMap<String, Shared<BoxFuture<'static, Lookup>>>,
+    hits: AtomicU64,
+    negative_hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl DnsCache {
+    pub fn new(config: &DnsCacheConfig) -> Self {
+        let (resolver_config, mut options) = if config.nameservers.is_empty() {
+            // Without /etc/resolv.conf every name goes to the system resolver.
+            system_conf::read_system_conf().unwrap_or_else(|_| (ResolverConfig::new(), ResolverOpts::default()))
+        } else {
+            let mut resolver_config = ResolverConfig::new();
+            for address in config.nameservers.iter().filter_map(|address| nameserver(address).ok()) {
+                // TCP carries the answers too long for UDP.
+                resolver_config.add_name_server(NameServerConfig::new(address, Protocol::Udp));
+                resolver_config.add_name_server(NameServerConfig::new(address, Protocol::Tcp));
+            }
+            (resolver_config, ResolverOpts::default())
+        };
+        options.timeout = Duration::from_millis(config.timeout_ms);
+        options.edns0 = true;
+        options.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+        options.use_hosts_file = ResolveHosts::Never;
+        // The entries here are the cache.
+        options.cache_size = 0;
+        Self {
+            resolver: TokioResolver::builder_with_config(resolver_config, TokioConnectionProvider::default())
+                .with_options(options)
+                .build(),
+            min_ttl: Duration::from_secs(config.min_ttl_secs),
+            max_ttl: Duration::from_secs(config.max_ttl_secs),
+            negative_ttl: Duration::from_secs(config.negative_ttl_secs),
+            entries: DashMap::new(),
+            pending: DashMap::new(),
+            hits: AtomicU64::new(0),
+            negative_hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// `name`'s addresses, looked up only when the cache has none that are
+    /// still fresh. Requests that miss while a lookup is under way wait for
+    /// it instead of starting another.
+    pub async fn resolve(&self, name: &str) -> io::Result<Vec<IpAddr>> {
+        let cached = self.entries.get(name).filter(|entry| entry.expires > Instant::now()).map(|entry| entry.addresses.clone());
+        let addresses = match cached {
+            Some(Ok(addresses)) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Ok(addresses)
+            }
+            Some(Err(message)) => {
+                self.negative_hits.fetch_add(1, Ordering::Relaxed);
+                Err(message)
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                let lookup = self.pending.entry(name.to_string()).or_insert_with(|| self.look_up(name).boxed().shared()).clone();
+                let found = lookup.clone().await;
+                let ttl = match &found {
+                    Ok((_, Some(ttl))) => self.ttl(*ttl),
+                    // The system resolver doesn't tell the TTL.
+                    Ok((_, None)) => self.min_ttl,
+                    Err(_) => self.negative_ttl,
+                };
+                let addresses = found.map(|(addresses, _)| addresses);
+                let entry = Entry { addresses: addresses.clone(), expires: Instant::now() + ttl };
+                self.entries.insert(name.to_string(), entry);
+                self.pending.remove_if(name, |_, pending| pending.ptr_eq(&lookup));
+                addresses
+            }
+        };
+        addresses.map_err(|message| io::Error::new(io::ErrorKind::NotFound, message))
+    }
+
+    /// Drops every entry, returning how many there were.
+    pub fn flush(&self) -> usize {
+        let flushed = self.entries.len();
+        self.entries.clear();
+        flushed
+    }
+
+    /// How long to keep addresses whose records hold for `ttl`.
+    fn ttl(&self, ttl: Duration) -> Duration {
+        ttl.clamp(self.min_ttl, self.max_ttl)
+    }
+
+    /// Asks the nameservers for `name`'s A and AAAA records, then the system
+    /// resolver if they have none.
+    fn look_up(&self, name: &str) -> impl Future<Output = Lookup> + Send + 'static {
+        let resolver = self.resolver.clone();
+        let name = name.to_string();
+        async move {
+            if is_qualified(&name) {
+                // Fully qualified, so no search domain is tried first.
+                if let Ok(found) = resolver.lookup_ip(format!("{}.", name.trim_end_matches('.'))).await {
+                    let ttl = found.valid_until().saturating_duration_since(Instant::now());
+                    return Ok((found.iter().collect(), Some(ttl)));
+                }
+            }
+            match lookup_host((name.as_str(), 0)).await {
+                Ok(found) => Ok((found.map(|address| address.ip()).collect(), None)),
+                Err(e) => Err(format!("{}: {}", name, e)),
+            }
+        }
+    }
+
+    /// The Prometheus text exposition of the cache's lookups and size.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "# HELP {} Upstream host name lookups, by whether the cache answered.", DNS_LOOKUPS);
+        let _ = writeln!(out, "# TYPE {} counter", DNS_LOOKUPS);
+        let _ = writeln!(out, "{}{{result=\"hit\"}} {}", DNS_LOOKUPS, self.hits.load(Ordering::Relaxed));
+        let _ = writeln!(out, "{}{{result=\"negative_hit\"}} {}", DNS_LOOKUPS, self.negative_hits.load(Ordering::Relaxed));
+        let _ = writeln!(out, "{}{{result=\"miss\"}} {}", DNS_LOOKUPS, self.misses.load(Ordering::Relaxed));
+        let _ = writeln!(out, "# HELP {} Host names in the DNS cache, resolved or not.", DNS_ENTRIES);
+        let _ = writeln!(out, "# TYPE {} gauge", DNS_ENTRIES);
+        let _ = writeln!(out, "{} {}", DNS_ENTRIES, self.entries.len());
+        out
+    }
+}
@@ -0,0 +1,185 @@
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use hickory_resolver::proto::op::{Message, MessageType, Query, ResponseCode};
+    use hickory_resolver::proto::rr::rdata::A;
+    use hickory_resolver::proto::rr::{Name, RData, Record, RecordType};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, UdpSocket};
+    use crate::config::DnsCacheConfig;
+    use crate::dns::{nameserver, DnsCache};
+
+    /// The response to `request` for `query`, holding one A record per
+    /// address.
+    fn response(request: &Message, query: &Query, code: ResponseCode, addresses: &[[u8; 4]], ttl: u32) -> Vec<u8> {
+        let mut response = Message::new();
+        response
+            .set_id(request.id())
+            .set_message_type(MessageType::Response)
+            .set_recursion_desired(true)
+            .set_recursion_available(true)
+            .set_response_code(code)
+            .add_query(query.clone());
+        for address in addresses {
+            response.add_answer(Record::from_rdata(query.name().clone(), ttl, RData::A(A(Ipv4Addr::from(*address)))));
+        }
+        response.to_vec().unwrap()
+    }
+
+    /// The answer of a nameserver knowing `orders.internal` at 10.0.0.7 with
+    /// `ttl` and no other name, counting the A queries it answers.
+    fn answer(request: &[u8], ttl: u32, queries: &AtomicUsize) -> Vec<u8> {
+        let request = Message::from_vec(request).unwrap();
+        let query = &request.queries()[0];
+        let known = query.name() == &Name::from_ascii("orders.internal.").unwrap();
+        match (known, query.query_type()) {
+            (true, RecordType::A) => {
+                queries.fetch_add(1, Ordering::SeqCst);
+                response(&request, query, ResponseCode::NoError, &[[10, 0, 0, 7]], ttl)
+            }
+            (true, _) => response(&request, query, ResponseCode::NoError, &[], 0),
+            (false, _) => response(&request, query, ResponseCode::NXDomain, &[], 0),
+        }
+    }
+
+    async fn nameserver_with(ttl: u32, queries: Arc<AtomicUsize>) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut request = [0; 4096];
+            loop {
+                let (len, from) = socket.recv_from(&mut request).await.unwrap();
+                socket.send_to(&answer(&request[..len], ttl, &queries), from).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    fn dns_cache(nameserver: SocketAddr, min_ttl_secs: u64) -> DnsCache {
+        DnsCache::new(&DnsCacheConfig {
+            nameservers: vec![nameserver.to_string()],
+            min_ttl_secs,
+            max_ttl_secs: 300,
+            negative_ttl_secs: 60,
+            timeout_ms: 1000,
+        })
+    }
+
+    #[test]
+    fn test_nameservers() {
+        assert_eq!(nameserver("10.0.0.2"), Ok("10.0.0.2:53".parse().unwrap()));
+        assert_eq!(nameserver("[2001:db8::1]:5353"), Ok("[2001:db8::1]:5353".parse().unwrap()));
+        assert!(nameserver("dns.internal").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_addresses_are_kept_for_their_ttl() {
+        let queries = Arc::new(AtomicUsize::new(0));
+        let cache = dns_cache(nameserver_with(300, queries.clone()).await, 0);
+        for _ in 0..3 {
+            assert_eq!(cache.resolve("orders.internal").await.unwrap(), [IpAddr::from([10, 0, 0, 7])]);
+        }
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+        assert!(cache.render().contains("gateway_dns_cache_lookups_total{result=\"hit\"} 2\n"));
+
+        assert_eq!(cache.flush(), 1);
+        cache.resolve("orders.internal").await.unwrap();
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_ttls_are_clamped() {
+        let queries = Arc::new(AtomicUsize::new(0));
+        let cache = dns_cache(nameserver_with(0, queries.clone()).await, 0);
+        cache.resolve("orders.internal").await.unwrap();
+        cache.resolve("orders.internal").await.unwrap();
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+
+        let cache = dns_cache(nameserver_with(0, queries.clone()).await, 30);
+        cache.resolve("orders.internal").await.unwrap();
+        cache.resolve("orders.internal").await.unwrap();
+        assert_eq!(queries.load(Ordering::SeqCst), 3);
+        assert_eq!(cache.ttl(Duration::from_secs(86_400)), Duration::from_secs(300));
+    }
+
+    #[tokio::test]
+    async fn test_names_that_do_not_resolve_are_remembered() {
+        let cache = dns_cache(nameserver_with(300, Arc::default()).await, 0);
+        // Unknown to the nameserver, and to the system resolver, as no
+        // `.invalid` name resolves.
+        assert!(cache.resolve("missing.invalid").await.is_err());
+        assert!(cache.resolve("missing.invalid").await.is_err());
+        let metrics = cache.render();
+        assert!(metrics.contains("gateway_dns_cache_lookups_total{result=\"negative_hit\"} 1\n"));
+        assert!(metrics.contains("gateway_dns_cache_lookups_total{result=\"miss\"} 1\n"));
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_misses_share_one_lookup() {
+        let queries = Arc::new(AtomicUsize::new(0));
+        let cache = dns_cache(nameserver_with(300, queries.clone()).await, 0);
+        let lookups = futures::future::join_all((0..8).map(|_| cache.resolve("orders.internal"))).await;
+        assert!(lookups.iter().all(|addresses| addresses.as_ref().unwrap() == &[IpAddr::from([10, 0, 0, 7])]));
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_truncated_answers_are_asked_again_over_tcp() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let listener = TcpListener::bind(addr).await.unwrap();
+        tokio::spawn(async move {
+            let mut request = [0; 4096];
+            loop {
+                let (len, from) = socket.recv_from(&mut request).await.unwrap();
+                let request = Message::from_vec(&request[..len]).unwrap();
+                let mut truncated = Message::from_vec(&response(&request, &request.queries()[0], ResponseCode::NoError, &[], 0)).unwrap();
+                truncated.set_truncated(true);
+                socket.send_to(&truncated.to_vec().unwrap(), from).await.unwrap();
+            }
+        });
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let queries = AtomicUsize::new(0);
+                    while let Ok(len) = stream.read_u16().await {
+                        let mut request = vec![0; usize::from(len)];
+                        stream.read_exact(&mut request).await.unwrap();
+                        let answer = answer(&request, 300, &queries);
+                        stream.write_u16(answer.len() as u16).await.unwrap();
+                        stream.write_all(&answer).await.unwrap();
+                    }
+                });
+            }
+        });
+
+        let cache = dns_cache(addr, 0);
+        assert_eq!(cache.resolve("orders.internal").await.unwrap(), [IpAddr::from([10, 0, 0, 7])]);
+    }
+
+    #[tokio::test]
+    async fn test_answers_to_another_question_are_ignored() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut request = [0; 4096];
+            let queries = AtomicUsize::new(0);
+            loop {
+                let (len, from) = socket.recv_from(&mut request).await.unwrap();
+                // The right id, but for a name nobody asked about.
+                let parsed = Message::from_vec(&request[..len]).unwrap();
+                let mut forged = parsed.queries()[0].clone();
+                forged.set_name(Name::from_ascii("payments.internal.").unwrap());
+                socket.send_to(&response(&parsed, &forged, ResponseCode::NoError, &[[10, 6, 6, 6]], 300), from).await.unwrap();
+                socket.send_to(&answer(&request[..len], 300, &queries), from).await.unwrap();
+            }
+        });
+
+        let cache = dns_cache(addr, 0);
+        assert_eq!(cache.resolve("orders.internal").await.unwrap(), [IpAddr::from([10, 0, 0, 7])]);
+    }
+}
@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use hyper::Uri;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use crate::config::{ProxyConfig, UpstreamConnectConfig};
+
+#[cfg(test)]
+mod tests;
+
+/// Given as a proxy URL to send an upstream direct.
+pub const DIRECT: &str = "direct";
+/// Longest CONNECT response head read from an HTTP proxy.
+const MAX_CONNECT_RESPONSE: usize = 8192;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyKind {
+    /// An HTTP proxy, tunnelled through with `CONNECT`.
+    Http,
+    /// SOCKS5 with upstream names resolved by the gateway.
+    Socks5,
+    /// SOCKS5 with upstream names resolved by the proxy.
+    Socks5h,
+}
+
+/// An egress proxy upstream connections are tunnelled through.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Proxy {
+    pub kind: ProxyKind,
+    /// `http://host:port`, for connecting to the proxy itself.
+    pub uri: Uri,
+    credentials: Option<(String, String)>,
+}
+
+impl std::fmt::Debug for Proxy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Proxy").field("kind", &self.kind).field("uri", &self.uri).finish_non_exhaustive()
+    }
+}
+
+impl Proxy {
+    /// The proxy at `config.url`, such as `socks5h://egress:1080`. `None`
+    /// for `direct`.
+    pub fn new(config: &ProxyConfig) -> Result<Option<Self>, String> {
+        let url = config.url.as_str();
+        if url == DIRECT {
+            return Ok(None);
+        }
+        let uri: Uri = url.parse().map_err(|e| format!("proxy \"{}\" is not a valid URL: {}", url, e))?;
+        let (kind, default_port) = match uri.scheme_str() {
+            Some("http") => (ProxyKind::Http, 80),
+            Some("socks5") => (ProxyKind::Socks5, 1080),
+            Some("socks5h") => (ProxyKind::Socks5h, 1080),
+            _ => return Err(format!("proxy \"{}\" must use the http, socks5 or socks5h scheme", url)),
+        };
+        let authority = uri.authority().ok_or_else(|| format!("proxy \"{}\" has no host", url))?;
+        if authority.as_str().contains('@') {
+            return Err(format!("proxy \"{}\" must not hold credentials, set username and password instead", url));
+        }
+        if !matches!(uri.path(), "" | "/") || uri.query().is_some() {
+            return Err(format!("proxy \"{}\" must not have a path", url));
+        }
+        let credentials = match (&config.username, &config.password) {
+            (Some(user), password) => Some((user.clone(), password.clone().unwrap_or_default())),
+            (None, Some(_)) => return Err("proxy password is set without a username".to_string()),
+            (None, None) => None,
+        };
+        if kind != ProxyKind::Http && credentials.as_ref().is_some_and(|(user, password)| user.len() > 255 || password.len() > 255) {
+            return Err("SOCKS5 username and password must be at most 255 bytes".to_string());
+        }
+        let uri = format!("http://{}:{}", authority.host(), authority.port_u16().unwrap_or(default_port))
+            .parse()
+            .map_err(|e| format!("proxy \"{}\" is not a valid URL: {}", url, e))?;
+        Ok(Some(Self { kind, uri, credentials }))
+    }
+
+    /// Asks the proxy, already connected on `stream`, for a tunnel to
+    /// `host:port`. `resolved` is the address to ask for instead of `host`
+    /// when the gateway resolves names itself.
+    pub async fn tunnel(&self, stream: &mut TcpStream, host: &str, port: u16, resolved: Option<IpAddr>) -> io::Result<()> {
+        match self.kind {
+            ProxyKind::Http => self.connect(stream, host, port).await,
+            ProxyKind::Socks5 | ProxyKind::Socks5h => self.socks5(stream, host, port, resolved).await,
+        }
+    }
+
+    async fn connect(&self, stream: &mut TcpStream, host: &str, port: u16) -> io::Result<()> {
+        let target = match host.parse::<IpAddr>() {
+            Ok(IpAddr::V6(ip)) => format!("[{}]:{}", ip, port),
+            _ => format!("{}:{}", host, port),
+        };
+        let mut request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", target, target);
+        if let Some((user, password)) = &self.credentials {
+            let token = STANDARD.encode(format!("{}:{}", user, password));
+            request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", token));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await?;
+
+        // Read byte by byte so nothing past the head is taken from the tunnel.
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            if head.len() == MAX_CONNECT_RESPONSE {
+                return Err(proxy_error("proxy sent an oversized CONNECT response".to_string()));
+            }
+            head.push(stream.read_u8().await?);
+        }
+        let status_line = String::from_utf8_lossy(&head[..head.iter().position(|&b| b == b'\r').unwrap_or(0)]).into_owned();
+        match status_line.split(' ').nth(1) {
+            Some(status) if status.starts_with('2') && status_line.starts_with("HTTP/1.") => Ok(()),
+            _ => Err(proxy_error(format!("proxy refused CONNECT to {}: {}", target, status_line))),
+        }
+    }
+
+    async fn socks5(&self, stream: &mut TcpStream, host: &str, port: u16, resolved: Option<IpAddr>) -> io::Result<()> {
+        let methods: &[u8] = if self.credentials.is_some() { &[0x00, 0x02] } else { &[0x00] };
+        stream.write_all(&[&[0x05, methods.len() as u8][..], methods].concat()).await?;
+        let mut choice = [0u8; 2];
+        stream.read_exact(&mut choice).await?;
+        match (choice, &self.credentials) {
+            ([0x05, 0x00], _) => {}
+            ([0x05, 0x02], Some((user, password))) => {
+                // Username/password authentication (RFC 1929).
+                let mut auth = vec![0x01, user.len() as u8];
+                auth.extend_from_slice(user.as_bytes());
+                auth.push(password.len() as u8);
+                auth.extend_from_slice(password.as_bytes());
+                stream.write_all(&auth).await?;
+                let mut status = [0u8; 2];
+                stream.read_exact(&mut status).await?;
+                if status[1] != 0x00 {
+                    return Err(proxy_error("SOCKS5 proxy rejected the credentials".to_string()));
+                }
+            }
+            _ => return Err(proxy_error("SOCKS5 proxy offered no acceptable authentication method".to_string())),
+        }
+
+        let mut request = vec![0x05, 0x01, 0x00];
+        match resolved.or_else(|| host.parse().ok()) {
+            Some(IpAddr::V4(ip)) => {
+                request.push(0x01);
+                request.extend_from_slice(&ip.octets());
+            }
+            Some(IpAddr::V6(ip)) => {
+                request.push(0x04);
+                request.extend_from_slice(&ip.octets());
+            }
+            None if host.len() <= 255 => {
+                request.extend_from_slice(&[0x03, host.len() as u8]);
+                request.extend_from_slice(host.as_bytes());
+            }
+            None => return Err(proxy_error(format!("{} is too long for SOCKS5", host))),
+        }
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request).await?;
+
+        let mut reply = [0u8; 4];
+        stream.read_exact(&mut reply).await?;
+        if reply[1] != 0x00 {
+            return Err(proxy_error(format!("SOCKS5 proxy refused {}:{}: {}", host, port, socks5_reply(reply[1]))));
+        }
+        // Skip the address the proxy bound for the tunnel.
+        let bound = match reply[3] {
+            0x01 => 4,
+            0x04 => 16,
+            0x03 => stream.read_u8().await? as usize,
+            other => return Err(proxy_error(format!("SOCKS5 proxy replied with address type {}", other))),
+        };
+        let mut skipped = vec![0u8; bound + 2];
+        stream.read_exact(&mut skipped).await?;
+        Ok(())
+    }
+}
+
+/// Which proxy, if any, each upstream is reached through: its entry in
+/// `upstream_proxies`, else the global `proxy`.
+#[derive(Debug, Default)]
+pub struct Egress {
+    proxy: Option<Proxy>,
+    upstreams: HashMap<String, Option<Proxy>>,
+}
+
+impl Egress {
+    pub fn new(config: &UpstreamConnectConfig) -> Result<Self, String> {
+        let proxy = match &config.proxy {
+            Some(proxy) => Proxy::new(proxy)?,
+            None => None,
+        };
+        let mut upstreams = HashMap::new();
+        for (upstream, proxy) in &config.upstream_proxies {
+            let uri: Uri = upstream.parse().map_err(|e| format!("{}: {}", upstream, e))?;
+            upstreams.insert(address(&uri), Proxy::new(proxy).map_err(|e| format!("{}: {}", upstream, e))?);
+        }
+        Ok(Self { proxy, upstreams })
+    }
+
+    /// The proxy for `address`, a `host:port`.
+    pub fn proxy(&self, address: &str) -> Option<&Proxy> {
+        match self.upstreams.get(address) {
+            Some(proxy) => proxy.as_ref(),
+            None => self.proxy.as_ref(),
+        }
+    }
+}
+
+/// `host:port` of `uri`, with the scheme's default port if it has none.
+pub fn address(uri: &Uri) -> String {
+    let port = uri.port_u16().unwrap_or(if uri.scheme_str() == Some("https") { 443 } else { 80 });
+    format!("{}:{}", uri.host().unwrap_or_default(), port)
+}
+
+fn proxy_error(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionRefused, message)
+}
+
+fn socks5_reply(code: u8) -> &'static str {
+    match code {
+        0x01 => "general failure",
+        0x02 => "connection not allowed by ruleset",
+        0x03 => "network unreachable",
+        0x04 => "host unreachable",
+        0x05 => "connection refused",
+        0x06 => "TTL expired",
+        0x07 => "command not supported",
+        0x08 => "address type not supported",
+        _ => "unknown error",
+    }
+}
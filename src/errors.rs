@@ -1,12 +1,41 @@
+use std::error::Error;
 use std::fmt;
+use std::io;
+use hyper::StatusCode;
+
+/// Response header carrying a machine-readable code for gateway errors, so a
+/// client can tell a DNS failure from a refused connection.
+pub const ERROR_CODE_HEADER: &str = "x-gateway-error";
 
 #[derive(Debug)]
 pub enum GatewayError {
     InvalidUri(String),
+    /// A failure inside the gateway itself.
     Http(String),
+    /// The upstream host name did not resolve.
+    UpstreamDns(String),
+    /// The upstream refused or dropped the connection attempt.
+    UpstreamConnect(String),
+    /// The upstream answered with a broken or truncated response.
+    BadGateway(String),
+    /// The upstream endpoint is being drained for maintenance.
+    UpstreamDraining(String),
+    BadRequest(String),
+    NotFound,
+    MethodNotAllowed(Vec<String>),
+    /// The route doesn't accept a body of this `Content-Type`.
+    UnsupportedMediaType(String),
+    /// The request body is over the limit, in bytes, once decompressed.
+    PayloadTooLarge(usize),
     RateLimitExceeded,
+    BulkheadFull(String),
     Timeout,
     Unauthorized,
+    /// The caller is known but the request is refused, e.g. a failed CSRF
+    /// check.
+    Forbidden(String),
+    /// The request clashes with one still being handled.
+    Conflict(String),
 }
 
 impl fmt::Display for GatewayError {
@@ -14,11 +43,105 @@ impl fmt::Display for GatewayError {
         match self {
             Self::InvalidUri(e) => write!(f, "Invalid URI: {}", e),
             Self::Http(e) => write!(f, "HTTP Error: {}", e),
+            Self::UpstreamDns(e) => write!(f, "Upstream DNS lookup failed: {}", e),
+            Self::UpstreamConnect(e) => write!(f, "Upstream connection failed: {}", e),
+            Self::BadGateway(e) => write!(f, "Invalid upstream response: {}", e),
+            Self::UpstreamDraining(e) => write!(f, "Upstream {} is draining", e),
+            Self::BadRequest(e) => write!(f, "Bad request: {}", e),
+            Self::NotFound => write!(f, "No route matched"),
+            Self::MethodNotAllowed(allowed) => write!(f, "Method not allowed, allowed: {}", allowed.join(", ")),
+            Self::UnsupportedMediaType(content_type) => write!(f, "Unsupported content type {}", content_type),
+            Self::PayloadTooLarge(limit) => write!(f, "Request body exceeds {} bytes", limit),
             Self::RateLimitExceeded => write!(f, "Rate limit exceeded"),
+            Self::BulkheadFull(name) => write!(f, "Bulkhead {} is at capacity", name),
             Self::Timeout => write!(f, "Request timed out"),
             Self::Unauthorized => write!(f, "Unauthorized"),
+            Self::Forbidden(e) => write!(f, "Forbidden: {}", e),
+            Self::Conflict(e) => write!(f, "Conflict: {}", e),
         }
     }
 }
 
-impl warp::reject::Reject for GatewayError {}
\ No newline at end of file
+impl GatewayError {
+    /// Classifies an error from the upstream client. hyper only says whether
+    /// the failure happened while connecting, so the cause chain is searched
+    /// for the resolver's error.
+    pub fn upstream(error: &hyper::Error) -> Self {
+        if !error.is_connect() {
+            return Self::BadGateway(error.to_string());
+        }
+        let mut cause = error.source();
+        while let Some(e) = cause {
+            if e.to_string().starts_with("dns error") {
+                return Self::UpstreamDns(e.source().unwrap_or(e).to_string());
+            }
+            if let Some(io) = e.downcast_ref::<io::Error>() {
+                return Self::UpstreamConnect(io.to_string());
+            }
+            cause = e.source();
+        }
+        Self::UpstreamConnect(error.to_string())
+    }
+
+    /// The status the client is answered with.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::InvalidUri(_) | Self::Http(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::UpstreamDns(_) | Self::UpstreamConnect(_) | Self::BadGateway(_) => StatusCode::BAD_GATEWAY,
+            Self::UpstreamDraining(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Self::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::MethodNotAllowed(_) => StatusCode::METHOD_NOT_ALLOWED,
+            Self::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Self::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::RateLimitExceeded => StatusCode::TOO_MANY_REQUESTS,
+            Self::BulkheadFull(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Self::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::Forbidden(_) => StatusCode::FORBIDDEN,
+            Self::Conflict(_) => StatusCode::CONFLICT,
+        }
+    }
+
+    /// The value sent in [`ERROR_CODE_HEADER`].
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::InvalidUri(_) => "invalid_upstream_uri",
+            Self::BadRequest(_) => "bad_request",
+            Self::Http(_) => "internal_error",
+            Self::UpstreamDns(_) => "upstream_dns_failure",
+            Self::UpstreamConnect(_) => "upstream_connect_failure",
+            Self::BadGateway(_) => "upstream_bad_response",
+            Self::UpstreamDraining(_) => "upstream_draining",
+            Self::NotFound => "no_route",
+            Self::MethodNotAllowed(_) => "method_not_allowed",
+            Self::UnsupportedMediaType(_) => "unsupported_media_type",
+            Self::PayloadTooLarge(_) => "payload_too_large",
+            Self::RateLimitExceeded => "rate_limited",
+            Self::BulkheadFull(_) => "bulkhead_full",
+            Self::Timeout => "upstream_timeout",
+            Self::Unauthorized => "unauthorized",
+            Self::Forbidden(_) => "forbidden",
+            Self::Conflict(_) => "conflict",
+        }
+    }
+}
+
+impl warp::reject::Reject for GatewayError {}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(String),
+    Parse(String),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Read(e) => write!(f, "Failed to read config: {}", e),
+            Self::Parse(e) => write!(f, "Failed to parse config: {}", e),
+            Self::Invalid(e) => write!(f, "Invalid config: {}", e),
+        }
+    }
+}
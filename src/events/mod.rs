@@ -0,0 +1,160 @@
+use std::fmt::Write as _;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use bytes::Bytes;
+use serde_json::Value;
+use tokio::sync::mpsc;
+use tokio::time::{timeout, timeout_at, Instant};
+use crate::config::{EventSinkConfig, EventsConfig, UpstreamTlsConfig};
+use crate::kafka::{self, Producer};
+use crate::nats::NatsAddress;
+use crate::syslog;
+
+#[cfg(test)]
+mod tests;
+
+pub const EVENTS_PUBLISHED: &str = "gateway_events_published_total";
+pub const EVENTS_DROPPED: &str = "gateway_events_dropped_total";
+/// How long batches are dropped for after the sink failed, before it is
+/// tried again.
+const RETRY_SECS: u64 = 1;
+
+/// Where batches are published.
+enum Client {
+    Kafka(Producer),
+    Nats { address: NatsAddress, subject: String, tls: Option<Box<UpstreamTlsConfig>>, client: Option<async_nats::Client> },
+}
+
+impl Client {
+    async fn send(&mut self, events: &[Vec<u8>]) -> io::Result<()> {
+        match self {
+            Client::Kafka(producer) => {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as i64;
+                producer.send(events, now).await.map_err(io::Error::other)
+            }
+            Client::Nats { address, subject, tls, client } => {
+                if client.is_none() {
+                    *client = Some(address.connect(tls.as_deref()).await.map_err(io::Error::other)?);
+                }
+                let Some(client) = client else { return Ok(()) };
+                for event in events {
+                    client.publish(subject.clone(), Bytes::copy_from_slice(event)).await.map_err(io::Error::other)?;
+                }
+                client.flush().await.map_err(io::Error::other)
+            }
+        }
+    }
+
+    /// Drops the NATS client, which a timed-out batch may have left
+    /// reconnecting; librdkafka keeps retrying on its own.
+    fn reset(&mut self) {
+        if let Client::Nats { client, .. } = self {
+            *client = None;
+        }
+    }
+}
+
+/// Publishes one event per request to Kafka or NATS. Publishing never
+/// waits: events are queued and sent in batches by a background task, and
+/// dropped when the queue is full or the sink can't be reached.
+pub struct EventSink {
+    queue: mpsc::Sender<Vec<u8>>,
+    published: AtomicU64,
+    dropped_queue_full: AtomicU64,
+    dropped_unavailable: AtomicU64,
+}
+
+impl EventSink {
+    /// Starts the background publisher.
+    pub fn start(config: &EventsConfig) -> Result<Arc<Self>, String> {
+        let timeout = Duration::from_millis(config.timeout_ms);
+        let client = match &config.sink {
+            EventSinkConfig::Kafka { brokers, topic, tls, sasl } => {
+                Client::Kafka(Producer::new(&kafka::client_config(brokers, tls.as_ref(), sasl.as_ref(), config.timeout_ms), topic)?)
+            }
+            EventSinkConfig::Nats { url, subject, username, password, tls } => Client::Nats {
+                address: NatsAddress::new(url, username.as_deref(), password.as_deref())?,
+                subject: subject.clone(),
+                tls: tls.clone().map(Box::new),
+                client: None,
+            },
+        };
+        let (queue, receiver) = mpsc::channel(config.queue_capacity);
+        let sink = Arc::new(Self {
+            queue,
+            published: AtomicU64::new(0),
+            dropped_queue_full: AtomicU64::new(0),
+            dropped_unavailable: AtomicU64::new(0),
+        });
+        let batches = Batches {
+            size: config.batch_size,
+            flush_interval: Duration::from_millis(config.flush_interval_ms),
+            timeout,
+        };
+        tokio::spawn(sink.clone().run(client, receiver, batches));
+        Ok(sink)
+    }
+
+    pub fn publish(&self, event: &Value) {
+        if self.queue.try_send(event.to_string().into_bytes()).is_err() {
+            self.dropped_queue_full.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    async fn run(self: Arc<Self>, mut client: Client, mut queue: mpsc::Receiver<Vec<u8>>, batches: Batches) {
+        let mut retry_at: Option<Instant> = None;
+        while let Some(first) = queue.recv().await {
+            let mut batch = vec![first];
+            let deadline = Instant::now() + batches.flush_interval;
+            while batch.len() < batches.size {
+                match timeout_at(deadline, queue.recv()).await {
+                    Ok(Some(event)) => batch.push(event),
+                    _ => break,
+                }
+            }
+            let count = batch.len() as u64;
+            if retry_at.is_some_and(|at| Instant::now() < at) {
+                self.dropped_unavailable.fetch_add(count, Ordering::Relaxed);
+                continue;
+            }
+            let error = match timeout(batches.timeout, client.send(&batch)).await {
+                Ok(Ok(())) => {
+                    if retry_at.take().is_some() {
+                        println!("Event sink reachable again");
+                    }
+                    self.published.fetch_add(count, Ordering::Relaxed);
+                    continue;
+                }
+                Ok(Err(e)) => e.to_string(),
+                Err(_) => "timed out".to_string(),
+            };
+            if retry_at.is_none() {
+                syslog::error(format_args!("Event sink unreachable, dropping events until it is back: {}", error));
+            }
+            retry_at = Some(Instant::now() + Duration::from_secs(RETRY_SECS));
+            client.reset();
+            self.dropped_unavailable.fetch_add(count, Ordering::Relaxed);
+        }
+    }
+
+    /// The Prometheus text exposition of the events published and dropped.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "# HELP {} Access events the sink took.", EVENTS_PUBLISHED);
+        let _ = writeln!(out, "# TYPE {} counter", EVENTS_PUBLISHED);
+        let _ = writeln!(out, "{} {}", EVENTS_PUBLISHED, self.published.load(Ordering::Relaxed));
+        let _ = writeln!(out, "# HELP {} Access events dropped, by why.", EVENTS_DROPPED);
+        let _ = writeln!(out, "# TYPE {} counter", EVENTS_DROPPED);
+        let _ = writeln!(out, "{}{{reason=\"queue_full\"}} {}", EVENTS_DROPPED, self.dropped_queue_full.load(Ordering::Relaxed));
+        let _ = writeln!(out, "{}{{reason=\"sink_unavailable\"}} {}", EVENTS_DROPPED, self.dropped_unavailable.load(Ordering::Relaxed));
+        out
+    }
+}
+
+struct Batches {
+    size: usize,
+    flush_interval: Duration,
+    timeout: Duration,
+}
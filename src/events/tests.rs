@@ -0,0 +1,83 @@
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use serde_json::{json, Value};
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+    use crate::config::{EventSinkConfig, EventsConfig};
+    use crate::events::EventSink;
+
+    fn config(url: String, batch_size: usize, queue_capacity: usize) -> EventsConfig {
+        EventsConfig {
+            sink: EventSinkConfig::Nats { url, subject: "gateway.access".to_string(), username: None, password: None, tls: None },
+            batch_size,
+            flush_interval_ms: 10,
+            queue_capacity,
+            timeout_ms: 1000,
+        }
+    }
+
+    /// A NATS server keeping each message published to it.
+    async fn nats(published: Arc<Mutex<Vec<Value>>>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            stream.get_mut().write_all(b"INFO {\"server_id\":\"test\"}\r\n").await.unwrap();
+            let mut line = String::new();
+            while stream.read_line(&mut line).await.unwrap() > 0 {
+                if let Some(len) = line.trim_end().strip_prefix("PUB gateway.access ") {
+                    let mut payload = vec![0; len.parse::<usize>().unwrap() + 2];
+                    stream.read_exact(&mut payload).await.unwrap();
+                    published.lock().unwrap().push(serde_json::from_slice(&payload[..payload.len() - 2]).unwrap());
+                } else if line == "PING\r\n" {
+                    stream.get_mut().write_all(b"PONG\r\n").await.unwrap();
+                }
+                line.clear();
+            }
+        });
+        addr
+    }
+
+    async fn eventually(condition: impl Fn() -> bool) {
+        for _ in 0..200 {
+            if condition() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("condition never held");
+    }
+
+    #[tokio::test]
+    async fn test_events_are_published_in_batches() {
+        let published = Arc::new(Mutex::new(Vec::new()));
+        let addr = nats(published.clone()).await;
+        let sink = EventSink::start(&config(format!("nats://{}", addr), 2, 100)).unwrap();
+        for n in 0..3 {
+            sink.publish(&json!({ "n": n }));
+        }
+        eventually(|| published.lock().unwrap().len() == 3).await;
+        assert_eq!(*published.lock().unwrap(), [json!({ "n": 0 }), json!({ "n": 1 }), json!({ "n": 2 })]);
+        eventually(|| sink.render().contains("gateway_events_published_total 3\n")).await;
+    }
+
+    #[tokio::test]
+    async fn test_events_are_dropped_rather_than_waited_on() {
+        // Nothing listens on the port once the listener is gone.
+        let addr = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let sink = EventSink::start(&config(format!("nats://{}", addr), 10, 2)).unwrap();
+        // The publisher only runs once this test yields, so the queue holds
+        // the first two events and the third finds it full.
+        for n in 0..3 {
+            sink.publish(&json!({ "n": n }));
+        }
+        eventually(|| sink.render().contains("gateway_events_dropped_total{reason=\"sink_unavailable\"} 2\n")).await;
+        let metrics = sink.render();
+        assert!(metrics.contains("gateway_events_published_total 0\n"));
+        assert!(metrics.contains("gateway_events_dropped_total{reason=\"queue_full\"} 1\n"));
+    }
+}
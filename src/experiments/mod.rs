@@ -0,0 +1,148 @@
+use std::collections::hash_map::{DefaultHasher, RandomState};
+use std::collections::HashSet;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use hyper::HeaderMap;
+use hyper::header::HeaderValue;
+use crate::config::{AssignBy, ExperimentConfig};
+use crate::middleware::cookie;
+
+#[cfg(test)]
+mod tests;
+
+/// Tells the upstream which variant served the request, as `<experiment>=<variant>`.
+pub const VARIANT_HEADER: &str = "x-experiment-variant";
+pub const EXPERIMENT_COOKIE_MAX_AGE_SECS: u64 = 30 * 24 * 60 * 60;
+
+#[derive(Debug, Clone)]
+pub struct Experiment {
+    pub name: String,
+    variants: Vec<Variant>,
+    total_weight: u64,
+    assign_by: AssignBy,
+    cookie: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Variant {
+    pub name: String,
+    pub upstream: String,
+    weight: u64,
+}
+
+/// The variant chosen for one request. `set_cookie` is present when the
+/// client has no valid assignment cookie yet.
+#[derive(Debug)]
+pub struct Assignment<'a> {
+    pub experiment: &'a str,
+    pub variant: &'a Variant,
+    pub set_cookie: Option<HeaderValue>,
+}
+
+impl Assignment<'_> {
+    pub fn header_value(&self) -> Option<HeaderValue> {
+        HeaderValue::from_str(&format!("{}={}", self.experiment, self.variant.name)).ok()
+    }
+
+    /// Keeps each variant's responses apart in the cache.
+    pub fn cache_key(&self, mut key: String) -> String {
+        key.push('#');
+        key.push_str(self.experiment);
+        key.push('=');
+        key.push_str(&self.variant.name);
+        key
+    }
+}
+
+impl Experiment {
+    pub fn from_config(config: &ExperimentConfig) -> Result<Self, String> {
+        if !is_token(&config.name) {
+            return Err(format!("name \"{}\" may only contain letters, digits, '-' and '_'", config.name));
+        }
+        if config.variants.is_empty() {
+            return Err("at least one variant is required".to_string());
+        }
+        let mut names = HashSet::new();
+        for variant in &config.variants {
+            if !is_token(&variant.name) {
+                return Err(format!("variant \"{}\" may only contain letters, digits, '-' and '_'", variant.name));
+            }
+            if !names.insert(&variant.name) {
+                return Err(format!("variant \"{}\" is defined twice", variant.name));
+            }
+        }
+        let total_weight = config.variants.iter().map(|v| u64::from(v.weight)).sum();
+        if total_weight == 0 {
+            return Err("variant weights must not all be zero".to_string());
+        }
+        let cookie = config.cookie.clone().unwrap_or_else(|| format!("gw_exp_{}", config.name));
+        if !is_token(&cookie) {
+            return Err(format!("cookie \"{}\" may only contain letters, digits, '-' and '_'", cookie));
+        }
+
+        Ok(Self {
+            name: config.name.clone(),
+            variants: config
+                .variants
+                .iter()
+                .map(|v| Variant {
+                    name: v.name.clone(),
+                    upstream: v.upstream.clone(),
+                    weight: u64::from(v.weight),
+                })
+                .collect(),
+            total_weight,
+            assign_by: config.assign_by,
+            cookie,
+        })
+    }
+
+    /// Picks the variant for a request: by user hash when configured and
+    /// known, else the one named by the client's cookie, else a weighted
+    /// random draw that the returned cookie makes sticky.
+    pub fn assign(&self, headers: &HeaderMap, user: Option<&str>) -> Assignment<'_> {
+        let assignment = |variant, set_cookie| Assignment { experiment: &self.name, variant, set_cookie };
+
+        if let (AssignBy::User, Some(user)) = (self.assign_by, user) {
+            let mut hasher = DefaultHasher::new();
+            (&self.name, user).hash(&mut hasher);
+            return assignment(self.weighted(hasher.finish()), None);
+        }
+
+        let assigned = cookie(headers, &self.cookie)
+            .and_then(|name| self.variants.iter().find(|v| v.name == name && v.weight > 0));
+        if let Some(variant) = assigned {
+            return assignment(variant, None);
+        }
+
+        let variant = self.weighted(random());
+        let set_cookie = HeaderValue::from_str(&format!(
+            "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax",
+            self.cookie, variant.name, EXPERIMENT_COOKIE_MAX_AGE_SECS
+        ))
+        .ok();
+        assignment(variant, set_cookie)
+    }
+
+    fn weighted(&self, draw: u64) -> &Variant {
+        let mut point = draw % self.total_weight;
+        for variant in &self.variants {
+            if point < variant.weight {
+                return variant;
+            }
+            point -= variant.weight;
+        }
+        &self.variants[self.variants.len() - 1]
+    }
+}
+
+/// Good enough to split traffic: `RandomState` keys are seeded per process,
+/// and the counter makes consecutive draws differ.
+pub(crate) fn random() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    RandomState::new().hash_one(COUNTER.fetch_add(1, Ordering::Relaxed))
+}
+
+fn is_token(value: &str) -> bool {
+    !value.is_empty() && value.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
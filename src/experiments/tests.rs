@@ -0,0 +1,71 @@
+#[cfg(test)]
+mod tests {
+    use hyper::HeaderMap;
+    use crate::config::ExperimentConfig;
+    use crate::experiments::Experiment;
+
+    fn experiment(json: &str) -> Result<Experiment, String> {
+        let config: ExperimentConfig = serde_json::from_str(json).unwrap();
+        Experiment::from_config(&config)
+    }
+
+    fn checkout() -> Experiment {
+        experiment(r#"{ "name": "checkout", "variants": [
+            { "name": "control", "upstream": "http://checkout-a:8080", "weight": 0 },
+            { "name": "new-flow", "upstream": "http://checkout-b:8080" }
+        ] }"#).unwrap()
+    }
+
+    #[test]
+    fn test_cookie_assignment_is_sticky() {
+        let experiment = checkout();
+        let first = experiment.assign(&HeaderMap::new(), None);
+        assert_eq!(first.variant.name, "new-flow", "zero-weight variants are never drawn");
+        assert_eq!(first.header_value().unwrap(), "checkout=new-flow");
+        let cookie = first.set_cookie.unwrap();
+        assert!(cookie.to_str().unwrap().starts_with("gw_exp_checkout=new-flow; Path=/; Max-Age="));
+
+        let mut headers = HeaderMap::new();
+        headers.insert("cookie", "theme=dark; gw_exp_checkout=new-flow".parse().unwrap());
+        let again = experiment.assign(&headers, None);
+        assert_eq!(again.variant.upstream, "http://checkout-b:8080");
+        assert!(again.set_cookie.is_none());
+
+        // A cookie naming a variant that no longer takes traffic is replaced.
+        headers.insert("cookie", "gw_exp_checkout=control".parse().unwrap());
+        let reassigned = experiment.assign(&headers, None);
+        assert_eq!(reassigned.variant.name, "new-flow");
+        assert!(reassigned.set_cookie.is_some());
+        assert_eq!(reassigned.cache_key("users:GET/a".to_string()), "users:GET/a#checkout=new-flow");
+    }
+
+    #[test]
+    fn test_user_assignment_is_deterministic() {
+        let experiment = experiment(r#"{ "name": "search", "assign_by": "user", "variants": [
+            { "name": "a", "upstream": "http://search-a:8080" },
+            { "name": "b", "upstream": "http://search-b:8080" }
+        ] }"#).unwrap();
+
+        let mut seen = std::collections::HashSet::new();
+        for user in (0..32).map(|i| format!("user-{}", i)) {
+            let assignment = experiment.assign(&HeaderMap::new(), Some(&user));
+            assert!(assignment.set_cookie.is_none());
+            assert_eq!(experiment.assign(&HeaderMap::new(), Some(&user)).variant.name, assignment.variant.name);
+            seen.insert(assignment.variant.name.clone());
+        }
+        assert_eq!(seen.len(), 2, "users are spread over both variants");
+
+        assert!(experiment.assign(&HeaderMap::new(), None).set_cookie.is_some(), "anonymous users get a cookie");
+    }
+
+    #[test]
+    fn test_invalid_experiments() {
+        let variant = r#"{ "name": "a", "upstream": "http://a:8080" }"#;
+        assert!(experiment(r#"{ "name": "x", "variants": [] }"#).is_err());
+        assert!(experiment(&format!(r#"{{ "name": "bad name", "variants": [{}] }}"#, variant)).is_err());
+        assert!(experiment(&format!(r#"{{ "name": "x", "variants": [{0}, {0}] }}"#, variant)).is_err());
+        assert!(experiment(r#"{ "name": "x", "variants": [{ "name": "a", "upstream": "http://a", "weight": 0 }] }"#).is_err());
+        assert!(experiment(&format!(r#"{{ "name": "x", "cookie": "a;b", "variants": [{}] }}"#, variant)).is_err());
+        assert!(experiment(&format!(r#"{{ "name": "x", "variants": [{}] }}"#, variant)).is_ok());
+    }
+}
@@ -0,0 +1,121 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use hyper::{Body, Response};
+use hyper::header::{CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, ETAG};
+use serde_json::{Map, Value};
+use crate::config::FieldsConfig;
+
+#[cfg(test)]
+mod tests;
+
+/// Requests naming more fields than this are answered unfiltered.
+pub const MAX_FIELDS: usize = 100;
+
+/// Trims JSON responses down to the fields a client lists in a query
+/// parameter, such as `?fields=id,name,owner.email`.
+#[derive(Debug, Clone)]
+pub struct FieldFilter {
+    param: String,
+}
+
+impl FieldFilter {
+    pub fn new(config: &FieldsConfig) -> Self {
+        Self { param: config.param.clone() }
+    }
+
+    /// The query without the filter parameter, which the upstream never
+    /// sees, and the fields it selected. Other parameters keep their
+    /// original encoding, and a query without the parameter isn't copied.
+    pub fn split<'a>(&self, query: &'a str) -> (Cow<'a, str>, Option<Selection>) {
+        let mut kept = Vec::new();
+        let mut requested = Vec::new();
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            match form_urlencoded::parse(pair.as_bytes()).next() {
+                Some((name, value)) if name == self.param => requested.push(value.into_owned()),
+                _ => kept.push(pair),
+            }
+        }
+        if requested.is_empty() {
+            return (Cow::Borrowed(query), None);
+        }
+        (Cow::Owned(kept.join("&")), Selection::parse(&requested.join(",")))
+    }
+}
+
+/// Field paths as a tree; a leaf keeps the whole value below it.
+#[derive(Debug, Default, PartialEq)]
+pub struct Selection {
+    fields: BTreeMap<String, Selection>,
+}
+
+impl Selection {
+    /// Comma-separated paths with `.` between nested names. `None` if no
+    /// path is given or there are more than `MAX_FIELDS`.
+    pub fn parse(text: &str) -> Option<Self> {
+        let paths: Vec<&str> = text.split(',').map(str::trim).filter(|path| !path.is_empty()).collect();
+        if paths.is_empty() || paths.len() > MAX_FIELDS {
+            return None;
+        }
+        let mut root = Self::default();
+        for path in paths {
+            let mut node = &mut root;
+            let mut names = path.split('.').filter(|name| !name.is_empty()).peekable();
+            while let Some(name) = names.next() {
+                let seen = node.fields.contains_key(name);
+                let child = node.fields.entry(name.to_string()).or_default();
+                if names.peek().is_none() {
+                    child.fields.clear();
+                    break;
+                }
+                // A field already selected whole stays whole.
+                if seen && child.fields.is_empty() {
+                    break;
+                }
+                node = child;
+            }
+        }
+        Some(root)
+    }
+
+    /// Keeps the selected fields of an object, or of every object in an
+    /// array. Selected fields that are missing stay missing.
+    pub fn apply(&self, value: &Value) -> Value {
+        if self.fields.is_empty() {
+            return value.clone();
+        }
+        match value {
+            Value::Object(object) => {
+                let mut kept = Map::new();
+                for (name, selection) in &self.fields {
+                    if let Some(field) = object.get(name) {
+                        kept.insert(name.clone(), selection.apply(field));
+                    }
+                }
+                Value::Object(kept)
+            }
+            Value::Array(items) => Value::Array(items.iter().map(|item| self.apply(item)).collect()),
+            other => other.clone(),
+        }
+    }
+
+    /// Filters successful, uncompressed JSON responses; anything else is
+    /// sent as it is.
+    pub async fn response(&self, response: Response<Body>) -> Response<Body> {
+        let (mut parts, body) = response.into_parts();
+        let is_json = parts
+            .headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.split(';').next().unwrap_or("").trim().eq_ignore_ascii_case("application/json"));
+        if !parts.status.is_success() || !is_json || parts.headers.contains_key(CONTENT_ENCODING) {
+            return Response::from_parts(parts, body);
+        }
+        let bytes = hyper::body::to_bytes(body).await.unwrap_or_default();
+        let Ok(value) = serde_json::from_slice::<Value>(&bytes) else {
+            return Response::from_parts(parts, Body::from(bytes));
+        };
+        parts.headers.remove(CONTENT_LENGTH);
+        parts.headers.remove(ETAG);
+        Response::from_parts(parts, Body::from(self.apply(&value).to_string()))
+    }
+}
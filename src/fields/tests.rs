@@ -0,0 +1,72 @@
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+    use hyper::{Body, Response};
+    use serde_json::{json, Value};
+    use crate::config::FieldsConfig;
+    use crate::fields::{FieldFilter, Selection, MAX_FIELDS};
+
+    #[test]
+    fn test_split_removes_the_parameter() {
+        let filter = FieldFilter::new(&FieldsConfig::default());
+        let (query, selection) = filter.split("page=2&fields=id,name&q=a%20b&fields=owner.email");
+        assert_eq!(query, "page=2&q=a%20b");
+        assert_eq!(selection, Selection::parse("id,name,owner.email"));
+
+        assert!(matches!(filter.split("page=2&q=a+b"), (Cow::Borrowed("page=2&q=a+b"), None)));
+        assert_eq!(filter.split("fields="), (Cow::from(""), None));
+
+        let custom = FieldFilter::new(&FieldsConfig { param: "select".to_string() });
+        assert_eq!(custom.split("fields=id&select=id").0, "fields=id");
+    }
+
+    #[test]
+    fn test_apply_keeps_selected_paths() {
+        let value = json!({
+            "id": 1,
+            "name": "Ann",
+            "owner": { "email": "a@example.com", "phone": "1" },
+            "lines": [{ "sku": "a", "qty": 1 }, { "sku": "b", "qty": 2 }],
+        });
+        let selection = Selection::parse("id, owner.email, lines.sku, missing").unwrap();
+        assert_eq!(
+            selection.apply(&value),
+            json!({ "id": 1, "owner": { "email": "a@example.com" }, "lines": [{ "sku": "a" }, { "sku": "b" }] })
+        );
+
+        let whole = Selection::parse("owner.email,owner").unwrap();
+        assert_eq!(whole, Selection::parse("owner,owner.phone").unwrap());
+        assert_eq!(whole.apply(&value), json!({ "owner": { "email": "a@example.com", "phone": "1" } }));
+
+        let list = json!([{ "id": 1, "name": "a" }, 7]);
+        assert_eq!(Selection::parse("id").unwrap().apply(&list), json!([{ "id": 1 }, 7]));
+
+        assert!(Selection::parse(" , ").is_none());
+        assert!(Selection::parse(&vec!["a"; MAX_FIELDS + 1].join(",")).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_response_only_filters_successful_json() {
+        let selection = Selection::parse("id").unwrap();
+        let response = |status: u16, content_type: &str| {
+            Response::builder()
+                .status(status)
+                .header("content-type", content_type)
+                .header("etag", "\"v1\"")
+                .body(Body::from(r#"{"id":1,"name":"Ann"}"#))
+                .unwrap()
+        };
+
+        let filtered = selection.response(response(200, "application/json; charset=utf-8")).await;
+        assert!(filtered.headers().get("etag").is_none());
+        let body = hyper::body::to_bytes(filtered.into_body()).await.unwrap();
+        assert_eq!(serde_json::from_slice::<Value>(&body).unwrap(), json!({ "id": 1 }));
+
+        for (status, content_type) in [(404, "application/json"), (200, "text/plain")] {
+            let untouched = selection.response(response(status, content_type)).await;
+            assert!(untouched.headers().contains_key("etag"));
+            let body = hyper::body::to_bytes(untouched.into_body()).await.unwrap();
+            assert_eq!(&body[..], br#"{"id":1,"name":"Ann"}"#);
+        }
+    }
+}
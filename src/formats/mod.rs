@@ -0,0 +1,427 @@
+use bytes::Bytes;
+use hyper::{Body, HeaderMap, Response};
+use hyper::header::{HeaderValue, ACCEPT, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, ETAG, VARY};
+use serde_json::{Map, Value};
+use crate::config::{Format, FormatsConfig};
+use crate::errors::GatewayError;
+
+#[cfg(test)]
+mod tests;
+
+pub const XML_CONTENT_TYPE: &str = "application/xml";
+pub const CSV_CONTENT_TYPE: &str = "text/csv";
+/// The element each JSON array entry is written as.
+pub const XML_ITEM: &str = "item";
+/// Nesting deeper than this is refused rather than parsed recursively.
+const MAX_XML_DEPTH: usize = 64;
+
+/// Lets clients that only speak XML or CSV use a JSON upstream. Responses
+/// are translated when `Accept` prefers one of the offered formats; request
+/// bodies in one of them are translated to JSON before being forwarded.
+#[derive(Debug, Clone)]
+pub struct Translator {
+    offer: Vec<Format>,
+    xml_root: String,
+}
+
+impl Translator {
+    pub fn new(config: &FormatsConfig) -> Self {
+        Self { offer: config.offer.clone(), xml_root: config.xml_root.clone() }
+    }
+
+    /// The offered format the client's `Accept` prefers, or `None` for
+    /// JSON, which wins ties and is what clients get without an `Accept`.
+    pub fn negotiate(&self, request_headers: &HeaderMap) -> Option<Format> {
+        let mut best: Option<(Option<Format>, f32)> = None;
+        for accept in request_headers.get_all(ACCEPT).iter().filter_map(|value| value.to_str().ok()) {
+            for range in accept.split(',') {
+                let mut params = range.split(';').map(str::trim);
+                let media_type = params.next().unwrap_or("").to_ascii_lowercase();
+                let quality = params
+                    .filter_map(|param| param.strip_prefix("q="))
+                    .find_map(|q| q.parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                let candidate = match media_type.as_str() {
+                    "application/json" | "application/*" | "*/*" => None,
+                    other => match format_of(other) {
+                        Some(format) if self.offer.contains(&format) => Some(format),
+                        _ => continue,
+                    },
+                };
+                let better = match best {
+                    None => true,
+                    Some((_, best_quality)) => quality > best_quality || (quality == best_quality && candidate.is_none()),
+                };
+                if quality > 0.0 && better {
+                    best = Some((candidate, quality));
+                }
+            }
+        }
+        best.and_then(|(format, _)| format)
+    }
+
+    /// The request body as JSON if it is in an offered format, `None` if it
+    /// should be forwarded as it is.
+    pub fn request(&self, headers: &HeaderMap, body: &[u8]) -> Result<Option<Bytes>, GatewayError> {
+        let Some(format) = essence(headers).as_deref().and_then(format_of).filter(|f| self.offer.contains(f)) else {
+            return Ok(None);
+        };
+        let text = std::str::from_utf8(body)
+            .map_err(|_| GatewayError::BadRequest("request body is not UTF-8".to_string()))?;
+        let value = match format {
+            Format::Xml => from_xml(text).map_err(|e| GatewayError::BadRequest(format!("invalid XML body: {}", e)))?,
+            Format::Csv => from_csv(text).map_err(|e| GatewayError::BadRequest(format!("invalid CSV body: {}", e)))?,
+        };
+        Ok(Some(Bytes::from(value.to_string())))
+    }
+
+    /// Translates a JSON response into the format the client negotiated.
+    /// Bodies that are compressed or don't fit the format, such as a CSV
+    /// of anything but an array of objects, are sent as they are.
+    pub async fn response(&self, request_headers: &HeaderMap, response: Response<Body>) -> Response<Body> {
+        let (mut parts, body) = response.into_parts();
+        let varies = parts
+            .headers
+            .get_all(VARY)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|name| name.trim() == "*" || name.trim().eq_ignore_ascii_case("accept"));
+        if !varies {
+            parts.headers.append(VARY, HeaderValue::from_static("accept"));
+        }
+
+        let format = self
+            .negotiate(request_headers)
+            .filter(|_| essence(&parts.headers).as_deref() == Some("application/json"))
+            .filter(|_| !parts.headers.contains_key(CONTENT_ENCODING));
+        let Some(format) = format else {
+            return Response::from_parts(parts, body);
+        };
+        let bytes = hyper::body::to_bytes(body).await.unwrap_or_default();
+        let translated = serde_json::from_slice::<Value>(&bytes).ok().and_then(|value| match format {
+            Format::Xml => Some((to_xml(&value, &self.xml_root), XML_CONTENT_TYPE)),
+            Format::Csv => to_csv(&value).map(|csv| (csv, CSV_CONTENT_TYPE)),
+        });
+        let Some((text, content_type)) = translated else {
+            return Response::from_parts(parts, Body::from(bytes));
+        };
+        parts.headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+        parts.headers.remove(CONTENT_LENGTH);
+        parts.headers.remove(ETAG);
+        Response::from_parts(parts, Body::from(text))
+    }
+}
+
+fn essence(headers: &HeaderMap) -> Option<String> {
+    let content_type = headers.get(CONTENT_TYPE)?.to_str().ok()?;
+    Some(content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase())
+}
+
+fn format_of(media_type: &str) -> Option<Format> {
+    match media_type {
+        "application/xml" | "text/xml" => Some(Format::Xml),
+        "text/csv" => Some(Format::Csv),
+        _ => None,
+    }
+}
+
+/// A letter or underscore followed by letters, digits, `-`, `_` or `.`.
+pub fn valid_xml_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// Writes `value` as an XML document. Object fields become child elements,
+/// named after the field with invalid characters replaced by `_`; array
+/// entries become `item` elements and `null` an empty element.
+pub fn to_xml(value: &Value, root: &str) -> String {
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>");
+    write_element(&mut out, root, value);
+    out
+}
+
+fn write_element(out: &mut String, name: &str, value: &Value) {
+    let name = if valid_xml_name(name) {
+        name.to_string()
+    } else {
+        let replaced: String = name
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.') { c } else { '_' })
+            .collect();
+        if valid_xml_name(&replaced) { replaced } else { format!("_{}", replaced) }
+    };
+    if value.is_null() {
+        out.push_str(&format!("<{}/>", name));
+        return;
+    }
+    out.push_str(&format!("<{}>", name));
+    match value {
+        Value::Object(fields) => fields.iter().for_each(|(field, value)| write_element(out, field, value)),
+        Value::Array(items) => items.iter().for_each(|item| write_element(out, XML_ITEM, item)),
+        Value::String(text) => out.push_str(&escape_xml(text)),
+        other => out.push_str(&other.to_string()),
+    }
+    out.push_str(&format!("</{}>", name));
+}
+
+/// `text` as XML or HTML character data or attribute value.
+pub fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Reads an XML document as the JSON `to_xml` would have written it, minus
+/// the root element. Elements whose children are all `item` become arrays,
+/// repeated elements too; text is kept as strings, empty elements are
+/// `null` and attributes are ignored. DTDs are refused.
+pub fn from_xml(text: &str) -> Result<Value, String> {
+    let mut parser = XmlParser { input: text, pos: 0 };
+    parser.skip_misc()?;
+    if !parser.rest().starts_with('<') {
+        return Err("expected a root element".to_string());
+    }
+    let (_, value) = parser.element(0)?;
+    parser.skip_misc()?;
+    if parser.pos < parser.input.len() {
+        return Err("unexpected content after the root element".to_string());
+    }
+    Ok(value)
+}
+
+struct XmlParser<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl<'a> XmlParser<'a> {
+    fn rest(&self) -> &'a str {
+        &self.input[self.pos..]
+    }
+
+    fn skip_past(&mut self, end: &str) -> Result<&'a str, String> {
+        let rest = self.rest();
+        let at = rest.find(end).ok_or_else(|| format!("missing \"{}\"", end))?;
+        self.pos += at + end.len();
+        Ok(&rest[..at])
+    }
+
+    /// Whitespace, comments and processing instructions outside elements.
+    fn skip_misc(&mut self) -> Result<(), String> {
+        loop {
+            let trimmed = self.rest().trim_start();
+            self.pos = self.input.len() - trimmed.len();
+            if trimmed.starts_with("<?") {
+                self.skip_past("?>")?;
+            } else if trimmed.starts_with("<!--") {
+                self.skip_past("-->")?;
+            } else if trimmed.starts_with("<!") {
+                return Err("DTDs are not supported".to_string());
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    fn element(&mut self, depth: usize) -> Result<(String, Value), String> {
+        if depth >= MAX_XML_DEPTH {
+            return Err("elements are nested too deeply".to_string());
+        }
+        self.pos += 1;
+        let tag = self.skip_past(">")?;
+        let (tag, empty) = match tag.strip_suffix('/') {
+            Some(tag) => (tag, true),
+            None => (tag, false),
+        };
+        let name = tag.split_whitespace().next().unwrap_or("").to_string();
+        if !valid_xml_name(name.split_once(':').map_or(name.as_str(), |(_, local)| local)) {
+            return Err(format!("invalid element name \"{}\"", name));
+        }
+        if empty {
+            return Ok((name, Value::Null));
+        }
+
+        let mut text = String::new();
+        let mut children: Vec<(String, Value)> = Vec::new();
+        loop {
+            let rest = self.rest();
+            let at = rest.find('<').ok_or_else(|| format!("unclosed element \"{}\"", name))?;
+            text.push_str(&unescape_xml(&rest[..at])?);
+            self.pos += at;
+            let rest = self.rest();
+            if let Some(closing) = rest.strip_prefix("</") {
+                let end = closing.find('>').ok_or_else(|| format!("unclosed element \"{}\"", name))?;
+                if closing[..end].trim() != name {
+                    return Err(format!("\"{}\" closed by \"{}\"", name, closing[..end].trim()));
+                }
+                self.pos += end + 3;
+                break;
+            } else if rest.starts_with("<![CDATA[") {
+                self.pos += "<![CDATA[".len();
+                text.push_str(self.skip_past("]]>")?);
+            } else if rest.starts_with("<!--") {
+                self.skip_past("-->")?;
+            } else if rest.starts_with("<?") {
+                self.skip_past("?>")?;
+            } else if rest.starts_with("<!") {
+                return Err("DTDs are not supported".to_string());
+            } else {
+                children.push(self.element(depth + 1)?);
+            }
+        }
+
+        if children.is_empty() {
+            return Ok((name, if text.is_empty() { Value::Null } else { Value::String(text) }));
+        }
+        if children.iter().all(|(child, _)| child == XML_ITEM) {
+            return Ok((name, Value::Array(children.into_iter().map(|(_, value)| value).collect())));
+        }
+        let mut fields = Map::new();
+        for (child, value) in children {
+            match fields.get_mut(&child) {
+                Some(Value::Array(values)) => values.push(value),
+                Some(existing) => *existing = Value::Array(vec![existing.take(), value]),
+                None => {
+                    fields.insert(child, value);
+                }
+            }
+        }
+        Ok((name, Value::Object(fields)))
+    }
+}
+
+fn unescape_xml(text: &str) -> Result<String, String> {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(at) = rest.find('&') {
+        out.push_str(&rest[..at]);
+        let end = rest[at..].find(';').ok_or("unterminated entity")? + at;
+        let entity = &rest[at + 1..end];
+        let c = match entity {
+            "amp" => '&',
+            "lt" => '<',
+            "gt" => '>',
+            "quot" => '"',
+            "apos" => '\'',
+            _ => {
+                let code = match entity.strip_prefix("#x").or_else(|| entity.strip_prefix("#X")) {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                    None => entity.strip_prefix('#').and_then(|dec| dec.parse().ok()),
+                };
+                code.and_then(char::from_u32).ok_or_else(|| format!("unknown entity \"&{};\"", entity))?
+            }
+        };
+        out.push(c);
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Writes an array of objects as CSV with a header row, columns in the
+/// order fields first appear. Nested values are written as JSON; anything
+/// but an array of objects has no CSV form.
+pub fn to_csv(value: &Value) -> Option<String> {
+    let rows = value.as_array()?.iter().map(Value::as_object).collect::<Option<Vec<_>>>()?;
+    let mut columns: Vec<&String> = Vec::new();
+    for row in &rows {
+        for field in row.keys() {
+            if !columns.contains(&field) {
+                columns.push(field);
+            }
+        }
+    }
+    let mut out = String::new();
+    write_csv_record(&mut out, columns.iter().map(|column| column.to_string()));
+    for row in rows {
+        write_csv_record(
+            &mut out,
+            columns.iter().map(|column| match row.get(*column) {
+                None | Some(Value::Null) => String::new(),
+                Some(Value::String(text)) => text.clone(),
+                Some(other) => other.to_string(),
+            }),
+        );
+    }
+    Some(out)
+}
+
+fn write_csv_record(out: &mut String, cells: impl Iterator<Item = String>) {
+    let cells: Vec<String> = cells
+        .map(|cell| {
+            if cell.contains([',', '"', '\r', '\n']) {
+                format!("\"{}\"", cell.replace('"', "\"\""))
+            } else {
+                cell
+            }
+        })
+        .collect();
+    out.push_str(&cells.join(","));
+    out.push_str("\r\n");
+}
+
+/// Reads CSV with a header row as an array of objects with string values.
+pub fn from_csv(text: &str) -> Result<Value, String> {
+    let mut records = parse_csv(text)?.into_iter();
+    let Some(columns) = records.next() else {
+        return Ok(Value::Array(Vec::new()));
+    };
+    let mut rows = Vec::new();
+    for (line, record) in records.enumerate() {
+        if record.len() != columns.len() {
+            return Err(format!("row {} has {} fields, the header {}", line + 1, record.len(), columns.len()));
+        }
+        let row: Map<String, Value> = columns.iter().cloned().zip(record.into_iter().map(Value::String)).collect();
+        rows.push(Value::Object(row));
+    }
+    Ok(Value::Array(rows))
+}
+
+/// RFC 4180 records; blank lines are skipped.
+fn parse_csv(text: &str) -> Result<Vec<Vec<String>>, String> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut cell = String::new();
+    let mut quoted = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (quoted, c) {
+            (true, '"') if chars.peek() == Some(&'"') => {
+                chars.next();
+                cell.push('"');
+            }
+            (true, '"') => quoted = false,
+            (true, c) => cell.push(c),
+            (false, '"') if cell.is_empty() => quoted = true,
+            (false, ',') => record.push(std::mem::take(&mut cell)),
+            (false, '\r') if chars.peek() == Some(&'\n') => {}
+            (false, '\n' | '\r') => {
+                record.push(std::mem::take(&mut cell));
+                if record.len() > 1 || !record[0].is_empty() {
+                    records.push(std::mem::take(&mut record));
+                }
+                record.clear();
+            }
+            (false, c) => cell.push(c),
+        }
+    }
+    if quoted {
+        return Err("unterminated quoted field".to_string());
+    }
+    if !cell.is_empty() || !record.is_empty() {
+        record.push(cell);
+        records.push(record);
+    }
+    Ok(records)
+}
@@ -0,0 +1,85 @@
+#[cfg(test)]
+mod tests {
+    use hyper::{Body, HeaderMap, Response};
+    use serde_json::json;
+    use crate::config::{Format, FormatsConfig};
+    use crate::formats::{from_csv, from_xml, to_csv, to_xml, Translator};
+
+    fn accept(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("accept", value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_negotiates_by_quality_and_offer() {
+        let translator = Translator::new(&FormatsConfig::default());
+        assert_eq!(translator.negotiate(&HeaderMap::new()), None);
+        assert_eq!(translator.negotiate(&accept("application/xml")), Some(Format::Xml));
+        assert_eq!(translator.negotiate(&accept("application/json;q=0.5, text/csv")), Some(Format::Csv));
+        assert_eq!(translator.negotiate(&accept("text/xml, application/json")), None);
+        assert_eq!(translator.negotiate(&accept("text/csv;q=0, */*;q=0.1")), None);
+
+        let xml_only = Translator::new(&FormatsConfig { offer: vec![Format::Xml], ..FormatsConfig::default() });
+        assert_eq!(xml_only.negotiate(&accept("text/csv")), None);
+    }
+
+    #[test]
+    fn test_xml_round_trip() {
+        let value = json!({ "id": 7, "tags": ["a", "b"], "note": "1 < 2 & \"x\"", "gift": null });
+        let xml = to_xml(&value, "order");
+        assert_eq!(
+            xml,
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?><order><gift/><id>7</id><note>1 &lt; 2 &amp; &quot;x&quot;</note>\
+             <tags><item>a</item><item>b</item></tags></order>"
+        );
+        assert_eq!(
+            from_xml(&xml).unwrap(),
+            json!({ "id": "7", "tags": ["a", "b"], "note": "1 < 2 & \"x\"", "gift": null })
+        );
+    }
+
+    #[test]
+    fn test_from_xml_repeats_cdata_and_errors() {
+        let xml = "<!-- c --><orders id=\"1\"><line>a</line><line><![CDATA[<b>]]></line><total>&#x35;</total></orders>";
+        assert_eq!(from_xml(xml).unwrap(), json!({ "line": ["a", "<b>"], "total": "5" }));
+        assert!(from_xml("<a><b></a>").is_err());
+        assert!(from_xml("<!DOCTYPE a [<!ENTITY x \"y\">]><a>&x;</a>").is_err());
+        assert!(from_xml(&"<a>".repeat(100)).is_err());
+    }
+
+    #[test]
+    fn test_csv_round_trip() {
+        let value = json!([{ "id": 1, "name": "Ann, \"A\"" }, { "id": 2, "city": "Oslo" }]);
+        let csv = to_csv(&value).unwrap();
+        assert_eq!(csv, "id,name,city\r\n1,\"Ann, \"\"A\"\"\",\r\n2,,Oslo\r\n");
+        assert_eq!(
+            from_csv(&csv).unwrap(),
+            json!([{ "id": "1", "name": "Ann, \"A\"", "city": "" }, { "id": "2", "name": "", "city": "Oslo" }])
+        );
+        assert_eq!(to_csv(&json!({ "id": 1 })), None);
+        assert!(from_csv("a,b\n1\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_translates_requests_and_responses() {
+        let translator = Translator::new(&FormatsConfig::default());
+        let mut request = accept("application/xml");
+        request.insert("content-type", "text/csv; charset=utf-8".parse().unwrap());
+        let body = translator.request(&request, b"id\n7\n").unwrap().unwrap();
+        assert_eq!(body, r#"[{"id":"7"}]"#);
+        assert!(translator.request(&request, b"\"").is_err());
+
+        let response = Response::builder()
+            .header("content-type", "application/json")
+            .header("etag", "\"abc\"")
+            .body(Body::from(r#"{"id":7}"#))
+            .unwrap();
+        let response = translator.response(&request, response).await;
+        assert_eq!(response.headers()["content-type"], "application/xml");
+        assert_eq!(response.headers()["vary"], "accept");
+        assert!(response.headers().get("etag").is_none());
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "<?xml version=\"1.0\" encoding=\"UTF-8\"?><response><id>7</id></response>");
+    }
+}
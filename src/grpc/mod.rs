@@ -0,0 +1,825 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use base64::Engine;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE};
+use bytes::{BufMut, Bytes, BytesMut};
+use hyper::{Body, Client, HeaderMap, Request, Response, StatusCode, body::HttpBody, client::HttpConnector};
+use hyper::header::{HeaderName, HeaderValue, ACCEPT, ACCEPT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, HOST, TE, USER_AGENT};
+use serde_json::{json, Map, Number, Value};
+use tokio::time::timeout;
+use crate::config::GrpcConfig;
+use crate::errors::{GatewayError, ERROR_CODE_HEADER};
+use crate::routes::Params;
+
+#[cfg(test)]
+mod tests;
+
+pub const GRPC_CONTENT_TYPE: &str = "application/grpc";
+/// Messages larger than this are refused in both directions.
+pub const MAX_GRPC_MESSAGE_BYTES: usize = 4 * 1024 * 1024;
+/// Nesting deeper than this is refused rather than walked recursively.
+const MAX_MESSAGE_DEPTH: usize = 64;
+
+/// Exposes one unary gRPC method as a REST endpoint. The request message is
+/// the JSON body with query and then path parameters filling in fields; the
+/// reply is sent back as JSON using the proto3 JSON mapping. Types come from
+/// a `protoc --include_imports --descriptor_set_out` file.
+#[derive(Debug)]
+pub struct GrpcRoute {
+    upstream: String,
+    path: String,
+    input: String,
+    output: String,
+    descriptors: Descriptors,
+    client: Client<HttpConnector>,
+}
+
+impl GrpcRoute {
+    pub fn from_config(config: &GrpcConfig) -> Result<Self, String> {
+        let bytes = std::fs::read(&config.descriptor)
+            .map_err(|e| format!("reading {}: {}", config.descriptor.display(), e))?;
+        let descriptors = Descriptors::parse(&bytes)
+            .map_err(|e| format!("{} is not a descriptor set: {}", config.descriptor.display(), e))?;
+        let method = descriptors
+            .methods
+            .get(config.method.trim_start_matches('/'))
+            .ok_or_else(|| format!("method \"{}\" is not in {}", config.method, config.descriptor.display()))?;
+        if method.client_streaming || method.server_streaming {
+            return Err(format!("method \"{}\" is streaming; only unary methods are supported", config.method));
+        }
+        for type_name in [&method.input, &method.output] {
+            if !descriptors.messages.contains_key(type_name) {
+                return Err(format!("message \"{}\" is not in {}", type_name, config.descriptor.display()));
+            }
+        }
+        Ok(Self {
+            upstream: config.upstream.trim_end_matches('/').to_string(),
+            path: format!("/{}", config.method.trim_start_matches('/')),
+            input: method.input.clone(),
+            output: method.output.clone(),
+            client: Client::builder().http2_only(true).build_http(),
+            descriptors,
+        })
+    }
+
+    pub fn upstream(&self) -> &str {
+        &self.upstream
+    }
+
+    /// The call path, `/package.Service/Method`.
+    pub fn method(&self) -> &str {
+        &self.path
+    }
+
+    /// Merges the body with query and path parameters into the request
+    /// message. Query parameters that name no field are ignored.
+    pub fn request_message(&self, params: &Params, query: &str, body: &[u8]) -> Result<Vec<u8>, String> {
+        let mut fields = if body.iter().all(u8::is_ascii_whitespace) {
+            Map::new()
+        } else {
+            match serde_json::from_slice(body) {
+                Ok(Value::Object(fields)) => fields,
+                Ok(_) => return Err("the request body must be a JSON object".to_string()),
+                Err(e) => return Err(format!("invalid JSON body: {}", e)),
+            }
+        };
+        let message = &self.descriptors.messages[&self.input];
+        for (name, value) in form_urlencoded::parse(query.as_bytes()) {
+            let Some(field) = message.field_named(&name) else {
+                continue;
+            };
+            let value = Value::String(value.into_owned());
+            match fields.get_mut(&field.json_name) {
+                Some(Value::Array(values)) if field.repeated => values.push(value),
+                _ if field.repeated => {
+                    fields.insert(field.json_name.clone(), Value::Array(vec![value]));
+                }
+                _ => {
+                    fields.insert(field.json_name.clone(), value);
+                }
+            }
+        }
+        for (name, value) in params {
+            if let Some(field) = message.field_named(name) {
+                fields.remove(&field.name);
+                fields.insert(field.json_name.clone(), Value::String(value.clone()));
+            }
+        }
+        self.descriptors.encode(&self.input, &fields, 0)
+    }
+
+    /// Calls the method and answers as JSON, mapping the gRPC status to the
+    /// HTTP one the way grpc-gateway does.
+    pub async fn call(
+        &self,
+        metadata: &HeaderMap,
+        params: &Params,
+        query: &str,
+        body: &[u8],
+        deadline: Duration,
+    ) -> Result<Response<Body>, GatewayError> {
+        let message = self.request_message(params, query, body).map_err(GatewayError::BadRequest)?;
+        let mut request = Request::post(format!("{}{}", self.upstream, self.path))
+            .header(CONTENT_TYPE, GRPC_CONTENT_TYPE)
+            .header(TE, "trailers")
+            .header("grpc-timeout", format!("{}m", deadline.as_millis().max(1)))
+            .body(Body::from(frame(&message)))
+            .map_err(|e| GatewayError::Http(format!("building gRPC request: {}", e)))?;
+        for (name, value) in metadata {
+            if forwards_metadata(name) {
+                request.headers_mut().append(name.clone(), value.clone());
+            }
+        }
+
+        let exchange = async {
+            let response = self.client.request(request).await.map_err(|e| GatewayError::upstream(&e))?;
+            let (parts, mut body) = response.into_parts();
+            let mut data = BytesMut::new();
+            while let Some(chunk) = body.data().await {
+                let chunk = chunk.map_err(|e| GatewayError::upstream(&e))?;
+                if data.len() + chunk.len() > MAX_GRPC_MESSAGE_BYTES + 5 {
+                    return Err(GatewayError::BadGateway("gRPC reply is too large".to_string()));
+                }
+                data.extend_from_slice(&chunk);
+            }
+            let trailers = body.trailers().await.map_err(|e| GatewayError::upstream(&e))?;
+            Ok((parts, data.freeze(), trailers))
+        };
+        let (parts, data, trailers) = timeout(deadline, exchange).await.map_err(|_| GatewayError::Timeout)??;
+        if !parts.status.is_success() {
+            return Err(GatewayError::BadGateway(format!("gRPC upstream answered HTTP {}", parts.status)));
+        }
+
+        // A call that fails before any reply sends its status in the headers.
+        let status_of = |headers: &HeaderMap| {
+            let code = headers.get("grpc-status")?.to_str().ok()?.parse::<u32>().ok()?;
+            let message = headers
+                .get("grpc-message")
+                .and_then(|value| value.to_str().ok())
+                .map(|message| percent_encoding::percent_decode_str(message).decode_utf8_lossy().into_owned())
+                .unwrap_or_default();
+            Some((code, message))
+        };
+        let (code, message) = trailers
+            .as_ref()
+            .and_then(status_of)
+            .or_else(|| status_of(&parts.headers))
+            .ok_or_else(|| GatewayError::BadGateway("gRPC reply has no grpc-status".to_string()))?;
+        if code != 0 {
+            return Ok(status_response(code, &message));
+        }
+
+        let reply = unframe(&data).map_err(|e| GatewayError::BadGateway(format!("invalid gRPC reply: {}", e)))?;
+        let value = self
+            .descriptors
+            .decode(&self.output, reply, 0)
+            .map_err(|e| GatewayError::BadGateway(format!("invalid gRPC reply: {}", e)))?;
+        let mut response = Response::new(Body::from(value.to_string()));
+        response.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        Ok(response)
+    }
+}
+
+/// Client headers passed on as call metadata. Transport headers and the
+/// `grpc-` namespace belong to the gateway's own call.
+fn forwards_metadata(name: &HeaderName) -> bool {
+    ![HOST, CONTENT_TYPE, CONTENT_LENGTH, ACCEPT, ACCEPT_ENCODING, TE, USER_AGENT].contains(name)
+        && !name.as_str().starts_with("grpc-")
+}
+
+/// The HTTP status grpc-gateway uses for each gRPC status code.
+pub fn http_status(code: u32) -> StatusCode {
+    match code {
+        0 => StatusCode::OK,
+        1 => StatusCode::from_u16(499).unwrap_or(StatusCode::BAD_REQUEST),
+        3 | 9 | 11 => StatusCode::BAD_REQUEST,
+        4 => StatusCode::GATEWAY_TIMEOUT,
+        5 => StatusCode::NOT_FOUND,
+        6 | 10 => StatusCode::CONFLICT,
+        7 => StatusCode::FORBIDDEN,
+        8 => StatusCode::TOO_MANY_REQUESTS,
+        12 => StatusCode::NOT_IMPLEMENTED,
+        14 => StatusCode::SERVICE_UNAVAILABLE,
+        16 => StatusCode::UNAUTHORIZED,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+fn status_response(code: u32, message: &str) -> Response<Body> {
+    let body = json!({ "code": code, "message": message });
+    let mut response = Response::new(Body::from(body.to_string()));
+    *response.status_mut() = http_status(code);
+    response.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    response.headers_mut().insert(ERROR_CODE_HEADER, HeaderValue::from_static("grpc_error"));
+    response
+}
+
+/// A length-prefixed, uncompressed gRPC message.
+fn frame(message: &[u8]) -> Bytes {
+    let mut framed = BytesMut::with_capacity(message.len() + 5);
+    framed.put_u8(0);
+    framed.put_u32(message.len() as u32);
+    framed.extend_from_slice(message);
+    framed.freeze()
+}
+
+fn unframe(data: &[u8]) -> Result<&[u8], String> {
+    if data.len() < 5 {
+        return Err("missing message".to_string());
+    }
+    if data[0] != 0 {
+        return Err("compressed messages are not supported".to_string());
+    }
+    let len = u32::from_be_bytes([data[1], data[2], data[3], data[4]]) as usize;
+    data.get(5..5 + len).ok_or_else(|| "truncated message".to_string())
+}
+
+/// The message, enum and method types of a `FileDescriptorSet`, by fully
+/// qualified name without the leading dot.
+#[derive(Debug, Default)]
+pub struct Descriptors {
+    messages: HashMap<String, MessageType>,
+    enums: HashMap<String, Vec<(String, i32)>>,
+    methods: HashMap<String, MethodType>,
+}
+
+#[derive(Debug, Default)]
+struct MessageType {
+    fields: Vec<FieldType>,
+    map_entry: bool,
+}
+
+impl MessageType {
+    /// Fields are named by their proto or JSON name.
+    fn field_named(&self, name: &str) -> Option<&FieldType> {
+        self.fields.iter().find(|field| field.json_name == name || field.name == name)
+    }
+}
+
+#[derive(Debug, Default)]
+struct FieldType {
+    name: String,
+    json_name: String,
+    number: u32,
+    kind: u64,
+    repeated: bool,
+    type_name: String,
+}
+
+#[derive(Debug, Default)]
+struct MethodType {
+    input: String,
+    output: String,
+    client_streaming: bool,
+    server_streaming: bool,
+}
+
+// `FieldDescriptorProto.Type` values.
+const TYPE_DOUBLE: u64 = 1;
+const TYPE_FLOAT: u64 = 2;
+const TYPE_INT64: u64 = 3;
+const TYPE_UINT64: u64 = 4;
+const TYPE_INT32: u64 = 5;
+const TYPE_FIXED64: u64 = 6;
+const TYPE_FIXED32: u64 = 7;
+const TYPE_BOOL: u64 = 8;
+const TYPE_STRING: u64 = 9;
+const TYPE_GROUP: u64 = 10;
+const TYPE_MESSAGE: u64 = 11;
+const TYPE_BYTES: u64 = 12;
+const TYPE_UINT32: u64 = 13;
+const TYPE_ENUM: u64 = 14;
+const TYPE_SFIXED32: u64 = 15;
+const TYPE_SFIXED64: u64 = 16;
+const TYPE_SINT32: u64 = 17;
+const TYPE_SINT64: u64 = 18;
+const LABEL_REPEATED: u64 = 3;
+
+/// One field of an encoded message.
+#[derive(Debug, Clone, Copy)]
+enum Wire<'a> {
+    Varint(u64),
+    Fixed64(u64),
+    Bytes(&'a [u8]),
+    Fixed32(u32),
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    fn varint(&mut self) -> Result<u64, String> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = *self.buf.get(self.pos).ok_or("truncated varint")?;
+            self.pos += 1;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err("varint is too long".to_string())
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let end = self.pos.checked_add(len).filter(|end| *end <= self.buf.len()).ok_or("truncated field")?;
+        let bytes = &self.buf[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn field(&mut self) -> Result<Option<(u32, Wire<'a>)>, String> {
+        if self.pos >= self.buf.len() {
+            return Ok(None);
+        }
+        let key = self.varint()?;
+        let number = (key >> 3) as u32;
+        let wire = match key & 7 {
+            0 => Wire::Varint(self.varint()?),
+            1 => Wire::Fixed64(u64::from_le_bytes(self.take(8)?.try_into().unwrap_or_default())),
+            2 => {
+                let len = self.varint()? as usize;
+                Wire::Bytes(self.take(len)?)
+            }
+            5 => Wire::Fixed32(u32::from_le_bytes(self.take(4)?.try_into().unwrap_or_default())),
+            other => return Err(format!("unsupported wire type {}", other)),
+        };
+        Ok(Some((number, wire)))
+    }
+}
+
+fn put_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn put_key(out: &mut Vec<u8>, number: u32, wire_type: u64) {
+    put_varint(out, (u64::from(number) << 3) | wire_type);
+}
+
+fn put_bytes(out: &mut Vec<u8>, number: u32, bytes: &[u8]) {
+    put_key(out, number, 2);
+    put_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+fn string(wire: Wire) -> Result<String, String> {
+    match wire {
+        Wire::Bytes(bytes) => String::from_utf8(bytes.to_vec()).map_err(|_| "string is not UTF-8".to_string()),
+        _ => Err("expected a string".to_string()),
+    }
+}
+
+fn varint(wire: Wire) -> u64 {
+    match wire {
+        Wire::Varint(value) | Wire::Fixed64(value) => value,
+        Wire::Fixed32(value) => u64::from(value),
+        Wire::Bytes(_) => 0,
+    }
+}
+
+/// `foo_bar` → `fooBar`, for descriptors written without `json_name`.
+fn json_name(name: &str) -> String {
+    let mut out = String::with_capacity(name.len());
+    let mut upper = false;
+    for c in name.chars() {
+        if c == '_' {
+            upper = true;
+        } else if upper {
+            out.push(c.to_ascii_uppercase());
+            upper = false;
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+impl Descriptors {
+    pub fn parse(bytes: &[u8]) -> Result<Self, String> {
+        let mut descriptors = Self::default();
+        let mut reader = Reader::new(bytes);
+        while let Some((number, wire)) = reader.field()? {
+            if let (1, Wire::Bytes(file)) = (number, wire) {
+                descriptors.parse_file(file)?;
+            }
+        }
+        Ok(descriptors)
+    }
+
+    fn parse_file(&mut self, bytes: &[u8]) -> Result<(), String> {
+        let mut package = String::new();
+        let mut messages = Vec::new();
+        let mut enums = Vec::new();
+        let mut services = Vec::new();
+        let mut reader = Reader::new(bytes);
+        while let Some((number, wire)) = reader.field()? {
+            match (number, wire) {
+                (2, wire) => package = string(wire)?,
+                (4, Wire::Bytes(message)) => messages.push(message),
+                (5, Wire::Bytes(enumeration)) => enums.push(enumeration),
+                (6, Wire::Bytes(service)) => services.push(service),
+                _ => {}
+            }
+        }
+        let scope = if package.is_empty() { String::new() } else { format!("{}.", package) };
+        for message in messages {
+            self.parse_message(&scope, message)?;
+        }
+        for enumeration in enums {
+            self.parse_enum(&scope, enumeration)?;
+        }
+        for service in services {
+            self.parse_service(&scope, service)?;
+        }
+        Ok(())
+    }
+
+    fn parse_message(&mut self, scope: &str, bytes: &[u8]) -> Result<(), String> {
+        let mut name = String::new();
+        let mut message = MessageType::default();
+        let mut nested = Vec::new();
+        let mut enums = Vec::new();
+        let mut reader = Reader::new(bytes);
+        while let Some((number, wire)) = reader.field()? {
+            match (number, wire) {
+                (1, wire) => name = string(wire)?,
+                (2, Wire::Bytes(field)) => message.fields.push(parse_field(field)?),
+                (3, Wire::Bytes(message)) => nested.push(message),
+                (4, Wire::Bytes(enumeration)) => enums.push(enumeration),
+                (7, Wire::Bytes(options)) => {
+                    let mut options = Reader::new(options);
+                    while let Some((number, wire)) = options.field()? {
+                        if number == 7 {
+                            message.map_entry = varint(wire) != 0;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        let full_name = format!("{}{}", scope, name);
+        let nested_scope = format!("{}.", full_name);
+        for message in nested {
+            self.parse_message(&nested_scope, message)?;
+        }
+        for enumeration in enums {
+            self.parse_enum(&nested_scope, enumeration)?;
+        }
+        self.messages.insert(full_name, message);
+        Ok(())
+    }
+
+    fn parse_enum(&mut self, scope: &str, bytes: &[u8]) -> Result<(), String> {
+        let mut name = String::new();
+        let mut values = Vec::new();
+        let mut reader = Reader::new(bytes);
+        while let Some((number, wire)) = reader.field()? {
+            match (number, wire) {
+                (1, wire) => name = string(wire)?,
+                (2, Wire::Bytes(value)) => {
+                    let (mut value_name, mut value_number) = (String::new(), 0);
+                    let mut reader = Reader::new(value);
+                    while let Some((number, wire)) = reader.field()? {
+                        match number {
+                            1 => value_name = string(wire)?,
+                            2 => value_number = varint(wire) as i32,
+                            _ => {}
+                        }
+                    }
+                    values.push((value_name, value_number));
+                }
+                _ => {}
+            }
+        }
+        self.enums.insert(format!("{}{}", scope, name), values);
+        Ok(())
+    }
+
+    fn parse_service(&mut self, scope: &str, bytes: &[u8]) -> Result<(), String> {
+        let mut name = String::new();
+        let mut methods = Vec::new();
+        let mut reader = Reader::new(bytes);
+        while let Some((number, wire)) = reader.field()? {
+            match (number, wire) {
+                (1, wire) => name = string(wire)?,
+                (2, Wire::Bytes(bytes)) => {
+                    let (mut method_name, mut method) = (String::new(), MethodType::default());
+                    let mut reader = Reader::new(bytes);
+                    while let Some((number, wire)) = reader.field()? {
+                        match number {
+                            1 => method_name = string(wire)?,
+                            2 => method.input = string(wire)?.trim_start_matches('.').to_string(),
+                            3 => method.output = string(wire)?.trim_start_matches('.').to_string(),
+                            5 => method.client_streaming = varint(wire) != 0,
+                            6 => method.server_streaming = varint(wire) != 0,
+                            _ => {}
+                        }
+                    }
+                    methods.push((method_name, method));
+                }
+                _ => {}
+            }
+        }
+        for (method_name, method) in methods {
+            self.methods.insert(format!("{}{}/{}", scope, name, method_name), method);
+        }
+        Ok(())
+    }
+
+    /// Encodes a JSON object as the message `type_name`. Scalars may be
+    /// given as strings, so path and query parameters can fill any field.
+    fn encode(&self, type_name: &str, fields: &Map<String, Value>, depth: usize) -> Result<Vec<u8>, String> {
+        if depth >= MAX_MESSAGE_DEPTH {
+            return Err("message is nested too deeply".to_string());
+        }
+        let message = self.messages.get(type_name).ok_or_else(|| format!("unknown message \"{}\"", type_name))?;
+        let mut out = Vec::new();
+        for (name, value) in fields {
+            let field = message.field_named(name).ok_or_else(|| format!("unknown field \"{}\"", name))?;
+            match value {
+                Value::Null => {}
+                Value::Object(entries) if self.is_map(field) => {
+                    let entry = &self.messages[&field.type_name];
+                    let (Some(key), Some(value_field)) = (entry.field_named("key"), entry.field_named("value")) else {
+                        return Err(format!("invalid map field \"{}\"", name));
+                    };
+                    for (key_text, value) in entries {
+                        let mut encoded = Vec::new();
+                        self.encode_value(key, &Value::String(key_text.clone()), &mut encoded, depth)?;
+                        self.encode_value(value_field, value, &mut encoded, depth)?;
+                        put_bytes(&mut out, field.number, &encoded);
+                    }
+                }
+                Value::Array(items) if field.repeated => {
+                    for item in items {
+                        self.encode_value(field, item, &mut out, depth)?;
+                    }
+                }
+                _ if field.repeated => return Err(format!("field \"{}\" must be an array", name)),
+                value => self.encode_value(field, value, &mut out, depth)?,
+            }
+        }
+        Ok(out)
+    }
+
+    fn is_map(&self, field: &FieldType) -> bool {
+        field.repeated && field.kind == TYPE_MESSAGE && self.messages.get(&field.type_name).is_some_and(|m| m.map_entry)
+    }
+
+    fn encode_value(&self, field: &FieldType, value: &Value, out: &mut Vec<u8>, depth: usize) -> Result<(), String> {
+        let invalid = || format!("invalid value {} for field \"{}\"", value, field.name);
+        let integer = || -> Result<i128, String> {
+            match value {
+                Value::Number(number) => number
+                    .as_i64()
+                    .map(i128::from)
+                    .or_else(|| number.as_u64().map(i128::from))
+                    .or_else(|| number.as_f64().filter(|f| f.fract() == 0.0).map(|f| f as i128)),
+                Value::String(text) => text.trim().parse().ok(),
+                _ => None,
+            }
+            .ok_or_else(invalid)
+        };
+        let float = || -> Result<f64, String> {
+            match value {
+                Value::Number(number) => number.as_f64(),
+                Value::String(text) => text.trim().parse().ok(),
+                _ => None,
+            }
+            .ok_or_else(invalid)
+        };
+        match field.kind {
+            TYPE_INT32 | TYPE_SFIXED32 | TYPE_SINT32 => {
+                let n = i32::try_from(integer()?).map_err(|_| invalid())?;
+                match field.kind {
+                    TYPE_SFIXED32 => {
+                        put_key(out, field.number, 5);
+                        out.extend_from_slice(&n.to_le_bytes());
+                    }
+                    TYPE_SINT32 => {
+                        put_key(out, field.number, 0);
+                        put_varint(out, u64::from(((n << 1) ^ (n >> 31)) as u32));
+                    }
+                    _ => {
+                        put_key(out, field.number, 0);
+                        put_varint(out, i64::from(n) as u64);
+                    }
+                }
+            }
+            TYPE_INT64 | TYPE_SFIXED64 | TYPE_SINT64 => {
+                let n = i64::try_from(integer()?).map_err(|_| invalid())?;
+                match field.kind {
+                    TYPE_SFIXED64 => {
+                        put_key(out, field.number, 1);
+                        out.extend_from_slice(&n.to_le_bytes());
+                    }
+                    TYPE_SINT64 => {
+                        put_key(out, field.number, 0);
+                        put_varint(out, ((n << 1) ^ (n >> 63)) as u64);
+                    }
+                    _ => {
+                        put_key(out, field.number, 0);
+                        put_varint(out, n as u64);
+                    }
+                }
+            }
+            TYPE_UINT32 | TYPE_FIXED32 => {
+                let n = u32::try_from(integer()?).map_err(|_| invalid())?;
+                if field.kind == TYPE_FIXED32 {
+                    put_key(out, field.number, 5);
+                    out.extend_from_slice(&n.to_le_bytes());
+                } else {
+                    put_key(out, field.number, 0);
+                    put_varint(out, u64::from(n));
+                }
+            }
+            TYPE_UINT64 | TYPE_FIXED64 => {
+                let n = u64::try_from(integer()?).map_err(|_| invalid())?;
+                if field.kind == TYPE_FIXED64 {
+                    put_key(out, field.number, 1);
+                    out.extend_from_slice(&n.to_le_bytes());
+                } else {
+                    put_key(out, field.number, 0);
+                    put_varint(out, n);
+                }
+            }
+            TYPE_DOUBLE => {
+                put_key(out, field.number, 1);
+                out.extend_from_slice(&float()?.to_le_bytes());
+            }
+            TYPE_FLOAT => {
+                put_key(out, field.number, 5);
+                out.extend_from_slice(&(float()? as f32).to_le_bytes());
+            }
+            TYPE_BOOL => {
+                let b = match value {
+                    Value::Bool(b) => *b,
+                    Value::String(text) if text == "true" => true,
+                    Value::String(text) if text == "false" => false,
+                    _ => return Err(invalid()),
+                };
+                put_key(out, field.number, 0);
+                put_varint(out, u64::from(b));
+            }
+            TYPE_STRING => match value {
+                Value::String(text) => put_bytes(out, field.number, text.as_bytes()),
+                _ => return Err(invalid()),
+            },
+            TYPE_BYTES => {
+                let text = value.as_str().ok_or_else(invalid)?;
+                let bytes = STANDARD.decode(text).or_else(|_| URL_SAFE.decode(text)).map_err(|_| invalid())?;
+                put_bytes(out, field.number, &bytes);
+            }
+            TYPE_ENUM => {
+                let values = self.enums.get(&field.type_name).ok_or_else(|| format!("unknown enum \"{}\"", field.type_name))?;
+                let number = match value {
+                    Value::String(text) => match values.iter().find(|(name, _)| name == text) {
+                        Some((_, number)) => *number,
+                        None => text.parse().map_err(|_| invalid())?,
+                    },
+                    _ => i32::try_from(integer()?).map_err(|_| invalid())?,
+                };
+                put_key(out, field.number, 0);
+                put_varint(out, i64::from(number) as u64);
+            }
+            TYPE_MESSAGE => {
+                let fields = value.as_object().ok_or_else(invalid)?;
+                let encoded = self.encode(&field.type_name, fields, depth + 1)?;
+                put_bytes(out, field.number, &encoded);
+            }
+            TYPE_GROUP => return Err(format!("field \"{}\" is a group, which is not supported", field.name)),
+            other => return Err(format!("field \"{}\" has unknown type {}", field.name, other)),
+        }
+        Ok(())
+    }
+
+    /// Decodes the message `type_name` with the proto3 JSON mapping: JSON
+    /// field names, 64-bit integers as strings, enums by name and bytes as
+    /// base64. Fields the descriptor doesn't know are dropped.
+    fn decode(&self, type_name: &str, bytes: &[u8], depth: usize) -> Result<Value, String> {
+        if depth >= MAX_MESSAGE_DEPTH {
+            return Err("message is nested too deeply".to_string());
+        }
+        let message = self.messages.get(type_name).ok_or_else(|| format!("unknown message \"{}\"", type_name))?;
+        let mut fields = Map::new();
+        let mut reader = Reader::new(bytes);
+        while let Some((number, wire)) = reader.field()? {
+            let Some(field) = message.fields.iter().find(|field| field.number == number) else {
+                continue;
+            };
+            if self.is_map(field) {
+                let Wire::Bytes(entry) = wire else {
+                    return Err(format!("invalid map entry in \"{}\"", field.name));
+                };
+                let entry = self.decode(&field.type_name, entry, depth + 1)?;
+                let key = match entry.get("key") {
+                    Some(Value::String(key)) => key.clone(),
+                    Some(key) => key.to_string(),
+                    None => String::new(),
+                };
+                let value = entry.get("value").cloned().unwrap_or(Value::Null);
+                let map = fields.entry(field.json_name.clone()).or_insert_with(|| Value::Object(Map::new()));
+                if let Value::Object(map) = map {
+                    map.insert(key, value);
+                }
+                continue;
+            }
+            let values = match wire {
+                // Packed repeated scalars.
+                Wire::Bytes(packed) if field.repeated && !matches!(field.kind, TYPE_STRING | TYPE_BYTES | TYPE_MESSAGE) => {
+                    let mut values = Vec::new();
+                    let mut reader = Reader::new(packed);
+                    while reader.pos < packed.len() {
+                        let wire = match field.kind {
+                            TYPE_DOUBLE | TYPE_FIXED64 | TYPE_SFIXED64 => {
+                                Wire::Fixed64(u64::from_le_bytes(reader.take(8)?.try_into().unwrap_or_default()))
+                            }
+                            TYPE_FLOAT | TYPE_FIXED32 | TYPE_SFIXED32 => {
+                                Wire::Fixed32(u32::from_le_bytes(reader.take(4)?.try_into().unwrap_or_default()))
+                            }
+                            _ => Wire::Varint(reader.varint()?),
+                        };
+                        values.push(self.decode_value(field, wire, depth)?);
+                    }
+                    values
+                }
+                wire => vec![self.decode_value(field, wire, depth)?],
+            };
+            if field.repeated {
+                let array = fields.entry(field.json_name.clone()).or_insert_with(|| Value::Array(Vec::new()));
+                if let Value::Array(array) = array {
+                    array.extend(values);
+                }
+            } else if let Some(value) = values.into_iter().next_back() {
+                fields.insert(field.json_name.clone(), value);
+            }
+        }
+        Ok(Value::Object(fields))
+    }
+
+    fn decode_value(&self, field: &FieldType, wire: Wire, depth: usize) -> Result<Value, String> {
+        let raw = varint(wire);
+        let float = |f: f64| Number::from_f64(f).map_or_else(|| Value::String(f.to_string()), Value::Number);
+        Ok(match field.kind {
+            TYPE_INT32 => Value::from(raw as i32),
+            TYPE_SFIXED32 => Value::from(raw as u32 as i32),
+            TYPE_SINT32 => Value::from(((raw as u32 >> 1) as i32) ^ -((raw & 1) as i32)),
+            TYPE_UINT32 | TYPE_FIXED32 => Value::from(raw as u32),
+            TYPE_INT64 | TYPE_SFIXED64 => Value::String((raw as i64).to_string()),
+            TYPE_SINT64 => Value::String((((raw >> 1) as i64) ^ -((raw & 1) as i64)).to_string()),
+            TYPE_UINT64 | TYPE_FIXED64 => Value::String(raw.to_string()),
+            TYPE_DOUBLE => float(f64::from_bits(raw)),
+            TYPE_FLOAT => float(f64::from(f32::from_bits(raw as u32))),
+            TYPE_BOOL => Value::Bool(raw != 0),
+            TYPE_ENUM => {
+                let number = raw as i32;
+                self.enums
+                    .get(&field.type_name)
+                    .and_then(|values| values.iter().find(|(_, n)| *n == number))
+                    .map_or_else(|| Value::from(number), |(name, _)| Value::String(name.clone()))
+            }
+            TYPE_STRING => Value::String(string(wire)?),
+            TYPE_BYTES | TYPE_MESSAGE => {
+                let Wire::Bytes(bytes) = wire else {
+                    return Err(format!("field \"{}\" has the wrong wire type", field.name));
+                };
+                if field.kind == TYPE_BYTES {
+                    Value::String(STANDARD.encode(bytes))
+                } else {
+                    self.decode(&field.type_name, bytes, depth + 1)?
+                }
+            }
+            other => return Err(format!("field \"{}\" has unsupported type {}", field.name, other)),
+        })
+    }
+}
+
+fn parse_field(bytes: &[u8]) -> Result<FieldType, String> {
+    let mut field = FieldType::default();
+    let mut reader = Reader::new(bytes);
+    while let Some((number, wire)) = reader.field()? {
+        match number {
+            1 => field.name = string(wire)?,
+            3 => field.number = varint(wire) as u32,
+            4 => field.repeated = varint(wire) == LABEL_REPEATED,
+            5 => field.kind = varint(wire),
+            6 => field.type_name = string(wire)?.trim_start_matches('.').to_string(),
+            10 => field.json_name = string(wire)?,
+            _ => {}
+        }
+    }
+    if field.json_name.is_empty() {
+        field.json_name = json_name(&field.name);
+    }
+    Ok(field)
+}
@@ -0,0 +1,222 @@
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::path::PathBuf;
+    use std::time::Duration;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, HeaderMap, Request, Response, Server, StatusCode};
+    use serde_json::{json, Value};
+    use crate::config::GrpcConfig;
+    use crate::grpc::{frame, http_status, put_bytes, put_key, put_varint, unframe, Descriptors, GrpcRoute};
+    use crate::routes::Params;
+
+    fn field(name: &str, number: u64, kind: u64, repeated: bool, type_name: &str) -> Vec<u8> {
+        let mut out = Vec::new();
+        put_bytes(&mut out, 1, name.as_bytes());
+        put_key(&mut out, 3, 0);
+        put_varint(&mut out, number);
+        put_key(&mut out, 4, 0);
+        put_varint(&mut out, if repeated { 3 } else { 1 });
+        put_key(&mut out, 5, 0);
+        put_varint(&mut out, kind);
+        if !type_name.is_empty() {
+            put_bytes(&mut out, 6, type_name.as_bytes());
+        }
+        out
+    }
+
+    fn message(name: &str, fields: &[Vec<u8>], nested: &[Vec<u8>], map_entry: bool) -> Vec<u8> {
+        let mut out = Vec::new();
+        put_bytes(&mut out, 1, name.as_bytes());
+        for field in fields {
+            put_bytes(&mut out, 2, field);
+        }
+        for message in nested {
+            put_bytes(&mut out, 3, message);
+        }
+        if map_entry {
+            put_bytes(&mut out, 7, &[7 << 3, 1]);
+        }
+        out
+    }
+
+    fn method(name: &str, input: &str, output: &str, server_streaming: bool) -> Vec<u8> {
+        let mut out = Vec::new();
+        put_bytes(&mut out, 1, name.as_bytes());
+        put_bytes(&mut out, 2, input.as_bytes());
+        put_bytes(&mut out, 3, output.as_bytes());
+        if server_streaming {
+            put_key(&mut out, 6, 0);
+            put_varint(&mut out, 1);
+        }
+        out
+    }
+
+    /// `shop.Orders` with a unary `GetOrder` and a streaming `Watch`.
+    fn descriptor_set() -> Vec<u8> {
+        let request = message(
+            "GetOrderRequest",
+            &[field("order_id", 1, 9, false, ""), field("tags", 2, 9, true, ""), field("limit", 3, 3, false, "")],
+            &[],
+            false,
+        );
+        let labels = message("LabelsEntry", &[field("key", 1, 9, false, ""), field("value", 2, 9, false, "")], &[], true);
+        let order = message(
+            "Order",
+            &[
+                field("order_id", 1, 9, false, ""),
+                field("total", 2, 3, false, ""),
+                field("status", 3, 14, false, ".shop.Status"),
+                field("labels", 4, 11, true, ".shop.Order.LabelsEntry"),
+                field("blob", 5, 12, false, ""),
+                field("quantities", 6, 5, true, ""),
+            ],
+            &[labels],
+            false,
+        );
+        let mut status = Vec::new();
+        put_bytes(&mut status, 1, b"Status");
+        for (name, number) in [("UNKNOWN", 0), ("SHIPPED", 1)] {
+            let mut value = Vec::new();
+            put_bytes(&mut value, 1, name.as_bytes());
+            put_key(&mut value, 2, 0);
+            put_varint(&mut value, number);
+            put_bytes(&mut status, 2, &value);
+        }
+        let mut service = Vec::new();
+        put_bytes(&mut service, 1, b"Orders");
+        put_bytes(&mut service, 2, &method("GetOrder", ".shop.GetOrderRequest", ".shop.Order", false));
+        put_bytes(&mut service, 2, &method("Watch", ".shop.GetOrderRequest", ".shop.Order", true));
+
+        let mut file = Vec::new();
+        put_bytes(&mut file, 2, b"shop");
+        put_bytes(&mut file, 4, &request);
+        put_bytes(&mut file, 4, &order);
+        put_bytes(&mut file, 5, &status);
+        put_bytes(&mut file, 6, &service);
+        let mut set = Vec::new();
+        put_bytes(&mut set, 1, &file);
+        set
+    }
+
+    fn route(name: &str, upstream: &str, method: &str) -> Result<GrpcRoute, String> {
+        let descriptor: PathBuf = std::env::temp_dir().join(format!("api-gateway-grpc-{}-{}.pb", name, std::process::id()));
+        std::fs::write(&descriptor, descriptor_set()).unwrap();
+        let route = GrpcRoute::from_config(&GrpcConfig {
+            upstream: upstream.to_string(),
+            descriptor: descriptor.clone(),
+            method: method.to_string(),
+        });
+        std::fs::remove_file(&descriptor).unwrap();
+        route
+    }
+
+    #[test]
+    fn test_from_config_checks_the_method() {
+        let grpc = route("config", "http://orders:50051/", "shop.Orders/GetOrder").unwrap();
+        assert_eq!(grpc.upstream(), "http://orders:50051");
+        assert_eq!(grpc.method(), "/shop.Orders/GetOrder");
+        assert!(route("missing", "http://orders:50051", "shop.Orders/Cancel").unwrap_err().contains("is not in"));
+        assert!(route("streaming", "http://orders:50051", "shop.Orders/Watch").unwrap_err().contains("streaming"));
+        assert!(Descriptors::parse(&[0x0a, 0x05, 0x01]).is_err());
+    }
+
+    #[test]
+    fn test_request_message_merges_body_query_and_params() {
+        let grpc = route("request", "http://orders:50051", "shop.Orders/GetOrder").unwrap();
+        let params = Params::from([("order_id".to_string(), "o-7".to_string())]);
+        let message = grpc.request_message(&params, "tags=a&tags=b&limit=5&ignored=1", br#"{"orderId": "body"}"#).unwrap();
+        let decoded = grpc.descriptors.decode("shop.GetOrderRequest", &message, 0).unwrap();
+        assert_eq!(decoded, json!({ "orderId": "o-7", "tags": ["a", "b"], "limit": "5" }));
+
+        assert!(grpc.request_message(&Params::new(), "", b"[1]").is_err());
+        assert!(grpc.request_message(&Params::new(), "", br#"{"nope": 1}"#).is_err());
+        assert!(grpc.request_message(&Params::new(), "limit=many", b"").is_err());
+    }
+
+    #[test]
+    fn test_decode_uses_the_json_mapping() {
+        let descriptors = Descriptors::parse(&descriptor_set()).unwrap();
+        let order = json!({
+            "orderId": "o-1",
+            "total": "-12",
+            "status": "SHIPPED",
+            "labels": { "gift": "yes" },
+            "blob": "AAE=",
+            "quantities": [1, 2],
+        });
+        let Value::Object(fields) = &order else { unreachable!() };
+        let encoded = descriptors.encode("shop.Order", fields, 0).unwrap();
+        assert_eq!(descriptors.decode("shop.Order", &encoded, 0).unwrap(), order);
+
+        // Packed repeated scalars, unknown fields and unknown enum numbers.
+        let mut packed = Vec::new();
+        put_bytes(&mut packed, 6, &[3, 4]);
+        put_key(&mut packed, 3, 0);
+        put_varint(&mut packed, 9);
+        put_bytes(&mut packed, 99, b"x");
+        assert_eq!(descriptors.decode("shop.Order", &packed, 0).unwrap(), json!({ "quantities": [3, 4], "status": 9 }));
+    }
+
+    #[test]
+    fn test_framing_and_status_mapping() {
+        assert_eq!(unframe(&frame(b"abc")).unwrap(), b"abc");
+        assert!(unframe(&[1, 0, 0, 0, 0]).is_err());
+        assert!(unframe(&[0, 0, 0, 0, 9, 1]).is_err());
+        assert_eq!(http_status(0), StatusCode::OK);
+        assert_eq!(http_status(5), StatusCode::NOT_FOUND);
+        assert_eq!(http_status(16), StatusCode::UNAUTHORIZED);
+        assert_eq!(http_status(2), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn test_call_round_trips_through_a_grpc_upstream() {
+        let make_service = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|request: Request<Body>| async move {
+                assert_eq!(request.uri().path(), "/shop.Orders/GetOrder");
+                assert_eq!(request.headers()["x-tenant"], "acme");
+                assert!(request.headers().get("user-agent").is_none());
+                let descriptors = Descriptors::parse(&descriptor_set()).unwrap();
+                let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                let input = descriptors.decode("shop.GetOrderRequest", unframe(&body).unwrap(), 0).unwrap();
+
+                let (mut sender, body) = Body::channel();
+                let mut trailers = HeaderMap::new();
+                if input["orderId"] == "missing" {
+                    trailers.insert("grpc-status", "5".parse().unwrap());
+                    trailers.insert("grpc-message", "no%20such%20order".parse().unwrap());
+                } else {
+                    let mut order = Vec::new();
+                    put_bytes(&mut order, 1, input["orderId"].as_str().unwrap().as_bytes());
+                    sender.send_data(frame(&order)).await.unwrap();
+                    trailers.insert("grpc-status", "0".parse().unwrap());
+                }
+                sender.send_trailers(trailers).await.unwrap();
+                let mut response = Response::new(body);
+                response.headers_mut().insert("content-type", "application/grpc".parse().unwrap());
+                Ok::<_, Infallible>(response)
+            }))
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).http2_only(true).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        let grpc = route("call", &format!("http://{}", addr), "shop.Orders/GetOrder").unwrap();
+        let mut metadata = HeaderMap::new();
+        metadata.insert("x-tenant", "acme".parse().unwrap());
+        metadata.insert("user-agent", "curl".parse().unwrap());
+        let deadline = Duration::from_secs(5);
+
+        let params = Params::from([("order_id".to_string(), "o-9".to_string())]);
+        let response = grpc.call(&metadata, &params, "", b"", deadline).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(serde_json::from_slice::<Value>(&body).unwrap(), json!({ "orderId": "o-9" }));
+
+        let params = Params::from([("order_id".to_string(), "missing".to_string())]);
+        let response = grpc.call(&metadata, &params, "", b"", deadline).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(serde_json::from_slice::<Value>(&body).unwrap(), json!({ "code": 5, "message": "no such order" }));
+    }
+}
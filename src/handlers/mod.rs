@@ -1,23 +1,153 @@
 use std::convert::Infallible;
-use hyper::StatusCode;
-// use warp::Reply;
-use crate::errors::GatewayError;
+use std::time::Duration;
+use bytes::{Bytes, BytesMut};
+use hyper::body::HttpBody;
+use hyper::{Body, HeaderMap, Response, StatusCode, header::{ALLOW, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, ETAG, LOCATION, HeaderValue}};
+use warp::Reply;
+use crate::config::{ErrorPageConfig, LimitAction, ResponseLimitConfig};
+use crate::errors::{GatewayError, ERROR_CODE_HEADER};
 #[cfg(test)]
 mod tests;
 
 pub async fn handle_rejection(err: warp::Rejection) -> Result<impl warp::Reply, Infallible> {
+    let mut allow = None;
+    let mut error_code = None;
     let (code, message) = if err.is_not_found() {
         (StatusCode::NOT_FOUND, "Not Found")
+    } else if err.find::<warp::filters::body::BodyDeserializeError>().is_some() {
+        (StatusCode::BAD_REQUEST, "Invalid request body")
     } else if let Some(e) = err.find::<GatewayError>() {
-        match e {
-            GatewayError::RateLimitExceeded => (StatusCode::TOO_MANY_REQUESTS, "Rate limit exceeded"),
-            GatewayError::Timeout => (StatusCode::GATEWAY_TIMEOUT, "Gateway timeout"),
-            GatewayError::Unauthorized => (StatusCode::UNAUTHORIZED, "Unauthorized"),
-            _ => (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error"),
+        // Checked before warp's method rejections: a route for another
+        // method on the same path refuses with one, while this comes from
+        // the route that matched.
+        error_code = Some(e.code());
+        if let GatewayError::MethodNotAllowed(allowed) = e {
+            allow = Some(allowed.join(", "));
         }
+        let message = match e {
+            GatewayError::RateLimitExceeded => "Rate limit exceeded",
+            GatewayError::BulkheadFull(_) | GatewayError::UpstreamDraining(_) => "Service unavailable",
+            GatewayError::Timeout => "Gateway timeout",
+            GatewayError::UpstreamDns(_) | GatewayError::UpstreamConnect(_) | GatewayError::BadGateway(_) => "Bad gateway",
+            GatewayError::Unauthorized => "Unauthorized",
+            GatewayError::Forbidden(_) => "Forbidden",
+            GatewayError::Conflict(_) => "Conflict",
+            GatewayError::BadRequest(_) => "Bad request",
+            GatewayError::NotFound => "Not Found",
+            GatewayError::MethodNotAllowed(_) => "Method not allowed",
+            GatewayError::UnsupportedMediaType(_) => "Unsupported media type",
+            GatewayError::PayloadTooLarge(_) => "Payload too large",
+            GatewayError::InvalidUri(_) | GatewayError::Http(_) => "Internal server error",
+        };
+        (e.status(), message)
+    } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
+        (StatusCode::METHOD_NOT_ALLOWED, "Method not allowed")
     } else {
         (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error")
     };
 
-    Ok(warp::reply::with_status(message.to_string(), code))
-}
\ No newline at end of file
+    let mut response = warp::reply::with_status(message.to_string(), code).into_response();
+    if let Some(value) = allow.and_then(|a| HeaderValue::from_str(&a).ok()) {
+        response.headers_mut().insert(ALLOW, value);
+    }
+    if let Some(code) = error_code {
+        response.headers_mut().insert(ERROR_CODE_HEADER, HeaderValue::from_static(code));
+    }
+    Ok(response)
+}
+
+pub fn redirect_response(status: StatusCode, location: &str) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    if let Ok(value) = HeaderValue::from_str(location) {
+        response.headers_mut().insert(LOCATION, value);
+    }
+    response
+}
+
+pub fn direct_response(status: StatusCode, headers: &HeaderMap, body: &Bytes) -> Response<Body> {
+    let mut response = Response::new(Body::from(body.clone()));
+    *response.status_mut() = status;
+    *response.headers_mut() = headers.clone();
+    response
+}
+
+/// Buffers an upstream body, giving up after `deadline`. A stalled stream is
+/// dropped, which closes its connection instead of returning it to the pool.
+pub async fn read_body(body: Body, deadline: Duration) -> Result<Bytes, GatewayError> {
+    match tokio::time::timeout(deadline, hyper::body::to_bytes(body)).await {
+        Ok(result) => result.map_err(|e| GatewayError::upstream(&e)),
+        Err(_) => Err(GatewayError::Timeout),
+    }
+}
+
+/// Set on responses cut short by a route's `response_limit`.
+pub const TRUNCATED_HEADER: &str = "x-gateway-truncated";
+
+/// Like [`read_body`], but stops at `limit.max_bytes`. Returns the body and
+/// whether it was truncated; with `on_exceed: reject` an oversized body is a
+/// 502 instead, refused up front when its `Content-Length` is already over.
+pub async fn read_body_limited(mut body: Body, deadline: Duration, limit: &ResponseLimitConfig) -> Result<(Bytes, bool), GatewayError> {
+    let too_large = || GatewayError::BadGateway(format!("upstream response is over the {} byte limit", limit.max_bytes));
+    if limit.on_exceed == LimitAction::Reject && body.size_hint().lower() > limit.max_bytes as u64 {
+        return Err(too_large());
+    }
+    let reading = async {
+        let mut data = BytesMut::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.map_err(|e| GatewayError::upstream(&e))?;
+            if data.len() + chunk.len() > limit.max_bytes {
+                if limit.on_exceed == LimitAction::Reject {
+                    return Err(too_large());
+                }
+                // The rest is left unread; dropping the body closes the
+                // connection rather than draining it.
+                data.extend_from_slice(&chunk[..limit.max_bytes - data.len()]);
+                return Ok((data.freeze(), true));
+            }
+            data.extend_from_slice(&chunk);
+        }
+        Ok((data.freeze(), false))
+    };
+    tokio::time::timeout(deadline, reading).await.map_err(|_| GatewayError::Timeout)?
+}
+
+const DEFAULT_ERROR_PAGE: &str = r#"{"error":"upstream_error","status":{status},"request_id":"{request_id}"}"#;
+
+/// What clients get instead of an upstream's 5xx body. `{status}`,
+/// `{reason}`, `{route}` and `{request_id}` in the body are filled in.
+#[derive(Debug, Clone)]
+pub struct ErrorPage {
+    body: String,
+    content_type: HeaderValue,
+}
+
+impl ErrorPage {
+    pub fn from_config(config: &ErrorPageConfig) -> Result<Self, String> {
+        let content_type = HeaderValue::from_str(&config.content_type)
+            .map_err(|_| format!("content_type \"{}\" is not a valid header value", config.content_type))?;
+        Ok(Self {
+            body: config.body.clone().unwrap_or_else(|| DEFAULT_ERROR_PAGE.to_string()),
+            content_type,
+        })
+    }
+
+    /// Replaces the body of a 5xx response, returning the original for the
+    /// gateway's log. Other responses are left alone.
+    pub fn apply(&self, status: StatusCode, headers: &mut HeaderMap, body: &mut Bytes, route: &str, request_id: &str) -> Option<Bytes> {
+        if !status.is_server_error() {
+            return None;
+        }
+        let page = self.body
+            .replace("{status}", status.as_str())
+            .replace("{reason}", status.canonical_reason().unwrap_or(""))
+            .replace("{route}", route)
+            .replace("{request_id}", request_id);
+        for header in [CONTENT_LENGTH, CONTENT_ENCODING, ETAG] {
+            headers.remove(header);
+        }
+        headers.insert(CONTENT_TYPE, self.content_type.clone());
+        Some(std::mem::replace(body, Bytes::from(page)))
+    }
+}
+
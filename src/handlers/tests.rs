@@ -3,9 +3,10 @@ mod tests {
     // use super::*; I used this but it didnt work, that's why I've commented it out
     use warp::http::StatusCode;
     // use warp::reject::Rejection;
-    use crate::handlers::handle_rejection;
+    use crate::handlers::{direct_response, handle_rejection, read_body, read_body_limited, ErrorPage};
+    use crate::config::{ErrorPageConfig, LimitAction, ResponseLimitConfig};
     use crate::GatewayError;
-    use warp::Reply;
+    use warp::{Filter, Reply};
 
     #[tokio::test]
     async fn test_handle_not_found_rejection() {
@@ -35,10 +36,148 @@ mod tests {
         assert_eq!(response.into_response().status(), StatusCode::UNAUTHORIZED);
     }
 
+    #[tokio::test]
+    async fn test_handle_method_not_allowed_rejection() {
+        let filter = warp::post().map(warp::reply).recover(handle_rejection);
+        let response = warp::test::request().method("GET").reply(&filter).await;
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[tokio::test]
+    async fn test_handler_error_outranks_other_methods() {
+        let refuse = warp::put().and_then(|| async { Err::<String, _>(warp::reject::custom(GatewayError::BadRequest("no".to_string()))) });
+        let filter = warp::get().map(|| "ok".to_string()).or(refuse).unify().recover(handle_rejection);
+        let response = warp::test::request().method("PUT").reply(&filter).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = warp::test::request().method("DELETE").reply(&filter).await;
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[tokio::test]
+    async fn test_handle_route_method_not_allowed_rejection() {
+        let rejection = warp::reject::custom(GatewayError::MethodNotAllowed(vec!["GET".to_string(), "HEAD".to_string()]));
+        let response = handle_rejection(rejection).await.unwrap().into_response();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers().get("allow").unwrap(), "GET, HEAD");
+    }
+
     #[tokio::test]
     async fn test_handle_unknown_rejection() {
         let rejection = warp::reject::custom(GatewayError::Http("Unknown error".to_string()));
         let response = handle_rejection(rejection).await.unwrap();
         assert_eq!(response.into_response().status(), StatusCode::INTERNAL_SERVER_ERROR);
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_direct_response() {
+        let mut headers = hyper::HeaderMap::new();
+        headers.insert("content-type", "text/plain".parse().unwrap());
+        let response = direct_response(StatusCode::GONE, &headers, &bytes::Bytes::from("gone"));
+
+        assert_eq!(response.status(), StatusCode::GONE);
+        assert_eq!(response.headers()["content-type"], "text/plain");
+        assert_eq!(hyper::body::to_bytes(response.into_body()).await.unwrap(), "gone");
+    }
+
+    #[test]
+    fn test_error_page_replaces_server_errors() {
+        let page = ErrorPage::from_config(&ErrorPageConfig {
+            body: None,
+            content_type: "application/json".to_string(),
+        })
+        .unwrap();
+        let mut headers = hyper::HeaderMap::new();
+        headers.insert("content-type", "text/html".parse().unwrap());
+        headers.insert("content-length", "17".parse().unwrap());
+        let mut body = bytes::Bytes::from("Traceback (most…");
+
+        let original = page.apply(StatusCode::BAD_GATEWAY, &mut headers, &mut body, "orders", "req-1");
+        assert_eq!(original.unwrap(), "Traceback (most…");
+        assert_eq!(body, r#"{"error":"upstream_error","status":502,"request_id":"req-1"}"#);
+        assert_eq!(headers["content-type"], "application/json");
+        assert!(!headers.contains_key("content-length"));
+
+        let mut ok = bytes::Bytes::from("fine");
+        assert!(page.apply(StatusCode::NOT_FOUND, &mut headers, &mut ok, "orders", "req-1").is_none());
+        assert_eq!(ok, "fine");
+    }
+
+    #[test]
+    fn test_error_page_template() {
+        let page = ErrorPage::from_config(&ErrorPageConfig {
+            body: Some("{route} is unavailable ({status} {reason})".to_string()),
+            content_type: "text/plain".to_string(),
+        })
+        .unwrap();
+        let mut body = bytes::Bytes::new();
+        page.apply(StatusCode::SERVICE_UNAVAILABLE, &mut hyper::HeaderMap::new(), &mut body, "orders", "-");
+        assert_eq!(body, "orders is unavailable (503 Service Unavailable)");
+
+        let invalid = ErrorPageConfig { body: None, content_type: "text/plain\n".to_string() };
+        assert!(ErrorPage::from_config(&invalid).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_body_deadline() {
+        let (mut sender, body) = hyper::Body::channel();
+        tokio::spawn(async move {
+            sender.send_data(bytes::Bytes::from("partial")).await.unwrap();
+            // Stall with the stream left open.
+            tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+            drop(sender);
+        });
+        let result = read_body(body, std::time::Duration::from_millis(50)).await;
+        assert!(matches!(result, Err(GatewayError::Timeout)));
+
+        let body = hyper::Body::from("complete");
+        assert_eq!(read_body(body, std::time::Duration::from_secs(1)).await.unwrap(), "complete");
+    }
+
+    #[tokio::test]
+    async fn test_read_body_limited() {
+        let deadline = std::time::Duration::from_secs(1);
+        let chunked = || {
+            let chunks: Vec<Result<&'static str, std::io::Error>> = vec![Ok("0123"), Ok("4567"), Ok("89")];
+            hyper::Body::wrap_stream(futures::stream::iter(chunks))
+        };
+        let limit = |max_bytes, on_exceed| ResponseLimitConfig { max_bytes, on_exceed };
+
+        let (body, truncated) = read_body_limited(chunked(), deadline, &limit(10, LimitAction::Reject)).await.unwrap();
+        assert_eq!((&body[..], truncated), (&b"0123456789"[..], false));
+        let (body, truncated) = read_body_limited(chunked(), deadline, &limit(6, LimitAction::Truncate)).await.unwrap();
+        assert_eq!((&body[..], truncated), (&b"012345"[..], true));
+        let result = read_body_limited(chunked(), deadline, &limit(6, LimitAction::Reject)).await;
+        assert!(matches!(result, Err(GatewayError::BadGateway(_))));
+        // A known length over the limit is refused before reading.
+        let result = read_body_limited(hyper::Body::from("0123456789"), deadline, &limit(4, LimitAction::Reject)).await;
+        assert!(matches!(result, Err(GatewayError::BadGateway(message)) if message == "upstream response is over the 4 byte limit"));
+    }
+
+    #[tokio::test]
+    async fn test_upstream_errors_are_classified() {
+        let client = hyper::Client::new();
+        // Bind and drop to find a port nothing is listening on.
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let refused = client.get(format!("http://{}/", closed).parse().unwrap()).await.unwrap_err();
+        assert!(matches!(GatewayError::upstream(&refused), GatewayError::UpstreamConnect(_)));
+
+        let unresolved = client.get("http://upstream.invalid/".parse().unwrap()).await.unwrap_err();
+        assert!(matches!(GatewayError::upstream(&unresolved), GatewayError::UpstreamDns(_)));
+    }
+
+    #[tokio::test]
+    async fn test_handle_upstream_rejections() {
+        for (error, code) in [
+            (GatewayError::UpstreamDns("no such host".to_string()), "upstream_dns_failure"),
+            (GatewayError::UpstreamConnect("connection refused".to_string()), "upstream_connect_failure"),
+            (GatewayError::BadGateway("truncated".to_string()), "upstream_bad_response"),
+        ] {
+            let response = handle_rejection(warp::reject::custom(error)).await.unwrap().into_response();
+            assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+            assert_eq!(response.headers()["x-gateway-error"], code);
+        }
+
+        let response = handle_rejection(warp::reject::custom(GatewayError::Timeout)).await.unwrap().into_response();
+        assert_eq!(response.headers()["x-gateway-error"], "upstream_timeout");
+    }
+}
@@ -0,0 +1,297 @@
+use std::fmt::Write;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use dashmap::{DashMap, DashSet};
+use hyper::StatusCode;
+use serde_json::{json, Value};
+use crate::config::{FailureInjectionConfig, UpstreamHealthConfig};
+use crate::errors::GatewayError;
+use crate::experiments::random;
+use crate::metrics::escape;
+use crate::syslog;
+
+#[cfg(test)]
+mod tests;
+
+pub const UPSTREAM_HEALTHY: &str = "gateway_upstream_healthy";
+pub const UPSTREAM_MARKED_UNHEALTHY: &str = "gateway_upstream_marked_unhealthy_total";
+pub const FAILURES_INJECTED: &str = "gateway_failures_injected_total";
+pub const UPSTREAM_DRAINING_IN_FLIGHT: &str = "gateway_upstream_draining_in_flight";
+
+/// What one request showed about the endpoint it went to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Success,
+    ConnectFailure,
+    ServerError,
+}
+
+impl Outcome {
+    /// The outcome of a request the endpoint answered with `status`.
+    pub fn of_status(status: StatusCode) -> Self {
+        if status.is_server_error() {
+            Self::ServerError
+        } else {
+            Self::Success
+        }
+    }
+
+    /// The outcome of a request that failed with `error`, if it says anything
+    /// about the endpoint: only connections that couldn't be opened do.
+    pub fn of_error(error: &GatewayError) -> Option<Self> {
+        match error {
+            GatewayError::UpstreamConnect(_) | GatewayError::UpstreamDns(_) => Some(Self::ConnectFailure),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Healthy,
+    Unhealthy,
+}
+
+#[derive(Default)]
+struct Endpoint {
+    unhealthy: bool,
+    connect_failures: u32,
+    server_errors: u32,
+    successes: u32,
+    marked_for_connect_failures: u64,
+    marked_for_server_errors: u64,
+}
+
+/// Each upstream endpoint's health, as its traffic shows it. Failed
+/// connections and 5xx responses are counted in streaks that any other
+/// outcome ends; a long enough streak marks the endpoint unhealthy, and a
+/// streak of successes marks it healthy again.
+pub struct UpstreamHealth {
+    config: UpstreamHealthConfig,
+    endpoints: DashMap<String, Endpoint>,
+}
+
+impl UpstreamHealth {
+    pub fn new(config: &UpstreamHealthConfig) -> Self {
+        Self { config: config.clone(), endpoints: DashMap::new() }
+    }
+
+    /// Counts `outcome` for `endpoint`, returning its new status if this
+    /// changed it.
+    pub fn observe(&self, endpoint: &str, outcome: Outcome) -> Option<Status> {
+        let mut state = self.endpoints.entry(endpoint.to_string()).or_default();
+        match outcome {
+            Outcome::Success => {
+                state.connect_failures = 0;
+                state.server_errors = 0;
+                state.successes = state.successes.saturating_add(1);
+            }
+            Outcome::ConnectFailure => {
+                state.connect_failures = state.connect_failures.saturating_add(1);
+                state.server_errors = 0;
+                state.successes = 0;
+            }
+            Outcome::ServerError => {
+                state.server_errors = state.server_errors.saturating_add(1);
+                state.connect_failures = 0;
+                state.successes = 0;
+            }
+        }
+        if state.unhealthy {
+            if state.successes < self.config.healthy_after {
+                return None;
+            }
+            state.unhealthy = false;
+            println!("Upstream {} marked healthy after {} successful responses", endpoint, state.successes);
+            return Some(Status::Healthy);
+        }
+        let streak = if state.connect_failures >= self.config.connect_failures {
+            state.marked_for_connect_failures += 1;
+            format!("{} failed connections", state.connect_failures)
+        } else if state.server_errors >= self.config.server_errors {
+            state.marked_for_server_errors += 1;
+            format!("{} 5xx responses", state.server_errors)
+        } else {
+            return None;
+        };
+        state.unhealthy = true;
+        syslog::error(format_args!("Upstream {} marked unhealthy after {} in a row", endpoint, streak));
+        Some(Status::Unhealthy)
+    }
+
+    /// The status of `endpoint`; those without traffic yet are healthy.
+    pub fn status(&self, endpoint: &str) -> Status {
+        match self.endpoints.get(endpoint) {
+            Some(state) if state.unhealthy => Status::Unhealthy,
+            _ => Status::Healthy,
+        }
+    }
+
+    /// The Prometheus text exposition of each endpoint's status and how
+    /// often it was marked unhealthy.
+    pub fn render(&self) -> String {
+        let mut endpoints: Vec<_> = self.endpoints.iter().collect();
+        endpoints.sort_by(|a, b| a.key().cmp(b.key()));
+        let mut out = String::new();
+        let _ = writeln!(out, "# HELP {} Whether the upstream endpoint is healthy, judged by its traffic.", UPSTREAM_HEALTHY);
+        let _ = writeln!(out, "# TYPE {} gauge", UPSTREAM_HEALTHY);
+        for endpoint in &endpoints {
+            let _ = writeln!(out, "{}{{upstream=\"{}\"}} {}", UPSTREAM_HEALTHY, escape(endpoint.key()), u8::from(!endpoint.unhealthy));
+        }
+        let _ = writeln!(out, "# HELP {} Times the upstream endpoint was marked unhealthy, by the streak that did it.", UPSTREAM_MARKED_UNHEALTHY);
+        let _ = writeln!(out, "# TYPE {} counter", UPSTREAM_MARKED_UNHEALTHY);
+        for endpoint in &endpoints {
+            let upstream = escape(endpoint.key());
+            for (reason, count) in [("connect_failures", endpoint.marked_for_connect_failures), ("server_errors", endpoint.marked_for_server_errors)] {
+                let _ = writeln!(out, "{}{{upstream=\"{}\",reason=\"{}\"}} {}", UPSTREAM_MARKED_UNHEALTHY, upstream, reason, count);
+            }
+        }
+        out
+    }
+}
+
+/// Failure injection for resilience drills. While on, requests to healthy
+/// endpoints are excluded from them with probability `fraction` and fail as
+/// refused connections, which passive health checks and clients' retries
+/// then have to cope with. Endpoints already marked unhealthy are left
+/// alone.
+pub struct FailureInjection {
+    enabled: AtomicBool,
+    /// The fraction's `f64` bits.
+    fraction: AtomicU64,
+    injected: AtomicU64,
+}
+
+impl FailureInjection {
+    pub fn new(config: &FailureInjectionConfig) -> Self {
+        Self {
+            enabled: AtomicBool::new(config.enabled),
+            fraction: AtomicU64::new(config.fraction.to_bits()),
+            injected: AtomicU64::new(0),
+        }
+    }
+
+    /// Turns injection on or off, with a new fraction if given, returning
+    /// the new status.
+    pub fn set(&self, enabled: bool, fraction: Option<f64>) -> Result<Value, GatewayError> {
+        if let Some(fraction) = fraction {
+            if !(0.0..=1.0).contains(&fraction) {
+                return Err(GatewayError::BadRequest("fraction must be between 0 and 1".to_string()));
+            }
+            self.fraction.store(fraction.to_bits(), Ordering::Relaxed);
+        }
+        self.enabled.store(enabled, Ordering::Relaxed);
+        Ok(self.status())
+    }
+
+    pub fn status(&self) -> Value {
+        json!({
+            "enabled": self.enabled.load(Ordering::Relaxed),
+            "fraction": f64::from_bits(self.fraction.load(Ordering::Relaxed)),
+            "injected": self.injected.load(Ordering::Relaxed),
+        })
+    }
+
+    /// Whether to fail this request to `endpoint` rather than send it.
+    pub fn excludes(&self, endpoint: &str, health: Option<&UpstreamHealth>) -> bool {
+        if !self.enabled.load(Ordering::Relaxed) || health.is_some_and(|health| health.status(endpoint) == Status::Unhealthy) {
+            return false;
+        }
+        let fraction = f64::from_bits(self.fraction.load(Ordering::Relaxed));
+        let excluded = fraction >= 1.0 || (fraction > 0.0 && (random() as f64 / u64::MAX as f64) < fraction);
+        if excluded {
+            self.injected.fetch_add(1, Ordering::Relaxed);
+        }
+        excluded
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "# HELP {} Requests failed on purpose by failure injection.", FAILURES_INJECTED);
+        let _ = writeln!(out, "# TYPE {} counter", FAILURES_INJECTED);
+        let _ = writeln!(out, "{} {}", FAILURES_INJECTED, self.injected.load(Ordering::Relaxed));
+        out
+    }
+}
+
+/// Endpoints taken out of rotation for maintenance. New requests to a
+/// draining endpoint are refused while those already sent to it finish, and
+/// once none are left it reports drained. Every endpoint's requests are
+/// counted, so those sent before draining began are waited for too.
+#[derive(Default)]
+pub struct Draining {
+    in_flight: DashMap<String, Arc<AtomicUsize>>,
+    draining: DashSet<String>,
+}
+
+/// A request on its way to an endpoint, counted until dropped.
+pub struct InFlight(Arc<AtomicUsize>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Draining {
+    /// Counts a request to `endpoint` until the returned guard is dropped.
+    pub fn begin(&self, endpoint: &str) -> InFlight {
+        let count = match self.in_flight.get(endpoint) {
+            Some(count) => count.clone(),
+            None => self.in_flight.entry(endpoint.to_string()).or_default().clone(),
+        };
+        count.fetch_add(1, Ordering::Relaxed);
+        InFlight(count)
+    }
+
+    /// Whether new requests to `endpoint` are refused.
+    pub fn is_draining(&self, endpoint: &str) -> bool {
+        self.draining.contains(endpoint)
+    }
+
+    /// Starts draining `endpoint`, returning its status.
+    pub fn start(&self, endpoint: &str) -> Value {
+        self.draining.insert(endpoint.to_string());
+        self.endpoint_status(endpoint)
+    }
+
+    /// Puts `endpoint` back in rotation; false if it wasn't draining.
+    pub fn stop(&self, endpoint: &str) -> bool {
+        self.draining.remove(endpoint).is_some()
+    }
+
+    /// The status of `endpoint` if it is draining.
+    pub fn status_of(&self, endpoint: &str) -> Option<Value> {
+        self.is_draining(endpoint).then(|| self.endpoint_status(endpoint))
+    }
+
+    pub fn status(&self) -> Value {
+        let mut endpoints: Vec<String> = self.draining.iter().map(|endpoint| endpoint.clone()).collect();
+        endpoints.sort();
+        let draining: Vec<Value> = endpoints.iter().map(|endpoint| self.endpoint_status(endpoint)).collect();
+        json!({ "draining": draining })
+    }
+
+    fn in_flight(&self, endpoint: &str) -> usize {
+        self.in_flight.get(endpoint).map_or(0, |count| count.load(Ordering::Relaxed))
+    }
+
+    fn endpoint_status(&self, endpoint: &str) -> Value {
+        let in_flight = self.in_flight(endpoint);
+        json!({ "upstream": endpoint, "in_flight": in_flight, "drained": in_flight == 0 })
+    }
+
+    /// The Prometheus text exposition of the requests still in flight to
+    /// each draining endpoint.
+    pub fn render(&self) -> String {
+        let mut endpoints: Vec<String> = self.draining.iter().map(|endpoint| endpoint.clone()).collect();
+        endpoints.sort();
+        let mut out = String::new();
+        let _ = writeln!(out, "# HELP {} Requests still in flight to the draining upstream endpoint.", UPSTREAM_DRAINING_IN_FLIGHT);
+        let _ = writeln!(out, "# TYPE {} gauge", UPSTREAM_DRAINING_IN_FLIGHT);
+        for endpoint in &endpoints {
+            let _ = writeln!(out, "{}{{upstream=\"{}\"}} {}", UPSTREAM_DRAINING_IN_FLIGHT, escape(endpoint), self.in_flight(endpoint));
+        }
+        out
+    }
+}
@@ -0,0 +1,104 @@
+#[cfg(test)]
+mod tests {
+    use hyper::StatusCode;
+    use serde_json::json;
+    use crate::config::{FailureInjectionConfig, UpstreamHealthConfig};
+    use crate::errors::GatewayError;
+    use crate::health::{Draining, FailureInjection, Outcome, Status, UpstreamHealth};
+
+    const ENDPOINT: &str = "orders:8080";
+
+    fn health() -> UpstreamHealth {
+        UpstreamHealth::new(&UpstreamHealthConfig { connect_failures: 2, server_errors: 3, healthy_after: 2 })
+    }
+
+    #[test]
+    fn test_outcomes() {
+        assert_eq!(Outcome::of_status(StatusCode::NOT_FOUND), Outcome::Success);
+        assert_eq!(Outcome::of_status(StatusCode::SERVICE_UNAVAILABLE), Outcome::ServerError);
+        assert_eq!(Outcome::of_error(&GatewayError::UpstreamConnect("refused".to_string())), Some(Outcome::ConnectFailure));
+        assert_eq!(Outcome::of_error(&GatewayError::UpstreamDns("no such host".to_string())), Some(Outcome::ConnectFailure));
+        assert_eq!(Outcome::of_error(&GatewayError::Timeout), None);
+    }
+
+    #[test]
+    fn test_connect_failures_in_a_row_mark_an_endpoint_unhealthy() {
+        let health = health();
+        assert_eq!(health.observe(ENDPOINT, Outcome::ConnectFailure), None);
+        assert_eq!(health.observe(ENDPOINT, Outcome::Success), None);
+        assert_eq!(health.observe(ENDPOINT, Outcome::ConnectFailure), None);
+        assert_eq!(health.observe(ENDPOINT, Outcome::ConnectFailure), Some(Status::Unhealthy));
+        assert_eq!(health.status(ENDPOINT), Status::Unhealthy);
+        assert_eq!(health.status("users:8080"), Status::Healthy);
+    }
+
+    #[test]
+    fn test_server_errors_in_a_row_mark_an_endpoint_unhealthy() {
+        let health = health();
+        for _ in 0..2 {
+            assert_eq!(health.observe(ENDPOINT, Outcome::ServerError), None);
+        }
+        // A failed connection ends the streak of 5xx responses.
+        assert_eq!(health.observe(ENDPOINT, Outcome::ConnectFailure), None);
+        for _ in 0..2 {
+            assert_eq!(health.observe(ENDPOINT, Outcome::ServerError), None);
+        }
+        assert_eq!(health.observe(ENDPOINT, Outcome::ServerError), Some(Status::Unhealthy));
+        assert!(health.render().contains("gateway_upstream_marked_unhealthy_total{upstream=\"orders:8080\",reason=\"server_errors\"} 1\n"));
+    }
+
+    #[test]
+    fn test_successes_in_a_row_mark_an_endpoint_healthy_again() {
+        let health = health();
+        health.observe(ENDPOINT, Outcome::ConnectFailure);
+        health.observe(ENDPOINT, Outcome::ConnectFailure);
+        assert!(health.render().contains("gateway_upstream_healthy{upstream=\"orders:8080\"} 0\n"));
+        assert_eq!(health.observe(ENDPOINT, Outcome::Success), None);
+        assert_eq!(health.observe(ENDPOINT, Outcome::ServerError), None);
+        assert_eq!(health.observe(ENDPOINT, Outcome::Success), None);
+        assert_eq!(health.observe(ENDPOINT, Outcome::Success), Some(Status::Healthy));
+        let metrics = health.render();
+        assert!(metrics.contains("gateway_upstream_healthy{upstream=\"orders:8080\"} 1\n"));
+        assert!(metrics.contains("gateway_upstream_marked_unhealthy_total{upstream=\"orders:8080\",reason=\"connect_failures\"} 1\n"));
+    }
+
+    #[test]
+    fn test_failure_injection_spares_unhealthy_endpoints() {
+        let injection = FailureInjection::new(&FailureInjectionConfig { enabled: false, fraction: 1.0 });
+        assert!(!injection.excludes(ENDPOINT, None));
+
+        injection.set(true, None).unwrap();
+        let health = health();
+        assert!(injection.excludes(ENDPOINT, Some(&health)));
+        for _ in 0..2 {
+            health.observe(ENDPOINT, Outcome::ConnectFailure);
+        }
+        assert!(!injection.excludes(ENDPOINT, Some(&health)), "already out of rotation");
+        assert!(injection.render().contains("gateway_failures_injected_total 1\n"));
+
+        assert_eq!(injection.set(true, Some(0.0)).unwrap(), json!({ "enabled": true, "fraction": 0.0, "injected": 1 }));
+        assert!(!injection.excludes("users:8080", None));
+        assert!(matches!(injection.set(true, Some(2.0)), Err(GatewayError::BadRequest(_))));
+    }
+
+    #[test]
+    fn test_draining_waits_for_requests_in_flight() {
+        let draining = Draining::default();
+        let in_flight = draining.begin(ENDPOINT);
+        let other = draining.begin("users:8080");
+        assert!(!draining.is_draining(ENDPOINT));
+        assert_eq!(draining.status_of(ENDPOINT), None);
+
+        assert_eq!(draining.start(ENDPOINT), json!({ "upstream": ENDPOINT, "in_flight": 1, "drained": false }));
+        assert!(draining.is_draining(ENDPOINT));
+        assert!(!draining.is_draining("users:8080"));
+        assert!(draining.render().contains("gateway_upstream_draining_in_flight{upstream=\"orders:8080\"} 1\n"));
+
+        drop(in_flight);
+        drop(other);
+        assert_eq!(draining.status(), json!({ "draining": [{ "upstream": ENDPOINT, "in_flight": 0, "drained": true }] }));
+        assert!(draining.stop(ENDPOINT));
+        assert!(!draining.stop(ENDPOINT));
+        assert!(!draining.is_draining(ENDPOINT));
+    }
+}
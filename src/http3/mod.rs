@@ -0,0 +1,167 @@
+use std::convert::Infallible;
+use std::future::poll_fn;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use bytes::{Buf, Bytes};
+use h3::error::Code;
+use h3::server::RequestStream;
+use hyper::body::HttpBody;
+use hyper::header::{HeaderName, HeaderValue, HOST};
+use hyper::service::Service;
+use hyper::{Body, HeaderMap, Method, Request, Response, Version};
+use quinn::crypto::rustls::QuicServerConfig;
+use quinn::rustls;
+use crate::config::TlsConfig;
+use crate::tls::read_pem;
+
+#[cfg(test)]
+mod tests;
+
+/// The ALPN protocol of HTTP/3 (RFC 9114).
+pub const H3_ALPN: &[u8] = b"h3";
+/// Headers that only mean something to an HTTP/1.1 connection, which an
+/// HTTP/3 response must not carry.
+const CONNECTION_HEADERS: &[&str] = &["connection", "keep-alive", "proxy-connection", "transfer-encoding", "upgrade"];
+
+/// A QUIC endpoint on `addr` serving the default certificate in `config`.
+/// QUIC is always TLS 1.3 with the provider's suites, so `min_version`
+/// and `cipher_suites` don't apply, and neither do the SNI and ACME
+/// certificates.
+pub fn endpoint(config: &TlsConfig, addr: SocketAddr) -> Result<quinn::Endpoint, String> {
+    let (Some(cert), Some(key)) = (read_pem(&config.cert, &config.cert_path)?, read_pem(&config.key, &config.key_path)?) else {
+        return Err("HTTP/3 needs the default certificate and key".to_string());
+    };
+    let certs = rustls_pemfile::certs(&mut &*cert)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("reading certificate: {}", e))?;
+    let key = rustls_pemfile::private_key(&mut &*key)
+        .map_err(|e| format!("reading private key: {}", e))?
+        .ok_or_else(|| "no private key found".to_string())?;
+    let mut tls = rustls::ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(|e| format!("TLS settings: {}", e))?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("certificate: {}", e))?;
+    tls.alpn_protocols = vec![H3_ALPN.to_vec()];
+    let crypto = QuicServerConfig::try_from(tls).map_err(|e| format!("QUIC settings: {}", e))?;
+    quinn::Endpoint::server(quinn::ServerConfig::with_crypto(Arc::new(crypto)), addr).map_err(|e| format!("binding {}: {}", addr, e))
+}
+
+/// Serves HTTP/3 on `endpoint` through `pipeline`, the same routes the TCP
+/// listener serves. Each connection and each request gets its own task.
+pub async fn serve<S>(endpoint: quinn::Endpoint, pipeline: S)
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    while let Some(incoming) = endpoint.accept().await {
+        let pipeline = pipeline.clone();
+        tokio::spawn(async move {
+            let Ok(connection) = incoming.await else {
+                return;
+            };
+            let Ok(mut connection) = h3::server::Connection::<_, Bytes>::new(h3_quinn::Connection::new(connection)).await else {
+                return;
+            };
+            // Ends once the client goes away or the connection fails.
+            while let Ok(Some(resolver)) = connection.accept().await {
+                let pipeline = pipeline.clone();
+                tokio::spawn(async move {
+                    if let Ok((request, stream)) = resolver.resolve_request().await {
+                        respond(request, stream, pipeline).await;
+                    }
+                });
+            }
+        });
+    }
+}
+
+/// Runs one request through `pipeline` and streams the response back. The
+/// request body is fed to the pipeline as it arrives.
+async fn respond<S>(head: http1::Request<()>, stream: RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>, mut pipeline: S)
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible>,
+{
+    let (mut send, mut recv) = stream.split();
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        loop {
+            match recv.recv_data().await {
+                Ok(Some(mut chunk)) => {
+                    if sender.send_data(chunk.copy_to_bytes(chunk.remaining())).await.is_err() {
+                        return;
+                    }
+                }
+                Ok(None) => return,
+                Err(_) => return sender.abort(),
+            }
+        }
+    });
+    let Some(request) = request(head, body) else {
+        send.stop_stream(Code::H3_MESSAGE_ERROR);
+        return;
+    };
+    let Ok(()) = poll_fn(|cx| pipeline.poll_ready(cx)).await;
+    let Ok(response) = pipeline.call(request).await;
+    let (parts, mut body) = response.into_parts();
+    let mut head = http1::Response::new(());
+    *head.status_mut() = http1::StatusCode::from_u16(parts.status.as_u16()).expect("hyper statuses are valid");
+    *head.headers_mut() = headers(&parts.headers);
+    if send.send_response(head).await.is_err() {
+        return;
+    }
+    while let Some(chunk) = body.data().await {
+        let sent = match chunk {
+            Ok(chunk) => send.send_data(chunk).await.is_ok(),
+            Err(_) => false,
+        };
+        if !sent {
+            send.stop_stream(Code::H3_INTERNAL_ERROR);
+            return;
+        }
+    }
+    match body.trailers().await {
+        Ok(Some(trailers)) => {
+            if send.send_trailers(headers(&trailers)).await.is_err() {
+                return;
+            }
+        }
+        Ok(None) => {}
+        Err(_) => return send.stop_stream(Code::H3_INTERNAL_ERROR),
+    }
+    let _ = send.finish().await;
+}
+
+/// The request as the pipeline takes it, with a `Host` header from the
+/// `:authority` if the client sent none. `None` if a part can't be carried
+/// over.
+fn request(head: http1::Request<()>, body: Body) -> Option<Request<Body>> {
+    let mut request = Request::new(body);
+    *request.method_mut() = Method::from_bytes(head.method().as_str().as_bytes()).ok()?;
+    *request.uri_mut() = head.uri().to_string().parse().ok()?;
+    *request.version_mut() = Version::HTTP_3;
+    for (name, value) in head.headers() {
+        let name = HeaderName::from_bytes(name.as_str().as_bytes()).ok()?;
+        request.headers_mut().append(name, HeaderValue::from_bytes(value.as_bytes()).ok()?);
+    }
+    if let (false, Some(authority)) = (request.headers().contains_key(HOST), head.uri().authority()) {
+        request.headers_mut().insert(HOST, HeaderValue::from_str(authority.as_str()).ok()?);
+    }
+    Some(request)
+}
+
+/// Response headers or trailers for HTTP/3, without the connection-specific
+/// ones.
+fn headers(from: &HeaderMap) -> http1::HeaderMap {
+    let mut headers = http1::HeaderMap::with_capacity(from.len());
+    for (name, value) in from {
+        if CONNECTION_HEADERS.contains(&name.as_str()) {
+            continue;
+        }
+        if let (Ok(name), Ok(value)) = (http1::HeaderName::from_bytes(name.as_str().as_bytes()), http1::HeaderValue::from_bytes(value.as_bytes())) {
+            headers.append(name, value);
+        }
+    }
+    headers
+}
@@ -0,0 +1,69 @@
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::future::poll_fn;
+    use std::sync::Arc;
+    use bytes::{Buf, Bytes};
+    use hyper::service::service_fn;
+    use hyper::{Body, Request, Response};
+    use quinn::crypto::rustls::QuicClientConfig;
+    use quinn::rustls;
+    use quinn::rustls::pki_types::CertificateDer;
+    use ring::rand::SystemRandom;
+    use serde_json::json;
+    use crate::acme::{pem, self_signed};
+    use crate::config::TlsConfig;
+    use crate::http3::{endpoint, serve, H3_ALPN};
+
+    /// A QUIC client that trusts only `cert`.
+    fn client(cert: Vec<u8>) -> quinn::Endpoint {
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(CertificateDer::from(cert)).unwrap();
+        let mut tls = rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        tls.alpn_protocols = vec![H3_ALPN.to_vec()];
+        let mut client = quinn::Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        client.set_default_client_config(quinn::ClientConfig::new(Arc::new(QuicClientConfig::try_from(tls).unwrap())));
+        client
+    }
+
+    #[tokio::test]
+    async fn test_requests_are_served_over_quic() {
+        let (cert, key) = self_signed("gateway.example.com", Vec::new(), 30, &SystemRandom::new()).unwrap();
+        let config: TlsConfig = serde_json::from_value(json!({ "cert": pem("CERTIFICATE", &cert), "key": pem("PRIVATE KEY", &key) })).unwrap();
+        let server = endpoint(&config, "127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = server.local_addr().unwrap();
+        let pipeline = service_fn(|request: Request<Body>| async move {
+            let summary = format!("{} {} {:?} {}", request.method(), request.uri().path(), request.version(), request.headers()["host"].to_str().unwrap());
+            let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+            Ok::<_, Infallible>(Response::builder().header("connection", "close").header("x-summary", summary).body(Body::from(body)).unwrap())
+        });
+        tokio::spawn(serve(server, pipeline));
+
+        let quic_client = client(cert);
+        let connection = quic_client.connect(addr, "gateway.example.com").unwrap().await.unwrap();
+        let (mut driver, mut send_request) = h3::client::new(h3_quinn::Connection::new(connection)).await.unwrap();
+        tokio::spawn(async move { poll_fn(|cx| driver.poll_close(cx)).await });
+        let request = http1::Request::post("https://gateway.example.com/echo").body(()).unwrap();
+        let mut stream = send_request.send_request(request).await.unwrap();
+        stream.send_data(Bytes::from_static(b"hello")).await.unwrap();
+        stream.finish().await.unwrap();
+
+        let response = stream.recv_response().await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["x-summary"], "POST /echo HTTP/3.0 gateway.example.com");
+        assert!(!response.headers().contains_key("connection"));
+        let mut body = Vec::new();
+        while let Some(mut chunk) = stream.recv_data().await.unwrap() {
+            body.extend_from_slice(&chunk.copy_to_bytes(chunk.remaining()));
+        }
+        assert_eq!(body, b"hello");
+
+        let config: TlsConfig = serde_json::from_value(json!({})).unwrap();
+        let error = endpoint(&config, "127.0.0.1:0".parse().unwrap()).err().unwrap();
+        assert_eq!(error, "HTTP/3 needs the default certificate and key");
+    }
+}
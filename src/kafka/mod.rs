@@ -0,0 +1,90 @@
+use rdkafka::config::ClientConfig;
+use rdkafka::error::KafkaError;
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use crate::config::{KafkaSaslConfig, SaslMechanism, UpstreamTlsConfig};
+
+#[cfg(test)]
+mod tests;
+
+const CLIENT_ID: &str = "api-gateway";
+const MAX_TOPIC_LEN: usize = 249;
+
+/// Kafka's own rules for topic names.
+pub fn check_topic(topic: &str) -> Result<(), String> {
+    if topic.is_empty() || topic.len() > MAX_TOPIC_LEN || topic == "." || topic == ".." {
+        return Err(format!("\"{}\" is not a valid topic name", topic));
+    }
+    if !topic.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-')) {
+        return Err(format!("topic \"{}\" may only hold letters, digits, '.', '_' and '-'", topic));
+    }
+    Ok(())
+}
+
+/// The librdkafka settings for producing to `brokers`: over TLS with `tls`,
+/// logged in with `sasl`, and giving up on a record after `timeout_ms`.
+pub fn client_config(brokers: &[String], tls: Option<&UpstreamTlsConfig>, sasl: Option<&KafkaSaslConfig>, timeout_ms: u64) -> ClientConfig {
+    let mut config = ClientConfig::new();
+    let protocol = match (tls, sasl) {
+        (None, None) => "plaintext",
+        (Some(_), None) => "ssl",
+        (None, Some(_)) => "sasl_plaintext",
+        (Some(_), Some(_)) => "sasl_ssl",
+    };
+    config
+        .set("bootstrap.servers", brokers.join(","))
+        .set("client.id", CLIENT_ID)
+        .set("security.protocol", protocol)
+        // Records are acknowledged by the partition leader alone.
+        .set("acks", "1")
+        .set("message.timeout.ms", timeout_ms.to_string());
+    if let Some(tls) = tls {
+        let files = [("ssl.ca.location", &tls.ca_path), ("ssl.certificate.location", &tls.cert_path), ("ssl.key.location", &tls.key_path)];
+        for (key, path) in files {
+            if let Some(path) = path {
+                config.set(key, path.to_string_lossy());
+            }
+        }
+    }
+    if let Some(sasl) = sasl {
+        let mechanism = match sasl.mechanism {
+            SaslMechanism::Plain => "PLAIN",
+            SaslMechanism::ScramSha256 => "SCRAM-SHA-256",
+            SaslMechanism::ScramSha512 => "SCRAM-SHA-512",
+        };
+        config.set("sasl.mechanism", mechanism).set("sasl.username", &sasl.username).set("sasl.password", &sasl.password);
+    }
+    config
+}
+
+/// Produces to one topic through librdkafka, which finds the partition
+/// leaders, keeps the connections to them and spreads records without keys
+/// over the partitions.
+pub struct Producer {
+    producer: FutureProducer,
+    topic: String,
+}
+
+impl Producer {
+    pub fn new(config: &ClientConfig, topic: &str) -> Result<Self, String> {
+        let producer = config.create().map_err(|e| format!("kafka producer: {}", e))?;
+        Ok(Self { producer, topic: topic.to_string() })
+    }
+
+    /// Produces `records`, as values without keys stamped `timestamp_ms`, and
+    /// waits for all of them to be acknowledged.
+    pub async fn send(&self, records: &[Vec<u8>], timestamp_ms: i64) -> Result<(), KafkaError> {
+        let mut deliveries = Vec::with_capacity(records.len());
+        for record in records {
+            let record = FutureRecord::<(), _>::to(&self.topic).payload(record).timestamp(timestamp_ms);
+            deliveries.push(self.producer.send_result(record).map_err(|(e, _)| e)?);
+        }
+        for delivery in deliveries {
+            match delivery.await {
+                Ok(Ok(_)) => {}
+                Ok(Err((e, _))) => return Err(e),
+                Err(_) => return Err(KafkaError::Canceled),
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,37 @@
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use crate::config::{KafkaSaslConfig, SaslMechanism, UpstreamTlsConfig};
+    use crate::kafka::{check_topic, client_config};
+
+    #[test]
+    fn test_topic_names() {
+        assert!(check_topic("gateway.access-events_v1").is_ok());
+        assert!(check_topic("").is_err());
+        assert!(check_topic("..").is_err());
+        assert!(check_topic("access events").is_err());
+        assert!(check_topic(&"a".repeat(250)).is_err());
+    }
+
+    #[test]
+    fn test_client_configs() {
+        let brokers = ["kafka-1:9092".to_string(), "kafka-2:9092".to_string()];
+        let config = client_config(&brokers, None, None, 5000);
+        assert_eq!(config.get("bootstrap.servers"), Some("kafka-1:9092,kafka-2:9092"));
+        assert_eq!(config.get("security.protocol"), Some("plaintext"));
+        assert_eq!((config.get("acks"), config.get("message.timeout.ms")), (Some("1"), Some("5000")));
+        assert_eq!(config.get("sasl.mechanism"), None);
+
+        let tls = UpstreamTlsConfig { ca_path: Some(PathBuf::from("/etc/kafka/ca.pem")), ..UpstreamTlsConfig::default() };
+        let sasl = KafkaSaslConfig { mechanism: SaslMechanism::ScramSha512, username: "gateway".to_string(), password: "s3cret".to_string() };
+        let config = client_config(&brokers, Some(&tls), Some(&sasl), 5000);
+        assert_eq!(config.get("security.protocol"), Some("sasl_ssl"));
+        assert_eq!(config.get("ssl.ca.location"), Some("/etc/kafka/ca.pem"));
+        assert_eq!(config.get("ssl.certificate.location"), None);
+        assert_eq!(config.get("sasl.mechanism"), Some("SCRAM-SHA-512"));
+        assert_eq!((config.get("sasl.username"), config.get("sasl.password")), (Some("gateway"), Some("s3cret")));
+
+        assert_eq!(client_config(&brokers, Some(&tls), None, 5000).get("security.protocol"), Some("ssl"));
+        assert_eq!(client_config(&brokers, None, Some(&sasl), 5000).get("security.protocol"), Some("sasl_plaintext"));
+    }
+}
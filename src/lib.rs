@@ -1,9 +1,65 @@
+#![allow(clippy::module_inception)]
+
+pub mod acme;
+pub mod admin;
+pub mod admission;
+pub mod analytics;
+pub mod api_keys;
+pub mod audit;
+pub mod auth;
+pub mod aws;
+pub mod bulkheads;
+pub mod challenges;
+pub mod cluster;
 pub mod config;
+pub mod connector;
+pub mod correlation;
+pub mod database;
+pub mod debug_log;
+pub mod decompression;
+pub mod deployments;
+pub mod diagnostics;
+pub mod dns;
+pub mod egress;
 pub mod errors;
+pub mod events;
+pub mod experiments;
+pub mod fields;
+pub mod formats;
+pub mod grpc;
 pub mod handlers;
+pub mod health;
+pub mod http3;
+pub mod kafka;
+pub mod lockout;
+pub mod metrics;
 pub mod middleware;
 pub mod models;
+pub mod nats;
+pub mod ntlm;
+pub mod oauth;
+pub mod oidc;
+pub mod openapi;
+pub mod persistence;
+pub mod portal;
+pub mod redaction;
+pub mod redis;
+pub mod rewriting;
+pub mod routes;
+pub mod schedules;
+pub mod secrets;
+pub mod server;
 pub mod services;
+pub mod signing;
+pub mod soap;
+pub mod static_files;
+pub mod synthetic;
+pub mod syslog;
+pub mod tenants;
+pub mod tls;
+pub mod traces;
+pub mod upstream_auth;
+pub mod webhooks;
 
 pub use errors::GatewayError;
-pub use models::{AppState, CacheEntry, RateLimit};
\ No newline at end of file
+pub use models::{AppState, CacheEntry, CachedResponse, IdempotencyRecord, RateLimit};
\ No newline at end of file
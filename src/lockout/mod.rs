@@ -0,0 +1,160 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use dashmap::DashMap;
+use serde_json::{json, Value};
+use crate::config::LockoutConfig;
+
+#[cfg(test)]
+mod tests;
+
+pub const LOGIN_FAILURES: &str = "gateway_login_failures_total";
+pub const LOGIN_THROTTLED: &str = "gateway_login_throttled_total";
+pub const LOGIN_LOCKOUTS: &str = "gateway_login_lockouts_total";
+pub const LOCKED_IDENTITIES: &str = "gateway_login_locked_identities";
+
+struct Attempts {
+    failures: u32,
+    last_failure: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// Recent failed logins per identity. Identities with too many failures have
+/// to wait before their credentials are checked again, and at `max_failures`
+/// are locked out until `lockout_secs` have passed or an admin unlocks them.
+pub struct Lockouts {
+    config: LockoutConfig,
+    attempts: DashMap<String, Attempts>,
+    failures: AtomicU64,
+    throttled: AtomicU64,
+    lockouts: AtomicU64,
+}
+
+impl Lockouts {
+    pub fn new(config: &LockoutConfig) -> Self {
+        Self {
+            config: config.clone(),
+            attempts: DashMap::new(),
+            failures: AtomicU64::new(0),
+            throttled: AtomicU64::new(0),
+            lockouts: AtomicU64::new(0),
+        }
+    }
+
+    /// How long `identity` has to wait before its credentials may be checked
+    /// again. `None` if they may be checked now.
+    pub fn retry_after(&self, identity: &str) -> Option<Duration> {
+        let now = Instant::now();
+        self.forget_if_quiet(identity, now);
+        let until = self.blocked_until(self.attempts.get(identity)?.value())?;
+        let wait = until.checked_duration_since(now)?;
+        self.throttled.fetch_add(1, Ordering::Relaxed);
+        Some(wait)
+    }
+
+    /// Counts a failed login, locking `identity` out once it reaches
+    /// `max_failures`.
+    pub fn record_failure(&self, identity: &str) {
+        let now = Instant::now();
+        self.forget_if_quiet(identity, now);
+        self.failures.fetch_add(1, Ordering::Relaxed);
+        let mut attempts = self
+            .attempts
+            .entry(identity.to_string())
+            .or_insert(Attempts { failures: 0, last_failure: now, locked_until: None });
+        attempts.failures = attempts.failures.saturating_add(1);
+        attempts.last_failure = now;
+        if attempts.failures >= self.config.max_failures && attempts.locked_until.is_none() {
+            attempts.locked_until = Some(now + self.lockout());
+            self.lockouts.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Forgets the failures of an identity that just logged in.
+    pub fn record_success(&self, identity: &str) {
+        self.attempts.remove(identity);
+    }
+
+    /// Clears the failures and any lockout of `identity`. `false` if it had
+    /// none.
+    pub fn unlock(&self, identity: &str) -> bool {
+        let now = Instant::now();
+        self.forget_if_quiet(identity, now);
+        self.attempts.remove(identity).is_some()
+    }
+
+    /// Every identity with recent failures, as the admin API lists them.
+    pub fn list(&self) -> Vec<Value> {
+        let now = Instant::now();
+        let mut listed: Vec<(String, Value)> = self
+            .attempts
+            .iter()
+            .filter(|attempts| !self.is_quiet(attempts.value(), now))
+            .map(|attempts| {
+                let wait = self.blocked_until(attempts.value()).and_then(|until| until.checked_duration_since(now));
+                let entry = json!({
+                    "identity": attempts.key(),
+                    "failures": attempts.failures,
+                    "locked": attempts.locked_until.is_some_and(|until| until > now),
+                    "retry_after_secs": wait.map_or(0, whole_secs),
+                });
+                (attempts.key().clone(), entry)
+            })
+            .collect();
+        listed.sort_by(|a, b| a.0.cmp(&b.0));
+        listed.into_iter().map(|(_, entry)| entry).collect()
+    }
+
+    /// The Prometheus text exposition of the login counters.
+    pub fn render(&self) -> String {
+        let now = Instant::now();
+        let locked = self
+            .attempts
+            .iter()
+            .filter(|attempts| attempts.locked_until.is_some_and(|until| until > now))
+            .count();
+        let mut out = String::new();
+        let counters = [
+            (LOGIN_FAILURES, "Logins refused for a wrong secret.", self.failures.load(Ordering::Relaxed)),
+            (LOGIN_THROTTLED, "Logins refused without checking the secret because of earlier failures.", self.throttled.load(Ordering::Relaxed)),
+            (LOGIN_LOCKOUTS, "Identities locked out after max_failures.", self.lockouts.load(Ordering::Relaxed)),
+        ];
+        for (name, help, value) in counters {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+        let _ = writeln!(out, "# HELP {} Identities locked out right now.", LOCKED_IDENTITIES);
+        let _ = writeln!(out, "# TYPE {} gauge", LOCKED_IDENTITIES);
+        let _ = writeln!(out, "{} {}", LOCKED_IDENTITIES, locked);
+        out
+    }
+
+    fn lockout(&self) -> Duration {
+        Duration::from_secs(self.config.lockout_secs)
+    }
+
+    /// A lockout ends `lockout_secs` after the last failure, and so does the
+    /// memory of failures that never led to one.
+    fn is_quiet(&self, attempts: &Attempts, now: Instant) -> bool {
+        now >= attempts.last_failure + self.lockout()
+    }
+
+    fn forget_if_quiet(&self, identity: &str, now: Instant) {
+        self.attempts.remove_if(identity, |_, attempts| self.is_quiet(attempts, now));
+    }
+
+    fn blocked_until(&self, attempts: &Attempts) -> Option<Instant> {
+        if attempts.locked_until.is_some() {
+            return attempts.locked_until;
+        }
+        let over = attempts.failures.checked_sub(self.config.free_attempts).filter(|over| *over > 0)?;
+        let backoff = self.config.backoff_base_ms.saturating_mul(1u64 << (over - 1).min(32));
+        Some(attempts.last_failure + Duration::from_millis(backoff).min(self.lockout()))
+    }
+}
+
+/// `Retry-After` seconds, rounded up so a client never retries too early.
+pub fn whole_secs(wait: Duration) -> u64 {
+    wait.as_secs() + u64::from(wait.subsec_nanos() > 0)
+}
@@ -0,0 +1,62 @@
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use crate::config::LockoutConfig;
+    use crate::lockout::{whole_secs, Lockouts};
+
+    fn lockouts() -> Lockouts {
+        Lockouts::new(&LockoutConfig { free_attempts: 2, backoff_base_ms: 50, max_failures: 4, lockout_secs: 60 })
+    }
+
+    #[tokio::test]
+    async fn test_backoff_then_lockout() {
+        let lockouts = lockouts();
+        lockouts.record_failure("billing");
+        lockouts.record_failure("billing");
+        assert_eq!(lockouts.retry_after("billing"), None);
+
+        lockouts.record_failure("billing");
+        let wait = lockouts.retry_after("billing").unwrap();
+        assert!(wait <= Duration::from_millis(50));
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(lockouts.retry_after("billing"), None);
+
+        lockouts.record_failure("billing");
+        let wait = lockouts.retry_after("billing").unwrap();
+        assert!(wait > Duration::from_secs(59));
+        assert_eq!(lockouts.retry_after("reports"), None);
+
+        let listed = lockouts.list();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0]["identity"], "billing");
+        assert_eq!(listed[0]["failures"], 4);
+        assert_eq!(listed[0]["locked"], true);
+        assert_eq!(listed[0]["retry_after_secs"], 60);
+
+        let metrics = lockouts.render();
+        assert!(metrics.contains("gateway_login_failures_total 4\n"));
+        assert!(metrics.contains("gateway_login_throttled_total 2\n"));
+        assert!(metrics.contains("gateway_login_lockouts_total 1\n"));
+        assert!(metrics.contains("gateway_login_locked_identities 1\n"));
+
+        assert!(lockouts.unlock("billing"));
+        assert!(!lockouts.unlock("billing"));
+        assert_eq!(lockouts.retry_after("billing"), None);
+        assert!(lockouts.render().contains("gateway_login_locked_identities 0\n"));
+    }
+
+    #[test]
+    fn test_success_forgets_failures() {
+        let lockouts = lockouts();
+        for _ in 0..3 {
+            lockouts.record_failure("billing");
+        }
+        lockouts.record_success("billing");
+        assert!(lockouts.list().is_empty());
+        lockouts.record_failure("billing");
+        assert_eq!(lockouts.retry_after("billing"), None);
+
+        assert_eq!(whole_secs(Duration::from_millis(1001)), 2);
+        assert_eq!(whole_secs(Duration::from_secs(3)), 3);
+    }
+}
@@ -1,36 +1,631 @@
+// The combined warp filter of every endpoint nests deeper than the default allows.
+#![recursion_limit = "256"]
+
 use bytes::Bytes;
-use hyper::{Body, Client, Request, Response, Method, HeaderMap};
-use std::sync::Arc;
-use std::time::{Duration, SystemTime};
-use tokio::sync::RwLock;
+use hyper::{Body, Request, Response, Method, HeaderMap, StatusCode, Version};
+use hyper::body::HttpBody;
+use hyper::header::{HeaderName, HeaderValue, AGE, ALT_SVC, AUTHORIZATION, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, HOST, SET_COOKIE};
+use std::borrow::Cow;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
+use serde_json::{json, Map};
 use tokio::time::timeout;
-use warp::{Filter, http::Uri};
+use warp::{Filter, Reply, http::Uri};
 use api_gateway::{
     AppState,
+    CachedResponse,
     GatewayError,
-    config::{BACKEND_BASE, REQUEST_TIMEOUT_SECS, STRIP_PATH_PREFIX},
+    config::{has_errors, AcmeChallenge, AltService, ConfigSource, GatewayConfig, WebhookEvent, CONFIG_PATH_ENV},
     services::{
         check_rate_limit, 
+        check_tenant_rate_limit,
+        rate_limit_remaining,
+        tarpit,
+        add_strike,
+        Tarpit,
         get_cached_response, 
+        cache_key,
         cache_response, 
-        is_authenticated
+        cache_tenant_response,
+        take_cache_request,
+        CacheStatus,
+        CACHE_STATUS_HEADER,
+        idempotency_key,
+        claim_idempotency,
+        Idempotency,
+        evict_idle_rate_limits,
+        evict_expired_idempotency,
+        watch_cache_invalidations,
     },
-    middleware::add_cors_headers,
-    handlers::handle_rejection,
+    auth::Authenticator,
+    tenants::Tenants,
+    middleware::{add_cors_headers, add_timing_headers, preflight_method, preflight_response, add_via_header, apply_if_none_match, ensure_etag, strip_hop_by_hop_headers},
+    handlers::{direct_response, handle_rejection, read_body, read_body_limited, redirect_response, TRUNCATED_HEADER},
+    routes::{LiveRouteTable, Route, RouteAction, RouteRequest, RouteTable},
+    static_files,
+    server,
+    admin,
+    bulkheads::Bulkheads,
+    diagnostics,
+    audit::AuditLog,
+    metrics::{Connections, Metrics, Observation},
+    debug_log::DebugLog,
+    correlation::{Correlation, REQUEST_ID_HEADER},
+    challenges::{Challenges, CHALLENGE_HEADER, SOLUTION_HEADER},
+    rewriting::{request_origin, rewrite_location},
+    redaction::Redactor,
+    redis::RedisCache,
+    experiments::VARIANT_HEADER,
+    secrets::Secrets,
+    cluster::{ClusterSync, LeaderElection, RunningConfig},
+    api_keys::ApiKeyStore,
+    persistence::Persistence,
+    webhooks::Webhooks,
+    synthetic::SyntheticChecks,
+    openapi::{self, OpenApi},
+    portal::{self, Portal},
+    analytics::Analytics,
+    events::EventSink,
+    health::{Draining, FailureInjection, Outcome, Status, UpstreamHealth},
+    syslog::{self, Syslog},
+    oauth,
+    oidc::{self, Oidc},
+    acme::{self, Acme},
+    http3,
+    tls,
+    traces::Tracer,
+    connector::{UpstreamClient, UpstreamTls},
 };
 use std::convert::Infallible;
 
-#[tokio::main]
-async fn main() {
-    let state = Arc::new(RwLock::new(AppState::new()));
+/// Bytes of a sanitized upstream error body kept in the log.
+const MAX_LOGGED_ERROR_BODY: usize = 4096;
+
+struct CliArgs {
+    config_path: Option<PathBuf>,
+    config_from_env: bool,
+    check_config: bool,
+    check_upstreams: bool,
+}
+
+fn parse_args() -> CliArgs {
+    let mut args = CliArgs {
+        config_path: std::env::var_os(CONFIG_PATH_ENV).map(PathBuf::from),
+        config_from_env: std::env::var_os(CONFIG_PATH_ENV).is_some(),
+        check_config: false,
+        check_upstreams: false,
+    };
+
+    let mut iter = std::env::args().skip(1);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--config" => match iter.next() {
+                Some(path) => {
+                    args.config_path = Some(PathBuf::from(path));
+                    args.config_from_env = false;
+                }
+                None => {
+                    eprintln!("--config requires a path");
+                    process::exit(2);
+                }
+            },
+            "--check-config" => args.check_config = true,
+            "--check-upstreams" => args.check_upstreams = true,
+            other => {
+                eprintln!("Unknown argument: {}", other);
+                eprintln!("Usage: api-gateway [--config <path>] [--check-config [--check-upstreams]]");
+                process::exit(2);
+            }
+        }
+    }
+
+    args
+}
+
+fn load_config(args: &CliArgs) -> (GatewayConfig, Option<ConfigSource>) {
+    let Some(path) = &args.config_path else {
+        return (GatewayConfig::default(), None);
+    };
+    match GatewayConfig::load_with_source(path, args.config_from_env) {
+        Ok((config, source)) => (config, Some(source)),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Runs `--check-config`: prints every diagnostic and returns the exit code.
+async fn check_config(config: &GatewayConfig, check_upstreams: bool) -> i32 {
+    let mut diagnostics = config.validate();
+    if check_upstreams {
+        diagnostics.extend(config.probe_upstreams().await);
+    }
+
+    for diagnostic in &diagnostics {
+        eprintln!("{}", diagnostic);
+    }
+
+    if has_errors(&diagnostics) {
+        eprintln!("Configuration check failed");
+        1
+    } else {
+        println!("Configuration OK ({} routes)", config.routes.len());
+        0
+    }
+}
+
+async fn send_upstream(
+    client: &UpstreamClient,
+    method: &Method,
+    uri: &Uri,
+    headers: &HeaderMap,
+    body: Bytes,
+    route: &Route,
+    redactor: &Redactor,
+) -> Result<Response<Body>, warp::Rejection> {
+    let mut req = Request::builder()
+        .method(method.clone())
+        .uri(uri.clone())
+        .body(Body::from(body))
+        .map_err(|e| {
+            if route.logs_errors() {
+                syslog::error(format_args!("Error building request: {}", e));
+            }
+            warp::reject::custom(GatewayError::Http(e.to_string()))
+        })?;
+    *req.headers_mut() = headers.clone();
+
+    match timeout(route.timeout, client.request(req)).await {
+        Ok(result) => result.map_err(|e| {
+            let error = GatewayError::upstream(&e);
+            if route.logs_errors() {
+                syslog::error(format_args!("Error forwarding request to {}: {}", redactor.uri(&uri.to_string()), error));
+            }
+            warp::reject::custom(error)
+        }),
+        Err(_) => Err(warp::reject::custom(GatewayError::Timeout)),
+    }
+}
+
+/// Advertises the configured alternative services on a response.
+fn with_alt_svc(reply: impl Reply, alt_svc: Option<&HeaderValue>) -> Response<Body> {
+    let mut response = reply.into_response();
+    if let Some(alt_svc) = alt_svc {
+        response.headers_mut().insert(ALT_SVC, alt_svc.clone());
+    }
+    response
+}
+
+fn main() {
+    let args = parse_args();
+    let (config, config_source) = load_config(&args);
+    let runtime = match server::build_runtime(&config.runtime) {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            eprintln!("Failed to start runtime: {}", e);
+            process::exit(1);
+        }
+    };
+    runtime.block_on(run(args, config, config_source));
+}
+
+async fn run(args: CliArgs, config: GatewayConfig, config_source: Option<ConfigSource>) {
+    if args.check_config {
+        process::exit(check_config(&config, args.check_upstreams).await);
+    }
+
+    let diagnostics = config.validate();
+    for diagnostic in &diagnostics {
+        eprintln!("{}", diagnostic);
+    }
+    if has_errors(&diagnostics) {
+        process::exit(1);
+    }
+    if let Some(output) = &config.syslog {
+        match Syslog::start(output) {
+            Ok(output) => syslog::install(output),
+            Err(e) => {
+                eprintln!("Invalid syslog output: {}", e);
+                process::exit(1);
+            }
+        }
+    }
+
+    // `raw_config` keeps the secret references, for refreshing and for the
+    // admin API; everything else is built from their values.
+    let secrets = Arc::new(Secrets::new(&config.secrets));
+    let raw_config = config;
+    let config = match secrets.resolve(&raw_config).await {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Failed to fetch secrets: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let route_table = match RouteTable::from_config(&config) {
+        Ok(route_table) => Arc::new(LiveRouteTable::new(route_table)),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    let bulkheads = Arc::new(Bulkheads::from_config(&config.bulkheads));
+    let persistence = match &config.persistence {
+        Some(persistence) => match Persistence::open(persistence).await {
+            Ok(persistence) => Some(persistence),
+            Err(e) => {
+                eprintln!("Failed to open the database: {}", e);
+                process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let key_store = match &config.auth.key_store {
+        Some(key_store) => {
+            let opened = match &persistence {
+                Some(persistence) => ApiKeyStore::open_persisted(key_store, persistence.clone()).await,
+                None => ApiKeyStore::open(key_store).await,
+            };
+            match opened {
+                Ok(key_store) => Some(Arc::new(key_store)),
+                Err(e) => {
+                    eprintln!("Failed to open the API key store: {}", e);
+                    process::exit(1);
+                }
+            }
+        }
+        None => None,
+    };
+    let oidc = match config.auth.oidc.as_ref().map(Oidc::new).transpose() {
+        Ok(oidc) => oidc.map(Arc::new),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    let authenticator = match Authenticator::from_config(&config.auth) {
+        Ok(mut authenticator) => {
+            if let Some(key_store) = &key_store {
+                authenticator = authenticator.with_key_store(key_store.clone());
+            }
+            if let Some(oidc) = &oidc {
+                authenticator = authenticator.with_oidc(oidc.clone());
+            }
+            Arc::new(authenticator)
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    if !authenticator.watched_files().is_empty() {
+        let interval = Duration::from_secs(config.auth.reload_interval_secs);
+        tokio::spawn(authenticator.clone().watch(interval));
+    }
+    let running_config = Arc::new(RunningConfig::new(raw_config, config_source));
+    let webhooks = config.webhooks.as_ref().map(|webhooks| Arc::new(Webhooks::new(webhooks)));
+    if let Some(cluster) = &config.cluster {
+        let mut sync = ClusterSync::new(
+            cluster,
+            running_config.clone(),
+            secrets.clone(),
+            authenticator.clone(),
+            route_table.clone(),
+        );
+        if let Some(webhooks) = &webhooks {
+            sync = sync.with_webhooks(webhooks.clone());
+        }
+        if let Some(persistence) = &persistence {
+            sync = sync.with_persistence(persistence.clone());
+        }
+        let sync = Arc::new(sync);
+        let version = || running_config.version().map(|version| version.version).unwrap_or_default();
+        match sync.sync().await {
+            Ok(_) => println!("Applied cluster configuration version {}", version()),
+            Err(e) => match sync.restore().await {
+                Ok(true) => syslog::error(format_args!("Cluster configuration sync failed, starting with saved version {}: {}", version(), e)),
+                Ok(false) => syslog::error(format_args!("Cluster configuration sync failed, starting with the local configuration: {}", e)),
+                Err(restore) => syslog::error(format_args!(
+                    "Cluster configuration sync failed and the saved version can't be applied, starting with the local configuration: {}; {}",
+                    e, restore
+                )),
+            },
+        }
+        tokio::spawn(sync.watch());
+    }
+    if let Some(persistence) = &persistence {
+        if let Err(e) = persistence.restore_deployments(&route_table.load()).await {
+            eprintln!("Failed to restore deployment switches: {}", e);
+            process::exit(1);
+        }
+    }
+    // Cluster-wide jobs check `is_leader` so they run on one instance only:
+    // database snapshots, ACME orders and certificate expiry webhooks.
+    let leader = match config.cluster.as_ref().and_then(|cluster| Some((cluster, cluster.leader_election.as_ref()?))) {
+        Some((cluster, election)) => match LeaderElection::new(cluster, election) {
+            Ok(leader) => {
+                let leader = Arc::new(leader);
+                tokio::spawn(leader.clone().campaign());
+                Some(leader)
+            }
+            Err(e) => {
+                eprintln!("Failed to start leader election: {}", e);
+                process::exit(1);
+            }
+        },
+        None => None,
+    };
+    // A cluster configuration may bring in secret references later.
+    if (secrets.in_use().await || config.cluster.is_some()) && config.secrets.refresh_interval_secs > 0 {
+        let interval = Duration::from_secs(config.secrets.refresh_interval_secs);
+        tokio::spawn(secrets.clone().watch(running_config.clone(), interval, authenticator.clone(), route_table.clone(), webhooks.clone()));
+    }
+    let tenants = Arc::new(Tenants::from_config(&config.tenancy));
+    let admin_config = Arc::new(config.admin.clone());
+    let mut app_state = AppState::new();
+    app_state.max_rate_limit_buckets = config.rate_limiting.max_buckets;
+    app_state.tarpit = config.rate_limiting.tarpit.clone();
+    app_state.set_cache_limits(&config.cache);
+    if let Some(redis) = &config.cache.redis {
+        match RedisCache::new(redis) {
+            Ok(redis) => app_state.redis_cache = Some(Arc::new(redis)),
+            Err(e) => {
+                eprintln!("Invalid Redis cache: {}", e);
+                process::exit(1);
+            }
+        }
+    }
+    app_state.persistence = persistence.clone();
+    app_state.webhooks = webhooks.clone();
+    if let Some(persistence) = &persistence {
+        if let Err(e) = persistence.restore_quotas(&app_state).await {
+            eprintln!("Failed to restore tenant quotas: {}", e);
+            process::exit(1);
+        }
+    }
+    let state = Arc::new(app_state);
+    tokio::spawn(watch_cache_invalidations(state.clone()));
+    let analytics = config.analytics.as_ref().map(|analytics| Arc::new(Analytics::new(analytics)));
+    if let (Some(persistence), Some(analytics), Some(settings)) = (persistence.clone(), analytics.clone(), &config.analytics) {
+        if let Err(e) = persistence.restore_analytics(&analytics).await {
+            eprintln!("Failed to restore analytics: {}", e);
+            process::exit(1);
+        }
+        let leader = leader.clone();
+        let mut snapshot = tokio::time::interval(Duration::from_secs(settings.snapshot_secs));
+        tokio::spawn(async move {
+            loop {
+                snapshot.tick().await;
+                // Replicas share the rows, so only the leader writes them.
+                if leader.as_ref().is_none_or(|leader| leader.is_leader()) {
+                    persistence.snapshot_analytics(&analytics);
+                }
+            }
+        });
+    }
+    if let (Some(persistence), Some(settings)) = (persistence.clone(), &config.persistence) {
+        let (state, leader) = (state.clone(), leader.clone());
+        let mut snapshot = tokio::time::interval(Duration::from_secs(settings.quota_snapshot_secs));
+        tokio::spawn(async move {
+            loop {
+                snapshot.tick().await;
+                if leader.as_ref().is_none_or(|leader| leader.is_leader()) {
+                    persistence.snapshot_quotas(&state);
+                }
+            }
+        });
+    }
+    {
+        let (state, tenants) = (state.clone(), tenants.clone());
+        let mut sweep = tokio::time::interval(Duration::from_secs(config.rate_limiting.sweep_interval_secs));
+        tokio::spawn(async move {
+            loop {
+                sweep.tick().await;
+                evict_idle_rate_limits(&state, &tenants).await;
+                evict_expired_idempotency(&state);
+            }
+        });
+    }
+    let audit = match AuditLog::open(config.admin.audit_log.as_deref(), Redactor::new(&config.redaction)) {
+        Ok(audit) => match &persistence {
+            Some(persistence) => Arc::new(audit.with_persistence(persistence.clone())),
+            None => Arc::new(audit),
+        },
+        Err(e) => {
+            eprintln!("Failed to open audit log: {}", e);
+            process::exit(1);
+        }
+    };
+    let resolver = match config.tls.as_ref().map(tls::resolver).transpose() {
+        Ok(resolver) => resolver.map(Arc::new),
+        Err(e) => {
+            eprintln!("Failed to load the TLS certificates: {}", e);
+            process::exit(1);
+        }
+    };
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs();
+    let report = diagnostics::report(&config, running_config.view(), &route_table.load(), resolver.as_deref(), now).await;
+    println!("Startup diagnostics: {}", report);
+    let oidc_endpoints = oidc::routes(oidc.clone());
+    let token_endpoint = oauth::routes(config.auth.token_endpoint.clone(), authenticator.clone(), key_store.clone());
+    let connections = Arc::new(Connections::default());
+    let upstream_tls = match UpstreamTls::new(&config.upstream_connect.tls) {
+        Ok(upstream_tls) => Arc::new(upstream_tls),
+        Err(e) => {
+            eprintln!("Failed to load the upstream TLS certificates: {}", e);
+            process::exit(1);
+        }
+    };
+    if !upstream_tls.watched_files().is_empty() {
+        let interval = Duration::from_secs(config.upstream_connect.tls_reload_interval_secs);
+        tokio::spawn(upstream_tls.clone().watch(interval));
+    }
+    let client = match UpstreamClient::new(&config.upstream_connect, upstream_tls, connections.clone()) {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("Invalid upstream_connect settings: {}", e);
+            process::exit(1);
+        }
+    };
+    let openapi = config.openapi.as_ref().map(|openapi| Arc::new(OpenApi::new(openapi, client.clone())));
+    if let Some(openapi) = &openapi {
+        tokio::spawn(openapi.clone().watch(route_table.clone()));
+    }
+    let openapi_endpoints = openapi::routes(openapi);
+    let failure_injection = Arc::new(FailureInjection::new(&config.failure_injection));
+    let draining = Arc::new(Draining::default());
+    let admin_routes = admin::routes(
+        admin_config.clone(),
+        running_config,
+        route_table.clone(),
+        state.clone(),
+        tenants.clone(),
+        bulkheads.clone(),
+        audit,
+        key_store.clone(),
+        Arc::new(report),
+        analytics.clone(),
+        client.dns_cache(),
+        failure_injection.clone(),
+        draining.clone(),
+    );
+    let portal = config.portal.as_ref().map(|portal| Arc::new(Portal::new(portal)));
+    let portal_endpoints = portal::routes(portal.clone(), authenticator.clone(), tenants.clone(), state.clone());
     let state_filter = warp::any().map(move || state.clone());
-    let client = Client::new();
+    let tls = config.tls.is_some();
+    let cors = config.cors.clone();
 
     let health_check = warp::path("health")
         .and(warp::get())
         .map(|| "OK");
 
+    let redactor = Redactor::new(&config.redaction);
+    let correlation = Correlation::new(&config.correlation);
+    let challenges = config.challenge.clone().map(|challenge| Arc::new(Challenges::new(challenge)));
+    let debug_log = Arc::new(DebugLog::new(&config.debug_log, &config.admin.tokens, redactor.clone()));
+    let metrics = config.metrics.enabled.then(|| Arc::new(Metrics::from_config(&config.metrics)));
+    let synthetic = (!config.synthetic_checks.is_empty()).then(|| Arc::new(SyntheticChecks::new(&config.synthetic_checks)));
+    let upstream_health = config.upstream_health.as_ref().map(|health| Arc::new(UpstreamHealth::new(health)));
+    let dns_cache = client.dns_cache();
+    let events = match &config.events {
+        Some(events) => match EventSink::start(events) {
+            Ok(events) => Some(events),
+            Err(e) => {
+                eprintln!("Invalid event sink: {}", e);
+                process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let tracer = config.tracing.as_ref().map(Tracer::start);
+    // Without metrics enabled, /metrics is proxied like any other path.
+    let metrics_endpoint = warp::path("metrics")
+        .and(warp::get())
+        .and_then({
+            let (metrics, key_store, connections, leader, synthetic, events, tracer, upstream_health, failure_injection, draining, dns_cache, persistence) = (
+                metrics.clone(),
+                key_store.clone(),
+                connections.clone(),
+                leader.clone(),
+                synthetic.clone(),
+                events.clone(),
+                tracer.clone(),
+                upstream_health.clone(),
+                failure_injection.clone(),
+                draining.clone(),
+                dns_cache.clone(),
+                persistence.clone(),
+            );
+            move || {
+                let (metrics, key_store, connections, leader, synthetic, events, tracer, upstream_health, failure_injection, draining, dns_cache, persistence) = (
+                    metrics.clone(),
+                    key_store.clone(),
+                    connections.clone(),
+                    leader.clone(),
+                    synthetic.clone(),
+                    events.clone(),
+                    tracer.clone(),
+                    upstream_health.clone(),
+                    failure_injection.clone(),
+                    draining.clone(),
+                    dns_cache.clone(),
+                    persistence.clone(),
+                );
+                async move {
+                    let metrics = metrics.ok_or_else(warp::reject::not_found)?;
+                    let mut body = metrics.render();
+                    body.push_str(&connections.render());
+                    if let Some(lockouts) = key_store.as_deref().and_then(ApiKeyStore::lockouts) {
+                        body.push_str(&lockouts.render());
+                    }
+                    if let Some(leader) = &leader {
+                        body.push_str(&leader.render());
+                    }
+                    if let Some(synthetic) = &synthetic {
+                        body.push_str(&synthetic.render());
+                    }
+                    if let Some(events) = &events {
+                        body.push_str(&events.render());
+                    }
+                    if let Some(tracer) = &tracer {
+                        body.push_str(&tracer.render());
+                    }
+                    if let Some(upstream_health) = &upstream_health {
+                        body.push_str(&upstream_health.render());
+                    }
+                    body.push_str(&failure_injection.render());
+                    body.push_str(&draining.render());
+                    if let Some(dns_cache) = &dns_cache {
+                        body.push_str(&dns_cache.render());
+                    }
+                    if let Some(persistence) = &persistence {
+                        body.push_str(&persistence.render());
+                    }
+                    if let Some(output) = syslog::output() {
+                        body.push_str(&output.render());
+                    }
+                    Ok::<_, warp::Rejection>(warp::reply::with_header(
+                        body,
+                        CONTENT_TYPE,
+                        "text/plain; version=0.0.4",
+                    ))
+                }
+            }
+        });
+
+    // Refuses bodies a route doesn't accept before they are read.
+    let content_type_check = warp::method()
+        .and(warp::header::headers_cloned())
+        .and(warp::path::full())
+        .and(warp::query::raw().or_else(|_| async { Ok::<(String,), Infallible>((String::new(),)) }))
+        .and_then({
+            let (route_table, authenticator, tenants) = (route_table.clone(), authenticator.clone(), tenants.clone());
+            move |method: Method, headers: HeaderMap, full_path: warp::path::FullPath, query: String| {
+                let (route_table, authenticator, tenants) = (route_table.load(), authenticator.clone(), tenants.clone());
+                async move {
+                    if !route_table.checks_content_types() {
+                        return Ok::<_, warp::Rejection>(());
+                    }
+                    let identity = authenticator.identify(&headers).await;
+                    let (_, path) = tenants.resolve(&headers, full_path.as_str(), identity.as_ref());
+                    let request = RouteRequest { method: &method, path, query: &query, headers: &headers, body: None };
+                    let result = match route_table.find(&request) {
+                        Ok(route_match) => route_match.route.check_content_type(&headers),
+                        Err(_) => Ok(()),
+                    };
+                    result.map_err(warp::reject::custom)
+                }
+            }
+        })
+        .untuple_one();
+
     let proxy = warp::any()
+        .and(content_type_check)
         .and(warp::method())
         .and(warp::header::headers_cloned())
         .and(warp::path::full())
@@ -38,115 +633,679 @@ async fn main() {
         .and(warp::body::bytes())
         .and(state_filter)
         .and_then(move |method: Method,
-                       headers: HeaderMap,
+                       mut headers: HeaderMap,
                        full_path: warp::path::FullPath,
                        query: String,
-                       body: Bytes,
-                       state: Arc<RwLock<AppState>>| {
+                       mut body: Bytes,
+                       state: Arc<AppState>| {
             let client = client.clone();
-            async move {
+            let route_table = route_table.load();
+            let bulkheads = bulkheads.clone();
+            let admin_config = admin_config.clone();
+            let authenticator = authenticator.clone();
+            let tenants = tenants.clone();
+            let metrics = metrics.clone();
+            let upstream_health = upstream_health.clone();
+            let failure_injection = failure_injection.clone();
+            let draining = draining.clone();
+            let debug_log = debug_log.clone();
+            let redactor = redactor.clone();
+            let challenges = challenges.clone();
+            let oidc = oidc.clone();
+            let client_span = tracer.as_ref().and_then(|_| correlation.client_parent_id(&headers));
+            let request_id = correlation.apply(&mut headers);
+            let trace = tracer.as_ref().and_then(|tracer| tracer.start_trace(&mut headers, client_span));
+            let recorded_trace = trace.clone();
+            let tracer = tracer.clone();
+            // Whom the request was from and the route it matched, for the
+            // portal's usage figures, the analytics, the access events and
+            // the trace.
+            let key_holder = Arc::new(OnceLock::new());
+            let matched_route = Arc::new(OnceLock::new());
+            let matched_tags = Arc::new(OnceLock::new());
+            let (recorded_holder, recorded_route, recorded_method) = (key_holder.clone(), matched_route.clone(), method.clone());
+            let recorded_tags = matched_tags.clone();
+            let portal = portal.clone();
+            let analytics = analytics.clone();
+            let events = events.clone();
+            let tracking_routes = analytics.is_some() || events.is_some() || trace.is_some();
+            let tracking_holders = tracking_routes || portal.is_some();
+            let tracking_tags = events.is_some();
+            let received = Instant::now();
+            let (recorded_path, bytes_in) = (events.as_ref().map(|_| full_path.as_str().to_string()), body.len());
+            let debug_entry = debug_log.capture(&method, full_path.as_str(), &query, &mut headers, &body);
+            let handled = async move {
                 let start_time = SystemTime::now();
+                let admin_caller = admin::holds_admin_token(&headers, &admin_config);
+                let upstream_override = admin::take_upstream_override(&mut headers, &admin_config)
+                    .map_err(warp::reject::custom)?;
 
-                if !is_authenticated(&headers) {
+                let identity = authenticator.identify(&headers).await;
+                let cache_request = take_cache_request(&mut headers, state.cache_limits.control.as_ref(), admin_caller, identity.as_ref())
+                    .map_err(warp::reject::custom)?;
+                if let Some(identity) = identity.as_ref().filter(|_| tracking_holders) {
+                    let _ = key_holder.set(identity.user_id.clone());
+                }
+                let mut session_cookie = None;
+                if let Some(oidc) = &oidc {
+                    session_cookie = identity.as_ref().and_then(|_| oidc.session_cookie(&headers));
+                    oidc.strip_cookies(&mut headers);
+                }
+                let user_id = identity.as_ref().map(|identity| identity.user_id.as_str());
+                let (tenant, path) = tenants.resolve(&headers, full_path.as_str(), identity.as_ref());
+                let tenant_name = tenant.map(|tenant| tenant.name.as_str());
+
+                if let Some(requested) = cors.preflight.then(|| preflight_method(&method, &headers)).flatten() {
+                    let preflight = RouteRequest { method: &requested, path, query: &query, headers: &headers, body: None };
+                    if route_table.find(&preflight).is_ok() {
+                        return Ok(preflight_response(cors.max_age_secs));
+                    }
+                }
+
+                let route_request = RouteRequest {
+                    method: &method,
+                    path,
+                    query: &query,
+                    headers: &headers,
+                    body: Some(&body),
+                };
+                let route_match = route_table
+                    .find(&route_request)
+                    .map_err(warp::reject::custom)?;
+                if tracking_routes {
+                    let _ = matched_route.set(route_match.route.name.clone());
+                }
+                if tracking_tags {
+                    let _ = matched_tags.set(route_match.route.tags.clone());
+                }
+                if tenant.is_some_and(|tenant| !tenant.route_enabled(&route_match.route.name)) {
+                    return Err(warp::reject::custom(GatewayError::NotFound));
+                }
+                if let Some(schedule) = &route_match.route.schedule {
+                    if let Some(response) = schedule.closed_response(&route_match.route.name, start_time) {
+                        return Ok(response);
+                    }
+                }
+
+                if route_match.route.action.requires_auth() && identity.is_none() {
+                    if let Some(oidc) = oidc.as_ref().filter(|_| Oidc::wants_login(&method, &headers)) {
+                        let return_to = if query.is_empty() {
+                            full_path.as_str().to_string()
+                        } else {
+                            format!("{}?{}", full_path.as_str(), query)
+                        };
+                        return oidc.login(&return_to).await.map_err(warp::reject::custom);
+                    }
                     return Err(warp::reject::custom(GatewayError::Unauthorized));
                 }
+                if let (Some(oidc), Some(session)) = (&oidc, &session_cookie) {
+                    oidc.check_csrf(route_match.route.csrf, &method, &headers, session)
+                        .map_err(warp::reject::custom)?;
+                }
 
-                if !check_rate_limit(&state, &headers).await {
+                if let Some(challenges) = &challenges {
+                    if let Some(challenge) = challenges.check(&state, &headers).await.map_err(warp::reject::custom)? {
+                        return Ok(challenge);
+                    }
+                }
+                match tarpit(&state, &headers) {
+                    Tarpit::Pass => {}
+                    Tarpit::Delay(delay) => tokio::time::sleep(delay).await,
+                    Tarpit::Reject(delay) => {
+                        tokio::time::sleep(delay).await;
+                        add_strike(&state, &headers);
+                        return Err(warp::reject::custom(GatewayError::RateLimitExceeded));
+                    }
+                }
+                let within_limit = match tenant {
+                    Some(tenant) => check_tenant_rate_limit(&state, &headers, tenant).await,
+                    None => check_rate_limit(&state, &headers).await,
+                };
+                if let Some(trace) = &trace {
+                    trace.event("rate_limit", &[
+                        ("gateway.rate_limit.outcome", json!(if within_limit { "allowed" } else { "rejected" })),
+                        ("gateway.rate_limit.remaining", json!(rate_limit_remaining(&state, &headers, tenant))),
+                    ]);
+                }
+                if !within_limit {
+                    add_strike(&state, &headers);
                     return Err(warp::reject::custom(GatewayError::RateLimitExceeded));
                 }
 
-                let cache_key = format!("{}{}{}", method, full_path.as_str(), query);
-                if method == Method::GET {
-                    if let Some(response) = get_cached_response(&state, &cache_key).await {
+                let route = route_match.route;
+                // The upstream and the cache only see the query without the
+                // field selection, which is applied to every response.
+                let (query, fields) = match &route.field_filter {
+                    Some(filter) => filter.split(&query),
+                    None => (Cow::Borrowed(query.as_str()), None),
+                };
+                let _permit = bulkheads
+                    .acquire(route.bulkhead.as_deref(), route.priority)
+                    .await
+                    .map_err(warp::reject::custom)?;
+
+                if let Some((status, location)) = route_match.redirect(&route_request) {
+                    return Ok(redirect_response(status, &location));
+                }
+
+                if let RouteAction::Respond { status, headers, body } = &route_match.route.action {
+                    return Ok(direct_response(*status, headers, body));
+                }
+
+                if let RouteAction::Grpc(grpc) = &route_match.route.action {
+                    // The request headers aren't needed after the call.
+                    let mut metadata = std::mem::take(&mut headers);
+                    strip_hop_by_hop_headers(&mut metadata);
+                    authenticator.apply_claim_headers(&mut metadata, identity.as_ref());
+                    return grpc
+                        .call(&metadata, &route_match.params, &query, &body, route.timeout)
+                        .await
+                        .map_err(warp::reject::custom);
+                }
+
+                if let RouteAction::StaticFiles(files) = &route_match.route.action {
+                    let rel_path = route_match.remaining_path(path);
+                    return static_files::serve(files, rel_path, &method, &headers)
+                        .await
+                        .map_err(warp::reject::custom);
+                }
+
+                // Pinned requests are for debugging one instance: never served from
+                // or stored in the shared cache.
+                let assignment = route.experiment.as_ref().map(|experiment| experiment.assign(&headers, user_id));
+                let set_cookie = |response: &mut Response<Body>| {
+                    if let Some(cookie) = assignment.as_ref().and_then(|a| a.set_cookie.clone()) {
+                        response.headers_mut().append(SET_COOKIE, cookie);
+                    }
+                };
+
+                let cache_key = if method == Method::GET && upstream_override.is_none() {
+                    cache_key(route, &method, path, &query, tenant_name, user_id, &state.cache_limits.key)
+                        .map(|key| match &assignment {
+                            Some(assignment) => assignment.cache_key(key),
+                            None => key,
+                        })
+                } else {
+                    None
+                };
+                // Every caller is told what the cache did; trusted ones may
+                // have it skipped.
+                let cache_status = cache_key
+                    .as_ref()
+                    .map(|_| cache_request.map_or(CacheStatus::Miss, CacheStatus::before_lookup));
+                let cache_key = cache_key.filter(|_| cache_status != Some(CacheStatus::Bypass));
+                if let Some(cache_key) = cache_key.as_ref().filter(|_| cache_status != Some(CacheStatus::Refresh)) {
+                    if let Some(mut response) = get_cached_response(&state, cache_key).await {
+                        if let Some(fields) = &fields {
+                            response = fields.response(response).await;
+                        }
+                        if let Some(translator) = &route.translator {
+                            response = translator.response(&headers, response).await;
+                        }
+                        apply_if_none_match(&headers, &mut response);
+                        set_cookie(&mut response);
+                        response.headers_mut().insert(CACHE_STATUS_HEADER, CacheStatus::Hit.header_value());
+                        if let Some(trace) = &trace {
+                            trace.event("cache", &[("gateway.cache.status", json!(CacheStatus::Hit.as_str()))]);
+                        }
+                        if route.timing_headers {
+                            add_timing_headers(response.headers_mut(), start_time.elapsed().unwrap_or_default(), None);
+                        }
                         return Ok(response);
                     }
                 }
 
-                let mut path = full_path.as_str().to_string();
-                if path.starts_with(STRIP_PATH_PREFIX) {
-                    path = path[STRIP_PATH_PREFIX.len()..].to_string();
+                if let (Some(trace), Some(cache_status)) = (&trace, cache_status) {
+                    trace.event("cache", &[("gateway.cache.status", json!(cache_status.as_str()))]);
                 }
-                
-                let mut uri_str = format!("{}{}", BACKEND_BASE, path);
-                if !query.is_empty() {
-                    uri_str.push('?');
-                    uri_str.push_str(&query);
+
+                let idempotency = idempotency_key(route, &method, &headers, tenant_name, user_id, &body)
+                    .zip(route.idempotency_window)
+                    .map(|(key, window)| claim_idempotency(&state, &key, window));
+                let idempotency_claim = match idempotency {
+                    Some(Idempotency::Replay(mut response)) => {
+                        if let Some(fields) = &fields {
+                            response = fields.response(response).await;
+                        }
+                        if let Some(translator) = &route.translator {
+                            response = translator.response(&headers, response).await;
+                        }
+                        set_cookie(&mut response);
+                        return Ok(response);
+                    }
+                    Some(Idempotency::InFlight) => {
+                        return Err(warp::reject::custom(GatewayError::Conflict(
+                            "a request with this Idempotency-Key is in progress".to_string(),
+                        )));
+                    }
+                    Some(Idempotency::Claimed(claim)) => Some(claim),
+                    None => None,
+                };
+
+                let upstream = upstream_override
+                    .as_deref()
+                    .or_else(|| tenant.and_then(|tenant| tenant.upstream(&route.name)))
+                    .or_else(|| assignment.as_ref().map(|assignment| assignment.variant.upstream.as_str()))
+                    .or_else(|| route.deployment.as_ref().map(|deployment| deployment.upstream()));
+                let uri_str = match upstream {
+                    Some(upstream) => route
+                        .upstream()
+                        .map(|_| route_match.upstream_uri_at(upstream, path, &query)),
+                    None => route_match.upstream_uri(path, &query),
                 }
+                .ok_or_else(|| warp::reject::custom(GatewayError::Http("route has no upstream".to_string())))?;
 
-                let uri: Uri = uri_str.parse().map_err(|e: hyper::http::uri::InvalidUri| {
-                    eprintln!("Failed to parse URI {}: {}", uri_str, e);
+                // The `Uri` keeps the string's buffer rather than a copy.
+                let uri_str = Bytes::from(uri_str);
+                let uri = Uri::from_maybe_shared(uri_str.clone()).map_err(|e| {
+                    if route.logs_errors() {
+                        let uri_str = String::from_utf8_lossy(&uri_str);
+                        syslog::error(format_args!("Failed to parse URI {}: {}", redactor.uri(&uri_str), e));
+                    }
                     warp::reject::custom(GatewayError::InvalidUri(e.to_string()))
                 })?;
 
-                let mut req_builder = Request::builder()
-                    .method(method.clone())
-                    .uri(uri);
+                let mut forwarded_headers = headers.clone();
+                forwarded_headers.remove(HOST);
+                strip_hop_by_hop_headers(&mut forwarded_headers);
+                add_via_header(&mut forwarded_headers, Version::HTTP_11);
+                for (name, value) in route_match.request_headers() {
+                    if let (Ok(name), Ok(value)) = (HeaderName::from_bytes(name.as_bytes()), HeaderValue::from_str(&value)) {
+                        forwarded_headers.append(name, value);
+                    }
+                }
+                authenticator.apply_claim_headers(&mut forwarded_headers, identity.as_ref());
+                forwarded_headers.remove(VARIANT_HEADER);
+                forwarded_headers.remove(CHALLENGE_HEADER);
+                forwarded_headers.remove(SOLUTION_HEADER);
+                if let Some(variant) = assignment.as_ref().and_then(|a| a.header_value()) {
+                    forwarded_headers.insert(VARIANT_HEADER, variant);
+                }
+                if let Some(host) = route_match.upstream_host(headers.get(HOST)) {
+                    forwarded_headers.insert(HOST, host);
+                }
+                if let (true, Some(identity)) = (route.token_exchange, &identity) {
+                    let authorization = authenticator.mint(identity).map_err(warp::reject::custom)?;
+                    forwarded_headers.insert(AUTHORIZATION, authorization);
+                }
+                if let Some(upstream_auth) = &route.upstream_auth {
+                    let authorization = upstream_auth.authorization().await.map_err(warp::reject::custom)?;
+                    forwarded_headers.insert(AUTHORIZATION, authorization);
+                }
 
-                for (name, value) in headers.iter() {
-                    if name.as_str().to_lowercase() != "host" {
-                        req_builder = req_builder.header(name, value);
+                if let Some(decompressor) = &route.decompressor {
+                    if let Some(inflated) = decompressor.request(&headers, &body).map_err(warp::reject::custom)? {
+                        body = inflated;
+                        forwarded_headers.remove(CONTENT_ENCODING);
+                        forwarded_headers.remove(CONTENT_LENGTH);
                     }
                 }
 
-                let req = req_builder.body(Body::from(body)).map_err(|e| {
-                    eprintln!("Error building request: {}", e);
-                    warp::reject::custom(GatewayError::Http(e.to_string()))
-                })?;
+                if let Some(translator) = &route.translator {
+                    if let Some(json) = translator.request(&headers, &body).map_err(warp::reject::custom)? {
+                        body = json;
+                        forwarded_headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+                        forwarded_headers.remove(CONTENT_LENGTH);
+                    }
+                }
 
-                let response = match timeout(
-                    Duration::from_secs(REQUEST_TIMEOUT_SECS),
-                    client.request(req)
-                ).await {
-                    Ok(result) => result.map_err(|e| {
-                        eprintln!("Error forwarding request: {}", e);
-                        warp::reject::custom(GatewayError::Http(e.to_string()))
-                    })?,
-                    Err(_) => return Err(warp::reject::custom(GatewayError::Timeout)),
-                };
+                if let Some(signer) = &route.signing {
+                    let path_and_query = uri.path_and_query().map_or("/", |p| p.as_str());
+                    signer
+                        .sign(&mut forwarded_headers, &method, path_and_query, &body)
+                        .map_err(warp::reject::custom)?;
+                }
 
-                let (parts, body) = response.into_parts();
-                let body_bytes = hyper::body::to_bytes(body).await.map_err(|e| {
-                    eprintln!("Error reading response body: {}", e);
-                    warp::reject::custom(GatewayError::Http(e.to_string()))
-                })?;
+                let endpoint = uri.authority().map_or("", |authority| authority.as_str());
+                // Pinned requests still reach a draining endpoint, to check on it.
+                if upstream_override.is_none() && draining.is_draining(endpoint) {
+                    return Err(warp::reject::custom(GatewayError::UpstreamDraining(endpoint.to_string())));
+                }
+                // Pinned requests are for debugging, so drills leave them be.
+                let excluded = upstream_override.is_none() && failure_injection.excludes(endpoint, upstream_health.as_deref());
+                if let Some(trace) = &trace {
+                    let mut attributes = vec![("gateway.upstream", json!(endpoint))];
+                    if let Some(upstream_health) = &upstream_health {
+                        let health = match upstream_health.status(endpoint) {
+                            Status::Healthy => "healthy",
+                            Status::Unhealthy => "unhealthy",
+                        };
+                        attributes.push(("gateway.upstream.health", json!(health)));
+                    }
+                    if excluded {
+                        attributes.push(("gateway.upstream.excluded", json!(true)));
+                    }
+                    trace.event("upstream", &attributes);
+                }
+                let in_flight = draining.begin(endpoint);
+                let forwarded_at = Instant::now();
+                let exchange = async {
+                    if excluded {
+                        return Err(warp::reject::custom(GatewayError::UpstreamConnect("excluded by failure injection".to_string())));
+                    }
+                    let mut response = match route.upstream_auth.as_ref().filter(|auth| auth.per_connection()) {
+                        Some(upstream_auth) => {
+                            let sending = upstream_auth.send_on_connection(&client, &method, &uri, &forwarded_headers, body.clone());
+                            match timeout(route.timeout, sending).await {
+                                Ok(result) => result.map_err(|error| {
+                                    if route.logs_errors() {
+                                        syslog::error(format_args!("Error forwarding request to {}: {}", redactor.uri(&uri.to_string()), error));
+                                    }
+                                    warp::reject::custom(error)
+                                })?,
+                                Err(_) => return Err(warp::reject::custom(GatewayError::Timeout)),
+                            }
+                        }
+                        None => send_upstream(&client, &method, &uri, &forwarded_headers, body.clone(), route, &redactor).await?,
+                    };
+                    // A 401 for gateway-managed credentials usually means they were
+                    // rotated: fetch fresh ones and retry once.
+                    let refreshable_auth = route.upstream_auth.as_ref().filter(|auth| auth.refreshable());
+                    if let (StatusCode::UNAUTHORIZED, Some(upstream_auth)) = (response.status(), refreshable_auth) {
+                        let authorization = upstream_auth.refresh().await.map_err(warp::reject::custom)?;
+                        forwarded_headers.insert(AUTHORIZATION, authorization);
+                        response = send_upstream(&client, &method, &uri, &forwarded_headers, body, route, &redactor).await?;
+                    }
 
-                let mut response = Response::builder()
-                    .status(parts.status)
-                    .body(Body::from(body_bytes.clone())).unwrap();
-                
-                let headers = response.headers_mut();
-                for (name, value) in parts.headers.iter() {
-                    headers.insert(name, value.clone());
+                    let (mut parts, body) = response.into_parts();
+                    let read = match &route.response_limit {
+                        Some(limit) => read_body_limited(body, route.body_timeout, limit).await,
+                        None => read_body(body, route.body_timeout).await.map(|bytes| (bytes, false)),
+                    };
+                    let (body_bytes, truncated) = read.map_err(|e| {
+                        if route.logs_errors() {
+                            syslog::error(format_args!("Error reading response body from {}: {}", redactor.uri(&uri.to_string()), e));
+                        }
+                        warp::reject::custom(e)
+                    })?;
+                    if truncated {
+                        if route.logs_errors() {
+                            syslog::error(format_args!(
+                                "Truncated response from {} at {} bytes",
+                                redactor.uri(&uri.to_string()),
+                                body_bytes.len()
+                            ));
+                        }
+                        parts.headers.remove(CONTENT_LENGTH);
+                        parts.headers.insert(TRUNCATED_HEADER, HeaderValue::from_static("true"));
+                    }
+                    Ok::<_, warp::Rejection>((parts, body_bytes))
+                }
+                .await;
+                drop(in_flight);
+                let upstream_time = forwarded_at.elapsed();
+                if let Some(trace) = &trace {
+                    trace.event("upstream_response", &[("gateway.upstream.duration_ms", json!(upstream_time.as_millis() as u64))]);
+                }
+                if let Some(upstream_health) = &upstream_health {
+                    let outcome = match &exchange {
+                        Ok((parts, _)) => Some(Outcome::of_status(parts.status)),
+                        Err(rejection) => rejection.find::<GatewayError>().and_then(Outcome::of_error),
+                    };
+                    let status = outcome.and_then(|outcome| upstream_health.observe(endpoint, outcome));
+                    if let (Some(Status::Unhealthy), Some(webhooks)) = (status, &state.webhooks) {
+                        let text = format!("Upstream {} was marked unhealthy", endpoint);
+                        webhooks.notify(WebhookEvent::UpstreamUnhealthy, text, json!({ "upstream": endpoint }));
+                    }
+                }
+                if let Some(metrics) = &metrics {
+                    metrics.observe(&Observation {
+                        route: &route.name,
+                        method: &method,
+                        upstream: endpoint,
+                        status: match &exchange {
+                            Ok((parts, _)) => parts.status,
+                            Err(rejection) => rejection
+                                .find::<GatewayError>()
+                                .map_or(StatusCode::INTERNAL_SERVER_ERROR, GatewayError::status),
+                        },
+                        duration: upstream_time,
+                        tags: &route.tags,
+                    });
                 }
 
-                add_cors_headers(headers);
+                let (mut parts, mut body_bytes) = exchange?;
+                if let Some(error_page) = &route.error_page {
+                    let request_id = headers.get(REQUEST_ID_HEADER).and_then(|id| id.to_str().ok()).unwrap_or("-");
+                    let original = error_page.apply(parts.status, &mut parts.headers, &mut body_bytes, &route.name, request_id);
+                    if let Some(original) = original.filter(|_| route.logs_errors()) {
+                        syslog::error(format_args!(
+                            "Upstream {} answered {} request_id={}: {}",
+                            redactor.uri(&uri.to_string()),
+                            parts.status,
+                            request_id,
+                            redactor.body(&original, MAX_LOGGED_ERROR_BODY)
+                        ));
+                    }
+                }
+                if (route.rewrite_location && parts.status.is_redirection())
+                    || route.url_rewriter.is_some()
+                    || route.cookie_rewriter.is_some()
+                {
+                    // What the client sent before the path the route saw, such
+                    // as a tenant prefix, plus what the route strips.
+                    let routed_from = full_path.as_str().strip_suffix(path).unwrap_or(full_path.as_str());
+                    let prefix = format!("{}{}", routed_from, route_match.stripped_prefix(path));
+                    let upstream_base = upstream.or_else(|| route.upstream()).unwrap_or_default();
+                    let public_base = match &route.url_rewriter {
+                        Some(rewriter) => rewriter.public_base(&headers, tls),
+                        None => request_origin(&headers, tls),
+                    };
+                    if route.rewrite_location && parts.status.is_redirection() {
+                        rewrite_location(&mut parts.headers, upstream_base, public_base.as_deref(), &prefix);
+                    }
+                    if let (Some(rewriter), Some(base)) = (&route.url_rewriter, &public_base) {
+                        let external = format!("{}{}", base, prefix);
+                        rewriter.rewrite(upstream_base, &external, &mut parts.headers, &mut body_bytes);
+                    }
+                    if let Some(rewriter) = &route.cookie_rewriter {
+                        rewriter.rewrite(&mut parts.headers, &prefix);
+                    }
+                }
+                strip_hop_by_hop_headers(&mut parts.headers);
+                add_via_header(&mut parts.headers, parts.version);
+                if route.generate_etag && method == Method::GET && parts.status == StatusCode::OK {
+                    ensure_etag(&mut parts.headers, &body_bytes);
+                }
 
-                if method == Method::GET {
-                    cache_response(
-                        &state,
-                        &cache_key,
-                        (parts.status, parts.headers, body_bytes),
-                    ).await;
+                let stored = Arc::new(CachedResponse::new(parts.status, parts.headers, body_bytes));
+                let mut response = stored.to_response();
+                add_cors_headers(response.headers_mut());
+                // Stored whole and as JSON so cached entries serve every
+                // field selection and format.
+                if let Some(fields) = &fields {
+                    response = fields.response(response).await;
+                }
+                if let Some(translator) = &route.translator {
+                    response = translator.response(&headers, response).await;
                 }
 
-                if let Ok(duration) = start_time.elapsed() {
-                    println!(
-                        "{} {} {} {}ms",
+                if let Some(claim) = idempotency_claim {
+                    claim.complete(stored.clone());
+                }
+
+                // A truncated body is not the upstream's response, so it is
+                // never replayed to later clients.
+                let truncated = stored.headers.contains_key(TRUNCATED_HEADER);
+                if let Some(cache_key) = cache_key.as_ref().filter(|_| !truncated) {
+                    match tenant {
+                        Some(tenant) => cache_tenant_response(&state, tenant, cache_key, stored).await,
+                        None => cache_response(&state, cache_key, stored).await,
+                    }
+                }
+
+                apply_if_none_match(&headers, &mut response);
+                set_cookie(&mut response);
+                if let Some(cache_status) = cache_status {
+                    response.headers_mut().insert(CACHE_STATUS_HEADER, cache_status.header_value());
+                    // Straight from the upstream, so only as old as it says.
+                    if !response.headers().contains_key(AGE) {
+                        response.headers_mut().insert(AGE, HeaderValue::from_static("0"));
+                    }
+                }
+                if route.timing_headers {
+                    add_timing_headers(response.headers_mut(), start_time.elapsed().unwrap_or_default(), Some(upstream_time));
+                }
+
+                if let (true, Ok(duration)) = (route.logs_access(response.status()), start_time.elapsed()) {
+                    syslog::access(format_args!(
+                        "{} {} {} {}ms tenant={} variant={} request_id={}{}",
                         method,
                         full_path.as_str(),
                         response.status(),
-                        duration.as_millis()
-                    );
+                        duration.as_millis(),
+                        tenant_name.unwrap_or("-"),
+                        assignment.as_ref().map_or("-", |a| a.variant.name.as_str()),
+                        headers.get(REQUEST_ID_HEADER).and_then(|id| id.to_str().ok()).unwrap_or("-"),
+                        route.tags.iter().map(|(name, value)| format!(" {}={}", name, value)).collect::<String>()
+                    ));
                 }
 
                 Ok(response)
+            };
+            async move {
+                let result = match debug_entry {
+                    Some(entry) => debug_log.finish(entry, handled.await).await,
+                    None => handled.await,
+                };
+                // Errors are rendered here rather than by the final recover so
+                // they carry the request id too.
+                let mut response = match result {
+                    Ok(response) => response,
+                    Err(rejection) => {
+                        let Ok(reply) = handle_rejection(rejection).await;
+                        reply.into_response()
+                    }
+                };
+                if let Ok(value) = HeaderValue::from_str(&request_id) {
+                    response.headers_mut().insert(REQUEST_ID_HEADER, value);
+                }
+                if let (Some(tracer), Some(trace)) = (&tracer, &recorded_trace) {
+                    tracer.finish(trace, &recorded_method, recorded_route.get().map(String::as_str), response.status());
+                }
+                if let (Some(portal), Some(holder)) = (&portal, recorded_holder.get()) {
+                    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs();
+                    portal.usage.record(holder, response.status(), now);
+                }
+                if let Some(analytics) = &analytics {
+                    analytics.record(
+                        recorded_route.get().map(String::as_str),
+                        &recorded_method,
+                        recorded_holder.get().map(String::as_str),
+                        response.status(),
+                        received.elapsed(),
+                        SystemTime::now(),
+                    );
+                }
+                if let Some(events) = &events {
+                    events.publish(&json!({
+                        "timestamp_ms": SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_millis() as u64,
+                        "request_id": request_id,
+                        "method": recorded_method.as_str(),
+                        "path": recorded_path,
+                        "route": recorded_route.get(),
+                        "tags": recorded_tags.get().map(|tags| tags.iter().map(|(name, value)| (name.clone(), json!(value))).collect::<Map<_, _>>()),
+                        "key_holder": recorded_holder.get(),
+                        "status": response.status().as_u16(),
+                        "latency_ms": received.elapsed().as_millis() as u64,
+                        "bytes_in": bytes_in,
+                        "bytes_out": response.body().size_hint().exact(),
+                    }));
+                }
+                Ok::<_, warp::Rejection>(response)
             }
         });
 
+    // The gateway's own HTTP/3 listener is advertised ahead of any other
+    // alternative service.
+    let mut alt_services = Vec::new();
+    let mut quic = None;
+    if let Some((tls_config, http3_config)) = config.tls.as_ref().and_then(|tls_config| Some((tls_config, tls_config.http3.as_ref()?))) {
+        let addr = http3_config.listen_addr.as_ref().unwrap_or(&config.listen_addr);
+        let endpoint = match http3::endpoint(tls_config, addr.parse().expect("listen addresses were validated")) {
+            Ok(endpoint) => endpoint,
+            Err(e) => {
+                eprintln!("Failed to start the HTTP/3 listener: {}", e);
+                process::exit(1);
+            }
+        };
+        let addr = endpoint.local_addr().expect("the endpoint is bound");
+        alt_services.push(AltService { protocol: "h3".to_string(), host: None, port: addr.port(), max_age_secs: http3_config.max_age_secs });
+        quic = Some((endpoint, addr));
+    }
+    alt_services.extend(config.tls.iter().flat_map(|tls_config| tls_config.alt_svc.iter().cloned()));
+
     let routes = health_check
+        .or(metrics_endpoint)
+        .or(admin_routes)
+        .or(token_endpoint)
+        .or(oidc_endpoints)
+        .or(openapi_endpoints)
+        .or(portal_endpoints)
         .or(proxy)
-        .recover(handle_rejection);
+        .recover(handle_rejection)
+        .map({
+            let alt_svc = tls::alt_svc(&alt_services);
+            move |reply| with_alt_svc(reply, alt_svc.as_ref())
+        });
+    if let Some(synthetic) = &synthetic {
+        synthetic.start(warp::service(routes.clone()));
+    }
+    if let Some((endpoint, addr)) = quic {
+        println!("HTTP/3 (experimental) listening on udp://{}", addr);
+        tokio::spawn(http3::serve(endpoint, warp::service(routes.clone())));
+    }
 
-    println!("API Gateway running on http://127.0.0.1:3030");
-    warp::serve(routes).run(([127, 0, 0, 1], 3030)).await;
-}
\ No newline at end of file
+    let addr: SocketAddr = config.listen_addr.parse().expect("listen_addr was validated");
+    let listener = match server::bind(addr, &config.socket) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Failed to bind {}: {}", addr, e);
+            process::exit(1);
+        }
+    };
+    let accepted = server::tracked(server::incoming(listener, config.socket.tcp_nodelay), connections.clone());
+    match (&config.tls, resolver) {
+        (Some(tls_config), Some(resolver)) => {
+            if let Some(webhooks) = &webhooks {
+                tokio::spawn(webhooks.clone().watch_certificates(resolver.clone(), leader.clone()));
+            }
+            if let Some(acme_config) = &tls_config.acme {
+                let acme = match Acme::new(acme_config, resolver.clone()) {
+                    Ok(acme) => Arc::new(acme),
+                    Err(e) => {
+                        eprintln!("Failed to set up ACME: {}", e);
+                        process::exit(1);
+                    }
+                };
+                if acme_config.challenge == AcmeChallenge::Http01 {
+                    let http_addr: SocketAddr = acme_config.http_listen_addr.parse().expect("http_listen_addr was validated");
+                    let http_listener = match server::bind(http_addr, &config.socket) {
+                        Ok(listener) => listener,
+                        Err(e) => {
+                            eprintln!("Failed to bind {}: {}", http_addr, e);
+                            process::exit(1);
+                        }
+                    };
+                    let challenges = warp::serve(acme::routes(Some(acme.clone())));
+                    tokio::spawn(challenges.run_incoming(server::incoming(http_listener, config.socket.tcp_nodelay)));
+                }
+                tokio::spawn(acme.watch(leader.clone()));
+            }
+            let acme_alpn = tls_config.acme.as_ref().is_some_and(|acme| acme.challenge == AcmeChallenge::TlsAlpn01);
+            let server_config = match tls::server_config(tls_config, resolver, acme_alpn) {
+                Ok(server_config) => server_config,
+                Err(e) => {
+                    eprintln!("Failed to configure TLS: {}", e);
+                    process::exit(1);
+                }
+            };
+            println!("API Gateway running on https://{}", addr);
+            warp::serve(routes).run_incoming(tls::incoming(accepted, server_config, connections)).await;
+        }
+        _ => {
+            println!("API Gateway running on http://{}", addr);
+            warp::serve(routes).run_incoming(accepted).await;
+        }
+    }
+}
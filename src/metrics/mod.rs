@@ -0,0 +1,246 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use dashmap::DashMap;
+use hyper::{Method, StatusCode};
+use crate::config::MetricsConfig;
+
+#[cfg(test)]
+mod tests;
+
+pub const UPSTREAM_LATENCY: &str = "gateway_upstream_request_duration_seconds";
+pub const SERIES_OVERFLOW: &str = "gateway_metrics_series_overflow_total";
+/// Label value used for every label once `max_series` is reached.
+pub const OVERFLOW_LABEL: &str = "other";
+pub const CONNECTIONS_ACCEPTED: &str = "gateway_connections_accepted_total";
+pub const CONNECTIONS_ACTIVE: &str = "gateway_connections_active";
+pub const TLS_HANDSHAKE_FAILURES: &str = "gateway_tls_handshake_failures_total";
+pub const TLS_CONNECTIONS: &str = "gateway_tls_connections_total";
+pub const CONNECTION_RTT: &str = "gateway_connection_rtt_seconds";
+pub const CONNECTION_RETRANSMITS: &str = "gateway_connection_retransmits_total";
+pub const UPSTREAM_CONNECTIONS: &str = "gateway_upstream_connections_opened_total";
+pub const UPSTREAM_REQUESTS: &str = "gateway_upstream_requests_total";
+
+/// One finished upstream exchange. `status` is what the client got, so a
+/// refused connection counts as 502 and a stalled one as 504.
+pub struct Observation<'a> {
+    pub route: &'a str,
+    pub method: &'a Method,
+    pub upstream: &'a str,
+    pub status: StatusCode,
+    pub duration: Duration,
+    /// The route's tags, for labels naming one.
+    pub tags: &'a [(String, String)],
+}
+
+struct Histogram {
+    /// Non-cumulative counts per bucket, with a final slot for `+Inf`.
+    counts: Vec<AtomicU64>,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn new(buckets: usize) -> Self {
+        Self {
+            counts: (0..=buckets).map(|_| AtomicU64::new(0)).collect(),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Upstream latency histograms keyed by the configured labels.
+pub struct Metrics {
+    buckets: Vec<f64>,
+    labels: Vec<String>,
+    max_series: usize,
+    series: DashMap<Vec<String>, Histogram>,
+    overflow: AtomicU64,
+}
+
+impl Metrics {
+    pub fn from_config(config: &MetricsConfig) -> Self {
+        Self {
+            buckets: config.buckets.clone(),
+            labels: config.labels.clone(),
+            max_series: config.max_series,
+            series: DashMap::new(),
+            overflow: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, observation: &Observation) {
+        let mut key: Vec<String> = self.labels.iter().map(|label| label_value(label, observation)).collect();
+        if !self.series.contains_key(&key) && self.series.len() >= self.max_series {
+            self.overflow.fetch_add(1, Ordering::Relaxed);
+            key.iter_mut().for_each(|value| *value = OVERFLOW_LABEL.to_string());
+        }
+        let seconds = observation.duration.as_secs_f64();
+        let bucket = self.buckets.iter().position(|&bound| seconds <= bound).unwrap_or(self.buckets.len());
+        let histogram = self.series.entry(key).or_insert_with(|| Histogram::new(self.buckets.len()));
+        histogram.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        histogram
+            .sum_micros
+            .fetch_add(observation.duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// The Prometheus text exposition of every series, sorted by labels.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "# HELP {} Time from forwarding a request to reading the upstream's body.", UPSTREAM_LATENCY);
+        let _ = writeln!(out, "# TYPE {} histogram", UPSTREAM_LATENCY);
+
+        let mut series: Vec<_> = self.series.iter().collect();
+        series.sort_by(|a, b| a.key().cmp(b.key()));
+        for entry in series {
+            let labels: Vec<String> = self
+                .labels
+                .iter()
+                .zip(entry.key())
+                .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+                .collect();
+            let labels = labels.join(",");
+            let with_le = |le: &str| {
+                let le = format!("le=\"{}\"", le);
+                if labels.is_empty() { le } else { format!("{},{}", labels, le) }
+            };
+
+            let histogram = entry.value();
+            let mut cumulative = 0;
+            for (i, count) in histogram.counts.iter().enumerate() {
+                cumulative += count.load(Ordering::Relaxed);
+                let le = self.buckets.get(i).map_or("+Inf".to_string(), |bound| bound.to_string());
+                let _ = writeln!(out, "{}_bucket{{{}}} {}", UPSTREAM_LATENCY, with_le(&le), cumulative);
+            }
+            let labels = if labels.is_empty() { labels } else { format!("{{{}}}", labels) };
+            let sum = histogram.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+            let _ = writeln!(out, "{}_sum{} {}", UPSTREAM_LATENCY, labels, sum);
+            let _ = writeln!(out, "{}_count{} {}", UPSTREAM_LATENCY, labels, cumulative);
+        }
+
+        let _ = writeln!(out, "# HELP {} Observations recorded under \"{}\" because max_series was reached.", SERIES_OVERFLOW, OVERFLOW_LABEL);
+        let _ = writeln!(out, "# TYPE {} counter", SERIES_OVERFLOW);
+        let _ = writeln!(out, "{} {}", SERIES_OVERFLOW, self.overflow.load(Ordering::Relaxed));
+        out
+    }
+}
+
+fn label_value(label: &str, observation: &Observation) -> String {
+    match label {
+        "route" => observation.route.to_string(),
+        "method" => observation.method.to_string(),
+        "upstream" => observation.upstream.to_string(),
+        "status" => observation.status.as_u16().to_string(),
+        "status_class" => format!("{}xx", observation.status.as_u16() / 100),
+        tag => observation.tags.iter().find(|(name, _)| name == tag).map_or(String::new(), |(_, value)| value.clone()),
+    }
+}
+
+/// `value` as a Prometheus label value.
+pub fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Counters for the listener's connections and the upstream connection
+/// pool. Comparing `UPSTREAM_CONNECTIONS` with `UPSTREAM_REQUESTS` shows how
+/// often a request reused a pooled connection.
+#[derive(Default)]
+pub struct Connections {
+    accepted: AtomicU64,
+    active: AtomicU64,
+    handshake_timeouts: AtomicU64,
+    handshake_errors: AtomicU64,
+    protocols: DashMap<String, AtomicU64>,
+    rtt_micros: AtomicU64,
+    rtt_samples: AtomicU64,
+    retransmits: AtomicU64,
+    upstream_connections: DashMap<String, AtomicU64>,
+    upstream_requests: DashMap<String, AtomicU64>,
+}
+
+impl Connections {
+    pub fn opened(&self) {
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+        self.active.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a closed connection, with its smoothed round-trip time and
+    /// retransmitted segments where the OS reports them.
+    pub fn closed(&self, tcp_info: Option<(Duration, u32)>) {
+        self.active.fetch_sub(1, Ordering::Relaxed);
+        if let Some((rtt, retransmits)) = tcp_info {
+            self.rtt_micros.fetch_add(rtt.as_micros() as u64, Ordering::Relaxed);
+            self.rtt_samples.fetch_add(1, Ordering::Relaxed);
+            self.retransmits.fetch_add(u64::from(retransmits), Ordering::Relaxed);
+        }
+    }
+
+    pub fn handshake_failed(&self, timed_out: bool) {
+        let counter = if timed_out { &self.handshake_timeouts } else { &self.handshake_errors };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a finished handshake by the protocol agreed over ALPN.
+    pub fn negotiated(&self, alpn: Option<&[u8]>) {
+        let protocol = alpn.map_or("none".to_string(), |alpn| String::from_utf8_lossy(alpn).into_owned());
+        increment(&self.protocols, protocol);
+    }
+
+    pub fn upstream_connected(&self, upstream: &str) {
+        increment(&self.upstream_connections, upstream.to_string());
+    }
+
+    pub fn upstream_request(&self, upstream: &str) {
+        increment(&self.upstream_requests, upstream.to_string());
+    }
+
+    /// The Prometheus text exposition of the connection counters.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let _ = writeln!(out, "# HELP {} Connections accepted by the listener.", CONNECTIONS_ACCEPTED);
+        let _ = writeln!(out, "# TYPE {} counter", CONNECTIONS_ACCEPTED);
+        let _ = writeln!(out, "{} {}", CONNECTIONS_ACCEPTED, load(&self.accepted));
+        let _ = writeln!(out, "# HELP {} Listener connections open right now.", CONNECTIONS_ACTIVE);
+        let _ = writeln!(out, "# TYPE {} gauge", CONNECTIONS_ACTIVE);
+        let _ = writeln!(out, "{} {}", CONNECTIONS_ACTIVE, load(&self.active));
+
+        let _ = writeln!(out, "# HELP {} TLS handshakes that failed or didn't finish in time.", TLS_HANDSHAKE_FAILURES);
+        let _ = writeln!(out, "# TYPE {} counter", TLS_HANDSHAKE_FAILURES);
+        let _ = writeln!(out, "{}{{reason=\"error\"}} {}", TLS_HANDSHAKE_FAILURES, load(&self.handshake_errors));
+        let _ = writeln!(out, "{}{{reason=\"timeout\"}} {}", TLS_HANDSHAKE_FAILURES, load(&self.handshake_timeouts));
+        let _ = writeln!(out, "# HELP {} TLS connections by the protocol agreed over ALPN.", TLS_CONNECTIONS);
+        let _ = writeln!(out, "# TYPE {} counter", TLS_CONNECTIONS);
+        render_labelled(&mut out, TLS_CONNECTIONS, "protocol", &self.protocols);
+
+        let _ = writeln!(out, "# HELP {} Smoothed round-trip time of listener connections when they closed.", CONNECTION_RTT);
+        let _ = writeln!(out, "# TYPE {} summary", CONNECTION_RTT);
+        let _ = writeln!(out, "{}_sum {}", CONNECTION_RTT, load(&self.rtt_micros) as f64 / 1_000_000.0);
+        let _ = writeln!(out, "{}_count {}", CONNECTION_RTT, load(&self.rtt_samples));
+        let _ = writeln!(out, "# HELP {} Segments retransmitted on closed listener connections.", CONNECTION_RETRANSMITS);
+        let _ = writeln!(out, "# TYPE {} counter", CONNECTION_RETRANSMITS);
+        let _ = writeln!(out, "{} {}", CONNECTION_RETRANSMITS, load(&self.retransmits));
+
+        let _ = writeln!(out, "# HELP {} New connections opened to each upstream.", UPSTREAM_CONNECTIONS);
+        let _ = writeln!(out, "# TYPE {} counter", UPSTREAM_CONNECTIONS);
+        render_labelled(&mut out, UPSTREAM_CONNECTIONS, "upstream", &self.upstream_connections);
+        let _ = writeln!(out, "# HELP {} Requests sent to each upstream, on new or pooled connections.", UPSTREAM_REQUESTS);
+        let _ = writeln!(out, "# TYPE {} counter", UPSTREAM_REQUESTS);
+        render_labelled(&mut out, UPSTREAM_REQUESTS, "upstream", &self.upstream_requests);
+        out
+    }
+}
+
+fn increment(counters: &DashMap<String, AtomicU64>, key: String) {
+    counters.entry(key).or_default().fetch_add(1, Ordering::Relaxed);
+}
+
+fn render_labelled(out: &mut String, name: &str, label: &str, counters: &DashMap<String, AtomicU64>) {
+    let mut values: Vec<(String, u64)> = counters
+        .iter()
+        .map(|entry| (entry.key().clone(), entry.value().load(Ordering::Relaxed)))
+        .collect();
+    values.sort();
+    for (value, count) in values {
+        let _ = writeln!(out, "{}{{{}=\"{}\"}} {}", name, label, escape(&value), count);
+    }
+}
@@ -0,0 +1,78 @@
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use hyper::{Method, StatusCode};
+    use crate::config::MetricsConfig;
+    use crate::metrics::{Metrics, Observation};
+
+    fn observe(metrics: &Metrics, route: &str, upstream: &str, status: u16, millis: u64) {
+        metrics.observe(&Observation {
+            route,
+            method: &Method::GET,
+            upstream,
+            status: StatusCode::from_u16(status).unwrap(),
+            duration: Duration::from_millis(millis),
+            tags: &[],
+        });
+    }
+
+    #[test]
+    fn test_histogram_uses_configured_buckets_and_labels() {
+        let metrics = Metrics::from_config(&MetricsConfig {
+            buckets: vec![0.1, 1.0],
+            ..MetricsConfig::default()
+        });
+        observe(&metrics, "users", "users:8080", 200, 50);
+        observe(&metrics, "users", "users:8080", 204, 500);
+        observe(&metrics, "users", "users:8080", 502, 3000);
+
+        let text = metrics.render();
+        let series = r#"route="users",upstream="users:8080",status_class="2xx""#;
+        assert!(text.contains(&format!("gateway_upstream_request_duration_seconds_bucket{{{},le=\"0.1\"}} 1", series)), "{}", text);
+        assert!(text.contains(&format!("gateway_upstream_request_duration_seconds_bucket{{{},le=\"1\"}} 2", series)));
+        assert!(text.contains(&format!("gateway_upstream_request_duration_seconds_bucket{{{},le=\"+Inf\"}} 2", series)));
+        assert!(text.contains(&format!("gateway_upstream_request_duration_seconds_count{{{}}} 2", series)));
+        assert!(text.contains(r#"status_class="5xx",le="+Inf"} 1"#));
+    }
+
+    #[test]
+    fn test_series_beyond_limit_are_folded() {
+        let metrics = Metrics::from_config(&MetricsConfig {
+            labels: vec!["route".to_string()],
+            max_series: 2,
+            ..MetricsConfig::default()
+        });
+        for route in ["a", "b", "c", "d", "a"] {
+            observe(&metrics, route, "up:80", 200, 10);
+        }
+
+        let text = metrics.render();
+        assert!(text.contains(r#"_count{route="a"} 2"#), "{}", text);
+        assert!(text.contains(r#"_count{route="b"} 1"#));
+        assert!(text.contains(r#"_count{route="other"} 2"#));
+        assert!(!text.contains(r#"route="c""#));
+        assert!(text.contains("gateway_metrics_series_overflow_total 2"));
+    }
+
+    #[test]
+    fn test_route_tags_as_labels() {
+        let metrics = Metrics::from_config(&MetricsConfig {
+            labels: vec!["route".to_string(), "team".to_string()],
+            ..MetricsConfig::default()
+        });
+        let tags = [("cost_center".to_string(), "cc-42".to_string()), ("team".to_string(), "payments".to_string())];
+        metrics.observe(&Observation {
+            route: "payments",
+            method: &Method::POST,
+            upstream: "payments:8080",
+            status: StatusCode::OK,
+            duration: Duration::from_millis(10),
+            tags: &tags,
+        });
+        observe(&metrics, "health", "api:8080", 200, 1);
+
+        let text = metrics.render();
+        assert!(text.contains(r#"_count{route="payments",team="payments"} 1"#), "{}", text);
+        assert!(text.contains(r#"_count{route="health",team=""} 1"#));
+    }
+}
@@ -1,7 +1,24 @@
-use hyper::{HeaderMap, header::{HeaderName, HeaderValue}};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::time::Duration;
+use hyper::{Body, HeaderMap, Method, Response, StatusCode, Version};
+use hyper::header::{
+    HeaderName, HeaderValue, ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_METHOD, CONNECTION, CONTENT_LENGTH, COOKIE,
+    ETAG, IF_NONE_MATCH, ORIGIN, VARY, VIA,
+};
 
 #[cfg(test)]
 mod tests;
+
+/// Pseudonym this gateway uses in `Via` entries.
+pub const VIA_PSEUDONYM: &str = "api-gateway";
+
+pub const GATEWAY_TIME_HEADER: &str = "x-gateway-time-ms";
+pub const UPSTREAM_TIME_HEADER: &str = "x-upstream-time-ms";
+
+/// Connection-scoped headers that never travel past a single hop (RFC 9110 §7.6.1).
+const HOP_BY_HOP_HEADERS: [&str; 5] = ["connection", "keep-alive", "te", "transfer-encoding", "upgrade"];
+
 pub fn add_cors_headers(headers: &mut HeaderMap) {
     headers.insert(
         HeaderName::from_static("access-control-allow-origin"),
@@ -15,4 +32,158 @@ pub fn add_cors_headers(headers: &mut HeaderMap) {
         HeaderName::from_static("access-control-allow-headers"),
         HeaderValue::from_static("Content-Type, Authorization"),
     );
-}
\ No newline at end of file
+}
+
+/// The method a CORS preflight asks about, if this request is one.
+pub fn preflight_method(method: &Method, headers: &HeaderMap) -> Option<Method> {
+    if method != Method::OPTIONS || !headers.contains_key(ORIGIN) {
+        return None;
+    }
+    Method::from_bytes(headers.get(ACCESS_CONTROL_REQUEST_METHOD)?.as_bytes()).ok()
+}
+
+/// The gateway's own answer to a preflight, with no upstream call. `Vary`
+/// keeps shared caches from mixing answers for different origins or methods.
+pub fn preflight_response(max_age_secs: u64) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = StatusCode::NO_CONTENT;
+    let headers = response.headers_mut();
+    add_cors_headers(headers);
+    headers.insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from(max_age_secs));
+    headers.insert(
+        VARY,
+        HeaderValue::from_static("Origin, Access-Control-Request-Method, Access-Control-Request-Headers"),
+    );
+    response
+}
+
+/// Removes hop-by-hop headers before a message is forwarded in either
+/// direction: the fixed set, every `Proxy-*` header, and any header the
+/// sender listed in `Connection`.
+pub fn strip_hop_by_hop_headers(headers: &mut HeaderMap) {
+    let listed: Vec<HeaderName> = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect();
+    for name in listed {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP_HEADERS {
+        headers.remove(name);
+    }
+
+    let proxy_headers: Vec<HeaderName> = headers
+        .keys()
+        .filter(|name| name.as_str().starts_with("proxy-"))
+        .cloned()
+        .collect();
+    for name in proxy_headers {
+        headers.remove(name);
+    }
+}
+
+/// Appends this gateway to the `Via` chain for a message received over `version`.
+pub fn add_via_header(headers: &mut HeaderMap, version: Version) {
+    let protocol = match version {
+        Version::HTTP_09 => "0.9",
+        Version::HTTP_10 => "1.0",
+        Version::HTTP_2 => "2",
+        Version::HTTP_3 => "3",
+        _ => "1.1",
+    };
+    if let Ok(value) = HeaderValue::from_str(&format!("{} {}", protocol, VIA_PSEUDONYM)) {
+        headers.append(VIA, value);
+    }
+}
+
+/// The value of the request cookie `name`, searching every `Cookie` header.
+pub fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value.trim_matches('"'))
+}
+
+/// Drops the request cookies in `names` from every `Cookie` header, and the
+/// header itself once it is empty.
+pub fn remove_cookies(headers: &mut HeaderMap, names: &[&str]) {
+    if names.iter().all(|name| cookie(headers, name).is_none()) {
+        return;
+    }
+    let kept: Vec<String> = headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .filter(|pair| !names.contains(&pair.split_once('=').map_or(*pair, |(key, _)| key)))
+        .map(str::to_string)
+        .collect();
+    headers.remove(COOKIE);
+    if let Ok(value) = HeaderValue::from_str(&kept.join("; ")) {
+        if !kept.is_empty() {
+            headers.insert(COOKIE, value);
+        }
+    }
+}
+
+/// Whether `If-None-Match` in `headers` matches `etag` (weak comparison).
+pub fn if_none_match(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get(IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| {
+            value
+                .split(',')
+                .map(|candidate| candidate.trim().trim_start_matches("W/"))
+                .any(|candidate| candidate == "*" || candidate == etag.trim_start_matches("W/"))
+        })
+}
+
+/// Adds a strong ETag derived from the body unless the upstream sent one.
+pub fn ensure_etag(headers: &mut HeaderMap, body: &[u8]) {
+    if headers.contains_key(ETAG) {
+        return;
+    }
+    let mut hasher = DefaultHasher::new();
+    body.hash(&mut hasher);
+    if let Ok(value) = HeaderValue::from_str(&format!("\"{:x}-{:016x}\"", body.len(), hasher.finish())) {
+        headers.insert(ETAG, value);
+    }
+}
+
+/// Splits `total` into the time spent upstream and the rest, the gateway's
+/// own overhead. Responses that never reached an upstream only get the latter.
+pub fn add_timing_headers(headers: &mut HeaderMap, total: Duration, upstream: Option<Duration>) {
+    let gateway = total.saturating_sub(upstream.unwrap_or_default());
+    headers.insert(GATEWAY_TIME_HEADER, HeaderValue::from(gateway.as_millis() as u64));
+    if let Some(upstream) = upstream {
+        headers.insert(UPSTREAM_TIME_HEADER, HeaderValue::from(upstream.as_millis() as u64));
+    }
+}
+
+/// Turns a `200 OK` into an empty `304 Not Modified` when the request's
+/// `If-None-Match` matches the response's ETag.
+pub fn apply_if_none_match(request_headers: &HeaderMap, response: &mut Response<Body>) {
+    if response.status() != StatusCode::OK {
+        return;
+    }
+    let matches = response
+        .headers()
+        .get(ETAG)
+        .and_then(|etag| etag.to_str().ok())
+        .is_some_and(|etag| if_none_match(request_headers, etag));
+    if matches {
+        *response.status_mut() = StatusCode::NOT_MODIFIED;
+        *response.body_mut() = Body::empty();
+        response.headers_mut().remove(CONTENT_LENGTH);
+    }
+}
@@ -1,8 +1,12 @@
 #[cfg(test)]
 mod tests {
     // use super::*; I used this but it didnt work, that's why I've commented it out
-    use hyper::HeaderMap;
-    use crate::middleware::add_cors_headers;
+    use std::time::Duration;
+    use hyper::{Body, HeaderMap, Method, Response, StatusCode, Version};
+    use crate::middleware::{
+        add_cors_headers, add_timing_headers, add_via_header, apply_if_none_match, ensure_etag, preflight_method,
+        preflight_response, remove_cookies, strip_hop_by_hop_headers,
+    };
 
     #[test]
     fn test_add_cors_headers() {
@@ -22,4 +26,119 @@ mod tests {
             "Content-Type, Authorization"
         );
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_strip_hop_by_hop_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("connection", "keep-alive, X-Trace-Hop".parse().unwrap());
+        headers.insert("keep-alive", "timeout=5".parse().unwrap());
+        headers.insert("transfer-encoding", "chunked".parse().unwrap());
+        headers.insert("te", "trailers".parse().unwrap());
+        headers.insert("upgrade", "websocket".parse().unwrap());
+        headers.insert("proxy-authorization", "Basic abc".parse().unwrap());
+        headers.insert("proxy-connection", "keep-alive".parse().unwrap());
+        headers.insert("x-trace-hop", "1".parse().unwrap());
+        headers.insert("content-type", "application/json".parse().unwrap());
+        headers.insert("authorization", "Bearer token".parse().unwrap());
+
+        strip_hop_by_hop_headers(&mut headers);
+
+        assert_eq!(headers.len(), 2, "{:?}", headers);
+        assert!(headers.contains_key("content-type"));
+        assert!(headers.contains_key("authorization"));
+    }
+
+    #[test]
+    fn test_add_via_header_appends() {
+        let mut headers = HeaderMap::new();
+        headers.insert("via", "1.1 edge-proxy".parse().unwrap());
+
+        add_via_header(&mut headers, Version::HTTP_2);
+
+        let via: Vec<_> = headers.get_all("via").iter().collect();
+        assert_eq!(via, vec!["1.1 edge-proxy", "2 api-gateway"]);
+    }
+
+    #[test]
+    fn test_ensure_etag_keeps_upstream_tag() {
+        let mut headers = HeaderMap::new();
+        ensure_etag(&mut headers, b"hello");
+        let generated = headers.get("etag").unwrap().clone();
+        assert!(generated.to_str().unwrap().starts_with("\"5-"));
+
+        let mut other = HeaderMap::new();
+        ensure_etag(&mut other, b"hellp");
+        assert_ne!(other.get("etag").unwrap(), generated);
+
+        let mut upstream = HeaderMap::new();
+        upstream.insert("etag", "\"v1\"".parse().unwrap());
+        ensure_etag(&mut upstream, b"hello");
+        assert_eq!(upstream.get("etag").unwrap(), "\"v1\"");
+    }
+
+    #[test]
+    fn test_preflight_detection() {
+        let mut headers = HeaderMap::new();
+        headers.insert("access-control-request-method", "PUT".parse().unwrap());
+        assert_eq!(preflight_method(&Method::OPTIONS, &headers), None, "no Origin, not CORS");
+
+        headers.insert("origin", "https://app.example.com".parse().unwrap());
+        assert_eq!(preflight_method(&Method::OPTIONS, &headers), Some(Method::PUT));
+        assert_eq!(preflight_method(&Method::PUT, &headers), None);
+    }
+
+    #[test]
+    fn test_preflight_response() {
+        let response = preflight_response(600);
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(response.headers()["access-control-max-age"], "600");
+        assert_eq!(response.headers()["access-control-allow-origin"], "*");
+        assert!(response.headers()["vary"].to_str().unwrap().contains("Origin"));
+    }
+
+    #[test]
+    fn test_add_timing_headers() {
+        let mut headers = HeaderMap::new();
+        add_timing_headers(&mut headers, Duration::from_millis(120), Some(Duration::from_millis(100)));
+        assert_eq!(headers["x-gateway-time-ms"], "20");
+        assert_eq!(headers["x-upstream-time-ms"], "100");
+
+        let mut cached = HeaderMap::new();
+        add_timing_headers(&mut cached, Duration::from_millis(3), None);
+        assert_eq!(cached["x-gateway-time-ms"], "3");
+        assert!(!cached.contains_key("x-upstream-time-ms"));
+    }
+
+    #[test]
+    fn test_apply_if_none_match() {
+        let response = || {
+            let mut response = Response::new(Body::from("hello"));
+            ensure_etag(response.headers_mut(), b"hello");
+            response
+        };
+        let etag = response().headers().get("etag").unwrap().clone();
+
+        let mut request = HeaderMap::new();
+        request.insert("if-none-match", etag);
+        let mut matched = response();
+        apply_if_none_match(&request, &mut matched);
+        assert_eq!(matched.status(), StatusCode::NOT_MODIFIED);
+
+        request.insert("if-none-match", "\"stale\"".parse().unwrap());
+        let mut unmatched = response();
+        apply_if_none_match(&request, &mut unmatched);
+        assert_eq!(unmatched.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_remove_cookies() {
+        let mut headers = HeaderMap::new();
+        headers.append("cookie", "theme=dark; gateway_session=abc".parse().unwrap());
+        headers.append("cookie", "gateway_session_login=xyz".parse().unwrap());
+        remove_cookies(&mut headers, &["gateway_session", "gateway_session_login"]);
+        assert_eq!(headers.get_all("cookie").iter().collect::<Vec<_>>(), ["theme=dark"]);
+
+        remove_cookies(&mut headers, &["theme"]);
+        assert!(!headers.contains_key("cookie"));
+    }
+}
@@ -1,37 +1,156 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::AtomicU64;
 use std::time::SystemTime;
-use hyper::{StatusCode, HeaderMap};
+use dashmap::DashMap;
+use hyper::{Body, HeaderMap, Response, StatusCode};
 use bytes::Bytes;
+use crate::admission::FrequencySketch;
+use crate::persistence::Persistence;
+use crate::redis::RedisCache;
+use crate::webhooks::Webhooks;
+use crate::config::{CacheConfig, TarpitConfig, MAX_RATE_LIMIT_BUCKETS};
 
 pub struct CacheEntry {
-    pub response_parts: (StatusCode, HeaderMap, Bytes),
+    pub response: Arc<CachedResponse>,
     pub expires_at: SystemTime,
 }
 
+/// A POST's `Idempotency-Key`, held from when the first request with it is
+/// sent upstream.
+pub struct IdempotencyRecord {
+    /// The first response, or `None` while that request is in flight.
+    pub response: Option<Arc<CachedResponse>>,
+    pub expires_at: SystemTime,
+}
+
+/// A response as stored for replay. Entries share it behind an `Arc`, so a
+/// hit holds the shard lock only to bump a count, and the body `Bytes` are
+/// handed to the response without copying.
+pub struct CachedResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+impl CachedResponse {
+    pub fn new(status: StatusCode, headers: HeaderMap, body: Bytes) -> Self {
+        Self { status, headers, body }
+    }
+
+    pub fn to_response(&self) -> Response<Body> {
+        let mut response = Response::new(Body::from(self.body.clone()));
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers.clone();
+        response
+    }
+}
+
 pub struct RateLimit {
     pub count: u32,
     pub window_start: SystemTime,
+    /// Time of the latest request, for least-recently-used eviction.
+    pub last_seen: SystemTime,
 }
 
 impl Default for RateLimit {
     fn default() -> Self {
+        let now = SystemTime::now();
         Self {
             count: 0,
-            window_start: SystemTime::now(),
+            window_start: now,
+            last_seen: now,
         }
     }
 }
 
+/// The 429s a client has had recently, for tarpitting.
+pub struct Penalty {
+    pub strikes: u32,
+    pub last_strike: SystemTime,
+}
+
+/// Rate-limit buckets dropped to stay within memory bounds.
+#[derive(Debug, Default)]
+pub struct RateLimitEvictions {
+    /// Least recently used buckets dropped because the map was full.
+    pub capacity: AtomicU64,
+    /// Buckets dropped by the sweeper because their window had passed.
+    pub idle: AtomicU64,
+}
+
+/// Shared by every request. The maps are sharded, each shard behind its own
+/// lock, so requests for different keys rarely contend; none of them is
+/// locked across an await.
 pub struct AppState {
-    pub cache: HashMap<String, CacheEntry>,
-    pub rate_limits: HashMap<String, RateLimit>,
+    pub cache: DashMap<String, CacheEntry>,
+    pub cache_limits: CacheConfig,
+    /// Keys in `cache`, oldest first, for eviction once it is full. Taken
+    /// before any shard of `cache`.
+    pub cache_order: Mutex<VecDeque<String>>,
+    /// How often each cache key has recently been looked up.
+    pub cache_sketch: Mutex<FrequencySketch>,
+    /// The shared tier behind `cache`, if configured.
+    pub redis_cache: Option<Arc<RedisCache>>,
+    /// Where deployment switches are kept across restarts, if configured.
+    pub persistence: Option<Arc<Persistence>>,
+    /// Told when a tenant runs out of quota, if configured.
+    pub webhooks: Option<Arc<Webhooks>>,
+    pub rate_limits: DashMap<String, RateLimit>,
+    /// Most buckets `rate_limits` may hold before the least recently used
+    /// are evicted.
+    pub max_rate_limit_buckets: usize,
+    pub rate_limit_evictions: RateLimitEvictions,
+    /// Strikes per client IP, kept only while tarpitting is enabled.
+    pub penalties: DashMap<String, Penalty>,
+    pub tarpit: Option<TarpitConfig>,
+    /// First responses to `Idempotency-Key` POSTs, for replay to retries.
+    pub idempotency: DashMap<String, IdempotencyRecord>,
+    /// Keys in `idempotency`, oldest first, for eviction once it holds
+    /// `cache_limits.max_idempotency_records`. Taken before any shard of
+    /// `idempotency`.
+    pub idempotency_order: Mutex<VecDeque<String>>,
+    /// Per tenant, the keys of its capped entries, oldest first. Taken before
+    /// any shard of the maps it indexes.
+    pub tenant_entries: Mutex<HashMap<String, TenantEntries>>,
+}
+
+#[derive(Default)]
+pub struct TenantEntries {
+    pub cache: VecDeque<String>,
+    pub rate_limits: VecDeque<String>,
 }
 
 impl AppState {
     pub fn new() -> Self {
         Self {
-            cache: HashMap::new(),
-            rate_limits: HashMap::new(),
+            cache: DashMap::new(),
+            cache_limits: CacheConfig::default(),
+            cache_order: Mutex::new(VecDeque::new()),
+            cache_sketch: Mutex::new(FrequencySketch::new(CacheConfig::default().max_entries)),
+            redis_cache: None,
+            persistence: None,
+            webhooks: None,
+            rate_limits: DashMap::new(),
+            max_rate_limit_buckets: MAX_RATE_LIMIT_BUCKETS,
+            rate_limit_evictions: RateLimitEvictions::default(),
+            penalties: DashMap::new(),
+            tarpit: None,
+            idempotency: DashMap::new(),
+            idempotency_order: Mutex::new(VecDeque::new()),
+            tenant_entries: Mutex::new(HashMap::new()),
         }
     }
+
+    /// Applies `config`, sizing the frequency sketch to its capacity.
+    pub fn set_cache_limits(&mut self, config: &CacheConfig) {
+        self.cache_limits = config.clone();
+        self.cache_sketch = Mutex::new(FrequencySketch::new(config.max_entries));
+    }
+}
+
+impl Default for AppState {
+    fn default() -> Self {
+        Self::new()
+    }
 }
\ No newline at end of file
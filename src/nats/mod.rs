@@ -0,0 +1,80 @@
+use async_nats::{Client, ConnectError, ConnectOptions};
+use hyper::Uri;
+use crate::config::UpstreamTlsConfig;
+
+#[cfg(test)]
+mod tests;
+
+const CLIENT_NAME: &str = "api-gateway";
+
+/// NATS's rules for a subject published to: dot-separated tokens, with no
+/// wildcards or whitespace.
+pub fn check_subject(subject: &str) -> Result<(), String> {
+    let valid = !subject.is_empty()
+        && subject.split('.').all(|token| !token.is_empty() && token != "*" && token != ">")
+        && !subject.chars().any(char::is_whitespace);
+    if !valid {
+        return Err(format!("\"{}\" is not a subject that can be published to", subject));
+    }
+    Ok(())
+}
+
+/// Where a NATS server is and how to log in to it.
+#[derive(Clone, PartialEq, Eq)]
+pub struct NatsAddress {
+    /// `host:port`.
+    pub host: String,
+    username: Option<String>,
+    password: Option<String>,
+}
+
+impl std::fmt::Debug for NatsAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NatsAddress").field("host", &self.host).finish_non_exhaustive()
+    }
+}
+
+impl NatsAddress {
+    /// Parses `nats://host:port`; credentials come from the config, never
+    /// the URL, so they stay out of logs.
+    pub fn new(url: &str, username: Option<&str>, password: Option<&str>) -> Result<Self, String> {
+        let uri: Uri = url.parse().map_err(|e| format!("nats url \"{}\" is not valid: {}", url, e))?;
+        if uri.scheme_str() != Some("nats") {
+            return Err(format!("nats url \"{}\" must use the nats scheme", url));
+        }
+        let authority = uri.authority().ok_or_else(|| format!("nats url \"{}\" has no host", url))?;
+        if authority.as_str().contains('@') {
+            return Err(format!("nats url \"{}\" must not hold credentials, set username and password instead", url));
+        }
+        if username.is_some() && password.is_none() {
+            return Err("nats username is set without a password".to_string());
+        }
+        Ok(Self {
+            host: format!("{}:{}", authority.host(), authority.port_u16().unwrap_or(4222)),
+            username: username.map(str::to_string),
+            password: password.map(str::to_string),
+        })
+    }
+
+    /// Connects, over TLS with `tls`, and logs in with the username and
+    /// password, or the password alone as an auth token. The client
+    /// reconnects by itself after the connection drops.
+    pub async fn connect(&self, tls: Option<&UpstreamTlsConfig>) -> Result<Client, ConnectError> {
+        let mut options = ConnectOptions::new().name(CLIENT_NAME);
+        match (&self.username, &self.password) {
+            (Some(username), Some(password)) => options = options.user_and_password(username.clone(), password.clone()),
+            (None, Some(token)) => options = options.token(token.clone()),
+            _ => {}
+        }
+        if let Some(tls) = tls {
+            options = options.require_tls(true);
+            if let Some(ca_path) = &tls.ca_path {
+                options = options.add_root_certificates(ca_path.clone());
+            }
+            if let (Some(cert_path), Some(key_path)) = (&tls.cert_path, &tls.key_path) {
+                options = options.add_client_certificate(cert_path.clone(), key_path.clone());
+            }
+        }
+        options.connect(format!("nats://{}", self.host)).await
+    }
+}
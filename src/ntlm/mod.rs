@@ -0,0 +1,200 @@
+use hmac::{Hmac, Mac};
+use md4::{Digest, Md4};
+use md5::Md5;
+
+#[cfg(test)]
+mod tests;
+
+const SIGNATURE: &[u8; 8] = b"NTLMSSP\0";
+const NEGOTIATE_UNICODE: u32 = 0x0000_0001;
+const NEGOTIATE_OEM: u32 = 0x0000_0002;
+const REQUEST_TARGET: u32 = 0x0000_0004;
+const NEGOTIATE_NTLM: u32 = 0x0000_0200;
+const NEGOTIATE_ALWAYS_SIGN: u32 = 0x0000_8000;
+const NEGOTIATE_EXTENDED_SESSIONSECURITY: u32 = 0x0008_0000;
+const NEGOTIATE_TARGET_INFO: u32 = 0x0080_0000;
+const NEGOTIATE_128: u32 = 0x2000_0000;
+const NEGOTIATE_56: u32 = 0x8000_0000;
+const NEGOTIATE_FLAGS: u32 = NEGOTIATE_UNICODE
+    | NEGOTIATE_OEM
+    | REQUEST_TARGET
+    | NEGOTIATE_NTLM
+    | NEGOTIATE_ALWAYS_SIGN
+    | NEGOTIATE_EXTENDED_SESSIONSECURITY
+    | NEGOTIATE_128
+    | NEGOTIATE_56;
+const AV_EOL: u16 = 0;
+const AV_FLAGS: u16 = 6;
+const AV_TIMESTAMP: u16 = 7;
+/// MsvAvFlags bit saying the AUTHENTICATE message carries a MIC.
+const AV_FLAG_MIC: u32 = 0x2;
+/// Where the MIC sits in an AUTHENTICATE message, after the version.
+const MIC_OFFSET: usize = 72;
+const AUTHENTICATE_HEADER: usize = 88;
+
+/// The account the gateway authenticates as.
+pub struct Credentials<'a> {
+    pub username: &'a str,
+    pub password: &'a str,
+    pub domain: &'a str,
+    pub workstation: &'a str,
+}
+
+/// What a server's CHALLENGE message holds that the answer depends on.
+#[derive(Debug, PartialEq)]
+pub struct Challenge {
+    pub flags: u32,
+    pub server_challenge: [u8; 8],
+    pub target_info: Vec<u8>,
+}
+
+/// The NEGOTIATE message that opens the exchange.
+pub fn negotiate() -> Vec<u8> {
+    let mut message = SIGNATURE.to_vec();
+    message.extend_from_slice(&1u32.to_le_bytes());
+    message.extend_from_slice(&NEGOTIATE_FLAGS.to_le_bytes());
+    // Empty domain and workstation fields.
+    message.extend_from_slice(&[0; 16]);
+    message
+}
+
+pub fn parse_challenge(message: &[u8]) -> Result<Challenge, String> {
+    if message.len() < 48 || &message[..8] != SIGNATURE || u32_at(message, 8) != 2 {
+        return Err("not an NTLM challenge".to_string());
+    }
+    let flags = u32_at(message, 20);
+    let mut server_challenge = [0; 8];
+    server_challenge.copy_from_slice(&message[24..32]);
+    let target_info = if flags & NEGOTIATE_TARGET_INFO != 0 {
+        let (len, offset) = (u16_at(message, 40) as usize, u32_at(message, 44) as usize);
+        message
+            .get(offset..offset + len)
+            .ok_or_else(|| "NTLM challenge target info is out of bounds".to_string())?
+            .to_vec()
+    } else {
+        Vec::new()
+    };
+    Ok(Challenge { flags, server_challenge, target_info })
+}
+
+/// The AUTHENTICATE message answering `challenge` with an NTLMv2 response.
+/// `time` is the client's clock as a FILETIME, used when the server sent
+/// no timestamp of its own. With a server timestamp the message carries a
+/// MIC over all three messages, as Windows servers expect.
+pub fn authenticate(
+    credentials: &Credentials,
+    negotiate: &[u8],
+    challenge_message: &[u8],
+    client_challenge: [u8; 8],
+    time: u64,
+) -> Result<Vec<u8>, String> {
+    let challenge = parse_challenge(challenge_message)?;
+    let mut pairs = av_pairs(&challenge.target_info)?;
+    let server_time = pairs.iter().find(|(id, _)| *id == AV_TIMESTAMP).and_then(|(_, value)| {
+        let bytes: [u8; 8] = value.as_slice().try_into().ok()?;
+        Some(u64::from_le_bytes(bytes))
+    });
+    if server_time.is_some() {
+        match pairs.iter_mut().find(|(id, _)| *id == AV_FLAGS) {
+            Some((_, value)) if value.len() == 4 => {
+                let flags = u32_at(value, 0) | AV_FLAG_MIC;
+                value.copy_from_slice(&flags.to_le_bytes());
+            }
+            _ => pairs.push((AV_FLAGS, AV_FLAG_MIC.to_le_bytes().to_vec())),
+        }
+    }
+    let mut target_info = Vec::new();
+    for (id, value) in &pairs {
+        target_info.extend_from_slice(&id.to_le_bytes());
+        target_info.extend_from_slice(&(value.len() as u16).to_le_bytes());
+        target_info.extend_from_slice(value);
+    }
+    target_info.extend_from_slice(&[0; 4]);
+
+    let key = ntowfv2(credentials);
+    let mut blob = vec![1, 1, 0, 0, 0, 0, 0, 0];
+    blob.extend_from_slice(&server_time.unwrap_or(time).to_le_bytes());
+    blob.extend_from_slice(&client_challenge);
+    blob.extend_from_slice(&[0; 4]);
+    blob.extend_from_slice(&target_info);
+    blob.extend_from_slice(&[0; 4]);
+    let proof = hmac_md5(&key, &[&challenge.server_challenge[..], &blob].concat());
+    let nt_response = [&proof[..], &blob].concat();
+    // With a server timestamp the LM response is left as zeroes.
+    let lm_response = match server_time {
+        Some(_) => vec![0; 24],
+        None => [&hmac_md5(&key, &[challenge.server_challenge, client_challenge].concat())[..], &client_challenge].concat(),
+    };
+
+    let flags = NEGOTIATE_FLAGS & challenge.flags | NEGOTIATE_UNICODE;
+    let fields = [
+        lm_response,
+        nt_response,
+        utf16(credentials.domain),
+        utf16(credentials.username),
+        utf16(credentials.workstation),
+        Vec::new(),
+    ];
+    let mut message = SIGNATURE.to_vec();
+    message.extend_from_slice(&3u32.to_le_bytes());
+    let mut offset = AUTHENTICATE_HEADER;
+    for field in &fields {
+        message.extend_from_slice(&(field.len() as u16).to_le_bytes());
+        message.extend_from_slice(&(field.len() as u16).to_le_bytes());
+        message.extend_from_slice(&(offset as u32).to_le_bytes());
+        offset += field.len();
+    }
+    message.extend_from_slice(&flags.to_le_bytes());
+    // No version, and the MIC is filled in once the message is complete.
+    message.extend_from_slice(&[0; 24]);
+    for field in &fields {
+        message.extend_from_slice(field);
+    }
+    if server_time.is_some() {
+        // Without key exchange the exported session key is the base key.
+        let session_key = hmac_md5(&key, &proof);
+        let mic = hmac_md5(&session_key, &[negotiate, challenge_message, &message].concat());
+        message[MIC_OFFSET..MIC_OFFSET + 16].copy_from_slice(&mic);
+    }
+    Ok(message)
+}
+
+/// The NTLMv2 response key: the password's NT hash keyed over the upper
+/// cased user name and the domain.
+pub fn ntowfv2(credentials: &Credentials) -> [u8; 16] {
+    let nt_hash = Md4::digest(utf16(credentials.password));
+    hmac_md5(&nt_hash, &utf16(&format!("{}{}", credentials.username.to_uppercase(), credentials.domain)))
+}
+
+/// The AV pairs of a target info field, without the terminating MsvAvEOL.
+fn av_pairs(mut target_info: &[u8]) -> Result<Vec<(u16, Vec<u8>)>, String> {
+    let mut pairs = Vec::new();
+    while target_info.len() >= 4 {
+        let (id, len) = (u16_at(target_info, 0), u16_at(target_info, 2) as usize);
+        if id == AV_EOL {
+            break;
+        }
+        let value = target_info.get(4..4 + len).ok_or_else(|| "NTLM target info is truncated".to_string())?;
+        pairs.push((id, value.to_vec()));
+        target_info = &target_info[4 + len..];
+    }
+    Ok(pairs)
+}
+
+fn utf16(text: &str) -> Vec<u8> {
+    text.encode_utf16().flat_map(u16::to_le_bytes).collect()
+}
+
+fn u16_at(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]])
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
+
+pub fn hmac_md5(key: &[u8], message: &[u8]) -> [u8; 16] {
+    let mut mac = <Hmac<Md5>>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(message);
+    mac.finalize().into_bytes().into()
+}
@@ -0,0 +1,99 @@
+#[cfg(test)]
+mod tests {
+    use md4::{Digest, Md4};
+    use crate::ntlm::{authenticate, hmac_md5, negotiate, ntowfv2, parse_challenge, Credentials};
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// The user, domain and password of the MS-NLMP 4.2.4 examples.
+    const CREDENTIALS: Credentials = Credentials { username: "User", password: "Password", domain: "Domain", workstation: "COMPUTER" };
+    const SERVER_CHALLENGE: [u8; 8] = [0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef];
+    /// MsvAvNbDomainName "Domain" and MsvAvNbComputerName "Server".
+    const TARGET_INFO: &[u8] = b"\x02\x00\x0c\x00D\x00o\x00m\x00a\x00i\x00n\x00\x01\x00\x0c\x00S\x00e\x00r\x00v\x00e\x00r\x00\x00\x00\x00\x00";
+
+    fn challenge(target_info: &[u8]) -> Vec<u8> {
+        let mut message = b"NTLMSSP\0\x02\x00\x00\x00".to_vec();
+        message.extend_from_slice(&[0, 0, 0, 0, 48, 0, 0, 0]);
+        message.extend_from_slice(&0xe28a_8233u32.to_le_bytes());
+        message.extend_from_slice(&SERVER_CHALLENGE);
+        message.extend_from_slice(&[0; 8]);
+        let len = (target_info.len() as u16).to_le_bytes();
+        message.extend_from_slice(&[len[0], len[1], len[0], len[1], 48, 0, 0, 0]);
+        message.extend_from_slice(target_info);
+        message
+    }
+
+    /// The bytes of field `index` of an AUTHENTICATE message.
+    fn field(message: &[u8], index: usize) -> &[u8] {
+        let at = 12 + index * 8;
+        let len = u16::from_le_bytes([message[at], message[at + 1]]) as usize;
+        let offset = u32::from_le_bytes([message[at + 4], message[at + 5], message[at + 6], message[at + 7]]) as usize;
+        &message[offset..offset + len]
+    }
+
+    #[test]
+    fn test_digests() {
+        // NTOWFv1 of the MS-NLMP 4.2.2.1.2 password.
+        assert_eq!(hex(&Md4::digest(b"P\0a\0s\0s\0w\0o\0r\0d\0")), "a4f49c406510bdcab6824ee7c30fd852");
+
+        // RFC 2202, test cases 1, 2, 3 and 6.
+        assert_eq!(hex(&hmac_md5(&[0x0b; 16], b"Hi There")), "9294727a3638bb1c13f48ef8158bfc9d");
+        assert_eq!(hex(&hmac_md5(b"Jefe", b"what do ya want for nothing?")), "750c783e6ab0b503eaa86e310a5db738");
+        assert_eq!(hex(&hmac_md5(&[0xaa; 16], &[0xdd; 50])), "56be34521d144c88dbb8c733f0e8b3f6");
+        assert_eq!(hex(&hmac_md5(&[0xaa; 80], b"Test Using Larger Than Block-Size Key - Hash Key First")), "6b1ab7fe4bd7bf8f0b62e6ce61b9d0cd");
+    }
+
+    #[test]
+    fn test_ntlmv2_response_matches_ms_nlmp_example() {
+        assert_eq!(hex(&ntowfv2(&CREDENTIALS)), "0c868a403bfd7a93a3001ef22ef02e3f");
+        let parsed = parse_challenge(&challenge(TARGET_INFO)).unwrap();
+        assert_eq!((parsed.server_challenge, parsed.target_info.as_slice()), (SERVER_CHALLENGE, TARGET_INFO));
+
+        let message = authenticate(&CREDENTIALS, &negotiate(), &challenge(TARGET_INFO), [0xaa; 8], 0).unwrap();
+        assert_eq!(&message[..12], b"NTLMSSP\0\x03\x00\x00\x00");
+        assert_eq!(hex(field(&message, 0)), "86c35097ac9cec102554764a57cccc19aaaaaaaaaaaaaaaa");
+        // MS-NLMP 4.2.4.2.2: NTProofStr, then the temp blob with a zero
+        // timestamp, the client challenge and the server's AV pairs.
+        let nt_response = field(&message, 1);
+        assert_eq!(hex(&nt_response[..16]), "68cd0ab851e51c96aabc927bebef6a1c");
+        assert_eq!(
+            hex(nt_response),
+            concat!(
+                "68cd0ab851e51c96aabc927bebef6a1c",
+                "0101000000000000",
+                "0000000000000000",
+                "aaaaaaaaaaaaaaaa",
+                "00000000",
+                "02000c0044006f006d00610069006e00",
+                "01000c005300650072007600650072000000000000000000",
+            )
+        );
+        // MS-NLMP 4.2.4.1.2: the session base key.
+        assert_eq!(hex(&hmac_md5(&ntowfv2(&CREDENTIALS), &nt_response[..16])), "8de40ccadbc14a82f15cb0ad0de95ca3");
+        assert_eq!(field(&message, 3), b"U\0s\0e\0r\0");
+        assert_eq!(&message[72..88], &[0; 16]);
+    }
+
+    #[test]
+    fn test_server_timestamp_adds_a_mic() {
+        let mut target_info = b"\x07\x00\x08\x00\x01\x02\x03\x04\x05\x06\x07\x08".to_vec();
+        target_info.extend_from_slice(TARGET_INFO);
+        let negotiate = negotiate();
+        let challenge = challenge(&target_info);
+        let message = authenticate(&CREDENTIALS, &negotiate, &challenge, [0xaa; 8], 42).unwrap();
+        assert_eq!(field(&message, 0), &[0; 24]);
+        let nt_response = field(&message, 1);
+        // The server's time is used, and MsvAvFlags says a MIC is present.
+        assert_eq!(&nt_response[24..32], &[1, 2, 3, 4, 5, 6, 7, 8]);
+        assert!(nt_response.windows(8).any(|pair| pair == b"\x06\x00\x04\x00\x02\x00\x00\x00"));
+
+        let session_key = hmac_md5(&ntowfv2(&CREDENTIALS), &nt_response[..16]);
+        let mut unsigned = message.clone();
+        unsigned[72..88].copy_from_slice(&[0; 16]);
+        assert_eq!(&message[72..88], &hmac_md5(&session_key, &[&negotiate[..], &challenge, &unsigned].concat()));
+
+        assert!(authenticate(&CREDENTIALS, &negotiate, b"NTLMSSP\0\x01\x00\x00\x00", [0; 8], 0).is_err());
+    }
+}
@@ -0,0 +1,165 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use bytes::Bytes;
+use hyper::{Body, HeaderMap, Response, StatusCode, header::{HeaderValue, AUTHORIZATION, CACHE_CONTROL, CONTENT_TYPE, PRAGMA, RETRY_AFTER, WWW_AUTHENTICATE}};
+use serde_json::{json, Value};
+use warp::{Filter, Rejection};
+use crate::api_keys::{ApiKeyStore, LoginError, KEY_PREFIX};
+use crate::auth::{Authenticator, Identity, CLIENT_ID_CLAIM};
+use crate::config::TokenEndpointConfig;
+use crate::lockout::whole_secs;
+
+#[cfg(test)]
+mod tests;
+
+pub const CLIENT_CREDENTIALS: &str = "client_credentials";
+
+/// An error response as RFC 6749 section 5.2 defines it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenError {
+    pub status: StatusCode,
+    /// `invalid_request`, `invalid_client`, `unsupported_grant_type`,
+    /// `invalid_scope` or, for a client locked out after failed logins,
+    /// `temporarily_unavailable`.
+    pub error: &'static str,
+    pub description: String,
+    /// Seconds for `Retry-After`.
+    pub retry_after: Option<u64>,
+}
+
+impl TokenError {
+    fn new(error: &'static str, description: impl Into<String>) -> Self {
+        let status = match error {
+            "invalid_client" => StatusCode::UNAUTHORIZED,
+            _ => StatusCode::BAD_REQUEST,
+        };
+        Self { status, error, description: description.into(), retry_after: None }
+    }
+}
+
+/// `POST /token`. Without a key store or token endpoint configured, `/token`
+/// is proxied like any other path.
+pub fn routes(
+    config: Option<TokenEndpointConfig>,
+    authenticator: Arc<Authenticator>,
+    key_store: Option<Arc<ApiKeyStore>>,
+) -> impl Filter<Extract = (Response<Body>,), Error = Rejection> + Clone {
+    let enabled = config.zip(key_store);
+    warp::path!("token")
+        .and(warp::post())
+        .and_then(move || {
+            let enabled = enabled.clone();
+            async move { enabled.ok_or_else(warp::reject::not_found) }
+        })
+        .and(warp::header::headers_cloned())
+        .and(warp::body::bytes())
+        .and_then(move |(config, key_store): (TokenEndpointConfig, Arc<ApiKeyStore>), headers: HeaderMap, body: Bytes| {
+            let authenticator = authenticator.clone();
+            async move {
+                let result = issue_token(&config, &authenticator, &key_store, &headers, &body).await;
+                Ok::<_, Rejection>(token_response(result))
+            }
+        })
+}
+
+/// Handles a token request: the client authenticates with HTTP Basic or with
+/// `client_id` and `client_secret` in the form, and may narrow its scopes
+/// with `scope`. The client id is the id of an issued key; the secret is the
+/// whole key or just its secret part.
+pub async fn issue_token(
+    config: &TokenEndpointConfig,
+    authenticator: &Authenticator,
+    key_store: &ApiKeyStore,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> Result<Value, TokenError> {
+    let form: HashMap<String, String> = form_urlencoded::parse(body).into_owned().collect();
+    match form.get("grant_type").map(String::as_str) {
+        Some(CLIENT_CREDENTIALS) => {}
+        Some(other) => {
+            return Err(TokenError::new("unsupported_grant_type", format!("grant_type {} is not supported", other)))
+        }
+        None => return Err(TokenError::new("invalid_request", "grant_type is required")),
+    }
+
+    let (client_id, client_secret) = match (basic_credentials(headers), &form.get("client_id"), &form.get("client_secret")) {
+        (Some(_), Some(_), _) | (Some(_), _, Some(_)) => {
+            return Err(TokenError::new("invalid_request", "use only one way of authenticating the client"))
+        }
+        (Some(credentials), None, None) => credentials,
+        (None, Some(id), Some(secret)) => (id.to_string(), secret.to_string()),
+        _ => return Err(TokenError::new("invalid_client", "client authentication is required")),
+    };
+    let prefix = format!("{}{}_", KEY_PREFIX, client_id);
+    let secret = client_secret.strip_prefix(&prefix).unwrap_or(&client_secret);
+    let key = key_store.login(&client_id, secret).await.map_err(|e| match e {
+        LoginError::Invalid => TokenError::new("invalid_client", "unknown client or wrong secret"),
+        LoginError::Throttled(wait) => TokenError {
+            status: StatusCode::TOO_MANY_REQUESTS,
+            error: "temporarily_unavailable",
+            description: "too many failed attempts for this client".to_string(),
+            retry_after: Some(whole_secs(wait)),
+        },
+    })?;
+
+    let scopes = match form.get("scope") {
+        Some(requested) => {
+            let granted: BTreeSet<&str> = key.scopes.iter().map(String::as_str).collect();
+            let requested: Vec<String> = requested.split_whitespace().map(str::to_string).collect();
+            if let Some(scope) = requested.iter().find(|scope| !granted.contains(scope.as_str())) {
+                return Err(TokenError::new("invalid_scope", format!("scope {} is not granted to this client", scope)));
+            }
+            requested
+        }
+        None => key.scopes.clone(),
+    };
+
+    let identity = Identity { user_id: key.user, scopes: scopes.clone(), claims: Default::default() };
+    let token = authenticator
+        .issue(&key.id, &identity, config.ttl_secs)
+        .map_err(|e| TokenError {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            error: "server_error",
+            description: e.to_string(),
+            retry_after: None,
+        })?;
+    Ok(json!({
+        "access_token": token,
+        "token_type": "Bearer",
+        "expires_in": config.ttl_secs,
+        "scope": scopes.join(" "),
+        CLIENT_ID_CLAIM: key.id,
+    }))
+}
+
+/// The `client_id` and `client_secret` of a Basic `Authorization` header,
+/// form-decoded as RFC 6749 section 2.3.1 requires.
+fn basic_credentials(headers: &HeaderMap) -> Option<(String, String)> {
+    let encoded = headers.get(AUTHORIZATION)?.to_str().ok()?.strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(STANDARD.decode(encoded.trim()).ok()?).ok()?;
+    let (id, secret) = decoded.split_once(':')?;
+    let decode = |part: &str| form_urlencoded::parse(format!("v={}", part).as_bytes()).next().map(|(_, value)| value.into_owned());
+    Some((decode(id)?, decode(secret)?))
+}
+
+fn token_response(result: Result<Value, TokenError>) -> Response<Body> {
+    let (status, body) = match &result {
+        Ok(token) => (StatusCode::OK, token.clone()),
+        Err(e) => (e.status, json!({ "error": e.error, "error_description": e.description })),
+    };
+    let mut response = Response::new(Body::from(body.to_string()));
+    *response.status_mut() = status;
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    headers.insert(PRAGMA, HeaderValue::from_static("no-cache"));
+    if status == StatusCode::UNAUTHORIZED {
+        headers.insert(WWW_AUTHENTICATE, HeaderValue::from_static("Basic realm=\"token\""));
+    }
+    if let Some(secs) = result.err().and_then(|e| e.retry_after) {
+        headers.insert(RETRY_AFTER, HeaderValue::from(secs));
+    }
+    response
+}
@@ -0,0 +1,435 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use base64::Engine;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use hyper::{Body, HeaderMap, Method, Request, Response, StatusCode, Uri, header::{HeaderValue, ACCEPT, AUTHORIZATION, CACHE_CONTROL, CONTENT_TYPE, LOCATION, SET_COOKIE}};
+use jsonwebtoken::{jwk::JwkSet, DecodingKey, Validation};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::digest::{digest, SHA256};
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tokio::sync::RwLock;
+use warp::{Filter, Rejection, Reply};
+use crate::auth::Identity;
+use crate::aws::{self, HttpsClient};
+use crate::config::{CsrfMode, OidcConfig};
+use crate::errors::GatewayError;
+use crate::handlers::handle_rejection;
+use crate::middleware::{cookie, remove_cookies};
+use crate::syslog;
+
+#[cfg(test)]
+mod tests;
+
+const DISCOVERY_PATH: &str = "/.well-known/openid-configuration";
+/// How long a browser has to come back from the provider.
+const LOGIN_TTL_SECS: u64 = 600;
+/// Browsers drop cookies over about 4 KB.
+const MAX_COOKIE_LEN: usize = 4000;
+/// ID token claims about the token itself, not the user; left out of
+/// sessions.
+const TOKEN_CLAIMS: [&str; 11] = ["iss", "aud", "exp", "iat", "nbf", "nonce", "at_hash", "c_hash", "azp", "auth_time", "jti"];
+
+/// The endpoints named by the provider's discovery document.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Provider {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub jwks_uri: String,
+}
+
+/// What the login cookie remembers between the redirect to the provider and
+/// the callback.
+#[derive(Debug, Serialize, Deserialize)]
+struct Login {
+    state: String,
+    nonce: String,
+    /// PKCE code verifier.
+    verifier: String,
+    /// Path and query the browser asked for.
+    return_to: String,
+    exp: u64,
+}
+
+/// A signed-in user, kept in the session cookie.
+#[derive(Debug, Serialize, Deserialize)]
+struct Session {
+    sub: String,
+    claims: Map<String, Value>,
+    exp: u64,
+}
+
+/// The gateway's side of the OIDC authorization-code flow, and the session
+/// cookies it leads to. Cookies are sealed with AES-256-GCM, so browsers can
+/// neither read nor forge them.
+pub struct Oidc {
+    config: OidcConfig,
+    callback_path: String,
+    key: LessSafeKey,
+    /// Derives each session's CSRF token from its cookie.
+    csrf_key: hmac::Key,
+    random: SystemRandom,
+    client: HttpsClient,
+    provider: RwLock<Option<Arc<Provider>>>,
+    jwks: RwLock<JwkSet>,
+}
+
+impl Oidc {
+    pub fn new(config: &OidcConfig) -> Result<Self, String> {
+        let callback_path = config
+            .redirect_uri
+            .parse::<Uri>()
+            .map_err(|e| format!("auth.oidc.redirect_uri: {}", e))?
+            .path()
+            .to_string();
+        let secret = digest(&SHA256, config.session.secret.as_bytes());
+        let key = UnboundKey::new(&AES_256_GCM, secret.as_ref()).map_err(|_| "deriving the session key failed".to_string())?;
+        Ok(Self {
+            config: config.clone(),
+            callback_path,
+            key: LessSafeKey::new(key),
+            csrf_key: hmac::Key::new(hmac::HMAC_SHA256, config.session.secret.as_bytes()),
+            random: SystemRandom::new(),
+            client: aws::https_client(),
+            provider: RwLock::new(None),
+            jwks: RwLock::new(JwkSet { keys: Vec::new() }),
+        })
+    }
+
+    pub fn callback_path(&self) -> &str {
+        &self.callback_path
+    }
+
+    fn login_cookie(&self) -> String {
+        format!("{}_login", self.config.session.cookie_name)
+    }
+
+    /// The signed-in user behind the request's session cookie, if it is
+    /// genuine and hasn't expired.
+    pub fn session(&self, headers: &HeaderMap) -> Option<Identity> {
+        let name = &self.config.session.cookie_name;
+        let session: Session = serde_json::from_slice(&self.open(name, cookie(headers, name)?)?).ok()?;
+        if session.exp <= now() {
+            return None;
+        }
+        let mut claims = session.claims;
+        claims.insert("sub".to_string(), Value::from(session.sub.clone()));
+        Some(Identity { user_id: session.sub, scopes: Vec::new(), claims })
+    }
+
+    /// The session cookie of a request that relies on it, i.e. one without
+    /// an `Authorization` header. Other requests can't be forged cross-site.
+    pub fn session_cookie(&self, headers: &HeaderMap) -> Option<String> {
+        if headers.contains_key(AUTHORIZATION) {
+            return None;
+        }
+        cookie(headers, &self.config.session.cookie_name).map(str::to_string)
+    }
+
+    /// Refuses a state-changing request made with the session cookie `session`
+    /// unless it passes the route's CSRF check, or else `auth.oidc.csrf.mode`.
+    pub fn check_csrf(&self, mode: Option<CsrfMode>, method: &Method, headers: &HeaderMap, session: &str) -> Result<(), GatewayError> {
+        if matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE) {
+            return Ok(());
+        }
+        let presented = headers.get(self.config.csrf.header.as_str());
+        let passed = match mode.unwrap_or(self.config.csrf.mode) {
+            CsrfMode::Off => true,
+            CsrfMode::CustomHeader => presented.is_some(),
+            CsrfMode::DoubleSubmit => presented
+                .and_then(|token| URL_SAFE_NO_PAD.decode(token.as_bytes()).ok())
+                .is_some_and(|token| hmac::verify(&self.csrf_key, session.as_bytes(), &token).is_ok()),
+        };
+        if passed {
+            Ok(())
+        } else {
+            Err(GatewayError::Forbidden(format!("missing or invalid {}", self.config.csrf.header)))
+        }
+    }
+
+    fn csrf_token(&self, session: &str) -> String {
+        URL_SAFE_NO_PAD.encode(hmac::sign(&self.csrf_key, session.as_bytes()))
+    }
+
+    /// Removes the gateway's cookies so upstreams never see them.
+    pub fn strip_cookies(&self, headers: &mut HeaderMap) {
+        let names = [&self.config.session.cookie_name, &self.login_cookie(), &self.config.csrf.cookie_name];
+        remove_cookies(headers, &names.map(String::as_str));
+    }
+
+    /// Whether an unauthenticated request should be sent to the provider
+    /// rather than refused: a page load, not an API call.
+    pub fn wants_login(method: &Method, headers: &HeaderMap) -> bool {
+        let accepts_html = headers
+            .get_all(ACCEPT)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .any(|value| value.contains("text/html"));
+        (method == Method::GET || method == Method::HEAD) && accepts_html
+    }
+
+    /// Redirects the browser to the provider, remembering `return_to` for
+    /// when it comes back.
+    pub async fn login(&self, return_to: &str) -> Result<Response<Body>, GatewayError> {
+        let provider = self.provider().await.map_err(GatewayError::Http)?;
+        let login = Login {
+            state: self.random_token()?,
+            nonce: self.random_token()?,
+            verifier: self.random_token()?,
+            return_to: return_to.to_string(),
+            exp: now() + LOGIN_TTL_SECS,
+        };
+        let challenge = URL_SAFE_NO_PAD.encode(digest(&SHA256, login.verifier.as_bytes()));
+        let query: String = form_urlencoded::Serializer::new(String::new())
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.config.client_id)
+            .append_pair("redirect_uri", &self.config.redirect_uri)
+            .append_pair("scope", &self.config.scopes.join(" "))
+            .append_pair("state", &login.state)
+            .append_pair("nonce", &login.nonce)
+            .append_pair("code_challenge", &challenge)
+            .append_pair("code_challenge_method", "S256")
+            .finish();
+        let separator = if provider.authorization_endpoint.contains('?') { '&' } else { '?' };
+        let location = format!("{}{}{}", provider.authorization_endpoint, separator, query);
+
+        let sealed = self.seal(&self.login_cookie(), &serde_json::to_vec(&login).map_err(|e| GatewayError::Http(e.to_string()))?)?;
+        let cookie = self.cookie(&self.login_cookie(), &sealed, &self.callback_path, LOGIN_TTL_SECS);
+        Ok(redirect(&location, &[cookie]))
+    }
+
+    /// Handles the provider's redirect back: checks `state` against the login
+    /// cookie, redeems the code, verifies the ID token and starts a session.
+    pub async fn callback(&self, headers: &HeaderMap, query: &str) -> Result<Response<Body>, GatewayError> {
+        let params: HashMap<String, String> = form_urlencoded::parse(query.as_bytes()).into_owned().collect();
+        if let Some(error) = params.get("error") {
+            let description = params.get("error_description").map(String::as_str).unwrap_or_default();
+            syslog::error(format_args!("OIDC sign-in refused by the provider: {} {}", error, description));
+            return Err(GatewayError::Unauthorized);
+        }
+        let (Some(code), Some(state)) = (params.get("code"), params.get("state")) else {
+            return Err(GatewayError::BadRequest("code and state are required".to_string()));
+        };
+        let login: Login = cookie(headers, &self.login_cookie())
+            .and_then(|sealed| self.open(&self.login_cookie(), sealed))
+            .and_then(|login| serde_json::from_slice(&login).ok())
+            .filter(|login: &Login| login.exp > now() && &login.state == state)
+            .ok_or_else(|| GatewayError::BadRequest("sign-in expired or was started elsewhere".to_string()))?;
+
+        let provider = self.provider().await.map_err(GatewayError::Http)?;
+        let id_token = self.redeem(&provider, code, &login.verifier).await.map_err(|e| {
+            syslog::error(format_args!("OIDC code exchange failed: {}", e));
+            GatewayError::Unauthorized
+        })?;
+        let claims = self.verify(&provider, &id_token, &login.nonce).await.map_err(|e| {
+            syslog::error(format_args!("OIDC ID token rejected: {}", e));
+            GatewayError::Unauthorized
+        })?;
+
+        let sealed = self.seal_session(claims)?;
+        let ttl_secs = self.config.session.ttl_secs;
+        let session = self.cookie(&self.config.session.cookie_name, &sealed, "/", ttl_secs);
+        let cleared = self.cookie(&self.login_cookie(), "", &self.callback_path, 0);
+        // Readable by the application's scripts, which send it back in the
+        // CSRF header.
+        let csrf = self.script_cookie(&self.config.csrf.cookie_name, &self.csrf_token(&sealed), "/", ttl_secs);
+        // Only ever a path on this gateway, never another site.
+        let return_to = Some(login.return_to.as_str())
+            .filter(|path| path.starts_with('/') && !path.starts_with("//") && !path.starts_with("/\\"))
+            .unwrap_or("/");
+        Ok(redirect(return_to, &[session, cleared, csrf]))
+    }
+
+    /// Clears the session and sends the browser to `/`.
+    pub fn sign_out(&self) -> Response<Body> {
+        let session = self.cookie(&self.config.session.cookie_name, "", "/", 0);
+        let csrf = self.script_cookie(&self.config.csrf.cookie_name, "", "/", 0);
+        redirect("/", &[session, csrf])
+    }
+
+    fn seal_session(&self, mut claims: Map<String, Value>) -> Result<String, GatewayError> {
+        let sub = claims
+            .remove("sub")
+            .and_then(|sub| sub.as_str().map(str::to_string))
+            .ok_or(GatewayError::Unauthorized)?;
+        for name in TOKEN_CLAIMS {
+            claims.remove(name);
+        }
+        let name = &self.config.session.cookie_name;
+        let mut session = Session { sub, claims, exp: now() + self.config.session.ttl_secs };
+        let sealed = self.seal(name, &serde_json::to_vec(&session).map_err(|e| GatewayError::Http(e.to_string()))?)?;
+        if sealed.len() <= MAX_COOKIE_LEN {
+            return Ok(sealed);
+        }
+        // Too many claims for a cookie: keep the ones that name the user.
+        session.claims.retain(|name, _| ["email", "name", "preferred_username"].contains(&name.as_str()));
+        self.seal(name, &serde_json::to_vec(&session).map_err(|e| GatewayError::Http(e.to_string()))?)
+    }
+
+    /// The discovery document, fetched once.
+    pub async fn provider(&self) -> Result<Arc<Provider>, String> {
+        if let Some(provider) = self.provider.read().await.as_ref() {
+            return Ok(provider.clone());
+        }
+        let url = format!("{}{}", self.config.issuer.trim_end_matches('/'), DISCOVERY_PATH);
+        let request = Request::get(url).body(Body::empty()).map_err(|e| e.to_string())?;
+        let body = aws::send(&self.client, request).await.map_err(|e| format!("OIDC discovery {}", e))?;
+        let provider: Provider = serde_json::from_slice(&body).map_err(|e| format!("invalid OIDC discovery document: {}", e))?;
+        if provider.issuer.trim_end_matches('/') != self.config.issuer.trim_end_matches('/') {
+            return Err(format!("discovery document is for issuer {}", provider.issuer));
+        }
+        let provider = Arc::new(provider);
+        *self.provider.write().await = Some(provider.clone());
+        Ok(provider)
+    }
+
+    /// Trades the authorization code for the ID token, authenticating with
+    /// HTTP Basic as RFC 6749 section 2.3.1 describes.
+    async fn redeem(&self, provider: &Provider, code: &str, verifier: &str) -> Result<String, String> {
+        let form = form_urlencoded::Serializer::new(String::new())
+            .append_pair("grant_type", "authorization_code")
+            .append_pair("code", code)
+            .append_pair("redirect_uri", &self.config.redirect_uri)
+            .append_pair("code_verifier", verifier)
+            .finish();
+        let encode = |value: &str| form_urlencoded::byte_serialize(value.as_bytes()).collect::<String>();
+        let credentials = STANDARD.encode(format!("{}:{}", encode(&self.config.client_id), encode(&self.config.client_secret)));
+        let request = Request::post(&provider.token_endpoint)
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .header(AUTHORIZATION, format!("Basic {}", credentials))
+            .body(Body::from(form))
+            .map_err(|e| e.to_string())?;
+        let body = aws::send(&self.client, request).await.map_err(|e| format!("token endpoint {}", e))?;
+        let response: Value = serde_json::from_slice(&body).map_err(|e| format!("invalid token response: {}", e))?;
+        response["id_token"]
+            .as_str()
+            .map(str::to_string)
+            .ok_or_else(|| "token response has no id_token".to_string())
+    }
+
+    /// The claims of a genuine ID token for this client and sign-in.
+    async fn verify(&self, provider: &Provider, id_token: &str, nonce: &str) -> Result<Map<String, Value>, String> {
+        let header = jsonwebtoken::decode_header(id_token).map_err(|e| e.to_string())?;
+        let key = match self.key(provider, header.kid.as_deref(), false).await? {
+            Some(key) => key,
+            // The provider may have rotated its keys since they were fetched.
+            None => self.key(provider, header.kid.as_deref(), true).await?.ok_or("no signing key matches the ID token")?,
+        };
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&provider.issuer]);
+        validation.set_audience(&[&self.config.client_id]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+        let claims = jsonwebtoken::decode::<Map<String, Value>>(id_token, &key, &validation)
+            .map_err(|e| e.to_string())?
+            .claims;
+        if claims.get("nonce").and_then(Value::as_str) != Some(nonce) {
+            return Err("nonce does not match".to_string());
+        }
+        Ok(claims)
+    }
+
+    async fn key(&self, provider: &Provider, kid: Option<&str>, refetch: bool) -> Result<Option<DecodingKey>, String> {
+        if refetch || self.jwks.read().await.keys.is_empty() {
+            let request = Request::get(&provider.jwks_uri).body(Body::empty()).map_err(|e| e.to_string())?;
+            let body = aws::send(&self.client, request).await.map_err(|e| format!("JWKS {}", e))?;
+            *self.jwks.write().await = serde_json::from_slice(&body).map_err(|e| format!("invalid JWKS: {}", e))?;
+        }
+        let jwks = self.jwks.read().await;
+        let jwk = match kid {
+            Some(kid) => jwks.find(kid),
+            None if jwks.keys.len() == 1 => jwks.keys.first(),
+            None => None,
+        };
+        jwk.map(|jwk| DecodingKey::from_jwk(jwk).map_err(|e| e.to_string())).transpose()
+    }
+
+    fn random_token(&self) -> Result<String, GatewayError> {
+        let mut bytes = [0u8; 32];
+        self.random.fill(&mut bytes).map_err(|_| GatewayError::Http("no randomness available".to_string()))?;
+        Ok(URL_SAFE_NO_PAD.encode(bytes))
+    }
+
+    /// `plaintext` encrypted for the cookie `name`; a value sealed for one
+    /// cookie doesn't open as another.
+    fn seal(&self, name: &str, plaintext: &[u8]) -> Result<String, GatewayError> {
+        let mut nonce = [0u8; NONCE_LEN];
+        self.random.fill(&mut nonce).map_err(|_| GatewayError::Http("no randomness available".to_string()))?;
+        let mut sealed = plaintext.to_vec();
+        self.key
+            .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(name.as_bytes()), &mut sealed)
+            .map_err(|_| GatewayError::Http("sealing the cookie failed".to_string()))?;
+        Ok(URL_SAFE_NO_PAD.encode([nonce.as_slice(), &sealed].concat()))
+    }
+
+    fn open(&self, name: &str, value: &str) -> Option<Vec<u8>> {
+        let mut sealed = URL_SAFE_NO_PAD.decode(value).ok()?;
+        if sealed.len() < NONCE_LEN {
+            return None;
+        }
+        let nonce = Nonce::try_assume_unique_for_key(&sealed[..NONCE_LEN]).ok()?;
+        let opened = self.key.open_in_place(nonce, Aad::from(name.as_bytes()), &mut sealed[NONCE_LEN..]).ok()?;
+        Some(opened.to_vec())
+    }
+
+    fn cookie(&self, name: &str, value: &str, path: &str, max_age: u64) -> String {
+        format!("{}; HttpOnly", self.script_cookie(name, value, path, max_age))
+    }
+
+    /// A cookie the application's scripts can read.
+    fn script_cookie(&self, name: &str, value: &str, path: &str, max_age: u64) -> String {
+        let secure = if self.config.session.secure { "; Secure" } else { "" };
+        format!("{}={}; Path={}; Max-Age={}; SameSite=Lax{}", name, value, path, max_age, secure)
+    }
+}
+
+/// The callback and sign-out endpoints. Other paths are passed on.
+pub fn routes(oidc: Option<Arc<Oidc>>) -> impl Filter<Extract = (Response<Body>,), Error = Rejection> + Clone {
+    warp::get()
+        .and(warp::path::full())
+        .and(warp::header::headers_cloned())
+        .and(warp::query::raw().or_else(|_| async { Ok::<(String,), Rejection>((String::new(),)) }))
+        .and_then(move |path: warp::path::FullPath, headers: HeaderMap, query: String| {
+            let oidc = oidc.clone();
+            async move {
+                let oidc = oidc.ok_or_else(warp::reject::not_found)?;
+                let result = if path.as_str() == oidc.callback_path() {
+                    oidc.callback(&headers, &query).await
+                } else if path.as_str() == oidc.config.sign_out_path {
+                    Ok(oidc.sign_out())
+                } else {
+                    return Err(warp::reject::not_found());
+                };
+                Ok::<_, Rejection>(match result {
+                    Ok(response) => response,
+                    Err(e) => {
+                        let Ok(reply) = handle_rejection(warp::reject::custom(e)).await;
+                        reply.into_response()
+                    }
+                })
+            }
+        })
+}
+
+fn redirect(location: &str, cookies: &[String]) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = StatusCode::FOUND;
+    let headers = response.headers_mut();
+    if let Ok(value) = HeaderValue::from_str(location) {
+        headers.insert(LOCATION, value);
+    }
+    for cookie in cookies {
+        if let Ok(value) = HeaderValue::from_str(cookie) {
+            headers.append(SET_COOKIE, value);
+        }
+    }
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    response
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
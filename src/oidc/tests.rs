@@ -0,0 +1,236 @@
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use base64::Engine;
+    use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+    use hyper::{HeaderMap, Method, Response, Body};
+    use jsonwebtoken::{Algorithm, EncodingKey, Header};
+    use ring::digest::{digest, SHA256};
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+    use serde_json::{json, Value};
+    use warp::Filter;
+    use crate::config::{CsrfConfig, CsrfMode, OidcConfig, SessionConfig};
+    use crate::errors::GatewayError;
+    use crate::oidc::Oidc;
+
+    /// What the provider puts in the next ID token.
+    type NextClaims = Arc<Mutex<Value>>;
+
+    /// A provider serving discovery, its key set and a token endpoint that
+    /// checks the client's credentials and PKCE verifier.
+    fn provider(challenge: Arc<Mutex<String>>) -> (String, NextClaims) {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+        let pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &rng).unwrap();
+        let point = pair.public_key().as_ref();
+        let jwks = json!({ "keys": [{
+            "kty": "EC", "crv": "P-256", "kid": "k1", "alg": "ES256", "use": "sig",
+            "x": URL_SAFE_NO_PAD.encode(&point[1..33]),
+            "y": URL_SAFE_NO_PAD.encode(&point[33..]),
+        }]});
+        let signing_key = EncodingKey::from_ec_der(pkcs8.as_ref());
+        let claims: NextClaims = Arc::new(Mutex::new(Value::Null));
+
+        let addr = Arc::new(Mutex::new(String::new()));
+        let discovery = warp::path!(".well-known" / "openid-configuration").map({
+            let addr = addr.clone();
+            move || {
+                let issuer = addr.lock().unwrap().clone();
+                warp::reply::json(&json!({
+                    "issuer": issuer,
+                    "authorization_endpoint": format!("{}/authorize", issuer),
+                    "token_endpoint": format!("{}/token", issuer),
+                    "jwks_uri": format!("{}/jwks", issuer),
+                }))
+            }
+        });
+        let keys = warp::path!("jwks").map(move || warp::reply::json(&jwks));
+        let token = warp::path!("token")
+            .and(warp::header::<String>("authorization"))
+            .and(warp::body::form())
+            .map({
+                let claims = claims.clone();
+                move |authorization: String, form: HashMap<String, String>| {
+                    assert_eq!(authorization, format!("Basic {}", STANDARD.encode("gateway:client-secret")));
+                    assert_eq!(form["grant_type"], "authorization_code");
+                    assert_eq!(form["code"], "the-code");
+                    let verified = URL_SAFE_NO_PAD.encode(digest(&SHA256, form["code_verifier"].as_bytes()));
+                    assert_eq!(verified, *challenge.lock().unwrap());
+                    let mut header = Header::new(Algorithm::ES256);
+                    header.kid = Some("k1".to_string());
+                    let id_token = jsonwebtoken::encode(&header, &*claims.lock().unwrap(), &signing_key).unwrap();
+                    warp::reply::json(&json!({ "id_token": id_token, "access_token": "opaque" }))
+                }
+            });
+        let (bound, server) = warp::serve(discovery.or(keys).or(token)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        *addr.lock().unwrap() = format!("http://{}", bound);
+        (format!("http://{}", bound), claims)
+    }
+
+    fn oidc(issuer: &str) -> Oidc {
+        Oidc::new(&OidcConfig {
+            issuer: issuer.to_string(),
+            client_id: "gateway".to_string(),
+            client_secret: "client-secret".to_string(),
+            redirect_uri: "https://gateway.example.com/oauth2/callback".to_string(),
+            scopes: vec!["openid".to_string(), "email".to_string()],
+            sign_out_path: "/oauth2/sign_out".to_string(),
+            session: SessionConfig {
+                secret: "0123456789abcdef0123456789abcdef".to_string(),
+                cookie_name: "gateway_session".to_string(),
+                ttl_secs: 3600,
+                secure: true,
+            },
+            csrf: CsrfConfig::default(),
+        })
+        .unwrap()
+    }
+
+    fn now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    fn set_cookies(response: &Response<Body>) -> Vec<String> {
+        response.headers().get_all("set-cookie").iter().map(|value| value.to_str().unwrap().to_string()).collect()
+    }
+
+    /// The request `Cookie` header for a `Set-Cookie` value.
+    fn cookie_header(set_cookie: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("cookie", set_cookie.split(';').next().unwrap().parse().unwrap());
+        headers
+    }
+
+    #[tokio::test]
+    async fn test_sign_in_through_the_provider() {
+        let challenge = Arc::new(Mutex::new(String::new()));
+        let (issuer, claims) = provider(challenge.clone());
+        let oidc = oidc(&issuer);
+
+        let response = oidc.login("/app/orders?page=2").await.unwrap();
+        assert_eq!(response.status(), 302);
+        let location = response.headers()["location"].to_str().unwrap();
+        assert!(location.starts_with(&format!("{}/authorize?", issuer)));
+        let params: HashMap<String, String> =
+            form_urlencoded::parse(location.split_once('?').unwrap().1.as_bytes()).into_owned().collect();
+        assert_eq!(params["client_id"], "gateway");
+        assert_eq!(params["redirect_uri"], "https://gateway.example.com/oauth2/callback");
+        assert_eq!(params["scope"], "openid email");
+        assert_eq!(params["code_challenge_method"], "S256");
+        *challenge.lock().unwrap() = params["code_challenge"].clone();
+        let login_cookie = set_cookies(&response).remove(0);
+        assert!(login_cookie.starts_with("gateway_session_login="));
+        assert!(login_cookie.contains("Path=/oauth2/callback") && login_cookie.contains("HttpOnly") && login_cookie.contains("Secure"));
+
+        *claims.lock().unwrap() = json!({
+            "iss": issuer, "aud": "gateway", "sub": "user-7", "exp": now() + 300,
+            "nonce": params["nonce"], "email": "ada@example.com",
+        });
+        let query = format!("code=the-code&state={}", params["state"]);
+        let response = oidc.callback(&cookie_header(&login_cookie), &query).await.unwrap();
+        assert_eq!(response.headers()["location"], "/app/orders?page=2");
+        let cookies = set_cookies(&response);
+        assert!(cookies[1].starts_with("gateway_session_login=;") && cookies[1].contains("Max-Age=0"));
+        assert!(cookies[2].starts_with("gateway_csrf=") && !cookies[2].contains("HttpOnly"));
+
+        let mut headers = cookie_header(&cookies[0]);
+        let identity = oidc.session(&headers).unwrap();
+        assert_eq!(identity.user_id, "user-7");
+        assert_eq!(identity.claim("email").as_deref(), Some("ada@example.com"));
+        assert!(!identity.claims.contains_key("nonce"));
+
+        // A session can't be passed off as a login, or tampered with.
+        let value = cookies[0].split(';').next().unwrap().split_once('=').unwrap().1;
+        let mut forged = HeaderMap::new();
+        forged.insert("cookie", format!("gateway_session_login={}", value).parse().unwrap());
+        assert!(oidc.callback(&forged, &query).await.is_err());
+        let mut tampered = HeaderMap::new();
+        tampered.insert("cookie", format!("gateway_session={}A", &value[..value.len() - 1]).parse().unwrap());
+        assert!(oidc.session(&tampered).is_none());
+
+        oidc.strip_cookies(&mut headers);
+        assert!(!headers.contains_key("cookie"));
+        assert!(set_cookies(&oidc.sign_out())[0].contains("Max-Age=0"));
+    }
+
+    #[tokio::test]
+    async fn test_callback_rejects_bad_sign_ins() {
+        let challenge = Arc::new(Mutex::new(String::new()));
+        let (issuer, claims) = provider(challenge.clone());
+        let oidc = oidc(&issuer);
+        let start = || async {
+            let response = oidc.login("//evil.example.com/").await.unwrap();
+            let location = response.headers()["location"].to_str().unwrap().to_string();
+            let params: HashMap<String, String> =
+                form_urlencoded::parse(location.split_once('?').unwrap().1.as_bytes()).into_owned().collect();
+            *challenge.lock().unwrap() = params["code_challenge"].clone();
+            (cookie_header(&set_cookies(&response)[0]), params)
+        };
+
+        let (headers, params) = start().await;
+        let wrong_state = oidc.callback(&headers, "code=the-code&state=other").await;
+        assert!(matches!(wrong_state, Err(GatewayError::BadRequest(_))));
+        assert!(matches!(oidc.callback(&HeaderMap::new(), &format!("code=the-code&state={}", params["state"])).await, Err(GatewayError::BadRequest(_))));
+        assert!(matches!(oidc.callback(&headers, "error=access_denied").await, Err(GatewayError::Unauthorized)));
+
+        *claims.lock().unwrap() = json!({ "iss": issuer, "aud": "gateway", "sub": "user-7", "exp": now() + 300, "nonce": "replayed" });
+        let replayed = oidc.callback(&headers, &format!("code=the-code&state={}", params["state"])).await;
+        assert!(matches!(replayed, Err(GatewayError::Unauthorized)));
+
+        *claims.lock().unwrap() = json!({ "iss": issuer, "aud": "other-client", "sub": "user-7", "exp": now() + 300, "nonce": params["nonce"] });
+        let foreign = oidc.callback(&headers, &format!("code=the-code&state={}", params["state"])).await;
+        assert!(matches!(foreign, Err(GatewayError::Unauthorized)));
+
+        // A return path that leaves the gateway is replaced with `/`.
+        *claims.lock().unwrap() = json!({ "iss": issuer, "aud": "gateway", "sub": "user-7", "exp": now() + 300, "nonce": params["nonce"] });
+        let response = oidc.callback(&headers, &format!("code=the-code&state={}", params["state"])).await.unwrap();
+        assert_eq!(response.headers()["location"], "/");
+    }
+
+    #[test]
+    fn test_csrf_checks() {
+        let oidc = oidc("https://idp.example.com");
+        let session = oidc.seal_session(json!({ "sub": "user-7" }).as_object().unwrap().clone()).unwrap();
+        let other = oidc.seal_session(json!({ "sub": "user-8" }).as_object().unwrap().clone()).unwrap();
+        let with_token = |token: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("x-csrf-token", token.parse().unwrap());
+            headers
+        };
+
+        assert!(oidc.check_csrf(None, &Method::GET, &HeaderMap::new(), &session).is_ok());
+        let missing = oidc.check_csrf(None, &Method::POST, &HeaderMap::new(), &session);
+        assert!(matches!(missing, Err(GatewayError::Forbidden(_))));
+        assert!(oidc.check_csrf(None, &Method::POST, &with_token(&oidc.csrf_token(&session)), &session).is_ok());
+        assert!(oidc.check_csrf(None, &Method::DELETE, &with_token(&oidc.csrf_token(&other)), &session).is_err());
+        assert!(oidc.check_csrf(None, &Method::PUT, &with_token("not-a-token"), &session).is_err());
+
+        assert!(oidc.check_csrf(Some(CsrfMode::CustomHeader), &Method::POST, &with_token("1"), &session).is_ok());
+        assert!(oidc.check_csrf(Some(CsrfMode::CustomHeader), &Method::POST, &HeaderMap::new(), &session).is_err());
+        assert!(oidc.check_csrf(Some(CsrfMode::Off), &Method::POST, &HeaderMap::new(), &session).is_ok());
+
+        // Only requests that rely on the cookie are checked.
+        let mut headers = HeaderMap::new();
+        headers.insert("cookie", format!("gateway_session={}", session).parse().unwrap());
+        assert_eq!(oidc.session_cookie(&headers), Some(session));
+        headers.insert("authorization", "Bearer example-token".parse().unwrap());
+        assert_eq!(oidc.session_cookie(&headers), None);
+    }
+
+    #[test]
+    fn test_only_page_loads_are_sent_to_sign_in() {
+        let mut browser = HeaderMap::new();
+        browser.insert("accept", "text/html,application/xhtml+xml,*/*;q=0.8".parse().unwrap());
+        assert!(Oidc::wants_login(&Method::GET, &browser));
+        assert!(!Oidc::wants_login(&Method::POST, &browser));
+
+        let mut api = HeaderMap::new();
+        api.insert("accept", "application/json".parse().unwrap());
+        assert!(!Oidc::wants_login(&Method::GET, &api));
+        assert!(!Oidc::wants_login(&Method::GET, &HeaderMap::new()));
+    }
+}
@@ -0,0 +1,240 @@
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use hyper::header::{CACHE_CONTROL, CONTENT_TYPE};
+use hyper::{Body, Method, Request, Response, StatusCode, Uri};
+use serde_json::{json, Map, Value};
+use tokio::time::timeout;
+use warp::{Filter, Rejection};
+use crate::config::{OpenApiConfig, SwaggerUiAssets, OPENAPI_SPEC_PATH};
+use crate::connector::UpstreamClient;
+use crate::errors::GatewayError;
+use crate::formats::escape_xml;
+use crate::routes::{has_segment_prefix, LiveRouteTable, Route, RouteTable};
+use crate::syslog;
+
+#[cfg(test)]
+mod tests;
+
+/// The OpenAPI version of the merged document. Sources are expected to be
+/// 3.0 or 3.1.
+pub const OPENAPI_VERSION: &str = "3.0.3";
+const OPERATIONS: [&str; 8] = ["get", "put", "post", "delete", "options", "head", "patch", "trace"];
+/// Swagger UI, loaded from a CDN at a pinned version and checked against its
+/// integrity hashes, pointed at the merged document.
+const SWAGGER_UI: &str = r##"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>{title}</title>
+<link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@{version}/swagger-ui.css" integrity="{css_integrity}" crossorigin="anonymous">
+</head>
+<body>
+<div id="swagger-ui"></div>
+<script src="https://unpkg.com/swagger-ui-dist@{version}/swagger-ui-bundle.js" integrity="{bundle_integrity}" crossorigin="anonymous"></script>
+<script>SwaggerUIBundle({ url: "{spec}", dom_id: "#swagger-ui" });</script>
+</body>
+</html>
+"##;
+
+/// The backends' OpenAPI documents, as clients see them through the
+/// gateway. Each source's document is refetched every
+/// `refresh_interval_secs`; one that can't be fetched keeps its last good
+/// copy. Documents are fetched with the proxy's upstream client, so its DNS
+/// overrides, upstream TLS and egress policy apply.
+pub struct OpenApi {
+    config: OpenApiConfig,
+    client: UpstreamClient,
+    /// Each source's document with its paths rewritten, once fetched.
+    documents: RwLock<Vec<Option<Value>>>,
+}
+
+impl OpenApi {
+    pub fn new(config: &OpenApiConfig, client: UpstreamClient) -> Self {
+        Self {
+            config: config.clone(),
+            client,
+            documents: RwLock::new(vec![None; config.sources.len()]),
+        }
+    }
+
+    /// Fetches every source's document again.
+    pub async fn refresh(&self, route_table: &RouteTable) {
+        for (i, source) in self.config.sources.iter().enumerate() {
+            match self.fetch(route_table, &source.route, &source.path).await {
+                Ok(document) => self.documents.write().unwrap_or_else(|e| e.into_inner())[i] = Some(document),
+                Err(e) => syslog::error(format_args!("Failed to fetch the OpenAPI document of route {}: {}", source.route, e)),
+            }
+        }
+    }
+
+    pub async fn watch(self: Arc<Self>, route_table: Arc<LiveRouteTable>) {
+        loop {
+            self.refresh(&route_table.load()).await;
+            tokio::time::sleep(Duration::from_secs(self.config.refresh_interval_secs)).await;
+        }
+    }
+
+    /// The merged document. Where two sources describe the same path or
+    /// component, the one listed first wins.
+    pub fn document(&self) -> Result<Value, GatewayError> {
+        let mut paths = Map::new();
+        let mut components = Map::new();
+        let mut tags: Vec<Value> = Vec::new();
+        for document in self.documents.read().unwrap_or_else(|e| e.into_inner()).iter().flatten() {
+            for (path, item) in document["paths"].as_object().into_iter().flatten() {
+                let merged = merged_object(&mut paths, path)?;
+                for (key, value) in item.as_object().into_iter().flatten() {
+                    merged.entry(key.clone()).or_insert_with(|| value.clone());
+                }
+            }
+            for (section, entries) in document["components"].as_object().into_iter().flatten() {
+                let merged = merged_object(&mut components, section)?;
+                for (name, value) in entries.as_object().into_iter().flatten() {
+                    merged.entry(name.clone()).or_insert_with(|| value.clone());
+                }
+            }
+            for tag in document["tags"].as_array().into_iter().flatten() {
+                if !tags.iter().any(|known| known["name"] == tag["name"]) {
+                    tags.push(tag.clone());
+                }
+            }
+        }
+        let mut merged = json!({
+            "openapi": OPENAPI_VERSION,
+            "info": { "title": self.config.title, "version": self.config.version },
+            "paths": paths,
+        });
+        if let Some(url) = &self.config.server_url {
+            merged["servers"] = json!([{ "url": url }]);
+        }
+        if !components.is_empty() {
+            merged["components"] = Value::Object(components);
+        }
+        if !tags.is_empty() {
+            merged["tags"] = Value::Array(tags);
+        }
+        Ok(merged)
+    }
+
+    async fn fetch(&self, route_table: &RouteTable, name: &str, path: &str) -> Result<Value, String> {
+        let route = route_table
+            .routes()
+            .iter()
+            .find(|route| route.name == name)
+            .ok_or_else(|| format!("no route named {}", name))?;
+        let upstream = route
+            .deployment
+            .as_ref()
+            .map(|deployment| deployment.upstream())
+            .or(route.upstream())
+            .ok_or_else(|| format!("route {} has no upstream", name))?;
+        let url = format!("{}{}", upstream.trim_end_matches('/'), path);
+        let request = Request::get(&url).body(Body::empty()).map_err(|e| e.to_string())?;
+        let response = timeout(Duration::from_millis(self.config.timeout_ms), self.client.request(request))
+            .await
+            .map_err(|_| format!("{} timed out", url))?
+            .map_err(|e| format!("{}: {}", url, e))?;
+        if !response.status().is_success() {
+            return Err(format!("{} answered {}", url, response.status()));
+        }
+        let body = hyper::body::to_bytes(response.into_body()).await.map_err(|e| format!("{}: {}", url, e))?;
+        let document: Value = serde_json::from_slice(&body).map_err(|e| format!("{} is not a JSON document: {}", url, e))?;
+        Ok(gateway_paths(&document, route, upstream))
+    }
+}
+
+/// `document` with each path as a client requests it through `route`, which
+/// forwards to `upstream`. Paths the route doesn't reach, and operations on
+/// methods it doesn't allow, are left out, as are the document's servers.
+pub fn gateway_paths(document: &Value, route: &Route, upstream: &str) -> Value {
+    let server_base = document["servers"][0]["url"].as_str().map_or(String::new(), url_path);
+    let upstream_base = url_path(upstream);
+    let mut paths = Map::new();
+    for (path, item) in document["paths"].as_object().into_iter().flatten() {
+        let full = format!("{}{}", server_base, path);
+        if !has_segment_prefix(&full, &upstream_base) {
+            continue;
+        }
+        let rest = match &full[upstream_base.len()..] {
+            "" => "/",
+            rest => rest,
+        };
+        let external = match &route.strip_prefix {
+            Some(prefix) => format!("{}{}", prefix.trim_end_matches('/'), rest),
+            None => rest.to_string(),
+        };
+        if route.pattern.match_path(&external).is_none() {
+            continue;
+        }
+        let Some(item) = item.as_object() else {
+            continue;
+        };
+        let allowed = |key: &str| match Method::from_bytes(key.to_ascii_uppercase().as_bytes()) {
+            Ok(method) if OPERATIONS.contains(&key) => route.allows(&method),
+            _ => key != "servers",
+        };
+        let item: Map<String, Value> = item.iter().filter(|(key, _)| allowed(key)).map(|(k, v)| (k.clone(), v.clone())).collect();
+        if item.keys().any(|key| OPERATIONS.contains(&key.as_str())) {
+            paths.insert(external, Value::Object(item));
+        }
+    }
+    let mut rewritten = document.clone();
+    if let Some(document) = rewritten.as_object_mut() {
+        document.remove("servers");
+        document.insert("paths".to_string(), Value::Object(paths));
+    }
+    rewritten
+}
+
+/// The object under `key` in `map`, inserted empty if missing.
+fn merged_object<'a>(map: &'a mut Map<String, Value>, key: &str) -> Result<&'a mut Map<String, Value>, GatewayError> {
+    map.entry(key.to_string())
+        .or_insert_with(|| json!({}))
+        .as_object_mut()
+        .ok_or_else(|| GatewayError::Http(format!("OpenAPI entry {} is not an object", key)))
+}
+
+/// The Swagger UI page for the merged document.
+fn swagger_ui(title: &str, assets: &SwaggerUiAssets) -> String {
+    SWAGGER_UI
+        .replace("{title}", &escape_xml(title))
+        .replace("{version}", &escape_xml(&assets.version))
+        .replace("{css_integrity}", &escape_xml(&assets.css_integrity))
+        .replace("{bundle_integrity}", &escape_xml(&assets.bundle_integrity))
+        .replace("{spec}", OPENAPI_SPEC_PATH)
+}
+
+/// The path of an absolute or relative URL, without its trailing slash.
+fn url_path(url: &str) -> String {
+    let path = match url.parse::<Uri>() {
+        Ok(uri) if uri.scheme().is_some() => uri.path().to_string(),
+        _ => url.split(['?', '#']).next().unwrap_or_default().to_string(),
+    };
+    path.trim_end_matches('/').to_string()
+}
+
+/// `GET /openapi.json`, and Swagger UI if configured. Without `openapi`
+/// both paths are proxied like any other.
+pub fn routes(openapi: Option<Arc<OpenApi>>) -> impl Filter<Extract = (Response<Body>,), Error = Rejection> + Clone {
+    warp::get().and(warp::path::full()).and_then(move |path: warp::path::FullPath| {
+        let openapi = openapi.clone();
+        async move {
+            let openapi = openapi.ok_or_else(warp::reject::not_found)?;
+            let (content_type, body) = if path.as_str() == OPENAPI_SPEC_PATH {
+                ("application/json", openapi.document().map_err(warp::reject::custom)?.to_string())
+            } else if let Some(assets) = (openapi.config.swagger_ui_path.as_deref() == Some(path.as_str()))
+                .then_some(openapi.config.swagger_ui_assets.as_ref())
+                .flatten()
+            {
+                ("text/html; charset=utf-8", swagger_ui(&openapi.config.title, assets))
+            } else {
+                return Err(warp::reject::not_found());
+            };
+            let mut response = Response::new(Body::from(body));
+            *response.status_mut() = StatusCode::OK;
+            response.headers_mut().insert(CONTENT_TYPE, content_type.parse().unwrap());
+            response.headers_mut().insert(CACHE_CONTROL, "no-cache".parse().unwrap());
+            Ok::<_, Rejection>(response)
+        }
+    })
+}
@@ -0,0 +1,133 @@
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use serde_json::{json, Value};
+    use warp::http::StatusCode;
+    use warp::Filter;
+    use crate::config::{GatewayConfig, OpenApiConfig, OpenApiSource, SwaggerUiAssets, UpstreamConnectConfig};
+    use crate::connector::{UpstreamClient, UpstreamTls};
+    use crate::metrics::Connections;
+    use crate::openapi::{gateway_paths, routes, OpenApi};
+    use crate::routes::RouteTable;
+
+    fn route_table(routes: Value) -> RouteTable {
+        let config: GatewayConfig = serde_json::from_value(json!({ "routes": routes })).unwrap();
+        RouteTable::from_config(&config).unwrap()
+    }
+
+    #[test]
+    fn test_paths_are_rewritten_to_the_gateway() {
+        let route_table = route_table(json!([{
+            "name": "users",
+            "path_prefix": "/api/users",
+            "strip_prefix": "/api",
+            "upstream": "http://users:8080/v1",
+            "methods": ["GET"]
+        }]));
+        let document = json!({
+            "openapi": "3.0.3",
+            "servers": [{ "url": "https://users.internal/v1" }],
+            "paths": {
+                "/users": { "get": { "operationId": "listUsers" } },
+                "/users/{id}": {
+                    "parameters": [{ "name": "id", "in": "path", "required": true }],
+                    "get": { "operationId": "getUser" },
+                    "delete": { "operationId": "deleteUser" },
+                    "servers": [{ "url": "https://users.internal/v1" }]
+                },
+                "/users/{id}/avatar": { "put": { "operationId": "setAvatar" } },
+                "/health": { "get": { "operationId": "health" } }
+            }
+        });
+
+        let rewritten = gateway_paths(&document, &route_table.routes()[0], "http://users:8080/v1");
+        assert_eq!(rewritten, json!({
+            "openapi": "3.0.3",
+            "paths": {
+                "/api/users": { "get": { "operationId": "listUsers" } },
+                "/api/users/{id}": {
+                    "parameters": [{ "name": "id", "in": "path", "required": true }],
+                    "get": { "operationId": "getUser" }
+                }
+            }
+        }));
+    }
+
+    /// A backend serving `document` at `/openapi.json`, which fails once
+    /// `down` is set.
+    fn backend(document: Value, down: Arc<AtomicBool>) -> SocketAddr {
+        let spec = warp::path!("openapi.json").map(move || {
+            let status = if down.load(Ordering::SeqCst) { StatusCode::INTERNAL_SERVER_ERROR } else { StatusCode::OK };
+            warp::reply::with_status(warp::reply::json(&document), status)
+        });
+        let (addr, server) = warp::serve(spec).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_documents_are_merged_and_served() {
+        let orders_down = Arc::new(AtomicBool::new(false));
+        let orders = backend(json!({
+            "openapi": "3.0.3",
+            "paths": { "/orders": { "get": { "tags": ["orders"] } } },
+            "components": { "schemas": { "Error": { "type": "object" }, "Order": { "type": "object" } } },
+            "tags": [{ "name": "orders" }, { "name": "shared" }]
+        }), orders_down.clone());
+        let products = backend(json!({
+            "openapi": "3.1.0",
+            "paths": { "/products": { "get": {} }, "/orders": { "post": {} } },
+            "components": { "schemas": { "Error": { "type": "string" } } },
+            "tags": [{ "name": "shared" }]
+        }), Arc::default());
+        let route_table = route_table(json!([
+            { "name": "orders", "path_prefix": "/orders", "upstream": format!("http://{}", orders) },
+            { "name": "products", "path_prefix": "/", "upstream": format!("http://{}", products) }
+        ]));
+        let source = |route: &str| OpenApiSource { route: route.to_string(), path: "/openapi.json".to_string() };
+        let connect = UpstreamConnectConfig::default();
+        let tls = Arc::new(UpstreamTls::new(&connect.tls).unwrap());
+        let client = UpstreamClient::new(&connect, tls, Arc::new(Connections::default())).unwrap();
+        let openapi = Arc::new(OpenApi::new(&OpenApiConfig {
+            server_url: Some("https://api.example.com".to_string()),
+            sources: vec![source("orders"), source("products")],
+            swagger_ui_path: Some("/docs".to_string()),
+            swagger_ui_assets: Some(SwaggerUiAssets {
+                version: "5.17.14".to_string(),
+                css_integrity: "sha384-css".to_string(),
+                bundle_integrity: "sha384-bundle".to_string(),
+            }),
+            ..OpenApiConfig::default()
+        }, client));
+
+        openapi.refresh(&route_table).await;
+        orders_down.store(true, Ordering::SeqCst);
+        openapi.refresh(&route_table).await;
+        let expected = json!({
+            "openapi": "3.0.3",
+            "info": { "title": "API Gateway", "version": "1.0.0" },
+            "servers": [{ "url": "https://api.example.com" }],
+            "paths": {
+                "/orders": { "get": { "tags": ["orders"] }, "post": {} },
+                "/products": { "get": {} }
+            },
+            "components": { "schemas": { "Error": { "type": "object" }, "Order": { "type": "object" } } },
+            "tags": [{ "name": "orders" }, { "name": "shared" }]
+        });
+        assert_eq!(openapi.document().unwrap(), expected);
+
+        let filter = routes(Some(openapi));
+        let response = warp::test::request().path("/openapi.json").reply(&filter).await;
+        assert_eq!(response.headers()["content-type"], "application/json");
+        assert_eq!(serde_json::from_slice::<Value>(response.body()).unwrap(), expected);
+        let response = warp::test::request().path("/docs").reply(&filter).await;
+        let page = String::from_utf8_lossy(response.body());
+        assert!(page.contains(r#"url: "/openapi.json""#));
+        assert!(page.contains(r#"href="https://unpkg.com/swagger-ui-dist@5.17.14/swagger-ui.css" integrity="sha384-css" crossorigin="anonymous""#));
+        assert!(page.contains(r#"src="https://unpkg.com/swagger-ui-dist@5.17.14/swagger-ui-bundle.js" integrity="sha384-bundle" crossorigin="anonymous""#));
+        assert!(warp::test::request().path("/orders").filter(&filter).await.is_err());
+        assert!(warp::test::request().path("/openapi.json").filter(&routes(None)).await.is_err());
+    }
+}
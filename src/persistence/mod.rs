@@ -0,0 +1,287 @@
+use std::collections::HashSet;
+use std::fmt::Write as _;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex as SyncMutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde_json::{json, Value};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::timeout;
+use crate::analytics::Analytics;
+use crate::config::PersistenceConfig;
+use crate::database::{Database, DatabaseAddress, Param};
+use crate::models::{AppState, RateLimit};
+use crate::routes::RouteTable;
+use crate::services::tenant_bucket;
+use crate::syslog;
+
+#[cfg(test)]
+mod tests;
+
+/// Kinds of rows in `gateway_state`.
+pub const API_KEYS: &str = "api_keys";
+pub const DEPLOYMENTS: &str = "deployment";
+pub const QUOTAS: &str = "quota";
+/// One row per cluster store, named `consul`, `etcd` or `s3`, holding the
+/// route definitions last applied from it.
+pub const ROUTES: &str = "routes";
+/// One row per day, named by its date.
+pub const ANALYTICS: &str = "analytics";
+/// The one `api_keys` row, holding the store as it would be written to its
+/// file.
+pub const API_KEY_STORE: &str = "store";
+/// Wait before retrying a write the database couldn't be reached for.
+const RETRY_SECS: u64 = 1;
+/// Tries at a write the database couldn't be reached for before it is
+/// dropped.
+const MAX_ATTEMPTS: u32 = 3;
+
+pub const PERSISTENCE_DROPPED: &str = "gateway_persistence_dropped_writes_total";
+
+/// Tables, created at startup if missing.
+const STATE_TABLE: &str = "CREATE TABLE IF NOT EXISTS gateway_state (kind text NOT NULL, name text NOT NULL, \
+     value text NOT NULL, updated_at bigint NOT NULL, PRIMARY KEY (kind, name))";
+pub const POSTGRES_SCHEMA: [&str; 2] = [
+    STATE_TABLE,
+    "CREATE TABLE IF NOT EXISTS gateway_audit (id bigserial PRIMARY KEY, recorded_at bigint NOT NULL, entry text NOT NULL)",
+];
+pub const SQLITE_SCHEMA: [&str; 2] = [
+    STATE_TABLE,
+    "CREATE TABLE IF NOT EXISTS gateway_audit (id integer PRIMARY KEY AUTOINCREMENT, recorded_at bigint NOT NULL, entry text NOT NULL)",
+];
+pub const LOAD: &str = "SELECT name, value FROM gateway_state WHERE kind = $1 ORDER BY name";
+pub const SAVE: &str = "INSERT INTO gateway_state (kind, name, value, updated_at) VALUES ($1, $2, $3, $4) \
+     ON CONFLICT (kind, name) DO UPDATE SET value = EXCLUDED.value, updated_at = EXCLUDED.updated_at";
+pub const DELETE: &str = "DELETE FROM gateway_state WHERE kind = $1 AND name = $2";
+pub const APPEND_AUDIT: &str = "INSERT INTO gateway_audit (recorded_at, entry) VALUES ($1, $2)";
+
+enum Write {
+    Save { kind: &'static str, name: String, value: String },
+    Delete { kind: &'static str, name: String },
+    Audit(String),
+    /// Answered once every write queued before it is done.
+    Flush(oneshot::Sender<()>),
+}
+
+/// Gateway state kept in Postgres or SQLite. Reads happen at startup;
+/// writes are queued and applied in order by one task, so the code changing
+/// the state never waits for the database. A write the database can't be
+/// reached for is retried a few times, and one it refuses is logged; either
+/// way, and when the queue is full, the write is dropped and counted.
+pub struct Persistence {
+    database: Database,
+    timeout: Duration,
+    writes: mpsc::Sender<Write>,
+    dropped_queue_full: AtomicU64,
+    dropped_unavailable: AtomicU64,
+    dropped_refused: AtomicU64,
+    /// Quota buckets in the last snapshot, to delete the ones since reset.
+    quotas: SyncMutex<HashSet<String>>,
+    /// Date of the last analytics snapshot, to delete it once the day is over.
+    analytics_date: SyncMutex<Option<String>>,
+}
+
+impl Persistence {
+    /// Connects, creates the tables and starts the writer.
+    pub async fn open(config: &PersistenceConfig) -> Result<Arc<Self>, String> {
+        let (writes, queue) = mpsc::channel(config.queue_capacity);
+        let timeout = Duration::from_millis(config.timeout_ms);
+        let persistence = Arc::new(Self {
+            database: Database::connect(&DatabaseAddress::new(config)?, timeout),
+            timeout,
+            writes,
+            dropped_queue_full: AtomicU64::new(0),
+            dropped_unavailable: AtomicU64::new(0),
+            dropped_refused: AtomicU64::new(0),
+            quotas: SyncMutex::new(HashSet::new()),
+            analytics_date: SyncMutex::new(None),
+        });
+        let schema = match persistence.database {
+            Database::Postgres(_) => POSTGRES_SCHEMA,
+            Database::Sqlite(_) => SQLITE_SCHEMA,
+        };
+        for statement in schema {
+            persistence.execute(statement, &[]).await.map_err(|e| e.to_string())?;
+        }
+        tokio::spawn(persistence.clone().write_behind(queue));
+        Ok(persistence)
+    }
+
+    /// Every row of `kind`, by name.
+    pub async fn load(&self, kind: &str) -> Result<Vec<(String, Value)>, String> {
+        let params = [Param::Text(kind)];
+        let rows = timeout(self.timeout, self.database.fetch_pairs(LOAD, &params))
+            .await.unwrap_or_else(|_| Err(timed_out())).map_err(|e| e.to_string())?;
+        rows.into_iter()
+            .map(|(name, value)| {
+                let value = serde_json::from_str(&value).map_err(|e| format!("{} {}: {}", kind, name, e))?;
+                Ok((name, value))
+            })
+            .collect()
+    }
+
+    pub fn save(&self, kind: &'static str, name: &str, value: &Value) {
+        self.queue(Write::Save { kind, name: name.to_string(), value: value.to_string() });
+    }
+
+    pub fn delete(&self, kind: &'static str, name: &str) {
+        self.queue(Write::Delete { kind, name: name.to_string() });
+    }
+
+    pub fn append_audit(&self, entry: &Value) {
+        self.queue(Write::Audit(entry.to_string()));
+    }
+
+    /// Waits for every write queued so far.
+    pub async fn flush(&self) {
+        let (done, flushed) = oneshot::channel();
+        // Waits for room rather than being dropped.
+        if self.writes.send(Write::Flush(done)).await.is_ok() {
+            let _ = flushed.await;
+        }
+    }
+
+    /// Switches deployments back to the groups they were last switched to.
+    /// Ramps are not resumed; the switch takes effect at once.
+    pub async fn restore_deployments(&self, route_table: &RouteTable) -> Result<(), String> {
+        for (route, saved) in self.load(DEPLOYMENTS).await? {
+            let deployment = route_table.routes().iter().find(|r| r.name == route).and_then(|r| r.deployment.as_ref());
+            let (Some(deployment), Some(active)) = (deployment, saved["active"].as_str()) else {
+                continue;
+            };
+            if let Err(e) = deployment.switch(active, Duration::ZERO) {
+                syslog::error(format_args!("Not restoring deployment {}: {}", route, e));
+            }
+        }
+        Ok(())
+    }
+
+    /// Loads the last snapshot of tenant quota usage into `state`.
+    pub async fn restore_quotas(&self, state: &AppState) -> Result<(), String> {
+        let mut quotas = self.quotas.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        for (key, saved) in self.load(QUOTAS).await? {
+            let (Some(count), Some(window_start)) = (saved["count"].as_u64(), saved["window_start_ms"].as_u64()) else {
+                continue;
+            };
+            let window_start = UNIX_EPOCH + Duration::from_millis(window_start);
+            let count = u32::try_from(count).unwrap_or(u32::MAX);
+            state.rate_limits.insert(key.clone(), RateLimit { count, window_start, last_seen: SystemTime::now() });
+            quotas.insert(key);
+        }
+        *self.quotas.lock().unwrap_or_else(|e| e.into_inner()) = quotas;
+        Ok(())
+    }
+
+    /// Queues the current tenant quota usage, and deletes the buckets that
+    /// have gone since the last snapshot.
+    pub fn snapshot_quotas(&self, state: &AppState) {
+        let mut current = HashSet::new();
+        for bucket in state.rate_limits.iter().filter(|bucket| tenant_bucket(bucket.key()).is_some_and(|(_, name)| name == "quota")) {
+            let window_start = bucket.window_start.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+            self.save(QUOTAS, bucket.key(), &json!({ "count": bucket.count, "window_start_ms": window_start }));
+            current.insert(bucket.key().clone());
+        }
+        let previous = std::mem::replace(&mut *self.quotas.lock().unwrap_or_else(|e| e.into_inner()), current.clone());
+        for gone in previous.difference(&current) {
+            self.delete(QUOTAS, gone);
+        }
+    }
+
+    /// Loads the current day's analytics into `analytics`, and deletes
+    /// earlier days'.
+    pub async fn restore_analytics(&self, analytics: &Analytics) -> Result<(), String> {
+        for (date, saved) in self.load(ANALYTICS).await? {
+            if analytics.restore(&saved, SystemTime::now()) {
+                *self.analytics_date.lock().unwrap_or_else(|e| e.into_inner()) = Some(date);
+            } else {
+                self.delete(ANALYTICS, &date);
+            }
+        }
+        Ok(())
+    }
+
+    /// Queues the day's analytics, and deletes the previous day's once a
+    /// new day has begun.
+    pub fn snapshot_analytics(&self, analytics: &Analytics) {
+        let (date, value) = analytics.snapshot(SystemTime::now());
+        self.save(ANALYTICS, &date, &value);
+        let previous = self.analytics_date.lock().unwrap_or_else(|e| e.into_inner()).replace(date.clone());
+        if let Some(previous) = previous.filter(|previous| *previous != date) {
+            self.delete(ANALYTICS, &previous);
+        }
+    }
+
+    /// The Prometheus text exposition of the writes dropped, by why.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "# HELP {} Writes to the database dropped, by why.", PERSISTENCE_DROPPED);
+        let _ = writeln!(out, "# TYPE {} counter", PERSISTENCE_DROPPED);
+        for (reason, count) in [
+            ("queue_full", &self.dropped_queue_full),
+            ("unavailable", &self.dropped_unavailable),
+            ("refused", &self.dropped_refused),
+        ] {
+            let _ = writeln!(out, "{}{{reason=\"{}\"}} {}", PERSISTENCE_DROPPED, reason, count.load(Ordering::Relaxed));
+        }
+        out
+    }
+
+    fn queue(&self, write: Write) {
+        // The writer only stops when the gateway does, so only a full queue
+        // refuses.
+        if self.writes.try_send(write).is_err() {
+            self.dropped_queue_full.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    async fn write_behind(self: Arc<Self>, mut queue: mpsc::Receiver<Write>) {
+        while let Some(write) = queue.recv().await {
+            let write = match write {
+                Write::Flush(done) => {
+                    let _ = done.send(());
+                    continue;
+                }
+                write => write,
+            };
+            let now = unix_millis();
+            let (sql, params) = match &write {
+                Write::Save { kind, name, value } => (SAVE, vec![Param::Text(kind), Param::Text(name), Param::Text(value), Param::Int(now)]),
+                Write::Delete { kind, name } => (DELETE, vec![Param::Text(kind), Param::Text(name)]),
+                Write::Audit(entry) => (APPEND_AUDIT, vec![Param::Int(now), Param::Text(entry)]),
+                Write::Flush(_) => continue,
+            };
+            for attempt in 1..=MAX_ATTEMPTS {
+                match self.execute(sql, &params).await {
+                    Ok(()) => break,
+                    Err(e @ sqlx::Error::Database(_)) => {
+                        syslog::error(format_args!("Database refused a write, dropping it: {}", e));
+                        self.dropped_refused.fetch_add(1, Ordering::Relaxed);
+                        break;
+                    }
+                    Err(e) if attempt == MAX_ATTEMPTS => {
+                        syslog::error(format_args!("Database write failed {} times, dropping it: {}", MAX_ATTEMPTS, e));
+                        self.dropped_unavailable.fetch_add(1, Ordering::Relaxed);
+                    }
+                    Err(e) => {
+                        syslog::error(format_args!("Database write failed, retrying: {}", e));
+                        tokio::time::sleep(Duration::from_secs(RETRY_SECS)).await;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Runs a statement. The pool reconnects by itself once a connection
+    /// fails.
+    async fn execute(&self, sql: &str, params: &[Param<'_>]) -> Result<(), sqlx::Error> {
+        timeout(self.timeout, self.database.execute(sql, params)).await.unwrap_or_else(|_| Err(timed_out()))
+    }
+}
+
+fn timed_out() -> sqlx::Error {
+    sqlx::Error::Io(io::Error::new(io::ErrorKind::TimedOut, "database statement timed out"))
+}
+
+fn unix_millis() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as i64
+}
@@ -0,0 +1,150 @@
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use serde_json::{json, Value};
+    use sqlx::SqlitePool;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use crate::admin::{switch_deployment, DeploymentSwitch};
+    use crate::analytics::Analytics;
+    use crate::api_keys::ApiKeyStore;
+    use crate::audit::AuditLog;
+    use crate::config::{AnalyticsConfig, ApiKeyStoreConfig, GatewayConfig, PersistenceConfig};
+    use crate::models::{AppState, RateLimit};
+    use crate::persistence::{Persistence, ANALYTICS, API_KEYS, DEPLOYMENTS, QUOTAS};
+    use crate::routes::RouteTable;
+
+    /// A fresh SQLite database for one test.
+    fn persistence_config(name: &str) -> PersistenceConfig {
+        let path = std::env::temp_dir().join(format!("api-gateway-persistence-{}-{}.db", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        PersistenceConfig { url: format!("sqlite://{}", path.display()), ..PersistenceConfig::default() }
+    }
+
+    /// The rows of `kind`, by name.
+    async fn rows(persistence: &Persistence, kind: &str) -> Vec<(String, Value)> {
+        persistence.flush().await;
+        persistence.load(kind).await.unwrap()
+    }
+
+    fn key_store_config(name: &str) -> ApiKeyStoreConfig {
+        let path = std::env::temp_dir().join(format!("api-gateway-persisted-keys-{}-{}.json", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        ApiKeyStoreConfig { path, kms: None, argon2_memory_kib: 8, argon2_iterations: 1, lockout: None }
+    }
+
+    #[tokio::test]
+    async fn test_refuses_plaintext_by_default() {
+        // A Postgres that doesn't offer TLS.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut ssl_request = [0; 8];
+                stream.read_exact(&mut ssl_request).await.unwrap();
+                assert_eq!(ssl_request, [0, 0, 0, 8, 4, 210, 22, 47]);
+                stream.write_all(b"N").await.unwrap();
+            }
+        });
+        let config = PersistenceConfig { url: format!("postgres://{}/gateway", addr), timeout_ms: 1000, ..PersistenceConfig::default() };
+        assert!(Persistence::open(&config).await.err().unwrap().contains("server does not support TLS"));
+    }
+
+    #[tokio::test]
+    async fn test_writes_over_the_queue_capacity_are_dropped() {
+        let config = PersistenceConfig { queue_capacity: 1, ..persistence_config("queue") };
+        let persistence = Persistence::open(&config).await.unwrap();
+        // The writer can't run between these, so only the first fits.
+        for i in 0..3 {
+            persistence.save(DEPLOYMENTS, &format!("route-{}", i), &json!({ "active": "blue" }));
+        }
+        assert_eq!(rows(&persistence, DEPLOYMENTS).await.len(), 1);
+        assert!(persistence.render().contains("gateway_persistence_dropped_writes_total{reason=\"queue_full\"} 2\n"));
+    }
+
+    #[tokio::test]
+    async fn test_api_keys_survive_restarts() {
+        let persistence = Persistence::open(&persistence_config("api-keys")).await.unwrap();
+        let config = key_store_config("restarts");
+        let (existing, _) = ApiKeyStore::open(&config).await.unwrap().create("alice", vec![]).unwrap();
+
+        // The file's keys move into the database the first time.
+        let store = ApiKeyStore::open_persisted(&config, persistence.clone()).await.unwrap();
+        let (issued, _) = store.create("bob", vec!["orders:read".to_string()]).unwrap();
+        persistence.flush().await;
+        std::fs::remove_file(&config.path).unwrap();
+
+        let reopened = ApiKeyStore::open_persisted(&config, persistence.clone()).await.unwrap();
+        assert_eq!(reopened.list(), [existing, issued]);
+        assert!(!config.path.exists());
+        assert_eq!(rows(&persistence, API_KEYS).await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_deployments_quotas_and_audit_survive_restarts() {
+        let database = persistence_config("deployments");
+        let persistence = Persistence::open(&database).await.unwrap();
+        let config = GatewayConfig::from_json(r#"{
+            "routes": [{ "name": "orders", "path_prefix": "/orders", "deployment": {
+                "groups": { "blue": "http://orders-blue:8080", "green": "http://orders-green:8080" },
+                "active": "blue"
+            } }]
+        }"#).unwrap();
+        let audit = AuditLog::stdout().with_persistence(persistence.clone());
+        let switch = DeploymentSwitch { active: "green".to_string(), ramp_secs: 60 };
+        switch_deployment(&RouteTable::from_config(&config).unwrap(), "orders", &switch, "admin.tokens[0]", &audit, Some(&persistence)).unwrap();
+
+        let state = AppState::new();
+        let window_start = UNIX_EPOCH + Duration::from_millis(1_700_000_000_000);
+        for key in ["acme#quota", "ip:198.51.100.7", "ip:acme#quota"] {
+            state.rate_limits.insert(key.to_string(), RateLimit { count: 42, window_start, last_seen: SystemTime::now() });
+        }
+        persistence.snapshot_quotas(&state);
+        persistence.flush().await;
+
+        // A restart: the ramp is skipped, and only quota buckets come back.
+        let route_table = RouteTable::from_config(&config).unwrap();
+        persistence.restore_deployments(&route_table).await.unwrap();
+        let status = route_table.routes()[0].deployment.as_ref().unwrap().status();
+        assert_eq!((status["active"].as_str(), &status["ramping_from"]), (Some("green"), &Value::Null));
+        let restored = AppState::new();
+        persistence.restore_quotas(&restored).await.unwrap();
+        assert_eq!(restored.rate_limits.len(), 1);
+        let bucket = restored.rate_limits.get("acme#quota").unwrap();
+        assert_eq!((bucket.count, bucket.window_start), (42, window_start));
+        drop(bucket);
+
+        let audit: Vec<(String,)> = sqlx::query_as("SELECT entry FROM gateway_audit ORDER BY id")
+            .fetch_all(&SqlitePool::connect(&database.url).await.unwrap())
+            .await
+            .unwrap();
+        let entry: Value = serde_json::from_str(&audit[0].0).unwrap();
+        assert_eq!((entry["action"].as_str(), entry["after"]["active"].as_str()), (Some("deployment.switch"), Some("green")));
+
+        // A quota reset since the last snapshot deletes its row.
+        restored.rate_limits.clear();
+        persistence.snapshot_quotas(&restored);
+        assert!(rows(&persistence, QUOTAS).await.is_empty());
+        assert_eq!(rows(&persistence, DEPLOYMENTS).await, [("orders".to_string(), json!({ "active": "green" }))]);
+    }
+
+    #[tokio::test]
+    async fn test_analytics_survive_restarts() {
+        let persistence = Persistence::open(&persistence_config("analytics")).await.unwrap();
+        persistence.save(ANALYTICS, "2020-01-01", &json!({ "day": 18_262 }));
+        let analytics = Analytics::new(&AnalyticsConfig::default());
+        analytics.record(Some("orders"), &hyper::Method::GET, Some("alice"), hyper::StatusCode::OK, Duration::from_millis(40), SystemTime::now());
+        persistence.snapshot_analytics(&analytics);
+        persistence.flush().await;
+
+        // A restart picks up today's figures and drops earlier days'.
+        let restored = Analytics::new(&AnalyticsConfig::default());
+        persistence.restore_analytics(&restored).await.unwrap();
+        persistence.flush().await;
+        let report = restored.report(None, SystemTime::now());
+        assert_eq!(report["top_routes"], json!([{ "route": "orders", "requests": 1, "errors": 0 }]));
+        let dates: Vec<String> = rows(&persistence, ANALYTICS).await.into_iter().map(|(date, _)| date).collect();
+        assert_eq!(dates, [report["date"].as_str().unwrap()]);
+    }
+}
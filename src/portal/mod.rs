@@ -0,0 +1,230 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use dashmap::DashMap;
+use hyper::header::{CACHE_CONTROL, CONTENT_TYPE};
+use hyper::{Body, HeaderMap, Response, StatusCode};
+use serde_json::{json, Value};
+use warp::{Filter, Rejection, Reply};
+use crate::auth::{Authenticator, Identity};
+use crate::config::{PortalConfig, RateLimitConfig, RATE_LIMIT_REQUESTS, RATE_LIMIT_WINDOW_SECS};
+use crate::errors::GatewayError;
+use crate::handlers::handle_rejection;
+use crate::models::AppState;
+use crate::services::{client_bucket, client_ip};
+use crate::tenants::{Tenant, Tenants};
+
+#[cfg(test)]
+mod tests;
+
+/// How far back `recent` looks, in one-minute slots.
+const USAGE_MINUTES: u64 = 60;
+/// Key holders tracked at once; the one idle longest makes room for a new one.
+const MAX_KEY_HOLDERS: usize = 10_000;
+/// Asks for a key, then shows what `<path>/usage` reports for it.
+const PAGE: &str = r##"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>API usage</title>
+<style>
+body { font-family: sans-serif; max-width: 40em; margin: 2em auto; }
+td { padding: 0.2em 1em 0.2em 0; }
+</style>
+</head>
+<body>
+<h1>API usage</h1>
+<form id="key"><input id="token" type="password" placeholder="API key" size="40"> <button>Show</button></form>
+<p id="error"></p>
+<table id="usage"></table>
+<script>
+document.getElementById("key").onsubmit = async (event) => {
+  event.preventDefault();
+  const token = document.getElementById("token").value;
+  const response = await fetch("{usage}", { headers: { Authorization: "Bearer " + token } });
+  const table = document.getElementById("usage");
+  table.innerHTML = "";
+  document.getElementById("error").textContent = response.ok ? "" : "That key was not accepted.";
+  if (!response.ok) return;
+  const usage = await response.json();
+  const row = (label, value) => {
+    const tr = table.insertRow();
+    tr.insertCell().textContent = label;
+    tr.insertCell().textContent = value;
+  };
+  const limit = (bucket) => bucket ? bucket.count + " of " + bucket.limit + " per " + bucket.window_secs + "s, " + bucket.remaining + " left" : "none";
+  row("User", usage.user);
+  row("Tier", usage.tenant || "default");
+  row("Rate limit", limit(usage.rate_limit));
+  row("Quota", limit(usage.quota));
+  row("Requests, last hour", usage.recent.requests);
+  row("Errors, last hour", usage.recent.client_errors + " client, " + usage.recent.server_errors + " server");
+  row("Error rate", (usage.recent.error_rate * 100).toFixed(1) + "%");
+};
+</script>
+</body>
+</html>
+"##;
+
+#[derive(Default, Clone, Copy)]
+struct Minute {
+    /// Minutes since the epoch this slot counts.
+    minute: u64,
+    requests: u32,
+    client_errors: u32,
+    server_errors: u32,
+}
+
+struct Recent {
+    minutes: [Minute; USAGE_MINUTES as usize],
+    last_seen: u64,
+}
+
+impl Default for Recent {
+    fn default() -> Self {
+        Self { minutes: [Minute::default(); USAGE_MINUTES as usize], last_seen: 0 }
+    }
+}
+
+/// Responses per key holder over the last hour, by whether they were errors.
+#[derive(Default)]
+pub struct Usage {
+    holders: DashMap<String, Recent>,
+}
+
+impl Usage {
+    pub fn record(&self, holder: &str, status: StatusCode, now: u64) {
+        if !self.holders.contains_key(holder) && self.holders.len() >= MAX_KEY_HOLDERS {
+            let idlest = self.holders.iter().min_by_key(|entry| entry.last_seen).map(|entry| entry.key().clone());
+            if let Some(idlest) = idlest {
+                self.holders.remove(&idlest);
+            }
+        }
+        let mut recent = self.holders.entry(holder.to_string()).or_default();
+        recent.last_seen = now;
+        let minute = now / 60;
+        let slot = &mut recent.minutes[(minute % USAGE_MINUTES) as usize];
+        if slot.minute != minute {
+            *slot = Minute { minute, ..Minute::default() };
+        }
+        slot.requests += 1;
+        if status.is_client_error() {
+            slot.client_errors += 1;
+        } else if status.is_server_error() {
+            slot.server_errors += 1;
+        }
+    }
+
+    pub fn recent(&self, holder: &str, now: u64) -> Value {
+        let since = (now / 60).saturating_sub(USAGE_MINUTES);
+        let mut total = Minute::default();
+        if let Some(recent) = self.holders.get(holder) {
+            for minute in recent.minutes.iter().filter(|minute| minute.minute > since) {
+                total.requests += minute.requests;
+                total.client_errors += minute.client_errors;
+                total.server_errors += minute.server_errors;
+            }
+        }
+        let errors = total.client_errors + total.server_errors;
+        json!({
+            "window_secs": USAGE_MINUTES * 60,
+            "requests": total.requests,
+            "client_errors": total.client_errors,
+            "server_errors": total.server_errors,
+            "error_rate": if total.requests == 0 { 0.0 } else { errors as f64 / total.requests as f64 },
+        })
+    }
+}
+
+/// The developer portal: a page, and the JSON it shows, telling the holder
+/// of an API key their rate-limit tier, how much of their limits they've
+/// used and how many of their recent requests failed.
+pub struct Portal {
+    path: String,
+    pub usage: Usage,
+}
+
+impl Portal {
+    pub fn new(config: &PortalConfig) -> Self {
+        Self { path: config.path.clone(), usage: Usage::default() }
+    }
+
+    /// What `<path>/usage` answers for `identity`. Rate limits count
+    /// requests per client address, so `rate_limit` is the bucket of the
+    /// address asking.
+    pub fn report(&self, identity: &Identity, tenant: Option<&Tenant>, state: &AppState, headers: &HeaderMap, now: SystemTime) -> Value {
+        let global = RateLimitConfig { requests: RATE_LIMIT_REQUESTS, window_secs: RATE_LIMIT_WINDOW_SECS };
+        let (rate_limit, quota) = match tenant {
+            Some(tenant) => (
+                bucket(state, &format!("{}#ip:{}", tenant.name, client_ip(headers)), tenant.rate_limit.unwrap_or(global), now),
+                tenant.quota.map(|quota| bucket(state, &format!("{}#quota", tenant.name), quota, now)),
+            ),
+            None => (bucket(state, &client_bucket(headers), global, now), None),
+        };
+        let seconds = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        json!({
+            "user": identity.user_id,
+            "scopes": identity.scopes,
+            "tenant": tenant.map(|tenant| &tenant.name),
+            "rate_limit": rate_limit,
+            "quota": quota,
+            "recent": self.usage.recent(&identity.user_id, seconds),
+        })
+    }
+}
+
+/// A rate-limit bucket as the admin API lists it, counting nothing.
+fn bucket(state: &AppState, key: &str, limit: RateLimitConfig, now: SystemTime) -> Value {
+    let (count, reset_at) = match state.rate_limits.get(key) {
+        Some(bucket) => {
+            let reset_at = bucket.window_start + Duration::from_secs(limit.window_secs);
+            (if reset_at > now { bucket.count } else { 0 }, Some(reset_at))
+        }
+        None => (0, None),
+    };
+    json!({
+        "count": count,
+        "limit": limit.requests,
+        "window_secs": limit.window_secs,
+        "remaining": limit.requests.saturating_sub(count),
+        "reset_at": reset_at.filter(|reset_at| *reset_at > now).and_then(|reset_at| reset_at.duration_since(UNIX_EPOCH).ok()).map(|d| d.as_secs()),
+    })
+}
+
+/// `GET <path>` and `GET <path>/usage`. The usage needs the key in
+/// `Authorization` like any other request; without a portal both paths are
+/// proxied.
+pub fn routes(
+    portal: Option<Arc<Portal>>,
+    authenticator: Arc<Authenticator>,
+    tenants: Arc<Tenants>,
+    state: Arc<AppState>,
+) -> impl Filter<Extract = (Response<Body>,), Error = Rejection> + Clone {
+    warp::get()
+        .and(warp::path::full())
+        .and(warp::header::headers_cloned())
+        .and_then(move |path: warp::path::FullPath, headers: HeaderMap| {
+            let (portal, authenticator, tenants, state) = (portal.clone(), authenticator.clone(), tenants.clone(), state.clone());
+            async move {
+                let portal = portal.ok_or_else(warp::reject::not_found)?;
+                let usage_path = format!("{}/usage", portal.path);
+                let (content_type, body) = if path.as_str() == portal.path {
+                    ("text/html; charset=utf-8", PAGE.replace("{usage}", &usage_path))
+                } else if path.as_str() == usage_path {
+                    let Some(identity) = authenticator.identify(&headers).await else {
+                        let Ok(reply) = handle_rejection(warp::reject::custom(GatewayError::Unauthorized)).await;
+                        return Ok(reply.into_response());
+                    };
+                    let (tenant, _) = tenants.resolve(&headers, path.as_str(), Some(&identity));
+                    let report = portal.report(&identity, tenant, &state, &headers, SystemTime::now());
+                    ("application/json", report.to_string())
+                } else {
+                    return Err(warp::reject::not_found());
+                };
+                let mut response = Response::new(Body::from(body));
+                *response.status_mut() = StatusCode::OK;
+                response.headers_mut().insert(CONTENT_TYPE, content_type.parse().unwrap());
+                response.headers_mut().insert(CACHE_CONTROL, "no-store".parse().unwrap());
+                Ok::<_, Rejection>(response)
+            }
+        })
+}
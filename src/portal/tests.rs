@@ -0,0 +1,92 @@
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use serde_json::{json, Value};
+    use warp::http::StatusCode;
+    use crate::auth::Authenticator;
+    use crate::config::{PortalConfig, TenancyConfig};
+    use crate::models::{AppState, RateLimit};
+    use crate::portal::{routes, Portal, Usage};
+    use crate::tenants::Tenants;
+
+    #[test]
+    fn test_usage_covers_the_last_hour() {
+        let usage = Usage::default();
+        let now = 1_700_000_000;
+        usage.record("user-1", StatusCode::OK, now - 3_700);
+        usage.record("user-1", StatusCode::OK, now - 120);
+        usage.record("user-1", StatusCode::TOO_MANY_REQUESTS, now - 60);
+        usage.record("user-1", StatusCode::BAD_GATEWAY, now);
+        usage.record("user-2", StatusCode::OK, now);
+
+        assert_eq!(usage.recent("user-1", now), json!({
+            "window_secs": 3600,
+            "requests": 3,
+            "client_errors": 1,
+            "server_errors": 1,
+            "error_rate": 2.0 / 3.0,
+        }));
+        assert_eq!(usage.recent("user-3", now)["requests"], 0);
+        assert_eq!(usage.recent("user-3", now)["error_rate"], 0.0);
+    }
+
+    fn tenants() -> Arc<Tenants> {
+        let config: TenancyConfig = serde_json::from_value(json!({
+            "tenants": {
+                "acme": {
+                    "hosts": ["acme.example.com"],
+                    "rate_limit": { "requests": 50, "window_secs": 60 },
+                    "quota": { "requests": 1000, "window_secs": 3600 }
+                }
+            }
+        }))
+        .unwrap();
+        Arc::new(Tenants::from_config(&config))
+    }
+
+    #[tokio::test]
+    async fn test_usage_reports_the_callers_limits() {
+        let now = SystemTime::now();
+        let state = Arc::new(AppState::new());
+        state.rate_limits.insert("acme#ip:10.0.0.1".to_string(), RateLimit { count: 20, window_start: now, last_seen: now });
+        state.rate_limits.insert("acme#quota".to_string(), RateLimit { count: 400, window_start: now, last_seen: now });
+        let portal = Arc::new(Portal::new(&PortalConfig::default()));
+        let seconds = now.duration_since(UNIX_EPOCH).unwrap().as_secs();
+        portal.usage.record("example-user", StatusCode::OK, seconds);
+        portal.usage.record("example-user", StatusCode::SERVICE_UNAVAILABLE, seconds);
+        let filter = routes(Some(portal), Arc::new(Authenticator::default()), tenants(), state);
+
+        let response = warp::test::request()
+            .path("/portal/usage")
+            .header("host", "acme.example.com")
+            .header("x-forwarded-for", "10.0.0.1")
+            .header("authorization", "Bearer example-token")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["cache-control"], "no-store");
+        let usage: Value = serde_json::from_slice(response.body()).unwrap();
+        let reset_at = |window: u64| (now + Duration::from_secs(window)).duration_since(UNIX_EPOCH).unwrap().as_secs();
+        assert_eq!(usage["user"], "example-user");
+        assert_eq!(usage["tenant"], "acme");
+        assert_eq!(usage["rate_limit"], json!({ "count": 20, "limit": 50, "window_secs": 60, "remaining": 30, "reset_at": reset_at(60) }));
+        assert_eq!(usage["quota"], json!({ "count": 400, "limit": 1000, "window_secs": 3600, "remaining": 600, "reset_at": reset_at(3600) }));
+        assert_eq!(usage["recent"]["requests"], 2);
+        assert_eq!(usage["recent"]["error_rate"], 0.5);
+
+        let response = warp::test::request().path("/portal/usage").reply(&filter).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = warp::test::request().path("/portal").reply(&filter).await;
+        assert!(String::from_utf8_lossy(response.body()).contains(r#"fetch("/portal/usage""#));
+        assert!(warp::test::request().path("/portal/other").filter(&filter).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_without_a_portal_paths_are_proxied() {
+        let filter = routes(None, Arc::new(Authenticator::default()), tenants(), Arc::new(AppState::new()));
+
+        assert!(warp::test::request().path("/portal").filter(&filter).await.is_err());
+        assert!(warp::test::request().path("/portal/usage").filter(&filter).await.is_err());
+    }
+}
@@ -0,0 +1,164 @@
+use std::collections::HashSet;
+use serde_json::Value;
+use crate::config::RedactionConfig;
+
+#[cfg(test)]
+mod tests;
+
+pub const REDACTED: &str = "[redacted]";
+/// Always redacted, whatever `redaction.headers` adds.
+pub const REDACTED_HEADERS: [&str; 6] = [
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+    "x-api-key",
+    "x-gateway-admin-token",
+];
+/// JSON fields and query parameters always redacted, covering the secrets in
+/// the gateway's own configuration.
+pub const REDACTED_FIELDS: [&str; 5] = ["tokens", "secret", "client_secret", "password", "bearer"];
+/// Configuration values redacted by location rather than name, as JSON
+/// pointers where `*` stands for every array item or map entry. Inline PEM
+/// keys share the name `key` with settings that aren't secret, and a
+/// webhook's URL is its credential.
+pub const REDACTED_CONFIG_POINTERS: [&str; 5] = [
+    "/tls/key",
+    "/tls/certificates/*/key",
+    "/auth/client_jwt/key",
+    "/auth/internal_jwt/key",
+    "/webhooks/endpoints/*/url",
+];
+
+/// The redaction rules every log and record the gateway writes goes through.
+/// Names are matched case-insensitively.
+#[derive(Debug, Clone)]
+pub struct Redactor {
+    headers: HashSet<String>,
+    fields: HashSet<String>,
+}
+
+impl Default for Redactor {
+    fn default() -> Self {
+        Self::new(&RedactionConfig::default())
+    }
+}
+
+impl Redactor {
+    pub fn new(config: &RedactionConfig) -> Self {
+        let lowercase = |names: &[&str], extra: &[String]| {
+            names
+                .iter()
+                .map(|name| name.to_string())
+                .chain(extra.iter().cloned())
+                .map(|name| name.to_ascii_lowercase())
+                .collect()
+        };
+        Self {
+            headers: lowercase(&REDACTED_HEADERS, &config.headers),
+            fields: lowercase(&REDACTED_FIELDS, &config.fields),
+        }
+    }
+
+    pub fn header<'a>(&self, name: &str, value: &'a str) -> &'a str {
+        if self.headers.contains(&name.to_ascii_lowercase()) {
+            REDACTED
+        } else {
+            value
+        }
+    }
+
+    /// Replaces the value of every redacted field, however deeply nested.
+    pub fn json(&self, value: &mut Value) {
+        match value {
+            Value::Object(map) => {
+                for (key, value) in map.iter_mut() {
+                    if !self.fields.contains(&key.to_ascii_lowercase()) {
+                        self.json(value);
+                    } else if let Value::Array(items) = value {
+                        items.iter_mut().for_each(|item| *item = Value::from(REDACTED));
+                    } else if !value.is_null() {
+                        *value = Value::from(REDACTED);
+                    }
+                }
+            }
+            Value::Array(items) => items.iter_mut().for_each(|item| self.json(item)),
+            _ => {}
+        }
+    }
+
+    /// Replaces the values at each of `pointers` that are set.
+    pub fn pointers(&self, value: &mut Value, pointers: &[&str]) {
+        for pointer in pointers {
+            let tokens: Vec<&str> = pointer.split('/').skip(1).collect();
+            redact_pointer(value, &tokens);
+        }
+    }
+
+    /// A query string with the values of redacted parameters replaced.
+    pub fn query(&self, query: &str) -> String {
+        query
+            .split('&')
+            .map(|pair| match pair.split_once('=') {
+                Some((name, _)) if self.fields.contains(&name.to_ascii_lowercase()) => format!("{}={}", name, REDACTED),
+                _ => pair.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join("&")
+    }
+
+    /// A body for logging: JSON has secrets redacted before being cut to
+    /// `max_bytes`, binary data is only described.
+    pub fn body(&self, body: &[u8], max_bytes: usize) -> String {
+        let text = match serde_json::from_slice::<Value>(body) {
+            Ok(mut value) => {
+                self.json(&mut value);
+                value.to_string()
+            }
+            Err(_) => match std::str::from_utf8(body) {
+                Ok(text) => text.to_string(),
+                Err(_) => return format!("<{} bytes of binary data>", body.len()),
+            },
+        };
+        if text.len() <= max_bytes {
+            return text;
+        }
+        let mut end = max_bytes;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        format!("{}… ({} bytes)", &text[..end], body.len())
+    }
+
+    /// A URI or path with its query string redacted.
+    pub fn uri(&self, uri: &str) -> String {
+        match uri.split_once('?') {
+            Some((path, query)) => format!("{}?{}", path, self.query(query)),
+            None => uri.to_string(),
+        }
+    }
+}
+
+fn redact_pointer(value: &mut Value, tokens: &[&str]) {
+    let Some((token, rest)) = tokens.split_first() else {
+        if !value.is_null() {
+            *value = Value::from(REDACTED);
+        }
+        return;
+    };
+    match (value, *token) {
+        (Value::Object(map), "*") => map.values_mut().for_each(|value| redact_pointer(value, rest)),
+        (Value::Array(items), "*") => items.iter_mut().for_each(|item| redact_pointer(item, rest)),
+        (Value::Object(map), key) => {
+            if let Some(value) = map.get_mut(key) {
+                redact_pointer(value, rest);
+            }
+        }
+        (Value::Array(items), index) => {
+            if let Some(item) = index.parse::<usize>().ok().and_then(|i| items.get_mut(i)) {
+                redact_pointer(item, rest);
+            }
+        }
+        _ => {}
+    }
+}
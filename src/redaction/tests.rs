@@ -0,0 +1,33 @@
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use crate::config::RedactionConfig;
+    use crate::redaction::{Redactor, REDACTED};
+
+    fn redactor() -> Redactor {
+        Redactor::new(&RedactionConfig {
+            headers: vec!["X-Session".to_string()],
+            fields: vec!["SSN".to_string()],
+        })
+    }
+
+    #[test]
+    fn test_configured_names_extend_builtins() {
+        let redactor = redactor();
+        assert_eq!(redactor.header("authorization", "Bearer abc"), REDACTED);
+        assert_eq!(redactor.header("x-session", "abc"), REDACTED);
+        assert_eq!(redactor.header("accept", "text/html"), "text/html");
+
+        let mut body = json!({ "user": { "ssn": "123-45-6789", "Password": "hunter2", "name": "Ann" }, "items": [{ "ssn": null }] });
+        redactor.json(&mut body);
+        assert_eq!(body, json!({ "user": { "ssn": REDACTED, "Password": REDACTED, "name": "Ann" }, "items": [{ "ssn": null }] }));
+    }
+
+    #[test]
+    fn test_query_parameters() {
+        let redactor = redactor();
+        assert_eq!(redactor.query("ssn=123&page=2&password=x"), "ssn=[redacted]&page=2&password=[redacted]");
+        assert_eq!(redactor.uri("http://users:8080/find?ssn=123"), "http://users:8080/find?ssn=[redacted]");
+        assert_eq!(redactor.uri("/plain"), "/plain");
+    }
+}
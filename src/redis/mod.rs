@@ -0,0 +1,303 @@
+use std::future::Future;
+use std::io;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use bytes::Bytes;
+use hyper::{HeaderMap, StatusCode, Uri};
+use hyper::header::{HeaderName, HeaderValue};
+use redis::aio::{MultiplexedConnection, PubSubStream};
+use redis::{Client, ConnectionAddr, ConnectionInfo, ErrorKind, FromRedisValue, ProtocolVersion, RedisConnectionInfo, RedisResult};
+use ring::rand::{SecureRandom, SystemRandom};
+use tokio::time::timeout;
+use crate::aws;
+use crate::config::RedisCacheConfig;
+use crate::models::CachedResponse;
+use crate::services::CachePurge;
+
+#[cfg(test)]
+mod tests;
+
+/// Keys asked for per `SCAN` while purging.
+const SCAN_COUNT: usize = 1000;
+
+/// Where a Redis server is and how to log in to it.
+#[derive(Clone, PartialEq, Eq)]
+pub struct RedisAddress {
+    pub host: String,
+    pub port: u16,
+    pub database: u32,
+    username: Option<String>,
+    password: Option<String>,
+}
+
+impl std::fmt::Debug for RedisAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RedisAddress")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("database", &self.database)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RedisAddress {
+    /// Parses `redis://host:port/database`; credentials come from the config,
+    /// never the URL, so they stay out of logs.
+    pub fn new(config: &RedisCacheConfig) -> Result<Self, String> {
+        let url = config.url.as_str();
+        let uri: Uri = url.parse().map_err(|e| format!("redis url \"{}\" is not valid: {}", url, e))?;
+        if uri.scheme_str() != Some("redis") {
+            return Err(format!("redis url \"{}\" must use the redis scheme", url));
+        }
+        let authority = uri.authority().ok_or_else(|| format!("redis url \"{}\" has no host", url))?;
+        if authority.as_str().contains('@') {
+            return Err(format!("redis url \"{}\" must not hold credentials, set username and password instead", url));
+        }
+        let database = match uri.path().trim_start_matches('/') {
+            "" => 0,
+            database => database.parse().map_err(|_| format!("redis url \"{}\" has an invalid database number", url))?,
+        };
+        if config.username.is_some() && config.password.is_none() {
+            return Err("redis username is set without a password".to_string());
+        }
+        Ok(Self {
+            host: authority.host().to_string(),
+            port: authority.port_u16().unwrap_or(6379),
+            database,
+            username: config.username.clone(),
+            password: config.password.clone(),
+        })
+    }
+
+    /// What the client needs to connect, log in and select the database.
+    pub fn connection_info(&self) -> ConnectionInfo {
+        ConnectionInfo {
+            addr: ConnectionAddr::Tcp(self.host.clone(), self.port),
+            redis: RedisConnectionInfo {
+                db: i64::from(self.database),
+                username: self.username.clone(),
+                password: self.password.clone(),
+                protocol: ProtocolVersion::RESP2,
+            },
+        }
+    }
+}
+
+/// What a replica announces on the invalidation channel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Invalidation {
+    /// A fresh copy of this key was stored in Redis.
+    Key(String),
+    Purge(CachePurge),
+}
+
+/// The shared tier behind the in-memory cache: every cached response, kept in
+/// Redis for all of the gateway's replicas. A replica that stores a response
+/// publishes its key, and the others drop their in-memory copy; purges are
+/// published the same way.
+pub struct RedisCache {
+    client: Client,
+    /// Whether responses are kept in Redis, or it only relays purges.
+    pub store_responses: bool,
+    key_prefix: String,
+    pub channel: String,
+    pub max_object_bytes: usize,
+    timeout: Duration,
+    /// Tags this replica's invalidations so it ignores its own.
+    instance: String,
+    /// Shared by every request; replaced after it fails or times out.
+    connection: Mutex<Option<MultiplexedConnection>>,
+}
+
+impl RedisCache {
+    pub fn new(config: &RedisCacheConfig) -> Result<Self, String> {
+        let mut instance = [0u8; 8];
+        SystemRandom::new().fill(&mut instance).map_err(|_| "no randomness available".to_string())?;
+        let client = Client::open(RedisAddress::new(config)?.connection_info()).map_err(|e| e.to_string())?;
+        Ok(Self {
+            client,
+            store_responses: config.store_responses,
+            key_prefix: config.key_prefix.clone(),
+            channel: config.channel.clone(),
+            max_object_bytes: config.max_object_bytes,
+            timeout: Duration::from_millis(config.timeout_ms),
+            instance: aws::hex(&instance),
+            connection: Mutex::new(None),
+        })
+    }
+
+    /// The response stored under `key` and when it expires, if any.
+    pub async fn get(&self, key: &str) -> RedisResult<Option<(CachedResponse, SystemTime)>> {
+        if !self.store_responses {
+            return Ok(None);
+        }
+        let data: Option<Vec<u8>> = self.query(redis::cmd("GET").arg(format!("{}{}", self.key_prefix, key))).await?;
+        match data {
+            Some(data) => decode_response(&data)
+                .map(Some)
+                .ok_or_else(|| (ErrorKind::TypeError, "cache entry is corrupt", key.to_string()).into()),
+            None => Ok(None),
+        }
+    }
+
+    /// Stores `response` until `expires_at` and tells the other replicas.
+    pub async fn set(&self, key: &str, response: &CachedResponse, expires_at: SystemTime) -> RedisResult<()> {
+        if !self.store_responses || response.body.len() > self.max_object_bytes {
+            return Ok(());
+        }
+        let ttl = expires_at.duration_since(SystemTime::now()).unwrap_or_default().as_millis().max(1) as u64;
+        let value = encode_response(response, expires_at);
+        self.query::<()>(redis::cmd("SET").arg(format!("{}{}", self.key_prefix, key)).arg(value).arg("PX").arg(ttl))
+            .await?;
+        self.publish(&Invalidation::Key(key.to_string())).await
+    }
+
+    /// Deletes the stored responses `purge` matches and tells the other
+    /// replicas to drop theirs. Returns how many were deleted from Redis.
+    pub async fn purge(&self, purge: &CachePurge) -> RedisResult<usize> {
+        let mut deleted = 0;
+        if self.store_responses {
+            let pattern = format!("{}*", escape_glob(&self.key_prefix));
+            let mut cursor = 0u64;
+            loop {
+                let (next, keys): (u64, Vec<Vec<u8>>) = self
+                    .query(redis::cmd("SCAN").arg(cursor).arg("MATCH").arg(&pattern).arg("COUNT").arg(SCAN_COUNT))
+                    .await?;
+                let matching: Vec<Vec<u8>> = keys
+                    .into_iter()
+                    .filter(|key| {
+                        std::str::from_utf8(key)
+                            .ok()
+                            .and_then(|key| key.strip_prefix(&self.key_prefix))
+                            .is_some_and(|key| purge.matches(key))
+                    })
+                    .collect();
+                if !matching.is_empty() {
+                    let count: i64 = self.query(redis::cmd("DEL").arg(matching)).await?;
+                    deleted += count.max(0) as usize;
+                }
+                if next == 0 {
+                    break;
+                }
+                cursor = next;
+            }
+        }
+        self.publish(&Invalidation::Purge(purge.clone())).await?;
+        Ok(deleted)
+    }
+
+    async fn publish(&self, invalidation: &Invalidation) -> RedisResult<()> {
+        let payload = match invalidation {
+            Invalidation::Key(key) => format!("{} key {}", self.instance, key),
+            Invalidation::Purge(purge) => format!("{} purge {}", self.instance, serde_json::to_string(purge).map_err(io::Error::from)?),
+        };
+        self.query::<i64>(redis::cmd("PUBLISH").arg(&self.channel).arg(payload)).await?;
+        Ok(())
+    }
+
+    /// The messages on the invalidation channel, from a connection of
+    /// their own.
+    pub async fn subscribe(&self) -> RedisResult<PubSubStream> {
+        let subscription = async {
+            let mut pubsub = self.client.get_async_pubsub().await?;
+            pubsub.subscribe(&self.channel).await?;
+            Ok(pubsub.into_on_message())
+        };
+        self.timed(subscription).await
+    }
+
+    /// What a message on the invalidation channel asks for, unless this
+    /// replica sent it.
+    pub fn invalidation(&self, payload: &[u8]) -> Option<Invalidation> {
+        let (instance, invalidation) = std::str::from_utf8(payload).ok()?.split_once(' ')?;
+        if instance == self.instance {
+            return None;
+        }
+        match invalidation.split_once(' ')? {
+            ("key", key) => Some(Invalidation::Key(key.to_string())),
+            ("purge", purge) => serde_json::from_str(purge).ok().map(Invalidation::Purge),
+            _ => None,
+        }
+    }
+
+    async fn query<T: FromRedisValue>(&self, command: &redis::Cmd) -> RedisResult<T> {
+        let shared = self.connection.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        let exchange = async {
+            let mut connection = match shared {
+                Some(connection) => connection,
+                None => {
+                    let connection = self.client.get_multiplexed_async_connection().await?;
+                    *self.connection.lock().unwrap_or_else(|e| e.into_inner()) = Some(connection.clone());
+                    connection
+                }
+            };
+            command.query_async(&mut connection).await
+        };
+        let result = self.timed(exchange).await;
+        // Redis refusing a command leaves the connection usable; anything
+        // else may have left it broken or mid-reply.
+        if result.as_ref().is_err_and(|e| e.is_unrecoverable_error() || e.is_timeout() || e.is_io_error()) {
+            self.connection.lock().unwrap_or_else(|e| e.into_inner()).take();
+        }
+        result
+    }
+
+    async fn timed<T>(&self, exchange: impl Future<Output = RedisResult<T>>) -> RedisResult<T> {
+        timeout(self.timeout, exchange)
+            .await
+            .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "redis timed out").into()))
+    }
+}
+
+/// `pattern` with the characters `MATCH` treats specially escaped.
+fn escape_glob(pattern: &str) -> String {
+    let mut escaped = String::with_capacity(pattern.len());
+    for c in pattern.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Expiry in Unix milliseconds, status, headers as length-prefixed names and
+/// values, then the body.
+pub fn encode_response(response: &CachedResponse, expires_at: SystemTime) -> Vec<u8> {
+    let expires_ms = expires_at.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+    let mut data = expires_ms.to_be_bytes().to_vec();
+    data.extend_from_slice(&response.status.as_u16().to_be_bytes());
+    data.extend_from_slice(&(response.headers.len() as u32).to_be_bytes());
+    for (name, value) in &response.headers {
+        for field in [name.as_str().as_bytes(), value.as_bytes()] {
+            data.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            data.extend_from_slice(field);
+        }
+    }
+    data.extend_from_slice(&response.body);
+    data
+}
+
+pub fn decode_response(data: &[u8]) -> Option<(CachedResponse, SystemTime)> {
+    fn take<'a>(data: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+        let (taken, rest) = data.split_at_checked(len)?;
+        *data = rest;
+        Some(taken)
+    }
+    fn take_u32(data: &mut &[u8]) -> Option<u32> {
+        Some(u32::from_be_bytes(take(data, 4)?.try_into().ok()?))
+    }
+
+    let mut data = data;
+    let expires_ms = u64::from_be_bytes(take(&mut data, 8)?.try_into().ok()?);
+    let status = StatusCode::from_u16(u16::from_be_bytes(take(&mut data, 2)?.try_into().ok()?)).ok()?;
+    let mut headers = HeaderMap::new();
+    for _ in 0..take_u32(&mut data)? {
+        let len = take_u32(&mut data)? as usize;
+        let name = HeaderName::from_bytes(take(&mut data, len)?).ok()?;
+        let len = take_u32(&mut data)? as usize;
+        headers.append(name, HeaderValue::from_bytes(take(&mut data, len)?).ok()?);
+    }
+    let expires_at = UNIX_EPOCH + Duration::from_millis(expires_ms);
+    Some((CachedResponse::new(status, headers, Bytes::copy_from_slice(data)), expires_at))
+}
@@ -0,0 +1,259 @@
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use bytes::Bytes;
+    use hyper::{HeaderMap, StatusCode};
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::broadcast;
+    use crate::config::RedisCacheConfig;
+    use crate::models::{AppState, CachedResponse};
+    use crate::redis::{decode_response, encode_response, RedisAddress, RedisCache};
+    use crate::services::{cache_response, get_cached_response, purge_cache, watch_cache_invalidations, CachePurge};
+
+    fn config(url: &str, username: Option<&str>, password: Option<&str>) -> RedisCacheConfig {
+        RedisCacheConfig {
+            url: url.to_string(),
+            username: username.map(String::from),
+            password: password.map(String::from),
+            ..RedisCacheConfig::default()
+        }
+    }
+
+    /// What the mock Redis answers, in RESP2.
+    enum Reply {
+        Simple(&'static str),
+        Error(&'static str),
+        Integer(i64),
+        Bulk(Option<Vec<u8>>),
+        Array(Vec<Reply>),
+    }
+
+    fn bulk(data: &[u8]) -> Reply {
+        Reply::Bulk(Some(data.to_vec()))
+    }
+
+    fn encode_reply(reply: &Reply) -> Vec<u8> {
+        match reply {
+            Reply::Simple(text) => format!("+{}\r\n", text).into_bytes(),
+            Reply::Error(text) => format!("-{}\r\n", text).into_bytes(),
+            Reply::Integer(n) => format!(":{}\r\n", n).into_bytes(),
+            Reply::Bulk(None) => b"$-1\r\n".to_vec(),
+            Reply::Bulk(Some(data)) => {
+                let mut encoded = format!("${}\r\n", data.len()).into_bytes();
+                encoded.extend_from_slice(data);
+                encoded.extend_from_slice(b"\r\n");
+                encoded
+            }
+            Reply::Array(items) => {
+                let mut data = format!("*{}\r\n", items.len()).into_bytes();
+                items.iter().for_each(|item| data.extend(encode_reply(item)));
+                data
+            }
+        }
+    }
+
+    /// The next command a client sent, as its arguments.
+    async fn read_command(stream: &mut BufReader<TcpStream>) -> Option<Vec<Vec<u8>>> {
+        let mut line = String::new();
+        stream.read_line(&mut line).await.ok()?;
+        let count: usize = line.strip_prefix('*')?.trim_end().parse().ok()?;
+        let mut args = Vec::with_capacity(count);
+        for _ in 0..count {
+            line.clear();
+            stream.read_line(&mut line).await.ok()?;
+            let len: usize = line.strip_prefix('$')?.trim_end().parse().ok()?;
+            let mut arg = vec![0; len + 2];
+            stream.read_exact(&mut arg).await.ok()?;
+            arg.truncate(len);
+            args.push(arg);
+        }
+        Some(args)
+    }
+
+    /// A Redis that wants the password `secret` and keeps GET, SET, SCAN,
+    /// DEL, PUBLISH and SUBSCRIBE. The sender counts the subscribers.
+    async fn redis() -> (SocketAddr, broadcast::Sender<(Vec<u8>, Vec<u8>)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let store = Arc::new(Mutex::new(HashMap::<Vec<u8>, Vec<u8>>::new()));
+        let (messages, _) = broadcast::channel(16);
+        let sender = messages.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let (store, messages) = (store.clone(), messages.clone());
+                tokio::spawn(async move {
+                    let mut stream = BufReader::new(stream);
+                    let mut authenticated = false;
+                    while let Some(args) = read_command(&mut stream).await {
+                        let reply = match (args[0].as_slice(), authenticated) {
+                            (b"AUTH", _) if args.last().unwrap() == b"secret" => {
+                                authenticated = true;
+                                Reply::Simple("OK")
+                            }
+                            (_, false) => Reply::Error("NOAUTH Authentication required"),
+                            (b"CLIENT", _) => Reply::Error("ERR unknown command 'CLIENT'"),
+                            (b"GET", _) => Reply::Bulk(store.lock().unwrap().get(&args[1]).cloned()),
+                            (b"SET", _) => {
+                                assert_eq!(args[3], b"PX");
+                                store.lock().unwrap().insert(args[1].clone(), args[2].clone());
+                                Reply::Simple("OK")
+                            }
+                            (b"SCAN", _) => {
+                                let prefix = args[3].strip_suffix(b"*").unwrap().to_vec();
+                                let keys = store.lock().unwrap().keys().filter(|key| key.starts_with(&prefix)).map(|key| bulk(key)).collect();
+                                Reply::Array(vec![bulk(b"0"), Reply::Array(keys)])
+                            }
+                            (b"DEL", _) => {
+                                let mut store = store.lock().unwrap();
+                                Reply::Integer(args[1..].iter().filter(|key| store.remove(*key).is_some()).count() as i64)
+                            }
+                            (b"PUBLISH", _) => {
+                                let _ = messages.send((args[1].clone(), args[2].clone()));
+                                Reply::Integer(messages.receiver_count() as i64)
+                            }
+                            (b"SUBSCRIBE", _) => {
+                                let mut receiver = messages.subscribe();
+                                let confirmation = Reply::Array(vec![bulk(b"subscribe"), bulk(&args[1]), Reply::Integer(1)]);
+                                stream.get_mut().write_all(&encode_reply(&confirmation)).await.unwrap();
+                                while let Ok((channel, payload)) = receiver.recv().await {
+                                    let message = Reply::Array(vec![bulk(b"message"), bulk(&channel), bulk(&payload)]);
+                                    if stream.get_mut().write_all(&encode_reply(&message)).await.is_err() {
+                                        break;
+                                    }
+                                }
+                                return;
+                            }
+                            (command, _) => panic!("unexpected command {}", String::from_utf8_lossy(command)),
+                        };
+                        stream.get_mut().write_all(&encode_reply(&reply)).await.unwrap();
+                    }
+                });
+            }
+        });
+        (addr, sender)
+    }
+
+    async fn replica(addr: SocketAddr, store_responses: bool) -> Arc<AppState> {
+        let mut state = AppState::new();
+        let config = RedisCacheConfig { store_responses, ..config(&format!("redis://{}/0", addr), None, Some("secret")) };
+        state.redis_cache = Some(Arc::new(RedisCache::new(&config).unwrap()));
+        let state = Arc::new(state);
+        tokio::spawn(watch_cache_invalidations(state.clone()));
+        state
+    }
+
+    async fn cached_body(state: &AppState, key: &str) -> Option<Bytes> {
+        let response = get_cached_response(state, key).await?;
+        Some(hyper::body::to_bytes(response.into_body()).await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_malformed_replies_are_errors() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut request = [0; 1024];
+                    while stream.read(&mut request).await.is_ok_and(|read| read > 0) {
+                        if stream.write_all(b"\r\n$-7\r\n").await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        let cache = RedisCache::new(&config(&format!("redis://{}", addr), None, None)).unwrap();
+        assert!(cache.get("/k").await.is_err());
+    }
+
+    #[test]
+    fn test_responses_round_trip() {
+        let mut headers = HeaderMap::new();
+        headers.append("set-cookie", "a=1".parse().unwrap());
+        headers.append("set-cookie", "b=2".parse().unwrap());
+        headers.insert("content-type", "text/plain".parse().unwrap());
+        let response = CachedResponse::new(StatusCode::NOT_FOUND, headers.clone(), Bytes::from("body"));
+        let expires_at = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+
+        let data = encode_response(&response, expires_at);
+        let (decoded, decoded_expiry) = decode_response(&data).unwrap();
+        assert_eq!((decoded.status, decoded.headers, decoded.body, decoded_expiry), (StatusCode::NOT_FOUND, headers, Bytes::from("body"), expires_at));
+        assert!(decode_response(&data[..20]).is_none());
+    }
+
+    #[test]
+    fn test_addresses() {
+        let address = RedisAddress::new(&config("redis://cache/2", None, None)).unwrap();
+        assert_eq!((address.host.as_str(), address.port, address.database), ("cache", 6379, 2));
+        let error = |url: &str, username: Option<&str>| RedisAddress::new(&config(url, username, None)).unwrap_err();
+        assert_eq!(error("rediss://cache", None), "redis url \"rediss://cache\" must use the redis scheme");
+        assert_eq!(
+            error("redis://:secret@cache", None),
+            "redis url \"redis://:secret@cache\" must not hold credentials, set username and password instead"
+        );
+        assert_eq!(error("redis://cache/db", None), "redis url \"redis://cache/db\" has an invalid database number");
+        assert_eq!(error("redis://cache", Some("gateway")), "redis username is set without a password");
+    }
+
+    #[tokio::test]
+    async fn test_replicas_share_the_cache() {
+        let (addr, messages) = redis().await;
+        let (first, second) = (replica(addr, true).await, replica(addr, true).await);
+        while messages.receiver_count() < 2 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        let response = |body: &'static str| Arc::new(CachedResponse::new(StatusCode::OK, HeaderMap::new(), Bytes::from(body)));
+
+        cache_response(&first, "/k", response("v1")).await;
+        assert_eq!(cached_body(&second, "/k").await.unwrap(), "v1");
+        assert!(second.cache.contains_key("/k"));
+
+        // The first replica's new copy invalidates the second's.
+        cache_response(&first, "/k", response("v2")).await;
+        let deadline = SystemTime::now() + Duration::from_secs(5);
+        while second.cache.contains_key("/k") && SystemTime::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert_eq!(cached_body(&second, "/k").await.unwrap(), "v2");
+        // A replica ignores its own announcements.
+        assert!(first.cache.contains_key("/k"));
+        assert!(cached_body(&second, "/missing").await.is_none());
+
+        // Purging deletes the Redis copy too.
+        assert_eq!(purge_cache(&first, &CachePurge::default()).await.unwrap(), 1);
+        while second.cache.contains_key("/k") && SystemTime::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert!(cached_body(&second, "/k").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_purges_reach_every_replica() {
+        let (addr, messages) = redis().await;
+        let (first, second) = (replica(addr, false).await, replica(addr, false).await);
+        while messages.receiver_count() < 2 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        let response = || Arc::new(CachedResponse::new(StatusCode::OK, HeaderMap::new(), Bytes::from("x")));
+        for key in ["products:GET/products/1", "products:GET/products/2?a=b", "users:GET/users/1"] {
+            cache_response(&first, key, response()).await;
+            cache_response(&second, key, response()).await;
+        }
+
+        let purge = CachePurge { route: Some("products".to_string()), ..CachePurge::default() };
+        assert_eq!(purge_cache(&first, &purge).await.unwrap(), 2);
+        let deadline = SystemTime::now() + Duration::from_secs(5);
+        while second.cache.len() > 1 && SystemTime::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        let keys = |state: &AppState| state.cache.iter().map(|entry| entry.key().clone()).collect::<Vec<_>>();
+        assert_eq!((keys(&first), keys(&second)), (vec!["users:GET/users/1".to_string()], vec!["users:GET/users/1".to_string()]));
+    }
+}
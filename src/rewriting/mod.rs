@@ -0,0 +1,218 @@
+use bytes::Bytes;
+use hyper::HeaderMap;
+use hyper::header::{HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, ETAG, HOST, LOCATION, SET_COOKIE};
+use crate::config::{CookieRewriteConfig, UrlRewriteConfig};
+
+#[cfg(test)]
+mod tests;
+
+/// Rewrites the upstream's own absolute URLs in `Location` and in textual
+/// bodies to the address clients use, so links and redirects keep working
+/// through the gateway.
+#[derive(Debug, Clone)]
+pub struct UrlRewriter {
+    public_url: Option<String>,
+    internal_urls: Vec<String>,
+    content_types: Vec<String>,
+}
+
+impl UrlRewriter {
+    pub fn new(config: &UrlRewriteConfig) -> Self {
+        Self {
+            public_url: config.public_url.as_ref().map(|url| url.trim_end_matches('/').to_string()),
+            internal_urls: config.internal_urls.iter().map(|url| url.trim_end_matches('/').to_string()).collect(),
+            content_types: config.content_types.iter().map(|ct| ct.to_ascii_lowercase()).collect(),
+        }
+    }
+
+    /// The client-facing origin: `public_url` if configured, else the
+    /// request's `Host` with `X-Forwarded-Proto` or the listener's scheme.
+    pub fn public_base(&self, request_headers: &HeaderMap, tls: bool) -> Option<String> {
+        match &self.public_url {
+            Some(url) => Some(url.clone()),
+            None => request_origin(request_headers, tls),
+        }
+    }
+
+    /// Replaces `upstream` and the configured internal URLs with `external`,
+    /// which should carry any path prefix the route strips. Compressed bodies
+    /// and other content types are left alone.
+    pub fn rewrite(&self, upstream: &str, external: &str, headers: &mut HeaderMap, body: &mut Bytes) {
+        let external = external.trim_end_matches('/');
+        let mut backends: Vec<&str> = vec![upstream.trim_end_matches('/')];
+        backends.extend(self.internal_urls.iter().map(String::as_str));
+
+        if let Some(location) = headers.get(LOCATION).and_then(|value| value.to_str().ok()) {
+            let rewritten = replace_bases(location, &backends, external);
+            if rewritten != location {
+                if let Ok(value) = HeaderValue::from_str(&rewritten) {
+                    headers.insert(LOCATION, value);
+                }
+            }
+        }
+
+        if headers.contains_key(CONTENT_ENCODING) || !self.rewrites_content_type(headers) {
+            return;
+        }
+        let Ok(text) = std::str::from_utf8(body) else {
+            return;
+        };
+        // JSON encoders may escape slashes, e.g. `http:\/\/orders:8080`.
+        let escaped: Vec<String> = backends.iter().map(|base| base.replace('/', "\\/")).collect();
+        let rewritten = replace_bases(text, &backends, external);
+        let rewritten = replace_bases(
+            &rewritten,
+            &escaped.iter().map(String::as_str).collect::<Vec<_>>(),
+            &external.replace('/', "\\/"),
+        );
+        if rewritten != text {
+            *body = Bytes::from(rewritten);
+            headers.remove(CONTENT_LENGTH);
+            headers.remove(ETAG);
+        }
+    }
+
+    fn rewrites_content_type(&self, headers: &HeaderMap) -> bool {
+        let Some(content_type) = headers.get(CONTENT_TYPE).and_then(|value| value.to_str().ok()) else {
+            return false;
+        };
+        let essence = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+        self.content_types.contains(&essence)
+    }
+}
+
+/// Rewrites upstream cookies so browsers keep sending them through the
+/// gateway rather than only to the backend's own host and paths.
+#[derive(Debug, Clone)]
+pub struct CookieRewriter {
+    config: CookieRewriteConfig,
+}
+
+impl CookieRewriter {
+    pub fn new(config: &CookieRewriteConfig) -> Self {
+        Self { config: config.clone() }
+    }
+
+    /// Rewrites every `Set-Cookie`; `prefix` is what the route strips.
+    pub fn rewrite(&self, headers: &mut HeaderMap, prefix: &str) {
+        let cookies: Vec<HeaderValue> = headers.get_all(SET_COOKIE).iter().cloned().collect();
+        if cookies.is_empty() {
+            return;
+        }
+        headers.remove(SET_COOKIE);
+        for cookie in cookies {
+            let rewritten = cookie
+                .to_str()
+                .ok()
+                .map(|cookie| self.rewrite_cookie(cookie, prefix))
+                .and_then(|cookie| HeaderValue::from_str(&cookie).ok())
+                .unwrap_or(cookie);
+            headers.append(SET_COOKIE, rewritten);
+        }
+    }
+
+    pub fn rewrite_cookie(&self, cookie: &str, prefix: &str) -> String {
+        let mut parts = cookie.split(';').map(str::trim);
+        let mut out = vec![parts.next().unwrap_or("").to_string()];
+        let prefix = prefix.trim_end_matches('/');
+        for attribute in parts.filter(|attribute| !attribute.is_empty()) {
+            let (name, value) = attribute.split_once('=').unwrap_or((attribute, ""));
+            match name.to_ascii_lowercase().as_str() {
+                "domain" => {}
+                "path" if !prefix.is_empty() => {
+                    let path = if value == "/" { prefix.to_string() } else { format!("{}{}", prefix, value) };
+                    out.push(format!("Path={}", path));
+                }
+                "secure" if self.config.secure => {}
+                "samesite" if self.config.same_site.is_some() => {}
+                _ => out.push(attribute.to_string()),
+            }
+        }
+        if let Some(domain) = &self.config.domain {
+            out.push(format!("Domain={}", domain));
+        }
+        if self.config.secure {
+            out.push("Secure".to_string());
+        }
+        if let Some(same_site) = &self.config.same_site {
+            out.push(format!("SameSite={}", same_site));
+        }
+        out.join("; ")
+    }
+}
+
+/// `scheme://host` as the client addressed the gateway.
+pub fn request_origin(request_headers: &HeaderMap, tls: bool) -> Option<String> {
+    let host = request_headers.get(HOST)?.to_str().ok()?;
+    let scheme = request_headers
+        .get("x-forwarded-proto")
+        .and_then(|proto| proto.to_str().ok())
+        .filter(|proto| matches!(*proto, "http" | "https"))
+        .unwrap_or(if tls { "https" } else { "http" });
+    Some(format!("{}://{}", scheme, host))
+}
+
+/// Maps an upstream redirect back through the route. A `Location` naming
+/// the upstream itself gets the public origin, and both it and absolute
+/// paths get back `prefix`, the part of the client's path the upstream
+/// never saw. Redirects elsewhere are left alone.
+pub fn rewrite_location(headers: &mut HeaderMap, upstream: &str, public_base: Option<&str>, prefix: &str) {
+    let Some(location) = headers.get(LOCATION).and_then(|value| value.to_str().ok()) else {
+        return;
+    };
+    let upstream = upstream.trim_end_matches('/');
+    let upstream_path = upstream.find("://").and_then(|scheme| upstream[scheme + 3..].find('/').map(|at| &upstream[scheme + 3 + at..]));
+    let prefix = prefix.trim_end_matches('/');
+
+    let rest = if location.starts_with('/') && !location.starts_with("//") {
+        match upstream_path {
+            Some(base) => strip_base(location, base),
+            None => Some(location),
+        }
+        .map(|rest| (None, rest))
+    } else {
+        strip_base(location, upstream).map(|rest| (public_base, rest))
+    };
+    let Some((origin, rest)) = rest else {
+        return;
+    };
+    let rest = if rest.is_empty() || rest.starts_with(['?', '#']) { format!("/{}", rest) } else { rest.to_string() };
+    let rewritten = format!("{}{}{}", origin.unwrap_or(""), prefix, rest);
+    if rewritten != location {
+        if let Ok(value) = HeaderValue::from_str(&rewritten) {
+            headers.insert(LOCATION, value);
+        }
+    }
+}
+
+/// `url` after `base`, if `base` ends on a path segment boundary there.
+fn strip_base<'a>(url: &'a str, base: &str) -> Option<&'a str> {
+    url.strip_prefix(base).filter(|rest| rest.is_empty() || rest.starts_with(['/', '?', '#']))
+}
+
+/// Replaces each occurrence of a base that ends where a URL's origin or path
+/// segment does, so `http://orders:8080` is not matched inside
+/// `http://orders:80801`.
+pub fn replace_bases(text: &str, bases: &[&str], replacement: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    loop {
+        let next = bases
+            .iter()
+            .filter(|base| !base.is_empty())
+            .filter_map(|base| rest.find(base).map(|at| (at, *base)))
+            .min_by_key(|&(at, base)| (at, std::cmp::Reverse(base.len())));
+        let Some((at, base)) = next else {
+            out.push_str(rest);
+            return out;
+        };
+        out.push_str(&rest[..at]);
+        let tail = &rest[at + base.len()..];
+        if tail.starts_with(|c: char| c.is_ascii_alphanumeric() || "-._~:".contains(c)) {
+            out.push_str(base);
+        } else {
+            out.push_str(replacement);
+        }
+        rest = tail;
+    }
+}
@@ -0,0 +1,138 @@
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use hyper::HeaderMap;
+    use crate::config::{CookieRewriteConfig, UrlRewriteConfig};
+    use crate::rewriting::{replace_bases, rewrite_location, CookieRewriter, UrlRewriter};
+
+    fn json_headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("content-type", "application/json; charset=utf-8".parse().unwrap());
+        headers.insert("content-length", "100".parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_replace_bases_respects_boundaries() {
+        let bases = ["http://orders:8080"];
+        assert_eq!(
+            replace_bases("http://orders:8080/a http://orders:80801/b http://orders:8080", &bases, "https://gw/api"),
+            "https://gw/api/a http://orders:80801/b https://gw/api"
+        );
+        assert_eq!(replace_bases("no links", &bases, "x"), "no links");
+    }
+
+    #[test]
+    fn test_rewrites_location_and_json_links() {
+        let rewriter = UrlRewriter::new(&UrlRewriteConfig {
+            internal_urls: vec!["http://localhost:8081/".to_string()],
+            ..UrlRewriteConfig::default()
+        });
+        let mut headers = json_headers();
+        headers.insert("location", "http://orders:8080/orders/7".parse().unwrap());
+        let mut body = Bytes::from(
+            r#"{"self":"http://localhost:8081/orders/7","next":"http:\/\/orders:8080\/orders\/8"}"#,
+        );
+
+        rewriter.rewrite("http://orders:8080/", "https://api.example.com/api", &mut headers, &mut body);
+        assert_eq!(headers["location"], "https://api.example.com/api/orders/7");
+        assert_eq!(
+            body,
+            r#"{"self":"https://api.example.com/api/orders/7","next":"https:\/\/api.example.com\/api\/orders\/8"}"#
+        );
+        assert!(!headers.contains_key("content-length"));
+    }
+
+    #[test]
+    fn test_skips_other_and_compressed_content() {
+        let rewriter = UrlRewriter::new(&UrlRewriteConfig::default());
+        let original = Bytes::from("http://orders:8080/file");
+
+        let mut headers = HeaderMap::new();
+        headers.insert("content-type", "application/octet-stream".parse().unwrap());
+        let mut body = original.clone();
+        rewriter.rewrite("http://orders:8080", "https://gw", &mut headers, &mut body);
+        assert_eq!(body, original);
+
+        let mut headers = json_headers();
+        headers.insert("content-encoding", "gzip".parse().unwrap());
+        rewriter.rewrite("http://orders:8080", "https://gw", &mut headers, &mut body);
+        assert_eq!(body, original);
+        assert!(headers.contains_key("content-length"));
+    }
+
+    #[test]
+    fn test_public_base() {
+        let mut request = HeaderMap::new();
+        request.insert("host", "api.example.com".parse().unwrap());
+        let derived = UrlRewriter::new(&UrlRewriteConfig::default());
+        assert_eq!(derived.public_base(&request, false).unwrap(), "http://api.example.com");
+        request.insert("x-forwarded-proto", "https".parse().unwrap());
+        assert_eq!(derived.public_base(&request, false).unwrap(), "https://api.example.com");
+
+        let configured = UrlRewriter::new(&UrlRewriteConfig {
+            public_url: Some("https://public.example.com/".to_string()),
+            ..UrlRewriteConfig::default()
+        });
+        assert_eq!(configured.public_base(&HeaderMap::new(), false).unwrap(), "https://public.example.com");
+    }
+
+    fn location(location: &str, upstream: &str, base: Option<&str>, prefix: &str) -> String {
+        let mut headers = HeaderMap::new();
+        headers.insert("location", location.parse().unwrap());
+        rewrite_location(&mut headers, upstream, base, prefix);
+        headers["location"].to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_location_gets_public_origin_and_prefix() {
+        let base = Some("https://api.example.com");
+        assert_eq!(location("http://orders:8080/login", "http://orders:8080", base, "/api"), "https://api.example.com/api/login");
+        assert_eq!(location("http://orders:8080?x=1", "http://orders:8080/", base, "/api"), "https://api.example.com/api/?x=1");
+        assert_eq!(location("/login?next=/", "http://orders:8080", base, "/api"), "/api/login?next=/");
+        assert_eq!(location("/login", "http://orders:8080", None, ""), "/login");
+    }
+
+    #[test]
+    fn test_location_with_upstream_base_path() {
+        let base = Some("https://gw");
+        assert_eq!(location("http://orders:8080/v2/items", "http://orders:8080/v2", base, "/api"), "https://gw/api/items");
+        assert_eq!(location("/v2/items", "http://orders:8080/v2", base, "/api"), "/api/items");
+        assert_eq!(location("/v20/items", "http://orders:8080/v2", base, "/api"), "/v20/items");
+        assert_eq!(location("https://sso.example.com/auth", "http://orders:8080", base, "/api"), "https://sso.example.com/auth");
+        assert_eq!(location("//cdn.example.com/x", "http://orders:8080", base, "/api"), "//cdn.example.com/x");
+    }
+
+    #[test]
+    fn test_cookie_domain_and_path() {
+        let rewriter = CookieRewriter::new(&CookieRewriteConfig::default());
+        assert_eq!(
+            rewriter.rewrite_cookie("sid=abc; Domain=orders.internal; Path=/; HttpOnly", "/api"),
+            "sid=abc; Path=/api; HttpOnly"
+        );
+        assert_eq!(rewriter.rewrite_cookie("sid=abc; path=/cart", "/api/"), "sid=abc; Path=/api/cart");
+        assert_eq!(rewriter.rewrite_cookie("sid=abc; Path=/", ""), "sid=abc; Path=/");
+    }
+
+    #[test]
+    fn test_cookie_security_attributes() {
+        let rewriter = CookieRewriter::new(&CookieRewriteConfig {
+            domain: Some("example.com".to_string()),
+            secure: true,
+            same_site: Some("Lax".to_string()),
+        });
+        let mut headers = HeaderMap::new();
+        headers.append("set-cookie", "a=1; SameSite=None; Secure".parse().unwrap());
+        headers.append("set-cookie", "b=2; Domain=orders.internal".parse().unwrap());
+        rewriter.rewrite(&mut headers, "");
+
+        let cookies: Vec<_> = headers.get_all("set-cookie").iter().map(|v| v.to_str().unwrap()).collect();
+        assert_eq!(
+            cookies,
+            [
+                "a=1; Domain=example.com; Secure; SameSite=Lax",
+                "b=2; Domain=example.com; Secure; SameSite=Lax",
+            ]
+        );
+    }
+}
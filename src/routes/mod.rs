@@ -0,0 +1,736 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use bytes::Bytes;
+use hyper::{HeaderMap, Method, StatusCode, header::{HeaderName, HeaderValue, CONTENT_LENGTH, CONTENT_TYPE, HOST, TRANSFER_ENCODING}};
+use regex::Regex;
+use crate::config::{CacheMode, CsrfMode, GatewayConfig, HostHeader, LogLevel, Priority, ResponseLimitConfig, RouteConfig, RouteLoggingConfig, StaticFilesConfig, ROUTE_ACTION_ERROR};
+use crate::errors::{ConfigError, GatewayError};
+use crate::decompression::Decompressor;
+use crate::deployments::Deployment;
+use crate::experiments::{random, Experiment};
+use crate::schedules::Schedule;
+use crate::fields::FieldFilter;
+use crate::formats::Translator;
+use crate::grpc::GrpcRoute;
+use crate::handlers::ErrorPage;
+use crate::rewriting::{CookieRewriter, UrlRewriter};
+use crate::upstream_auth::UpstreamAuth;
+use crate::signing::RequestSigner;
+use crate::soap::SoapMatch;
+
+#[cfg(test)]
+mod tests;
+
+pub type Params = HashMap<String, String>;
+
+/// A compiled `path_prefix`. Literal text matches itself, `{name}` captures one
+/// path segment and `{name:regex}` captures whatever the regex matches. The
+/// pattern always has to end on a segment boundary.
+#[derive(Debug, Clone)]
+pub struct PathPattern {
+    regex: Regex,
+    params: Vec<String>,
+    segments: usize,
+    literal_len: usize,
+    literal_segments: Vec<String>,
+}
+
+impl PathPattern {
+    pub fn parse(pattern: &str) -> Result<Self, String> {
+        let trimmed = pattern.trim_end_matches('/');
+        let mut regex = String::from("^(");
+        let mut params: Vec<String> = Vec::new();
+        let mut literal = String::new();
+        let mut literal_len = 0;
+
+        let mut chars = trimmed.char_indices();
+        while let Some((start, c)) = chars.next() {
+            match c {
+                '{' => {
+                    let mut depth = 1;
+                    let mut end = None;
+                    for (i, c) in chars.by_ref() {
+                        match c {
+                            '{' => depth += 1,
+                            '}' => depth -= 1,
+                            _ => {}
+                        }
+                        if depth == 0 {
+                            end = Some(i);
+                            break;
+                        }
+                    }
+                    let end = end.ok_or_else(|| format!("unclosed '{{' in pattern \"{}\"", pattern))?;
+                    let inner = &trimmed[start + 1..end];
+                    let (name, expr) = inner.split_once(':').unwrap_or((inner, "[^/]+"));
+
+                    if !is_param_name(name) {
+                        return Err(format!("invalid parameter name \"{}\" in pattern \"{}\"", name, pattern));
+                    }
+                    if params.iter().any(|p| p == name) {
+                        return Err(format!("duplicate parameter \"{}\" in pattern \"{}\"", name, pattern));
+                    }
+
+                    regex.push_str(&regex::escape(&literal));
+                    literal.clear();
+                    regex.push_str(&format!("(?P<{}>{})", name, expr));
+                    params.push(name.to_string());
+                }
+                '}' => return Err(format!("unmatched '}}' in pattern \"{}\"", pattern)),
+                c => {
+                    literal.push(c);
+                    literal_len += c.len_utf8();
+                }
+            }
+        }
+        regex.push_str(&regex::escape(&literal));
+        regex.push_str(")(?:/|$)");
+
+        let regex = Regex::new(&regex)
+            .map_err(|e| format!("invalid pattern \"{}\": {}", pattern, e))?;
+
+        Ok(Self {
+            regex,
+            params,
+            segments: trimmed.split('/').filter(|s| !s.is_empty()).count(),
+            literal_len,
+            literal_segments: trimmed
+                .split('/')
+                .take_while(|segment| !segment.contains('{'))
+                .filter(|segment| !segment.is_empty())
+                .map(str::to_string)
+                .collect(),
+        })
+    }
+
+    pub fn params(&self) -> &[String] {
+        &self.params
+    }
+
+    /// The whole segments the pattern starts with before any parameter,
+    /// which every path it matches starts with too.
+    pub fn literal_segments(&self) -> &[String] {
+        &self.literal_segments
+    }
+
+    /// Returns how many bytes of `path` the pattern consumed and the captured
+    /// parameters.
+    pub fn match_path(&self, path: &str) -> Option<(usize, Params)> {
+        let captures = self.regex.captures(path)?;
+        let matched = captures.get(1).map_or(0, |m| m.end());
+        let params = self.params
+            .iter()
+            .filter_map(|name| captures.name(name).map(|m| (name.clone(), m.as_str().to_string())))
+            .collect();
+        Some((matched, params))
+    }
+
+    /// Deeper patterns win, then patterns with more literal text.
+    fn specificity(&self) -> (usize, usize) {
+        (self.segments, self.literal_len)
+    }
+}
+
+fn is_param_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+}
+
+/// Names referenced as `{name}` in a template.
+pub fn template_params(template: &str) -> Vec<&str> {
+    let mut names = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        match rest[start..].find('}') {
+            Some(end) => {
+                names.push(&rest[start + 1..start + end]);
+                rest = &rest[start + end + 1..];
+            }
+            None => break,
+        }
+    }
+    names
+}
+
+pub fn render_template(template: &str, params: &Params) -> String {
+    let mut rendered = template.to_string();
+    for (name, value) in params {
+        rendered = rendered.replace(&format!("{{{}}}", name), value);
+    }
+    rendered
+}
+
+/// What the gateway does with a request once the route is selected.
+#[derive(Debug, Clone)]
+pub enum RouteAction {
+    Proxy {
+        upstream: String,
+    },
+    Redirect {
+        status: StatusCode,
+        location: String,
+        preserve_query: bool,
+    },
+    StaticFiles(StaticFilesConfig),
+    Respond {
+        status: StatusCode,
+        headers: HeaderMap,
+        body: Bytes,
+    },
+    Grpc(Arc<GrpcRoute>),
+}
+
+impl RouteAction {
+    fn from_config(config: &RouteConfig) -> Result<Self, String> {
+        if config.actions().len() != 1 {
+            return Err(ROUTE_ACTION_ERROR.to_string());
+        }
+        let active_group = config.deployment.as_ref().and_then(|d| d.groups.get(&d.active));
+        if let Some(upstream) = config.upstream.as_ref().or(active_group) {
+            return Ok(Self::Proxy {
+                upstream: upstream.trim_end_matches('/').to_string(),
+            });
+        }
+        if let Some(redirect) = &config.redirect {
+            return Ok(Self::Redirect {
+                status: StatusCode::from_u16(redirect.status)
+                    .map_err(|_| format!("invalid redirect status {}", redirect.status))?,
+                location: redirect.location.clone(),
+                preserve_query: redirect.preserve_query,
+            });
+        }
+        if let Some(files) = &config.static_files {
+            return Ok(Self::StaticFiles(files.clone()));
+        }
+        if let Some(grpc) = &config.grpc {
+            return Ok(Self::Grpc(Arc::new(GrpcRoute::from_config(grpc)?)));
+        }
+        let respond = config.respond.as_ref().ok_or_else(|| ROUTE_ACTION_ERROR.to_string())?;
+        let mut headers = HeaderMap::new();
+        for (name, value) in &respond.headers {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| format!("\"{}\" is not a valid header name", name))?;
+            let value = HeaderValue::from_str(value)
+                .map_err(|_| format!("invalid value for header \"{}\"", name))?;
+            headers.insert(name, value);
+        }
+        Ok(Self::Respond {
+            status: StatusCode::from_u16(respond.status)
+                .map_err(|_| format!("invalid respond status {}", respond.status))?,
+            headers,
+            body: Bytes::from(respond.body.clone()),
+        })
+    }
+
+    /// Actions answered by the gateway itself don't forward client credentials
+    /// anywhere, so they are served without authentication.
+    pub fn requires_auth(&self) -> bool {
+        matches!(self, Self::Proxy { .. } | Self::Grpc(_))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Route {
+    pub name: String,
+    pub path_prefix: String,
+    pub pattern: PathPattern,
+    pub action: RouteAction,
+    pub strip_prefix: Option<String>,
+    /// Replaces the matched prefix when forwarding; may reference parameters.
+    pub rewrite: Option<String>,
+    /// Headers added to the upstream request; values may reference parameters.
+    pub request_headers: Vec<(String, String)>,
+    /// Methods this route accepts; empty means any method.
+    pub methods: Vec<Method>,
+    /// Headers that must be present with exactly these values.
+    pub match_headers: Vec<(HeaderName, String)>,
+    /// Query parameters that must be present with exactly these values.
+    pub match_query: Vec<(String, String)>,
+    pub match_soap: Option<SoapMatch>,
+    pub bulkhead: Option<String>,
+    pub priority: Priority,
+    pub host_header: HostHeader,
+    pub cache: CacheMode,
+    pub generate_etag: bool,
+    pub timing_headers: bool,
+    /// Replay window for `Idempotency-Key` retries, if enabled.
+    pub idempotency_window: Option<Duration>,
+    pub upstream_auth: Option<Arc<UpstreamAuth>>,
+    pub signing: Option<Arc<RequestSigner>>,
+    pub content_types: Vec<String>,
+    pub translator: Option<Translator>,
+    pub decompressor: Option<Decompressor>,
+    pub field_filter: Option<FieldFilter>,
+    pub error_page: Option<ErrorPage>,
+    pub rewrite_location: bool,
+    pub url_rewriter: Option<UrlRewriter>,
+    pub cookie_rewriter: Option<CookieRewriter>,
+    pub token_exchange: bool,
+    pub csrf: Option<CsrfMode>,
+    pub schedule: Option<Schedule>,
+    pub experiment: Option<Experiment>,
+    pub deployment: Option<Arc<Deployment>>,
+    /// Deadline for the upstream's response headers.
+    pub timeout: Duration,
+    /// Deadline for reading the upstream's body after the headers.
+    pub body_timeout: Duration,
+    pub response_limit: Option<ResponseLimitConfig>,
+    pub logging: RouteLoggingConfig,
+    /// The route's tags, sorted by name.
+    pub tags: Vec<(String, String)>,
+}
+
+impl Route {
+    pub fn from_config(index: usize, config: &RouteConfig) -> Result<Self, String> {
+        let mut request_headers: Vec<(String, String)> = config.request_headers
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+        request_headers.sort();
+        let mut tags: Vec<(String, String)> = config.tags.iter().map(|(name, value)| (name.clone(), value.clone())).collect();
+        tags.sort();
+
+        let mut match_headers = config.match_headers
+            .iter()
+            .map(|(name, value)| {
+                HeaderName::from_bytes(name.as_bytes())
+                    .map(|name| (name, value.clone()))
+                    .map_err(|_| format!("\"{}\" is not a valid header name", name))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        match_headers.sort_by(|a, b| a.0.as_str().cmp(b.0.as_str()));
+
+        let mut match_query: Vec<(String, String)> = config.match_query
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+        match_query.sort();
+
+        Ok(Self {
+            name: config.name.clone().unwrap_or_else(|| format!("route-{}", index)),
+            path_prefix: config.path_prefix.clone(),
+            pattern: PathPattern::parse(&config.path_prefix)?,
+            action: RouteAction::from_config(config)?,
+            strip_prefix: config.strip_prefix.clone(),
+            rewrite: config.rewrite.clone(),
+            request_headers,
+            methods: config.methods
+                .iter()
+                .filter_map(|m| Method::from_bytes(m.as_bytes()).ok())
+                .collect(),
+            match_headers,
+            match_query,
+            match_soap: config.match_soap.as_ref().map(SoapMatch::new),
+            bulkhead: config.bulkhead.clone(),
+            priority: config.priority,
+            host_header: config.host_header.clone(),
+            cache: config.cache,
+            generate_etag: config.generate_etag,
+            timing_headers: config.timing_headers,
+            idempotency_window: config.idempotency.as_ref().map(|i| Duration::from_secs(i.window_secs)),
+            upstream_auth: config.upstream_auth.clone().map(|auth| Arc::new(UpstreamAuth::new(auth))),
+            signing: config.signing.as_ref().map(|signing| Arc::new(RequestSigner::new(signing))),
+            content_types: config.content_types.iter().map(|ct| ct.to_ascii_lowercase()).collect(),
+            translator: config.formats.as_ref().map(Translator::new),
+            decompressor: config.decompress_requests.as_ref().map(Decompressor::new),
+            field_filter: config.fields.as_ref().map(FieldFilter::new),
+            error_page: config.sanitize_errors.as_ref().map(ErrorPage::from_config).transpose()?,
+            rewrite_location: config.rewrite_location,
+            url_rewriter: config.rewrite_urls.as_ref().map(UrlRewriter::new),
+            cookie_rewriter: config.rewrite_cookies.as_ref().map(CookieRewriter::new),
+            token_exchange: config.token_exchange,
+            csrf: config.csrf,
+            schedule: config.schedule.as_ref().map(Schedule::from_config).transpose()?,
+            experiment: config.experiment.as_ref().map(Experiment::from_config).transpose()?,
+            deployment: config.deployment.as_ref().map(Deployment::from_config).transpose()?.map(Arc::new),
+            timeout: Duration::from_secs(config.timeout_secs),
+            body_timeout: Duration::from_secs(config.body_timeout_secs),
+            response_limit: config.response_limit.clone(),
+            logging: config.logging,
+            tags,
+        })
+    }
+
+    pub fn upstream(&self) -> Option<&str> {
+        match &self.action {
+            RouteAction::Proxy { upstream } => Some(upstream),
+            _ => None,
+        }
+    }
+
+    pub fn allows(&self, method: &Method) -> bool {
+        self.methods.is_empty() || self.methods.contains(method)
+    }
+
+    /// Whether a request answered with `status` gets an access log line.
+    /// Lines for 5xx responses are never sampled out.
+    pub fn logs_access(&self, status: StatusCode) -> bool {
+        match self.logging.level {
+            LogLevel::Off => false,
+            LogLevel::Error => status.is_server_error(),
+            LogLevel::Info => {
+                let rate = self.logging.sample_rate;
+                status.is_server_error() || rate >= 1.0 || (rate > 0.0 && (random() as f64 / u64::MAX as f64) < rate)
+            }
+        }
+    }
+
+    /// Whether errors met while serving the route are logged.
+    pub fn logs_errors(&self) -> bool {
+        self.logging.level != LogLevel::Off
+    }
+
+    /// Checks the request body's `Content-Type` against `content_types`,
+    /// judging from the headers alone so it can run before the body is read.
+    /// Requests that declare no body need no type.
+    pub fn check_content_type(&self, headers: &HeaderMap) -> Result<(), GatewayError> {
+        if self.content_types.is_empty() {
+            return Ok(());
+        }
+        let Some(content_type) = headers.get(CONTENT_TYPE) else {
+            let has_body = headers.contains_key(TRANSFER_ENCODING)
+                || headers
+                    .get(CONTENT_LENGTH)
+                    .and_then(|length| length.to_str().ok())
+                    .is_some_and(|length| length.trim() != "0");
+            return if has_body {
+                Err(GatewayError::UnsupportedMediaType("(none)".to_string()))
+            } else {
+                Ok(())
+            };
+        };
+        let content_type = String::from_utf8_lossy(content_type.as_bytes());
+        let essence = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+        let accepted = self.content_types.iter().any(|accepted| match accepted.strip_suffix("/*") {
+            Some(kind) => essence.split_once('/').is_some_and(|(k, _)| k == kind),
+            None => *accepted == essence,
+        });
+        if accepted {
+            Ok(())
+        } else {
+            Err(GatewayError::UnsupportedMediaType(essence))
+        }
+    }
+
+    /// Whether the request satisfies every header, query and SOAP predicate.
+    pub fn predicates_match(&self, request: &RouteRequest) -> bool {
+        if let Some(soap) = &self.match_soap {
+            if !soap.matches(request.headers, request.body) {
+                return false;
+            }
+        }
+        let headers_match = self.match_headers.iter().all(|(name, expected)| {
+            request.headers
+                .get_all(name)
+                .iter()
+                .any(|value| value.as_bytes() == expected.as_bytes())
+        });
+        if !headers_match {
+            return false;
+        }
+        if self.match_query.is_empty() {
+            return true;
+        }
+
+        let query: Vec<(String, String)> = form_urlencoded::parse(request.query.as_bytes())
+            .into_owned()
+            .collect();
+        self.match_query
+            .iter()
+            .all(|expected| query.iter().any(|param| param == expected))
+    }
+
+    /// Deeper patterns win, then patterns with more literal text, then routes
+    /// with more predicates.
+    fn specificity(&self) -> (usize, usize, usize) {
+        let (segments, literal_len) = self.pattern.specificity();
+        let soap = self.match_soap.as_ref().map_or(0, SoapMatch::predicates);
+        (segments, literal_len, self.match_headers.len() + self.match_query.len() + soap)
+    }
+}
+
+/// The parts of an incoming request that take part in route selection.
+pub struct RouteRequest<'a> {
+    pub method: &'a Method,
+    pub path: &'a str,
+    pub query: &'a str,
+    pub headers: &'a HeaderMap,
+    /// `None` while the body hasn't been read.
+    pub body: Option<&'a [u8]>,
+}
+
+/// A route selected for a request together with what its pattern captured.
+#[derive(Debug)]
+pub struct RouteMatch<'a> {
+    pub route: &'a Route,
+    pub params: Params,
+    matched_len: usize,
+}
+
+impl<'a> RouteMatch<'a> {
+    /// Path sent to the upstream after `rewrite` or `strip_prefix` is applied.
+    /// Only a rewritten path is a new string.
+    pub fn upstream_path<'p>(&self, path: &'p str) -> Cow<'p, str> {
+        let route = self.route;
+        if let Some(rewrite) = &route.rewrite {
+            let mut rewritten = render_template(rewrite, &self.params);
+            rewritten.push_str(&path[self.matched_len..]);
+            if !rewritten.starts_with('/') {
+                rewritten.insert(0, '/');
+            }
+            return Cow::Owned(rewritten);
+        }
+
+        match &route.strip_prefix {
+            Some(prefix) if has_segment_prefix(path, prefix) => {
+                let stripped = &path[prefix.trim_end_matches('/').len()..];
+                Cow::Borrowed(if stripped.is_empty() { "/" } else { stripped })
+            }
+            _ => Cow::Borrowed(path),
+        }
+    }
+
+    /// The leading part of `path` that `strip_prefix` keeps from the
+    /// upstream, which links back from the upstream must get again. Empty
+    /// for rewritten routes, whose mapping can't be reversed.
+    pub fn stripped_prefix<'p>(&self, path: &'p str) -> &'p str {
+        match &self.route.strip_prefix {
+            Some(prefix) if self.route.rewrite.is_none() && has_segment_prefix(path, prefix) => {
+                &path[..prefix.trim_end_matches('/').len()]
+            }
+            _ => "",
+        }
+    }
+
+    /// The part of the request path after the matched prefix.
+    pub fn remaining_path<'p>(&self, path: &'p str) -> &'p str {
+        &path[self.matched_len..]
+    }
+
+    /// Full upstream URI for proxy routes.
+    pub fn upstream_uri(&self, path: &str, query: &str) -> Option<String> {
+        Some(self.upstream_uri_at(self.route.upstream()?, path, query))
+    }
+
+    /// Like `upstream_uri`, but against an explicit upstream base.
+    pub fn upstream_uri_at(&self, upstream: &str, path: &str, query: &str) -> String {
+        let upstream = upstream.trim_end_matches('/');
+        let path = self.upstream_path(path);
+        let mut uri = String::with_capacity(upstream.len() + path.len() + query.len() + 1);
+        uri.push_str(upstream);
+        uri.push_str(&path);
+        if !query.is_empty() {
+            uri.push('?');
+            uri.push_str(query);
+        }
+        uri
+    }
+
+    /// Status and rendered `Location` for redirect routes.
+    pub fn redirect(&self, request: &RouteRequest) -> Option<(StatusCode, String)> {
+        let RouteAction::Redirect { status, location, preserve_query } = &self.route.action else {
+            return None;
+        };
+
+        let mut params = self.params.clone();
+        let host = request.headers.get(HOST).and_then(|h| h.to_str().ok()).unwrap_or("");
+        for (name, value) in [("host", host), ("path", request.path), ("rest", &request.path[self.matched_len..])] {
+            params.entry(name.to_string()).or_insert_with(|| value.to_string());
+        }
+
+        let mut rendered = render_template(location, &params);
+        if *preserve_query && !request.query.is_empty() {
+            rendered.push(if rendered.contains('?') { '&' } else { '?' });
+            rendered.push_str(request.query);
+        }
+        Some((*status, rendered))
+    }
+
+    /// `Host` value to send upstream. `None` leaves it to the HTTP client,
+    /// which uses the upstream URI's authority.
+    pub fn upstream_host(&self, incoming: Option<&HeaderValue>) -> Option<HeaderValue> {
+        match &self.route.host_header {
+            HostHeader::Upstream => None,
+            HostHeader::Preserve => incoming.cloned(),
+            HostHeader::Override(host) => HeaderValue::from_str(host).ok(),
+        }
+    }
+
+    pub fn request_headers(&self) -> Vec<(String, String)> {
+        self.route.request_headers
+            .iter()
+            .map(|(name, value)| (name.clone(), render_template(value, &self.params)))
+            .collect()
+    }
+}
+
+/// Routes by the literal path segments their patterns start with. Walking
+/// a path down the trie visits every route that could match it, one step
+/// per segment, however many routes there are.
+#[derive(Default)]
+struct PathTrie {
+    routes: Vec<usize>,
+    children: HashMap<String, PathTrie>,
+}
+
+impl PathTrie {
+    fn insert(&mut self, segments: &[String], route: usize) {
+        let node = segments.iter().fold(self, |node, segment| node.children.entry(segment.clone()).or_default());
+        node.routes.push(route);
+    }
+
+    /// Adds the routes whose literal segments `path` starts with.
+    fn collect(&self, path: &str, found: &mut Vec<usize>) {
+        let mut node = self;
+        found.extend(&node.routes);
+        for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+            match node.children.get(segment) {
+                Some(child) => node = child,
+                None => break,
+            }
+            found.extend(&node.routes);
+        }
+    }
+}
+
+/// The route table compiled for lookups: routes that only match one `Host`
+/// are kept apart by host, the rest in a trie of their own.
+#[derive(Default)]
+struct RouteIndex {
+    any_host: PathTrie,
+    hosts: HashMap<String, PathTrie>,
+}
+
+impl RouteIndex {
+    fn new(routes: &[Route]) -> Self {
+        let mut index = Self::default();
+        for (i, route) in routes.iter().enumerate() {
+            let segments = route.pattern.literal_segments();
+            match route.match_headers.iter().find(|(name, _)| *name == HOST) {
+                Some((_, host)) => index.hosts.entry(host.clone()).or_default().insert(segments, i),
+                None => index.any_host.insert(segments, i),
+            }
+        }
+        index
+    }
+
+    /// The positions, in table order, of the routes that may match
+    /// `request`; the others can't.
+    fn candidates(&self, request: &RouteRequest) -> Vec<usize> {
+        let mut found = Vec::new();
+        self.any_host.collect(request.path, &mut found);
+        for host in request.headers.get_all(HOST) {
+            if let Some(trie) = std::str::from_utf8(host.as_bytes()).ok().and_then(|host| self.hosts.get(host)) {
+                trie.collect(request.path, &mut found);
+            }
+        }
+        found.sort_unstable();
+        found.dedup();
+        found
+    }
+}
+
+pub struct RouteTable {
+    routes: Vec<Route>,
+    index: RouteIndex,
+}
+
+impl RouteTable {
+    pub fn new(routes: Vec<Route>) -> Self {
+        let index = RouteIndex::new(&routes);
+        Self { routes, index }
+    }
+
+    pub fn from_config(config: &GatewayConfig) -> Result<Self, ConfigError> {
+        let routes = config.routes
+            .iter()
+            .enumerate()
+            .map(|(i, route)| Route::from_config(i, route))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(ConfigError::Invalid)?;
+        Ok(Self::new(routes))
+    }
+
+    pub fn routes(&self) -> &[Route] {
+        &self.routes
+    }
+
+    /// Whether any route limits the content types it accepts.
+    pub fn checks_content_types(&self) -> bool {
+        self.routes.iter().any(|route| !route.content_types.is_empty())
+    }
+
+    /// Finds the route for a request. Among routes whose pattern and
+    /// predicates match, the most specific ones are candidates and the first
+    /// of those accepting the method wins. If none accept it the error carries
+    /// the allowed methods. Only the routes the index offers are tried.
+    pub fn find(&self, request: &RouteRequest) -> Result<RouteMatch<'_>, GatewayError> {
+        let matches: Vec<(&Route, usize, Params)> = self.index
+            .candidates(request)
+            .into_iter()
+            .map(|i| &self.routes[i])
+            .filter(|route| route.predicates_match(request))
+            .filter_map(|route| {
+                route.pattern
+                    .match_path(request.path)
+                    .map(|(matched_len, params)| (route, matched_len, params))
+            })
+            .collect();
+
+        let best = matches
+            .iter()
+            .map(|(route, _, _)| route.specificity())
+            .max()
+            .ok_or(GatewayError::NotFound)?;
+
+        let mut allowed: Vec<String> = Vec::new();
+        for (route, matched_len, params) in matches {
+            if route.specificity() != best {
+                continue;
+            }
+            if route.allows(request.method) {
+                return Ok(RouteMatch { route, params, matched_len });
+            }
+            for m in &route.methods {
+                if !allowed.iter().any(|a| a == m.as_str()) {
+                    allowed.push(m.to_string());
+                }
+            }
+        }
+
+        Err(GatewayError::MethodNotAllowed(allowed))
+    }
+}
+
+/// The route table in use, which a cluster configuration update replaces
+/// whole. Requests keep the table they started with.
+pub struct LiveRouteTable {
+    current: RwLock<Arc<RouteTable>>,
+}
+
+impl LiveRouteTable {
+    pub fn new(route_table: RouteTable) -> Self {
+        Self { current: RwLock::new(Arc::new(route_table)) }
+    }
+
+    pub fn load(&self) -> Arc<RouteTable> {
+        self.current.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    pub fn store(&self, route_table: RouteTable) {
+        *self.current.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(route_table);
+    }
+}
+
+/// Prefix match that only succeeds on path segment boundaries, so `/api`
+/// matches `/api` and `/api/users` but not `/apiary`.
+pub(crate) fn has_segment_prefix(path: &str, prefix: &str) -> bool {
+    let prefix = prefix.trim_end_matches('/');
+    match path.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
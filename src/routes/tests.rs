@@ -0,0 +1,298 @@
+#[cfg(test)]
+mod tests {
+    use hyper::{HeaderMap, Method, StatusCode};
+    use crate::config::{GatewayConfig, HostHeader, LogLevel, RedirectConfig, RouteConfig, RouteLoggingConfig, SoapMatchConfig};
+    use crate::GatewayError;
+    use crate::routes::{PathPattern, RouteMatch, RouteRequest, RouteTable};
+
+    fn route(name: &str, path_prefix: &str, upstream: &str, strip_prefix: Option<&str>) -> RouteConfig {
+        RouteConfig {
+            name: Some(name.to_string()),
+            path_prefix: path_prefix.to_string(),
+            upstream: Some(upstream.to_string()),
+            strip_prefix: strip_prefix.map(str::to_string),
+            ..RouteConfig::default()
+        }
+    }
+
+    fn find<'a>(table: &'a RouteTable, method: Method, path: &str) -> Result<RouteMatch<'a>, GatewayError> {
+        let (path, query) = path.split_once('?').unwrap_or((path, ""));
+        table.find(&RouteRequest { method: &method, path, query, headers: &HeaderMap::new(), body: None })
+    }
+
+    fn table(routes: Vec<RouteConfig>) -> RouteTable {
+        RouteTable::from_config(&GatewayConfig {
+            routes,
+            ..GatewayConfig::default()
+        }).unwrap()
+    }
+
+    #[test]
+    fn test_longest_prefix_wins() {
+        let table = table(vec![
+            route("root", "/", "http://root:80", None),
+            route("users", "/api/users", "http://users:80", None),
+            route("api", "/api", "http://api:80", None),
+        ]);
+
+        assert_eq!(find(&table, Method::GET, "/api/users/1").unwrap().route.name, "users");
+        assert_eq!(find(&table, Method::GET, "/api/orders").unwrap().route.name, "api");
+        assert_eq!(find(&table, Method::GET, "/health").unwrap().route.name, "root");
+    }
+
+    #[test]
+    fn test_prefix_matches_on_segment_boundary() {
+        let table = table(vec![route("api", "/api", "http://api:80", None)]);
+
+        assert!(find(&table, Method::GET, "/api").is_ok());
+        assert!(find(&table, Method::GET, "/api/").is_ok());
+        assert!(find(&table, Method::GET, "/apiary").is_err());
+    }
+
+    #[test]
+    fn test_upstream_uri_strips_prefix() {
+        let table = table(vec![route("api", "/api", "http://backend:8081/", Some("/api"))]);
+        let route = find(&table, Method::GET, "/api/users").unwrap();
+
+        assert_eq!(route.upstream_uri("/api/users", "").as_deref(), Some("http://backend:8081/users"));
+        assert_eq!(route.upstream_uri("/api", "a=1").as_deref(), Some("http://backend:8081/?a=1"));
+    }
+
+    #[test]
+    fn test_default_config_proxies_everything() {
+        let table = RouteTable::from_config(&GatewayConfig::default()).unwrap();
+        let route = find(&table, Method::GET, "/api/test").unwrap();
+
+        assert_eq!(route.upstream_uri("/api/test", "").as_deref(), Some("http://localhost:8081/test"));
+        assert_eq!(route.upstream_uri("/other", "").as_deref(), Some("http://localhost:8081/other"));
+    }
+
+    #[test]
+    fn test_method_restricted_route() {
+        let mut reports = route("reports", "/api/reports", "http://reports:80", None);
+        reports.methods = vec!["GET".to_string(), "HEAD".to_string()];
+        let table = table(vec![reports, route("api", "/api", "http://api:80", None)]);
+
+        assert_eq!(find(&table, Method::HEAD, "/api/reports/1").unwrap().route.name, "reports");
+        match find(&table, Method::POST, "/api/reports/1") {
+            Err(GatewayError::MethodNotAllowed(allowed)) => assert_eq!(allowed, vec!["GET", "HEAD"]),
+            other => panic!("expected MethodNotAllowed, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_same_prefix_split_by_method() {
+        let mut reads = route("reads", "/api/orders", "http://reads:80", None);
+        reads.methods = vec!["GET".to_string()];
+        let mut writes = route("writes", "/api/orders", "http://writes:80", None);
+        writes.methods = vec!["POST".to_string(), "PUT".to_string()];
+        let table = table(vec![reads, writes]);
+
+        assert_eq!(find(&table, Method::GET, "/api/orders").unwrap().route.name, "reads");
+        assert_eq!(find(&table, Method::PUT, "/api/orders").unwrap().route.name, "writes");
+        match find(&table, Method::DELETE, "/api/orders") {
+            Err(GatewayError::MethodNotAllowed(allowed)) => assert_eq!(allowed, vec!["GET", "POST", "PUT"]),
+            other => panic!("expected MethodNotAllowed, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parameterized_pattern() {
+        let mut orders = route("orders", "/api/users/{id:\\d+}/orders", "http://orders:80", None);
+        orders.rewrite = Some("/orders/by-user/{id}".to_string());
+        orders.request_headers.insert("X-User-Id".to_string(), "{id}".to_string());
+        let table = table(vec![orders, route("api", "/api", "http://api:80", None)]);
+
+        let route_match = find(&table, Method::GET, "/api/users/42/orders/7").unwrap();
+        assert_eq!(route_match.route.name, "orders");
+        assert_eq!(route_match.params["id"], "42");
+        assert_eq!(
+            route_match.upstream_uri("/api/users/42/orders/7", "page=2").as_deref(),
+            Some("http://orders:80/orders/by-user/42/7?page=2")
+        );
+        assert_eq!(route_match.request_headers(), vec![("X-User-Id".to_string(), "42".to_string())]);
+
+        // Non-numeric ids don't satisfy the regex and fall back to the prefix route.
+        assert_eq!(find(&table, Method::GET, "/api/users/bob/orders").unwrap().route.name, "api");
+    }
+
+    #[test]
+    fn test_literal_beats_parameter_at_same_depth() {
+        let table = table(vec![
+            route("by-id", "/users/{id}", "http://a:80", None),
+            route("me", "/users/me", "http://b:80", None),
+        ]);
+
+        assert_eq!(find(&table, Method::GET, "/users/me").unwrap().route.name, "me");
+        assert_eq!(find(&table, Method::GET, "/users/7").unwrap().route.name, "by-id");
+    }
+
+    #[test]
+    fn test_routes_are_indexed_by_host_and_literal_segments() {
+        assert_eq!(PathPattern::parse("/api/users/{id}/orders").unwrap().literal_segments(), ["api", "users"]);
+        assert_eq!(PathPattern::parse("/files/v{version}").unwrap().literal_segments(), ["files"]);
+        assert!(PathPattern::parse("/").unwrap().literal_segments().is_empty());
+
+        let mut routes: Vec<RouteConfig> = (0..200)
+            .map(|i| route(&format!("service-{}", i), &format!("/api/service-{}/{{id}}", i), "http://api:80", None))
+            .collect();
+        routes.push(route("files", "/files/v{version}", "http://files:80", None));
+        routes.push(RouteConfig {
+            match_headers: [("Host".to_string(), "partner.example.com".to_string())].into(),
+            ..route("partner", "/api", "http://partner:80", None)
+        });
+        routes.push(route("api", "/api", "http://api:80", None));
+        let table = table(routes);
+
+        assert_eq!(find(&table, Method::GET, "/api/service-187/42").unwrap().route.name, "service-187");
+        assert_eq!(find(&table, Method::GET, "/files/v2/report.pdf").unwrap().route.name, "files");
+        assert_eq!(find(&table, Method::GET, "/api/service-187").unwrap().route.name, "api");
+        assert!(find(&table, Method::GET, "/other").is_err());
+
+        let mut headers = HeaderMap::new();
+        headers.insert("host", "partner.example.com".parse().unwrap());
+        let request = RouteRequest { method: &Method::GET, path: "/api/status", query: "", headers: &headers, body: None };
+        assert_eq!(table.find(&request).unwrap().route.name, "partner");
+    }
+
+    #[test]
+    fn test_invalid_patterns() {
+        assert!(PathPattern::parse("/users/{id").is_err());
+        assert!(PathPattern::parse("/users/id}").is_err());
+        assert!(PathPattern::parse("/users/{id:[}").is_err());
+        assert!(PathPattern::parse("/a/{id}/b/{id}").is_err());
+        assert!(PathPattern::parse("/codes/{code:\\d{3}}").is_ok());
+    }
+
+    #[test]
+    fn test_header_and_query_predicates() {
+        let mut v2 = route("v2", "/api", "http://v2:80", None);
+        v2.match_headers.insert("X-API-Version".to_string(), "2".to_string());
+        let mut beta = route("beta", "/api", "http://beta:80", None);
+        beta.match_query.insert("channel".to_string(), "beta".to_string());
+        let table = table(vec![route("v1", "/api", "http://v1:80", None), v2, beta]);
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-api-version", "2".parse().unwrap());
+        let request = RouteRequest { method: &Method::GET, path: "/api/users", query: "", headers: &headers, body: None };
+        assert_eq!(table.find(&request).unwrap().route.name, "v2");
+
+        headers.insert("x-api-version", "3".parse().unwrap());
+        let request = RouteRequest { method: &Method::GET, path: "/api/users", query: "", headers: &headers, body: None };
+        assert_eq!(table.find(&request).unwrap().route.name, "v1");
+
+        assert_eq!(find(&table, Method::GET, "/api/users?a=1&channel=beta").unwrap().route.name, "beta");
+        assert_eq!(find(&table, Method::GET, "/api/users?channel=stable").unwrap().route.name, "v1");
+    }
+
+    #[test]
+    fn test_soap_predicates() {
+        let mut get = route("get", "/soap", "http://orders:80", None);
+        get.match_soap = Some(SoapMatchConfig { operation: Some("GetOrder".to_string()), ..SoapMatchConfig::default() });
+        let mut cancel = route("cancel", "/soap", "http://cancel:80", None);
+        cancel.match_soap = Some(SoapMatchConfig {
+            action: Some("urn:orders/Cancel".to_string()),
+            operation: Some("CancelOrder".to_string()),
+        });
+        let table = table(vec![route("legacy", "/soap", "http://legacy:80", None), get, cancel]);
+
+        let mut headers = HeaderMap::new();
+        headers.insert("content-type", "text/xml; charset=utf-8".parse().unwrap());
+        let find_soap = |headers: &HeaderMap, body: &str| {
+            let request = RouteRequest { method: &Method::POST, path: "/soap", query: "", headers, body: Some(body.as_bytes()) };
+            table.find(&request).unwrap().route.name.clone()
+        };
+        assert_eq!(find_soap(&headers, "<Envelope><Body><GetOrder/></Body></Envelope>"), "get");
+        assert_eq!(find_soap(&headers, "<Envelope><Body><CancelOrder/></Body></Envelope>"), "legacy");
+        assert_eq!(find_soap(&headers, "<Envelope><Body><ListOrders/></Body></Envelope>"), "legacy");
+
+        headers.insert("soapaction", "\"urn:orders/Cancel\"".parse().unwrap());
+        assert_eq!(find_soap(&headers, "<Envelope><Body><CancelOrder/></Body></Envelope>"), "cancel");
+    }
+
+    #[test]
+    fn test_redirect_location_template() {
+        let mut moved = route("moved", "/old/items/{id}", "http://unused:80", None);
+        moved.upstream = None;
+        moved.redirect = Some(RedirectConfig {
+            status: 308,
+            location: "https://{host}/items/{id}{rest}".to_string(),
+            preserve_query: true,
+        });
+        let table = table(vec![moved]);
+
+        let mut headers = HeaderMap::new();
+        headers.insert("host", "example.com".parse().unwrap());
+        let request = RouteRequest { method: &Method::GET, path: "/old/items/9/photos", query: "size=large", headers: &headers, body: None };
+        let route_match = table.find(&request).unwrap();
+
+        assert!(!route_match.route.action.requires_auth());
+        assert!(route_match.upstream_uri(request.path, request.query).is_none());
+        let (status, location) = route_match.redirect(&request).unwrap();
+        assert_eq!(status.as_u16(), 308);
+        assert_eq!(location, "https://example.com/items/9/photos?size=large");
+    }
+
+    #[test]
+    fn test_upstream_host_header() {
+        let mut preserve = route("preserve", "/a", "http://a:80", None);
+        preserve.host_header = HostHeader::Preserve;
+        let mut fixed = route("fixed", "/b", "http://b:80", None);
+        fixed.host_header = HostHeader::Override("api.internal".to_string());
+        let table = table(vec![preserve, fixed, route("upstream", "/c", "http://c:80", None)]);
+        let incoming = "shop.example.com".parse().unwrap();
+
+        let host = find(&table, Method::GET, "/a").unwrap().upstream_host(Some(&incoming));
+        assert_eq!(host.unwrap(), "shop.example.com");
+        let host = find(&table, Method::GET, "/b").unwrap().upstream_host(Some(&incoming));
+        assert_eq!(host.unwrap(), "api.internal");
+        assert!(find(&table, Method::GET, "/c").unwrap().upstream_host(Some(&incoming)).is_none());
+    }
+
+    #[test]
+    fn test_content_type_check() {
+        let table = table(vec![RouteConfig {
+            content_types: vec!["application/json".to_string(), "text/*".to_string()],
+            ..route("api", "/api", "http://api:80", None)
+        }]);
+        let route_match = find(&table, Method::POST, "/api/orders").unwrap();
+        let check = |pairs: &[(&'static str, &str)]| {
+            let mut headers = HeaderMap::new();
+            for &(name, value) in pairs {
+                headers.insert(name, value.parse().unwrap());
+            }
+            route_match.route.check_content_type(&headers)
+        };
+
+        assert!(check(&[("content-type", "Application/JSON; charset=utf-8")]).is_ok());
+        assert!(check(&[("content-type", "text/csv")]).is_ok());
+        assert!(check(&[]).is_ok(), "no body, no type needed");
+        assert!(check(&[("content-length", "0")]).is_ok());
+        assert!(matches!(
+            check(&[("content-type", "application/xml")]),
+            Err(GatewayError::UnsupportedMediaType(ct)) if ct == "application/xml"
+        ));
+        assert!(check(&[("content-length", "12")]).is_err());
+        assert!(check(&[("transfer-encoding", "chunked")]).is_err());
+    }
+
+    #[test]
+    fn test_route_logging() {
+        let logging = |level, sample_rate| RouteConfig {
+            logging: RouteLoggingConfig { level, sample_rate },
+            ..route("health", "/health", "http://api:80", None)
+        };
+        let logs = |config: RouteConfig, status: StatusCode| {
+            let table = table(vec![config]);
+            let route = find(&table, Method::GET, "/health").unwrap().route;
+            (route.logs_access(status), route.logs_errors())
+        };
+
+        assert_eq!(logs(logging(LogLevel::Info, 1.0), StatusCode::OK), (true, true));
+        assert_eq!(logs(logging(LogLevel::Info, 0.0), StatusCode::OK), (false, true));
+        assert_eq!(logs(logging(LogLevel::Info, 0.0), StatusCode::BAD_GATEWAY), (true, true), "failures are never sampled out");
+        assert_eq!(logs(logging(LogLevel::Error, 1.0), StatusCode::NOT_FOUND), (false, true));
+        assert_eq!(logs(logging(LogLevel::Error, 1.0), StatusCode::SERVICE_UNAVAILABLE), (true, true));
+        assert_eq!(logs(logging(LogLevel::Off, 1.0), StatusCode::SERVICE_UNAVAILABLE), (false, false));
+    }
+}
@@ -0,0 +1,170 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use hyper::{Body, Response, StatusCode, header::{HeaderValue, CONTENT_TYPE, RETRY_AFTER}};
+use crate::config::{ScheduleConfig, ScheduleWindowConfig};
+use crate::routes::template_params;
+
+#[cfg(test)]
+mod tests;
+
+const DAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+const EVERY_DAY: u8 = 0b111_1111;
+const MINUTES_PER_DAY: u32 = 24 * 60;
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+pub const SCHEDULE_TEMPLATE_VARS: &[&str] = &["route", "windows", "next_open"];
+const DEFAULT_MESSAGE: &str = "{route} is unavailable until {next_open}; it is open {windows}";
+
+/// When a route accepts requests, as a set of weekly UTC windows.
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    windows: Vec<Window>,
+    status: StatusCode,
+    message: String,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Window {
+    /// One bit per day the window opens on, Monday first.
+    days: u8,
+    /// Minutes after midnight; `end` below `start` means the next day.
+    start: u32,
+    end: u32,
+}
+
+impl Window {
+    fn opens_on(&self, weekday: u32) -> bool {
+        self.days & (1 << weekday) != 0
+    }
+
+    fn contains(&self, weekday: u32, minute: u32) -> bool {
+        if self.start < self.end {
+            self.opens_on(weekday) && (self.start..self.end).contains(&minute)
+        } else {
+            (self.opens_on(weekday) && minute >= self.start)
+                || (self.opens_on((weekday + 6) % 7) && minute < self.end)
+        }
+    }
+
+    fn describe(&self) -> String {
+        let days = if self.days == EVERY_DAY {
+            "daily".to_string()
+        } else {
+            let names: Vec<_> = (0..7).filter(|&d| self.opens_on(d)).map(|d| DAYS[d as usize]).collect();
+            names.join(",")
+        };
+        let time = |m: u32| format!("{:02}:{:02}", m / 60, m % 60);
+        format!("{} {}-{} UTC", days, time(self.start), time(self.end))
+    }
+}
+
+impl Schedule {
+    pub fn from_config(config: &ScheduleConfig) -> Result<Self, String> {
+        if config.windows.is_empty() {
+            return Err("at least one window is required".to_string());
+        }
+        let status = StatusCode::from_u16(config.status)
+            .ok()
+            .filter(|status| status.is_client_error() || status.is_server_error())
+            .ok_or_else(|| format!("status {} must be a 4xx or 5xx code", config.status))?;
+        let message = config.message.clone().unwrap_or_else(|| DEFAULT_MESSAGE.to_string());
+        if let Some(name) = template_params(&message).into_iter().find(|name| !SCHEDULE_TEMPLATE_VARS.contains(name)) {
+            return Err(format!("message references unknown variable {{{}}}", name));
+        }
+        Ok(Self {
+            windows: config.windows.iter().map(parse_window).collect::<Result<_, _>>()?,
+            status,
+            message,
+        })
+    }
+
+    pub fn is_open(&self, at: SystemTime) -> bool {
+        let (_, weekday, minute) = civil(at);
+        self.windows.iter().any(|window| window.contains(weekday, minute))
+    }
+
+    /// `at` itself if the route is open, else the start of the next window.
+    pub fn next_open(&self, at: SystemTime) -> SystemTime {
+        if self.is_open(at) {
+            return at;
+        }
+        let secs = at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let (day, weekday, _) = civil(at);
+        let next = (0..=7)
+            .flat_map(|offset| {
+                let weekday = (weekday + offset) % 7;
+                self.windows
+                    .iter()
+                    .filter(move |window| window.opens_on(weekday))
+                    .map(move |window| (day + offset as u64) * SECS_PER_DAY + window.start as u64 * 60)
+            })
+            .filter(|&start| start > secs)
+            .min()
+            .unwrap_or(secs);
+        UNIX_EPOCH + Duration::from_secs(next)
+    }
+
+    /// The refusal for a request at `at`, or `None` while the route is open.
+    /// A 503 carries `Retry-After` for when the next window opens.
+    pub fn closed_response(&self, route: &str, at: SystemTime) -> Option<Response<Body>> {
+        if self.is_open(at) {
+            return None;
+        }
+        let next_open = self.next_open(at);
+        let windows: Vec<_> = self.windows.iter().map(Window::describe).collect();
+        let message = self.message
+            .replace("{route}", route)
+            .replace("{windows}", &windows.join("; "))
+            .replace("{next_open}", &httpdate::fmt_http_date(next_open));
+
+        let mut response = Response::new(Body::from(message));
+        *response.status_mut() = self.status;
+        let headers = response.headers_mut();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/plain; charset=utf-8"));
+        if self.status == StatusCode::SERVICE_UNAVAILABLE {
+            let wait = next_open.duration_since(at).unwrap_or_default();
+            headers.insert(RETRY_AFTER, HeaderValue::from(wait.as_secs() + u64::from(wait.subsec_nanos() > 0)));
+        }
+        Some(response)
+    }
+}
+
+/// Days since the epoch, weekday (Monday is 0) and minute of the day, in UTC.
+fn civil(at: SystemTime) -> (u64, u32, u32) {
+    let secs = at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let day = secs / SECS_PER_DAY;
+    // 1970-01-01 was a Thursday.
+    (day, ((day + 3) % 7) as u32, ((secs % SECS_PER_DAY) / 60) as u32)
+}
+
+fn parse_window(config: &ScheduleWindowConfig) -> Result<Window, String> {
+    let mut days = 0;
+    for day in &config.days {
+        let index = DAYS
+            .iter()
+            .position(|name| name.eq_ignore_ascii_case(day))
+            .ok_or_else(|| format!("unknown day \"{}\", expected one of {}", day, DAYS.join(", ")))?;
+        days |= 1 << index;
+    }
+    let start = parse_time(&config.start, false)?;
+    let end = parse_time(&config.end, true)?;
+    if start == end {
+        return Err(format!("window {}-{} is empty", config.start, config.end));
+    }
+    Ok(Window {
+        days: if days == 0 { EVERY_DAY } else { days },
+        start,
+        end,
+    })
+}
+
+/// `HH:MM` as minutes after midnight; `24:00` is allowed as an end.
+fn parse_time(value: &str, is_end: bool) -> Result<u32, String> {
+    let minutes = value.split_once(':').and_then(|(hours, minutes)| {
+        let (hours, minutes) = (hours.parse::<u32>().ok()?, minutes.parse::<u32>().ok()?);
+        (minutes < 60 && hours * 60 + minutes <= MINUTES_PER_DAY).then_some(hours * 60 + minutes)
+    });
+    match minutes {
+        Some(MINUTES_PER_DAY) if !is_end => Err("24:00 may only end a window".to_string()),
+        Some(minutes) => Ok(minutes),
+        None => Err(format!("\"{}\" is not an HH:MM time", value)),
+    }
+}
@@ -0,0 +1,69 @@
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use hyper::StatusCode;
+    use crate::config::ScheduleConfig;
+    use crate::schedules::Schedule;
+
+    fn parse(json: &str) -> Result<Schedule, String> {
+        let config: ScheduleConfig = serde_json::from_str(json).unwrap();
+        Schedule::from_config(&config)
+    }
+
+    /// 2024-01-01 was a Monday.
+    fn monday(hours: u64, minutes: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_704_067_200 + hours * 3600 + minutes * 60)
+    }
+
+    #[test]
+    fn test_windows_by_day_and_across_midnight() {
+        let schedule = parse(r#"{ "windows": [
+            { "start": "01:00", "end": "05:00" },
+            { "days": ["fri"], "start": "22:00", "end": "02:00" }
+        ] }"#).unwrap();
+
+        assert!(schedule.is_open(monday(1, 0)));
+        assert!(schedule.is_open(monday(4, 59)));
+        assert!(!schedule.is_open(monday(5, 0)));
+        assert!(!schedule.is_open(monday(23, 0)));
+        let friday = |h: u64, m: u64| monday(4 * 24 + h, m);
+        assert!(schedule.is_open(friday(23, 0)));
+        assert!(schedule.is_open(friday(24, 30)), "window runs into saturday");
+        assert!(!schedule.is_open(friday(0, 30)), "thursday did not open it");
+
+        assert_eq!(schedule.next_open(monday(12, 0)), monday(25, 0));
+        assert_eq!(schedule.next_open(monday(2, 0)), monday(2, 0));
+    }
+
+    #[test]
+    fn test_closed_response() {
+        let schedule = parse(r#"{ "windows": [{ "start": "01:00", "end": "05:00" }] }"#).unwrap();
+        assert!(schedule.closed_response("ingest", monday(3, 0)).is_none());
+
+        let response = schedule.closed_response("ingest", monday(23, 30)).unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()["retry-after"], "5400");
+        let body = futures::executor::block_on(hyper::body::to_bytes(response.into_body())).unwrap();
+        assert_eq!(
+            body,
+            "ingest is unavailable until Tue, 02 Jan 2024 01:00:00 GMT; it is open daily 01:00-05:00 UTC"
+        );
+
+        let forbidden = parse(r#"{ "windows": [{ "days": ["sat", "sun"], "start": "00:00", "end": "24:00" }],
+            "status": 403, "message": "{route}: weekends only" }"#).unwrap();
+        let response = forbidden.closed_response("reports", monday(9, 0)).unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(response.headers().get("retry-after").is_none());
+    }
+
+    #[test]
+    fn test_invalid_schedules() {
+        assert!(parse(r#"{ "windows": [] }"#).is_err());
+        assert!(parse(r#"{ "windows": [{ "start": "25:00", "end": "05:00" }] }"#).is_err());
+        assert!(parse(r#"{ "windows": [{ "start": "24:00", "end": "05:00" }] }"#).is_err());
+        assert!(parse(r#"{ "windows": [{ "start": "01:00", "end": "01:00" }] }"#).is_err());
+        assert!(parse(r#"{ "windows": [{ "days": ["funday"], "start": "01:00", "end": "02:00" }] }"#).is_err());
+        assert!(parse(r#"{ "windows": [{ "start": "01:00", "end": "02:00" }], "status": 200 }"#).is_err());
+        assert!(parse(r#"{ "windows": [{ "start": "01:00", "end": "02:00" }], "message": "{when}" }"#).is_err());
+    }
+}
@@ -0,0 +1,309 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use hyper::{Body, Request};
+use serde_json::{json, Value};
+use tokio::sync::RwLock;
+use crate::auth::Authenticator;
+use crate::aws::{self, HttpsClient};
+use crate::config::{GatewayConfig, SecretsConfig, WebhookEvent};
+use crate::cluster::RunningConfig;
+use crate::routes::LiveRouteTable;
+use crate::webhooks::Webhooks;
+use crate::syslog;
+
+#[cfg(test)]
+mod tests;
+
+pub const VAULT_PREFIX: &str = "vault:";
+pub const VAULT_TOKEN_ENV: &str = "VAULT_TOKEN";
+const VAULT_TOKEN_HEADER: &str = "x-vault-token";
+const VAULT_NAMESPACE_HEADER: &str = "x-vault-namespace";
+const AWS_SERVICE: &str = "secretsmanager";
+const AWS_TARGET: &str = "secretsmanager.GetSecretValue";
+
+/// Where a secret lives, parsed from a configuration string.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SecretRef {
+    /// `vault:<mount>/<path>#<key>`, one field of a KV secret.
+    Vault { mount: String, path: String, key: String },
+    /// A Secrets Manager ARN. With `#<key>` the secret is a JSON object and
+    /// only that field is used.
+    AwsSecretsManager { arn: String, region: String, key: Option<String> },
+}
+
+impl SecretRef {
+    /// `None` if the value isn't a reference at all, and an error if it looks
+    /// like one but is malformed.
+    pub fn parse(value: &str) -> Option<Result<Self, String>> {
+        if let Some(rest) = value.strip_prefix(VAULT_PREFIX) {
+            return Some(parse_vault(rest).ok_or_else(|| {
+                format!("secret reference \"{}\" must look like vault:<mount>/<path>#<key>", value)
+            }));
+        }
+        if value.starts_with("arn:") && value.split(':').nth(2) == Some(AWS_SERVICE) {
+            return Some(parse_arn(value).ok_or_else(|| {
+                format!(
+                    "secret reference \"{}\" must look like arn:aws:secretsmanager:<region>:<account>:secret:<name>[#<key>]",
+                    value
+                )
+            }));
+        }
+        None
+    }
+}
+
+fn parse_vault(rest: &str) -> Option<SecretRef> {
+    let (location, key) = rest.rsplit_once('#')?;
+    let (mount, path) = location.split_once('/')?;
+    if mount.is_empty() || path.is_empty() || key.is_empty() {
+        return None;
+    }
+    Some(SecretRef::Vault { mount: mount.to_string(), path: path.to_string(), key: key.to_string() })
+}
+
+fn parse_arn(value: &str) -> Option<SecretRef> {
+    let (arn, key) = match value.split_once('#') {
+        Some((arn, key)) => (arn, Some(key)),
+        None => (value, None),
+    };
+    let parts: Vec<&str> = arn.splitn(7, ':').collect();
+    if parts.len() < 7 || parts[3].is_empty() || parts[5] != "secret" || parts[6].is_empty() || key == Some("") {
+        return None;
+    }
+    Some(SecretRef::AwsSecretsManager {
+        arn: arn.to_string(),
+        region: parts[3].to_string(),
+        key: key.map(str::to_string),
+    })
+}
+
+/// Every string in the configuration that looks like a secret reference,
+/// with its location such as `routes[0].upstream_auth.bearer`. The
+/// `secrets` section itself is left out.
+pub fn config_references(config: &GatewayConfig) -> Vec<(String, String)> {
+    let mut value = serde_json::to_value(config).unwrap_or_default();
+    if let Value::Object(sections) = &mut value {
+        sections.remove("secrets");
+    }
+    let mut found = Vec::new();
+    references(&value, String::new(), &mut found);
+    found
+}
+
+fn references(value: &Value, location: String, out: &mut Vec<(String, String)>) {
+    match value {
+        Value::String(text) if SecretRef::parse(text).is_some() => out.push((location, text.clone())),
+        Value::Array(items) => {
+            for (i, item) in items.iter().enumerate() {
+                references(item, format!("{}[{}]", location, i), out);
+            }
+        }
+        Value::Object(fields) => {
+            for (name, field) in fields {
+                let location = if location.is_empty() { name.clone() } else { format!("{}.{}", location, name) };
+                references(field, location, out);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn substitute(value: &mut Value, secrets: &HashMap<String, String>) {
+    match value {
+        Value::String(text) => {
+            if let Some(secret) = secrets.get(text.as_str()) {
+                *text = secret.clone();
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(|item| substitute(item, secrets)),
+        Value::Object(fields) => fields.values_mut().for_each(|field| substitute(field, secrets)),
+        _ => {}
+    }
+}
+
+/// Fetches the secrets a configuration refers to and remembers their values
+/// so they can be fetched again while the gateway runs.
+pub struct Secrets {
+    config: SecretsConfig,
+    client: HttpsClient,
+    /// Reference to its last fetched value.
+    values: RwLock<HashMap<String, String>>,
+}
+
+impl Secrets {
+    pub fn new(config: &SecretsConfig) -> Self {
+        Self {
+            config: config.clone(),
+            client: aws::https_client(),
+            values: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Whether any secret has been fetched, i.e. whether refreshing can
+    /// change anything.
+    pub async fn in_use(&self) -> bool {
+        !self.values.read().await.is_empty()
+    }
+
+    /// `config` with every secret reference replaced by the secret's value.
+    /// References seen before use the value last fetched.
+    pub async fn resolve(&self, config: &GatewayConfig) -> Result<GatewayConfig, String> {
+        let wanted: BTreeSet<String> = config_references(config).into_iter().map(|(_, reference)| reference).collect();
+        for reference in wanted {
+            if self.values.read().await.contains_key(&reference) {
+                continue;
+            }
+            let secret = self.fetch(&reference).await.map_err(|e| format!("{}: {}", reference, e))?;
+            self.values.write().await.insert(reference, secret);
+        }
+
+        let values = self.values.read().await;
+        let mut value = serde_json::to_value(config).map_err(|e| e.to_string())?;
+        if let Value::Object(sections) = &mut value {
+            for (name, section) in sections.iter_mut() {
+                if name != "secrets" {
+                    substitute(section, &values);
+                }
+            }
+        }
+        serde_json::from_value(value).map_err(|e| e.to_string())
+    }
+
+    /// Fetches every known secret again and reports whether any changed. If
+    /// one can't be fetched, all keep their previous values.
+    pub async fn refresh(&self) -> Result<bool, String> {
+        let references: Vec<String> = self.values.read().await.keys().cloned().collect();
+        let mut fetched = HashMap::new();
+        for reference in references {
+            let secret = self.fetch(&reference).await.map_err(|e| format!("{}: {}", reference, e))?;
+            fetched.insert(reference, secret);
+        }
+        let mut values = self.values.write().await;
+        let changed = *values != fetched;
+        *values = fetched;
+        Ok(changed)
+    }
+
+    /// Refreshes the secrets every `interval` and hands changed values to
+    /// what can take them live: the auth keys and the routes' upstream
+    /// credentials. Everything else, TLS included, keeps the values it
+    /// started with. Each refresh applied is sent to `webhooks` as
+    /// `config_reloaded`.
+    pub async fn watch(
+        self: Arc<Self>,
+        config: Arc<RunningConfig>,
+        interval: Duration,
+        authenticator: Arc<Authenticator>,
+        route_table: Arc<LiveRouteTable>,
+        webhooks: Option<Arc<Webhooks>>,
+    ) {
+        loop {
+            tokio::time::sleep(interval).await;
+            match self.refresh().await {
+                Ok(true) => {}
+                Ok(false) => continue,
+                Err(e) => {
+                    syslog::error(format_args!("Secret refresh failed, keeping previous values: {}", e));
+                    continue;
+                }
+            }
+            let resolved = match self.resolve(&config.config()).await {
+                Ok(resolved) => resolved,
+                Err(e) => {
+                    syslog::error(format_args!("Secret refresh failed, keeping previous values: {}", e));
+                    continue;
+                }
+            };
+            if let Err(e) = authenticator.reconfigure(&resolved.auth) {
+                syslog::error(format_args!("Auth key reload failed, keeping previous keys: {}", e));
+            }
+            for (route, route_config) in route_table.load().routes().iter().zip(&resolved.routes) {
+                if let (Some(auth), Some(auth_config)) = (&route.upstream_auth, &route_config.upstream_auth) {
+                    auth.reconfigure(auth_config.clone()).await;
+                }
+            }
+            println!("Refreshed secrets");
+            if let Some(webhooks) = &webhooks {
+                webhooks.notify(WebhookEvent::ConfigReloaded, "Refreshed secrets".to_string(), json!({ "source": "secrets" }));
+            }
+        }
+    }
+
+    /// The current value of a secret, straight from its store.
+    pub async fn fetch(&self, reference: &str) -> Result<String, String> {
+        match SecretRef::parse(reference) {
+            Some(Ok(SecretRef::Vault { mount, path, key })) => self.vault(&mount, &path, &key).await,
+            Some(Ok(SecretRef::AwsSecretsManager { arn, region, key })) => {
+                self.secrets_manager(&arn, &region, key.as_deref()).await
+            }
+            Some(Err(e)) => Err(e),
+            None => Err("not a secret reference".to_string()),
+        }
+    }
+
+    async fn vault(&self, mount: &str, path: &str, key: &str) -> Result<String, String> {
+        let vault = self.config.vault.as_ref().ok_or("secrets.vault is not configured")?;
+        let token = match &vault.token_file {
+            Some(token_file) => tokio::fs::read_to_string(token_file)
+                .await
+                .map_err(|e| format!("reading Vault token {}: {}", token_file.display(), e))?
+                .trim()
+                .to_string(),
+            None => std::env::var(VAULT_TOKEN_ENV).map_err(|_| format!("{} is not set", VAULT_TOKEN_ENV))?,
+        };
+        let address = vault.address.trim_end_matches('/');
+        let url = match vault.kv_version {
+            1 => format!("{}/v1/{}/{}", address, mount, path),
+            _ => format!("{}/v1/{}/data/{}", address, mount, path),
+        };
+
+        let mut request = Request::get(url).header(VAULT_TOKEN_HEADER, token);
+        if let Some(namespace) = &vault.namespace {
+            request = request.header(VAULT_NAMESPACE_HEADER, namespace);
+        }
+        let request = request.body(Body::empty()).map_err(|e| format!("building Vault request: {}", e))?;
+        let body = aws::send(&self.client, request).await.map_err(|e| format!("Vault {}", e))?;
+        let response: Value = serde_json::from_slice(&body).map_err(|e| format!("invalid Vault response: {}", e))?;
+        let data = match vault.kv_version {
+            1 => &response["data"],
+            _ => &response["data"]["data"],
+        };
+        field(data, key)
+    }
+
+    async fn secrets_manager(&self, arn: &str, region: &str, key: Option<&str>) -> Result<String, String> {
+        let payload = json!({ "SecretId": arn });
+        let endpoint = self.config.aws.endpoint.as_deref();
+        let response = aws::call(&self.client, AWS_SERVICE, region, endpoint, AWS_TARGET, &payload)
+            .await
+            .map_err(|e| format!("Secrets Manager {}", e))?;
+        let secret = match (&response["SecretString"], &response["SecretBinary"]) {
+            (Value::String(secret), _) => secret.clone(),
+            (_, Value::String(binary)) => STANDARD
+                .decode(binary)
+                .ok()
+                .and_then(|bytes| String::from_utf8(bytes).ok())
+                .ok_or("SecretBinary is not UTF-8 text")?,
+            _ => return Err("Secrets Manager response has no SecretString".to_string()),
+        };
+        match key {
+            Some(key) => {
+                let fields: Value = serde_json::from_str(&secret).map_err(|_| "secret is not a JSON object".to_string())?;
+                field(&fields, key)
+            }
+            None => Ok(secret),
+        }
+    }
+}
+
+/// A field of a secret; strings as they are, other values as JSON.
+fn field(data: &Value, key: &str) -> Result<String, String> {
+    match data.get(key) {
+        Some(Value::String(secret)) => Ok(secret.clone()),
+        None | Some(Value::Null) => Err(format!("secret has no field \"{}\"", key)),
+        Some(other) => Ok(other.to_string()),
+    }
+}
@@ -0,0 +1,129 @@
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use serde_json::json;
+    use warp::Filter;
+    use crate::config::{AwsSecretsConfig, GatewayConfig, SecretsConfig, UpstreamAuthConfig, VaultConfig};
+    use crate::secrets::{SecretRef, Secrets};
+
+    #[test]
+    fn test_parse_references() {
+        assert_eq!(
+            SecretRef::parse("vault:secret/payments/stripe#api_key"),
+            Some(Ok(SecretRef::Vault {
+                mount: "secret".to_string(),
+                path: "payments/stripe".to_string(),
+                key: "api_key".to_string(),
+            }))
+        );
+        assert_eq!(
+            SecretRef::parse("arn:aws:secretsmanager:eu-west-1:123456789012:secret:db-AbCdEf#password"),
+            Some(Ok(SecretRef::AwsSecretsManager {
+                arn: "arn:aws:secretsmanager:eu-west-1:123456789012:secret:db-AbCdEf".to_string(),
+                region: "eu-west-1".to_string(),
+                key: Some("password".to_string()),
+            }))
+        );
+        assert!(matches!(
+            SecretRef::parse("arn:aws-cn:secretsmanager:cn-north-1:1:secret:tls"),
+            Some(Ok(SecretRef::AwsSecretsManager { key: None, .. }))
+        ));
+
+        assert!(SecretRef::parse("vault:secret/payments").unwrap().is_err());
+        assert!(SecretRef::parse("vault:secret#key").unwrap().is_err());
+        assert!(SecretRef::parse("arn:aws:secretsmanager:eu-west-1:1:key:x").unwrap().is_err());
+        assert!(SecretRef::parse("arn:aws:secretsmanager:eu-west-1:1:secret:x#").unwrap().is_err());
+        assert_eq!(SecretRef::parse("arn:aws:iam::123456789012:role/gateway"), None);
+        assert_eq!(SecretRef::parse("plain-token"), None);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_and_refresh_from_vault() {
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let counter = fetches.clone();
+        let vault = warp::path!("v1" / "secret" / "data" / "orders")
+            .and(warp::header::<String>("x-vault-token"))
+            .map(move |token: String| {
+                assert_eq!(token, "vault-token");
+                let n = counter.fetch_add(1, Ordering::SeqCst);
+                let password = if n < 2 { "first" } else { "second" };
+                warp::reply::json(&json!({ "data": { "data": { "password": password, "pin": 1234 } } }))
+            });
+        let (addr, server) = warp::serve(vault).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let token_file = std::env::temp_dir().join(format!("api-gateway-vault-token-{}", std::process::id()));
+        std::fs::write(&token_file, "vault-token\n").unwrap();
+        let secrets = Secrets::new(&SecretsConfig {
+            vault: Some(VaultConfig {
+                address: format!("http://{}/", addr),
+                token_file: Some(token_file.clone()),
+                namespace: None,
+                kv_version: 2,
+            }),
+            ..SecretsConfig::default()
+        });
+        assert!(!secrets.in_use().await);
+
+        let mut config = GatewayConfig::default();
+        config.routes[0].upstream_auth = Some(UpstreamAuthConfig::Basic {
+            username: "gateway".to_string(),
+            password: "vault:secret/orders#password".to_string(),
+        });
+        config.routes[0].name = Some("vault:secret/orders#pin".to_string());
+        let resolved = secrets.resolve(&config).await.unwrap();
+        assert_eq!(
+            resolved.routes[0].upstream_auth,
+            Some(UpstreamAuthConfig::Basic { username: "gateway".to_string(), password: "first".to_string() })
+        );
+        assert_eq!(resolved.routes[0].name.as_deref(), Some("1234"));
+        assert!(secrets.in_use().await);
+
+        // Known references aren't fetched again until a refresh.
+        secrets.resolve(&config).await.unwrap();
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+        assert!(secrets.refresh().await.unwrap());
+        let resolved = secrets.resolve(&config).await.unwrap();
+        assert!(matches!(resolved.routes[0].upstream_auth, Some(UpstreamAuthConfig::Basic { ref password, .. }) if password == "second"));
+        assert!(!secrets.refresh().await.unwrap());
+
+        assert!(secrets.fetch("vault:secret/orders#missing").await.unwrap_err().contains("no field"));
+        assert!(secrets.fetch("vault:secret/other#password").await.unwrap_err().contains("404"));
+        std::fs::remove_file(token_file).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_fetch_from_secrets_manager() {
+        let secrets_manager = warp::post()
+            .and(warp::header::<String>("authorization"))
+            .and(warp::header::<String>("x-amz-target"))
+            .and(warp::body::bytes())
+            .map(|authorization: String, target: String, body: bytes::Bytes| {
+                assert!(authorization.starts_with("AWS4-HMAC-SHA256 Credential=AKIDTEST/"));
+                assert!(authorization.contains("/eu-west-1/secretsmanager/aws4_request"));
+                assert!(authorization.contains("SignedHeaders=content-type;host;x-amz-date;x-amz-target,"));
+                assert_eq!(target, "secretsmanager.GetSecretValue");
+                let request: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                let secret = match request["SecretId"].as_str().unwrap() {
+                    "arn:aws:secretsmanager:eu-west-1:1:secret:db" => json!({ "SecretString": "{\"password\":\"hunter2\"}" }),
+                    _ => json!({ "SecretBinary": "LS0tLS1CRUdJTg==" }),
+                };
+                warp::reply::json(&secret)
+            });
+        let (addr, server) = warp::serve(secrets_manager).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        std::env::set_var("AWS_ACCESS_KEY_ID", "AKIDTEST");
+        std::env::set_var("AWS_SECRET_ACCESS_KEY", "secret");
+        let secrets = Secrets::new(&SecretsConfig {
+            aws: AwsSecretsConfig { endpoint: Some(format!("http://{}/", addr)) },
+            ..SecretsConfig::default()
+        });
+        let db = "arn:aws:secretsmanager:eu-west-1:1:secret:db";
+        assert_eq!(secrets.fetch(&format!("{}#password", db)).await.unwrap(), "hunter2");
+        assert_eq!(secrets.fetch(db).await.unwrap(), "{\"password\":\"hunter2\"}");
+        assert_eq!(secrets.fetch("arn:aws:secretsmanager:eu-west-1:1:secret:tls").await.unwrap(), "-----BEGIN");
+        assert!(secrets.fetch("vault:secret/x#y").await.unwrap_err().contains("not configured"));
+    }
+}
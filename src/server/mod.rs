@@ -0,0 +1,129 @@
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use futures::{Stream, TryStreamExt};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::{Builder, Runtime};
+use crate::config::{RuntimeConfig, SocketConfig};
+use crate::metrics::Connections;
+
+#[cfg(test)]
+mod tests;
+
+/// Builds the multi-threaded runtime the gateway runs on. Zero counts are
+/// left to Tokio's defaults here; validation reports them as errors.
+pub fn build_runtime(config: &RuntimeConfig) -> io::Result<Runtime> {
+    let mut builder = Builder::new_multi_thread();
+    builder.enable_all();
+    if let Some(threads) = config.worker_threads.filter(|&n| n > 0) {
+        builder.worker_threads(threads);
+    }
+    if let Some(threads) = config.max_blocking_threads.filter(|&n| n > 0) {
+        builder.max_blocking_threads(threads);
+    }
+    builder.build()
+}
+
+/// Binds the listening socket with `SO_REUSEADDR`, `SO_REUSEPORT` if asked
+/// for, and the configured backlog.
+pub fn bind(addr: SocketAddr, config: &SocketConfig) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    if config.reuse_port {
+        socket.set_reuse_port(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(config.backlog.min(i32::MAX as u32) as i32)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Accepted connections, with `TCP_NODELAY` set as configured. Accept errors
+/// such as running out of file descriptors are yielded, and warp logs them
+/// and keeps accepting.
+pub fn incoming(listener: TcpListener, nodelay: bool) -> impl Stream<Item = io::Result<TcpStream>> {
+    futures::stream::unfold(listener, move |listener| async move {
+        let accepted = listener.accept().await.and_then(|(stream, _)| {
+            stream.set_nodelay(nodelay)?;
+            Ok(stream)
+        });
+        Some((accepted, listener))
+    })
+}
+
+/// An accepted connection that counts itself in `Connections` until it is
+/// dropped.
+pub struct Tracked {
+    stream: TcpStream,
+    connections: Arc<Connections>,
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        self.connections.closed(tcp_info(&self.stream));
+    }
+}
+
+impl AsyncRead for Tracked {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Tracked {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// `incoming` with every connection counted in `connections`.
+pub fn tracked<S>(incoming: S, connections: Arc<Connections>) -> impl Stream<Item = io::Result<Tracked>>
+where
+    S: Stream<Item = io::Result<TcpStream>>,
+{
+    incoming.map_ok(move |stream| {
+        connections.opened();
+        Tracked { stream, connections: connections.clone() }
+    })
+}
+
+/// The kernel's smoothed round-trip time and total retransmitted segments
+/// for `stream`.
+#[cfg(target_os = "linux")]
+fn tcp_info(stream: &TcpStream) -> Option<(Duration, u32)> {
+    use std::os::fd::AsRawFd;
+    // SAFETY: tcp_info is plain old data, so all zeroes is a valid value.
+    let mut info: libc::tcp_info = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+    // SAFETY: the descriptor stays open while `stream` is borrowed, and the
+    // kernel writes at most `len` bytes into `info`.
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_INFO,
+            (&mut info as *mut libc::tcp_info).cast(),
+            &mut len,
+        )
+    };
+    (result == 0).then(|| (Duration::from_micros(u64::from(info.tcpi_rtt)), info.tcpi_total_retrans))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn tcp_info(_: &TcpStream) -> Option<(Duration, u32)> {
+    None
+}
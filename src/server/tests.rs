@@ -0,0 +1,68 @@
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use futures::StreamExt;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use crate::config::{RuntimeConfig, SocketConfig};
+    use crate::metrics::Connections;
+    use crate::server::{bind, build_runtime, incoming, tracked};
+
+    #[test]
+    fn test_runtime_uses_configured_workers() {
+        let runtime = build_runtime(&RuntimeConfig {
+            worker_threads: Some(3),
+            max_blocking_threads: Some(4),
+        })
+        .unwrap();
+        assert_eq!(runtime.metrics().num_workers(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_accepted_streams_get_nodelay() {
+        for nodelay in [true, false] {
+            let config = SocketConfig { tcp_nodelay: nodelay, ..SocketConfig::default() };
+            let listener = bind("127.0.0.1:0".parse().unwrap(), &config).unwrap();
+            let addr = listener.local_addr().unwrap();
+            let mut accepted = Box::pin(incoming(listener, config.tcp_nodelay));
+
+            let _client = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let stream = accepted.next().await.unwrap().unwrap();
+            assert_eq!(stream.nodelay().unwrap(), nodelay);
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_reuse_port_allows_shared_listeners() {
+        let config = SocketConfig { reuse_port: true, ..SocketConfig::default() };
+        let first = bind("127.0.0.1:0".parse().unwrap(), &config).unwrap();
+        let addr = first.local_addr().unwrap();
+        assert!(bind(addr, &config).is_ok());
+        assert!(bind(addr, &SocketConfig::default()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_tracked_connections_are_counted_until_dropped() {
+        let listener = bind("127.0.0.1:0".parse().unwrap(), &SocketConfig::default()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connections = Arc::new(Connections::default());
+        let mut accepted = Box::pin(tracked(incoming(listener, true), connections.clone()));
+
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let mut first = accepted.next().await.unwrap().unwrap();
+        let _second_client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let second = accepted.next().await.unwrap().unwrap();
+        client.write_all(b"ping").await.unwrap();
+        let mut received = [0u8; 4];
+        first.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"ping");
+        assert!(connections.render().contains("gateway_connections_active 2"));
+
+        drop((first, second));
+        let text = connections.render();
+        assert!(text.contains("gateway_connections_accepted_total 2"), "{}", text);
+        assert!(text.contains("gateway_connections_active 0"));
+        #[cfg(target_os = "linux")]
+        assert!(text.contains("gateway_connection_rtt_seconds_count 2"));
+    }
+}
@@ -1,80 +1,808 @@
-use crate::models::{AppState, CacheEntry};
-use crate::config::{RATE_LIMIT_REQUESTS, RATE_LIMIT_WINDOW_SECS, CACHE_DURATION_SECS, VALID_AUTH_TOKENS};
-use std::sync::Arc;
-use tokio::sync::RwLock;
-use hyper::{Response, Body, StatusCode, HeaderMap};
-use bytes::Bytes;
+use crate::models::{AppState, CacheEntry, CachedResponse, IdempotencyRecord, Penalty};
+use crate::auth::Identity;
+use crate::config::{CacheAdmission, CacheControlConfig, CacheKeyConfig, CacheMode, RateLimitConfig, TarpitConfig, WebhookEvent, RATE_LIMIT_REQUESTS, RATE_LIMIT_WINDOW_SECS, CACHE_DURATION_SECS, VALID_AUTH_TOKENS};
+use crate::errors::GatewayError;
+use crate::redis::Invalidation;
+use crate::routes::Route;
+use crate::tenants::{Tenant, Tenants};
+use crate::syslog;
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::sync::{Arc, OnceLock};
+use std::sync::atomic::Ordering;
+use dashmap::DashMap;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use hyper::{Response, Body, HeaderMap, Method, header::{HeaderValue, AGE}};
+use ring::{digest, hmac};
+use ring::rand::SystemRandom;
 use std::time::{SystemTime, Duration};
 
 #[cfg(test)]
 mod tests;
 
-pub async fn check_rate_limit(state: &Arc<RwLock<AppState>>, headers: &HeaderMap) -> bool {
-    let mut state = state.write().await;
-    let client_ip = headers
+/// Pause before subscribing again after losing Redis.
+const REDIS_RESUBSCRIBE_SECS: u64 = 1;
+/// Room in a cache key for the method, the hashes and a variant suffix.
+const CACHE_KEY_SLACK: usize = 64;
+/// Prefix of the global per-client rate-limit buckets. Tenant buckets are
+/// `<tenant>#quota` and `<tenant>#ip:<address>`; the prefix keeps a client
+/// from naming its address after one of them.
+pub const CLIENT_BUCKET_PREFIX: &str = "ip:";
+
+pub async fn check_rate_limit(state: &AppState, headers: &HeaderMap) -> bool {
+    let limit = RateLimitConfig {
+        requests: RATE_LIMIT_REQUESTS,
+        window_secs: RATE_LIMIT_WINDOW_SECS,
+    };
+    check_limit(state, &client_bucket(headers), limit, None).await
+}
+
+/// Applies a tenant's quota and then its per-client limit (or the global one).
+/// Buckets are namespaced by tenant, so tenants never share a counter.
+pub async fn check_tenant_rate_limit(state: &AppState, headers: &HeaderMap, tenant: &Tenant) -> bool {
+    if let Some(quota) = tenant.quota {
+        let count = count_request(state, &format!("{}#quota", tenant.name), quota, None).await;
+        // Only the first request over the quota in a window is reported.
+        if count == quota.requests.saturating_add(1) {
+            if let Some(webhooks) = &state.webhooks {
+                let text = format!("Tenant {} used up its quota of {} requests per {}s", tenant.name, quota.requests, quota.window_secs);
+                let details = json!({ "tenant": tenant.name, "requests": quota.requests, "window_secs": quota.window_secs });
+                webhooks.notify(WebhookEvent::QuotaExceeded, text, details);
+            }
+        }
+        if count > quota.requests {
+            return false;
+        }
+    }
+    let limit = tenant.rate_limit.unwrap_or(RateLimitConfig {
+        requests: RATE_LIMIT_REQUESTS,
+        window_secs: RATE_LIMIT_WINDOW_SECS,
+    });
+    let cap = tenant.max_rate_limit_buckets.map(|cap| (tenant.name.as_str(), cap));
+    check_limit(state, &format!("{}#ip:{}", tenant.name, client_ip(headers)), limit, cap).await
+}
+
+/// Requests the client has left in its current window under the per-client
+/// limit the checks above applied, for tracing.
+pub fn rate_limit_remaining(state: &AppState, headers: &HeaderMap, tenant: Option<&Tenant>) -> u32 {
+    let global = RateLimitConfig {
+        requests: RATE_LIMIT_REQUESTS,
+        window_secs: RATE_LIMIT_WINDOW_SECS,
+    };
+    let (key, limit) = match tenant {
+        Some(tenant) => (format!("{}#ip:{}", tenant.name, client_ip(headers)), tenant.rate_limit.unwrap_or(global)),
+        None => (client_bucket(headers), global),
+    };
+    let count = state.rate_limits.get(&key).map_or(0, |bucket| bucket.count);
+    limit.requests.saturating_sub(count)
+}
+
+/// What tarpitting does with a request before it is processed.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Tarpit {
+    Pass,
+    Delay(Duration),
+    /// Hold the request for this long, then answer 429 without counting it.
+    Reject(Duration),
+}
+
+/// The penalty for this client's recent 429s under `state.tarpit`.
+pub fn tarpit(state: &AppState, headers: &HeaderMap) -> Tarpit {
+    let Some(config) = &state.tarpit else {
+        return Tarpit::Pass;
+    };
+    let strikes = strikes(state, headers);
+    let max_delay = Duration::from_millis(config.max_delay_ms);
+    if strikes >= config.hard_threshold {
+        Tarpit::Reject(max_delay)
+    } else if strikes >= config.soft_threshold {
+        let doublings = (strikes - config.soft_threshold).min(32);
+        Tarpit::Delay(Duration::from_millis(config.base_delay_ms.saturating_mul(1 << doublings)).min(max_delay))
+    } else {
+        Tarpit::Pass
+    }
+}
+
+/// The client's strikes that haven't expired yet.
+pub fn strikes(state: &AppState, headers: &HeaderMap) -> u32 {
+    let Some(config) = &state.tarpit else {
+        return 0;
+    };
+    match state.penalties.get(client_ip(headers)) {
+        Some(penalty) if !penalty_expired(&penalty, config, SystemTime::now()) => penalty.strikes,
+        _ => 0,
+    }
+}
+
+/// Counts a 429 against the client. New clients are not tracked once the
+/// map holds `max_rate_limit_buckets`, so spoofed addresses can't grow it
+/// without bound.
+pub fn add_strike(state: &AppState, headers: &HeaderMap) {
+    let Some(config) = &state.tarpit else {
+        return;
+    };
+    let ip = client_ip(headers);
+    if !state.penalties.contains_key(ip) && state.penalties.len() >= state.max_rate_limit_buckets {
+        return;
+    }
+    let now = SystemTime::now();
+    state
+        .penalties
+        .entry(ip.to_string())
+        .and_modify(|penalty| {
+            if penalty_expired(penalty, config, now) {
+                penalty.strikes = 0;
+            }
+            penalty.strikes = penalty.strikes.saturating_add(1);
+            penalty.last_strike = now;
+        })
+        .or_insert(Penalty { strikes: 1, last_strike: now });
+}
+
+fn penalty_expired(penalty: &Penalty, config: &TarpitConfig, now: SystemTime) -> bool {
+    now.duration_since(penalty.last_strike)
+        .is_ok_and(|elapsed| elapsed >= Duration::from_secs(config.reset_secs))
+}
+
+/// Drops the least recently used tenth of the buckets, so a full map pays
+/// for one scan per many new clients rather than one per client.
+fn evict_least_recently_used(state: &AppState) {
+    let mut last_seen: Vec<_> = state.rate_limits.iter().map(|bucket| bucket.last_seen).collect();
+    if last_seen.is_empty() {
+        return;
+    }
+    let evict = (state.max_rate_limit_buckets / 10).clamp(1, last_seen.len());
+    let (_, cutoff, _) = last_seen.select_nth_unstable(evict - 1);
+    let cutoff = *cutoff;
+
+    let mut evicted = 0;
+    state.rate_limits.retain(|_, bucket| {
+        let keep = bucket.last_seen > cutoff;
+        evicted += u64::from(!keep);
+        keep
+    });
+    state.rate_limit_evictions.capacity.fetch_add(evicted, Ordering::Relaxed);
+}
+
+/// Removes buckets whose window has passed. They carry no state: the next
+/// request from that client would start a fresh window anyway.
+pub async fn evict_idle_rate_limits(state: &AppState, tenants: &Tenants) -> usize {
+    let now = SystemTime::now();
+    let mut evicted = 0;
+    state.rate_limits.retain(|key, bucket| {
+        let window = Duration::from_secs(bucket_limit(key, tenants).window_secs);
+        let keep = now.duration_since(bucket.window_start).map_or(true, |elapsed| elapsed < window);
+        evicted += usize::from(!keep);
+        keep
+    });
+    state.rate_limit_evictions.idle.fetch_add(evicted as u64, Ordering::Relaxed);
+    if let Some(config) = &state.tarpit {
+        state.penalties.retain(|_, penalty| !penalty_expired(penalty, config, now));
+    }
+    evicted
+}
+
+/// The limit that applies to the bucket `key`, as named by the functions above.
+pub fn bucket_limit(key: &str, tenants: &Tenants) -> RateLimitConfig {
+    let global = RateLimitConfig {
+        requests: RATE_LIMIT_REQUESTS,
+        window_secs: RATE_LIMIT_WINDOW_SECS,
+    };
+    let Some((tenant, bucket)) = tenant_bucket(key) else {
+        return global;
+    };
+    let Some(tenant) = tenants.get(tenant) else {
+        return global;
+    };
+    match bucket {
+        "quota" => tenant.quota.unwrap_or(global),
+        _ => tenant.rate_limit.unwrap_or(global),
+    }
+}
+
+/// The tenant and bucket name of a tenant's bucket `key`, or `None` for a
+/// global per-client bucket.
+pub fn tenant_bucket(key: &str) -> Option<(&str, &str)> {
+    if key.starts_with(CLIENT_BUCKET_PREFIX) {
+        return None;
+    }
+    key.split_once('#')
+}
+
+/// The global rate-limit bucket of the client making the request.
+pub fn client_bucket(headers: &HeaderMap) -> String {
+    format!("{}{}", CLIENT_BUCKET_PREFIX, client_ip(headers))
+}
+
+pub fn client_ip(headers: &HeaderMap) -> &str {
+    headers
         .get("x-forwarded-for")
         .and_then(|h| h.to_str().ok())
-        .unwrap_or("unknown");
+        .unwrap_or("unknown")
+}
+
+/// Counts one request against the fixed-window bucket `key`. With a
+/// `(tenant, cap)`, a new bucket evicts that tenant's oldest beyond the cap.
+async fn check_limit(
+    state: &AppState,
+    key: &str,
+    limit: RateLimitConfig,
+    cap: Option<(&str, usize)>,
+) -> bool {
+    count_request(state, key, limit, cap).await <= limit.requests
+}
 
+/// Counts a request in `key`'s bucket and returns the count for its window.
+async fn count_request(
+    state: &AppState,
+    key: &str,
+    limit: RateLimitConfig,
+    cap: Option<(&str, usize)>,
+) -> u32 {
+    if !state.rate_limits.contains_key(key) {
+        if let Some((tenant, cap)) = cap {
+            let mut tenant_entries = state.tenant_entries.lock().unwrap_or_else(|e| e.into_inner());
+            let order = &mut tenant_entries.entry(tenant.to_string()).or_default().rate_limits;
+            make_room(&state.rate_limits, order, key, cap);
+        }
+        if state.rate_limits.len() >= state.max_rate_limit_buckets {
+            evict_least_recently_used(state);
+        }
+    }
     let now = SystemTime::now();
-    let rate_limit = state.rate_limits.entry(client_ip.to_string())
+    let rate_limit = state.rate_limits.entry(key.to_string())
         .and_modify(|rl| {
             if let Ok(duration) = now.duration_since(rl.window_start) {
-                if duration.as_secs() >= RATE_LIMIT_WINDOW_SECS {
+                if duration.as_secs() >= limit.window_secs {
                     rl.count = 1;
                     rl.window_start = now;
                 } else {
                     rl.count += 1;
                 }
             }
+            rl.last_seen = now;
         })
         .or_insert_with(|| crate::models::RateLimit {
             count: 1,
             window_start: now,
+            last_seen: now,
         });
 
-    rate_limit.count <= RATE_LIMIT_REQUESTS
+    rate_limit.count
 }
 
-pub async fn get_cached_response(state: &Arc<RwLock<AppState>>, cache_key: &str) -> Option<Response<Body>> {
-    let state = state.read().await;
-    if let Some(entry) = state.cache.get(cache_key) {
-        if SystemTime::now() < entry.expires_at {
-            let (status, headers, body) = entry.response_parts.clone();
-            let mut response = Response::builder()
-                .status(status)
-                .body(Body::from(body))
-                .unwrap();
-            *response.headers_mut() = headers;
-            return Some(response);
+/// Looks `cache_key` up in memory, then in Redis if configured. A Redis hit
+/// is kept in memory too when it passes admission. Hits carry their `Age`.
+pub async fn get_cached_response(state: &AppState, cache_key: &str) -> Option<Response<Body>> {
+    if state.cache_limits.admission == CacheAdmission::TinyLfu {
+        state.cache_sketch.lock().unwrap_or_else(|e| e.into_inner()).record(cache_key);
+    }
+    let cached = state
+        .cache
+        .get(cache_key)
+        .filter(|entry| SystemTime::now() < entry.expires_at)
+        .map(|entry| (entry.response.clone(), entry.expires_at));
+    if let Some((cached, expires_at)) = cached {
+        return Some(aged_response(&cached, expires_at));
+    }
+    let redis = state.redis_cache.as_ref()?;
+    match redis.get(cache_key).await {
+        Ok(Some((response, expires_at))) if SystemTime::now() < expires_at => {
+            let response = Arc::new(response);
+            store_cached(state, cache_key, response.clone(), expires_at);
+            Some(aged_response(&response, expires_at))
+        }
+        Ok(_) => None,
+        Err(e) => {
+            syslog::error(format_args!("Redis cache lookup failed: {}", e));
+            None
         }
     }
-    None
+}
+
+/// `cached` with `Age` set to how long ago the upstream produced it: the
+/// age it arrived with plus the time since it was stored, which was
+/// `CACHE_DURATION_SECS` before `expires_at`.
+fn aged_response(cached: &CachedResponse, expires_at: SystemTime) -> Response<Body> {
+    let stored_at = expires_at - Duration::from_secs(CACHE_DURATION_SECS);
+    let resident = SystemTime::now().duration_since(stored_at).unwrap_or_default().as_secs();
+    let mut response = cached.to_response();
+    let initial = response.headers().get(AGE).and_then(|age| age.to_str().ok()?.parse::<u64>().ok()).unwrap_or(0);
+    response.headers_mut().insert(AGE, HeaderValue::from(initial.saturating_add(resident)));
+    response
 }
 
 pub async fn cache_response(
-    state: &Arc<RwLock<AppState>>,
+    state: &AppState,
     cache_key: &str,
-    response_parts: (StatusCode, HeaderMap, Bytes),
+    response: Arc<CachedResponse>,
 ) {
-    let mut state = state.write().await;
-    state.cache.insert(
-        cache_key.to_string(),
-        CacheEntry {
-            response_parts,
-            expires_at: SystemTime::now() + Duration::from_secs(CACHE_DURATION_SECS),
-        },
-    );
+    let expires_at = SystemTime::now() + Duration::from_secs(CACHE_DURATION_SECS);
+    store_cached(state, cache_key, response.clone(), expires_at);
+    store_shared(state, cache_key, &response, expires_at).await;
 }
 
-pub fn is_authenticated(headers: &HeaderMap) -> bool {
-    if let Some(auth_header) = headers.get("Authorization") {
-        if let Ok(auth_str) = auth_header.to_str() {
-            if auth_str.starts_with("Bearer ") {
-                let token = &auth_str[7..];
-                return VALID_AUTH_TOKENS.contains_key(token);
+/// Like `cache_response`, but within the tenant's `max_cache_entries`: once
+/// full, its own oldest entries are evicted, never another tenant's.
+pub async fn cache_tenant_response(
+    state: &AppState,
+    tenant: &Tenant,
+    cache_key: &str,
+    response: Arc<CachedResponse>,
+) {
+    let expires_at = SystemTime::now() + Duration::from_secs(CACHE_DURATION_SECS);
+    store_shared(state, cache_key, &response, expires_at).await;
+    if response.body.len() > state.cache_limits.max_object_bytes {
+        return;
+    }
+    if let Some(cap) = tenant.max_cache_entries {
+        if !state.cache.contains_key(cache_key) {
+            let mut tenant_entries = state.tenant_entries.lock().unwrap_or_else(|e| e.into_inner());
+            let order = &mut tenant_entries.entry(tenant.name.clone()).or_default().cache;
+            make_room(&state.cache, order, cache_key, cap);
+        }
+    }
+    store_cached(state, cache_key, response, expires_at);
+}
+
+async fn store_shared(state: &AppState, cache_key: &str, response: &CachedResponse, expires_at: SystemTime) {
+    if let Some(redis) = &state.redis_cache {
+        if let Err(e) = redis.set(cache_key, response, expires_at).await {
+            syslog::error(format_args!("Redis cache store failed: {}", e));
+        }
+    }
+}
+
+/// Which cache entries a purge drops: those of `route`, of `tenant` and
+/// under `path_prefix`, each if given. An empty purge drops everything.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CachePurge {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub route: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path_prefix: Option<String>,
+}
+
+impl CachePurge {
+    /// Whether `key`, as made by `cache_key`, is to be dropped.
+    pub fn matches(&self, key: &str) -> bool {
+        let (namespaced_route, rest) = key.split_once(':').unwrap_or((key, ""));
+        let (tenant, route) = match namespaced_route.split_once('#') {
+            Some((tenant, route)) => (Some(tenant), route),
+            None => (None, namespaced_route),
+        };
+        // After the method, and for per-user keys the user hash, comes the path.
+        let path = rest.find('/').map_or("", |start| &rest[start..]);
+        self.route.as_deref().is_none_or(|wanted| wanted == route)
+            && self.tenant.as_deref().is_none_or(|wanted| Some(wanted) == tenant)
+            && self.path_prefix.as_deref().is_none_or(|prefix| path.starts_with(prefix))
+    }
+}
+
+/// Header trusted callers steer the cache with.
+pub const CACHE_CONTROL_HEADER: &str = "x-gateway-cache";
+/// What the cache did with a request, on every response of a cacheable one.
+pub const CACHE_STATUS_HEADER: &str = "x-cache";
+
+/// How a trusted caller asked for a request to be served.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheRequest {
+    /// From the cache if it can be.
+    Default,
+    /// From the upstream, leaving the cache as it is.
+    Bypass,
+    /// From the upstream, storing the response over the cached one.
+    Refresh,
+}
+
+/// What the cache did with a request, as `X-Cache` reports it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheStatus {
+    Hit,
+    Miss,
+    Bypass,
+    Refresh,
+}
+
+impl CacheStatus {
+    /// The status a cacheable request starts with, before a lookup.
+    pub fn before_lookup(request: CacheRequest) -> Self {
+        match request {
+            CacheRequest::Default => Self::Miss,
+            CacheRequest::Bypass => Self::Bypass,
+            CacheRequest::Refresh => Self::Refresh,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Hit => "HIT",
+            Self::Miss => "MISS",
+            Self::Bypass => "BYPASS",
+            Self::Refresh => "REFRESH",
+        }
+    }
+
+    pub fn header_value(self) -> HeaderValue {
+        HeaderValue::from_static(self.as_str())
+    }
+}
+
+/// Reads and removes `X-Gateway-Cache`, which never reaches the upstream.
+/// Returns what the caller asked for if `config` trusts it, as an admin or
+/// through its scopes; other callers' requests are served as usual.
+pub fn take_cache_request(
+    headers: &mut HeaderMap,
+    config: Option<&CacheControlConfig>,
+    admin: bool,
+    identity: Option<&Identity>,
+) -> Result<Option<CacheRequest>, GatewayError> {
+    let requested = headers.remove(CACHE_CONTROL_HEADER);
+    let Some(config) = config else {
+        return Ok(None);
+    };
+    let scoped = config.scope.as_ref().is_some_and(|scope| {
+        identity.is_some_and(|identity| identity.scopes.contains(scope))
+    });
+    if !admin && !scoped {
+        return Ok(None);
+    }
+    let Some(requested) = requested else {
+        return Ok(Some(CacheRequest::Default));
+    };
+    match requested.to_str().map(str::trim) {
+        Ok(value) if value.eq_ignore_ascii_case("bypass") => Ok(Some(CacheRequest::Bypass)),
+        Ok(value) if value.eq_ignore_ascii_case("refresh") => Ok(Some(CacheRequest::Refresh)),
+        _ => Err(GatewayError::BadRequest("X-Gateway-Cache must be bypass or refresh".to_string())),
+    }
+}
+
+/// Drops the entries `purge` matches here and, when Redis is configured,
+/// there and in every other replica's memory. Returns how many were dropped
+/// here.
+pub async fn purge_cache(state: &AppState, purge: &CachePurge) -> Result<usize, String> {
+    let removed = purge_local(state, purge);
+    if let Some(redis) = &state.redis_cache {
+        redis
+            .purge(purge)
+            .await
+            .map_err(|e| format!("purged {} entries here, but not through Redis: {}", removed, e))?;
+    }
+    Ok(removed)
+}
+
+fn purge_local(state: &AppState, purge: &CachePurge) -> usize {
+    let mut removed = 0;
+    state.cache.retain(|key, _| {
+        let keep = !purge.matches(key);
+        removed += usize::from(!keep);
+        keep
+    });
+    removed
+}
+
+/// Drops in-memory entries other replicas have replaced in Redis or purged.
+/// After the subscription drops, everything held in memory may be stale, so
+/// it is cleared before subscribing again.
+pub async fn watch_cache_invalidations(state: Arc<AppState>) {
+    let Some(redis) = state.redis_cache.clone() else {
+        return;
+    };
+    let mut subscribed_before = false;
+    loop {
+        match redis.subscribe().await {
+            Ok(mut messages) => {
+                if subscribed_before {
+                    state.cache.clear();
+                }
+                subscribed_before = true;
+                while let Some(message) = messages.next().await {
+                    match redis.invalidation(message.get_payload_bytes()) {
+                        Some(Invalidation::Key(key)) => {
+                            state.cache.remove(&key);
+                        }
+                        Some(Invalidation::Purge(purge)) => {
+                            purge_local(&state, &purge);
+                        }
+                        None => {}
+                    }
+                }
+                syslog::error(format_args!("Redis cache invalidations interrupted"));
+            }
+            Err(e) => syslog::error(format_args!("Redis cache subscription failed: {}", e)),
+        }
+        tokio::time::sleep(Duration::from_secs(REDIS_RESUBSCRIBE_SECS)).await;
+    }
+}
+
+/// Stores `response` under `cache_key` unless it is too large or, with the
+/// cache full, loses the admission check against the oldest entry.
+fn store_cached(state: &AppState, cache_key: &str, response: Arc<CachedResponse>, expires_at: SystemTime) {
+    let limits = &state.cache_limits;
+    if response.body.len() > limits.max_object_bytes {
+        return;
+    }
+    let mut order = state.cache_order.lock().unwrap_or_else(|e| e.into_inner());
+    if !state.cache.contains_key(cache_key) {
+        // Keys evicted by a tenant cap linger here until they reach the front.
+        if order.len() >= limits.max_entries * 2 {
+            order.retain(|key| state.cache.contains_key(key));
+        }
+        let now = SystemTime::now();
+        while state.cache.len() >= limits.max_entries {
+            let Some(victim) = order.pop_front() else {
+                break;
+            };
+            let Some(expired) = state.cache.get(&victim).map(|entry| entry.expires_at <= now) else {
+                continue;
+            };
+            if !expired && limits.admission == CacheAdmission::TinyLfu {
+                let sketch = state.cache_sketch.lock().unwrap_or_else(|e| e.into_inner());
+                if !sketch.admit(cache_key, &victim) {
+                    order.push_front(victim);
+                    return;
+                }
             }
+            state.cache.remove(&victim);
+        }
+        order.push_back(cache_key.to_string());
+    }
+    state.cache.insert(cache_key.to_string(), CacheEntry { response, expires_at });
+}
+
+/// Evicts the oldest keys in `order` until a new `key` fits under `cap`,
+/// then records it as the newest.
+fn make_room<V>(map: &DashMap<String, V>, order: &mut VecDeque<String>, key: &str, cap: usize) {
+    order.retain(|existing| map.contains_key(existing));
+    while order.len() >= cap {
+        match order.pop_front() {
+            Some(oldest) => {
+                map.remove(&oldest);
+            }
+            None => break,
+        }
+    }
+    order.push_back(key.to_string());
+}
+
+/// Header a POST retry repeats to get the first response replayed.
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+/// Marks a response that was replayed rather than produced by the upstream.
+pub const IDEMPOTENT_REPLAYED_HEADER: &str = "idempotent-replayed";
+
+/// Store key for an idempotent POST through `route`: the client's key, the
+/// route, the caller and a hash of the body, so a reused key with a different
+/// payload or from another user is never answered with someone else's result.
+pub fn idempotency_key(
+    route: &Route,
+    method: &Method,
+    headers: &HeaderMap,
+    tenant: Option<&str>,
+    user: Option<&str>,
+    body: &[u8],
+) -> Option<String> {
+    route.idempotency_window?;
+    if method != Method::POST {
+        return None;
+    }
+    let key = headers.get(IDEMPOTENCY_KEY_HEADER)?.to_str().ok()?;
+    let fingerprint = hash(&[user.unwrap_or_default().as_bytes(), body]);
+    let mut stored = String::with_capacity(namespace_len(tenant) + route.name.len() + key.len() + 18);
+    push_namespace(&mut stored, tenant);
+    let _ = write!(stored, "{}:{:016x}:{}", route.name, fingerprint, key);
+    Some(stored)
+}
+
+/// What becomes of a POST carrying an `Idempotency-Key`.
+pub enum Idempotency {
+    /// The first request's response, to replay.
+    Replay(Response<Body>),
+    /// The first request is still with the upstream.
+    InFlight,
+    /// This is the first request. Retries are held off until the claim is
+    /// completed, or dropped without a response to keep.
+    Claimed(IdempotencyClaim),
+}
+
+/// The key of a request sent upstream. Dropped without `complete`, it frees
+/// the key, so a retry after a failure reaches the upstream again.
+pub struct IdempotencyClaim {
+    state: Arc<AppState>,
+    key: String,
+    window: Duration,
+}
+
+impl IdempotencyClaim {
+    /// Keeps `response` for replay during the window. Server errors are not
+    /// kept.
+    pub fn complete(self, response: Arc<CachedResponse>) {
+        if response.status.is_server_error() {
+            return;
+        }
+        if let Some(mut record) = self.state.idempotency.get_mut(&self.key) {
+            *record = IdempotencyRecord {
+                response: Some(response),
+                expires_at: SystemTime::now() + self.window,
+            };
+        }
+    }
+}
+
+impl Drop for IdempotencyClaim {
+    fn drop(&mut self) {
+        self.state.idempotency.remove_if(&self.key, |_, record| record.response.is_none());
+    }
+}
+
+/// Replays the response for `key`, or claims it for this request. An
+/// expired record is claimed over rather than replayed. Once
+/// `max_idempotency_records` are held, a new key evicts the oldest. Expired
+/// records are dropped by `evict_expired_idempotency`.
+pub fn claim_idempotency(state: &Arc<AppState>, key: &str, window: Duration) -> Idempotency {
+    let now = SystemTime::now();
+    // Held throughout, so two retries can't both find the key free.
+    let mut order = state.idempotency_order.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(record) = state.idempotency.get(key).filter(|record| record.expires_at > now) {
+        let Some(stored) = &record.response else {
+            return Idempotency::InFlight;
+        };
+        let mut response = stored.to_response();
+        response.headers_mut().insert(IDEMPOTENT_REPLAYED_HEADER, HeaderValue::from_static("true"));
+        return Idempotency::Replay(response);
+    }
+    if !state.idempotency.contains_key(key) {
+        let cap = state.cache_limits.max_idempotency_records;
+        // Keys already swept linger here until they reach the front.
+        if order.len() >= cap * 2 {
+            order.retain(|existing| state.idempotency.contains_key(existing));
+        }
+        while state.idempotency.len() >= cap {
+            let Some(oldest) = order.pop_front() else {
+                break;
+            };
+            state.idempotency.remove(&oldest);
         }
+        order.push_back(key.to_string());
+    }
+    state.idempotency.insert(key.to_string(), IdempotencyRecord { response: None, expires_at: now + window });
+    Idempotency::Claimed(IdempotencyClaim { state: state.clone(), key: key.to_string(), window })
+}
+
+/// Removes idempotency records whose window has passed. Runs with the
+/// rate-limit sweep.
+pub fn evict_expired_idempotency(state: &AppState) -> usize {
+    let now = SystemTime::now();
+    let before = state.idempotency.len();
+    state.idempotency.retain(|_, entry| entry.expires_at > now);
+    before.saturating_sub(state.idempotency.len())
+}
+
+pub fn is_authenticated(headers: &HeaderMap) -> bool {
+    authenticated_user(headers).is_some()
+}
+
+/// The user a valid bearer token belongs to.
+pub fn authenticated_user(headers: &HeaderMap) -> Option<&'static str> {
+    let token = headers
+        .get("Authorization")?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")?;
+    VALID_AUTH_TOKENS.get(token).map(String::as_str)
+}
+
+/// Cache key for a GET through `route`, or `None` if the response must not be
+/// cached. Per-user keys carry a hash of the caller's user id rather than the
+/// id itself. The query is hashed too, so however long it is the key is
+/// built in one allocation, with room left for an experiment's variant. The
+/// path and query are normalized first as `normalization` says.
+pub fn cache_key(
+    route: &Route,
+    method: &Method,
+    path: &str,
+    query: &str,
+    tenant: Option<&str>,
+    user: Option<&str>,
+    normalization: &CacheKeyConfig,
+) -> Option<String> {
+    let user = match route.cache {
+        CacheMode::Shared => None,
+        CacheMode::PerUser => Some(user?),
+        CacheMode::Disabled => return None,
+    };
+    let mut key = String::with_capacity(namespace_len(tenant) + route.name.len() + path.len() + CACHE_KEY_SLACK);
+    push_namespace(&mut key, tenant);
+    key.push_str(&route.name);
+    key.push(':');
+    if let Some(user) = user {
+        let _ = write!(key, "{:016x}:", hash(&[user.as_bytes()]));
+    }
+    key.push_str(method.as_str());
+    let path_start = key.len();
+    key.push_str(path);
+    if normalization.lowercase_path {
+        key[path_start..].make_ascii_lowercase();
+    }
+    if let Some(query) = query_hash(query, normalization) {
+        let _ = write!(key, "?{:016x}", query);
     }
-    false
-}
\ No newline at end of file
+    Some(key)
+}
+
+/// A hash of what is left of `query` once `normalization` drops the ignored
+/// parameters and sorts the rest, or `None` if nothing is.
+fn query_hash(query: &str, normalization: &CacheKeyConfig) -> Option<u64> {
+    if !normalization.normalizes_query() {
+        return (!query.is_empty()).then(|| hash(&[query.as_bytes()]));
+    }
+    let mut pairs: Vec<&str> = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .filter(|pair| !normalization.ignores(pair.split_once('=').map_or(*pair, |(name, _)| name)))
+        .collect();
+    if pairs.is_empty() {
+        return None;
+    }
+    if normalization.sort_query {
+        pairs.sort_unstable();
+    }
+    let pairs: Vec<&[u8]> = pairs.iter().map(|pair| pair.as_bytes()).collect();
+    Some(hash(&pairs))
+}
+
+/// The first 64 bits of a SHA-256 over `parts`, each prefixed with its
+/// length. Unlike `DefaultHasher` it is the same on every build and replica,
+/// so keys stored in Redis are found by all of them.
+fn hash(parts: &[&[u8]]) -> u64 {
+    let mut context = digest::Context::new(&digest::SHA256);
+    for part in parts {
+        context.update(&(part.len() as u64).to_be_bytes());
+        context.update(part);
+    }
+    let digest = context.finish();
+    let mut prefix = [0; 8];
+    prefix.copy_from_slice(&digest.as_ref()[..8]);
+    u64::from_be_bytes(prefix)
+}
+
+fn namespace_len(tenant: Option<&str>) -> usize {
+    tenant.map_or(0, |tenant| tenant.len() + 1)
+}
+
+/// Keys of a tenant's entries start with its name and `#`.
+fn push_namespace(key: &mut String, tenant: Option<&str>) {
+    if let Some(tenant) = tenant {
+        key.push_str(tenant);
+        key.push('#');
+    }
+}
+
+pub fn is_admin(headers: &HeaderMap, admin_tokens: &[String]) -> bool {
+    headers
+        .get("Authorization")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|auth_str| auth_str.strip_prefix("Bearer "))
+        .is_some_and(|token| admin_token_index(admin_tokens, token).is_some())
+}
+
+/// Admin tokens are compared through HMACs under this per-process key.
+static ADMIN_TOKEN_KEY: OnceLock<hmac::Key> = OnceLock::new();
+
+/// The position of `token` in `admin_tokens`. Every token is compared, in
+/// constant time, so response timing reveals neither how much of a token
+/// matched nor which one did.
+pub fn admin_token_index(admin_tokens: &[String], token: &str) -> Option<usize> {
+    let key = ADMIN_TOKEN_KEY.get_or_init(|| {
+        hmac::Key::generate(hmac::HMAC_SHA256, &SystemRandom::new()).expect("generating the admin token key")
+    });
+    let tag = hmac::sign(key, token.as_bytes());
+    admin_tokens.iter().enumerate().fold(None, |found, (i, expected)| {
+        let matches = hmac::verify(key, expected.as_bytes(), tag.as_ref()).is_ok();
+        found.or(matches.then_some(i))
+    })
+}
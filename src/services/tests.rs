@@ -2,29 +2,53 @@
 mod tests {
     // use super::*; I used this but it didnt work, that's why I've commented it out
     use crate::AppState;
-    use hyper::{HeaderMap, header::AUTHORIZATION};
+    use hyper::{HeaderMap, header::{AGE, AUTHORIZATION}};
     use std::time::Duration;
-    use tokio::sync::RwLock;
+    use std::sync::atomic::Ordering;
     use std::sync::Arc;
     // use crate::services::check_rate_limit;
     use crate::services::{
         RATE_LIMIT_REQUESTS, 
         RATE_LIMIT_WINDOW_SECS, 
-        StatusCode, 
-        Bytes, 
         cache_response, 
         get_cached_response, 
         SystemTime, 
         is_authenticated, 
-        check_rate_limit
+        check_rate_limit,
+        check_tenant_rate_limit,
+        rate_limit_remaining,
+        cache_tenant_response,
+        cache_key,
+        idempotency_key,
+        claim_idempotency,
+        Idempotency,
+        evict_expired_idempotency,
+        evict_idle_rate_limits,
+        bucket_limit,
+        tarpit,
+        add_strike,
+        Tarpit,
+        CachePurge,
+        take_cache_request,
+        CacheRequest,
+        CACHE_CONTROL_HEADER,
+        admin_token_index,
     };
+    use crate::auth::Identity;
+    use crate::config::{CacheAdmission, CacheConfig, CacheControlConfig, CacheKeyConfig, CacheMode, IdempotencyConfig, RouteConfig, TarpitConfig};
+    use crate::models::Penalty;
+    use crate::routes::Route;
+    use crate::tenants::Tenants;
+    use hyper::Method;
     use crate::RateLimit;
     // use crate::services::SystemTime;
-    use crate::CacheEntry;
+    use crate::{CacheEntry, CachedResponse};
+    use bytes::Bytes;
+    use hyper::StatusCode;
 
     #[tokio::test]
     async fn test_rate_limit() {
-        let state = Arc::new(RwLock::new(AppState::new()));
+        let state = Arc::new(AppState::new());
         let mut headers = HeaderMap::new();
         headers.insert("x-forwarded-for", "127.0.0.1".parse().unwrap());
 
@@ -33,8 +57,7 @@ mod tests {
 
         // Add more requests up to the limit
         {
-            let mut state = state.write().await;
-            let rate_limit = state.rate_limits.get_mut("127.0.0.1").unwrap();
+            let mut rate_limit = state.rate_limits.get_mut("ip:127.0.0.1").unwrap();
             rate_limit.count = RATE_LIMIT_REQUESTS;
         }
 
@@ -42,20 +65,31 @@ mod tests {
         assert!(!check_rate_limit(&state, &headers).await);
     }
 
+    #[tokio::test]
+    async fn test_rate_limit_remaining() {
+        let state = Arc::new(AppState::new());
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "127.0.0.1".parse().unwrap());
+        assert_eq!(rate_limit_remaining(&state, &headers, None), RATE_LIMIT_REQUESTS);
+        check_rate_limit(&state, &headers).await;
+        check_rate_limit(&state, &headers).await;
+        assert_eq!(rate_limit_remaining(&state, &headers, None), RATE_LIMIT_REQUESTS - 2);
+    }
+
     #[tokio::test]
     async fn test_rate_limit_window_reset() {
-        let state = Arc::new(RwLock::new(AppState::new()));
+        let state = Arc::new(AppState::new());
         let mut headers = HeaderMap::new();
         headers.insert("x-forwarded-for", "127.0.0.1".parse().unwrap());
 
         // Add requests at limit
         {
-            let mut state = state.write().await;
             state.rate_limits.insert(
-                "127.0.0.1".to_string(),
+                "ip:127.0.0.1".to_string(),
                 RateLimit {
                     count: RATE_LIMIT_REQUESTS,
                     window_start: SystemTime::now() - Duration::from_secs(RATE_LIMIT_WINDOW_SECS + 1),
+                    ..RateLimit::default()
                 },
             );
         }
@@ -66,7 +100,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_cache_operations() {
-        let state = Arc::new(RwLock::new(AppState::new()));
+        let state = Arc::new(AppState::new());
         let cache_key = "test_key";
         let status = StatusCode::OK;
         let headers = HeaderMap::new();
@@ -76,7 +110,7 @@ mod tests {
         cache_response(
             &state,
             cache_key,
-            (status, headers.clone(), body.clone()),
+            Arc::new(CachedResponse::new(status, headers.clone(), body.clone())),
         ).await;
 
         // Retrieve cached response
@@ -90,22 +124,53 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_cache_hits_share_the_stored_body() {
+        let state = Arc::new(AppState::new());
+        let body = Bytes::from(vec![b'x'; 4096]);
+        let mut headers = HeaderMap::new();
+        headers.insert("content-type", "text/plain".parse().unwrap());
+        cache_response(&state, "shared", Arc::new(CachedResponse::new(StatusCode::OK, headers, body.clone()))).await;
+
+        for _ in 0..2 {
+            let response = get_cached_response(&state, "shared").await.unwrap();
+            assert_eq!(response.headers().get("content-type").unwrap(), "text/plain");
+            let hit = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            assert_eq!(hit.as_ptr(), body.as_ptr(), "hits must not copy the body");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cache_hits_carry_their_age() {
+        let state = Arc::new(AppState::new());
+        let mut headers = HeaderMap::new();
+        cache_response(&state, "fresh", Arc::new(CachedResponse::new(StatusCode::OK, headers.clone(), Bytes::new()))).await;
+        let response = get_cached_response(&state, "fresh").await.unwrap();
+        assert_eq!(response.headers().get(AGE).unwrap(), "0");
+
+        // Stored 30 seconds ago, with the 5 seconds it had upstream.
+        headers.insert(AGE, "5".parse().unwrap());
+        cache_response(&state, "older", Arc::new(CachedResponse::new(StatusCode::OK, headers, Bytes::new()))).await;
+        state.cache.get_mut("older").unwrap().expires_at -= Duration::from_secs(30);
+        let response = get_cached_response(&state, "older").await.unwrap();
+        assert_eq!(response.headers().get(AGE).unwrap(), "35");
+    }
+
     #[tokio::test]
     async fn test_cache_expiration() {
-        let state = Arc::new(RwLock::new(AppState::new()));
+        let state = Arc::new(AppState::new());
         let cache_key = "test_key";
         
         // Cache a response with immediate expiration
         {
-            let mut state = state.write().await;
             state.cache.insert(
                 cache_key.to_string(),
                 CacheEntry {
-                    response_parts: (
+                    response: Arc::new(CachedResponse::new(
                         StatusCode::OK,
                         HeaderMap::new(),
                         Bytes::from("test"),
-                    ),
+                    )),
                     expires_at: SystemTime::now() - Duration::from_secs(1),
                 },
             );
@@ -129,4 +194,428 @@ mod tests {
         headers.insert(AUTHORIZATION, "Bearer example-token".parse().unwrap());
         assert!(is_authenticated(&headers));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_admin_token_index() {
+        let tokens = vec!["first".to_string(), "second".to_string()];
+        assert_eq!(admin_token_index(&tokens, "second"), Some(1));
+        assert_eq!(admin_token_index(&tokens, "secon"), None);
+        assert_eq!(admin_token_index(&tokens, "second!"), None);
+        assert_eq!(admin_token_index(&[], ""), None);
+    }
+
+    #[test]
+    fn test_cache_key_modes() {
+        let mut config = RouteConfig::default();
+        let shared = Route::from_config(0, &config).unwrap();
+        config.cache = CacheMode::PerUser;
+        let per_user = Route::from_config(0, &config).unwrap();
+        config.cache = CacheMode::Disabled;
+        let disabled = Route::from_config(0, &config).unwrap();
+
+        let anonymous = None;
+        let user = Some("example-user");
+
+        let key = |route: &Route, user: Option<&str>| cache_key(route, &Method::GET, "/api/me/orders", "", None, user, &CacheKeyConfig::default());
+        assert_eq!(key(&shared, anonymous), key(&shared, user));
+        assert!(key(&disabled, user).is_none());
+        assert!(key(&per_user, anonymous).is_none());
+
+        let user_key = key(&per_user, user).unwrap();
+        assert_ne!(Some(user_key.clone()), key(&shared, user));
+        assert_ne!(Some(user_key.clone()), key(&per_user, Some("other-user")));
+        assert!(!user_key.contains("example-user"));
+        let tenant_key = cache_key(&shared, &Method::GET, "/api/me/orders", "", Some("acme"), None, &CacheKeyConfig::default());
+        assert_ne!(tenant_key, key(&shared, anonymous));
+
+        let query_key = cache_key(&shared, &Method::GET, "/api/me/orders", "page=2", None, None, &CacheKeyConfig::default()).unwrap();
+        assert_ne!(Some(query_key.clone()), key(&shared, anonymous));
+        assert_ne!(Some(query_key.clone()), cache_key(&shared, &Method::GET, "/api/me/orders", "page=3", None, None, &CacheKeyConfig::default()));
+        assert!(!query_key.contains("page"));
+        // The hash is SHA-256 based, so every build and replica agrees on it.
+        assert!(query_key.ends_with("?9f6876f5fd9c4b5a"), "{}", query_key);
+        let purge = CachePurge { path_prefix: Some("/api/me/orders".to_string()), ..CachePurge::default() };
+        assert!(purge.matches(&query_key) && purge.matches(&user_key));
+    }
+
+    #[test]
+    fn test_cache_requests_from_trusted_callers() {
+        let config = CacheControlConfig { scope: Some("cache:control".to_string()) };
+        let identity = |scopes: &[&str]| Identity {
+            user_id: "ops".to_string(),
+            scopes: scopes.iter().map(|scope| scope.to_string()).collect(),
+            claims: Default::default(),
+        };
+        let take = |value: &str, config: Option<&CacheControlConfig>, admin: bool, identity: Option<&Identity>| {
+            let mut headers = HeaderMap::new();
+            if !value.is_empty() {
+                headers.insert(CACHE_CONTROL_HEADER, value.parse().unwrap());
+            }
+            let request = take_cache_request(&mut headers, config, admin, identity);
+            assert!(!headers.contains_key(CACHE_CONTROL_HEADER));
+            request
+        };
+
+        let scoped = identity(&["read", "cache:control"]);
+        assert_eq!(take("bypass", Some(&config), false, Some(&scoped)).unwrap(), Some(CacheRequest::Bypass));
+        assert_eq!(take("Refresh", Some(&config), false, Some(&scoped)).unwrap(), Some(CacheRequest::Refresh));
+        assert_eq!(take("", Some(&config), false, Some(&scoped)).unwrap(), Some(CacheRequest::Default));
+        assert_eq!(take("refresh", Some(&config), true, None).unwrap(), Some(CacheRequest::Refresh));
+        assert!(take("purge", Some(&config), true, None).is_err());
+
+        // Untrusted callers and gateways without the feature are served as usual.
+        assert_eq!(take("bypass", Some(&config), false, Some(&identity(&["read"]))).unwrap(), None);
+        assert_eq!(take("bypass", None, true, Some(&scoped)).unwrap(), None);
+    }
+
+    #[test]
+    fn test_cache_key_normalization() {
+        let route = Route::from_config(0, &RouteConfig::default()).unwrap();
+        let normalization = CacheKeyConfig {
+            lowercase_path: true,
+            sort_query: true,
+            ignore_query_params: vec!["utm_*".to_string(), "fbclid".to_string()],
+        };
+        let key = |path: &str, query: &str, normalization: &CacheKeyConfig| {
+            cache_key(&route, &Method::GET, path, query, None, None, normalization).unwrap()
+        };
+
+        let normalized = key("/Products/42", "color=red&size=m&utm_source=mail&fbclid=x", &normalization);
+        assert_eq!(normalized, key("/products/42", "size=m&utm_campaign=spring&color=red", &normalization));
+        assert_ne!(normalized, key("/products/42", "color=blue&size=m", &normalization));
+        assert_eq!(key("/products/42", "utm_source=mail", &normalization), key("/products/42", "", &normalization));
+        // The parameter named exactly is ignored, not others it starts.
+        assert_ne!(key("/", "fbclid_count=1", &normalization), key("/", "", &normalization));
+
+        let plain = CacheKeyConfig::default();
+        assert_ne!(key("/Products/42", "", &plain), key("/products/42", "", &plain));
+        assert_ne!(key("/", "a=1&b=2", &plain), key("/", "b=2&a=1", &plain));
+    }
+
+    #[tokio::test]
+    async fn test_idempotent_replay() {
+        let config = RouteConfig {
+            idempotency: Some(IdempotencyConfig { window_secs: 60 }),
+            ..RouteConfig::default()
+        };
+        let route = Route::from_config(0, &config).unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("idempotency-key", "order-1".parse().unwrap());
+
+        let user = Some("example-user");
+        assert!(idempotency_key(&route, &Method::GET, &headers, None, user, b"{}").is_none());
+        assert!(idempotency_key(&route, &Method::POST, &HeaderMap::new(), None, user, b"{}").is_none());
+        let key = idempotency_key(&route, &Method::POST, &headers, None, user, b"{\"qty\":1}").unwrap();
+        assert_ne!(Some(key.clone()), idempotency_key(&route, &Method::POST, &headers, None, user, b"{\"qty\":2}"));
+        assert_ne!(Some(key.clone()), idempotency_key(&route, &Method::POST, &headers, None, Some("other"), b"{\"qty\":1}"));
+
+        let state = Arc::new(AppState::new());
+        let window = Duration::from_secs(60);
+        let created = |body: &'static str| {
+            Arc::new(CachedResponse::new(StatusCode::CREATED, HeaderMap::new(), Bytes::from(body)))
+        };
+        let Idempotency::Claimed(first) = claim_idempotency(&state, &key, window) else {
+            panic!("the first request claims its key");
+        };
+        assert!(matches!(claim_idempotency(&state, &key, window), Idempotency::InFlight));
+        first.complete(created("first"));
+
+        let Idempotency::Replay(replayed) = claim_idempotency(&state, &key, window) else {
+            panic!("a retry is replayed");
+        };
+        assert_eq!(replayed.status(), StatusCode::CREATED);
+        assert_eq!(replayed.headers().get("idempotent-replayed").unwrap(), "true");
+        let body = hyper::body::to_bytes(replayed.into_body()).await.unwrap();
+        assert_eq!(body, "first");
+    }
+
+    #[tokio::test]
+    async fn test_idempotency_frees_keys_after_failures() {
+        let state = Arc::new(AppState::new());
+        let window = Duration::from_secs(60);
+        let Idempotency::Claimed(claim) = claim_idempotency(&state, "key", window) else {
+            panic!("the first request claims its key");
+        };
+        claim.complete(Arc::new(CachedResponse::new(StatusCode::BAD_GATEWAY, HeaderMap::new(), Bytes::new())));
+        assert!(state.idempotency.is_empty(), "server errors are not kept");
+
+        // A request that fails before answering drops its claim.
+        let Idempotency::Claimed(claim) = claim_idempotency(&state, "key", window) else {
+            panic!("the retry reaches the upstream");
+        };
+        drop(claim);
+        assert!(matches!(claim_idempotency(&state, "key", window), Idempotency::Claimed(_)));
+    }
+
+    #[tokio::test]
+    async fn test_idempotency_records_are_capped() {
+        let mut app_state = AppState::new();
+        app_state.set_cache_limits(&CacheConfig { max_idempotency_records: 2, ..CacheConfig::default() });
+        let state = Arc::new(app_state);
+        let window = Duration::from_secs(60);
+        for key in ["a", "b", "c"] {
+            let Idempotency::Claimed(claim) = claim_idempotency(&state, key, window) else {
+                panic!("{} is new", key);
+            };
+            claim.complete(Arc::new(CachedResponse::new(StatusCode::CREATED, HeaderMap::new(), Bytes::new())));
+        }
+        let mut keys: Vec<_> = state.idempotency.iter().map(|record| record.key().clone()).collect();
+        keys.sort();
+        assert_eq!(keys, vec!["b", "c"]);
+    }
+
+    #[tokio::test]
+    async fn test_expired_idempotency_records_are_swept() {
+        let state = Arc::new(AppState::new());
+        let store = |key: &str, body: &'static str, window: Duration| {
+            let Idempotency::Claimed(claim) = claim_idempotency(&state, key, window) else {
+                panic!("{} is free", key);
+            };
+            claim.complete(Arc::new(CachedResponse::new(StatusCode::CREATED, HeaderMap::new(), Bytes::from(body))));
+        };
+        store("old", "old", Duration::ZERO);
+        store("new", "new", Duration::from_secs(60));
+
+        // An expired record is claimed over rather than replayed.
+        store("old", "again", Duration::from_secs(60));
+        let Idempotency::Replay(replayed) = claim_idempotency(&state, "old", Duration::from_secs(60)) else {
+            panic!("old is answered again");
+        };
+        assert_eq!(hyper::body::to_bytes(replayed.into_body()).await.unwrap(), "again");
+
+        store("gone", "gone", Duration::ZERO);
+        assert_eq!(evict_expired_idempotency(&state), 1);
+        assert_eq!(state.idempotency.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_tenant_rate_limits_are_namespaced() {
+        let config = crate::config::GatewayConfig::from_json(r#"{
+            "tenancy": { "tenants": {
+                "acme": { "quota": { "requests": 2, "window_secs": 60 } },
+                "globex": { "rate_limit": { "requests": 1, "window_secs": 60 } }
+            } }
+        }"#).unwrap();
+        let tenants = Tenants::from_config(&config.tenancy);
+        let (acme, globex) = (tenants.get("acme").unwrap(), tenants.get("globex").unwrap());
+        let state = Arc::new(AppState::new());
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "10.0.0.1".parse().unwrap());
+
+        assert!(check_tenant_rate_limit(&state, &headers, globex).await);
+        assert!(!check_tenant_rate_limit(&state, &headers, globex).await);
+        assert!(check_rate_limit(&state, &headers).await);
+
+        assert!(check_tenant_rate_limit(&state, &headers, acme).await);
+        headers.insert("x-forwarded-for", "10.0.0.2".parse().unwrap());
+        assert!(check_tenant_rate_limit(&state, &headers, acme).await);
+        assert!(!check_tenant_rate_limit(&state, &headers, acme).await, "quota spans all clients");
+    }
+
+    #[tokio::test]
+    async fn test_client_address_cannot_name_a_tenant_bucket() {
+        let config = crate::config::GatewayConfig::from_json(r#"{
+            "tenancy": { "tenants": { "acme": { "quota": { "requests": 2, "window_secs": 60 } } } }
+        }"#).unwrap();
+        let tenants = Tenants::from_config(&config.tenancy);
+        let acme = tenants.get("acme").unwrap();
+        let state = Arc::new(AppState::new());
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "acme#quota".parse().unwrap());
+
+        for _ in 0..3 {
+            assert!(check_rate_limit(&state, &headers).await);
+        }
+        assert!(!state.rate_limits.contains_key("acme#quota"));
+        assert_eq!(bucket_limit("ip:acme#quota", &tenants).requests, RATE_LIMIT_REQUESTS);
+
+        headers.insert("x-forwarded-for", "10.0.0.1".parse().unwrap());
+        assert!(check_tenant_rate_limit(&state, &headers, acme).await);
+        assert_eq!(state.rate_limits.get("acme#quota").unwrap().count, 1);
+    }
+
+    #[tokio::test]
+    async fn test_tenant_capacity_caps() {
+        let config = crate::config::GatewayConfig::from_json(r#"{
+            "tenancy": { "tenants": {
+                "noisy": { "max_cache_entries": 2, "max_rate_limit_buckets": 2 },
+                "quiet": {}
+            } }
+        }"#).unwrap();
+        let tenants = Tenants::from_config(&config.tenancy);
+        let (noisy, quiet) = (tenants.get("noisy").unwrap(), tenants.get("quiet").unwrap());
+        let state = Arc::new(AppState::new());
+        let parts = || Arc::new(CachedResponse::new(StatusCode::OK, HeaderMap::new(), Bytes::from("x")));
+
+        cache_tenant_response(&state, quiet, "quiet#a", parts()).await;
+        for key in ["noisy#1", "noisy#2", "noisy#3", "noisy#3"] {
+            cache_tenant_response(&state, noisy, key, parts()).await;
+        }
+        let mut keys: Vec<_> = state.cache.iter().map(|entry| entry.key().clone()).collect();
+        keys.sort();
+        assert_eq!(keys, vec!["noisy#2", "noisy#3", "quiet#a"]);
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "10.0.0.1".parse().unwrap());
+        assert!(check_tenant_rate_limit(&state, &headers, quiet).await);
+        for ip in ["10.0.0.1", "10.0.0.2", "10.0.0.3"] {
+            headers.insert("x-forwarded-for", ip.parse().unwrap());
+            assert!(check_tenant_rate_limit(&state, &headers, noisy).await);
+        }
+        let mut buckets: Vec<_> = state.rate_limits.iter().map(|bucket| bucket.key().clone()).collect();
+        buckets.sort();
+        assert_eq!(buckets, vec!["noisy#ip:10.0.0.2", "noisy#ip:10.0.0.3", "quiet#ip:10.0.0.1"]);
+    }
+
+    #[tokio::test]
+    async fn test_cache_admission() {
+        let mut app_state = AppState::new();
+        app_state.set_cache_limits(&CacheConfig { max_entries: 2, max_object_bytes: 4, admission: CacheAdmission::TinyLfu, ..CacheConfig::default() });
+        let state = Arc::new(app_state);
+        let body = |body: &'static str| Arc::new(CachedResponse::new(StatusCode::OK, HeaderMap::new(), Bytes::from(body)));
+        let keys = || {
+            let mut keys: Vec<_> = state.cache.iter().map(|entry| entry.key().clone()).collect();
+            keys.sort();
+            keys
+        };
+
+        cache_response(&state, "/large", body("large")).await;
+        assert!(state.cache.is_empty());
+
+        // Each lookup is a miss followed by a store.
+        for key in ["/a", "/b", "/a", "/b", "/once"] {
+            if get_cached_response(&state, key).await.is_none() {
+                cache_response(&state, key, body("x")).await;
+            }
+        }
+        assert_eq!(keys(), vec!["/a", "/b"]);
+
+        // Asked for more often than the oldest entry, `/c` replaces it.
+        for _ in 0..3 {
+            get_cached_response(&state, "/c").await;
+        }
+        cache_response(&state, "/c", body("x")).await;
+        assert_eq!(keys(), vec!["/b", "/c"]);
+
+        // An expired entry makes room for anything.
+        state.cache.get_mut("/b").unwrap().expires_at = SystemTime::now() - Duration::from_secs(1);
+        cache_response(&state, "/new", body("x")).await;
+        assert_eq!(keys(), vec!["/c", "/new"]);
+    }
+
+    #[test]
+    fn test_cache_purge_matches() {
+        let purge = |route: Option<&str>, tenant: Option<&str>, path_prefix: Option<&str>| CachePurge {
+            route: route.map(String::from),
+            tenant: tenant.map(String::from),
+            path_prefix: path_prefix.map(String::from),
+        };
+        let keys = ["acme#orders:GET/orders/1?x=1", "orders:00000000000000ff:GET/orders/2", "users:GET/users"];
+        let matching = |purge: CachePurge| keys.iter().filter(|key| purge.matches(key)).count();
+        assert_eq!(matching(purge(None, None, None)), 3);
+        assert_eq!(matching(purge(Some("orders"), None, None)), 2);
+        assert_eq!(matching(purge(Some("orders"), Some("acme"), None)), 1);
+        assert_eq!(matching(purge(None, None, Some("/orders/2"))), 1);
+        assert_eq!(matching(purge(Some("users"), None, Some("/orders"))), 0);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_evicts_least_recently_used() {
+        let mut app_state = AppState::new();
+        app_state.max_rate_limit_buckets = 3;
+        let state = Arc::new(app_state);
+        let mut headers = HeaderMap::new();
+        for ip in ["10.0.0.1", "10.0.0.2", "10.0.0.3"] {
+            headers.insert("x-forwarded-for", ip.parse().unwrap());
+            assert!(check_rate_limit(&state, &headers).await);
+        }
+        state.rate_limits.get_mut("ip:10.0.0.2").unwrap().last_seen -= Duration::from_secs(10);
+
+        headers.insert("x-forwarded-for", "10.0.0.4".parse().unwrap());
+        assert!(check_rate_limit(&state, &headers).await);
+        let mut buckets: Vec<_> = state.rate_limits.iter().map(|bucket| bucket.key().clone()).collect();
+        buckets.sort();
+        assert_eq!(buckets, vec!["ip:10.0.0.1", "ip:10.0.0.3", "ip:10.0.0.4"]);
+        assert_eq!(state.rate_limit_evictions.capacity.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_idle_rate_limits_are_swept() {
+        let state = Arc::new(AppState::new());
+        {
+            state.rate_limits.insert("ip:10.0.0.1".to_string(), RateLimit::default());
+            state.rate_limits.insert(
+                "ip:10.0.0.2".to_string(),
+                RateLimit {
+                    count: 1,
+                    window_start: SystemTime::now() - Duration::from_secs(RATE_LIMIT_WINDOW_SECS),
+                    ..RateLimit::default()
+                },
+            );
+        }
+
+        assert_eq!(evict_idle_rate_limits(&state, &Tenants::default()).await, 1);
+        assert!(state.rate_limits.contains_key("ip:10.0.0.1"));
+        assert_eq!(state.rate_limit_evictions.idle.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_tarpit_escalates_with_strikes() {
+        let mut state = AppState::new();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "10.0.0.9".parse().unwrap());
+        add_strike(&state, &headers);
+        assert!(state.penalties.is_empty(), "strikes are only kept with tarpitting on");
+
+        state.tarpit = Some(TarpitConfig {
+            soft_threshold: 2,
+            hard_threshold: 5,
+            base_delay_ms: 100,
+            max_delay_ms: 300,
+            reset_secs: 60,
+        });
+        let mut seen = Vec::new();
+        for _ in 0..5 {
+            add_strike(&state, &headers);
+            seen.push(tarpit(&state, &headers));
+        }
+        assert_eq!(
+            seen,
+            vec![
+                Tarpit::Pass,
+                Tarpit::Delay(Duration::from_millis(100)),
+                Tarpit::Delay(Duration::from_millis(200)),
+                Tarpit::Delay(Duration::from_millis(300)),
+                Tarpit::Reject(Duration::from_millis(300)),
+            ]
+        );
+
+        let mut other = HeaderMap::new();
+        other.insert("x-forwarded-for", "10.0.0.10".parse().unwrap());
+        assert_eq!(tarpit(&state, &other), Tarpit::Pass);
+    }
+
+    #[tokio::test]
+    async fn test_tarpit_strikes_expire() {
+        let mut state = AppState::new();
+        state.tarpit = Some(TarpitConfig { soft_threshold: 1, reset_secs: 60, ..TarpitConfig::default() });
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "10.0.0.9".parse().unwrap());
+        state.penalties.insert(
+            "10.0.0.9".to_string(),
+            Penalty { strikes: 10, last_strike: SystemTime::now() - Duration::from_secs(60) },
+        );
+        assert_eq!(tarpit(&state, &headers), Tarpit::Pass);
+
+        add_strike(&state, &headers);
+        assert_eq!(state.penalties.get("10.0.0.9").unwrap().strikes, 1);
+
+        state.penalties.insert(
+            "10.0.0.8".to_string(),
+            Penalty { strikes: 1, last_strike: SystemTime::now() - Duration::from_secs(61) },
+        );
+        evict_idle_rate_limits(&state, &Tenants::default()).await;
+        assert!(state.penalties.contains_key("10.0.0.9"));
+        assert!(!state.penalties.contains_key("10.0.0.8"));
+    }
+}
@@ -0,0 +1,47 @@
+#[cfg(test)]
+mod tests {
+    use hyper::{HeaderMap, Method};
+    use jsonwebtoken::{Algorithm, DecodingKey};
+    use crate::config::SigningConfig;
+    use crate::signing::{signed_payload, RequestSigner, SIGNATURE_HEADER};
+
+    fn config(header: &str) -> SigningConfig {
+        SigningConfig { secret: "shared-secret".to_string(), header: header.to_string() }
+    }
+
+    fn parse(value: &str) -> (u64, &str) {
+        let (timestamp, signature) = value.split_once(',').unwrap();
+        (
+            timestamp.strip_prefix("t=").unwrap().parse().unwrap(),
+            signature.strip_prefix("v1=").unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_signature_verifies_with_shared_secret() {
+        let signer = RequestSigner::new(&config(SIGNATURE_HEADER));
+        let mut headers = HeaderMap::new();
+        headers.insert(SIGNATURE_HEADER, "t=1,v1=forged".parse().unwrap());
+        signer.sign(&mut headers, &Method::POST, "/orders?id=7", b"{\"qty\":1}").unwrap();
+
+        let (timestamp, signature) = parse(headers[SIGNATURE_HEADER].to_str().unwrap());
+        assert_ne!(signature, "forged");
+        let key = DecodingKey::from_secret(b"shared-secret");
+        let payload = signed_payload(timestamp, &Method::POST, "/orders?id=7", b"{\"qty\":1}");
+        assert!(jsonwebtoken::crypto::verify(signature, &payload, &key, Algorithm::HS256).unwrap());
+
+        let tampered = signed_payload(timestamp, &Method::POST, "/orders?id=8", b"{\"qty\":1}");
+        assert!(!jsonwebtoken::crypto::verify(signature, &tampered, &key, Algorithm::HS256).unwrap());
+        let other_key = DecodingKey::from_secret(b"other-secret");
+        assert!(!jsonwebtoken::crypto::verify(signature, &payload, &other_key, Algorithm::HS256).unwrap());
+    }
+
+    #[test]
+    fn test_custom_header() {
+        let signer = RequestSigner::new(&config("X-Signature"));
+        let mut headers = HeaderMap::new();
+        signer.sign(&mut headers, &Method::GET, "/", b"").unwrap();
+        assert!(headers.contains_key("x-signature"));
+        assert!(!headers.contains_key(SIGNATURE_HEADER));
+    }
+}
@@ -0,0 +1,194 @@
+use hyper::HeaderMap;
+use hyper::header::CONTENT_TYPE;
+use crate::config::SoapMatchConfig;
+
+#[cfg(test)]
+mod tests;
+
+pub const SOAP_ACTION_HEADER: &str = "soapaction";
+/// How much of a body is searched for the operation. Envelopes are forwarded
+/// as they are, so only their start is ever looked at.
+pub const MAX_SCAN_BYTES: usize = 64 * 1024;
+
+/// The `match_soap` predicate of a route.
+#[derive(Debug, Clone)]
+pub struct SoapMatch {
+    action: Option<String>,
+    operation: Option<String>,
+}
+
+impl SoapMatch {
+    pub fn new(config: &SoapMatchConfig) -> Self {
+        Self { action: config.action.clone(), operation: config.operation.clone() }
+    }
+
+    /// How many of action and operation are set, for route specificity.
+    pub fn predicates(&self) -> usize {
+        usize::from(self.action.is_some()) + usize::from(self.operation.is_some())
+    }
+
+    /// Whether an XML request has the configured action and operation. A
+    /// body of `None` hasn't been read yet and is assumed to match, so the
+    /// route can be picked from the headers alone.
+    pub fn matches(&self, headers: &HeaderMap, body: Option<&[u8]>) -> bool {
+        if !is_xml(headers) {
+            return false;
+        }
+        if let Some(action) = &self.action {
+            if soap_action(headers).as_deref() != Some(action.as_str()) {
+                return false;
+            }
+        }
+        match (&self.operation, body) {
+            (Some(expected), Some(body)) => operation(body).as_deref() == Some(expected.as_str()),
+            _ => true,
+        }
+    }
+}
+
+/// `text/xml`, `application/xml` and any `+xml` type such as SOAP 1.2's
+/// `application/soap+xml`, whatever their parameters.
+pub fn is_xml(headers: &HeaderMap) -> bool {
+    let Some(content_type) = headers.get(CONTENT_TYPE).and_then(|value| value.to_str().ok()) else {
+        return false;
+    };
+    let essence = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    matches!(essence.as_str(), "text/xml" | "application/xml") || essence.ends_with("+xml")
+}
+
+/// The SOAP 1.1 `SOAPAction` header, or SOAP 1.2's `action` parameter of
+/// the `Content-Type`, without quotes.
+pub fn soap_action(headers: &HeaderMap) -> Option<String> {
+    let unquote = |value: &str| value.trim().trim_matches('"').to_string();
+    if let Some(action) = headers.get(SOAP_ACTION_HEADER).and_then(|value| value.to_str().ok()) {
+        return Some(unquote(action));
+    }
+    let content_type = headers.get(CONTENT_TYPE)?.to_str().ok()?;
+    content_type.split(';').skip(1).find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        name.trim().eq_ignore_ascii_case("action").then(|| unquote(value))
+    })
+}
+
+/// The local name of the first element inside a SOAP envelope's `Body`, or
+/// of the root element if the body isn't an envelope. `None` if it doesn't
+/// appear within the first `MAX_SCAN_BYTES`, or the `Body` is empty.
+pub fn operation(body: &[u8]) -> Option<String> {
+    let mut tags = Tags { input: &body[..body.len().min(MAX_SCAN_BYTES)], pos: 0 };
+    let root = tags.next_tag()?;
+    if root.kind == TagKind::End {
+        return None;
+    }
+    if root.local_name() != b"Envelope" {
+        return root.operation();
+    }
+    if root.kind == TagKind::Empty {
+        return None;
+    }
+
+    let mut depth = 1;
+    while let Some(tag) = tags.next_tag() {
+        match tag.kind {
+            TagKind::Start if depth == 1 && tag.local_name() == b"Body" => {
+                let first = tags.next_tag()?;
+                return if first.kind == TagKind::End { None } else { first.operation() };
+            }
+            TagKind::Start => depth += 1,
+            TagKind::Empty if depth == 1 && tag.local_name() == b"Body" => return None,
+            TagKind::Empty => {}
+            TagKind::End => {
+                depth -= 1;
+                if depth == 0 {
+                    return None;
+                }
+            }
+        }
+    }
+    None
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TagKind {
+    Start,
+    End,
+    Empty,
+}
+
+struct Tag<'a> {
+    name: &'a [u8],
+    kind: TagKind,
+}
+
+impl Tag<'_> {
+    /// The name without its namespace prefix.
+    fn local_name(&self) -> &[u8] {
+        self.name.rsplit(|&b| b == b':').next().unwrap_or(self.name)
+    }
+
+    fn operation(&self) -> Option<String> {
+        String::from_utf8(self.local_name().to_vec()).ok().filter(|name| !name.is_empty())
+    }
+}
+
+/// Element tags in document order, skipping text, comments, CDATA,
+/// processing instructions and declarations. Stops at the first tag that
+/// runs past the end of the input.
+struct Tags<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Tags<'a> {
+    fn skip_past(&mut self, end: &[u8]) -> Option<()> {
+        let at = self.input[self.pos..].windows(end.len()).position(|window| window == end)?;
+        self.pos += at + end.len();
+        Some(())
+    }
+
+    fn next_tag(&mut self) -> Option<Tag<'a>> {
+        loop {
+            self.pos += self.input[self.pos..].iter().position(|&b| b == b'<')?;
+            let rest = &self.input[self.pos..];
+            if rest.starts_with(b"<!--") {
+                self.skip_past(b"-->")?;
+            } else if rest.starts_with(b"<![CDATA[") {
+                self.skip_past(b"]]>")?;
+            } else if rest.starts_with(b"<?") {
+                self.skip_past(b"?>")?;
+            } else if rest.starts_with(b"<!") {
+                self.skip_past(b">")?;
+            } else {
+                return self.tag();
+            }
+        }
+    }
+
+    /// Reads the tag at `pos`, allowing `>` inside quoted attribute values.
+    fn tag(&mut self) -> Option<Tag<'a>> {
+        let start = self.pos + 1;
+        let mut quote = None;
+        let mut end = start;
+        loop {
+            let byte = *self.input.get(end)?;
+            match (quote, byte) {
+                (None, b'>') => break,
+                (None, b'"' | b'\'') => quote = Some(byte),
+                (Some(q), _) if q == byte => quote = None,
+                _ => {}
+            }
+            end += 1;
+        }
+        self.pos = end + 1;
+
+        let inner = &self.input[start..end];
+        let (inner, kind) = if let Some(name) = inner.strip_prefix(b"/") {
+            (name, TagKind::End)
+        } else if let Some(inner) = inner.strip_suffix(b"/") {
+            (inner, TagKind::Empty)
+        } else {
+            (inner, TagKind::Start)
+        };
+        let name_len = inner.iter().position(|b| b.is_ascii_whitespace() || *b == b'/').unwrap_or(inner.len());
+        Some(Tag { name: &inner[..name_len], kind })
+    }
+}
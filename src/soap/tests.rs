@@ -0,0 +1,74 @@
+#[cfg(test)]
+mod tests {
+    use hyper::HeaderMap;
+    use crate::config::SoapMatchConfig;
+    use crate::soap::{is_xml, operation, soap_action, SoapMatch, MAX_SCAN_BYTES};
+
+    const ENVELOPE: &str = r#"<?xml version="1.0"?>
+        <!-- order service -->
+        <soap:Envelope xmlns:soap="http://schemas.xmlsoap.org/soap/envelope/" xmlns:m="urn:orders">
+          <soap:Header><m:Auth note="a > b"><m:Body/></m:Auth></soap:Header>
+          <soap:Body><m:GetOrder><m:Id>7</m:Id></m:GetOrder></soap:Body>
+        </soap:Envelope>"#;
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn test_operation_is_the_first_body_element() {
+        assert_eq!(operation(ENVELOPE.as_bytes()).as_deref(), Some("GetOrder"));
+        assert_eq!(operation(b"<Envelope><Body><![CDATA[<x>]]><Ping/></Body></Envelope>").as_deref(), Some("Ping"));
+        assert_eq!(operation(b"<s:Envelope xmlns:s=\"x\"><s:Body/></s:Envelope>"), None);
+        assert_eq!(operation(b"<s:Envelope><s:Body>  </s:Body></s:Envelope>"), None);
+        assert_eq!(operation(b"<?xml version=\"1.0\"?><ns:Invoice id='1'/>").as_deref(), Some("Invoice"));
+        assert_eq!(operation(b"not xml"), None);
+        assert_eq!(operation(b"<Envelope><Header>"), None);
+    }
+
+    #[test]
+    fn test_operation_only_scans_the_start_of_large_bodies() {
+        let padding = "<pad/>".repeat(MAX_SCAN_BYTES / 6);
+        let late = format!("<Envelope><Header>{}</Header><Body><Late/></Body></Envelope>", padding);
+        assert_eq!(operation(late.as_bytes()), None);
+
+        let early = format!("<Envelope><Body><Early>{}</Early></Body></Envelope>", padding.repeat(4));
+        assert_eq!(operation(early.as_bytes()).as_deref(), Some("Early"));
+    }
+
+    #[test]
+    fn test_soap_action_and_xml_content_types() {
+        let soap11 = headers(&[("content-type", "text/xml; charset=utf-8"), ("soapaction", "\"urn:orders/GetOrder\"")]);
+        assert!(is_xml(&soap11));
+        assert_eq!(soap_action(&soap11).as_deref(), Some("urn:orders/GetOrder"));
+
+        let soap12 = headers(&[("content-type", "application/soap+xml; charset=utf-8; action=\"urn:orders/Cancel\"")]);
+        assert!(is_xml(&soap12));
+        assert_eq!(soap_action(&soap12).as_deref(), Some("urn:orders/Cancel"));
+
+        assert!(!is_xml(&headers(&[("content-type", "application/json")])));
+        assert!(!is_xml(&HeaderMap::new()));
+        assert_eq!(soap_action(&headers(&[("content-type", "text/xml")])), None);
+    }
+
+    #[test]
+    fn test_matches_action_and_operation() {
+        let soap = SoapMatch::new(&SoapMatchConfig {
+            action: Some("urn:orders/GetOrder".to_string()),
+            operation: Some("GetOrder".to_string()),
+        });
+        let request = headers(&[("content-type", "text/xml"), ("soapaction", "urn:orders/GetOrder")]);
+        assert!(soap.matches(&request, Some(ENVELOPE.as_bytes())));
+        assert!(soap.matches(&request, None));
+        assert!(!soap.matches(&request, Some(b"<Envelope><Body><Cancel/></Body></Envelope>")));
+
+        let wrong_action = headers(&[("content-type", "text/xml"), ("soapaction", "urn:orders/Cancel")]);
+        assert!(!soap.matches(&wrong_action, Some(ENVELOPE.as_bytes())));
+        let json = headers(&[("content-type", "application/json"), ("soapaction", "urn:orders/GetOrder")]);
+        assert!(!soap.matches(&json, Some(ENVELOPE.as_bytes())));
+    }
+}
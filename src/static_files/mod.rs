@@ -0,0 +1,168 @@
+use std::path::{Component, Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use bytes::Bytes;
+use hyper::{Body, HeaderMap, Method, Response, StatusCode};
+use hyper::header::{
+    HeaderValue, ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG,
+    IF_RANGE, LAST_MODIFIED, RANGE,
+};
+use percent_encoding::percent_decode_str;
+use crate::config::StaticFilesConfig;
+use crate::errors::GatewayError;
+use crate::middleware::if_none_match;
+
+#[cfg(test)]
+mod tests;
+
+/// Serves `rel_path` from the configured directory, answering conditional and
+/// single-range requests. Multi-range requests get the whole file.
+pub async fn serve(
+    config: &StaticFilesConfig,
+    rel_path: &str,
+    method: &Method,
+    headers: &HeaderMap,
+) -> Result<Response<Body>, GatewayError> {
+    if method != Method::GET && method != Method::HEAD {
+        return Err(GatewayError::MethodNotAllowed(vec!["GET".to_string(), "HEAD".to_string()]));
+    }
+
+    let path = resolve(config, rel_path).await.ok_or(GatewayError::NotFound)?;
+    let metadata = tokio::fs::metadata(&path)
+        .await
+        .map_err(|_| GatewayError::NotFound)?;
+    let modified = metadata.modified().ok();
+    let modified_nanos = modified
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_nanos());
+    let etag = etag(metadata.len(), modified_nanos);
+
+    let mut response = Response::new(Body::empty());
+    let response_headers = response.headers_mut();
+    if let Ok(value) = HeaderValue::from_str(&etag) {
+        response_headers.insert(ETAG, value);
+    }
+    if let Some(modified) = modified {
+        if let Ok(value) = HeaderValue::from_str(&httpdate::fmt_http_date(modified)) {
+            response_headers.insert(LAST_MODIFIED, value);
+        }
+    }
+    response_headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+
+    if if_none_match(headers, &etag) {
+        *response.status_mut() = StatusCode::NOT_MODIFIED;
+        return Ok(response);
+    }
+
+    let content_type = mime_guess::from_path(&path).first_or_octet_stream();
+    if let Ok(value) = HeaderValue::from_str(content_type.as_ref()) {
+        response.headers_mut().insert(CONTENT_TYPE, value);
+    }
+
+    let contents = Bytes::from(
+        tokio::fs::read(&path)
+            .await
+            .map_err(|e| GatewayError::Http(format!("reading {}: {}", path.display(), e)))?,
+    );
+    let len = contents.len() as u64;
+
+    // A stale If-Range validator means the client gets the whole, new file.
+    let if_range_ok = match headers.get(IF_RANGE) {
+        Some(value) => value.to_str().is_ok_and(|v| v == etag),
+        None => true,
+    };
+    let range = headers
+        .get(RANGE)
+        .and_then(|value| value.to_str().ok())
+        .filter(|_| if_range_ok)
+        .map(|value| parse_range(value, len));
+
+    let body = match range {
+        Some(ByteRange::Unsatisfiable) => {
+            *response.status_mut() = StatusCode::RANGE_NOT_SATISFIABLE;
+            if let Ok(value) = HeaderValue::from_str(&format!("bytes */{}", len)) {
+                response.headers_mut().insert(CONTENT_RANGE, value);
+            }
+            return Ok(response);
+        }
+        Some(ByteRange::Satisfiable(start, end)) => {
+            *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+            if let Ok(value) = HeaderValue::from_str(&format!("bytes {}-{}/{}", start, end, len)) {
+                response.headers_mut().insert(CONTENT_RANGE, value);
+            }
+            contents.slice(start as usize..=end as usize)
+        }
+        Some(ByteRange::Ignored) | None => contents,
+    };
+
+    response.headers_mut().insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+    if method == Method::GET {
+        *response.body_mut() = Body::from(body);
+    }
+    Ok(response)
+}
+
+/// Maps a request path onto a file below the root, refusing anything that
+/// would escape it. Directories resolve to their index file.
+async fn resolve(config: &StaticFilesConfig, rel_path: &str) -> Option<PathBuf> {
+    let decoded = percent_decode_str(rel_path).decode_utf8().ok()?;
+    let relative = Path::new(decoded.trim_start_matches('/'));
+    if decoded.contains('\0') || decoded.contains('\\')
+        || relative.components().any(|c| !matches!(c, Component::Normal(_)))
+    {
+        return None;
+    }
+
+    let mut path = config.root.join(relative);
+    if tokio::fs::metadata(&path).await.map(|m| m.is_dir()).unwrap_or(false) {
+        path.push(&config.index);
+    }
+    if tokio::fs::metadata(&path).await.map(|m| m.is_file()).unwrap_or(false) {
+        return Some(path);
+    }
+
+    let fallback = config.root.join(config.fallback.as_ref()?);
+    tokio::fs::metadata(&fallback).await.ok()?.is_file().then_some(fallback)
+}
+
+fn etag(len: u64, modified_nanos: Option<u128>) -> String {
+    format!("\"{:x}-{:x}\"", len, modified_nanos.unwrap_or(0))
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum ByteRange {
+    /// Inclusive start and end offsets.
+    Satisfiable(u64, u64),
+    Unsatisfiable,
+    /// Malformed or multi-range headers, which are answered with the full body.
+    Ignored,
+}
+
+fn parse_range(header: &str, len: u64) -> ByteRange {
+    let Some(spec) = header.trim().strip_prefix("bytes=") else {
+        return ByteRange::Ignored;
+    };
+    if spec.contains(',') {
+        return ByteRange::Ignored;
+    }
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return ByteRange::Ignored;
+    };
+
+    let (start, end) = match (start.parse::<u64>(), end.parse::<u64>()) {
+        (Ok(start), Ok(end)) if start <= end => (start, end.min(len.saturating_sub(1))),
+        (Ok(start), Err(_)) if end.is_empty() => (start, len.saturating_sub(1)),
+        (Err(_), Ok(suffix)) if start.is_empty() => {
+            if suffix == 0 {
+                return ByteRange::Unsatisfiable;
+            }
+            (len.saturating_sub(suffix), len.saturating_sub(1))
+        }
+        _ => return ByteRange::Ignored,
+    };
+
+    if len == 0 || start >= len {
+        ByteRange::Unsatisfiable
+    } else {
+        ByteRange::Satisfiable(start, end)
+    }
+}
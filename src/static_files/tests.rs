@@ -0,0 +1,100 @@
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use hyper::{HeaderMap, Method, StatusCode};
+    use crate::config::StaticFilesConfig;
+    use crate::static_files::serve;
+    use crate::GatewayError;
+
+    fn site(name: &str, fallback: Option<&str>) -> StaticFilesConfig {
+        let root: PathBuf = std::env::temp_dir().join(format!("api-gateway-static-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(root.join("assets")).unwrap();
+        std::fs::write(root.join("index.html"), "<html>home</html>").unwrap();
+        std::fs::write(root.join("assets/app.js"), "console.log('0123456789');").unwrap();
+        StaticFilesConfig {
+            root,
+            index: "index.html".to_string(),
+            fallback: fallback.map(str::to_string),
+        }
+    }
+
+    async fn body(response: hyper::Response<hyper::Body>) -> String {
+        String::from_utf8(hyper::body::to_bytes(response.into_body()).await.unwrap().to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_serves_file_and_index() {
+        let config = site("index", None);
+
+        let response = serve(&config, "/assets/app.js", &Method::GET, &HeaderMap::new()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], "text/javascript");
+        assert!(response.headers().contains_key("etag"));
+        assert!(response.headers().contains_key("last-modified"));
+
+        let response = serve(&config, "/", &Method::GET, &HeaderMap::new()).await.unwrap();
+        assert_eq!(body(response).await, "<html>home</html>");
+    }
+
+    #[tokio::test]
+    async fn test_conditional_request() {
+        let config = site("etag", None);
+        let response = serve(&config, "/assets/app.js", &Method::GET, &HeaderMap::new()).await.unwrap();
+        let etag = response.headers()["etag"].clone();
+
+        let mut headers = HeaderMap::new();
+        headers.insert("if-none-match", etag);
+        let response = serve(&config, "/assets/app.js", &Method::GET, &headers).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    }
+
+    #[tokio::test]
+    async fn test_range_requests() {
+        let config = site("range", None);
+
+        let mut headers = HeaderMap::new();
+        headers.insert("range", "bytes=0-6".parse().unwrap());
+        let response = serve(&config, "/assets/app.js", &Method::GET, &headers).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()["content-range"], "bytes 0-6/26");
+        assert_eq!(body(response).await, "console");
+
+        headers.insert("range", "bytes=-3".parse().unwrap());
+        let response = serve(&config, "/assets/app.js", &Method::GET, &headers).await.unwrap();
+        assert_eq!(body(response).await, "');");
+
+        headers.insert("range", "bytes=100-".parse().unwrap());
+        let response = serve(&config, "/assets/app.js", &Method::GET, &headers).await.unwrap();
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.headers()["content-range"], "bytes */26");
+    }
+
+    #[tokio::test]
+    async fn test_traversal_and_missing_files() {
+        let config = site("traversal", None);
+
+        for path in ["/../index.html", "/assets/%2e%2e/%2e%2e/etc/passwd", "/missing.js"] {
+            match serve(&config, path, &Method::GET, &HeaderMap::new()).await {
+                Err(GatewayError::NotFound) => {}
+                other => panic!("expected NotFound for {}, got {:?}", path, other.map(|r| r.status())),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_spa_fallback_and_methods() {
+        let config = site("fallback", Some("index.html"));
+
+        let response = serve(&config, "/dashboard/settings", &Method::GET, &HeaderMap::new()).await.unwrap();
+        assert_eq!(body(response).await, "<html>home</html>");
+
+        let response = serve(&config, "/", &Method::HEAD, &HeaderMap::new()).await.unwrap();
+        assert_eq!(response.headers()["content-length"], "17");
+        assert_eq!(body(response).await, "");
+
+        assert!(matches!(
+            serve(&config, "/", &Method::POST, &HeaderMap::new()).await,
+            Err(GatewayError::MethodNotAllowed(_))
+        ));
+    }
+}
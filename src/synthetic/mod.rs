@@ -0,0 +1,149 @@
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::future::poll_fn;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use hyper::service::Service;
+use hyper::{Body, Request, Response};
+use tokio::time::timeout;
+use crate::config::SyntheticCheckConfig;
+use crate::metrics::escape;
+use crate::syslog;
+
+#[cfg(test)]
+mod tests;
+
+/// Names the check on every synthetic request, so upstreams and logs can
+/// tell it from client traffic.
+pub const SYNTHETIC_HEADER: &str = "x-gateway-synthetic";
+pub const SYNTHETIC_SUCCESS: &str = "gateway_synthetic_check_success";
+pub const SYNTHETIC_DURATION: &str = "gateway_synthetic_check_duration_seconds";
+pub const SYNTHETIC_RUNS: &str = "gateway_synthetic_checks_total";
+
+#[derive(Default)]
+struct Outcomes {
+    passed: AtomicBool,
+    duration_micros: AtomicU64,
+    successes: AtomicU64,
+    failures: AtomicU64,
+}
+
+/// Requests the gateway sends to itself on a schedule. They go through the
+/// same filters as client requests, so auth, routing, the upstream and
+/// every policy in between are exercised; only the listener is skipped.
+pub struct SyntheticChecks {
+    checks: Vec<(SyntheticCheckConfig, Outcomes)>,
+}
+
+impl SyntheticChecks {
+    pub fn new(checks: &[SyntheticCheckConfig]) -> Self {
+        Self { checks: checks.iter().map(|check| (check.clone(), Outcomes::default())).collect() }
+    }
+
+    /// Runs every check every `interval_secs` through `pipeline`, the
+    /// gateway's filters as a service.
+    pub fn start<S>(self: &Arc<Self>, pipeline: S)
+    where
+        S: Service<Request<Body>, Response = Response<Body>, Error = Infallible> + Clone + Send + 'static,
+        S::Future: Send,
+    {
+        for index in 0..self.checks.len() {
+            let (checks, mut pipeline) = (self.clone(), pipeline.clone());
+            let mut schedule = tokio::time::interval(Duration::from_secs(self.checks[index].0.interval_secs));
+            tokio::spawn(async move {
+                loop {
+                    schedule.tick().await;
+                    let _ = checks.run(index, &mut pipeline).await;
+                }
+            });
+        }
+    }
+
+    /// Runs the check at `index` once and records the outcome. A check that
+    /// starts failing is logged, and so is one that recovers.
+    pub async fn run<S>(&self, index: usize, pipeline: &mut S) -> Result<(), String>
+    where
+        S: Service<Request<Body>, Response = Response<Body>, Error = Infallible>,
+    {
+        let (check, outcomes) = &self.checks[index];
+        let started = Instant::now();
+        let result = send(check, pipeline).await;
+        outcomes.duration_micros.store(started.elapsed().as_micros() as u64, Ordering::Relaxed);
+        let ran_before = outcomes.successes.load(Ordering::Relaxed) + outcomes.failures.load(Ordering::Relaxed) > 0;
+        let passed_before = outcomes.passed.swap(result.is_ok(), Ordering::Relaxed);
+        match &result {
+            Ok(()) => {
+                outcomes.successes.fetch_add(1, Ordering::Relaxed);
+                if ran_before && !passed_before {
+                    println!("Synthetic check {} passes again", check.name);
+                }
+            }
+            Err(e) => {
+                outcomes.failures.fetch_add(1, Ordering::Relaxed);
+                if !ran_before || passed_before {
+                    syslog::error(format_args!("Synthetic check {} failed: {}", check.name, e));
+                }
+            }
+        }
+        result
+    }
+
+    /// The Prometheus text exposition of each check's outcomes, once it has
+    /// run.
+    pub fn render(&self) -> String {
+        let ran: Vec<_> = self
+            .checks
+            .iter()
+            .filter(|(_, outcomes)| outcomes.successes.load(Ordering::Relaxed) + outcomes.failures.load(Ordering::Relaxed) > 0)
+            .map(|(check, outcomes)| (escape(&check.name), outcomes))
+            .collect();
+        let mut out = String::new();
+        let _ = writeln!(out, "# HELP {} Whether the check's last run passed.", SYNTHETIC_SUCCESS);
+        let _ = writeln!(out, "# TYPE {} gauge", SYNTHETIC_SUCCESS);
+        for (name, outcomes) in &ran {
+            let _ = writeln!(out, "{}{{check=\"{}\"}} {}", SYNTHETIC_SUCCESS, name, u8::from(outcomes.passed.load(Ordering::Relaxed)));
+        }
+        let _ = writeln!(out, "# HELP {} How long the check's last run took.", SYNTHETIC_DURATION);
+        let _ = writeln!(out, "# TYPE {} gauge", SYNTHETIC_DURATION);
+        for (name, outcomes) in &ran {
+            let seconds = outcomes.duration_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+            let _ = writeln!(out, "{}{{check=\"{}\"}} {}", SYNTHETIC_DURATION, name, seconds);
+        }
+        let _ = writeln!(out, "# HELP {} Runs of each check by result.", SYNTHETIC_RUNS);
+        let _ = writeln!(out, "# TYPE {} counter", SYNTHETIC_RUNS);
+        for (name, outcomes) in &ran {
+            let _ = writeln!(out, "{}{{check=\"{}\",result=\"success\"}} {}", SYNTHETIC_RUNS, name, outcomes.successes.load(Ordering::Relaxed));
+            let _ = writeln!(out, "{}{{check=\"{}\",result=\"failure\"}} {}", SYNTHETIC_RUNS, name, outcomes.failures.load(Ordering::Relaxed));
+        }
+        out
+    }
+}
+
+/// Sends `check`'s request and reads the whole response, within its
+/// timeout.
+async fn send<S>(check: &SyntheticCheckConfig, pipeline: &mut S) -> Result<(), String>
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible>,
+{
+    let mut request = Request::builder().method(check.method.as_str()).uri(&check.path).header(SYNTHETIC_HEADER, &check.name);
+    for (name, value) in &check.headers {
+        request = request.header(name, value);
+    }
+    let request = request.body(Body::from(check.body.clone())).map_err(|e| e.to_string())?;
+    let exchange = async {
+        let Ok(()) = poll_fn(|cx| pipeline.poll_ready(cx)).await;
+        let Ok(response) = pipeline.call(request).await;
+        let status = response.status();
+        hyper::body::to_bytes(response.into_body()).await.map_err(|e| format!("reading the response: {}", e))?;
+        Ok::<_, String>(status)
+    };
+    let status = timeout(Duration::from_millis(check.timeout_ms), exchange)
+        .await
+        .map_err(|_| format!("no response within {}ms", check.timeout_ms))??;
+    match check.expect_status {
+        Some(expected) if status.as_u16() != expected => Err(format!("expected {}, got {}", expected, status.as_u16())),
+        None if !status.is_success() => Err(format!("got {}", status.as_u16())),
+        _ => Ok(()),
+    }
+}
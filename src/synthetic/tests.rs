@@ -0,0 +1,82 @@
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+    use warp::http::StatusCode;
+    use warp::{Filter, Reply};
+    use crate::config::SyntheticCheckConfig;
+    use crate::synthetic::{SyntheticChecks, SYNTHETIC_HEADER};
+
+    fn check(name: &str, path: &str, expect_status: Option<u16>) -> SyntheticCheckConfig {
+        SyntheticCheckConfig {
+            name: name.to_string(),
+            method: "GET".to_string(),
+            path: path.to_string(),
+            headers: HashMap::from([("authorization".to_string(), "Bearer probe".to_string())]),
+            body: String::new(),
+            expect_status,
+            interval_secs: 60,
+            timeout_ms: 100,
+        }
+    }
+
+    /// Stands in for the gateway's filters: wants the probe's token and
+    /// answers by path.
+    fn pipeline() -> impl Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone {
+        warp::path::full()
+            .and(warp::header::<String>("authorization"))
+            .and(warp::header::<String>(SYNTHETIC_HEADER))
+            .then(|path: warp::path::FullPath, authorization: String, check: String| async move {
+                let status = match path.as_str() {
+                    _ if authorization != "Bearer probe" => StatusCode::UNAUTHORIZED,
+                    "/orders" => StatusCode::OK,
+                    "/slow" => {
+                        tokio::time::sleep(Duration::from_secs(5)).await;
+                        StatusCode::OK
+                    }
+                    _ => StatusCode::SERVICE_UNAVAILABLE,
+                };
+                warp::reply::with_status(check, status).into_response()
+            })
+    }
+
+    #[tokio::test]
+    async fn test_checks_record_outcomes() {
+        let checks = SyntheticChecks::new(&[
+            check("orders", "/orders", None),
+            check("down", "/down", None),
+            check("expected-outage", "/down", Some(503)),
+            check("slow", "/slow", None),
+        ]);
+        let mut service = warp::service(pipeline());
+        assert!(checks.render().ends_with("# TYPE gateway_synthetic_checks_total counter\n"), "nothing is shown before a run");
+
+        assert_eq!(checks.run(0, &mut service).await, Ok(()));
+        assert_eq!(checks.run(1, &mut service).await, Err("got 503".to_string()));
+        assert_eq!(checks.run(2, &mut service).await, Ok(()));
+        assert_eq!(checks.run(3, &mut service).await, Err("no response within 100ms".to_string()));
+        assert_eq!(checks.run(1, &mut service).await, Err("got 503".to_string()));
+
+        let rendered = checks.render();
+        for line in [
+            "gateway_synthetic_check_success{check=\"orders\"} 1",
+            "gateway_synthetic_check_success{check=\"down\"} 0",
+            "gateway_synthetic_check_success{check=\"expected-outage\"} 1",
+            "gateway_synthetic_check_success{check=\"slow\"} 0",
+            "gateway_synthetic_checks_total{check=\"down\",result=\"failure\"} 2",
+            "gateway_synthetic_checks_total{check=\"orders\",result=\"success\"} 1",
+        ] {
+            assert!(rendered.lines().any(|l| l == line), "{} missing from\n{}", line, rendered);
+        }
+        let slow = rendered.lines().find(|l| l.starts_with("gateway_synthetic_check_duration_seconds{check=\"slow\"}")).unwrap();
+        assert!(slow.rsplit(' ').next().unwrap().parse::<f64>().unwrap() >= 0.1);
+    }
+
+    #[tokio::test]
+    async fn test_wrong_status_fails() {
+        let mut unauthorized = check("unauthorized", "/orders", Some(200));
+        unauthorized.headers.insert("authorization".to_string(), "Bearer wrong".to_string());
+        let checks = SyntheticChecks::new(&[unauthorized]);
+        assert_eq!(checks.run(0, &mut warp::service(pipeline())).await, Err("expected 200, got 401".to_string()));
+    }
+}
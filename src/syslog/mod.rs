@@ -0,0 +1,291 @@
+use std::fmt::{self, Write as _};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use hyper::http::uri::Authority;
+use rustls::pki_types::ServerName;
+use rustls::ClientConfig;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{lookup_host, TcpStream, UdpSocket};
+use tokio::sync::mpsc;
+use tokio::time::{timeout, Instant};
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+use crate::acme::civil_from_days;
+use crate::config::{SyslogConfig, SyslogTransport};
+use crate::connector;
+
+#[cfg(test)]
+mod tests;
+
+pub const SYSLOG_SENT: &str = "gateway_syslog_messages_total";
+pub const SYSLOG_DROPPED: &str = "gateway_syslog_dropped_total";
+pub const MAX_APP_NAME_LEN: usize = 48;
+pub const MAX_HOSTNAME_LEN: usize = 255;
+/// Longest message sent in one datagram; longer ones are cut short, which
+/// RFC 5426 allows.
+const MAX_DATAGRAM_LEN: usize = 8192;
+/// Most messages written to a stream at once.
+const MAX_WRITE_BATCH: usize = 100;
+const WRITE_TIMEOUT_SECS: u64 = 5;
+/// How long messages are dropped for after the collector failed, before it
+/// is tried again.
+const RETRY_SECS: u64 = 1;
+
+/// The output `access` and `error` copy to, once started.
+static OUTPUT: OnceLock<Arc<Syslog>> = OnceLock::new();
+
+/// Prints an access log line, and sends it to syslog too.
+pub fn access(line: fmt::Arguments) {
+    let line = line.to_string();
+    println!("{}", line);
+    if let Some(output) = OUTPUT.get() {
+        output.send(Severity::Informational, "access", &line);
+    }
+}
+
+/// Prints an error to stderr, and sends it to syslog too.
+pub fn error(message: fmt::Arguments) {
+    let message = message.to_string();
+    eprintln!("{}", message);
+    if let Some(output) = OUTPUT.get() {
+        output.send(Severity::Error, "error", &message);
+    }
+}
+
+/// Makes `output` where `access` and `error` send to. Only the first call
+/// has any effect.
+pub fn install(output: Arc<Syslog>) {
+    let _ = OUTPUT.set(output);
+}
+
+/// The installed output, if any.
+pub fn output() -> Option<&'static Syslog> {
+    OUTPUT.get().map(Arc::as_ref)
+}
+
+/// Whether `value` can be a header field of a message: RFC 5424 allows
+/// printable ASCII other than spaces.
+pub fn is_header_field(value: &str, max_len: usize) -> bool {
+    (1..=max_len).contains(&value.len()) && value.bytes().all(|b| b.is_ascii_graphic())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error = 3,
+    Informational = 6,
+}
+
+/// How messages reach the collector.
+enum Transport {
+    Udp,
+    Tcp,
+    Tls { config: Arc<ClientConfig>, server_name: ServerName<'static> },
+}
+
+enum Connection {
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl Connection {
+    async fn open(address: &str, transport: &Transport) -> io::Result<Self> {
+        let addr = lookup_host(address).await?.next().ok_or_else(|| io::Error::other(format!("{} has no address", address)))?;
+        Ok(match transport {
+            Transport::Udp => {
+                let local: SocketAddr = if addr.is_ipv4() { ([0, 0, 0, 0], 0).into() } else { ([0u16; 8], 0).into() };
+                let socket = UdpSocket::bind(local).await?;
+                socket.connect(addr).await?;
+                Connection::Udp(socket)
+            }
+            Transport::Tcp => Connection::Tcp(TcpStream::connect(addr).await?),
+            Transport::Tls { config, server_name } => {
+                let stream = TcpStream::connect(addr).await?;
+                Connection::Tls(Box::new(TlsConnector::from(config.clone()).connect(server_name.clone(), stream).await?))
+            }
+        })
+    }
+
+    /// Sends each message in its own datagram, or framed by its length on a
+    /// stream (RFC 6587's octet counting).
+    async fn send(&mut self, messages: &[Vec<u8>]) -> io::Result<()> {
+        let framed = || {
+            let mut framed = Vec::new();
+            for message in messages {
+                framed.extend_from_slice(format!("{} ", message.len()).as_bytes());
+                framed.extend_from_slice(message);
+            }
+            framed
+        };
+        match self {
+            Connection::Udp(socket) => {
+                for message in messages {
+                    socket.send(&message[..message.len().min(MAX_DATAGRAM_LEN)]).await?;
+                }
+                Ok(())
+            }
+            Connection::Tcp(stream) => stream.write_all(&framed()).await,
+            Connection::Tls(stream) => {
+                stream.write_all(&framed()).await?;
+                stream.flush().await
+            }
+        }
+    }
+}
+
+/// Sends log lines to a syslog collector as RFC 5424 messages. Sending
+/// never waits: messages are queued and written by a background task, and
+/// dropped when the queue is full or the collector can't be reached.
+pub struct Syslog {
+    queue: mpsc::Sender<Vec<u8>>,
+    facility: u8,
+    hostname: String,
+    app_name: String,
+    sent: AtomicU64,
+    dropped_queue_full: AtomicU64,
+    dropped_unavailable: AtomicU64,
+}
+
+impl Syslog {
+    /// Starts the background sender.
+    pub fn start(config: &SyslogConfig) -> Result<Arc<Self>, String> {
+        let transport = match &config.transport {
+            SyslogTransport::Udp => Transport::Udp,
+            SyslogTransport::Tcp => Transport::Tcp,
+            SyslogTransport::Tls(tls) => {
+                let host = config.address.parse::<Authority>().map_err(|e| format!("{}: {}", config.address, e))?;
+                let host = host.host().trim_start_matches('[').trim_end_matches(']').to_string();
+                Transport::Tls {
+                    config: Arc::new(connector::client_config(tls)?),
+                    server_name: ServerName::try_from(host).map_err(|e| format!("{}: {}", config.address, e))?,
+                }
+            }
+        };
+        let (queue, receiver) = mpsc::channel(config.queue_capacity);
+        let output = Arc::new(Self {
+            queue,
+            facility: config.facility.code(),
+            hostname: config.hostname.clone().unwrap_or_else(hostname),
+            app_name: config.app_name.clone(),
+            sent: AtomicU64::new(0),
+            dropped_queue_full: AtomicU64::new(0),
+            dropped_unavailable: AtomicU64::new(0),
+        });
+        tokio::spawn(output.clone().run(config.address.clone(), transport, receiver));
+        Ok(output)
+    }
+
+    /// Queues `message` with the message id `msg_id`.
+    pub fn send(&self, severity: Severity, msg_id: &str, message: &str) {
+        let message = self.format(severity, msg_id, message, SystemTime::now());
+        if self.queue.try_send(message).is_err() {
+            self.dropped_queue_full.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// `message` as an RFC 5424 message sent at `now`.
+    pub fn format(&self, severity: Severity, msg_id: &str, message: &str, now: SystemTime) -> Vec<u8> {
+        let priority = u32::from(self.facility) * 8 + severity as u32;
+        format!(
+            "<{}>1 {} {} {} {} {} - {}",
+            priority,
+            timestamp(now),
+            self.hostname,
+            self.app_name,
+            std::process::id(),
+            msg_id,
+            message
+        )
+        .into_bytes()
+    }
+
+    async fn run(self: Arc<Self>, address: String, transport: Transport, mut queue: mpsc::Receiver<Vec<u8>>) {
+        let mut connection: Option<Connection> = None;
+        let mut retry_at: Option<Instant> = None;
+        while let Some(first) = queue.recv().await {
+            let mut messages = vec![first];
+            while messages.len() < MAX_WRITE_BATCH {
+                match queue.try_recv() {
+                    Ok(message) => messages.push(message),
+                    Err(_) => break,
+                }
+            }
+            let count = messages.len() as u64;
+            if retry_at.is_some_and(|at| Instant::now() < at) {
+                self.dropped_unavailable.fetch_add(count, Ordering::Relaxed);
+                continue;
+            }
+            let sending = async {
+                if connection.is_none() {
+                    connection = Some(Connection::open(&address, &transport).await?);
+                }
+                match connection.as_mut() {
+                    Some(connection) => connection.send(&messages).await,
+                    None => Ok(()),
+                }
+            };
+            let error = match timeout(Duration::from_secs(WRITE_TIMEOUT_SECS), sending).await {
+                Ok(Ok(())) => {
+                    if retry_at.take().is_some() {
+                        println!("Syslog collector {} reachable again", address);
+                    }
+                    self.sent.fetch_add(count, Ordering::Relaxed);
+                    continue;
+                }
+                Ok(Err(e)) => e.to_string(),
+                Err(_) => "timed out".to_string(),
+            };
+            // Not through `error`, which would queue this behind the
+            // messages that can't be sent.
+            if retry_at.is_none() {
+                eprintln!("Syslog collector {} unreachable, dropping messages until it is back: {}", address, error);
+            }
+            retry_at = Some(Instant::now() + Duration::from_secs(RETRY_SECS));
+            connection = None;
+            self.dropped_unavailable.fetch_add(count, Ordering::Relaxed);
+        }
+    }
+
+    /// The Prometheus text exposition of the messages sent and dropped.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "# HELP {} Log lines the syslog collector took.", SYSLOG_SENT);
+        let _ = writeln!(out, "# TYPE {} counter", SYSLOG_SENT);
+        let _ = writeln!(out, "{} {}", SYSLOG_SENT, self.sent.load(Ordering::Relaxed));
+        let _ = writeln!(out, "# HELP {} Log lines not sent to syslog, by why.", SYSLOG_DROPPED);
+        let _ = writeln!(out, "# TYPE {} counter", SYSLOG_DROPPED);
+        let _ = writeln!(out, "{}{{reason=\"queue_full\"}} {}", SYSLOG_DROPPED, self.dropped_queue_full.load(Ordering::Relaxed));
+        let _ = writeln!(out, "{}{{reason=\"collector_unavailable\"}} {}", SYSLOG_DROPPED, self.dropped_unavailable.load(Ordering::Relaxed));
+        out
+    }
+}
+
+/// `now` in UTC to the millisecond, as RFC 5424 timestamps are written.
+pub fn timestamp(now: SystemTime) -> String {
+    let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs % 86_400 / 3600,
+        secs % 3600 / 60,
+        secs % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// The machine's host name, or the nil value `-` when it can't be told.
+fn hostname() -> String {
+    std::env::var("HOSTNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| is_header_field(name, MAX_HOSTNAME_LEN))
+        .unwrap_or_else(|| "-".to_string())
+}
@@ -0,0 +1,108 @@
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+    use tokio::io::AsyncReadExt;
+    use tokio::net::{TcpListener, UdpSocket};
+    use crate::config::{SyslogConfig, SyslogFacility, SyslogTransport, SYSLOG_APP_NAME, SYSLOG_QUEUE_CAPACITY};
+    use crate::syslog::{timestamp, Severity, Syslog};
+
+    fn config(address: String, transport: SyslogTransport) -> SyslogConfig {
+        SyslogConfig {
+            address,
+            transport,
+            facility: SyslogFacility::Local0,
+            app_name: SYSLOG_APP_NAME.to_string(),
+            hostname: Some("gw-1".to_string()),
+            queue_capacity: SYSLOG_QUEUE_CAPACITY,
+        }
+    }
+
+    async fn eventually(condition: impl Fn() -> bool) {
+        for _ in 0..200 {
+            if condition() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("condition never held");
+    }
+
+    #[test]
+    fn test_timestamp() {
+        assert_eq!(timestamp(UNIX_EPOCH + Duration::from_millis(1_700_000_000_250)), "2023-11-14T22:13:20.250Z");
+        assert_eq!(timestamp(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+    }
+
+    #[tokio::test]
+    async fn test_messages_follow_rfc_5424() {
+        let output = Syslog::start(&config("127.0.0.1:1".to_string(), SyslogTransport::Udp)).unwrap();
+        let at = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let message = output.format(Severity::Error, "error", "Redis cache lookup failed: timed out", at);
+        assert_eq!(
+            String::from_utf8(message).unwrap(),
+            format!("<131>1 2023-11-14T22:13:20.000Z gw-1 api-gateway {} error - Redis cache lookup failed: timed out", std::process::id())
+        );
+        let message = output.format(Severity::Informational, "access", "GET /orders 200 OK 3ms", at);
+        assert!(message.starts_with(b"<134>1 "));
+    }
+
+    #[tokio::test]
+    async fn test_one_datagram_per_message_over_udp() {
+        let collector = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let output = Syslog::start(&config(collector.local_addr().unwrap().to_string(), SyslogTransport::Udp)).unwrap();
+        output.send(Severity::Informational, "access", "GET /orders 200 OK 3ms");
+        output.send(Severity::Error, "error", "Error forwarding request");
+
+        let mut datagram = [0; 1024];
+        let len = collector.recv(&mut datagram).await.unwrap();
+        let first = String::from_utf8_lossy(&datagram[..len]).into_owned();
+        assert!(first.starts_with("<134>1 "));
+        assert!(first.ends_with(" access - GET /orders 200 OK 3ms"));
+        let len = collector.recv(&mut datagram).await.unwrap();
+        assert!(String::from_utf8_lossy(&datagram[..len]).ends_with(" error - Error forwarding request"));
+        eventually(|| output.render().contains("gateway_syslog_messages_total 2\n")).await;
+    }
+
+    #[tokio::test]
+    async fn test_messages_are_framed_by_length_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let output = Syslog::start(&config(listener.local_addr().unwrap().to_string(), SyslogTransport::Tcp)).unwrap();
+        output.send(Severity::Informational, "access", "GET /orders 200 OK 3ms");
+        output.send(Severity::Informational, "access", "GET /users 404 Not Found 1ms");
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut received = Vec::new();
+        let mut messages = Vec::new();
+        while messages.len() < 2 {
+            let mut chunk = [0; 1024];
+            let len = stream.read(&mut chunk).await.unwrap();
+            received.extend_from_slice(&chunk[..len]);
+            while let Some(space) = received.iter().position(|b| *b == b' ') {
+                let frame_len: usize = std::str::from_utf8(&received[..space]).unwrap().parse().unwrap();
+                if received.len() < space + 1 + frame_len {
+                    break;
+                }
+                messages.push(String::from_utf8(received[space + 1..space + 1 + frame_len].to_vec()).unwrap());
+                received.drain(..space + 1 + frame_len);
+            }
+        }
+        assert!(messages[0].ends_with(" access - GET /orders 200 OK 3ms"));
+        assert!(messages[1].ends_with(" access - GET /users 404 Not Found 1ms"));
+    }
+
+    #[tokio::test]
+    async fn test_messages_are_dropped_while_the_collector_is_down() {
+        // Nothing listens on the port once the listener is gone.
+        let address = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let output = Syslog::start(&SyslogConfig { queue_capacity: 2, ..config(address.to_string(), SyslogTransport::Tcp) }).unwrap();
+        // The sender only runs once this test yields, so the third message
+        // finds the queue full.
+        for _ in 0..3 {
+            output.send(Severity::Error, "error", "Database write failed");
+        }
+        eventually(|| output.render().contains("gateway_syslog_dropped_total{reason=\"collector_unavailable\"} 2\n")).await;
+        let metrics = output.render();
+        assert!(metrics.contains("gateway_syslog_messages_total 0\n"));
+        assert!(metrics.contains("gateway_syslog_dropped_total{reason=\"queue_full\"} 1\n"));
+    }
+}
@@ -0,0 +1,114 @@
+use std::collections::{HashMap, HashSet};
+use hyper::{HeaderMap, header::HOST};
+use crate::auth::Identity;
+use crate::config::{RateLimitConfig, TenancyConfig};
+use crate::routes::has_segment_prefix;
+
+#[cfg(test)]
+mod tests;
+
+pub struct Tenant {
+    pub name: String,
+    pub rate_limit: Option<RateLimitConfig>,
+    pub quota: Option<RateLimitConfig>,
+    pub max_cache_entries: Option<usize>,
+    pub max_rate_limit_buckets: Option<usize>,
+    path_prefix: Option<String>,
+    upstreams: HashMap<String, String>,
+    disabled_routes: HashSet<String>,
+}
+
+impl Tenant {
+    /// The upstream this tenant uses for `route` instead of the route's own.
+    pub fn upstream(&self, route: &str) -> Option<&str> {
+        self.upstreams.get(route).map(String::as_str)
+    }
+
+    pub fn route_enabled(&self, route: &str) -> bool {
+        !self.disabled_routes.contains(route)
+    }
+}
+
+#[derive(Default)]
+pub struct Tenants {
+    claim: Option<String>,
+    tenants: HashMap<String, Tenant>,
+    by_host: HashMap<String, String>,
+    /// Longest prefix first.
+    by_prefix: Vec<(String, String)>,
+}
+
+impl Tenants {
+    pub fn from_config(config: &TenancyConfig) -> Self {
+        let mut tenants = Self {
+            claim: config.claim.clone(),
+            ..Self::default()
+        };
+        for (name, tenant) in &config.tenants {
+            for host in &tenant.hosts {
+                tenants.by_host.insert(host.to_ascii_lowercase(), name.clone());
+            }
+            let path_prefix = tenant.path_prefix.as_ref().map(|p| p.trim_end_matches('/').to_string());
+            if let Some(prefix) = &path_prefix {
+                tenants.by_prefix.push((prefix.clone(), name.clone()));
+            }
+            tenants.tenants.insert(
+                name.clone(),
+                Tenant {
+                    name: name.clone(),
+                    rate_limit: tenant.rate_limit,
+                    quota: tenant.quota,
+                    max_cache_entries: tenant.max_cache_entries,
+                    max_rate_limit_buckets: tenant.max_rate_limit_buckets,
+                    path_prefix,
+                    upstreams: tenant.upstreams.clone(),
+                    disabled_routes: tenant.disabled_routes.iter().cloned().collect(),
+                },
+            );
+        }
+        tenants.by_prefix.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+        tenants
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Tenant> {
+        self.tenants.get(name)
+    }
+
+    /// The tenant a request belongs to, and the path to route it by: the
+    /// request path without the tenant's path prefix.
+    pub fn resolve<'a>(
+        &'a self,
+        headers: &HeaderMap,
+        path: &'a str,
+        identity: Option<&Identity>,
+    ) -> (Option<&'a Tenant>, &'a str) {
+        let by_claim = self
+            .claim
+            .as_ref()
+            .and_then(|claim| identity?.claim(claim))
+            .and_then(|name| self.tenants.get(&name));
+        let by_host = || {
+            let host = headers.get(HOST)?.to_str().ok()?;
+            let host = host.rsplit_once(':').map_or(host, |(host, _)| host);
+            self.tenants.get(self.by_host.get(&host.to_ascii_lowercase())?)
+        };
+        let by_prefix = || {
+            self.by_prefix
+                .iter()
+                .find(|(prefix, _)| has_segment_prefix(path, prefix))
+                .and_then(|(_, name)| self.tenants.get(name))
+        };
+
+        let Some(tenant) = by_claim.or_else(by_host).or_else(by_prefix) else {
+            return (None, path);
+        };
+        let routed = match &tenant.path_prefix {
+            Some(prefix) if has_segment_prefix(path, prefix) => match &path[prefix.len()..] {
+                "" => "/",
+                rest => rest,
+            },
+            _ => path,
+        };
+        (Some(tenant), routed)
+    }
+}
@@ -0,0 +1,61 @@
+#[cfg(test)]
+mod tests {
+    use hyper::HeaderMap;
+    use serde_json::{json, Map};
+    use crate::auth::Identity;
+    use crate::config::GatewayConfig;
+    use crate::tenants::Tenants;
+
+    fn tenants() -> Tenants {
+        let config = GatewayConfig::from_json(r#"{
+            "routes": [{ "name": "orders", "path_prefix": "/orders", "upstream": "http://orders:80" }],
+            "tenancy": {
+                "claim": "tenant",
+                "tenants": {
+                    "acme": { "hosts": ["acme.example.com"], "path_prefix": "/t/acme", "upstreams": { "orders": "http://acme-orders:80" } },
+                    "globex": { "path_prefix": "/t/globex", "disabled_routes": ["orders"] }
+                }
+            }
+        }"#).unwrap();
+        assert!(config.validate().is_empty(), "{:?}", config.validate());
+        Tenants::from_config(&config.tenancy)
+    }
+
+    fn host(host: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("host", host.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_resolve_by_host_and_prefix() {
+        let tenants = tenants();
+
+        let (tenant, path) = tenants.resolve(&host("ACME.example.com:443"), "/orders/1", None);
+        assert_eq!(tenant.unwrap().name, "acme");
+        assert_eq!(path, "/orders/1");
+
+        let (tenant, path) = tenants.resolve(&HeaderMap::new(), "/t/globex/orders/1", None);
+        assert_eq!(tenant.unwrap().name, "globex");
+        assert_eq!(path, "/orders/1");
+        assert!(!tenant.unwrap().route_enabled("orders"));
+
+        let (tenant, path) = tenants.resolve(&HeaderMap::new(), "/t/globexx/orders", None);
+        assert!(tenant.is_none());
+        assert_eq!(path, "/t/globexx/orders");
+    }
+
+    #[test]
+    fn test_claim_takes_precedence() {
+        let tenants = tenants();
+        let mut claims = Map::new();
+        claims.insert("tenant".to_string(), json!("acme"));
+        let identity = Identity { user_id: "u".to_string(), scopes: Vec::new(), claims };
+
+        let (tenant, path) = tenants.resolve(&HeaderMap::new(), "/t/acme", Some(&identity));
+        let tenant = tenant.unwrap();
+        assert_eq!(tenant.name, "acme");
+        assert_eq!(path, "/");
+        assert_eq!(tenant.upstream("orders"), Some("http://acme-orders:80"));
+    }
+}
@@ -0,0 +1,296 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use futures::{Stream, StreamExt};
+use hyper::header::HeaderValue;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::crypto::ring::ALL_CIPHER_SUITES;
+use rustls::version::{TLS12, TLS13};
+use rustls::{ServerConfig, SupportedCipherSuite, SupportedProtocolVersion};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::mpsc;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use crate::acme::not_after;
+use crate::config::{AltService, TlsConfig, TlsVersion};
+use crate::metrics::Connections;
+
+#[cfg(test)]
+mod tests;
+
+/// The ALPN protocol of TLS-ALPN-01 validation connections (RFC 8737).
+pub const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+const HANDSHAKE_TIMEOUT_SECS: u64 = 10;
+/// Finished handshakes waiting for warp to pick them up.
+const HANDSHAKE_QUEUE: usize = 128;
+
+/// Picks the certificate for each handshake: the one for the SNI name if
+/// there is one, else the one for its wildcard, else the configured
+/// certificate. ACME validation handshakes only ever get a challenge
+/// certificate.
+#[derive(Default)]
+pub struct CertResolver {
+    fallback: Option<Arc<CertifiedKey>>,
+    certs: RwLock<HashMap<String, Arc<CertifiedKey>>>,
+    challenges: RwLock<HashMap<String, Arc<CertifiedKey>>>,
+}
+
+impl fmt::Debug for CertResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<String> = self.certs.read().unwrap_or_else(|e| e.into_inner()).keys().cloned().collect();
+        f.debug_struct("CertResolver").field("names", &names).finish_non_exhaustive()
+    }
+}
+
+impl CertResolver {
+    pub fn new(fallback: Option<CertifiedKey>) -> Self {
+        Self { fallback: fallback.map(Arc::new), ..Self::default() }
+    }
+
+    /// Serves `key` for `name` from the next handshake on.
+    pub fn insert(&self, name: &str, key: CertifiedKey) {
+        self.certs.write().unwrap_or_else(|e| e.into_inner()).insert(name.to_ascii_lowercase(), Arc::new(key));
+    }
+
+    /// Answers TLS-ALPN-01 validation of `name` with `key` until removed.
+    pub fn insert_challenge(&self, name: &str, key: CertifiedKey) {
+        self.challenges.write().unwrap_or_else(|e| e.into_inner()).insert(name.to_ascii_lowercase(), Arc::new(key));
+    }
+
+    pub fn remove_challenge(&self, name: &str) {
+        self.challenges.write().unwrap_or_else(|e| e.into_inner()).remove(&name.to_ascii_lowercase());
+    }
+
+    /// When each certificate served expires, in Unix seconds, by host name.
+    /// The configured certificate is listed as `default`.
+    pub fn expiries(&self) -> Vec<(String, u64)> {
+        let certs = self.certs.read().unwrap_or_else(|e| e.into_inner());
+        let fallback = self.fallback.iter().map(|key| ("default", key));
+        certs
+            .iter()
+            .map(|(name, key)| (name.as_str(), key))
+            .chain(fallback)
+            .filter_map(|(name, key)| Some((name.to_string(), not_after(key.cert.first()?)?)))
+            .collect()
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let name = hello.server_name().map(str::to_ascii_lowercase);
+        if hello.alpn().is_some_and(|mut protocols| protocols.any(|protocol| protocol == ACME_TLS_ALPN)) {
+            return self.challenges.read().unwrap_or_else(|e| e.into_inner()).get(name.as_deref()?).cloned();
+        }
+        name.and_then(|name| {
+            let certs = self.certs.read().unwrap_or_else(|e| e.into_inner());
+            let wildcard = || name.split_once('.').and_then(|(_, parent)| certs.get(&format!("*.{}", parent)));
+            certs.get(&name).or_else(wildcard).cloned()
+        })
+        .or_else(|| self.fallback.clone())
+    }
+}
+
+/// Parses a PEM certificate chain and its private key.
+pub fn certified_key(cert_pem: &[u8], key_pem: &[u8]) -> Result<CertifiedKey, String> {
+    let certs = rustls_pemfile::certs(&mut &*cert_pem)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("reading certificate: {}", e))?;
+    if certs.is_empty() {
+        return Err("no certificate found".to_string());
+    }
+    let key = rustls_pemfile::private_key(&mut &*key_pem)
+        .map_err(|e| format!("reading private key: {}", e))?
+        .ok_or_else(|| "no private key found".to_string())?;
+    let key = rustls::crypto::ring::sign::any_supported_type(&key).map_err(|e| format!("unsupported private key: {}", e))?;
+    Ok(CertifiedKey::new(certs, key))
+}
+
+/// A resolver with the configured certificates: the default one, then
+/// those in `cert_dir`, then those in `certificates`, which win for a name
+/// that is in both.
+pub fn resolver(config: &TlsConfig) -> Result<CertResolver, String> {
+    let resolver = CertResolver::new(pem_pair(&config.cert, &config.cert_path, &config.key, &config.key_path)?);
+    if let Some(dir) = &config.cert_dir {
+        for (name, key) in dir_certificates(dir)? {
+            resolver.insert(&name, key);
+        }
+    }
+    for (name, certificate) in &config.certificates {
+        let key = pem_pair(&certificate.cert, &certificate.cert_path, &certificate.key, &certificate.key_path)
+            .map_err(|e| format!("{}: {}", name, e))?
+            .ok_or_else(|| format!("{}: no certificate and key configured", name))?;
+        resolver.insert(name, key);
+    }
+    Ok(resolver)
+}
+
+/// A certificate and key, read from their files or given inline. `None` if
+/// neither is set.
+fn pem_pair(
+    cert: &Option<String>,
+    cert_path: &Option<PathBuf>,
+    key: &Option<String>,
+    key_path: &Option<PathBuf>,
+) -> Result<Option<CertifiedKey>, String> {
+    match (read_pem(cert, cert_path)?, read_pem(key, key_path)?) {
+        (Some(cert), Some(key)) => certified_key(&cert, &key).map(Some),
+        _ => Ok(None),
+    }
+}
+
+/// A PEM given inline, else read from `path`. `None` if neither is set.
+pub fn read_pem(pem: &Option<String>, path: &Option<PathBuf>) -> Result<Option<Vec<u8>>, String> {
+    match (pem, path) {
+        (Some(pem), _) => Ok(Some(pem.clone().into_bytes())),
+        (None, Some(path)) => std::fs::read(path).map(Some).map_err(|e| format!("reading {}: {}", path.display(), e)),
+        (None, None) => Ok(None),
+    }
+}
+
+/// Every `<name>.crt` in `dir` with its `<name>.key`. A first label of `_`
+/// stands for `*`, so `_.example.com.crt` serves `*.example.com`.
+fn dir_certificates(dir: &Path) -> Result<Vec<(String, CertifiedKey)>, String> {
+    let entries = std::fs::read_dir(dir).map_err(|e| format!("reading {}: {}", dir.display(), e))?;
+    let mut certificates = Vec::new();
+    for entry in entries {
+        let path = entry.map_err(|e| format!("reading {}: {}", dir.display(), e))?.path();
+        if path.extension().is_none_or(|extension| extension != "crt") {
+            continue;
+        }
+        let Some(stem) = path.file_stem().and_then(|stem| stem.to_str()) else {
+            continue;
+        };
+        let read = |path: &Path| std::fs::read(path).map_err(|e| format!("reading {}: {}", path.display(), e));
+        let key_path = path.with_extension("key");
+        let key = certified_key(&read(&path)?, &read(&key_path)?).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let name = match stem.strip_prefix("_.") {
+            Some(parent) => format!("*.{}", parent),
+            None => stem.to_string(),
+        };
+        certificates.push((name, key));
+    }
+    Ok(certificates)
+}
+
+/// The listener's TLS settings from `config`, with `acme-tls/1` added to
+/// the ALPN protocols while certificates may be validated with
+/// TLS-ALPN-01.
+pub fn server_config(config: &TlsConfig, resolver: Arc<CertResolver>, acme_alpn: bool) -> Result<Arc<ServerConfig>, String> {
+    let mut provider = rustls::crypto::ring::default_provider();
+    let suites = cipher_suites(&config.cipher_suites)?;
+    if !suites.is_empty() {
+        provider.cipher_suites = suites;
+    }
+    let versions = protocol_versions(config.min_version, &provider.cipher_suites);
+    let mut server = ServerConfig::builder_with_provider(Arc::new(provider))
+        .with_protocol_versions(&versions)
+        .map_err(|e| format!("TLS settings: {}", e))?
+        .with_no_client_auth()
+        .with_cert_resolver(resolver);
+    server.alpn_protocols = config.alpn_protocols.iter().map(|protocol| protocol.as_bytes().to_vec()).collect();
+    if acme_alpn {
+        server.alpn_protocols.push(ACME_TLS_ALPN.to_vec());
+    }
+    if config.session_tickets {
+        server.ticketer = rustls::crypto::ring::Ticketer::new().map_err(|e| format!("session tickets: {}", e))?;
+    }
+    Ok(Arc::new(server))
+}
+
+/// The cipher suites with the given IANA names, in the same order. Empty
+/// for no names.
+pub fn cipher_suites(names: &[String]) -> Result<Vec<SupportedCipherSuite>, String> {
+    names
+        .iter()
+        .map(|name| {
+            ALL_CIPHER_SUITES
+                .iter()
+                .find(|suite| suite.suite().as_str() == Some(name.as_str()))
+                .copied()
+                .ok_or_else(|| format!("unknown cipher suite \"{}\"", name))
+        })
+        .collect()
+}
+
+/// The versions from `min` up that at least one of `suites` can be used
+/// with.
+pub fn protocol_versions(min: TlsVersion, suites: &[SupportedCipherSuite]) -> Vec<&'static SupportedProtocolVersion> {
+    let allowed: &[&'static SupportedProtocolVersion] = match min {
+        TlsVersion::Tls12 => &[&TLS13, &TLS12],
+        TlsVersion::Tls13 => &[&TLS13],
+    };
+    allowed
+        .iter()
+        .copied()
+        .filter(|version| suites.iter().any(|suite| suite.version() == *version))
+        .collect()
+}
+
+/// The `Alt-Svc` value advertising `services`, or `None` for none.
+pub fn alt_svc(services: &[AltService]) -> Option<HeaderValue> {
+    let entries: Vec<String> = services
+        .iter()
+        .map(|service| {
+            let authority = format!("{}:{}", service.host.as_deref().unwrap_or_default(), service.port);
+            format!("{}=\"{}\"; ma={}", service.protocol, authority, service.max_age_secs)
+        })
+        .collect();
+    if entries.is_empty() {
+        return None;
+    }
+    HeaderValue::from_str(&entries.join(", ")).ok()
+}
+
+/// Completes TLS handshakes on the accepted connections, each in its own
+/// task so a slow client can't hold up the others. Failed handshakes are
+/// dropped, and so are ACME validation connections, which are done once
+/// the handshake has shown the challenge certificate.
+pub fn incoming<S, IO>(
+    connections: S,
+    config: Arc<ServerConfig>,
+    metrics: Arc<Connections>,
+) -> impl Stream<Item = io::Result<TlsStream<IO>>>
+where
+    S: Stream<Item = io::Result<IO>> + Send + 'static,
+    IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let acceptor = TlsAcceptor::from(config);
+    let (sender, receiver) = mpsc::channel(HANDSHAKE_QUEUE);
+    tokio::spawn(async move {
+        futures::pin_mut!(connections);
+        while let Some(accepted) = connections.next().await {
+            let stream = match accepted {
+                Ok(stream) => stream,
+                Err(e) => {
+                    if sender.send(Err(e)).await.is_err() {
+                        return;
+                    }
+                    continue;
+                }
+            };
+            let (acceptor, sender, metrics) = (acceptor.clone(), sender.clone(), metrics.clone());
+            tokio::spawn(async move {
+                let handshake = tokio::time::timeout(Duration::from_secs(HANDSHAKE_TIMEOUT_SECS), acceptor.accept(stream)).await;
+                match handshake {
+                    Ok(Ok(stream)) => {
+                        let alpn = stream.get_ref().1.alpn_protocol();
+                        if alpn != Some(ACME_TLS_ALPN) {
+                            metrics.negotiated(alpn);
+                            let _ = sender.send(Ok(stream)).await;
+                        }
+                    }
+                    Ok(Err(_)) => metrics.handshake_failed(false),
+                    Err(_) => metrics.handshake_failed(true),
+                }
+            });
+        }
+    });
+    futures::stream::unfold(receiver, |mut receiver| async move {
+        let stream = receiver.recv().await?;
+        Some((stream, receiver))
+    })
+}
@@ -0,0 +1,250 @@
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use futures::StreamExt;
+    use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+    use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+    use rustls::version::{TLS12, TLS13};
+    use rustls::{ClientConfig, DigitallySignedStruct, SignatureScheme, SupportedProtocolVersion};
+    use ring::rand::SystemRandom;
+    use serde_json::{json, Value};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio_rustls::client::TlsStream;
+    use tokio_rustls::TlsConnector;
+    use crate::acme::{challenge_key, pem, self_signed};
+    use crate::config::{SocketConfig, TlsConfig, TlsVersion};
+    use crate::metrics::Connections;
+    use crate::server;
+    use crate::tls::{
+        alt_svc, certified_key, cipher_suites, incoming, protocol_versions, resolver, server_config, CertResolver, ACME_TLS_ALPN,
+    };
+
+    /// Accepts any certificate; the tests check which one was served.
+    #[derive(Debug)]
+    struct AnyCertificate;
+
+    impl ServerCertVerifier for AnyCertificate {
+        fn verify_server_cert(
+            &self,
+            _: &CertificateDer<'_>,
+            _: &[CertificateDer<'_>],
+            _: &ServerName<'_>,
+            _: &[u8],
+            _: UnixTime,
+        ) -> Result<ServerCertVerified, rustls::Error> {
+            Ok(ServerCertVerified::assertion())
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            _: &[u8],
+            _: &CertificateDer<'_>,
+            _: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            Ok(HandshakeSignatureValid::assertion())
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            _: &[u8],
+            _: &CertificateDer<'_>,
+            _: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            Ok(HandshakeSignatureValid::assertion())
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+            rustls::crypto::ring::default_provider().signature_verification_algorithms.supported_schemes()
+        }
+    }
+
+    fn client(versions: &[&'static SupportedProtocolVersion], alpn: &[&[u8]]) -> Arc<ClientConfig> {
+        let mut config = ClientConfig::builder_with_protocol_versions(versions)
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(AnyCertificate))
+            .with_no_client_auth();
+        config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+        Arc::new(config)
+    }
+
+    async fn handshake(addr: SocketAddr, name: &str, client: Arc<ClientConfig>) -> std::io::Result<TlsStream<TcpStream>> {
+        let stream = TcpStream::connect(addr).await?;
+        let name = ServerName::try_from(name.to_string()).unwrap();
+        TlsConnector::from(client).connect(name, stream).await
+    }
+
+    /// Connects with SNI `name` offering `alpn`, and returns the certificate
+    /// served and the protocol agreed on.
+    async fn connect(addr: SocketAddr, name: &str, alpn: &[u8]) -> (Vec<u8>, Option<Vec<u8>>, TlsStream<TcpStream>) {
+        let stream = handshake(addr, name, client(&[&TLS13, &TLS12], &[alpn])).await.unwrap();
+        let connection = stream.get_ref().1;
+        let cert = connection.peer_certificates().unwrap()[0].to_vec();
+        let protocol = connection.alpn_protocol().map(<[u8]>::to_vec);
+        (cert, protocol, stream)
+    }
+
+    fn tls_config(value: Value) -> TlsConfig {
+        serde_json::from_value(value).unwrap()
+    }
+
+    /// A self-signed certificate for `name` and its key, as PEM.
+    fn pem_pair(name: &str) -> (String, String) {
+        let (cert, key) = self_signed(name, Vec::new(), 30, &SystemRandom::new()).unwrap();
+        (pem("CERTIFICATE", &cert), pem("PRIVATE KEY", &key))
+    }
+
+    #[tokio::test]
+    async fn test_certificates_are_picked_by_server_name() {
+        let fallback = challenge_key("gateway.example.com", "unused").unwrap();
+        let fallback_cert = fallback.cert[0].to_vec();
+        let resolver = Arc::new(CertResolver::new(Some(fallback)));
+        let api = challenge_key("api.example.com", "unused").unwrap();
+        let api_cert = api.cert[0].to_vec();
+        resolver.insert("API.example.com", api);
+        let proof = challenge_key("api.example.com", "token.thumbprint").unwrap();
+        let proof_cert = proof.cert[0].to_vec();
+        resolver.insert_challenge("api.example.com", proof);
+
+        let listener = server::bind(([127, 0, 0, 1], 0).into(), &SocketConfig::default()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = server_config(&tls_config(json!({})), resolver.clone(), true).unwrap();
+        let connections = Arc::new(Connections::default());
+        let mut accepted = Box::pin(incoming(server::incoming(listener, true), server, connections.clone()));
+
+        let (cert, protocol, mut stream) = connect(addr, "api.example.com", b"http/1.1").await;
+        assert_eq!(cert, api_cert);
+        assert_eq!(protocol.as_deref(), Some(&b"http/1.1"[..]));
+        let mut served = accepted.next().await.unwrap().unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut received = [0u8; 4];
+        served.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"ping");
+
+        let (cert, protocol, _client) = connect(addr, "other.example.com", b"h2").await;
+        assert_eq!(cert, fallback_cert);
+        assert_eq!(protocol.as_deref(), Some(&b"h2"[..]));
+        assert!(accepted.next().await.unwrap().is_ok());
+
+        // Validation handshakes get the challenge certificate and never
+        // reach the gateway.
+        let (cert, protocol, _client) = connect(addr, "api.example.com", ACME_TLS_ALPN).await;
+        assert_eq!(cert, proof_cert);
+        assert_eq!(protocol.as_deref(), Some(ACME_TLS_ALPN));
+        assert!(tokio::time::timeout(Duration::from_millis(200), accepted.next()).await.is_err());
+
+        resolver.remove_challenge("api.example.com");
+        let validation = client(&[&TLS13], &[ACME_TLS_ALPN]);
+        assert!(handshake(addr, "api.example.com", validation).await.is_err());
+
+        // Validation connections aren't counted; failed handshakes are.
+        let mut plain = TcpStream::connect(addr).await.unwrap();
+        plain.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        let mut rest = Vec::new();
+        let _ = plain.read_to_end(&mut rest).await;
+        for _ in 0..50 {
+            if connections.render().contains("reason=\"error\"} 2") {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let text = connections.render();
+        assert!(text.contains("gateway_tls_connections_total{protocol=\"h2\"} 1"), "{}", text);
+        assert!(text.contains("gateway_tls_connections_total{protocol=\"http/1.1\"} 1"));
+        assert!(!text.contains("acme-tls/1"));
+        assert!(text.contains("gateway_tls_handshake_failures_total{reason=\"error\"} 2"));
+    }
+
+    #[test]
+    fn test_certified_key_needs_a_certificate_and_key() {
+        assert_eq!(certified_key(b"", b"").err().unwrap(), "no certificate found");
+        let cert = b"-----BEGIN CERTIFICATE-----\nMAA=\n-----END CERTIFICATE-----\n";
+        assert_eq!(certified_key(cert, b"").err().unwrap(), "no private key found");
+    }
+
+    #[tokio::test]
+    async fn test_configured_certificates_by_name() {
+        let dir = std::env::temp_dir().join(format!("api-gateway-tls-certs-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut served = std::collections::HashMap::new();
+        for (file, name) in [("api.example.com", "api.example.com"), ("_.apps.example.com", "*.apps.example.com")] {
+            let (cert, key) = pem_pair(name);
+            std::fs::write(dir.join(format!("{}.crt", file)), &cert).unwrap();
+            std::fs::write(dir.join(format!("{}.key", file)), &key).unwrap();
+            served.insert(name, cert);
+        }
+        std::fs::write(dir.join("account.key"), "not a certificate's key").unwrap();
+        let (inline_cert, inline_key) = pem_pair("api.example.com");
+        let config = tls_config(json!({
+            "cert_dir": dir,
+            "certificates": { "API.example.com": { "cert": inline_cert, "key": inline_key } }
+        }));
+
+        let listener = server::bind(([127, 0, 0, 1], 0).into(), &SocketConfig::default()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let certs = Arc::new(resolver(&config).unwrap());
+        let server = server_config(&config, certs, false).unwrap();
+        let accepted = incoming(server::incoming(listener, true), server, Arc::new(Connections::default()));
+        tokio::spawn(accepted.for_each(|_| async {}));
+        let der = |pem: &str| rustls_pemfile::certs(&mut pem.as_bytes()).next().unwrap().unwrap().to_vec();
+
+        // The certificates map wins over the directory.
+        assert_eq!(connect(addr, "api.example.com", b"h2").await.0, der(&inline_cert));
+        assert_eq!(connect(addr, "web.apps.example.com", b"h2").await.0, der(&served["*.apps.example.com"]));
+
+        // A wildcard covers one label, and there is no default certificate.
+        assert!(handshake(addr, "a.web.apps.example.com", client(&[&TLS13], &[b"h2"])).await.is_err());
+
+        std::fs::remove_file(dir.join("api.example.com.key")).unwrap();
+        let error = resolver(&config).err().unwrap();
+        assert!(error.contains("api.example.com.key"), "{}", error);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_policy_limits_versions_suites_and_protocols() {
+        let config = tls_config(json!({
+            "min_version": "1.3",
+            "cipher_suites": ["TLS13_AES_256_GCM_SHA384", "TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384"],
+            "alpn_protocols": ["http/1.1"],
+            "session_tickets": true
+        }));
+        let certs = Arc::new(CertResolver::new(Some(challenge_key("gateway.example.com", "unused").unwrap())));
+        let server = server_config(&config, certs.clone(), false).unwrap();
+        assert!(server.ticketer.enabled());
+        assert!(!server_config(&tls_config(json!({})), certs, false).unwrap().ticketer.enabled());
+
+        let listener = server::bind(([127, 0, 0, 1], 0).into(), &SocketConfig::default()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(incoming(server::incoming(listener, true), server, Arc::new(Connections::default())).for_each(|_| async {}));
+
+        let stream = handshake(addr, "gateway.example.com", client(&[&TLS13, &TLS12], &[b"h2", b"http/1.1"])).await.unwrap();
+        let connection = stream.get_ref().1;
+        assert_eq!(connection.alpn_protocol(), Some(&b"http/1.1"[..]));
+        assert_eq!(connection.negotiated_cipher_suite().unwrap().suite().as_str(), Some("TLS13_AES_256_GCM_SHA384"));
+        assert!(handshake(addr, "gateway.example.com", client(&[&TLS12], &[])).await.is_err());
+    }
+
+    #[test]
+    fn test_alt_svc_value() {
+        let config = tls_config(json!({
+            "alt_svc": [{ "port": 443 }, { "protocol": "h3", "host": "quic.example.com", "port": 8443, "max_age_secs": 60 }]
+        }));
+        let value = alt_svc(&config.alt_svc).unwrap();
+        assert_eq!(value, r#"h3=":443"; ma=86400, h3="quic.example.com:8443"; ma=60"#);
+        assert!(alt_svc(&[]).is_none());
+    }
+
+    #[test]
+    fn test_cipher_suites_by_name() {
+        let names = ["TLS13_CHACHA20_POLY1305_SHA256".to_string(), "TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256".to_string()];
+        let suites = cipher_suites(&names).unwrap();
+        assert_eq!(suites.len(), 2);
+        assert_eq!(protocol_versions(TlsVersion::Tls12, &suites), [&TLS13, &TLS12]);
+        assert_eq!(protocol_versions(TlsVersion::Tls13, &suites[1..]), Vec::<&SupportedProtocolVersion>::new());
+        assert_eq!(cipher_suites(&["RC4".to_string()]).err().unwrap(), "unknown cipher suite \"RC4\"");
+    }
+}
@@ -0,0 +1,272 @@
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::{Body, HeaderMap, Method, Request, StatusCode};
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+use tokio::time::{timeout, timeout_at, Instant};
+use crate::aws::{self, HttpsClient};
+use crate::config::TracingConfig;
+use crate::correlation::{parse_traceparent_ids, TRACEPARENT_HEADER};
+use crate::experiments::random;
+use crate::syslog;
+
+#[cfg(test)]
+mod tests;
+
+pub const SPANS_EXPORTED: &str = "gateway_spans_exported_total";
+pub const SPANS_DROPPED: &str = "gateway_spans_dropped_total";
+/// How long batches are dropped for after the collector failed, before it
+/// is tried again.
+const RETRY_SECS: u64 = 1;
+/// OTLP's `SPAN_KIND_SERVER`.
+const SPAN_KIND_SERVER: u8 = 2;
+/// OTLP's `STATUS_CODE_ERROR`.
+const STATUS_CODE_ERROR: u8 = 2;
+
+/// Exports one span per traced request to an OTLP/HTTP collector. Like
+/// access events, spans are queued and sent in batches by a background
+/// task, and dropped when the queue is full or the collector can't be
+/// reached.
+pub struct Tracer {
+    sample_rate: f64,
+    queue: mpsc::Sender<Value>,
+    exported: AtomicU64,
+    dropped_queue_full: AtomicU64,
+    dropped_unavailable: AtomicU64,
+}
+
+/// Where batches are sent and how.
+struct Exporter {
+    client: HttpsClient,
+    endpoint: String,
+    resource: Value,
+    size: usize,
+    flush_interval: Duration,
+    timeout: Duration,
+}
+
+impl Tracer {
+    /// Starts the background exporter.
+    pub fn start(config: &TracingConfig) -> Arc<Self> {
+        let (queue, receiver) = mpsc::channel(config.queue_capacity);
+        let tracer = Arc::new(Self {
+            sample_rate: config.sample_rate,
+            queue,
+            exported: AtomicU64::new(0),
+            dropped_queue_full: AtomicU64::new(0),
+            dropped_unavailable: AtomicU64::new(0),
+        });
+        let exporter = Exporter {
+            client: aws::https_client(),
+            endpoint: config.endpoint.clone(),
+            resource: json!({ "attributes": [attribute("service.name", json!(config.service_name))] }),
+            size: config.batch_size,
+            flush_interval: Duration::from_millis(config.flush_interval_ms),
+            timeout: Duration::from_millis(config.timeout_ms),
+        };
+        tokio::spawn(tracer.clone().run(exporter, receiver));
+        tracer
+    }
+
+    /// Starts tracing the request if it is sampled, from the `traceparent`
+    /// correlation set. `parent_span_id` is the client's span, if its
+    /// trace was kept: the client's sampling decision is followed then,
+    /// and the gateway makes its own otherwise, marking the forwarded
+    /// `traceparent` sampled so the upstream traces the request too.
+    pub fn start_trace(&self, headers: &mut HeaderMap, parent_span_id: Option<String>) -> Option<Arc<Trace>> {
+        let traceparent = headers.get(TRACEPARENT_HEADER)?.to_str().ok()?;
+        let (trace_id, span_id, flags) = parse_traceparent_ids(traceparent)?;
+        let flags = u8::from_str_radix(flags, 16).ok()?;
+        let sampled = match parent_span_id {
+            Some(_) => flags & 1 == 1,
+            None => self.sample_rate >= 1.0 || (self.sample_rate > 0.0 && (random() as f64 / u64::MAX as f64) < self.sample_rate),
+        };
+        if !sampled {
+            return None;
+        }
+        let trace = Arc::new(Trace {
+            trace_id: trace_id.to_string(),
+            span_id: span_id.to_string(),
+            parent_span_id,
+            start: SystemTime::now(),
+            recorded: Mutex::new(Recorded::default()),
+        });
+        if flags & 1 == 0 {
+            let traceparent = format!("00-{}-{}-{:02x}", trace.trace_id, trace.span_id, flags | 1);
+            if let Ok(value) = HeaderValue::from_str(&traceparent) {
+                headers.insert(TRACEPARENT_HEADER, value);
+            }
+        }
+        Some(trace)
+    }
+
+    /// Ends `trace` with the response's status and queues its span, named
+    /// after the route it matched.
+    pub fn finish(&self, trace: &Trace, method: &Method, route: Option<&str>, status: StatusCode) {
+        let span = trace.span(method, route, status, SystemTime::now());
+        if self.queue.try_send(span).is_err() {
+            self.dropped_queue_full.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    async fn run(self: Arc<Self>, exporter: Exporter, mut queue: mpsc::Receiver<Value>) {
+        let mut retry_at: Option<Instant> = None;
+        while let Some(first) = queue.recv().await {
+            let mut batch = vec![first];
+            let deadline = Instant::now() + exporter.flush_interval;
+            while batch.len() < exporter.size {
+                match timeout_at(deadline, queue.recv()).await {
+                    Ok(Some(span)) => batch.push(span),
+                    _ => break,
+                }
+            }
+            let count = batch.len() as u64;
+            if retry_at.is_some_and(|at| Instant::now() < at) {
+                self.dropped_unavailable.fetch_add(count, Ordering::Relaxed);
+                continue;
+            }
+            let error = match timeout(exporter.timeout, exporter.send(batch)).await {
+                Ok(Ok(())) => {
+                    if retry_at.take().is_some() {
+                        println!("Trace collector reachable again");
+                    }
+                    self.exported.fetch_add(count, Ordering::Relaxed);
+                    continue;
+                }
+                Ok(Err(e)) => e,
+                Err(_) => "timed out".to_string(),
+            };
+            if retry_at.is_none() {
+                syslog::error(format_args!("Trace collector unreachable, dropping spans until it is back: {}", error));
+            }
+            retry_at = Some(Instant::now() + Duration::from_secs(RETRY_SECS));
+            self.dropped_unavailable.fetch_add(count, Ordering::Relaxed);
+        }
+    }
+
+    /// The Prometheus text exposition of the spans exported and dropped.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "# HELP {} Spans the trace collector took.", SPANS_EXPORTED);
+        let _ = writeln!(out, "# TYPE {} counter", SPANS_EXPORTED);
+        let _ = writeln!(out, "{} {}", SPANS_EXPORTED, self.exported.load(Ordering::Relaxed));
+        let _ = writeln!(out, "# HELP {} Spans dropped, by why.", SPANS_DROPPED);
+        let _ = writeln!(out, "# TYPE {} counter", SPANS_DROPPED);
+        let _ = writeln!(out, "{}{{reason=\"queue_full\"}} {}", SPANS_DROPPED, self.dropped_queue_full.load(Ordering::Relaxed));
+        let _ = writeln!(out, "{}{{reason=\"collector_unavailable\"}} {}", SPANS_DROPPED, self.dropped_unavailable.load(Ordering::Relaxed));
+        out
+    }
+}
+
+impl Exporter {
+    async fn send(&self, spans: Vec<Value>) -> Result<(), String> {
+        let body = json!({
+            "resourceSpans": [{
+                "resource": self.resource,
+                "scopeSpans": [{ "scope": { "name": "api-gateway" }, "spans": spans }],
+            }],
+        });
+        let request = Request::post(&self.endpoint)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .map_err(|e| e.to_string())?;
+        let response = self.client.request(request).await.map_err(|e| e.to_string())?;
+        let status = response.status();
+        // Read so the connection can be reused.
+        let _ = hyper::body::to_bytes(response.into_body()).await;
+        if !status.is_success() {
+            return Err(format!("collector answered {}", status));
+        }
+        Ok(())
+    }
+}
+
+/// A traced request's span, collecting what the gateway decided while
+/// handling it.
+pub struct Trace {
+    trace_id: String,
+    span_id: String,
+    parent_span_id: Option<String>,
+    start: SystemTime,
+    recorded: Mutex<Recorded>,
+}
+
+#[derive(Default)]
+struct Recorded {
+    attributes: Vec<Value>,
+    events: Vec<Value>,
+}
+
+impl Trace {
+    pub fn attribute(&self, key: &str, value: Value) {
+        let mut recorded = self.recorded.lock().unwrap_or_else(|e| e.into_inner());
+        recorded.attributes.push(attribute(key, value));
+    }
+
+    /// Records a decision as an event at the time it was made. Its
+    /// attributes are set on the span as well, so traces can be searched
+    /// by them.
+    pub fn event(&self, name: &str, attributes: &[(&str, Value)]) {
+        let attributes: Vec<Value> = attributes.iter().map(|(key, value)| attribute(key, value.clone())).collect();
+        let mut recorded = self.recorded.lock().unwrap_or_else(|e| e.into_inner());
+        recorded.attributes.extend(attributes.iter().cloned());
+        recorded.events.push(json!({
+            "timeUnixNano": unix_nanos(SystemTime::now()),
+            "name": name,
+            "attributes": attributes,
+        }));
+    }
+
+    /// The OTLP/JSON span, ending at `end`.
+    pub fn span(&self, method: &Method, route: Option<&str>, status: StatusCode, end: SystemTime) -> Value {
+        let recorded = self.recorded.lock().unwrap_or_else(|e| e.into_inner());
+        let mut attributes = vec![
+            attribute("http.request.method", json!(method.as_str())),
+            attribute("http.response.status_code", json!(status.as_u16())),
+        ];
+        if let Some(route) = route {
+            attributes.push(attribute("http.route", json!(route)));
+        }
+        attributes.extend(recorded.attributes.iter().cloned());
+        let mut span = json!({
+            "traceId": self.trace_id,
+            "spanId": self.span_id,
+            "name": match route {
+                Some(route) => format!("{} {}", method, route),
+                None => method.to_string(),
+            },
+            "kind": SPAN_KIND_SERVER,
+            "startTimeUnixNano": unix_nanos(self.start),
+            "endTimeUnixNano": unix_nanos(end),
+            "attributes": attributes,
+            "events": recorded.events,
+        });
+        if let Some(parent_span_id) = &self.parent_span_id {
+            span["parentSpanId"] = json!(parent_span_id);
+        }
+        if status.is_server_error() {
+            span["status"] = json!({ "code": STATUS_CODE_ERROR });
+        }
+        span
+    }
+}
+
+/// An OTLP key-value pair. Integers go out as strings, as OTLP/JSON has
+/// them.
+fn attribute(key: &str, value: Value) -> Value {
+    let value = match value {
+        Value::Bool(value) => json!({ "boolValue": value }),
+        Value::Number(number) if number.is_f64() => json!({ "doubleValue": number }),
+        Value::Number(number) => json!({ "intValue": number.to_string() }),
+        Value::String(value) => json!({ "stringValue": value }),
+        other => json!({ "stringValue": other.to_string() }),
+    };
+    json!({ "key": key, "value": value })
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos().to_string()
+}
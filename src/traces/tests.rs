@@ -0,0 +1,107 @@
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::{Duration, SystemTime};
+    use hyper::{HeaderMap, Method, StatusCode};
+    use serde_json::{json, Value};
+    use tokio::sync::mpsc;
+    use warp::Filter;
+    use crate::config::TracingConfig;
+    use crate::traces::Tracer;
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+    const SPAN_ID: &str = "00f067aa0ba902b7";
+
+    fn config(endpoint: String, sample_rate: f64) -> TracingConfig {
+        TracingConfig {
+            endpoint,
+            service_name: "edge".to_string(),
+            sample_rate,
+            batch_size: 2,
+            flush_interval_ms: 10,
+            queue_capacity: 100,
+            timeout_ms: 1000,
+        }
+    }
+
+    fn traceparent(flags: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("traceparent", format!("00-{}-{}-{}", TRACE_ID, SPAN_ID, flags).parse().unwrap());
+        headers
+    }
+
+    /// A collector passing on each batch exported to it.
+    fn collector() -> (SocketAddr, mpsc::UnboundedReceiver<Value>) {
+        let (batches, received) = mpsc::unbounded_channel();
+        let traces = warp::post()
+            .and(warp::path!("v1" / "traces"))
+            .and(warp::body::json())
+            .map(move |batch: Value| {
+                let _ = batches.send(batch);
+                warp::reply()
+            });
+        let (addr, server) = warp::serve(traces).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        (addr, received)
+    }
+
+    #[tokio::test]
+    async fn test_sampling_follows_the_client() {
+        let tracer = Tracer::start(&config("http://127.0.0.1:1/v1/traces".to_string(), 0.0));
+        let mut headers = traceparent("01");
+        assert!(tracer.start_trace(&mut headers, Some("b7ad6b7169203331".to_string())).is_some());
+        let mut headers = traceparent("00");
+        assert!(tracer.start_trace(&mut headers, Some("b7ad6b7169203331".to_string())).is_none());
+        // Without a client span the sample rate decides.
+        assert!(tracer.start_trace(&mut headers, None).is_none());
+
+        let tracer = Tracer::start(&config("http://127.0.0.1:1/v1/traces".to_string(), 1.0));
+        assert!(tracer.start_trace(&mut headers, None).is_some());
+        assert_eq!(headers["traceparent"], format!("00-{}-{}-01", TRACE_ID, SPAN_ID), "the upstream is told to trace it too");
+    }
+
+    #[tokio::test]
+    async fn test_spans_carry_decisions() {
+        let tracer = Tracer::start(&config("http://127.0.0.1:1/v1/traces".to_string(), 1.0));
+        let trace = tracer.start_trace(&mut traceparent("01"), Some("b7ad6b7169203331".to_string())).unwrap();
+        trace.event("rate_limit", &[("gateway.rate_limit.outcome", json!("allowed")), ("gateway.rate_limit.remaining", json!(41))]);
+        trace.attribute("gateway.upstream", json!("orders:8080"));
+
+        let span = trace.span(&Method::GET, Some("orders"), StatusCode::BAD_GATEWAY, SystemTime::now());
+        assert_eq!((span["traceId"].as_str(), span["spanId"].as_str()), (Some(TRACE_ID), Some(SPAN_ID)));
+        assert_eq!((span["parentSpanId"].as_str(), span["name"].as_str()), (Some("b7ad6b7169203331"), Some("GET orders")));
+        assert_eq!(span["status"], json!({ "code": 2 }));
+        assert_eq!(span["events"][0]["name"], "rate_limit");
+        assert_eq!(span["events"][0]["attributes"][1], json!({ "key": "gateway.rate_limit.remaining", "value": { "intValue": "41" } }));
+        let attributes: Vec<&str> = span["attributes"].as_array().unwrap().iter().map(|a| a["key"].as_str().unwrap()).collect();
+        assert_eq!(attributes, [
+            "http.request.method",
+            "http.response.status_code",
+            "http.route",
+            "gateway.rate_limit.outcome",
+            "gateway.rate_limit.remaining",
+            "gateway.upstream",
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_spans_are_exported_in_batches() {
+        let (addr, mut received) = collector();
+        let tracer = Tracer::start(&config(format!("http://{}/v1/traces", addr), 1.0));
+        for _ in 0..2 {
+            let trace = tracer.start_trace(&mut traceparent("01"), None).unwrap();
+            tracer.finish(&trace, &Method::GET, None, StatusCode::OK);
+        }
+        let batch = received.recv().await.unwrap();
+        let resource = &batch["resourceSpans"][0];
+        assert_eq!(resource["resource"]["attributes"][0], json!({ "key": "service.name", "value": { "stringValue": "edge" } }));
+        assert_eq!(resource["scopeSpans"][0]["spans"].as_array().unwrap().len(), 2);
+        for _ in 0..200 {
+            if tracer.render().contains("gateway_spans_exported_total 2\n") {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("spans never counted as exported");
+    }
+}
@@ -0,0 +1,254 @@
+use std::sync::RwLock as SyncRwLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use bytes::Bytes;
+use hyper::{Body, Client, HeaderMap, Method, Request, Response, StatusCode, Uri, client::HttpConnector};
+use hyper::header::{HeaderValue, AUTHORIZATION, CONTENT_TYPE, HOST, WWW_AUTHENTICATE};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::Deserialize;
+use tokio::sync::RwLock;
+use tokio::time::timeout;
+use crate::config::{NtlmConfig, OAuth2Config, UpstreamAuthConfig, REQUEST_TIMEOUT_SECS};
+use crate::connector::UpstreamClient;
+use crate::errors::GatewayError;
+use crate::ntlm::{self, Credentials};
+
+#[cfg(test)]
+mod tests;
+
+/// How long before its stated expiry an OAuth2 token is replaced.
+const TOKEN_EXPIRY_MARGIN: Duration = Duration::from_secs(30);
+/// Seconds from 1601, where FILETIMEs start, to the Unix epoch.
+const FILETIME_UNIX_EPOCH_SECS: u64 = 11_644_473_600;
+
+/// Gateway-managed credentials for one route. The current `Authorization`
+/// value is fetched on first use and kept until it expires or the upstream
+/// rejects it.
+#[derive(Debug)]
+pub struct UpstreamAuth {
+    config: SyncRwLock<UpstreamAuthConfig>,
+    current: RwLock<Option<Credential>>,
+    client: Client<HttpConnector>,
+}
+
+#[derive(Debug, Clone)]
+struct Credential {
+    authorization: HeaderValue,
+    expires_at: Option<Instant>,
+}
+
+impl Credential {
+    fn expired(&self) -> bool {
+        matches!(self.expires_at, Some(at) if Instant::now() >= at)
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    #[serde(default)]
+    expires_in: Option<u64>,
+}
+
+impl UpstreamAuth {
+    pub fn new(config: UpstreamAuthConfig) -> Self {
+        Self {
+            config: SyncRwLock::new(config),
+            current: RwLock::new(None),
+            client: Client::new(),
+        }
+    }
+
+    /// Whether fetching again can yield a different credential, i.e. whether
+    /// a 401 is worth a retry.
+    pub fn refreshable(&self) -> bool {
+        matches!(self.config(), UpstreamAuthConfig::TokenFile(_) | UpstreamAuthConfig::OAuth2(_))
+    }
+
+    /// Whether the credential authenticates a connection rather than a
+    /// request, so requests go through `send_on_connection`.
+    pub fn per_connection(&self) -> bool {
+        matches!(self.config(), UpstreamAuthConfig::Ntlm(_))
+    }
+
+    /// Switches to new settings, such as refreshed secrets. The current
+    /// credential is dropped if they changed, so the next request fetches
+    /// one with them.
+    pub async fn reconfigure(&self, config: UpstreamAuthConfig) {
+        if self.config() == config {
+            return;
+        }
+        *self.config.write().unwrap_or_else(|e| e.into_inner()) = config;
+        *self.current.write().await = None;
+    }
+
+    fn config(&self) -> UpstreamAuthConfig {
+        self.config.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// The `Authorization` value to send upstream.
+    pub async fn authorization(&self) -> Result<HeaderValue, GatewayError> {
+        if let Some(credential) = self.current.read().await.clone() {
+            if !credential.expired() {
+                return Ok(credential.authorization);
+            }
+        }
+        self.refresh().await
+    }
+
+    /// Fetches a fresh credential from the token source, replacing the
+    /// current one.
+    pub async fn refresh(&self) -> Result<HeaderValue, GatewayError> {
+        let credential = self.fetch().await?;
+        let authorization = credential.authorization.clone();
+        *self.current.write().await = Some(credential);
+        Ok(authorization)
+    }
+
+    async fn fetch(&self) -> Result<Credential, GatewayError> {
+        let authorization = match &self.config() {
+            UpstreamAuthConfig::Bearer(token) => header_value(format!("Bearer {}", token))?,
+            UpstreamAuthConfig::Basic { username, password } => {
+                let credentials = STANDARD.encode(format!("{}:{}", username, password));
+                header_value(format!("Basic {}", credentials))?
+            }
+            UpstreamAuthConfig::TokenFile(path) => {
+                let token = tokio::fs::read_to_string(path).await.map_err(|e| {
+                    GatewayError::Http(format!("reading upstream token {}: {}", path.display(), e))
+                })?;
+                header_value(format!("Bearer {}", token.trim()))?
+            }
+            UpstreamAuthConfig::OAuth2(oauth2) => return self.client_credentials(oauth2).await,
+            UpstreamAuthConfig::Ntlm(config) => {
+                header_value(format!("{} {}", config.scheme.as_str(), STANDARD.encode(ntlm::negotiate())))?
+            }
+        };
+        Ok(Credential { authorization, expires_at: None })
+    }
+
+    /// Runs the OAuth2 client-credentials grant against the token endpoint.
+    async fn client_credentials(&self, config: &OAuth2Config) -> Result<Credential, GatewayError> {
+        let form = {
+            let mut form = form_urlencoded::Serializer::new(String::new());
+            form.append_pair("grant_type", "client_credentials")
+                .append_pair("client_id", &config.client_id)
+                .append_pair("client_secret", &config.client_secret);
+            if let Some(scope) = &config.scope {
+                form.append_pair("scope", scope);
+            }
+            form.finish()
+        };
+
+        let request = Request::post(config.token_url.as_str())
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from(form))
+            .map_err(|e| GatewayError::Http(format!("building token request: {}", e)))?;
+        let response = timeout(Duration::from_secs(REQUEST_TIMEOUT_SECS), self.client.request(request))
+            .await
+            .map_err(|_| GatewayError::Timeout)?
+            .map_err(|e| GatewayError::upstream(&e))?;
+        if !response.status().is_success() {
+            return Err(GatewayError::BadGateway(format!(
+                "token endpoint {} answered {}",
+                config.token_url,
+                response.status()
+            )));
+        }
+
+        let body = hyper::body::to_bytes(response.into_body())
+            .await
+            .map_err(|e| GatewayError::BadGateway(format!("reading upstream token: {}", e)))?;
+        let token: TokenResponse = serde_json::from_slice(&body)
+            .map_err(|e| GatewayError::BadGateway(format!("invalid token response: {}", e)))?;
+        let expires_at = token
+            .expires_in
+            .map(|secs| Instant::now() + Duration::from_secs(secs).saturating_sub(TOKEN_EXPIRY_MARGIN));
+        Ok(Credential {
+            authorization: header_value(format!("Bearer {}", token.access_token))?,
+            expires_at,
+        })
+    }
+
+    /// Sends a request on a connection of its own, authenticating the
+    /// connection with NTLM on the way: the request goes out with a
+    /// NEGOTIATE message and, if the upstream answers with a challenge,
+    /// again with the AUTHENTICATE message. Any other answer is returned
+    /// as it is.
+    pub async fn send_on_connection(
+        &self,
+        client: &UpstreamClient,
+        method: &Method,
+        uri: &Uri,
+        headers: &HeaderMap,
+        body: Bytes,
+    ) -> Result<Response<Body>, GatewayError> {
+        let UpstreamAuthConfig::Ntlm(config) = self.config() else {
+            return Err(GatewayError::Http("upstream_auth is not connection-based".to_string()));
+        };
+        let scheme = config.scheme.as_str();
+        let request = |authorization: HeaderValue| {
+            let path = uri.path_and_query().map_or("/", |path| path.as_str());
+            let mut request = Request::builder()
+                .method(method.clone())
+                .uri(path)
+                .body(Body::from(body.clone()))
+                .map_err(|e| GatewayError::Http(e.to_string()))?;
+            *request.headers_mut() = headers.clone();
+            if let (false, Some(authority)) = (headers.contains_key(HOST), uri.authority()) {
+                request.headers_mut().insert(HOST, header_value(authority.to_string())?);
+            }
+            request.headers_mut().insert(AUTHORIZATION, authorization);
+            Ok::<_, GatewayError>(request)
+        };
+
+        let mut connection = client.connection(uri).await?;
+        let negotiate = ntlm::negotiate();
+        let opening = header_value(format!("{} {}", scheme, STANDARD.encode(&negotiate)))?;
+        let response = connection.send_request(request(opening)?).await.map_err(|e| GatewayError::upstream(&e))?;
+        let challenge = response
+            .headers()
+            .get_all(WWW_AUTHENTICATE)
+            .iter()
+            .filter_map(|value| value.to_str().ok()?.split_once(' '))
+            .find(|(offered, _)| offered.eq_ignore_ascii_case(scheme))
+            .map(|(_, token)| token.trim().to_string());
+        let challenge = match (response.status(), challenge) {
+            (StatusCode::UNAUTHORIZED, Some(challenge)) => challenge,
+            _ => return Ok(response),
+        };
+        let challenge = STANDARD
+            .decode(challenge)
+            .map_err(|_| GatewayError::BadGateway("upstream sent an invalid NTLM challenge".to_string()))?;
+        // The connection is only reused once the challenge's body is read.
+        hyper::body::to_bytes(response.into_body()).await.map_err(|e| GatewayError::upstream(&e))?;
+
+        let answer = authenticate(&config, &negotiate, &challenge)?;
+        let authorization = header_value(format!("{} {}", scheme, STANDARD.encode(answer)))?;
+        futures::future::poll_fn(|cx| connection.poll_ready(cx)).await.map_err(|e| GatewayError::upstream(&e))?;
+        connection.send_request(request(authorization)?).await.map_err(|e| GatewayError::upstream(&e))
+    }
+}
+
+/// The AUTHENTICATE message answering an upstream's NTLM challenge.
+fn authenticate(config: &NtlmConfig, negotiate: &[u8], challenge: &[u8]) -> Result<Vec<u8>, GatewayError> {
+    let mut client_challenge = [0u8; 8];
+    SystemRandom::new()
+        .fill(&mut client_challenge)
+        .map_err(|_| GatewayError::Http("no randomness available".to_string()))?;
+    let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let filetime = (since_epoch.as_secs() + FILETIME_UNIX_EPOCH_SECS) * 10_000_000 + u64::from(since_epoch.subsec_nanos() / 100);
+    let credentials = Credentials {
+        username: &config.username,
+        password: &config.password,
+        domain: &config.domain,
+        workstation: &config.workstation,
+    };
+    ntlm::authenticate(&credentials, negotiate, challenge, client_challenge, filetime)
+        .map_err(|e| GatewayError::BadGateway(format!("answering the upstream's NTLM challenge: {}", e)))
+}
+
+fn header_value(value: String) -> Result<HeaderValue, GatewayError> {
+    HeaderValue::from_str(&value)
+        .map_err(|_| GatewayError::Http("upstream credential is not a valid header value".to_string()))
+}
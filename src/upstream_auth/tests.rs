@@ -0,0 +1,190 @@
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, HeaderMap, Method, Request, Response, Server, StatusCode};
+    use warp::Filter;
+    use crate::config::{NtlmConfig, NtlmScheme, OAuth2Config, UpstreamAuthConfig, UpstreamConnectConfig};
+    use crate::connector::{UpstreamClient, UpstreamTls};
+    use crate::metrics::Connections;
+    use crate::ntlm::{hmac_md5, ntowfv2, Credentials};
+    use crate::upstream_auth::UpstreamAuth;
+
+    fn token_file(name: &str, token: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("api-gateway-{}-{}", name, std::process::id()));
+        std::fs::write(&path, token).unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn test_token_file_is_cached_until_refresh() {
+        let path = token_file("token-refresh", "first\n");
+        let auth = UpstreamAuth::new(UpstreamAuthConfig::TokenFile(path.clone()));
+
+        assert_eq!(auth.authorization().await.unwrap(), "Bearer first");
+
+        std::fs::write(&path, "second").unwrap();
+        assert_eq!(auth.authorization().await.unwrap(), "Bearer first");
+        assert_eq!(auth.refresh().await.unwrap(), "Bearer second");
+        assert_eq!(auth.authorization().await.unwrap(), "Bearer second");
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_missing_token_file() {
+        let auth = UpstreamAuth::new(UpstreamAuthConfig::TokenFile(PathBuf::from("/nonexistent/token")));
+
+        assert!(auth.authorization().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_static_credentials() {
+        let bearer = UpstreamAuth::new(UpstreamAuthConfig::Bearer("service-token".to_string()));
+        assert_eq!(bearer.authorization().await.unwrap(), "Bearer service-token");
+        assert!(!bearer.refreshable());
+
+        let basic = UpstreamAuth::new(UpstreamAuthConfig::Basic {
+            username: "gateway".to_string(),
+            password: "s3cret".to_string(),
+        });
+        assert_eq!(basic.authorization().await.unwrap(), "Basic Z2F0ZXdheTpzM2NyZXQ=");
+    }
+
+    #[tokio::test]
+    async fn test_reconfigure_replaces_the_credential() {
+        let auth = UpstreamAuth::new(UpstreamAuthConfig::Bearer("old-token".to_string()));
+        assert_eq!(auth.authorization().await.unwrap(), "Bearer old-token");
+
+        auth.reconfigure(UpstreamAuthConfig::Bearer("old-token".to_string())).await;
+        assert!(auth.current.read().await.is_some());
+        auth.reconfigure(UpstreamAuthConfig::Bearer("new-token".to_string())).await;
+        assert_eq!(auth.authorization().await.unwrap(), "Bearer new-token");
+    }
+
+    #[tokio::test]
+    async fn test_oauth2_client_credentials() {
+        let issued = Arc::new(AtomicUsize::new(0));
+        let counter = issued.clone();
+        let token_endpoint = warp::path("token")
+            .and(warp::post())
+            .and(warp::body::form())
+            .map(move |form: std::collections::HashMap<String, String>| {
+                assert_eq!(form["grant_type"], "client_credentials");
+                assert_eq!(form["client_id"], "gateway");
+                assert_eq!(form["scope"], "orders:read");
+                let n = counter.fetch_add(1, Ordering::SeqCst) + 1;
+                warp::reply::json(&serde_json::json!({
+                    "access_token": format!("token-{}", n),
+                    "token_type": "Bearer",
+                    "expires_in": 3600
+                }))
+            });
+        let (addr, server) = warp::serve(token_endpoint).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let auth = UpstreamAuth::new(UpstreamAuthConfig::OAuth2(OAuth2Config {
+            token_url: format!("http://{}/token", addr),
+            client_id: "gateway".to_string(),
+            client_secret: "s3cret".to_string(),
+            scope: Some("orders:read".to_string()),
+        }));
+
+        assert_eq!(auth.authorization().await.unwrap(), "Bearer token-1");
+        assert_eq!(auth.authorization().await.unwrap(), "Bearer token-1");
+        assert_eq!(auth.refresh().await.unwrap(), "Bearer token-2");
+        assert_eq!(issued.load(Ordering::SeqCst), 2);
+    }
+
+    const SERVER_CHALLENGE: [u8; 8] = [0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef];
+
+    fn unauthorized(challenge: Option<Vec<u8>>) -> Response<Body> {
+        let offer = match challenge {
+            Some(challenge) => format!("Negotiate {}", STANDARD.encode(challenge)),
+            None => "Negotiate".to_string(),
+        };
+        Response::builder().status(StatusCode::UNAUTHORIZED).header("www-authenticate", offer).body(Body::from("denied")).unwrap()
+    }
+
+    /// A Windows-style upstream that challenges each new connection and
+    /// accepts `CORP\svc-gateway` with password `hunter2` on the connection
+    /// that was challenged.
+    async fn ntlm_upstream() -> std::net::SocketAddr {
+        let make_service = make_service_fn(|_| async {
+            let challenged = Arc::new(Mutex::new(false));
+            Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                let challenged = challenged.clone();
+                async move {
+                    assert_eq!(request.uri(), "/reports?year=2024");
+                    assert!(request.headers().contains_key("host"));
+                    let token = request.headers().get("authorization").and_then(|value| value.to_str().ok()?.strip_prefix("Negotiate "));
+                    let message = token.map(|token| STANDARD.decode(token).unwrap()).unwrap_or_default();
+                    let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                    assert_eq!(body, "payload");
+                    let response = match message.get(8) {
+                        Some(1) => {
+                            *challenged.lock().unwrap() = true;
+                            let mut challenge = b"NTLMSSP\0\x02\x00\x00\x00\0\0\0\0\x30\0\0\0".to_vec();
+                            challenge.extend_from_slice(&0xe28a_8233u32.to_le_bytes());
+                            challenge.extend_from_slice(&SERVER_CHALLENGE);
+                            challenge.extend_from_slice(&[0; 8]);
+                            challenge.extend_from_slice(&[4, 0, 4, 0, 0x30, 0, 0, 0]);
+                            challenge.extend_from_slice(&[0; 4]);
+                            unauthorized(Some(challenge))
+                        }
+                        Some(3) if *challenged.lock().unwrap() => {
+                            let (len, offset) = (message[20] as usize, message[24] as usize);
+                            let nt_response = &message[offset..offset + len];
+                            let credentials = Credentials { username: "svc-gateway", password: "hunter2", domain: "CORP", workstation: "" };
+                            let expected = hmac_md5(&ntowfv2(&credentials), &[&SERVER_CHALLENGE[..], &nt_response[16..]].concat());
+                            match nt_response[..16] == expected {
+                                true => Response::new(Body::from("report")),
+                                false => unauthorized(None),
+                            }
+                        }
+                        _ => unauthorized(None),
+                    };
+                    Ok::<_, Infallible>(response)
+                }
+            }))
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_ntlm_authenticates_the_connection() {
+        let addr = ntlm_upstream().await;
+        let connect = UpstreamConnectConfig::default();
+        let tls = Arc::new(UpstreamTls::new(&connect.tls).unwrap());
+        let client = UpstreamClient::new(&connect, tls, Arc::new(Connections::default())).unwrap();
+        let uri = format!("http://{}/reports?year=2024", addr).parse().unwrap();
+        let ntlm = |password: &str| {
+            UpstreamAuth::new(UpstreamAuthConfig::Ntlm(NtlmConfig {
+                username: "svc-gateway".to_string(),
+                password: password.to_string(),
+                domain: "CORP".to_string(),
+                workstation: String::new(),
+                scheme: NtlmScheme::Negotiate,
+            }))
+        };
+
+        let auth = ntlm("hunter2");
+        assert!(auth.per_connection() && !auth.refreshable());
+        assert!(auth.authorization().await.unwrap().to_str().unwrap().starts_with("Negotiate TlRMTVNTUAAB"));
+        let response = auth.send_on_connection(&client, &Method::POST, &uri, &HeaderMap::new(), "payload".into()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(hyper::body::to_bytes(response.into_body()).await.unwrap(), "report");
+
+        // A rejected answer is passed on as the upstream gave it.
+        let response = ntlm("wrong").send_on_connection(&client, &Method::POST, &uri, &HeaderMap::new(), "payload".into()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,151 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Request};
+use ring::hmac;
+use serde_json::{json, Value};
+use tokio::time::timeout;
+use crate::aws::{self, HttpsClient};
+use crate::cluster::LeaderElection;
+use crate::config::{WebhookEndpoint, WebhookEvent, WebhooksConfig};
+use crate::tls::CertResolver;
+use crate::syslog;
+
+#[cfg(test)]
+mod tests;
+
+pub const EVENT_HEADER: &str = "x-gateway-event";
+/// `sha256=` and the hex HMAC-SHA256 of the body under the endpoint's secret.
+pub const WEBHOOK_SIGNATURE_HEADER: &str = "x-gateway-webhook-signature";
+const CERTIFICATE_CHECK_SECS: u64 = 3600;
+/// Wait before the second try of a delivery, doubled for each one after.
+const RETRY_MS: u64 = 1000;
+const DAY_SECS: u64 = 86400;
+
+/// Sends lifecycle events to the configured endpoints. Each delivery runs
+/// in the background and is retried with backoff; one that never succeeds is
+/// logged and dropped.
+pub struct Webhooks {
+    endpoints: Vec<WebhookEndpoint>,
+    client: HttpsClient,
+    max_attempts: u32,
+    timeout: Duration,
+    retry: Duration,
+    certificate_warning_secs: u64,
+    /// Certificates already warned about, by name and expiry, so each one is
+    /// reported once rather than on every check.
+    warned: Mutex<HashSet<(String, u64)>>,
+}
+
+impl Webhooks {
+    pub fn new(config: &WebhooksConfig) -> Self {
+        Self {
+            endpoints: config.endpoints.clone(),
+            client: aws::https_client(),
+            max_attempts: config.max_attempts,
+            timeout: Duration::from_millis(config.timeout_ms),
+            retry: Duration::from_millis(RETRY_MS),
+            certificate_warning_secs: config.certificate_warning_days * DAY_SECS,
+            warned: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Sends `event` to every endpoint subscribed to it. `text` is a one-line
+    /// summary, which chat integrations show as the message.
+    pub fn notify(self: &Arc<Self>, event: WebhookEvent, text: String, details: Value) {
+        let body = json!({
+            "event": event_name(event),
+            "text": text,
+            "timestamp": unix_now(),
+            "details": details,
+        })
+        .to_string();
+        let subscribed = self.endpoints.iter().filter(|endpoint| endpoint.events.is_empty() || endpoint.events.contains(&event));
+        for endpoint in subscribed {
+            let (webhooks, endpoint, body) = (self.clone(), endpoint.clone(), body.clone());
+            tokio::spawn(async move {
+                let mut wait = webhooks.retry;
+                for attempt in 1..=webhooks.max_attempts {
+                    match webhooks.deliver(&endpoint, event, &body).await {
+                        Ok(()) => return,
+                        Err(e) if attempt == webhooks.max_attempts => {
+                            syslog::error(format_args!("Dropping {} webhook after {} attempts: {}", event_name(event), attempt, e));
+                        }
+                        Err(_) => {
+                            tokio::time::sleep(wait).await;
+                            wait *= 2;
+                        }
+                    }
+                }
+            });
+        }
+    }
+
+    /// Sends `certificate_expiring` for each certificate being served that
+    /// expires within the warning window and hasn't been reported yet.
+    pub fn check_certificates(self: &Arc<Self>, resolver: &CertResolver, now: u64) {
+        for (name, expires) in resolver.expiries() {
+            if expires > now + self.certificate_warning_secs {
+                continue;
+            }
+            if !self.warned.lock().unwrap_or_else(|e| e.into_inner()).insert((name.clone(), expires)) {
+                continue;
+            }
+            let expiry = httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(expires));
+            let text = match expires.checked_sub(now) {
+                Some(left) => format!("Certificate for {} expires in {} days, on {}", name, left / DAY_SECS, expiry),
+                None => format!("Certificate for {} expired on {}", name, expiry),
+            };
+            self.notify(WebhookEvent::CertificateExpiring, text, json!({ "name": name, "expires_at": expires }));
+        }
+    }
+
+    /// Checks the served certificates every hour; with `leader`, only on
+    /// the leader, since every instance serves the same ones.
+    pub async fn watch_certificates(self: Arc<Self>, resolver: Arc<CertResolver>, leader: Option<Arc<LeaderElection>>) {
+        loop {
+            if leader.as_ref().is_none_or(|leader| leader.is_leader()) {
+                self.check_certificates(&resolver, unix_now());
+            }
+            tokio::time::sleep(Duration::from_secs(CERTIFICATE_CHECK_SECS)).await;
+        }
+    }
+
+    async fn deliver(&self, endpoint: &WebhookEndpoint, event: WebhookEvent, body: &str) -> Result<(), String> {
+        let mut request = Request::post(&endpoint.url)
+            .header(CONTENT_TYPE, "application/json")
+            .header(EVENT_HEADER, event_name(event));
+        if let Some(secret) = &endpoint.secret {
+            request = request.header(WEBHOOK_SIGNATURE_HEADER, sign(secret, body));
+        }
+        let request = request.body(Body::from(body.to_string())).map_err(|e| e.to_string())?;
+        let response = timeout(self.timeout, self.client.request(request))
+            .await
+            .map_err(|_| format!("{} timed out", endpoint.url))?
+            .map_err(|e| format!("{}: {}", endpoint.url, e))?;
+        if !response.status().is_success() {
+            return Err(format!("{} answered {}", endpoint.url, response.status()));
+        }
+        Ok(())
+    }
+}
+
+/// The `WEBHOOK_SIGNATURE_HEADER` value for `body`.
+pub fn sign(secret: &str, body: &str) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    format!("sha256={}", aws::hex(hmac::sign(&key, body.as_bytes()).as_ref()))
+}
+
+pub fn event_name(event: WebhookEvent) -> &'static str {
+    match event {
+        WebhookEvent::ConfigReloaded => "config_reloaded",
+        WebhookEvent::QuotaExceeded => "quota_exceeded",
+        WebhookEvent::CertificateExpiring => "certificate_expiring",
+        WebhookEvent::UpstreamUnhealthy => "upstream_unhealthy",
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
@@ -0,0 +1,138 @@
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use hyper::{HeaderMap, StatusCode};
+    use ring::rand::SystemRandom;
+    use serde_json::{json, Value};
+    use tokio::sync::mpsc;
+    use warp::Filter;
+    use crate::acme::{not_after, pem, self_signed};
+    use crate::config::{GatewayConfig, WebhookEndpoint, WebhookEvent, WebhooksConfig};
+    use crate::models::AppState;
+    use crate::services::check_tenant_rate_limit;
+    use crate::tenants::Tenants;
+    use crate::tls::{certified_key, CertResolver};
+    use crate::webhooks::{sign, Webhooks, EVENT_HEADER, WEBHOOK_SIGNATURE_HEADER};
+
+    /// The path, event header, signature header and body of a delivery.
+    type Delivery = (String, String, Option<String>, Value);
+
+    /// A receiver that answers `statuses` in turn, then 200, and passes on
+    /// each delivery.
+    fn receiver(statuses: Vec<u16>) -> (SocketAddr, mpsc::UnboundedReceiver<Delivery>) {
+        let (deliveries, received) = mpsc::unbounded_channel();
+        let answered = Arc::new(AtomicUsize::new(0));
+        let hooks = warp::post()
+            .and(warp::path::full())
+            .and(warp::header::<String>(EVENT_HEADER))
+            .and(warp::header::optional::<String>(WEBHOOK_SIGNATURE_HEADER))
+            .and(warp::body::bytes())
+            .map(move |path: warp::path::FullPath, event: String, signature: Option<String>, body: bytes::Bytes| {
+                let body = String::from_utf8(body.to_vec()).unwrap();
+                if let Some(signature) = &signature {
+                    assert_eq!(signature, &sign("hook-secret", &body));
+                }
+                let _ = deliveries.send((path.as_str().to_string(), event, signature, serde_json::from_str(&body).unwrap()));
+                let status = statuses.get(answered.fetch_add(1, Ordering::SeqCst)).copied().unwrap_or(200);
+                warp::reply::with_status(warp::reply(), StatusCode::from_u16(status).unwrap())
+            });
+        let (addr, server) = warp::serve(hooks).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        (addr, received)
+    }
+
+    fn webhooks(endpoints: Vec<WebhookEndpoint>, max_attempts: u32) -> Arc<Webhooks> {
+        let mut webhooks = Webhooks::new(&WebhooksConfig { endpoints, max_attempts, ..WebhooksConfig::default() });
+        webhooks.retry = Duration::from_millis(10);
+        Arc::new(webhooks)
+    }
+
+    fn endpoint(addr: SocketAddr, path: &str, events: Vec<WebhookEvent>, secret: Option<&str>) -> WebhookEndpoint {
+        WebhookEndpoint { url: format!("http://{}{}", addr, path), events, secret: secret.map(String::from) }
+    }
+
+    #[tokio::test]
+    async fn test_events_reach_subscribed_endpoints() {
+        let (addr, mut received) = receiver(Vec::new());
+        let webhooks = webhooks(vec![
+            endpoint(addr, "/certificates", vec![WebhookEvent::CertificateExpiring], None),
+            endpoint(addr, "/all", Vec::new(), Some("hook-secret")),
+        ], 1);
+
+        webhooks.notify(WebhookEvent::ConfigReloaded, "Refreshed secrets".to_string(), json!({ "source": "secrets" }));
+        let (path, event, signature, body) = received.recv().await.unwrap();
+        assert_eq!((path.as_str(), event.as_str()), ("/all", "config_reloaded"));
+        assert!(signature.is_some());
+        assert_eq!((body["event"].as_str(), body["text"].as_str()), (Some("config_reloaded"), Some("Refreshed secrets")));
+        assert_eq!(body["details"], json!({ "source": "secrets" }));
+
+        webhooks.notify(WebhookEvent::CertificateExpiring, "expiring".to_string(), json!({}));
+        let mut paths = vec![received.recv().await.unwrap().0, received.recv().await.unwrap().0];
+        paths.sort();
+        assert_eq!(paths, ["/all", "/certificates"]);
+    }
+
+    #[tokio::test]
+    async fn test_failed_deliveries_are_retried() {
+        let (addr, mut received) = receiver(vec![500, 503]);
+        let retried = webhooks(vec![endpoint(addr, "/hook", Vec::new(), None)], 3);
+        retried.notify(WebhookEvent::ConfigReloaded, "reloaded".to_string(), json!({}));
+        for _ in 0..3 {
+            assert_eq!(received.recv().await.unwrap().0, "/hook");
+        }
+
+        // Past max_attempts the delivery is dropped.
+        let (addr, mut received) = receiver(vec![500, 500, 500]);
+        let dropped = webhooks(vec![endpoint(addr, "/hook", Vec::new(), None)], 2);
+        dropped.notify(WebhookEvent::ConfigReloaded, "reloaded".to_string(), json!({}));
+        received.recv().await.unwrap();
+        received.recv().await.unwrap();
+        assert!(tokio::time::timeout(Duration::from_millis(200), received.recv()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_expiring_certificates_are_reported_once() {
+        let (addr, mut received) = receiver(Vec::new());
+        let webhooks = webhooks(vec![endpoint(addr, "/hook", Vec::new(), None)], 1);
+        let random = SystemRandom::new();
+        let key = |days| {
+            let (cert, key) = self_signed("api.example.com", Vec::new(), days, &random).unwrap();
+            (not_after(&cert).unwrap(), certified_key(pem("CERTIFICATE", &cert).as_bytes(), pem("PRIVATE KEY", &key).as_bytes()).unwrap())
+        };
+        let (expires, soon) = key(5);
+        let resolver = CertResolver::new(Some(soon));
+        resolver.insert("api.example.com", key(90).1);
+
+        let now = expires - 3 * 86400;
+        webhooks.check_certificates(&resolver, now);
+        webhooks.check_certificates(&resolver, now + 60);
+        let (_, event, _, body) = received.recv().await.unwrap();
+        assert_eq!(event, "certificate_expiring");
+        assert_eq!(body["details"], json!({ "name": "default", "expires_at": expires }));
+        assert!(body["text"].as_str().unwrap().starts_with("Certificate for default expires in 3 days"));
+        assert!(tokio::time::timeout(Duration::from_millis(200), received.recv()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_quota_exceeded_is_reported_once_per_window() {
+        let (addr, mut received) = receiver(Vec::new());
+        let config = GatewayConfig::from_json(r#"{
+            "tenancy": { "tenants": { "acme": { "quota": { "requests": 1, "window_secs": 60 } } } }
+        }"#).unwrap();
+        let tenants = Tenants::from_config(&config.tenancy);
+        let mut state = AppState::new();
+        state.webhooks = Some(webhooks(vec![endpoint(addr, "/hook", vec![WebhookEvent::QuotaExceeded], None)], 1));
+        let headers = HeaderMap::new();
+
+        assert!(check_tenant_rate_limit(&state, &headers, tenants.get("acme").unwrap()).await);
+        assert!(!check_tenant_rate_limit(&state, &headers, tenants.get("acme").unwrap()).await);
+        assert!(!check_tenant_rate_limit(&state, &headers, tenants.get("acme").unwrap()).await);
+        let (_, event, _, body) = received.recv().await.unwrap();
+        assert_eq!(event, "quota_exceeded");
+        assert_eq!(body["details"], json!({ "tenant": "acme", "requests": 1, "window_secs": 60 }));
+        assert!(tokio::time::timeout(Duration::from_millis(200), received.recv()).await.is_err());
+    }
+}